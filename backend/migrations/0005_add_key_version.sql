@@ -0,0 +1,5 @@
+-- Migration: Track a version number for each user's public key
+-- Used for optimistic locking when multiple devices update the key concurrently
+
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS key_version INT NOT NULL DEFAULT 0;
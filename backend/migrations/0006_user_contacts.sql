@@ -0,0 +1,17 @@
+-- Migration: Per-user contact lists and conversation peers
+-- user_contacts holds the contacts a user explicitly added.
+-- conversation_peers records every user someone has sent a message to, so replies
+-- within an existing conversation survive the read-deletion of the messages themselves.
+
+CREATE TABLE IF NOT EXISTS user_contacts (
+    owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    contact_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (owner_id, contact_id)
+);
+
+CREATE TABLE IF NOT EXISTS conversation_peers (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    peer_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, peer_id)
+);
@@ -0,0 +1,18 @@
+-- Migration: Contact request / approval handshake
+-- status is one of PENDING, ACCEPTED, DECLINED, WITHDRAWN
+
+CREATE TABLE IF NOT EXISTS contact_requests (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    requester_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    target_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    status TEXT NOT NULL DEFAULT 'PENDING',
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    responded_at TIMESTAMPTZ
+);
+
+-- Only one pending request per pair of users, regardless of direction
+CREATE UNIQUE INDEX IF NOT EXISTS contact_requests_one_pending_per_pair
+    ON contact_requests (LEAST(requester_id, target_id), GREATEST(requester_id, target_id))
+    WHERE status = 'PENDING';
+
+CREATE INDEX IF NOT EXISTS contact_requests_target_idx ON contact_requests (target_id, status);
@@ -0,0 +1,9 @@
+-- Migration: Messages start as PENDING until a delivery attempt has been made
+-- Valid statuses: PENDING, SENT, DELIVERED, READ, FAILED
+
+-- Speeds up the background sweep that upgrades stale PENDING messages to SENT
+CREATE INDEX IF NOT EXISTS messages_pending_timestamp_idx
+    ON messages (timestamp)
+    WHERE status = 'PENDING';
+
+COMMENT ON COLUMN messages.status IS 'One of PENDING, SENT, DELIVERED, READ, FAILED';
@@ -0,0 +1,8 @@
+-- Migration: Conversation-scoped message pins
+-- Each message can be pinned once per conversation; either participant may pin or unpin it.
+
+CREATE TABLE IF NOT EXISTS message_pins (
+    message_id UUID PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    pinned_by UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    pinned_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,6 @@
+-- Migration: Support per-user backlog metrics for undelivered messages
+
+-- Backlog queries count SENT messages per receiver and find the oldest one
+CREATE INDEX IF NOT EXISTS messages_sent_receiver_timestamp_idx
+    ON messages (receiver_id, timestamp)
+    WHERE status = 'SENT';
@@ -0,0 +1,5 @@
+-- Migration: Track forwarded messages
+-- forwarded_from_id has no foreign key so the flag survives deletion of the read source message
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS forwarded_from_id UUID;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS forward_count INT NOT NULL DEFAULT 0;
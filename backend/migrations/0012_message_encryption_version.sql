@@ -0,0 +1,4 @@
+-- Migration: Record which encryption scheme each message uses
+-- Existing rows are backfilled with version 1, the only scheme used so far
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS encryption_version SMALLINT NOT NULL DEFAULT 1;
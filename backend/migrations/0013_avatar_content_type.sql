@@ -0,0 +1,4 @@
+-- Migration: Record the validated content type of each avatar
+-- Existing avatars keep NULL and are re-checked when served
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar_content_type TEXT;
@@ -0,0 +1,9 @@
+-- Migration: Indexes for the read-heavy message queries
+
+-- Conversation history looks up each direction of a pair and walks it in timestamp order
+CREATE INDEX IF NOT EXISTS messages_sender_receiver_timestamp_idx
+    ON messages (sender_id, receiver_id, timestamp);
+
+-- Per-receiver status lookups, such as counting undelivered messages
+CREATE INDEX IF NOT EXISTS messages_receiver_status_idx
+    ON messages (receiver_id, status);
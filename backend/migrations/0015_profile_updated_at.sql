@@ -0,0 +1,4 @@
+-- Migration: Track when a user's public profile last changed
+-- Bumped on username, avatar and public key updates; profile ETags are derived from it
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS profile_updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
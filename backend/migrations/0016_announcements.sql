@@ -0,0 +1,21 @@
+-- Migration: Operator announcements broadcast to all users, and an audit log of admin actions
+-- Valid severities: info, warning, critical
+
+CREATE TABLE IF NOT EXISTS announcements (
+    id UUID PRIMARY KEY,
+    message TEXT NOT NULL,
+    severity TEXT NOT NULL CHECK (severity IN ('info', 'warning', 'critical')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS announcements_created_at_idx ON announcements (created_at);
+
+CREATE TABLE IF NOT EXISTS admin_audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    action TEXT NOT NULL,
+    target_id UUID,
+    source_ip TEXT,
+    details TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
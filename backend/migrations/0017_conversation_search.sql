@@ -0,0 +1,11 @@
+-- Migration: Indexes for searching a user's conversations by peer username
+-- The trigram index serves `lower(username) LIKE '%...%'`; the peer_id index serves the
+-- reverse direction of conversation_peers (users who messaged someone).
+
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS users_username_lower_trgm_idx
+    ON users USING gin (lower(username) gin_trgm_ops);
+
+CREATE INDEX IF NOT EXISTS conversation_peers_peer_id_idx
+    ON conversation_peers (peer_id, user_id);
@@ -0,0 +1,9 @@
+-- Migration: Reserve usernames of deleted accounts
+-- A deleted user's username cannot be registered again until its grace period ends,
+-- so contacts of the deleted account do not unknowingly reach someone else.
+
+CREATE TABLE IF NOT EXISTS deleted_usernames (
+    username TEXT PRIMARY KEY,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    grace_period_days INT NOT NULL DEFAULT 30
+);
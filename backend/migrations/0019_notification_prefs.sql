@@ -0,0 +1,8 @@
+-- Migration: Per-user preferences
+-- timezone is an IANA name used to render timestamps for the user; timestamps are stored in UTC
+
+CREATE TABLE IF NOT EXISTS notification_prefs (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,15 @@
+-- Migration: Per-device identity keys (used when MULTI_DEVICE is enabled)
+-- Each device holds its own keypair; a message may target one device of its receiver
+
+CREATE TABLE IF NOT EXISTS devices (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    public_key TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_active TIMESTAMPTZ,
+    UNIQUE (user_id, public_key)
+);
+
+-- The ciphertext copy of a revoked device is useless to the others, so it goes with the device
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS device_id UUID REFERENCES devices(id) ON DELETE CASCADE;
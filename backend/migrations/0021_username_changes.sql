@@ -0,0 +1,12 @@
+-- Migration: History of username changes, used to limit how often a user can rename
+-- At most 2 changes are allowed per rolling 30 days (enforced by the application)
+
+CREATE TABLE IF NOT EXISTS username_changes (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    old_username TEXT NOT NULL,
+    new_username TEXT NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS username_changes_user_changed_at_idx ON username_changes (user_id, changed_at);
@@ -0,0 +1,10 @@
+-- Migration: Per-user send counters for fixed minute, hour and day windows
+-- Each row counts the messages a user sent in one window; rows older than the current day are pruned on send
+
+CREATE TABLE IF NOT EXISTS send_counters (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    window_name TEXT NOT NULL,
+    window_start TIMESTAMPTZ NOT NULL,
+    count INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, window_name, window_start)
+);
@@ -0,0 +1,4 @@
+-- Migration: When each user's last WebSocket connection closed
+-- NULL until the user disconnects for the first time
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+-- Migration: Optional note the requester attaches to a contact request
+
+ALTER TABLE contact_requests ADD COLUMN IF NOT EXISTS message TEXT;
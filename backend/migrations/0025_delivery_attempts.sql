@@ -0,0 +1,14 @@
+-- Migration: Recent delivery attempts per message, for debugging messages that never arrive
+-- channel is ws or redis; outcome is delivered, published, offline or failed.
+-- Only the newest attempts of each message are kept (enforced by the application)
+
+CREATE TABLE IF NOT EXISTS delivery_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    channel TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS delivery_attempts_message_attempt_at_idx ON delivery_attempts (message_id, attempt_at);
@@ -0,0 +1,24 @@
+-- Migration: Messages with sealed metadata (FEATURE_SEALED_SENDER)
+-- The sender and exact time of a sealed message are only inside encrypted_metadata, which
+-- the receiver decrypts. The server keeps a pseudonymous routing_id per sender, receiver
+-- and UTC day, and message_routing maps it to the receiver so the message can be delivered.
+
+CREATE TABLE IF NOT EXISTS message_routing (
+    routing_id TEXT PRIMARY KEY,
+    actual_receiver_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS message_routing_actual_receiver_id_idx ON message_routing (actual_receiver_id);
+
+CREATE TABLE IF NOT EXISTS sealed_messages (
+    id UUID PRIMARY KEY,
+    routing_id TEXT NOT NULL REFERENCES message_routing(routing_id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    type TEXT NOT NULL,
+    encrypted_metadata BYTEA NOT NULL,
+    encrypted_content BYTEA NOT NULL,
+    iv BYTEA NOT NULL,
+    encryption_version SMALLINT NOT NULL DEFAULT 1
+);
+
+CREATE INDEX IF NOT EXISTS sealed_messages_routing_id_idx ON sealed_messages (routing_id);
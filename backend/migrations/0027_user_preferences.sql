@@ -0,0 +1,4 @@
+-- Migration: Per-user settings that change how the server treats the user's data
+-- Stored as a JSON object so new settings need no migration; absent keys use their defaults
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS user_preferences JSONB NOT NULL DEFAULT '{}'::jsonb;
@@ -0,0 +1,11 @@
+-- Migration: Per-conversation settings of each participant
+-- keep_read_messages keeps READ messages instead of deleting them; a message is only
+-- deleted if neither participant of its conversation keeps it
+
+CREATE TABLE IF NOT EXISTS conversation_settings (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    peer_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    keep_read_messages BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, peer_id)
+);
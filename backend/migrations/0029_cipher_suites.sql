@@ -0,0 +1,6 @@
+-- Migration: Per-message cipher suite and the ciphers each device can decrypt
+-- Existing messages and devices predate the choice and use AES-256-GCM
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS cipher_suite TEXT NOT NULL DEFAULT 'AES_256_GCM';
+
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS supported_ciphers TEXT[] NOT NULL DEFAULT ARRAY['AES_256_GCM'];
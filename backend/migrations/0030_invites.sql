@@ -0,0 +1,15 @@
+-- Migration: Single-use invite codes for REGISTRATION_MODE=invite
+-- An invite is spent when used_at is set; registration sets it in the same transaction
+-- that creates the user.
+
+CREATE TABLE IF NOT EXISTS invites (
+    id UUID PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    note TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    used_by UUID REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS invites_unused_idx ON invites (expires_at) WHERE used_at IS NULL;
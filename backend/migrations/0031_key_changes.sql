@@ -0,0 +1,14 @@
+-- Migration: History of public key changes
+-- format_only marks a rewrite of the same key in another encoding, e.g. a raw 32-byte key
+-- stored again as X.509 by POST /admin/keys/normalize; such a change is not a new key.
+
+CREATE TABLE IF NOT EXISTS key_changes (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    old_public_key TEXT NOT NULL,
+    new_public_key TEXT NOT NULL,
+    format_only BOOLEAN NOT NULL DEFAULT FALSE,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS key_changes_user_changed_at_idx ON key_changes (user_id, changed_at);
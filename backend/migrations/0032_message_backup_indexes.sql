@@ -0,0 +1,9 @@
+-- Migration: Indexes for GET /messages/backup
+-- A backup walks everything a user sent, and everything they received, in (timestamp, id)
+-- order across all of their conversations.
+
+CREATE INDEX IF NOT EXISTS messages_sender_timestamp_id_idx
+    ON messages (sender_id, timestamp, id);
+
+CREATE INDEX IF NOT EXISTS messages_receiver_timestamp_id_idx
+    ON messages (receiver_id, timestamp, id);
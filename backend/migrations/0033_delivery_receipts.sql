@@ -0,0 +1,17 @@
+-- Migration: Delivery receipts signed by the receiver of a message
+-- One receipt per message; a READ receipt replaces the DELIVERED one. Receipts outlive the
+-- message itself, which is deleted once read, so they do not reference messages.
+-- status and message_timestamp are part of the signed data.
+
+CREATE TABLE IF NOT EXISTS delivery_receipts (
+    message_id UUID PRIMARY KEY,
+    sender_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    receiver_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    status TEXT NOT NULL,
+    message_timestamp BIGINT NOT NULL,
+    signature TEXT NOT NULL,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS delivery_receipts_sender_id_idx ON delivery_receipts (sender_id);
+CREATE INDEX IF NOT EXISTS delivery_receipts_receiver_id_idx ON delivery_receipts (receiver_id);
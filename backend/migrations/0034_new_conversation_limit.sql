@@ -0,0 +1,13 @@
+-- Migration: Daily limit on conversations a user opens
+-- opened_at is set on the conversation_peers row written by the first message of a
+-- conversation neither side had before; replies and rows from before this migration leave
+-- it NULL. The partial index keeps counting a sender's recent openings cheap.
+-- new_conversation_limit_exempt is set by an admin for accounts that legitimately open many
+-- conversations, such as support accounts.
+
+ALTER TABLE conversation_peers ADD COLUMN IF NOT EXISTS opened_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS conversation_peers_opened_at_idx
+    ON conversation_peers (user_id, opened_at) WHERE opened_at IS NOT NULL;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS new_conversation_limit_exempt BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+-- Migration: Replies quoting an earlier message
+-- reply_to_id has no foreign key, like forwarded_from_id: messages are deleted once read,
+-- and the reply keeps pointing at the quoted message so clients can show it as deleted.
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS reply_to_id UUID;
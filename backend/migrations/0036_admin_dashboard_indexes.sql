@@ -0,0 +1,7 @@
+-- Migration: Indexes for the admin dashboard
+-- The dashboard sums the send counters of one window across all users and counts recent
+-- registrations; without these both scan their whole table every time the page refreshes.
+
+CREATE INDEX IF NOT EXISTS send_counters_window_idx ON send_counters (window_name, window_start);
+
+CREATE INDEX IF NOT EXISTS users_created_at_idx ON users (created_at);
@@ -0,0 +1,6 @@
+-- Migration: Record which admin performed an audited action
+-- admin_username is the ADMIN_USERNAME the request authenticated with; for requests made
+-- with an impersonation token, the admin who started the session. Rows from before this
+-- migration leave it NULL.
+
+ALTER TABLE admin_audit_log ADD COLUMN IF NOT EXISTS admin_username TEXT;
@@ -0,0 +1,64 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The base URL is not an absolute `http` or `https` URL.
+    InvalidUrl(String),
+    /// The call needs a token: register or log in first, or use `Client::with_token`.
+    NotAuthenticated,
+    Http(reqwest::Error),
+    /// The server answered with an error status; `body` is its response as text.
+    Api {
+        status: u16,
+        body: String,
+    },
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    /// A response or frame did not have the expected shape.
+    Decode(serde_json::Error),
+    /// The server closed the WebSocket connection, with its close reason if it gave one.
+    Closed(Option<String>),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(url) => write!(f, "invalid server URL {:?}", url),
+            ClientError::NotAuthenticated => f.write_str("not authenticated"),
+            ClientError::Http(e) => write!(f, "request failed: {}", e),
+            ClientError::Api { status, body } => write!(f, "server returned {}: {}", status, body),
+            ClientError::WebSocket(e) => write!(f, "WebSocket error: {}", e),
+            ClientError::Decode(e) => write!(f, "unexpected response: {}", e),
+            ClientError::Closed(Some(reason)) => write!(f, "connection closed: {}", reason),
+            ClientError::Closed(None) => f.write_str("connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            ClientError::WebSocket(e) => Some(e.as_ref()),
+            ClientError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for ClientError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        ClientError::WebSocket(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        ClientError::Decode(e)
+    }
+}
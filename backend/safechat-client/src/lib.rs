@@ -0,0 +1,263 @@
+//! Client library for the Safe Chat API
+//!
+//! `Client` wraps the JSON endpoints and `Connection` the `/ws` WebSocket. Requests and
+//! responses are the `safechat-types` structs the server itself serializes, re-exported as
+//! `safechat_client::types`. Messages are sent and their status updated over the
+//! WebSocket, so `send_message` and `update_status` live on `Connection`.
+//!
+//! ```no_run
+//! use futures_util::StreamExt;
+//! use safechat_client::{Client, ServerEvent, new_message};
+//! use safechat_client::types::messages::MessageStatus;
+//!
+//! # async fn flow() -> Result<(), safechat_client::ClientError> {
+//! let mut alice = Client::new("http://localhost:8080")?;
+//! alice.register("alice", "correct horse battery", None).await?;
+//! let mut bob = Client::new("http://localhost:8080")?;
+//! let bob_id = bob.register("bob", "correct horse battery", None).await?.id.parse().unwrap();
+//!
+//! let alice_ws = alice.connect_ws().await?;
+//! let mut bob_ws = bob.connect_ws().await?;
+//! let message = new_message(bob_id, "Text", b"ciphertext", &[0; 12]);
+//! alice_ws.send_message(&message).await?;
+//! while let Some(event) = bob_ws.next().await {
+//!     if let ServerEvent::NewMessage(received) = event? {
+//!         bob_ws.update_status(received.id.parse().unwrap(), MessageStatus::Read).await?;
+//!         break;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod error;
+mod ws;
+
+pub use error::ClientError;
+pub use safechat_types as types;
+pub use ws::{Connection, ServerEvent, new_message};
+
+use reqwest::{RequestBuilder, Response, Url};
+use safechat_types::auth::{AuthResponse, LoginRequest, RegisterRequest, UserProfile};
+use safechat_types::messages::MessageResponse;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    token: Option<String>,
+}
+
+impl Client {
+    /// A client for the server at `base_url`, e.g. `http://localhost:8080`.
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        let base_url = Url::parse(base_url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .ok_or_else(|| ClientError::InvalidUrl(base_url.to_string()))?;
+        Ok(Client {
+            http: reqwest::Client::new(),
+            base_url,
+            token: None,
+        })
+    }
+
+    /// Uses `token` for authenticated calls instead of registering or logging in.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// The token of the registered or logged in user.
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// Creates an account and keeps its token for later calls. `invite_code` is needed
+    /// when the server runs with `REGISTRATION_MODE=invite`.
+    pub async fn register(
+        &mut self,
+        username: &str,
+        password: &str,
+        invite_code: Option<&str>,
+    ) -> Result<AuthResponse, ClientError> {
+        let request = RegisterRequest {
+            username: username.to_string(),
+            password: password.to_string(),
+            invite_code: invite_code.map(str::to_string),
+        };
+        let auth: AuthResponse =
+            parse(self.http.post(self.url("auth/register")?).json(&request)).await?;
+        self.token = Some(auth.token.clone());
+        Ok(auth)
+    }
+
+    /// Logs in and keeps the token for later calls.
+    pub async fn login(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<AuthResponse, ClientError> {
+        let request = LoginRequest {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        let auth: AuthResponse =
+            parse(self.http.post(self.url("auth/login")?).json(&request)).await?;
+        self.token = Some(auth.token.clone());
+        Ok(auth)
+    }
+
+    pub async fn profile(&self) -> Result<UserProfile, ClientError> {
+        parse(self.authorized(self.http.get(self.url("profile")?))?).await
+    }
+
+    /// The stored messages between this user and `user_id`, oldest first. Messages
+    /// addressed to this user are marked DELIVERED by fetching them.
+    pub async fn fetch_messages(&self, user_id: Uuid) -> Result<Vec<MessageResponse>, ClientError> {
+        let url = self.url(&format!("messages/{}", user_id))?;
+        parse(self.authorized(self.http.get(url))?).await
+    }
+
+    /// Opens `/ws` as this user. Returns once the server has answered `hello`, so events
+    /// sent to the user afterwards reach the connection. A rejected token fails with
+    /// `ClientError::Closed` naming `token_expired` or `invalid_token`.
+    pub async fn connect_ws(&self) -> Result<Connection, ClientError> {
+        let token = self.token.as_deref().ok_or(ClientError::NotAuthenticated)?;
+        Connection::connect(ws_url(&self.base_url, token)?).await
+    }
+
+    fn url(&self, path: &str) -> Result<Url, ClientError> {
+        self.base_url
+            .join(path)
+            .map_err(|_| ClientError::InvalidUrl(format!("{}{}", self.base_url, path)))
+    }
+
+    fn authorized(&self, request: RequestBuilder) -> Result<RequestBuilder, ClientError> {
+        let token = self.token.as_deref().ok_or(ClientError::NotAuthenticated)?;
+        Ok(request.bearer_auth(token))
+    }
+}
+
+/// Sends `request` and decodes a success body as `T`.
+async fn parse<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, ClientError> {
+    let response = check(request.send().await?).await?;
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+async fn check(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    Err(ClientError::Api {
+        status: status.as_u16(),
+        body: response.text().await.unwrap_or_default(),
+    })
+}
+
+/// `/ws` on the same host as `base_url`, over `wss` when the API is served over `https`.
+fn ws_url(base_url: &Url, token: &str) -> Result<Url, ClientError> {
+    let mut url = base_url
+        .join("ws")
+        .map_err(|_| ClientError::InvalidUrl(base_url.to_string()))?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    url.set_scheme(scheme)
+        .map_err(|_| ClientError::InvalidUrl(base_url.to_string()))?;
+    url.query_pairs_mut().append_pair("token", token);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url_must_be_http() {
+        assert!(Client::new("http://localhost:8080").is_ok());
+        assert!(matches!(
+            Client::new("ws://localhost:8080"),
+            Err(ClientError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            Client::new("localhost:8080"),
+            Err(ClientError::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn test_ws_url_follows_the_api_scheme() {
+        let plain = Url::parse("http://localhost:8080").unwrap();
+        assert_eq!(
+            ws_url(&plain, "a.b+c").unwrap().as_str(),
+            "ws://localhost:8080/ws?token=a.b%2Bc"
+        );
+        let tls = Url::parse("https://chat.example.com/").unwrap();
+        assert_eq!(
+            ws_url(&tls, "t").unwrap().as_str(),
+            "wss://chat.example.com/ws?token=t"
+        );
+    }
+
+    /// Runs the whole message flow against a server started with `cargo run` in `backend/`.
+    #[tokio::test]
+    #[ignore = "needs SAFECHAT_URL pointing to a running server with open registration"]
+    async fn test_message_flow_against_server() {
+        use futures_util::StreamExt;
+        use safechat_types::messages::MessageStatus;
+
+        let url = std::env::var("SAFECHAT_URL").expect("SAFECHAT_URL must be set");
+        let suffix = Uuid::new_v4().simple().to_string();
+        let password = "client-test-password";
+        let mut alice = Client::new(&url).unwrap();
+        let alice_id: Uuid = alice
+            .register(&format!("alice{}", &suffix[..8]), password, None)
+            .await
+            .unwrap()
+            .id
+            .parse()
+            .unwrap();
+        let mut bob = Client::new(&url).unwrap();
+        let bob_name = format!("bob{}", &suffix[..8]);
+        bob.register(&bob_name, password, None).await.unwrap();
+        let bob_id: Uuid = bob
+            .login(&bob_name, password)
+            .await
+            .unwrap()
+            .id
+            .parse()
+            .unwrap();
+        assert_eq!(bob.profile().await.unwrap().username, bob_name);
+
+        let mut alice_ws = alice.connect_ws().await.unwrap();
+        let mut bob_ws = bob.connect_ws().await.unwrap();
+        let message = new_message(bob_id, "Text", b"ciphertext", &[0; 12]);
+        alice_ws.send_message(&message).await.unwrap();
+
+        let received = loop {
+            if let ServerEvent::NewMessage(received) = bob_ws.next().await.unwrap().unwrap() {
+                break received;
+            }
+        };
+        assert_eq!(received.id, message.message_id);
+        assert_eq!(received.sender_id, alice_id.to_string());
+        let stored = bob.fetch_messages(alice_id).await.unwrap();
+        assert!(stored.iter().any(|m| m.id == message.message_id));
+
+        let message_id: Uuid = message.message_id.parse().unwrap();
+        bob_ws
+            .update_status(message_id, MessageStatus::Read)
+            .await
+            .unwrap();
+        loop {
+            if let ServerEvent::StatusUpdate(update) = alice_ws.next().await.unwrap().unwrap()
+                && update.status == MessageStatus::Read
+            {
+                assert_eq!(update.message_id, message.message_id);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! The `/ws` connection of `Client::connect_ws`.
+
+use crate::ClientError;
+
+use base64::Engine;
+use base64::engine::general_purpose;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, Stream, StreamExt};
+use reqwest::Url;
+use safechat_types::messages::MessageStatus;
+use safechat_types::ws::{
+    ClientAckData, DEFAULT_CIPHER_SUITE, DEFAULT_ENCRYPTION_VERSION, ErrorNotification, HelloData,
+    MessageNotification, SendMessageData, StatusUpdate, UpdateStatusData, WebSocketMessage,
+};
+use serde::Serialize;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use uuid::Uuid;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type Sink = Arc<Mutex<SplitSink<Socket, Message>>>;
+
+/// An event from the server. Events without a variant of their own arrive as `Other`;
+/// `GET /ws/schema` describes their `data`.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    NewMessage(MessageNotification),
+    StatusUpdate(StatusUpdate),
+    Error(ErrorNotification),
+    Other(WebSocketMessage),
+}
+
+impl ServerEvent {
+    pub fn from_frame(frame: WebSocketMessage) -> Result<Self, serde_json::Error> {
+        Ok(match frame.message_type.as_str() {
+            "new_message" => ServerEvent::NewMessage(serde_json::from_value(frame.data)?),
+            "status_update" => ServerEvent::StatusUpdate(serde_json::from_value(frame.data)?),
+            "error" => ServerEvent::Error(serde_json::from_value(frame.data)?),
+            _ => ServerEvent::Other(frame),
+        })
+    }
+}
+
+/// A message for `receiver_id` with a new id, encrypted with the default version and
+/// cipher suite. `encrypted_content` and `iv` are the raw bytes; they are base64-encoded
+/// here.
+pub fn new_message(
+    receiver_id: Uuid,
+    message_type: &str,
+    encrypted_content: &[u8],
+    iv: &[u8],
+) -> SendMessageData {
+    SendMessageData {
+        message_id: Uuid::new_v4().to_string(),
+        receiver_id: receiver_id.to_string(),
+        r#type: message_type.to_string(),
+        encrypted_content: general_purpose::STANDARD.encode(encrypted_content),
+        iv: general_purpose::STANDARD.encode(iv),
+        forwarded_from_id: None,
+        reply_to: None,
+        encryption_version: DEFAULT_ENCRYPTION_VERSION,
+        cipher_suite: DEFAULT_CIPHER_SUITE.to_string(),
+        device_id: None,
+        sealed_metadata: false,
+        encrypted_metadata: None,
+    }
+}
+
+/// An open `/ws` connection. It is a `Stream` of the server's events; events that ask
+/// for an acknowledgement are acknowledged before they are yielded. The stream ends
+/// after yielding the error that ended the connection, `ClientError::Closed` when the
+/// server closed it.
+pub struct Connection {
+    sink: Sink,
+    events: mpsc::UnboundedReceiver<Result<ServerEvent, ClientError>>,
+    reader: JoinHandle<()>,
+}
+
+impl Connection {
+    pub(crate) async fn connect(url: Url) -> Result<Self, ClientError> {
+        let (socket, _) = connect_async(url).await?;
+        let (sink, mut stream) = socket.split();
+        let sink = Arc::new(Mutex::new(sink));
+        send_frame(
+            &sink,
+            "hello",
+            serde_json::to_value(HelloData { acks: true })?,
+        )
+        .await?;
+        // Nothing is addressed to the connection before the server answers `hello`
+        loop {
+            let frame = next_frame(&mut stream, &sink).await?;
+            if frame.message_type == "hello_ack" {
+                break;
+            }
+        }
+
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_events(stream, sink.clone(), events_tx));
+        Ok(Connection {
+            sink,
+            events,
+            reader,
+        })
+    }
+
+    /// Sends a frame of any `message_type` the server accepts.
+    pub async fn send(&self, message_type: &str, data: impl Serialize) -> Result<(), ClientError> {
+        send_frame(&self.sink, message_type, serde_json::to_value(data)?).await
+    }
+
+    /// Sends a message; the server answers with a SENT `StatusUpdate`, or an `Error`
+    /// naming `message.message_id`.
+    pub async fn send_message(&self, message: &SendMessageData) -> Result<(), ClientError> {
+        self.send("send_message", message).await
+    }
+
+    /// Marks a message this user received as DELIVERED or READ.
+    pub async fn update_status(
+        &self,
+        message_id: Uuid,
+        status: MessageStatus,
+    ) -> Result<(), ClientError> {
+        let update = UpdateStatusData {
+            message_id: message_id.to_string(),
+            status: status.to_string(),
+        };
+        self.send("update_status", update).await
+    }
+
+    pub async fn close(self) -> Result<(), ClientError> {
+        self.sink.lock().await.close().await?;
+        Ok(())
+    }
+}
+
+impl Stream for Connection {
+    type Item = Result<ServerEvent, ClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().events.poll_recv(cx)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn send_frame(
+    sink: &Sink,
+    message_type: &str,
+    data: serde_json::Value,
+) -> Result<(), ClientError> {
+    let frame = WebSocketMessage {
+        message_type: message_type.to_string(),
+        data,
+        ack_id: None,
+    };
+    let text = serde_json::to_string(&frame)?;
+    sink.lock().await.send(Message::Text(text)).await?;
+    Ok(())
+}
+
+/// Reads the next frame, acknowledging it if the server asks for that.
+async fn next_frame(
+    stream: &mut SplitStream<Socket>,
+    sink: &Sink,
+) -> Result<WebSocketMessage, ClientError> {
+    while let Some(message) = stream.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Close(close) => {
+                return Err(ClientError::Closed(
+                    close.map(|frame| frame.reason.into_owned()),
+                ));
+            }
+            _ => continue,
+        };
+        let frame: WebSocketMessage = serde_json::from_str(&text)?;
+        if let Some(ack_id) = frame.ack_id {
+            send_frame(sink, "ack", serde_json::to_value(ClientAckData { ack_id })?).await?;
+        }
+        return Ok(frame);
+    }
+    Err(ClientError::Closed(None))
+}
+
+async fn read_events(
+    mut stream: SplitStream<Socket>,
+    sink: Sink,
+    events: mpsc::UnboundedSender<Result<ServerEvent, ClientError>>,
+) {
+    loop {
+        let event = match next_frame(&mut stream, &sink).await {
+            Ok(frame) => ServerEvent::from_frame(frame).map_err(ClientError::from),
+            Err(e) => {
+                let _ = events.send(Err(e));
+                return;
+            }
+        };
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_typed_by_message_type() {
+        let frame = |message_type: &str, data: serde_json::Value| WebSocketMessage {
+            message_type: message_type.to_string(),
+            data,
+            ack_id: None,
+        };
+        let update = frame(
+            "status_update",
+            serde_json::json!({ "message_id": "m", "status": "READ", "updated_by": "u" }),
+        );
+        assert!(matches!(
+            ServerEvent::from_frame(update),
+            Ok(ServerEvent::StatusUpdate(StatusUpdate {
+                status: MessageStatus::Read,
+                ..
+            }))
+        ));
+        let presence = frame("user_online", serde_json::json!({ "user_id": "u" }));
+        assert!(matches!(
+            ServerEvent::from_frame(presence),
+            Ok(ServerEvent::Other(_))
+        ));
+        // A known event whose data does not match its type is an error, not `Other`
+        let malformed = frame("error", serde_json::json!({ "code": "no_such_code" }));
+        assert!(ServerEvent::from_frame(malformed).is_err());
+    }
+
+    #[test]
+    fn test_new_message_encodes_content() {
+        let receiver = Uuid::new_v4();
+        let message = new_message(receiver, "Text", &[1, 2, 3], &[0; 12]);
+        assert_eq!(message.receiver_id, receiver.to_string());
+        assert_eq!(message.encrypted_content, "AQID");
+        assert_eq!(message.iv, "AAAAAAAAAAAAAAAA");
+        assert!(message.message_id.parse::<Uuid>().is_ok());
+    }
+}
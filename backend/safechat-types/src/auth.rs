@@ -0,0 +1,49 @@
+//! Bodies of `/auth/register`, `/auth/login` and `GET /profile`.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub password: String,
+    /// Required when `REGISTRATION_MODE` is `invite`; ignored otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// Success body of `register` and `login`: the token plus the basic profile, so clients
+/// can populate their state without calling `/profile`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthResponse {
+    pub token: String,
+    pub id: String,
+    pub username: String,
+    pub public_key: String,
+    pub avatar: Option<String>, // base64-encoded
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub id: String,
+    pub username: String,
+    pub public_key: String,
+    pub created_at: String,
+    pub avatar: Option<String>,
+    pub key_version: i32,
+    pub preferences: UserPreferences,
+}
+
+/// Settings stored in `users.user_preferences`. Keys missing from the stored object take
+/// their default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserPreferences {
+    /// Delete the user's sent messages once they are DELIVERED instead of once READ.
+    pub delete_on_delivered: bool,
+}
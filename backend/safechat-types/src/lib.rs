@@ -0,0 +1,11 @@
+//! Wire types of the Safe Chat API
+//!
+//! The backend serializes these structs into its JSON responses and WebSocket frames, and
+//! `safechat-client` deserializes them, so a field renamed on one side is renamed on both.
+//! - `auth`: registration, login and the user's profile.
+//! - `messages`: stored messages as returned by `GET /messages/{user_id}`.
+//! - `ws`: frames on `/ws`, in both directions.
+
+pub mod auth;
+pub mod messages;
+pub mod ws;
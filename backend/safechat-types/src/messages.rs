@@ -0,0 +1,145 @@
+//! Stored messages and their delivery status.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Delivery status of a message, stored and sent over the wire in upper case.
+///
+/// A message is PENDING until its first delivery attempt, then SENT; the receiver moves
+/// it to DELIVERED and READ. FAILED is reported by a client before delivery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum MessageStatus {
+    #[default]
+    Pending,
+    Sent,
+    Delivered,
+    Read,
+    Failed,
+}
+
+impl MessageStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageStatus::Pending => "PENDING",
+            MessageStatus::Sent => "SENT",
+            MessageStatus::Delivered => "DELIVERED",
+            MessageStatus::Read => "READ",
+            MessageStatus::Failed => "FAILED",
+        }
+    }
+}
+
+impl fmt::Display for MessageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Returned when parsing a string that is not one of the `MessageStatus` values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownMessageStatus(pub String);
+
+impl fmt::Display for UnknownMessageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown message status {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownMessageStatus {}
+
+impl FromStr for MessageStatus {
+    type Err = UnknownMessageStatus;
+
+    /// Parses the upper-case form used in the database and on the wire.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PENDING" => Ok(MessageStatus::Pending),
+            "SENT" => Ok(MessageStatus::Sent),
+            "DELIVERED" => Ok(MessageStatus::Delivered),
+            "READ" => Ok(MessageStatus::Read),
+            "FAILED" => Ok(MessageStatus::Failed),
+            _ => Err(UnknownMessageStatus(s.to_string())),
+        }
+    }
+}
+
+// `messages.status` is TEXT; values outside `MessageStatus` fail to decode.
+#[cfg(feature = "sqlx")]
+mod postgres {
+    use super::MessageStatus;
+    use sqlx::encode::IsNull;
+    use sqlx::error::BoxDynError;
+    use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+    use sqlx::{Decode, Encode, Postgres, Type};
+
+    impl Type<Postgres> for MessageStatus {
+        fn type_info() -> PgTypeInfo {
+            <&str as Type<Postgres>>::type_info()
+        }
+
+        fn compatible(ty: &PgTypeInfo) -> bool {
+            <&str as Type<Postgres>>::compatible(ty)
+        }
+    }
+
+    impl<'r> Decode<'r, Postgres> for MessageStatus {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            Ok(<&str as Decode<Postgres>>::decode(value)?.parse()?)
+        }
+    }
+
+    impl Encode<'_, Postgres> for MessageStatus {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+            <&str as Encode<Postgres>>::encode(self.as_str(), buf)
+        }
+    }
+}
+
+/// One message of `GET /messages/{user_id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageResponse {
+    pub id: String,
+    /// Unix milliseconds, as a string.
+    pub timestamp: String,
+    /// `timestamp` as RFC 3339 in the requesting user's timezone.
+    pub sent_at: String,
+    pub sender_id: String,
+    pub receiver_id: String,
+    pub status: MessageStatus,
+    pub r#type: String,
+    pub encrypted_content: String,
+    pub iv: String,
+    /// Capped at 5; see `forwarded_many_times`.
+    pub forward_count: i32,
+    pub forwarded_many_times: bool,
+    pub was_forwarded: bool,
+    /// Set when the message replies to another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyReference>,
+    pub encryption_version: i16,
+    /// The cipher to decrypt `encrypted_content` with.
+    pub cipher_suite: String,
+    /// The receiver's device this copy was encrypted for, if it targets one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+}
+
+/// The message a reply quotes. Its content is not repeated; clients look it up in the
+/// conversation they already have.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ReplyReference {
+    pub message_id: String,
+    /// The quoted message no longer exists, e.g. because it was deleted once read; clients
+    /// show a placeholder instead of its content.
+    pub deleted: bool,
+}
+
+/// A page of `GET /messages/{user_id}`; pass `next_cursor` back as `cursor` for the next one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessagePageResponse {
+    pub items: Vec<MessageResponse>,
+    pub next_cursor: Option<String>,
+}
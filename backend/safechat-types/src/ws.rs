@@ -0,0 +1,192 @@
+//! Frames on `/ws`. Every frame is a `WebSocketMessage`; its `data` is one of the structs
+//! below depending on `message_type`. `GET /ws/schema` describes all of them.
+
+use crate::messages::{MessageStatus, ReplyReference};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The `encryption_version` of clients that predate versioning.
+pub const DEFAULT_ENCRYPTION_VERSION: i16 = 1;
+/// The cipher of clients that predate `cipher_suite`.
+pub const DEFAULT_CIPHER_SUITE: &str = "AES_256_GCM";
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WebSocketMessage {
+    pub message_type: String,
+    pub data: serde_json::Value,
+    /// Set on server events the client must acknowledge with an `ack` carrying the same id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClientAckData {
+    pub ack_id: u64,
+}
+
+/// Data of a client `hello`: the optional protocol features the client supports.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct HelloData {
+    /// The client answers `ack_id`s with `ack`; without it events carry no `ack_id` and
+    /// are never resent.
+    #[serde(default)]
+    pub acks: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SendMessageData {
+    pub message_id: String,
+    pub receiver_id: String,
+    pub r#type: String,
+    pub encrypted_content: String,
+    pub iv: String,
+    /// Set when the client forwards an existing message to a new receiver.
+    #[serde(default)]
+    pub forwarded_from_id: Option<String>,
+    /// The message this one replies to; it must belong to the same conversation.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Encryption scheme of `encrypted_content`; clients that predate versioning omit it.
+    #[serde(default = "default_encryption_version")]
+    pub encryption_version: i16,
+    /// Symmetric cipher of `encrypted_content`; one of the server's `cipher_suites`.
+    #[serde(default = "default_cipher_suite")]
+    pub cipher_suite: String,
+    /// The receiver's device this copy was encrypted for, when `MULTI_DEVICE` is enabled.
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// Seal the sender and timestamp into `encrypted_metadata` instead of sending them in
+    /// the clear. Needs `FEATURE_SEALED_SENDER`.
+    #[serde(default)]
+    pub sealed_metadata: bool,
+    /// Base64 of `{"sender_id": ..., "timestamp": ...}` encrypted for the receiver's key.
+    #[serde(default)]
+    pub encrypted_metadata: Option<String>,
+}
+
+fn default_encryption_version() -> i16 {
+    DEFAULT_ENCRYPTION_VERSION
+}
+
+fn default_cipher_suite() -> String {
+    DEFAULT_CIPHER_SUITE.to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UpdateStatusData {
+    pub message_id: String,
+    pub status: String,
+}
+
+/// Data of `signed_receipt`: a DELIVERED or READ status update the receiver signed, so the
+/// sender can tell it was not made up by the server.
+///
+/// `recipient_signature` is the base64 Ed25519 signature of the UTF-8 concatenation of the
+/// message id, the status and the message's `timestamp` (Unix milliseconds), e.g.
+/// `"0b0f…e4d2READ1718000000000"`. The server stores it without checking it, since it does
+/// not know the receiver's signing key.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SignedReceiptData {
+    pub message_id: String,
+    pub status: String,
+    pub recipient_signature: String,
+}
+
+/// A receipt signed by the receiver of a message; sent to the sender as `delivery_receipt`
+/// and returned by `GET /messages/{id}/receipt`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DeliveryReceipt {
+    pub message_id: String,
+    pub status: MessageStatus,
+    /// The message's timestamp in Unix milliseconds, as a string; part of the signed data.
+    pub timestamp: String,
+    /// The receiver, whose key made `recipient_signature`.
+    pub signed_by: String,
+    pub recipient_signature: String,
+    /// When the server stored the receipt.
+    pub received_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessageNotification {
+    pub id: String,
+    pub timestamp: String,
+    pub sender_id: String,
+    pub receiver_id: String,
+    pub status: MessageStatus,
+    pub r#type: String,
+    pub encrypted_content: String,
+    pub iv: String,
+    pub was_forwarded: bool,
+    /// Set when the message replies to another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyReference>,
+    pub encryption_version: i16,
+    /// The cipher to decrypt `encrypted_content` with.
+    pub cipher_suite: String,
+    /// Set when this copy is only for one of the receiver's devices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct StatusUpdate {
+    pub message_id: String,
+    pub status: MessageStatus,
+    pub updated_by: String,
+}
+
+/// Machine-readable reason in an `error` event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame exceeded `WS_MAX_FRAME_BYTES`; the connection is closed.
+    FrameTooLarge,
+    /// The frame nested deeper than the server accepts; the connection is closed.
+    FrameTooDeep,
+    /// The message was addressed to its sender and `SELF_MESSAGES_ENABLED` is off.
+    SelfMessageDisabled,
+    /// `encryption_version` is not one the server supports; see `hello_ack`.
+    UnsupportedEncryptionVersion,
+    /// `cipher_suite` is not one the server accepts; see `GET /server-info`.
+    UnsupportedCipherSuite,
+    /// `type` is not one of the allowed message types.
+    InvalidMessageType,
+    /// `device_id` was set but the server runs without `MULTI_DEVICE`.
+    MultiDeviceDisabled,
+    /// `device_id` is not one of the receiver's devices.
+    UnknownDevice,
+    /// The receiver only accepts messages from their contacts.
+    NotAContact,
+    /// A send or probe limit was reached; retry after `retry_after` seconds.
+    RateLimited,
+    /// The message would start a conversation, and the sender already started as many as
+    /// they may in a day; retry after `retry_after` seconds. Messages in existing
+    /// conversations are still accepted.
+    NewConversationLimit,
+    /// `sealed_metadata` was set but the server runs without `FEATURE_SEALED_SENDER`.
+    SealedMetadataDisabled,
+    /// Sealed messages cannot be forwarded, reply to a message, be addressed to one device or
+    /// use another cipher suite.
+    SealedMetadataUnsupported,
+    /// `reply_to` is a message of another conversation.
+    InvalidReplyTarget,
+    /// Sealed messages can only be sent in an existing conversation, or to a receiver who
+    /// added the sender as a contact; the first message must be sent unsealed.
+    SealedNewConversation,
+    /// An id is not a UUID or a field is not valid base64. Only reported by
+    /// `POST /messages/validate`; `send_message` drops such a frame without an `error`.
+    InvalidField,
+    /// The receiver does not exist. Only reported by `POST /messages/validate`.
+    UnknownReceiver,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ErrorNotification {
+    pub code: ErrorCode,
+    pub message: String,
+    pub message_id: Option<String>,
+    /// Seconds until a `rate_limited` or `new_conversation_limit` request may be retried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
@@ -0,0 +1,726 @@
+//! Admin access control for Safe Chat backend
+//!
+//! The `/admin/*` routes need the HTTP Basic credentials set with `ADMIN_USERNAME` and
+//! `ADMIN_PASSWORD`, and are disabled while either is unset. They can further be
+//! restricted to a set of networks with `ADMIN_IP_ALLOWLIST` (a comma-separated list of
+//! CIDRs or bare IPs). An empty list allows every source.
+//! When `TRUST_PROXY_HEADERS` is enabled the client address is the hop the reverse proxy
+//! appended to `X-Forwarded-For` (or its `X-Real-IP`); otherwise the socket peer address
+//! is used, so clients cannot spoof their way in.
+//!
+//! The admin pages are static files under `src/static`, served at `/admin/*` with a
+//! custom 404 page and an `ETag` derived from each file's modification time.
+//!
+//! It also serves backlog metrics: how many messages are SENT but not yet delivered to
+//! a user, and how old the oldest one is. A growing backlog usually means a broken client.
+//! The recent delivery attempts of a single message show where it got stuck.
+//! Per-shard connection counts show whether `WS_CONNECTION_SHARD_COUNT` needs tuning.
+
+use crate::api::etag_matches;
+use crate::clock::Clock;
+use crate::preferences::{DEFAULT_TIMEZONE, format_timestamp};
+use crate::repo::postgres::ROW_DECODE_ERRORS;
+use crate::repo::{BacklogRecord, DeliveryAttemptRecord, MessageRepo, MessageStatus, RepoResult};
+use crate::service::{self, BacklogThresholds, DeliveryState};
+use crate::state::AppState;
+use crate::webhooks::{WebhookDispatcher, WebhookEvent};
+use crate::websocket::ConnectionManagerStats;
+
+use axum::extract::{ConnectInfo, Json, OriginalUri, Path, Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_LENGTH, ETAG, LAST_MODIFIED, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use base64::Engine;
+use base64::engine::general_purpose;
+use chrono::DateTime;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tower_http::services::{ServeDir, ServeFile};
+use tower_http::set_status::SetStatus;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// How long the global backlog listing is served from cache.
+const BACKLOG_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Number of receivers kept in the cached global listing.
+const BACKLOG_LISTING_SIZE: i64 = 100;
+const DEFAULT_BACKLOG_LIMIT: usize = 20;
+
+/// Directory holding the admin pages, relative to the working directory.
+pub const ADMIN_STATIC_DIR: &str = "src/static";
+
+/// Cached global backlog listing and when it was loaded.
+pub type BacklogCache = Arc<Mutex<Option<(Instant, Vec<BacklogRecord>)>>>;
+
+pub fn create_backlog_cache() -> BacklogCache {
+    Arc::new(Mutex::new(None))
+}
+
+#[derive(Serialize)]
+pub struct BacklogResponse {
+    pub user_id: String,
+    pub count: i64,
+    /// Age of the oldest undelivered message in seconds, 0 when the backlog is empty.
+    pub oldest_age_secs: i64,
+    /// Delivery state of the oldest undelivered message; only in the per-user backlog.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_state: Option<DeliveryState>,
+}
+
+#[derive(Serialize)]
+pub struct DeliveryAttemptResponse {
+    pub attempt_at: String,
+    pub channel: String,
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DeliveryAttemptsResponse {
+    pub message_id: String,
+    pub status: MessageStatus,
+    pub delivery_state: DeliveryState,
+    /// Newest first.
+    pub attempts: Vec<DeliveryAttemptResponse>,
+}
+
+#[derive(Serialize)]
+pub struct MetricsResponse {
+    /// Messages whose timestamp was moved forward to keep their conversation ordered,
+    /// since this instance started. A rising count points at clock drift between instances.
+    pub clock_skew_corrections: u64,
+    /// Database columns that failed to decode, since this instance started. Each one is
+    /// also logged as a `row_decode_error` with the record id.
+    pub row_decode_errors: u64,
+    /// WebSocket connections open on this instance.
+    pub ws_connections: usize,
+    /// Connections closed with `connection_limit` because their user opened too many.
+    pub ws_connections_evicted: u64,
+    /// WebSocket upgrades refused with 503 because the instance was at `WS_MAX_CONNECTIONS`.
+    pub ws_upgrades_refused: u64,
+    /// Messages refused with `new_conversation_limit` because their sender already started
+    /// as many conversations as they may in a day.
+    pub new_conversation_limit_hits: u64,
+    /// Requests answered with `rate_limited` and messages refused over a send or probe
+    /// limit, since this instance started.
+    pub rate_limit_hits: u64,
+    /// Entries in the fullest connection shard over the average per shard; see
+    /// `/admin/connections/shards`.
+    pub dashmap_shard_imbalance_ratio: f64,
+}
+
+#[derive(Deserialize)]
+pub struct BacklogListQuery {
+    pub limit: Option<usize>,
+}
+
+fn backlog_response(backlog: &BacklogRecord, now_millis: i64) -> BacklogResponse {
+    BacklogResponse {
+        user_id: backlog.user_id.to_string(),
+        count: backlog.count,
+        oldest_age_secs: service::backlog_age_secs(backlog, now_millis),
+        delivery_state: None,
+    }
+}
+
+fn delivery_attempt_response(attempt: DeliveryAttemptRecord) -> DeliveryAttemptResponse {
+    DeliveryAttemptResponse {
+        attempt_at: format_timestamp(attempt.attempt_at, DEFAULT_TIMEZONE),
+        channel: attempt.channel,
+        outcome: attempt.outcome,
+        error: attempt.error,
+    }
+}
+
+/// Parses a comma-separated list of CIDRs; bare addresses are treated as single hosts.
+pub fn parse_ip_allowlist(raw: &str) -> Result<Vec<IpNet>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid ADMIN_IP_ALLOWLIST entry: {}", entry))
+        })
+        .collect()
+}
+
+/// Whether `ip` may reach the admin routes. An empty allowlist permits everything.
+pub fn ip_allowed(allowlist: &[IpNet], ip: IpAddr) -> bool {
+    // Treat IPv4-mapped IPv6 peers (dual-stack sockets) as their IPv4 address
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    };
+    allowlist.is_empty() || allowlist.iter().any(|net| net.contains(&ip))
+}
+
+/// Determines the client address, honouring proxy headers only when they are trusted.
+///
+/// Of `X-Forwarded-For`, only the last entry is used: the address the trusted proxy
+/// appended. Entries before it were sent by the client and can be anything.
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr, trust_proxy_headers: bool) -> IpAddr {
+    if !trust_proxy_headers {
+        return peer;
+    }
+    let forwarded = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|v| v.trim().parse::<IpAddr>().ok());
+    let real_ip = || {
+        headers
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<IpAddr>().ok())
+    };
+    forwarded.or_else(real_ip).unwrap_or(peer)
+}
+
+/// The HTTP Basic credentials of the admin routes, from `ADMIN_USERNAME` and `ADMIN_PASSWORD`.
+#[derive(Debug, Clone)]
+pub struct AdminCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl AdminCredentials {
+    /// Whether `headers` carry these credentials in an `Authorization: Basic` header.
+    pub fn accepts(&self, headers: &HeaderMap) -> bool {
+        let Some((username, password)) = basic_credentials(headers) else {
+            return false;
+        };
+        // Both are always compared, so the time taken does not tell which one was wrong
+        let username_matches = digests_equal(&username, &self.username);
+        let password_matches = digests_equal(&password, &self.password);
+        username_matches & password_matches
+    }
+}
+
+/// The username and password of an `Authorization: Basic` header.
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| general_purpose::STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| {
+            decoded
+                .split_once(':')
+                .map(|(username, password)| (username.to_string(), password.to_string()))
+        })
+}
+
+/// The admin a request to the admin routes authenticated as. Only meaningful behind
+/// `require_admin`, which has checked the password.
+pub fn admin_username(headers: &HeaderMap) -> Option<String> {
+    basic_credentials(headers).map(|(username, _)| username)
+}
+
+/// Compares the SHA-256 digests of `a` and `b` in constant time.
+fn digests_equal(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a.as_bytes()), Sha256::digest(b.as_bytes()));
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+/// Middleware for the admin routes. Sources outside `ADMIN_IP_ALLOWLIST` get 403, as does
+/// everyone while no admin credentials are configured; requests without the credentials
+/// get 401 with a Basic challenge, so browsers prompt for them.
+pub async fn require_admin<B>(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = client_ip(request.headers(), peer.ip(), state.trust_proxy_headers);
+    // The admin router is nested, so the request URI no longer has the /admin prefix
+    let path = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.path().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    if !ip_allowed(&state.admin_ip_allowlist, ip) {
+        warn!(
+            "Rejected admin request to {} from disallowed address {}",
+            path, ip
+        );
+        return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+    }
+    let Some(credentials) = &state.admin_credentials else {
+        warn!(
+            "Rejected admin request to {} from {}: no admin credentials configured",
+            path, ip
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            "Admin routes are disabled; set ADMIN_USERNAME and ADMIN_PASSWORD",
+        )
+            .into_response();
+    };
+    if !credentials.accepts(request.headers()) {
+        if request.headers().contains_key(AUTHORIZATION) {
+            warn!(
+                "Rejected admin request to {} from {} with wrong credentials",
+                path, ip
+            );
+        }
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(
+                WWW_AUTHENTICATE,
+                "Basic realm=\"Safe Chat admin\", charset=\"UTF-8\"",
+            )],
+            "Unauthorized",
+        )
+            .into_response();
+    }
+    info!("Admin request to {} from {}", path, ip);
+    next.run(request).await
+}
+
+/// Serves the admin pages, answering unknown paths with `404.html` and a 404 status.
+pub fn admin_static_service() -> ServeDir<SetStatus<ServeFile>> {
+    ServeDir::new(ADMIN_STATIC_DIR)
+        .not_found_service(ServeFile::new(format!("{}/404.html", ADMIN_STATIC_DIR)))
+}
+
+/// Sends `/admin/` to the admin overview page.
+pub async fn redirect_to_admin_index() -> Redirect {
+    Redirect::temporary("/admin/index.html")
+}
+
+/// ETag for a static file: its modification time and size, so it changes whenever the file does.
+fn static_file_etag(last_modified: &str, content_length: &str) -> Option<String> {
+    let modified = DateTime::parse_from_rfc2822(last_modified).ok()?;
+    Some(format!("\"{:x}-{}\"", modified.timestamp(), content_length))
+}
+
+/// Middleware that adds an `ETag` to successful static file responses and answers a
+/// matching `If-None-Match` with `304 Not Modified`. Responses without `Last-Modified`,
+/// such as the JSON endpoints, pass through unchanged.
+pub async fn static_file_etag_layer<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_headers = request.headers().clone();
+    let mut response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    let Some(etag) = header(LAST_MODIFIED)
+        .zip(header(CONTENT_LENGTH))
+        .and_then(|(modified, length)| static_file_etag(modified, length))
+    else {
+        return response;
+    };
+    let Ok(etag_value) = HeaderValue::from_str(&etag) else {
+        return response;
+    };
+    if etag_matches(&request_headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(ETAG, etag_value)]).into_response();
+    }
+    response.headers_mut().insert(ETAG, etag_value);
+    response
+}
+
+/// Returns the global backlog listing, reloading it if the cache is older than a minute.
+async fn cached_backlogs(
+    cache: &BacklogCache,
+    messages: &dyn MessageRepo,
+) -> RepoResult<Vec<BacklogRecord>> {
+    // Holding the lock while loading keeps concurrent requests to a single query
+    let mut cached = cache.lock().await;
+    if let Some((loaded_at, listing)) = cached.as_ref()
+        && loaded_at.elapsed() < BACKLOG_CACHE_TTL
+    {
+        return Ok(listing.clone());
+    }
+    let listing = messages.largest_backlogs(BACKLOG_LISTING_SIZE).await?;
+    *cached = Some((Instant::now(), listing.clone()));
+    Ok(listing)
+}
+
+/// Reports the undelivered backlog for one user.
+///
+/// Returns the number of messages addressed to the user that are still SENT, the age of
+/// the oldest one, and that message's `delivery_state`. Always served fresh from the database.
+pub async fn get_user_backlog(
+    Path(user_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let user_id = match Uuid::parse_str(&user_id) {
+        Ok(uid) => uid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user_id format").into_response(),
+    };
+    let backlog = match state.messages.backlog_for(user_id).await {
+        Ok(backlog) => backlog,
+        Err(err) => {
+            info!("Database error in /admin/users/{{id}}/backlog: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let mut response = backlog_response(&backlog, state.clock.now().timestamp_millis());
+    if let Some(oldest_id) = backlog.oldest_message_id {
+        match state.messages.delivery_attempts(oldest_id).await {
+            Ok(attempts) => response.delivery_state = Some(service::delivery_state(&attempts)),
+            Err(err) => {
+                info!("Database error in /admin/users/{{id}}/backlog: {}", err);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+            }
+        }
+    }
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+/// Lists the recent delivery attempts of a message, newest first, with its condensed
+/// delivery state. Only the newest 20 attempts are kept.
+///
+/// Returns 400 for an invalid message ID and 404 if the message does not exist.
+pub async fn get_message_attempts(
+    Path(message_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let message_id = match Uuid::parse_str(&message_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid message id format").into_response(),
+    };
+    let loaded = match state.messages.find_message(message_id).await {
+        Ok(Some(message)) => state
+            .messages
+            .delivery_attempts(message_id)
+            .await
+            .map(|attempts| (message, attempts)),
+        Ok(None) => return (StatusCode::NOT_FOUND, "Message not found").into_response(),
+        Err(err) => Err(err),
+    };
+    let (message, attempts) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            info!("Database error in /admin/messages/{{id}}/attempts: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let response = DeliveryAttemptsResponse {
+        message_id: message_id.to_string(),
+        status: message.status,
+        delivery_state: service::delivery_state(&attempts),
+        attempts: attempts
+            .into_iter()
+            .map(delivery_attempt_response)
+            .collect(),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+/// Lists the users with the largest undelivered backlogs, largest first.
+///
+/// The listing is cached for a minute; `limit` (default 20, at most 100) trims it.
+pub async fn list_backlogs(
+    Query(query): Query<BacklogListQuery>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_BACKLOG_LIMIT);
+    match cached_backlogs(&state.backlog_cache, state.messages.as_ref()).await {
+        Ok(listing) => {
+            let now = state.clock.now().timestamp_millis();
+            let backlogs: Vec<BacklogResponse> = listing
+                .iter()
+                .take(limit)
+                .map(|backlog| backlog_response(backlog, now))
+                .collect();
+            (StatusCode::OK, Json(backlogs)).into_response()
+        }
+        Err(err) => {
+            info!("Database error in /admin/backlog: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// Returns this instance's operational counters.
+pub async fn get_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let shard_stats = ConnectionManagerStats::collect(&state.connections);
+    shard_stats.warn_if_imbalanced();
+    Json(MetricsResponse {
+        clock_skew_corrections: state.clock_skew_corrections.load(Ordering::Relaxed),
+        row_decode_errors: ROW_DECODE_ERRORS.load(Ordering::Relaxed),
+        ws_connections: state.connection_tracker.open_connections(),
+        ws_connections_evicted: state.connection_tracker.evictions.load(Ordering::Relaxed),
+        ws_upgrades_refused: state.connection_tracker.refused.load(Ordering::Relaxed),
+        new_conversation_limit_hits: state.new_conversation_limit_hits.load(Ordering::Relaxed),
+        rate_limit_hits: service::RATE_LIMIT_HITS.load(Ordering::Relaxed),
+        dashmap_shard_imbalance_ratio: shard_stats.imbalance_ratio(),
+    })
+}
+
+/// Lists the entry count and load factor of each connection shard, keyed by shard index.
+pub async fn get_connection_shards(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let stats = ConnectionManagerStats::collect(&state.connections);
+    stats.warn_if_imbalanced();
+    Json(stats.shards)
+}
+
+/// Users whose backlog is over a threshold, so each crossing alerts once.
+#[derive(Default)]
+struct BacklogAlerts {
+    alerting: HashSet<Uuid>,
+}
+
+impl BacklogAlerts {
+    /// Returns the backlogs that crossed a threshold since the last listing. A user alerts
+    /// again only after recovering (see `service::backlog_recovered`), or dropping out of a
+    /// listing shorter than `BACKLOG_LISTING_SIZE`, which holds every waiting receiver.
+    fn update<'a>(
+        &mut self,
+        listing: &'a [BacklogRecord],
+        thresholds: BacklogThresholds,
+        now_millis: i64,
+    ) -> Vec<&'a BacklogRecord> {
+        if (listing.len() as i64) < BACKLOG_LISTING_SIZE {
+            self.alerting
+                .retain(|user_id| listing.iter().any(|backlog| backlog.user_id == *user_id));
+        }
+        let mut crossed = Vec::new();
+        for backlog in listing {
+            if self.alerting.contains(&backlog.user_id) {
+                if service::backlog_recovered(backlog, thresholds, now_millis) {
+                    self.alerting.remove(&backlog.user_id);
+                }
+            } else if service::backlog_exceeds(backlog, thresholds, now_millis) {
+                self.alerting.insert(backlog.user_id);
+                crossed.push(backlog);
+            }
+        }
+        crossed
+    }
+}
+
+/// Spawns a task that refreshes the backlog listing every minute and raises an alert for
+/// each user who crosses `thresholds`. Does nothing when no threshold is configured.
+///
+/// Alerts are logged as `backlog_alert` warnings and, with `webhooks`, sent as
+/// `backlog_alert` events.
+pub fn spawn_backlog_monitor(
+    cache: BacklogCache,
+    messages: Arc<dyn MessageRepo>,
+    thresholds: BacklogThresholds,
+    clock: Arc<dyn Clock>,
+    webhooks: Option<WebhookDispatcher>,
+) {
+    if !thresholds.enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut alerts = BacklogAlerts::default();
+        loop {
+            sleep(BACKLOG_CACHE_TTL).await;
+            let listing = match cached_backlogs(&cache, messages.as_ref()).await {
+                Ok(listing) => listing,
+                Err(e) => {
+                    error!("Failed to load message backlogs: {}", e);
+                    continue;
+                }
+            };
+            let now = clock.now();
+            let now_millis = now.timestamp_millis();
+            for backlog in alerts.update(&listing, thresholds, now_millis) {
+                let oldest_age_secs = service::backlog_age_secs(backlog, now_millis);
+                warn!(
+                    "backlog_alert: user {} has {} undelivered messages, oldest {}s old",
+                    backlog.user_id, backlog.count, oldest_age_secs
+                );
+                if let Some(webhooks) = &webhooks {
+                    webhooks.dispatch(WebhookEvent::BacklogAlert {
+                        user_id: backlog.user_id.to_string(),
+                        count: backlog.count,
+                        oldest_age_secs,
+                        timestamp: format_timestamp(now, DEFAULT_TIMEZONE),
+                    });
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::admin_routes;
+    use crate::websocket::tests::fake_state_with;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    async fn call_admin(
+        configure: impl FnOnce(&mut AppState),
+        peer: &str,
+        authorization: Option<&str>,
+    ) -> Response {
+        let (state, _) = fake_state_with(configure);
+        let app = admin_routes(state.clone()).with_state(state);
+        let mut request = Request::builder().uri("/admin/metrics");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        let peer: SocketAddr = format!("{}:4000", peer).parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        app.oneshot(request).await.unwrap()
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        let encoded = general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        format!("Basic {}", encoded)
+    }
+
+    fn with_credentials(state: &mut AppState) {
+        state.admin_credentials = Some(AdminCredentials {
+            username: "admin".to_string(),
+            password: "secret".to_string(),
+        });
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_need_credentials() {
+        // Disabled while no credentials are configured
+        let response = call_admin(|_| {}, "127.0.0.1", Some(&basic("admin", "secret"))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = call_admin(with_credentials, "127.0.0.1", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(
+            response.headers()[WWW_AUTHENTICATE]
+                .to_str()
+                .unwrap()
+                .starts_with("Basic ")
+        );
+        let response = call_admin(
+            with_credentials,
+            "127.0.0.1",
+            Some(&basic("admin", "wrong")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = call_admin(
+            with_credentials,
+            "127.0.0.1",
+            Some(&basic("admin", "secret")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_admin_credentials_do_not_bypass_allowlist() {
+        let configure = |state: &mut AppState| {
+            with_credentials(state);
+            state.admin_ip_allowlist = parse_ip_allowlist("10.0.0.0/8").unwrap();
+        };
+        let response = call_admin(configure, "203.0.113.7", Some(&basic("admin", "secret"))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = call_admin(configure, "10.1.2.3", Some(&basic("admin", "secret"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_parse_allowlist() {
+        let allowlist = parse_ip_allowlist("10.0.0.0/8, 192.168.1.5,::1/128").unwrap();
+        assert_eq!(allowlist.len(), 3);
+        assert!(parse_ip_allowlist("").unwrap().is_empty());
+        assert!(parse_ip_allowlist("10.0.0.0/33").is_err());
+        assert!(parse_ip_allowlist("not-an-ip").is_err());
+    }
+
+    #[test]
+    fn test_ip_allowed() {
+        let allowlist = parse_ip_allowlist("10.0.0.0/8,192.168.1.5").unwrap();
+        assert!(ip_allowed(&allowlist, "10.20.30.40".parse().unwrap()));
+        assert!(ip_allowed(&allowlist, "192.168.1.5".parse().unwrap()));
+        assert!(ip_allowed(&allowlist, "::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!ip_allowed(&allowlist, "192.168.1.6".parse().unwrap()));
+        assert!(ip_allowed(&[], "203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_proxy_headers_only_used_when_trusted() {
+        let peer: IpAddr = "172.17.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.1.1.1, 172.17.0.1".parse().unwrap());
+
+        assert_eq!(client_ip(&headers, peer, false), peer);
+        // The client wrote 10.1.1.1; the proxy appended the address it saw
+        assert_eq!(client_ip(&headers, peer, true), peer);
+        headers.insert("x-forwarded-for", "10.1.1.1, 203.0.113.9".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, peer, true),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "10.2.2.2".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, peer, true),
+            "10.2.2.2".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_static_file_etag() {
+        let etag = static_file_etag("Wed, 21 Oct 2015 07:28:00 GMT", "1024").unwrap();
+        assert_eq!(etag, "\"56273e80-1024\"");
+        // A different size or modification time gives a different tag
+        assert_ne!(
+            static_file_etag("Wed, 21 Oct 2015 07:28:01 GMT", "1024").unwrap(),
+            etag
+        );
+        assert_ne!(
+            static_file_etag("Wed, 21 Oct 2015 07:28:00 GMT", "1025").unwrap(),
+            etag
+        );
+        assert!(static_file_etag("yesterday", "1024").is_none());
+    }
+
+    #[test]
+    fn test_backlog_alerts_once_per_crossing() {
+        let thresholds = BacklogThresholds {
+            max_count: Some(10),
+            max_age_secs: None,
+        };
+        let user_id = Uuid::new_v4();
+        let backlog = |count| BacklogRecord {
+            user_id,
+            count,
+            oldest_timestamp: None,
+            oldest_message_id: None,
+        };
+        let mut alerts = BacklogAlerts::default();
+        assert!(alerts.update(&[backlog(8)], thresholds, 0).is_empty());
+        assert_eq!(alerts.update(&[backlog(11)], thresholds, 0).len(), 1);
+        // Still over, or dipping just below the threshold, does not alert again
+        assert!(alerts.update(&[backlog(30)], thresholds, 0).is_empty());
+        assert!(alerts.update(&[backlog(9)], thresholds, 0).is_empty());
+        assert!(alerts.update(&[backlog(11)], thresholds, 0).is_empty());
+        // Recovering below half the threshold re-arms the alert
+        assert!(alerts.update(&[backlog(5)], thresholds, 0).is_empty());
+        assert_eq!(alerts.update(&[backlog(11)], thresholds, 0).len(), 1);
+        // So does leaving a complete listing, i.e. an empty backlog
+        assert!(alerts.update(&[], thresholds, 0).is_empty());
+        assert_eq!(alerts.update(&[backlog(11)], thresholds, 0).len(), 1);
+    }
+}
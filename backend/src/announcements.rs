@@ -0,0 +1,136 @@
+//! Announcements module for Safe Chat backend
+//!
+//! Operators post announcements (planned maintenance, incidents) through
+//! `POST /admin/announcements`. Each one is stored, written to the admin audit log, and
+//! pushed to every connected user as an `announcement` WebSocket event. Clients that
+//! connect later fetch the unexpired ones from `GET /announcements/active`.
+//!
+//! Announcements are plaintext by design: they come from the server, not from a contact,
+//! and are never end-to-end encrypted.
+
+use crate::admin::{admin_username, client_ip};
+use crate::api::extract_user_id_from_auth;
+use crate::repo::AuditActor;
+use crate::service;
+use crate::state::AppState;
+use crate::websocket::{AnnouncementNotification, broadcast_announcement};
+
+use axum::extract::{ConnectInfo, Json, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct CreateAnnouncementRequest {
+    pub message: String,
+    /// `info` (default), `warning` or `critical`.
+    pub severity: Option<String>,
+    /// RFC 3339 time after which the announcement is no longer shown.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+pub(crate) fn audit_actor(state: &AppState, headers: &HeaderMap, peer: SocketAddr) -> AuditActor {
+    AuditActor {
+        source_ip: client_ip(headers, peer.ip(), state.trust_proxy_headers).to_string(),
+        admin: admin_username(headers),
+    }
+}
+
+/// Creates an announcement and broadcasts it to every connected user.
+pub async fn create_announcement(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateAnnouncementRequest>,
+) -> impl IntoResponse {
+    let actor = audit_actor(&state, &headers, peer);
+    match service::create_announcement(
+        state.announcements.as_ref(),
+        &payload.message,
+        payload.severity.as_deref(),
+        payload.expires_at,
+        &actor,
+        state.clock.now(),
+    )
+    .await
+    {
+        Ok(record) => {
+            info!(
+                "Announcement {} ({}) created from {}",
+                record.id, record.severity, actor.source_ip
+            );
+            let notification = AnnouncementNotification::from_record(&record);
+            broadcast_announcement(&state, notification.clone()).await;
+            (StatusCode::CREATED, Json(notification)).into_response()
+        }
+        Err(err) => {
+            info!("Creating announcement failed: {}", err);
+            err.into_response()
+        }
+    }
+}
+
+/// Deletes an announcement so it is no longer returned as active.
+///
+/// Clients that already displayed it keep it until it expires or they reconnect.
+pub async fn delete_announcement(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let id = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid announcement id").into_response(),
+    };
+    let actor = audit_actor(&state, &headers, peer);
+    match state.announcements.delete_announcement(id, &actor).await {
+        Ok(true) => {
+            info!("Announcement {} deleted from {}", id, actor.source_ip);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "Announcement not found").into_response(),
+        Err(err) => {
+            info!("Database error in /admin/announcements/{{id}}: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// Lists the announcements that have not expired, oldest first.
+pub async fn list_active_announcements(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(e) = extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        info!("Unauthorized access attempt to /announcements/active endpoint");
+        return e.into_response();
+    }
+    match state
+        .announcements
+        .active_announcements(state.clock.now())
+        .await
+    {
+        Ok(records) => {
+            let announcements: Vec<AnnouncementNotification> = records
+                .iter()
+                .map(AnnouncementNotification::from_record)
+                .collect();
+            (StatusCode::OK, Json(announcements)).into_response()
+        }
+        Err(err) => {
+            info!("Database error in /announcements/active: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
@@ -1,31 +1,63 @@
 //! API module for Safe Chat backend
 //!
-//! This module handles all API endpoints and implements proper timezone handling
-//! for the Europe/Brussels timezone. All timestamps are:
-//! - Stored as Unix timestamps (BIGINT) in the database
-//! - Generated in Brussels timezone when creating new messages
-//! - Converted to Brussels timezone when returning data to clients
+//! This module handles all API endpoints. All timestamps are:
+//! - Stored as Unix timestamps (BIGINT) or `TIMESTAMPTZ` in the database
+//! - Generated in UTC, whatever region the server runs in
+//! - Rendered in the requesting user's preferred timezone for message and pin lists,
+//!   and in UTC elsewhere (see `preferences`)
 //! - The created_at fields remain static as stored in the database
 
+use crate::admin::client_ip;
+use crate::auth::{Claims, decode_jwt_token};
+use crate::crypto::{
+    self, ColumnKeyring, SUPPORTED_ENCRYPTION_VERSIONS, max_encryption_version, standard_public_key,
+};
+use crate::db::{IsolationLevel, WithIsolation};
+use crate::devices::{DeviceKey, device_key};
+use crate::media;
+use crate::preferences::{
+    DEFAULT_TIMEZONE, format_millis, format_timestamp, preferred_timezone, try_format_millis,
+};
+use crate::repo::postgres::nullable;
+use crate::repo::{
+    ConversationQuery, MessageCursor, MessageRecord, MessageRepo, SortOrder, UserRecord,
+};
+use crate::service;
 use crate::state::AppState;
+use crate::websocket::{
+    ConversationCleared, MessageDraft, MetaUpdate, PinUpdate, UnreadCounts,
+    broadcast_conversation_cleared_to_user, broadcast_fetch_receipts,
+    broadcast_meta_update_to_user, broadcast_pin_update_to_user, delivery_receipt_response,
+    validate_send_message,
+};
 
-use axum::extract::{Json, Path, State};
+use async_trait::async_trait;
+use axum::body::{Bytes, StreamBody};
+use axum::extract::rejection::PathRejection;
+use axum::extract::{ConnectInfo, FromRequestParts, Json, Path, Query, RawPathParams, State};
 use axum::http::HeaderMap;
 use axum::http::StatusCode;
-use axum::http::header::AUTHORIZATION;
-use axum::response::IntoResponse;
+use axum::http::header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
 use base64;
 use base64::Engine;
 use base64::engine::general_purpose;
-use chrono::{DateTime, Utc};
-use chrono_tz::Europe::Brussels;
-use jsonwebtoken::{DecodingKey, Validation, decode};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures_util::stream;
 use serde::Serialize;
-use serde_json::json;
-use sqlx::Row;
+use serde_json::{Map, Value, json};
+use sqlx::postgres::PgRow;
 use sqlx::types::Uuid;
+use sqlx::{PgConnection, PgPool, Row};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tracing::info;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+pub use safechat_types::messages::{MessagePageResponse, MessageResponse, ReplyReference};
 
 #[derive(Serialize)]
 pub struct UserResponse {
@@ -34,27 +66,75 @@ pub struct UserResponse {
     pub public_key: String,
     pub created_at: String,
     pub avatar: Option<String>,
+    /// Per-device keys, present when `MULTI_DEVICE` is enabled. Senders encrypt one copy
+    /// of each message per device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<DeviceKey>>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct Claims {
-    sub: Uuid,
-    exp: usize,
+#[derive(serde::Deserialize)]
+pub struct ProfileQuery {
+    /// Comma-separated subset of fields to return, e.g. `username,public_key`.
+    pub fields: Option<String>,
 }
 
 #[derive(serde::Serialize)]
-pub struct MessageResponse {
-    pub id: String,
-    pub timestamp: String,
-    pub sender_id: String,
-    pub receiver_id: String,
-    pub status: String,
-    pub r#type: String,
-    pub encrypted_content: String,
-    pub iv: String,
+pub struct ForwardCountResponse {
+    pub forward_count: i32,
+    pub forwarded_many_times: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct MessageRangeQuery {
+    pub after: Option<i64>,
+    pub before: Option<i64>,
+    /// `asc` or `desc`.
+    pub order: Option<String>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    /// `true` to leave fetched messages SENT instead of marking them DELIVERED.
+    pub no_receipt: Option<bool>,
+}
+
+/// Response of `GET /user/by-id/{user_id}/online`.
+#[derive(Serialize)]
+pub struct PresenceResponse {
+    pub online: bool,
+    /// When the user's last connection closed, in the caller's timezone; `null` if never.
+    pub last_seen: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct OnDateQuery {
+    pub date: String,
+    pub timezone: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PinnedMessageResponse {
+    #[serde(flatten)]
+    pub message: MessageResponse,
+    pub pinned_by: String,
+    pub pinned_at: String,
 }
 
+/// Largest request body accepted on REST endpoints unless `MAX_REQUEST_BODY_BYTES` is set.
+/// Leaves room for a base64-encoded avatar.
+pub const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 2 * 1024 * 1024;
 
+/// Reports the server version and the message encryption versions it accepts.
+///
+/// Clients use this to pick an `encryption_version` before sending. No authentication required.
+pub async fn get_version() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "max_encryption_version": max_encryption_version(),
+            "supported_encryption_versions": SUPPORTED_ENCRYPTION_VERSIONS,
+        })),
+    )
+}
 
 /// Extracts and validates a user ID from a JWT Bearer token in the HTTP Authorization header.
 ///
@@ -68,7 +148,7 @@ pub struct MessageResponse {
 ///     .header("Authorization", "Bearer <valid_jwt>")
 ///     .body(())
 ///     .unwrap();
-/// let user_id = extract_user_id_from_auth(&req, "mysecret");
+/// let user_id = extract_user_id_from_auth(&req, "mysecret", 30, Utc::now());
 /// assert!(user_id.is_ok() || user_id.is_err());
 /// Extracts and validates a user UUID from a JWT Bearer token in the `Authorization` header.
 ///
@@ -80,13 +160,26 @@ pub struct MessageResponse {
 /// use axum::http::HeaderMap;
 /// let mut headers = HeaderMap::new();
 /// headers.insert("authorization", "Bearer <valid_jwt_token>".parse().unwrap());
-/// let user_id = extract_user_id_from_auth(&headers, "my_jwt_secret");
+/// let user_id = extract_user_id_from_auth(&headers, "my_jwt_secret", 30, Utc::now());
 /// assert!(user_id.is_ok() || user_id.is_err());
 /// ```
-fn extract_user_id_from_auth(
+pub(crate) fn extract_user_id_from_auth(
     req: &HeaderMap,
     jwt_secret: &str,
+    leeway_secs: u64,
+    now: DateTime<Utc>,
 ) -> Result<Uuid, (StatusCode, &'static str)> {
+    extract_claims_from_auth(req, jwt_secret, leeway_secs, now).map(|claims| claims.sub)
+}
+
+/// Like `extract_user_id_from_auth`, for handlers that also need to know whether the token
+/// is an impersonation token.
+pub(crate) fn extract_claims_from_auth(
+    req: &HeaderMap,
+    jwt_secret: &str,
+    leeway_secs: u64,
+    now: DateTime<Utc>,
+) -> Result<Claims, (StatusCode, &'static str)> {
     let auth_header = req.get(AUTHORIZATION).and_then(|h| h.to_str().ok());
     let token = match auth_header.and_then(|h| h.strip_prefix("Bearer ")) {
         Some(t) => t,
@@ -97,20 +190,60 @@ fn extract_user_id_from_auth(
             ));
         }
     };
-    let token_data = match decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(jwt_secret.as_bytes()),
-        &Validation::default(),
-    ) {
-        Ok(data) => data,
-        Err(_) => return Err((StatusCode::UNAUTHORIZED, "Invalid token")),
-    };
-    Ok(token_data.claims.sub)
+    decode_jwt_token(token, jwt_secret, leeway_secs, now)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid token"))
+}
+
+/// A path segment that must be a UUID, extracted through `Path<Uuid>`.
+///
+/// A malformed segment is rejected with 400 and `{"error": "invalid_uuid", "param": <name>}`
+/// before the handler runs, so handlers never parse ids themselves.
+pub struct UuidPath(pub Uuid);
+
+#[derive(Debug)]
+pub enum UuidPathRejection {
+    InvalidUuid { param: String },
+    Path(PathRejection),
+}
+
+impl IntoResponse for UuidPathRejection {
+    fn into_response(self) -> Response {
+        match self {
+            UuidPathRejection::InvalidUuid { param } => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_uuid", "param": param })),
+            )
+                .into_response(),
+            UuidPathRejection::Path(rejection) => rejection.into_response(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UuidPath {
+    type Rejection = UuidPathRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Path::<Uuid>::from_request_parts(parts, state).await {
+            Ok(Path(id)) => Ok(UuidPath(id)),
+            Err(PathRejection::FailedToDeserializePathParams(_)) => {
+                // The deserializer does not say which segment failed; every route using
+                // this extractor has exactly one
+                let param = RawPathParams::from_request_parts(parts, state)
+                    .await
+                    .ok()
+                    .and_then(|params| params.iter().next().map(|(name, _)| name.to_string()))
+                    .unwrap_or_default();
+                Err(UuidPathRejection::InvalidUuid { param })
+            }
+            Err(rejection) => Err(UuidPathRejection::Path(rejection)),
+        }
+    }
 }
 
 /// Retrieves user information by public key, returning user details as JSON if found.
 ///
-/// This endpoint requires a valid JWT Bearer token in the `Authorization` header. If the token is missing or invalid, an unauthorized response is returned. On success, the user matching the provided public key is returned as a JSON object. If no user is found, a 404 response is returned. In case of a database error, a 500 response is returned.
+/// This endpoint requires a valid JWT Bearer token in the `Authorization` header. If the token is missing or invalid, an unauthorized response is returned. On success, the user matching the provided public key is returned as a JSON object. A key that is not X.509-encoded X25519 is rejected with 400 before any lookup. If no user is found, a 404 response is returned. In case of a database error, a 500 response is returned.
 ///
 /// # Examples
 ///
@@ -135,10 +268,16 @@ fn extract_user_id_from_auth(
 /// ```
 pub async fn get_user_by_public_key(
     Path(public_key): Path<String>,
+    Query(query): Query<ProfileQuery>,
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    let auth_result = extract_user_id_from_auth(&headers, &state.jwt_secret);
+    let auth_result = extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    );
     let requesting_user = match auth_result {
         Ok(uid) => uid,
         Err(e) => {
@@ -146,17 +285,19 @@ pub async fn get_user_by_public_key(
             return e.into_response();
         }
     };
+    // Keys are stored in standard base64, but base64url needs no escaping in the path
+    let Some(public_key) = standard_public_key(&public_key) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_public_key", "param": "public_key" })),
+        )
+            .into_response();
+    };
     info!(
         "User {} requested user lookup by public key: {}",
         requesting_user, public_key
     );
-    let row = match sqlx::query(
-        "SELECT id, username, public_key, created_at, avatar FROM users WHERE public_key = $1",
-    )
-    .bind(&public_key)
-    .fetch_optional(&state.db)
-    .await
-    {
+    let row = match state.users.find_by_public_key(&public_key).await {
         Ok(Some(record)) => record,
         Ok(None) => {
             info!("User not found for public key: {}", public_key);
@@ -172,26 +313,23 @@ pub async fn get_user_by_public_key(
         }
     };
 
-    // Get created_at from database and convert to Brussels timezone
-    let created_at_utc: DateTime<Utc> = row.try_get::<DateTime<Utc>, _>("created_at").unwrap();
-    let created_at_brussels = created_at_utc.with_timezone(&Brussels);
-
-    let user = UserResponse {
-        id: row.try_get::<Uuid, _>("id").unwrap().to_string(),
-        username: row.try_get::<String, _>("username").unwrap(),
-        public_key: row.try_get::<String, _>("public_key").unwrap(),
-        created_at: created_at_brussels.to_rfc3339(),
-        avatar: row
-            .try_get::<Option<Vec<u8>>, _>("avatar")
-            .ok()
-            .flatten()
-            .map(base64::encode),
+    let (user_id, updated_at) = (row.id, row.profile_updated_at);
+    let response = user_response(row, &state.column_keys);
+    let user = match with_device_keys(&state, user_id, response).await {
+        Ok(user) => user,
+        Err(err) => {
+            info!(
+                "Database error loading devices in /user/{{public_key}}: {}",
+                err
+            );
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
     };
     info!(
         "User found for public key: {} (id: {})",
         public_key, user.id
     );
-    (axum::http::StatusCode::OK, Json(user)).into_response()
+    profile_response(&user, updated_at, query.fields.as_deref(), &headers)
 }
 
 /// Retrieves user information by user ID, requiring JWT authentication.
@@ -204,18 +342,24 @@ pub async fn get_user_by_public_key(
 /// // Example Axum route usage:
 /// // GET /user/by-id/{user_id} with Authorization: Bearer <token>
 /// let response = get_user_by_id(
-///     Path("user-uuid-string".to_string()),
+///     UuidPath(user_id),
 ///     State(app_state_arc),
 ///     headers_with_valid_jwt()
 /// ).await;
 /// assert_eq!(response.status(), StatusCode::OK);
 /// ```
 pub async fn get_user_by_id(
-    Path(user_id): Path<String>,
+    UuidPath(target_user_id): UuidPath,
+    Query(query): Query<ProfileQuery>,
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    let auth_result = extract_user_id_from_auth(&headers, &state.jwt_secret);
+    let auth_result = extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    );
     let requesting_user = match auth_result {
         Ok(uid) => uid,
         Err(e) => {
@@ -224,30 +368,12 @@ pub async fn get_user_by_id(
         }
     };
 
-    // Parse the user ID
-    let target_user_id = match Uuid::parse_str(&user_id) {
-        Ok(uid) => uid,
-        Err(_) => {
-            return (
-                axum::http::StatusCode::BAD_REQUEST,
-                "Invalid user_id format",
-            )
-                .into_response();
-        }
-    };
-
     info!(
         "User {} requested user lookup by ID: {}",
         requesting_user, target_user_id
     );
 
-    let row = match sqlx::query(
-        "SELECT id, username, public_key, created_at, avatar FROM users WHERE id = $1",
-    )
-    .bind(&target_user_id)
-    .fetch_optional(&state.db)
-    .await
-    {
+    let row = match state.users.find_by_id(target_user_id).await {
         Ok(Some(record)) => record,
         Ok(None) => {
             info!("User not found for ID: {}", target_user_id);
@@ -263,153 +389,1709 @@ pub async fn get_user_by_id(
         }
     };
 
-    // Get created_at from database and convert to Brussels timezone
-    let created_at_utc: DateTime<Utc> = row.try_get::<DateTime<Utc>, _>("created_at").unwrap();
-    let created_at_brussels = created_at_utc.with_timezone(&Brussels);
-
-    let user = UserResponse {
-        id: row.try_get::<Uuid, _>("id").unwrap().to_string(),
-        username: row.try_get::<String, _>("username").unwrap(),
-        public_key: row.try_get::<String, _>("public_key").unwrap(),
-        created_at: created_at_brussels.to_rfc3339(),
-        avatar: row
-            .try_get::<Option<Vec<u8>>, _>("avatar")
-            .ok()
-            .flatten()
-            .map(base64::encode),
+    let updated_at = row.profile_updated_at;
+    let response = user_response(row, &state.column_keys);
+    let user = match with_device_keys(&state, target_user_id, response).await {
+        Ok(user) => user,
+        Err(err) => {
+            info!(
+                "Database error loading devices in /user/by-id/{{user_id}}: {}",
+                err
+            );
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
     };
 
     info!(
         "User found for ID: {} (username: {})",
         target_user_id, user.username
     );
-    (axum::http::StatusCode::OK, Json(user)).into_response()
+    profile_response(&user, updated_at, query.fields.as_deref(), &headers)
 }
 
-/// Handles sending a new message from the authenticated user to a specified recipient.
-///
-/// Validates the JWT Bearer token from the `Authorization` header, parses and verifies the recipient's UUID,
-/// decodes base64-encoded encrypted content and IV, inserts the message into the database, and returns the stored message as JSON.
-/// Returns appropriate HTTP status codes for authorization failures, invalid input, decoding errors, or database errors.
+/// Reports whether one of the caller's contacts is connected, and when they were last seen.
 ///
-/// # Examples
+/// `online` reflects the WebSocket connections held by this instance. Responds with 401 if
+/// authentication fails, 400 for an invalid user ID, 403 if the user is not one of the
+/// caller's contacts, or 500 on database errors.
+pub async fn get_user_online(
+    UuidPath(target_user_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /user/by-id/{{}}/online endpoint");
+            return e.into_response();
+        }
+    };
+    let presence = match service::contact_presence(
+        state.users.as_ref(),
+        state.contacts.as_ref(),
+        requesting_user,
+        target_user_id,
+        state.connections.contains_key(&target_user_id),
+    )
+    .await
+    {
+        Ok(presence) => presence,
+        Err(err) => {
+            info!(
+                "Presence of {} not shown to user {}: {}",
+                target_user_id, requesting_user, err
+            );
+            return err.into_response();
+        }
+    };
+    let timezone = preferred_timezone(state.users.as_ref(), requesting_user).await;
+    let response = PresenceResponse {
+        online: presence.online,
+        last_seen: presence
+            .last_seen
+            .map(|last_seen| format_timestamp(last_seen, timezone)),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+/// Serves a user's avatar as raw image bytes, requiring JWT authentication.
 ///
-/// ```
-/// // Example Axum route registration:
-/// // router.route("/messages/send", post(send_message));
-/// ```
+/// The stored content type is sent together with `X-Content-Type-Options: nosniff` and an
+/// inline `Content-Disposition`. Avatars uploaded before types were recorded are checked
+/// again; anything that is not a recognised image is served as an `application/octet-stream`
+/// attachment so a browser never renders it.
+pub async fn get_user_avatar(
+    UuidPath(target_user_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(e) = extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        info!("Unauthorized access attempt to /user/by-id/{{}}/avatar endpoint");
+        return e.into_response();
+    }
+    let user = match state.users.find_by_id(target_user_id).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(err) => {
+            info!("Database error in /user/by-id/{{user_id}}/avatar: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let Some(stored) = user.avatar else {
+        return (StatusCode::NOT_FOUND, "No avatar").into_response();
+    };
+    let avatar = match service::open_avatar(&state.column_keys, user.id, stored) {
+        Ok(avatar) => avatar,
+        Err(reason) => {
+            error!("Avatar of user {} cannot be decrypted: {}", user.id, reason);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Avatar cannot be decrypted",
+            )
+                .into_response();
+        }
+    };
+    let image_type = match user.avatar_content_type.as_deref() {
+        Some(content_type) => media::ImageType::from_content_type(content_type),
+        None => media::detect_image_type(&avatar).ok(),
+    };
+    match image_type {
+        Some(image_type) => {
+            let filename = format!("{}.{}", user.username, image_type.extension());
+            media::inline_image_response(avatar, image_type, &filename)
+        }
+        None => media::attachment_response(avatar, &format!("{}-avatar", user.username)),
+    }
+}
 
+#[derive(serde::Deserialize)]
+pub struct AvatarBatchRequest {
+    pub user_ids: Vec<String>,
+}
 
-/// Placeholder endpoint for retrieving messages exchanged with a specific user.
-///
-/// Currently not implemented; always returns HTTP 200 with a not implemented message.
-///
-/// # Examples
-///
-/// ```
-/// // This endpoint is not implemented and always returns a 200 OK status.
-/// Retrieves all messages exchanged between the authenticated user and the specified user.
-///
-/// Requires a valid JWT Bearer token in the `Authorization` header. Returns a JSON array of messages ordered by timestamp, with binary fields base64-encoded. Responds with appropriate HTTP status codes for authorization failures, invalid user IDs, or database errors.
-///
-/// # Examples
+/// Returns the avatars of up to `MAX_AVATAR_BATCH` users as `{"avatars": {id: base64}}`,
+/// requiring JWT authentication. Users without an avatar are left out of the map.
+/// 400 if an id is not a UUID or too many are sent.
+pub async fn get_avatars_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<AvatarBatchRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        info!("Unauthorized access attempt to /avatars/batch endpoint");
+        return e.into_response();
+    }
+    let user_ids: Vec<Uuid> = match payload
+        .user_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect()
+    {
+        Ok(ids) => ids,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user_id format").into_response(),
+    };
+    match service::avatars(state.users.as_ref(), &state.column_keys, &user_ids).await {
+        Ok(avatars) => {
+            let avatars: serde_json::Map<String, serde_json::Value> = avatars
+                .into_iter()
+                .map(|(id, bytes)| {
+                    (
+                        id.to_string(),
+                        general_purpose::STANDARD.encode(bytes).into(),
+                    )
+                })
+                .collect();
+            (StatusCode::OK, Json(json!({ "avatars": avatars }))).into_response()
+        }
+        Err(err) => {
+            info!("Fetching avatars failed: {}", err);
+            err.into_response()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct KeyFingerprintsRequest {
+    pub public_keys: Vec<String>,
+}
+
+/// The fingerprint of one requested key, or the reason it is invalid.
+#[derive(Serialize)]
+pub struct KeyFingerprintResponse {
+    pub public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Resolves up to `MAX_FINGERPRINT_KEYS` public keys to fingerprints, in request order,
+/// requiring JWT authentication. Invalid keys get an `error` instead of failing the batch;
+/// 400 if too many keys are sent.
+pub async fn key_fingerprints(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<KeyFingerprintsRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        info!("Unauthorized access attempt to /keys/fingerprints endpoint");
+        return e.into_response();
+    }
+    let results = match service::key_fingerprints(&payload.public_keys) {
+        Ok(results) => results,
+        Err(err) => return err.into_response(),
+    };
+    let fingerprints: Vec<KeyFingerprintResponse> = payload
+        .public_keys
+        .into_iter()
+        .zip(results)
+        .map(|(public_key, result)| match result {
+            Ok(fingerprint) => KeyFingerprintResponse {
+                public_key,
+                fingerprint: Some(fingerprint),
+                error: None,
+            },
+            Err(reason) => KeyFingerprintResponse {
+                public_key,
+                fingerprint: None,
+                error: Some(reason.to_string()),
+            },
+        })
+        .collect();
+    (
+        StatusCode::OK,
+        Json(json!({ "fingerprints": fingerprints })),
+    )
+        .into_response()
+}
+
+/// Signature checks per client address within `VERIFY_SIGNATURE_WINDOW`.
+const VERIFY_SIGNATURE_LIMIT: u32 = 100;
+const VERIFY_SIGNATURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Rate limit key of the `POST /verify-signature` calls from `ip`.
+fn verify_signature_limit_key(ip: IpAddr) -> String {
+    format!("verify_signature:{}", ip)
+}
+
+#[derive(serde::Deserialize)]
+pub struct VerifySignatureRequest {
+    /// Base64 X.509 (or raw) Ed25519 public key of the signer.
+    pub public_key: String,
+    /// Base64 of the signed bytes.
+    pub message: String,
+    /// Base64 of the 64-byte detached signature.
+    pub signature: String,
+}
+
+/// Checks a detached Ed25519 signature, so clients can verify a contact's signed messages
+/// without a crypto library of their own. No authentication is required; calls are limited
+/// to `VERIFY_SIGNATURE_LIMIT` per client address per minute so it cannot serve as a
+/// signature oracle. Responds with `{"valid": true|false}`, or 400 for a malformed key,
+/// message or signature.
+pub async fn verify_signature(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<VerifySignatureRequest>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers, peer.ip(), state.trust_proxy_headers);
+    if let Err(retry_after) = state
+        .rate_limiter
+        .check_and_increment(
+            &verify_signature_limit_key(ip),
+            VERIFY_SIGNATURE_LIMIT,
+            VERIFY_SIGNATURE_WINDOW,
+        )
+        .await
+        .into_result()
+    {
+        info!(
+            "Signature checks from {} rate limited for {}s",
+            ip,
+            retry_after.as_secs()
+        );
+        return service::rate_limited_response(retry_after);
+    }
+    let decode = |field: &str| general_purpose::STANDARD.decode(field);
+    let (Ok(message), Ok(signature)) = (decode(&payload.message), decode(&payload.signature))
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Invalid base64 in message or signature",
+        )
+            .into_response();
+    };
+    match crypto::verify_ed25519_signature(&payload.public_key, &message, &signature) {
+        Ok(valid) => (StatusCode::OK, Json(json!({ "valid": valid }))).into_response(),
+        Err(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+    }
+}
+
+/// Fields of a `send_message` that `POST /messages/validate` lets clients leave out, with
+/// the placeholder they are parsed with. A UUID placeholder keeps the frame size right.
+const DRAFT_PLACEHOLDERS: [(&str, &str); 3] = [
+    ("message_id", "00000000-0000-0000-0000-000000000000"),
+    ("encrypted_content", ""),
+    ("iv", ""),
+];
+
+/// Checks a message against the rules `send_message` applies without storing or sending
+/// anything, so clients can learn the server's rules before encrypting and uploading.
 ///
-/// ```
-/// // Example Axum route usage:
-/// // GET /messages/{user_id} with Authorization header
-/// let response = get_messages_with_user(
-///     Path("target-user-uuid".to_string()),
-///     State(app_state_arc),
-///     headers_with_valid_jwt()
-/// ).await;
-/// assert_eq!(response.status(), axum::http::StatusCode::OK);
-/// Retrieves all messages exchanged between the authenticated user and the specified user.
+/// The body is the `data` of a `send_message`. `message_id`, `encrypted_content` and `iv`
+/// may be left out; `encrypted_content_bytes`, the size of the ciphertext, then stands in
+/// for the content in the frame size check. Responds with
+/// `{"ok": bool, "errors": [{"code", "message"}]}` using the codes of WebSocket `error`
+/// events, 400 if the body is not a `send_message` shape and 401 without a valid token.
+pub async fn validate_message(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mut body): Json<Value>,
+) -> impl IntoResponse {
+    let sender_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/validate endpoint");
+            return e.into_response();
+        }
+    };
+    let Some(fields) = body.as_object_mut() else {
+        return (StatusCode::BAD_REQUEST, "Expected a JSON object").into_response();
+    };
+    let content_bytes = fields
+        .remove("encrypted_content_bytes")
+        .and_then(|bytes| bytes.as_u64())
+        .filter(|_| !fields.contains_key("encrypted_content"))
+        .unwrap_or(0);
+    let mut omitted = Vec::new();
+    for (field, placeholder) in DRAFT_PLACEHOLDERS {
+        if !fields.contains_key(field) {
+            fields.insert(field.to_string(), json!(placeholder));
+            omitted.push(field);
+        }
+    }
+    let frame_bytes = json!({ "message_type": "send_message", "data": &body })
+        .to_string()
+        .len()
+        + content_bytes.div_ceil(3) as usize * 4;
+    let data = match serde_json::from_value(body) {
+        Ok(data) => data,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid message: {}", e)).into_response();
+        }
+    };
+    let draft = MessageDraft {
+        data,
+        omitted,
+        frame_bytes,
+    };
+    match validate_send_message(&state, sender_id, &draft).await {
+        Ok(violations) => {
+            let errors: Vec<Value> = violations
+                .into_iter()
+                .map(|(code, message)| json!({ "code": code, "message": message }))
+                .collect();
+            Json(json!({ "ok": errors.is_empty(), "errors": errors })).into_response()
+        }
+        Err(err) => {
+            error!(
+                "Failed to validate a message from user_id {}: {}",
+                sender_id, err
+            );
+            err.into_response()
+        }
+    }
+}
+
+/// Retrieves messages exchanged between the authenticated user and the specified user.
 ///
-/// Authenticates the request using the JWT Bearer token in the `Authorization` header. Returns a JSON array of messages ordered by timestamp, with encrypted content and IV fields base64-encoded. Responds with 401 if authentication fails, 400 if the user ID is invalid, or 500 on database errors.
+/// Authenticates the request using the JWT Bearer token in the `Authorization` header. Returns a JSON array of messages ordered by timestamp, with encrypted content and IV fields base64-encoded.
+/// The optional `after` and `before` query parameters (Unix milliseconds, inclusive) limit the results to a time window.
+/// `order=asc|desc` picks the direction. With `limit` or `cursor` the response is a page
+/// (`{"items", "next_cursor"}`) that defaults to newest first; without them the whole
+/// conversation is returned as an array, oldest first unless `order` says otherwise.
+/// Messages addressed to the caller that are still SENT are marked DELIVERED, and their
+/// senders get a `status_update`, unless `no_receipt=true` or the token is an impersonation
+/// token.
+/// Responds with 401 if authentication fails, 400 if the user ID, range, order or cursor is invalid, or 500 on database errors.
 ///
 /// # Examples
 ///
 /// ```
 /// // Example Axum route usage:
-/// // GET /messages/{user_id}
+/// // GET /messages/{user_id}?after=1715040000000&before=1715126399999
 /// let response = get_messages_with_user(
-///     Path("target-user-uuid".to_string()),
+///     UuidPath(target_user_id),
+///     Query(MessageRangeQuery { after: Some(1715040000000), before: None, order: None, limit: None, cursor: None, no_receipt: None }),
 ///     State(app_state_arc),
 ///     headers
 /// ).await;
 /// ```
-
 pub async fn get_messages_with_user(
-    Path(user_id): Path<String>,
+    UuidPath(other_user): UuidPath,
+    Query(range): Query<MessageRangeQuery>,
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
     // Authenticate user
-    let auth_result = extract_user_id_from_auth(&headers, &state.jwt_secret);
-    let requesting_user = match auth_result {
-        Ok(uid) => uid,
+    let auth_result = extract_claims_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    );
+    let claims = match auth_result {
+        Ok(claims) => claims,
         Err(e) => {
             info!("Unauthorized access attempt to /messages/{{}} endpoint");
             return e.into_response();
         }
     };
-    let other_user = match Uuid::parse_str(&user_id) {
-        Ok(uid) => uid,
-        Err(_) => {
+    let requesting_user = claims.sub;
+    let order = match range.order.as_deref() {
+        None => None,
+        Some("asc") => Some(SortOrder::Asc),
+        Some("desc") => Some(SortOrder::Desc),
+        Some(_) => {
             return (
-                axum::http::StatusCode::BAD_REQUEST,
-                "Invalid user_id format",
+                StatusCode::BAD_REQUEST,
+                "Invalid order. Expected asc or desc",
             )
                 .into_response();
         }
     };
-    // Query messages between requesting_user and other_user
-    let rows = match sqlx::query(
-        "SELECT id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv FROM messages WHERE (sender_id = $1 AND receiver_id = $2) OR (sender_id = $2 AND receiver_id = $1) ORDER BY timestamp ASC"
+    let cursor = match range
+        .cursor
+        .as_deref()
+        .map(service::parse_message_cursor)
+        .transpose()
+    {
+        Ok(cursor) => cursor,
+        Err(err) => return err.into_response(),
+    };
+    // Unpaginated requests keep the original oldest-first array
+    let paginated = range.limit.is_some() || cursor.is_some();
+    let query = ConversationQuery {
+        after: range.after,
+        before: range.before,
+        order: order.unwrap_or(if paginated {
+            SortOrder::Desc
+        } else {
+            SortOrder::Asc
+        }),
+        cursor,
+        limit: range.limit,
+    };
+    // Reading as an admin during impersonation must not mark messages delivered for the user
+    let receipts = !claims.impersonation && !range.no_receipt.unwrap_or(false);
+    if paginated {
+        return conversation_page_response(&state, requesting_user, other_user, query, receipts)
+            .await;
+    }
+    conversation_response(&state, requesting_user, other_user, query, receipts).await
+}
+
+/// Deletes the whole conversation between the authenticated user and the specified user.
+///
+/// Messages are removed for both participants, matching how read messages are already
+/// deleted server-side. Both participants are notified with a `conversation_cleared`
+/// WebSocket event. Returns `{"deleted": n}`.
+pub async fn delete_conversation(
+    UuidPath(other_user): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to DELETE /messages/{{}} endpoint");
+            return e.into_response();
+        }
+    };
+    let deleted = match state
+        .messages
+        .delete_conversation(requesting_user, other_user)
+        .await
+    {
+        Ok(deleted) => deleted,
+        Err(err) => {
+            info!("Database error in DELETE /messages/{{user_id}}: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    info!(
+        "User {} cleared conversation with {} ({} messages deleted)",
+        requesting_user, other_user, deleted
+    );
+    for (recipient, peer) in [(requesting_user, other_user), (other_user, requesting_user)] {
+        broadcast_conversation_cleared_to_user(
+            &state,
+            recipient,
+            ConversationCleared {
+                user_id: peer.to_string(),
+                cleared_by: requesting_user.to_string(),
+                deleted_count: deleted,
+            },
+        )
+        .await;
+    }
+    (StatusCode::OK, Json(json!({ "deleted": deleted }))).into_response()
+}
+
+/// Retrieves the messages exchanged with the specified user on a single calendar day.
+///
+/// `date` is a `YYYY-MM-DD` date and `timezone` an IANA zone name (defaults to the user's
+/// preferred timezone).
+/// The day is converted to a Unix millisecond range and served like `GET /messages/{user_id}`,
+/// which lets clients jump to a date in a conversation.
+pub async fn get_messages_on_date(
+    UuidPath(other_user): UuidPath,
+    Query(query): Query<OnDateQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/{{}}/on-date endpoint");
+            return e.into_response();
+        }
+    };
+    let date = match NaiveDate::parse_from_str(&query.date, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, "Invalid date. Expected YYYY-MM-DD").into_response();
+        }
+    };
+    let timezone = match query.timezone.as_deref() {
+        Some(name) => match name.parse::<Tz>() {
+            Ok(tz) => tz,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid timezone").into_response(),
+        },
+        None => preferred_timezone(state.users.as_ref(), requesting_user).await,
+    };
+    let (after, before) = match service::day_range_millis(date, timezone) {
+        Some(range) => range,
+        None => return (StatusCode::BAD_REQUEST, "Date out of range").into_response(),
+    };
+    let range = ConversationQuery {
+        after: Some(after),
+        before: Some(before),
+        ..Default::default()
+    };
+    conversation_response(&state, requesting_user, other_user, range, false).await
+}
+
+/// Loads a conversation within an optional time window and renders it as a JSON response.
+async fn conversation_response(
+    state: &Arc<AppState>,
+    requesting_user: Uuid,
+    other_user: Uuid,
+    query: ConversationQuery,
+    receipts: bool,
+) -> axum::response::Response {
+    let mut rows = match service::conversation_messages(
+        state.messages.as_ref(),
+        requesting_user,
+        other_user,
+        query,
     )
-    .bind(requesting_user)
-    .bind(other_user)
-    .fetch_all(&state.db)
-    .await {
+    .await
+    {
         Ok(records) => records,
         Err(err) => {
-            info!("Database error in /messages/{{user_id}}: {}", err);
-            return (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                "Database error",
-            )
-                .into_response();
+            info!("Message history request failed: {}", err);
+            return err.into_response();
         }
     };
+    if receipts {
+        deliver_fetched(state, requesting_user, &mut rows).await;
+    }
+    let timezone = preferred_timezone(state.users.as_ref(), requesting_user).await;
     let messages: Vec<MessageResponse> = rows
         .into_iter()
-        .map(|row| MessageResponse {
-            id: row.try_get::<Uuid, _>("id").unwrap().to_string(),
-            timestamp: row.try_get::<i64, _>("timestamp").unwrap().to_string(),
-            sender_id: row.try_get::<Uuid, _>("sender_id").unwrap().to_string(),
-            receiver_id: row.try_get::<Uuid, _>("receiver_id").unwrap().to_string(),
-            status: row.try_get::<String, _>("status").unwrap_or_default(),
-            r#type: row.try_get::<String, _>("type").unwrap_or_default(),
-            encrypted_content: base64::encode(
-                row.try_get::<Vec<u8>, _>("encrypted_content")
-                    .unwrap_or_default(),
-            ),
-            iv: base64::encode(row.try_get::<Vec<u8>, _>("iv").unwrap_or_default()),
-        })
+        .map(|record| message_response(record, timezone))
         .collect();
-    (axum::http::StatusCode::OK, axum::Json(messages)).into_response()
+    (StatusCode::OK, Json(messages)).into_response()
 }
 
-/// Returns a JSON dump of all users, contacts, and messages for admin viewing.
-/// No authentication required (for demo purposes).
-#[axum::debug_handler]
-/// Returns a JSON dump of all users, contacts, and messages in the database.
-///
-/// This endpoint retrieves all records from the `users`, `contacts`, and `messages` tables,
-/// encoding binary fields such as avatars and encrypted content as base64 strings. No authentication is required.
-/// If any query fails, the corresponding section in the response will be an empty array.
+async fn conversation_page_response(
+    state: &Arc<AppState>,
+    requesting_user: Uuid,
+    other_user: Uuid,
+    query: ConversationQuery,
+    receipts: bool,
+) -> axum::response::Response {
+    let mut page = match service::conversation_page(
+        state.messages.as_ref(),
+        requesting_user,
+        other_user,
+        query,
+    )
+    .await
+    {
+        Ok(page) => page,
+        Err(err) => {
+            info!("Message history page request failed: {}", err);
+            return err.into_response();
+        }
+    };
+    if receipts {
+        deliver_fetched(state, requesting_user, &mut page.items).await;
+    }
+    let timezone = preferred_timezone(state.users.as_ref(), requesting_user).await;
+    let response = MessagePageResponse {
+        items: page
+            .items
+            .into_iter()
+            .map(|record| message_response(record, timezone))
+            .collect(),
+        next_cursor: page
+            .next_cursor
+            .as_ref()
+            .map(service::encode_message_cursor),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+/// Marks the fetched messages addressed to `requesting_user` as DELIVERED and tells their
+/// senders. A failure is logged and the messages are returned as they were read.
+async fn deliver_fetched(state: &Arc<AppState>, requesting_user: Uuid, rows: &mut [MessageRecord]) {
+    match service::mark_fetched_delivered(state.messages.as_ref(), requesting_user, rows).await {
+        Ok(delivered) => broadcast_fetch_receipts(state, requesting_user, &delivered).await,
+        Err(err) => warn!(
+            "Marking fetched messages delivered for {} failed: {}",
+            requesting_user, err
+        ),
+    }
+}
+
+/// Builds a `UserResponse`, rendering `created_at` in UTC and base64-encoding the avatar,
+/// decrypted with `column_key` if it was stored encrypted.
 ///
-/// # Examples
+/// Profiles are cached through ETags shared by every viewer, so they are not rendered in
+/// the viewer's timezone.
+pub(crate) fn user_response(user: UserRecord, column_keys: &ColumnKeyring) -> UserResponse {
+    UserResponse {
+        id: user.id.to_string(),
+        username: user.username,
+        public_key: user.public_key,
+        created_at: format_timestamp(user.created_at, DEFAULT_TIMEZONE),
+        avatar: service::readable_avatar(column_keys, user.id, user.avatar)
+            .map(|a| general_purpose::STANDARD.encode(a)),
+        devices: None,
+    }
+}
+
+/// Adds the user's device keys to a profile when multi-device support is enabled.
+async fn with_device_keys(
+    state: &AppState,
+    user_id: Uuid,
+    mut user: UserResponse,
+) -> Result<UserResponse, crate::repo::RepoError> {
+    if state.multi_device {
+        let devices = state.devices.list_devices(user_id).await?;
+        user.devices = Some(devices.into_iter().map(device_key).collect());
+    }
+    Ok(user)
+}
+
+/// Returns the requested fields that exist in `profile`, sorted and deduplicated.
+///
+/// Unknown names are ignored. `None` means every field, which is also the result when
+/// none of the requested names exist.
+fn field_mask(profile: &Map<String, Value>, fields: Option<&str>) -> Option<Vec<String>> {
+    let mut mask: Vec<String> = fields?
+        .split(',')
+        .map(str::trim)
+        .filter(|field| profile.contains_key(*field))
+        .map(str::to_string)
+        .collect();
+    mask.sort();
+    mask.dedup();
+    if mask.is_empty() { None } else { Some(mask) }
+}
+
+/// ETag for a profile representation: when the profile last changed, plus the field mask.
+fn profile_etag(updated_at: DateTime<Utc>, mask: Option<&[String]>) -> String {
+    let fields = mask
+        .map(|mask| mask.join("+"))
+        .unwrap_or_else(|| "*".to_string());
+    format!("\"{}-{}\"", updated_at.timestamp_micros(), fields)
+}
+
+/// Whether an `If-None-Match` header lists `etag` (weak comparison) or is `*`.
+pub(crate) fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Serializes a profile (`UserProfile` or `UserResponse`) honoring the `fields` mask.
+///
+/// Every response carries an `ETag` derived from `updated_at` and the mask; a request whose
+/// `If-None-Match` matches it gets `304 Not Modified` without a body.
+pub(crate) fn profile_response<T: Serialize>(
+    profile: &T,
+    updated_at: DateTime<Utc>,
+    fields: Option<&str>,
+    headers: &HeaderMap,
+) -> axum::response::Response {
+    let mut profile = match serde_json::to_value(profile) {
+        Ok(Value::Object(map)) => map,
+        _ => return (StatusCode::INTERNAL_SERVER_ERROR, "Serialization error").into_response(),
+    };
+    let mask = field_mask(&profile, fields);
+    let etag = profile_etag(updated_at, mask.as_deref());
+    if etag_matches(headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response();
+    }
+    if let Some(mask) = mask {
+        profile.retain(|field, _| mask.contains(field));
+    }
+    (StatusCode::OK, [(ETAG, etag)], Json(profile)).into_response()
+}
+
+/// Builds a `MessageResponse`, base64-encoding the binary fields and rendering the
+/// timestamp in `timezone`.
+fn message_response(message: MessageRecord, timezone: Tz) -> MessageResponse {
+    MessageResponse {
+        id: message.id.to_string(),
+        timestamp: message.timestamp.to_string(),
+        sent_at: format_millis(message.timestamp, timezone),
+        sender_id: message.sender_id.to_string(),
+        receiver_id: message.receiver_id.to_string(),
+        status: message.status,
+        r#type: message.r#type,
+        encrypted_content: general_purpose::STANDARD.encode(message.encrypted_content),
+        iv: general_purpose::STANDARD.encode(message.iv),
+        forward_count: service::displayed_forward_count(message.forward_count),
+        forwarded_many_times: service::forwarded_many_times(message.forward_count),
+        was_forwarded: message.forwarded_from_id.is_some(),
+        reply_to: message.reply_to_id.map(|id| ReplyReference {
+            message_id: id.to_string(),
+            deleted: message.reply_to_deleted,
+        }),
+        encryption_version: message.encryption_version,
+        cipher_suite: message.cipher_suite,
+        device_id: message.device_id.map(|id| id.to_string()),
+    }
+}
+
+/// Pins or unpins a message for both participants of its conversation.
+async fn set_message_pin(
+    message_id: Uuid,
+    state: Arc<AppState>,
+    headers: HeaderMap,
+    pinned: bool,
+) -> axum::response::Response {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/{{}}/pin endpoint");
+            return e.into_response();
+        }
+    };
+    let change = match service::set_message_pin(
+        state.messages.as_ref(),
+        requesting_user,
+        message_id,
+        pinned,
+    )
+    .await
+    {
+        Ok(change) => change,
+        Err(err) => {
+            info!("Pin update failed for message {}: {}", message_id, err);
+            return err.into_response();
+        }
+    };
+
+    if change.changed {
+        info!(
+            "Message {} {} by user {}",
+            message_id,
+            if pinned { "pinned" } else { "unpinned" },
+            requesting_user
+        );
+        let update = PinUpdate {
+            message_id: message_id.to_string(),
+            pinned,
+            updated_by: requesting_user.to_string(),
+        };
+        broadcast_pin_update_to_user(&state, change.sender_id, update.clone()).await;
+        broadcast_pin_update_to_user(&state, change.receiver_id, update).await;
+    }
+    if pinned {
+        (
+            StatusCode::OK,
+            Json(json!({ "message_id": message_id, "pinned": true })),
+        )
+            .into_response()
+    } else {
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
+
+/// Pins a message in its conversation.
+///
+/// Requires a valid JWT Bearer token; only the sender or receiver of the message may pin it.
+/// Both participants are notified with a `pin_update` WebSocket event.
+pub async fn pin_message(
+    UuidPath(message_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    set_message_pin(message_id, state, headers, true).await
+}
+
+/// Unpins a message in its conversation.
+///
+/// Returns 204 on success and 404 if the message does not exist or is not pinned.
+pub async fn unpin_message(
+    UuidPath(message_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    set_message_pin(message_id, state, headers, false).await
+}
+
+/// Corrects a message's metadata, currently its `type`.
+///
+/// Requires a valid JWT Bearer token; only the sender may update a message, and its
+/// content and participants cannot be changed. The new `type` is validated like on send.
+/// Both participants are notified with a `message_meta_update` WebSocket event.
+pub async fn update_message_meta(
+    UuidPath(message_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(update): Json<service::MessageMetaUpdate>,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/{{}}/meta endpoint");
+            return e.into_response();
+        }
+    };
+    let change = match service::update_message_meta(
+        state.messages.as_ref(),
+        requesting_user,
+        message_id,
+        &update,
+    )
+    .await
+    {
+        Ok(change) => change,
+        Err(err) => {
+            info!("Metadata update failed for message {}: {}", message_id, err);
+            return err.into_response();
+        }
+    };
+
+    if change.changed {
+        info!(
+            "Message {} type set to {} by user {}",
+            message_id, change.r#type, requesting_user
+        );
+        let update = MetaUpdate {
+            message_id: message_id.to_string(),
+            r#type: change.r#type.clone(),
+            updated_by: requesting_user.to_string(),
+        };
+        broadcast_meta_update_to_user(&state, change.sender_id, update.clone()).await;
+        broadcast_meta_update_to_user(&state, change.receiver_id, update).await;
+    }
+    (
+        StatusCode::OK,
+        Json(json!({ "message_id": message_id, "type": change.r#type })),
+    )
+        .into_response()
+}
+
+/// Returns how many messages to the authenticated user are not yet READ, per sender.
+///
+/// The same data is available over the WebSocket with an `unread_counts` message.
+pub async fn get_unread_counts(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/unread-counts endpoint");
+            return e.into_response();
+        }
+    };
+    match state.messages.unread_counts(user_id, None).await {
+        Ok(counts) => (StatusCode::OK, Json(UnreadCounts::from(counts))).into_response(),
+        Err(err) => {
+            info!("Database error in /messages/unread-counts: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// Returns how often a message has been forwarded.
+///
+/// Only the sender or receiver may ask. Like `MessageResponse`, the count is capped at 5
+/// and larger values are reported as `forwarded_many_times`.
+pub async fn get_forward_count(
+    UuidPath(message_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/{{}}/forward-count endpoint");
+            return e.into_response();
+        }
+    };
+    match service::message_forward_count(state.messages.as_ref(), requesting_user, message_id).await
+    {
+        Ok(count) => (
+            StatusCode::OK,
+            Json(ForwardCountResponse {
+                forward_count: service::displayed_forward_count(count),
+                forwarded_many_times: service::forwarded_many_times(count),
+            }),
+        )
+            .into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Returns the receipt the receiver of a message signed when marking it DELIVERED or READ,
+/// in the shape of the `delivery_receipt` WebSocket event. Both participants may fetch it,
+/// also after the message itself was deleted. Responds with 404 if there is no receipt or
+/// the caller is not a participant.
+pub async fn get_message_receipt(
+    UuidPath(message_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/{{}}/receipt endpoint");
+            return e.into_response();
+        }
+    };
+    match service::delivery_receipt(state.messages.as_ref(), requesting_user, message_id).await {
+        Ok(receipt) => {
+            let timezone = preferred_timezone(state.users.as_ref(), requesting_user).await;
+            (
+                StatusCode::OK,
+                Json(delivery_receipt_response(&receipt, timezone)),
+            )
+                .into_response()
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Lists the pinned messages in the conversation between the authenticated user and another user.
+///
+/// Returns a JSON array of messages (same shape as `GET /messages/{user_id}`) with
+/// `pinned_by` and `pinned_at` fields, ordered by message timestamp.
+pub async fn get_pinned_messages(
+    UuidPath(other_user): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/{{}}/pinned endpoint");
+            return e.into_response();
+        }
+    };
+    let rows = match state
+        .messages
+        .pinned_in_conversation(requesting_user, other_user)
+        .await
+    {
+        Ok(records) => records,
+        Err(err) => {
+            info!("Database error in /messages/{{user_id}}/pinned: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let timezone = preferred_timezone(state.users.as_ref(), requesting_user).await;
+    let pinned: Vec<PinnedMessageResponse> = rows
+        .into_iter()
+        .map(|record| PinnedMessageResponse {
+            message: message_response(record.message, timezone),
+            pinned_by: record.pinned_by.to_string(),
+            pinned_at: format_timestamp(record.pinned_at, timezone),
+        })
+        .collect();
+    (StatusCode::OK, Json(pinned)).into_response()
+}
+
+#[derive(serde::Deserialize)]
+pub struct ExportQuery {
+    /// `json` (default) or `csv`.
+    pub format: Option<String>,
+}
+
+/// Conversations up to this many messages are exported in one response body; longer ones
+/// are streamed.
+const EXPORT_BUFFERED_MESSAGES: i64 = 10_000;
+/// Messages fetched per query while streaming an export.
+const EXPORT_BATCH_SIZE: i64 = 500;
+/// How often a user may export the same conversation.
+const EXPORT_WINDOW: Duration = Duration::from_secs(3600);
+const EXPORT_CSV_HEADER: &str =
+    "id,timestamp,sender_id,receiver_id,status,type,encrypted_content_b64,iv_b64\n";
+
+/// Rate limit key of `user_id`'s exports of the conversation with `contact_id`.
+fn export_limit_key(user_id: Uuid, contact_id: Uuid) -> String {
+    format!("export:{}:{}", user_id, contact_id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    fn parse(raw: Option<&str>) -> Option<Self> {
+        match raw {
+            None | Some("json") => Some(ExportFormat::Json),
+            Some("csv") => Some(ExportFormat::Csv),
+            Some(_) => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn filename(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "conversation_export.json",
+            ExportFormat::Csv => "conversation_export.csv",
+        }
+    }
+
+    fn opening(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "[",
+            ExportFormat::Csv => EXPORT_CSV_HEADER,
+        }
+    }
+
+    fn closing(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "]",
+            ExportFormat::Csv => "",
+        }
+    }
+
+    /// Renders a batch of messages; `first` is whether the batch starts the export.
+    fn render(self, messages: Vec<MessageRecord>, timezone: Tz, first: bool) -> String {
+        let mut chunk = String::new();
+        for (i, message) in messages.into_iter().enumerate() {
+            match self {
+                ExportFormat::Json => {
+                    if i > 0 || !first {
+                        chunk.push(',');
+                    }
+                    let response = message_response(message, timezone);
+                    chunk.push_str(&serde_json::to_string(&response).unwrap_or_default());
+                }
+                ExportFormat::Csv => {
+                    let fields = [
+                        message.id.to_string(),
+                        message.timestamp.to_string(),
+                        message.sender_id.to_string(),
+                        message.receiver_id.to_string(),
+                        csv_field(message.status.as_str()),
+                        csv_field(&message.r#type),
+                        general_purpose::STANDARD.encode(message.encrypted_content),
+                        general_purpose::STANDARD.encode(message.iv),
+                    ];
+                    chunk.push_str(&fields.join(","));
+                    chunk.push('\n');
+                }
+            }
+        }
+        chunk
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+type ExportSender = mpsc::Sender<Result<Bytes, std::io::Error>>;
+
+/// Streams an export that did not fit in one body. `first` holds its oldest messages; the
+/// rest are fetched `EXPORT_BATCH_SIZE` at a time after them. A query error cuts the
+/// response off rather than closing it, so a partial export cannot pass for a complete one.
+async fn write_export(
+    messages: Arc<dyn MessageRepo>,
+    user_id: Uuid,
+    contact_id: Uuid,
+    format: ExportFormat,
+    timezone: Tz,
+    first: Vec<MessageRecord>,
+    tx: ExportSender,
+) {
+    let mut cursor = first.last().map(|last| MessageCursor {
+        timestamp: last.timestamp,
+        id: last.id,
+    });
+    let mut chunk = format!(
+        "{}{}",
+        format.opening(),
+        format.render(first, timezone, true)
+    );
+    while let Some(after) = cursor {
+        if tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+            info!("Client disconnected during conversation export");
+            return;
+        }
+        let query = ConversationQuery {
+            order: SortOrder::Asc,
+            cursor: Some(after),
+            limit: Some(EXPORT_BATCH_SIZE),
+            ..Default::default()
+        };
+        let batch = match messages.conversation(user_id, contact_id, query).await {
+            Ok(batch) => batch,
+            Err(err) => {
+                info!(
+                    "Conversation export for user {} failed part-way: {}",
+                    user_id, err
+                );
+                let _ = tx.send(Err(std::io::Error::other(err.to_string()))).await;
+                return;
+            }
+        };
+        cursor = match batch.last() {
+            Some(last) if batch.len() as i64 == EXPORT_BATCH_SIZE => Some(MessageCursor {
+                timestamp: last.timestamp,
+                id: last.id,
+            }),
+            _ => None,
+        };
+        chunk = format.render(batch, timezone, false);
+    }
+    chunk.push_str(format.closing());
+    let _ = tx.send(Ok(Bytes::from(chunk))).await;
+}
+
+/// Exports the whole conversation with the specified user as a file download.
+///
+/// `format` is `json` (an array of messages shaped like `GET /messages/{user_id}`) or
+/// `csv`. Content stays encrypted; only the client can decrypt it. Each user may export a
+/// conversation once an hour. Conversations over `EXPORT_BUFFERED_MESSAGES` messages are
+/// streamed in batches as they are read.
+pub async fn export_conversation(
+    UuidPath(contact_id): UuidPath,
+    Query(query): Query<ExportQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /conversations/{{}}/export endpoint");
+            return e.into_response();
+        }
+    };
+    let Some(format) = ExportFormat::parse(query.format.as_deref()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Invalid format. Expected json or csv",
+        )
+            .into_response();
+    };
+    let limit_key = export_limit_key(requesting_user, contact_id);
+    if let Err(retry_after) = state
+        .rate_limiter
+        .check_and_increment(&limit_key, 1, EXPORT_WINDOW)
+        .await
+        .into_result()
+    {
+        info!(
+            "User {} exported the conversation with {} too recently",
+            requesting_user, contact_id
+        );
+        return service::rate_limited_response(retry_after);
+    }
+    // One extra row tells whether the export has to be streamed
+    let range = ConversationQuery {
+        order: SortOrder::Asc,
+        limit: Some(EXPORT_BUFFERED_MESSAGES + 1),
+        ..Default::default()
+    };
+    let mut messages = match service::conversation_messages(
+        state.messages.as_ref(),
+        requesting_user,
+        contact_id,
+        range,
+    )
+    .await
+    {
+        Ok(records) => records,
+        Err(err) => {
+            info!("Conversation export failed: {}", err);
+            // Nothing was exported, so the attempt does not count
+            state.rate_limiter.refund(&limit_key).await;
+            return err.into_response();
+        }
+    };
+    info!(
+        "User {} exporting conversation with {} as {:?}",
+        requesting_user, contact_id, format
+    );
+    let timezone = preferred_timezone(state.users.as_ref(), requesting_user).await;
+    let response_headers = [
+        (CONTENT_TYPE, format.content_type().to_string()),
+        (
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", format.filename()),
+        ),
+    ];
+    if messages.len() as i64 <= EXPORT_BUFFERED_MESSAGES {
+        let body = format!(
+            "{}{}{}",
+            format.opening(),
+            format.render(messages, timezone, true),
+            format.closing()
+        );
+        return (StatusCode::OK, response_headers, body).into_response();
+    }
+    messages.truncate(EXPORT_BUFFERED_MESSAGES as usize);
+    let (tx, rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+    tokio::spawn(write_export(
+        state.messages.clone(),
+        requesting_user,
+        contact_id,
+        format,
+        timezone,
+        messages,
+        tx,
+    ));
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (StatusCode::OK, response_headers, StreamBody::new(chunks)).into_response()
+}
+
+/// Messages read per query while streaming `GET /messages/backup`.
+const BACKUP_BATCH_SIZE: i64 = 500;
+
+#[derive(serde::Deserialize)]
+pub struct BackupQuery {
+    /// The last message already backed up, as `{timestamp}_{id}`.
+    pub since: Option<String>,
+}
+
+/// Renders messages as NDJSON, one `MessageResponse` per line.
+fn backup_lines(messages: Vec<MessageRecord>, timezone: Tz) -> String {
+    let mut chunk = String::new();
+    for message in messages {
+        chunk.push_str(
+            &serde_json::to_string(&message_response(message, timezone)).unwrap_or_default(),
+        );
+        chunk.push('\n');
+    }
+    chunk
+}
+
+/// Streams the rest of a backup after `first`, `BACKUP_BATCH_SIZE` messages at a time. As
+/// with exports, a query error cuts the response off so a partial backup cannot pass for
+/// a complete one.
+async fn write_backup(
+    messages: Arc<dyn MessageRepo>,
+    user_id: Uuid,
+    timezone: Tz,
+    first: Vec<MessageRecord>,
+    tx: ExportSender,
+) {
+    let mut batch = first;
+    loop {
+        let cursor = match batch.last() {
+            Some(last) if batch.len() as i64 == BACKUP_BATCH_SIZE => Some(MessageCursor {
+                timestamp: last.timestamp,
+                id: last.id,
+            }),
+            _ => None,
+        };
+        if !batch.is_empty()
+            && tx
+                .send(Ok(Bytes::from(backup_lines(batch, timezone))))
+                .await
+                .is_err()
+        {
+            info!("Client disconnected during message backup");
+            return;
+        }
+        let Some(after) = cursor else {
+            return;
+        };
+        batch = match messages
+            .messages_of_user(user_id, Some(after), BACKUP_BATCH_SIZE)
+            .await
+        {
+            Ok(batch) => batch,
+            Err(err) => {
+                info!(
+                    "Message backup for user {} failed part-way: {}",
+                    user_id, err
+                );
+                let _ = tx.send(Err(std::io::Error::other(err.to_string()))).await;
+                return;
+            }
+        };
+    }
+}
+
+/// Streams every message the caller sent or received, in all conversations, as NDJSON:
+/// one message per line shaped like `GET /messages/{user_id}`, oldest first. Content
+/// stays encrypted; only the client can decrypt it.
+///
+/// `since` continues a backup after the message it names as `{timestamp}_{id}`, taken from
+/// the last line already stored, so an interrupted or incremental backup only downloads
+/// what it is missing. Fetching a backup does not mark messages DELIVERED.
+pub async fn backup_messages(
+    Query(query): Query<BackupQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let requesting_user = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /messages/backup endpoint");
+            return e.into_response();
+        }
+    };
+    let since = match query
+        .since
+        .as_deref()
+        .map(service::parse_message_cursor)
+        .transpose()
+    {
+        Ok(since) => since,
+        Err(err) => return err.into_response(),
+    };
+    let first = match state
+        .messages
+        .messages_of_user(requesting_user, since, BACKUP_BATCH_SIZE)
+        .await
+    {
+        Ok(first) => first,
+        Err(err) => {
+            info!(
+                "Message backup for user {} failed: {}",
+                requesting_user, err
+            );
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let timezone = preferred_timezone(state.users.as_ref(), requesting_user).await;
+    let (tx, rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+    tokio::spawn(write_backup(
+        state.messages.clone(),
+        requesting_user,
+        timezone,
+        first,
+        tx,
+    ));
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(chunks),
+    )
+        .into_response()
+}
+
+/// Rows fetched per query while streaming a dump.
+const DUMP_BATCH_SIZE: i64 = 500;
+/// Batches buffered ahead of a slow client; bounds the dump's memory use.
+pub(crate) const DUMP_CHANNEL_CAPACITY: usize = 2;
+
+/// One top-level array of the database dump.
+struct DumpSection {
+    name: &'static str,
+    table: &'static str,
+    columns: &'static str,
+    to_json: fn(&PgRow) -> Result<Value, sqlx::Error>,
+}
+
+const DUMP_SECTIONS: [DumpSection; 3] = [
+    DumpSection {
+        name: "users",
+        table: "users",
+        columns: "id, username, public_key, created_at, avatar",
+        to_json: user_dump_json,
+    },
+    DumpSection {
+        name: "contacts",
+        table: "contacts",
+        columns: "id, name, public_key, last_seen, status, avatar_url",
+        to_json: contact_dump_json,
+    },
+    DumpSection {
+        name: "messages",
+        table: "messages",
+        columns: "id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv, forwarded_from_id, forward_count, reply_to_id, encryption_version, device_id, cipher_suite",
+        to_json: message_dump_json,
+    },
+];
+
+fn user_dump_json(row: &PgRow) -> Result<Value, sqlx::Error> {
+    let id: sqlx::types::Uuid = row.try_get("id")?;
+    let username: String = row.try_get("username")?;
+    let public_key: String = row.try_get("public_key")?;
+    let created_at: DateTime<Utc> = row.try_get("created_at")?;
+    let avatar: Option<Vec<u8>> = nullable(row, "avatar", id)?;
+    Ok(json!({
+        "id": id,
+        "username": username,
+        "public_key": public_key,
+        "created_at": format_timestamp(created_at, DEFAULT_TIMEZONE),
+        "avatar": avatar.map(|a| general_purpose::STANDARD.encode(a)),
+    }))
+}
+
+fn contact_dump_json(row: &PgRow) -> Result<Value, sqlx::Error> {
+    let id: sqlx::types::Uuid = row.try_get("id")?;
+    let name: String = row.try_get("name")?;
+    let public_key: String = row.try_get("public_key")?;
+    let last_seen: String = row.try_get("last_seen")?;
+    let status: Option<String> = nullable(row, "status", id)?;
+    let avatar_url: Option<String> = nullable(row, "avatar_url", id)?;
+    Ok(json!({
+        "id": id,
+        "name": name,
+        "public_key": public_key,
+        "last_seen": last_seen,
+        "status": status,
+        "avatar_url": avatar_url,
+    }))
+}
+
+fn message_dump_json(row: &PgRow) -> Result<Value, sqlx::Error> {
+    let id: sqlx::types::Uuid = row.try_get("id")?;
+    let timestamp_millis: Option<i64> = nullable(row, "timestamp", id)?;
+    let sender_id: sqlx::types::Uuid = row.try_get("sender_id")?;
+    let receiver_id: sqlx::types::Uuid = row.try_get("receiver_id")?;
+    let status: Option<String> = nullable(row, "status", id)?;
+    let r#type: Option<String> = nullable(row, "type", id)?;
+    let encrypted_content: Option<Vec<u8>> = nullable(row, "encrypted_content", id)?;
+    let iv: Option<Vec<u8>> = nullable(row, "iv", id)?;
+    let forwarded_from_id: Option<sqlx::types::Uuid> = nullable(row, "forwarded_from_id", id)?;
+    let forward_count: i32 = nullable(row, "forward_count", id)?.unwrap_or(0);
+    let reply_to_id: Option<sqlx::types::Uuid> = nullable(row, "reply_to_id", id)?;
+    let encryption_version: i16 = nullable(row, "encryption_version", id)?.unwrap_or(1);
+    let device_id: Option<sqlx::types::Uuid> = nullable(row, "device_id", id)?;
+    let cipher_suite: String = row.try_get("cipher_suite")?;
+    Ok(json!({
+        "id": id,
+        // `null` when the stored value is missing or out of range; `timestamp_millis` keeps
+        // it as stored so corrupt rows stay visible
+        "timestamp": timestamp_millis.and_then(|millis| try_format_millis(millis, DEFAULT_TIMEZONE)),
+        "timestamp_millis": timestamp_millis,
+        "sender_id": sender_id,
+        "receiver_id": receiver_id,
+        "status": status,
+        "type": r#type,
+        "encrypted_content": encrypted_content.map(|ec| general_purpose::STANDARD.encode(ec)),
+        "iv": iv.map(|iv| general_purpose::STANDARD.encode(iv)),
+        "forwarded_from_id": forwarded_from_id,
+        "forward_count": forward_count,
+        "reply_to_id": reply_to_id,
+        "encryption_version": encryption_version,
+        "device_id": device_id,
+        "cipher_suite": cipher_suite,
+    }))
+}
+
+/// Serializes a batch of array elements, with a leading comma unless it starts the array.
+fn dump_batch_chunk(starts_array: bool, rows: &[Value]) -> String {
+    let mut chunk = String::new();
+    for (i, row) in rows.iter().enumerate() {
+        if i > 0 || !starts_array {
+            chunk.push(',');
+        }
+        chunk.push_str(&row.to_string());
+    }
+    chunk
+}
+
+pub(crate) type DumpSender = mpsc::Sender<Result<Bytes, std::io::Error>>;
+
+/// Sends a chunk of the dump, returning false once the client has gone away.
+pub(crate) async fn send_dump_chunk(tx: &DumpSender, chunk: String) -> bool {
+    tx.send(Ok(Bytes::from(chunk))).await.is_ok()
+}
+
+/// Ends the response with an error, so the client sees the transfer break off instead of
+/// a body that merely stops.
+pub(crate) async fn abort_dump(tx: &DumpSender, reason: &str) {
+    let _ = tx
+        .send(Err(std::io::Error::other(reason.to_string())))
+        .await;
+}
+
+/// How writing a dump section ended.
+enum SectionEnd {
+    Done,
+    Disconnected,
+    Failed,
+}
+
+/// Writes one section's rows in id order, `DUMP_BATCH_SIZE` at a time. A query error, or a
+/// row whose columns do not decode, is logged and fails the section.
+async fn write_dump_section(
+    conn: &mut PgConnection,
+    tx: &DumpSender,
+    section: &DumpSection,
+) -> SectionEnd {
+    let query = format!(
+        "SELECT {} FROM {} WHERE ($1::uuid IS NULL OR id > $1) ORDER BY id LIMIT $2",
+        section.columns, section.table
+    );
+    let mut last_id: Option<Uuid> = None;
+    loop {
+        let rows = match sqlx::query(&query)
+            .bind(last_id)
+            .bind(DUMP_BATCH_SIZE)
+            .fetch_all(&mut *conn)
+            .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Database error dumping {}: {}", section.name, e);
+                return SectionEnd::Failed;
+            }
+        };
+        let Some(last_row) = rows.last() else {
+            return SectionEnd::Done;
+        };
+        let decoded = last_row.try_get::<Uuid, _>("id").and_then(|id| {
+            Ok((
+                id,
+                rows.iter()
+                    .map(section.to_json)
+                    .collect::<Result<Vec<Value>, _>>()?,
+            ))
+        });
+        let (next_id, batch) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                error!("Row error dumping {}: {}", section.name, e);
+                return SectionEnd::Failed;
+            }
+        };
+        if !send_dump_chunk(tx, dump_batch_chunk(last_id.is_none(), &batch)).await {
+            return SectionEnd::Disconnected;
+        }
+        if (rows.len() as i64) < DUMP_BATCH_SIZE {
+            return SectionEnd::Done;
+        }
+        last_id = Some(next_id);
+    }
+}
+
+/// Writes every section from one read-only snapshot, so rows that change while the dump
+/// streams cannot appear twice or reference rows missing from an earlier section. A
+/// database error aborts the response, so the client sees a failed transfer rather than a
+/// dump that silently lacks rows.
+async fn write_db_dump(db: PgPool, tx: DumpSender) {
+    let mut conn = match db
+        .begin_with_isolation(IsolationLevel::RepeatableRead)
+        .await
+    {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to start /admin/dbdump: {}", e);
+            abort_dump(&tx, "Database error").await;
+            return;
+        }
+    };
+    if let Err(e) = sqlx::query("SET TRANSACTION READ ONLY")
+        .execute(&mut *conn)
+        .await
+    {
+        error!("Failed to start /admin/dbdump: {}", e);
+        abort_dump(&tx, "Database error").await;
+        return;
+    }
+    for (i, section) in DUMP_SECTIONS.iter().enumerate() {
+        let open = format!("{}\"{}\":[", if i == 0 { "{" } else { "," }, section.name);
+        if !send_dump_chunk(&tx, open).await {
+            info!("Client disconnected during /admin/dbdump");
+            return;
+        }
+        match write_dump_section(&mut conn, &tx, section).await {
+            SectionEnd::Done => {}
+            SectionEnd::Disconnected => {
+                info!("Client disconnected during /admin/dbdump");
+                return;
+            }
+            SectionEnd::Failed => {
+                abort_dump(&tx, "Database error").await;
+                return;
+            }
+        }
+        if !send_dump_chunk(&tx, "]".to_string()).await {
+            info!("Client disconnected during /admin/dbdump");
+            return;
+        }
+    }
+    send_dump_chunk(&tx, "}".to_string()).await;
+}
+
+/// Returns a JSON dump of all users, contacts, and messages in the database.
+///
+/// This endpoint retrieves all records from the `users`, `contacts`, and `messages` tables,
+/// encoding binary fields such as avatars and encrypted content as base64 strings.
+///
+/// The response is streamed with chunked encoding: rows are fetched in batches of
+/// `DUMP_BATCH_SIZE` and written as they arrive, so memory use does not grow with the
+/// database. All sections are read from one snapshot; if a query fails, the response is
+/// aborted.
+///
+/// # Examples
 ///
 /// ```
 /// // Example Axum route registration:
@@ -421,99 +2103,632 @@ pub async fn get_messages_with_user(
 /// //   "messages": [ ... ]
 /// // }
 /// ```
+#[axum::debug_handler]
 pub async fn db_dump(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    // Fetch users
-    let users =
-        match sqlx::query(r#"SELECT id, username, public_key, created_at, avatar FROM users"#)
-            .fetch_all(&state.db)
-            .await
-        {
-            Ok(rows) => rows
-                .into_iter()
-                .map(|row| {
-                    let id: sqlx::types::Uuid = row.try_get("id").unwrap();
-                    let username: String = row.try_get("username").unwrap();
-                    let public_key: String = row.try_get("public_key").unwrap();
-                    let created_at_utc: DateTime<Utc> = row.try_get("created_at").unwrap();
-                    let created_at_brussels = created_at_utc.with_timezone(&Brussels);
-                    let avatar: Option<Vec<u8>> = row.try_get("avatar").ok().flatten();
-                    json!({
-                        "id": id,
-                        "username": username,
-                        "public_key": public_key,
-                        "created_at": created_at_brussels.to_rfc3339(),
-                        "avatar": avatar.map(|a| general_purpose::STANDARD.encode(a)),
-                    })
-                })
-                .collect::<Vec<_>>(),
-            Err(_) => vec![],
-        };
-    // Fetch contacts
-    let contacts = match sqlx::query(
-        r#"SELECT id, name, public_key, last_seen, status, avatar_url FROM contacts"#,
-    )
-    .fetch_all(&state.db)
-    .await
-    {
-        Ok(rows) => rows
-            .into_iter()
-            .map(|row| {
-                let id: sqlx::types::Uuid = row.try_get("id").unwrap();
-                let name: String = row.try_get("name").unwrap();
-                let public_key: String = row.try_get("public_key").unwrap();
-                let last_seen: String = row.try_get("last_seen").unwrap();
-                let status: Option<String> = row.try_get("status").ok().flatten();
-                let avatar_url: Option<String> = row.try_get("avatar_url").ok().flatten();
-                json!({
-                    "id": id,
-                    "name": name,
-                    "public_key": public_key,
-                    "last_seen": last_seen,
-                    "status": status,
-                    "avatar_url": avatar_url,
-                })
-            })
-            .collect::<Vec<_>>(),
-        Err(_) => vec![],
-    };
-    // Fetch messages
-    let messages = match sqlx::query(r#"SELECT id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv FROM messages"#)
-        .fetch_all(&state.db)
-        .await {
-            Ok(rows) => rows.into_iter().map(|row| {
-                let id: sqlx::types::Uuid = row.try_get("id").unwrap();
-                let timestamp_millis: i64 = row.try_get("timestamp").unwrap_or(0);
-                // Convert Unix timestamp to Brussels timezone for display
-                let timestamp_utc = DateTime::from_timestamp_millis(timestamp_millis).unwrap_or_else(|| Utc::now());
-                let timestamp_brussels = timestamp_utc.with_timezone(&Brussels);
-                let sender_id: sqlx::types::Uuid = row.try_get("sender_id").unwrap();
-                let receiver_id: sqlx::types::Uuid = row.try_get("receiver_id").unwrap();
-                let status: Option<String> = row.try_get("status").ok().flatten();
-                let r#type: Option<String> = row.try_get("type").ok().flatten();
-                let encrypted_content: Option<Vec<u8>> = row.try_get("encrypted_content").ok().flatten();
-                let iv: Option<Vec<u8>> = row.try_get("iv").ok().flatten();
-                json!({
-                    "id": id,
-                    "timestamp": timestamp_brussels.to_rfc3339(),
-                    "sender_id": sender_id,
-                    "receiver_id": receiver_id,
-                    "status": status,
-                    "type": r#type,
-                    "encrypted_content": encrypted_content.map(|ec| general_purpose::STANDARD.encode(ec)),
-                    "iv": iv.map(|iv| general_purpose::STANDARD.encode(iv)),
-                })
-            }).collect::<Vec<_>>(),
-            Err(_) => vec![],
-        };
+    let (tx, rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+    tokio::spawn(write_db_dump(state.db.clone(), tx));
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
     (
         StatusCode::OK,
-        Json(json!({
-            "users": users,
-            "contacts": contacts,
-            "messages": messages,
-        })),
+        [(CONTENT_TYPE, "application/json")],
+        StreamBody::new(chunks),
     )
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::InMemoryBackend;
+    use crate::repo::MessageStatus;
+    use axum::body::HttpBody;
+    use std::time::Instant;
+
+    fn alice() -> UserResponse {
+        UserResponse {
+            id: Uuid::new_v4().to_string(),
+            username: "alice".to_string(),
+            public_key: "key".to_string(),
+            created_at: "2024-01-01T00:00:00+01:00".to_string(),
+            avatar: Some("aGVsbG8=".to_string()),
+            devices: None,
+        }
+    }
+
+    async fn body_json(response: axum::response::Response) -> Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_matching_etag_returns_not_modified() {
+        let updated_at = Utc::now();
+        let response = profile_response(&alice(), updated_at, None, &HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, etag.clone());
+        let response = profile_response(&alice(), updated_at, None, &headers);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag);
+        assert!(
+            response
+                .into_body()
+                .collect()
+                .await
+                .unwrap()
+                .to_bytes()
+                .is_empty()
+        );
+
+        // Weak validators and lists are accepted
+        let weak = format!("\"other\", W/{}", etag.to_str().unwrap());
+        headers.insert(IF_NONE_MATCH, weak.parse().unwrap());
+        let response = profile_response(&alice(), updated_at, None, &headers);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // A profile change invalidates the ETag
+        headers.insert(IF_NONE_MATCH, etag);
+        let later = updated_at + chrono::Duration::seconds(1);
+        let response = profile_response(&alice(), later, None, &headers);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_field_mask_ignores_unknown_fields() {
+        let updated_at = Utc::now();
+        let headers = HeaderMap::new();
+        let response = profile_response(
+            &alice(),
+            updated_at,
+            Some("username, public_key,shoe_size"),
+            &headers,
+        );
+        let masked_etag = response.headers()[ETAG].clone();
+        let body = body_json(response).await;
+        assert_eq!(body, json!({ "username": "alice", "public_key": "key" }));
+
+        // Only unknown names falls back to the full profile
+        let response = profile_response(&alice(), updated_at, Some("shoe_size"), &headers);
+        let full_etag = response.headers()[ETAG].clone();
+        let body = body_json(response).await;
+        assert!(body.get("avatar").is_some());
+        assert_ne!(masked_etag, full_etag);
+    }
+
+    #[test]
+    fn test_dump_batches_form_valid_json() {
+        let rows: Vec<Value> = (0..5).map(|i| json!({ "id": i })).collect();
+        let mut document = "{\"users\":[".to_string();
+        document.push_str(&dump_batch_chunk(true, &rows[..2]));
+        document.push_str(&dump_batch_chunk(false, &rows[2..]));
+        document.push_str("],\"contacts\":[]}");
+
+        let parsed: Value = serde_json::from_str(&document).unwrap();
+        assert_eq!(parsed["users"].as_array().unwrap().len(), 5);
+        assert_eq!(parsed["users"][4]["id"], 4);
+        assert_eq!(dump_batch_chunk(false, &[]), "");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_dump_keeps_out_of_range_timestamps_visible() {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a migrated database");
+        let db = PgPool::connect(&url).await.unwrap();
+        let user: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (username, password_hash, public_key) VALUES ($1, 'hash', $1) RETURNING id",
+        )
+        .bind(format!("dump-{}", Uuid::new_v4().simple()))
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let (valid, corrupt) = (Uuid::new_v4(), Uuid::new_v4());
+        for (id, timestamp) in [(valid, 1_715_342_400_000), (corrupt, i64::MAX)] {
+            sqlx::query(
+                "INSERT INTO messages (id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv) VALUES ($1, $2, $3, $3, 'SENT', 'Text', '\\x01', '\\x00')",
+            )
+            .bind(id)
+            .bind(timestamp)
+            .bind(user)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let section = &DUMP_SECTIONS[2];
+        let query = format!("SELECT {} FROM messages WHERE id = $1", section.columns);
+        let dump = |id: Uuid| {
+            let (db, query) = (db.clone(), query.clone());
+            async move {
+                let row = sqlx::query(&query).bind(id).fetch_one(&db).await.unwrap();
+                (section.to_json)(&row).unwrap()
+            }
+        };
+
+        let row = dump(valid).await;
+        assert_eq!(row["timestamp"], "2024-05-10T12:00:00+00:00");
+        assert_eq!(row["timestamp_millis"], 1_715_342_400_000i64);
+        let row = dump(corrupt).await;
+        assert_eq!(row["timestamp"], Value::Null);
+        assert_eq!(row["timestamp_millis"], i64::MAX);
+
+        sqlx::query("DELETE FROM messages WHERE sender_id = $1")
+            .bind(user)
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    /// The dump's body, or `Err` with what was sent before the response was aborted.
+    async fn collect_dump(db: PgPool) -> Result<String, String> {
+        let (tx, mut rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+        tokio::spawn(write_db_dump(db, tx));
+        let mut body = String::new();
+        while let Some(chunk) = rx.recv().await {
+            match chunk {
+                Ok(chunk) => body.push_str(std::str::from_utf8(&chunk).unwrap()),
+                Err(_) => return Err(body),
+            }
+        }
+        Ok(body)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_dump_is_one_complete_document() {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a migrated database");
+        let db = PgPool::connect(&url).await.unwrap();
+        let dump: Value = serde_json::from_str(&collect_dump(db.clone()).await.unwrap()).unwrap();
+        for section in DUMP_SECTIONS {
+            assert!(
+                dump[section.name].is_array(),
+                "missing section {}",
+                section.name
+            );
+        }
+
+        // A dump that cannot start aborts the response before sending anything
+        db.close().await;
+        assert_eq!(collect_dump(db).await, Err(String::new()));
+    }
+
+    #[test]
+    fn test_export_rate_limit_is_per_conversation() {
+        let limiter = InMemoryBackend::new();
+        let try_acquire_export = |user_id: Uuid, contact_id: Uuid, now: Instant| {
+            limiter
+                .check_and_increment_at(
+                    &export_limit_key(user_id, contact_id),
+                    1,
+                    EXPORT_WINDOW,
+                    now,
+                )
+                .into_result()
+        };
+        let (user, contact, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert_eq!(try_acquire_export(user, contact, now), Ok(()));
+        let later = now + Duration::from_secs(600);
+        assert_eq!(
+            try_acquire_export(user, contact, later),
+            Err(Duration::from_secs(3000))
+        );
+        assert_eq!(try_acquire_export(user, other, later), Ok(()));
+        assert_eq!(try_acquire_export(contact, user, later), Ok(()));
+        assert_eq!(
+            try_acquire_export(user, contact, now + EXPORT_WINDOW),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_signature_is_rate_limited_per_address() {
+        let (state, _) = crate::websocket::tests::fake_state(false);
+        // RFC 8032 section 7.1, test 2
+        let request = || {
+            VerifySignatureRequest {
+            public_key: "MCowBQYDK2VwAyEAPUAXw+hDiVqStwqnTRt+vJyYLM8uxJaMwM1V8Sr0Zgw=".to_string(),
+            message: "cg==".to_string(),
+            signature: "kqAJqfDUyrhyDoILX2QlQKKye1QWUD+Ps3YiI+vbadoIWsHkPhWZbkWPNhPQ8R2MOHsurrQwKu6wDSkWErsMAA==".to_string(),
+        }
+        };
+        let verify = |peer: &str, request: VerifySignatureRequest| {
+            let state = state.clone();
+            let peer: SocketAddr = peer.parse().unwrap();
+            async move {
+                verify_signature(
+                    State(state),
+                    ConnectInfo(peer),
+                    HeaderMap::new(),
+                    Json(request),
+                )
+                .await
+                .into_response()
+            }
+        };
+
+        let response = verify("10.0.0.1:4000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await, json!({ "valid": true }));
+        let tampered = VerifySignatureRequest {
+            message: "cw==".to_string(),
+            ..request()
+        };
+        assert_eq!(
+            body_json(verify("10.0.0.1:4000", tampered).await).await,
+            json!({ "valid": false })
+        );
+        let garbled = VerifySignatureRequest {
+            signature: "not base64!".to_string(),
+            ..request()
+        };
+        assert_eq!(
+            verify("10.0.0.1:4000", garbled).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        for _ in 3..VERIFY_SIGNATURE_LIMIT {
+            assert_eq!(
+                verify("10.0.0.1:4001", request()).await.status(),
+                StatusCode::OK
+            );
+        }
+        let response = verify("10.0.0.1:4002", request()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            verify("10.0.0.2:4000", request()).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    fn export_message(sender_id: Uuid, receiver_id: Uuid, timestamp: i64) -> MessageRecord {
+        MessageRecord {
+            id: Uuid::new_v4(),
+            timestamp,
+            sender_id,
+            receiver_id,
+            status: MessageStatus::Read,
+            r#type: "Text".to_string(),
+            encrypted_content: vec![1, 2, 3],
+            iv: vec![0; 12],
+            forwarded_from_id: None,
+            forward_count: 0,
+            reply_to_id: None,
+            reply_to_deleted: false,
+            encryption_version: 1,
+            device_id: None,
+            cipher_suite: "AES_256_GCM".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_csv_export_quotes_free_text_fields() {
+        let mut message = export_message(Uuid::new_v4(), Uuid::new_v4(), 1_700_000_000_000);
+        message.r#type = "Note, \"quoted\"".to_string();
+        let csv = format!(
+            "{}{}",
+            ExportFormat::Csv.opening(),
+            ExportFormat::Csv.render(vec![message.clone()], DEFAULT_TIMEZONE, true)
+        );
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], EXPORT_CSV_HEADER.trim_end());
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},1700000000000,{},{},READ,\"Note, \"\"quoted\"\"\",AQID,AAAAAAAAAAAAAAAA",
+                message.id, message.sender_id, message.receiver_id
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_reports_every_broken_rule_without_sending() {
+        let (state, messages) = crate::websocket::tests::fake_state(true);
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let receiver = state
+            .users
+            .create_user("receiver", "hash", "key2")
+            .await
+            .unwrap();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(sender, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        let validate = |body: Value| {
+            let (state, headers) = (state.clone(), headers.clone());
+            async move {
+                let response = validate_message(State(state), headers, Json(body))
+                    .await
+                    .into_response();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = body_json(response).await;
+                let codes: Vec<String> = body["errors"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|error| error["code"].as_str().unwrap().to_string())
+                    .collect();
+                assert_eq!(body["ok"], codes.is_empty());
+                codes
+            }
+        };
+
+        // Nothing encrypted yet: content, iv and message_id may be left out
+        let draft = json!({ "receiver_id": receiver.to_string(), "type": "Image", "encrypted_content_bytes": 1024 });
+        assert_eq!(validate(draft).await, vec!["not_a_contact"]);
+        // Added through the repo, so the cached relationships are dropped by hand
+        state
+            .contacts
+            .add_contact(receiver, sender, 10)
+            .await
+            .unwrap();
+        state.relationships.clear();
+        let draft = json!({ "receiver_id": receiver.to_string(), "type": "Image", "encrypted_content_bytes": 1024 });
+        assert!(validate(draft).await.is_empty());
+
+        let broken = json!({
+            "message_id": "not-a-uuid",
+            "receiver_id": Uuid::new_v4().to_string(),
+            "type": "Video",
+            "cipher_suite": "ROT13",
+            "encrypted_content": "not base64!",
+            "iv": "AAAAAAAAAAAAAAAA",
+        });
+        assert_eq!(
+            validate(broken).await,
+            vec![
+                "invalid_field",
+                "unsupported_cipher_suite",
+                "invalid_message_type",
+                "invalid_field",
+                "unknown_receiver",
+            ]
+        );
+        let oversized = json!({
+            "receiver_id": receiver.to_string(),
+            "type": "File",
+            "encrypted_content_bytes": state.ws_max_frame_bytes,
+        });
+        assert_eq!(validate(oversized).await, vec!["frame_too_large"]);
+        let other_conversation = messages.seed_message(Uuid::new_v4(), sender, MessageStatus::Read);
+        let misplaced_reply = json!({
+            "receiver_id": receiver.to_string(),
+            "type": "Text",
+            "reply_to": other_conversation.to_string(),
+        });
+        assert_eq!(
+            validate(misplaced_reply).await,
+            vec!["invalid_reply_target"]
+        );
+        assert!(
+            messages
+                .unread_counts(receiver, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let response = validate_message(
+            State(state.clone()),
+            headers.clone(),
+            Json(json!({ "type": "Text" })),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_delete_conversation_clears_it_for_both_participants() {
+        use crate::websocket::WSEvent;
+        let (state, messages) = crate::websocket::tests::fake_state(false);
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        messages.seed_message_at(alice, bob, MessageStatus::Sent, 1_000);
+        messages.seed_message_at(bob, alice, MessageStatus::Read, 2_000);
+        let kept = messages.seed_message_at(alice, carol, MessageStatus::Sent, 3_000);
+        let mut channels = Vec::new();
+        for user in [alice, bob, carol] {
+            let (tx, rx) = tokio::sync::broadcast::channel(16);
+            state.connections.insert(user, tx);
+            channels.push(rx);
+        }
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(alice, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+
+        let response = delete_conversation(UuidPath(bob), State(state.clone()), headers)
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await, json!({ "deleted": 2 }));
+        assert!(
+            messages
+                .conversation(alice, bob, Default::default())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(messages.status_of(kept), Some(MessageStatus::Sent));
+
+        // Each participant hears about it with the other one as `user_id`
+        for (rx, peer) in channels.iter_mut().zip([bob, alice]) {
+            match rx.try_recv().unwrap() {
+                WSEvent::ConversationCleared(cleared) => {
+                    assert_eq!(cleared.user_id, peer.to_string());
+                    assert_eq!(cleared.cleared_by, alice.to_string());
+                    assert_eq!(cleared.deleted_count, 2);
+                }
+                other => panic!("expected conversation_cleared, got {:?}", other),
+            }
+        }
+        assert!(channels[2].try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backup_streams_every_conversation_in_order() {
+        let repo = Arc::new(crate::repo::fake::FakeMessageRepo::new());
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let total = BACKUP_BATCH_SIZE + 3;
+        for timestamp in 0..total {
+            // Alternate conversations and directions
+            match timestamp % 3 {
+                0 => repo.seed_message_at(alice, bob, MessageStatus::Read, timestamp),
+                1 => repo.seed_message_at(carol, alice, MessageStatus::Sent, timestamp),
+                _ => repo.seed_message_at(alice, alice, MessageStatus::Read, timestamp),
+            };
+        }
+        repo.seed_message_at(bob, carol, MessageStatus::Read, 1);
+
+        let backup = |since: Option<MessageCursor>| {
+            let repo = repo.clone();
+            async move {
+                let first = repo
+                    .messages_of_user(alice, since, BACKUP_BATCH_SIZE)
+                    .await
+                    .unwrap();
+                let (tx, mut rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+                tokio::spawn(write_backup(repo, alice, DEFAULT_TIMEZONE, first, tx));
+                let mut body = Vec::new();
+                while let Some(chunk) = rx.recv().await {
+                    body.extend_from_slice(&chunk.unwrap());
+                }
+                String::from_utf8(body)
+                    .unwrap()
+                    .lines()
+                    .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                    .collect::<Vec<_>>()
+            }
+        };
+        let timestamp = |m: &Value| m["timestamp"].as_str().unwrap().parse::<i64>().unwrap();
+
+        let lines = backup(None).await;
+        assert_eq!(
+            lines.iter().map(timestamp).collect::<Vec<_>>(),
+            (0..total).collect::<Vec<_>>()
+        );
+        assert_eq!(lines[0]["encrypted_content"], "AQID");
+
+        // Resuming after a line only returns what came after it
+        let resumed_from = &lines[BACKUP_BATCH_SIZE as usize - 1];
+        let since = service::parse_message_cursor(&format!(
+            "{}_{}",
+            timestamp(resumed_from),
+            resumed_from["id"].as_str().unwrap()
+        ))
+        .unwrap();
+        let rest = backup(Some(since)).await;
+        assert_eq!(
+            rest.iter().map(timestamp).collect::<Vec<_>>(),
+            (BACKUP_BATCH_SIZE..total).collect::<Vec<_>>()
+        );
+        assert!(
+            backup(Some(MessageCursor {
+                timestamp: total,
+                id: Uuid::nil()
+            }))
+            .await
+            .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_export_includes_every_message_once() {
+        let repo = Arc::new(crate::repo::fake::FakeMessageRepo::new());
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let total = EXPORT_BATCH_SIZE * 2 + 7;
+        for timestamp in 0..total {
+            repo.seed_message_at(alice, bob, MessageStatus::Read, timestamp);
+        }
+        // The handler passes the first messages it already read
+        let first = repo
+            .conversation(
+                alice,
+                bob,
+                ConversationQuery {
+                    order: SortOrder::Asc,
+                    limit: Some(10),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+        tokio::spawn(write_export(
+            repo.clone(),
+            alice,
+            bob,
+            ExportFormat::Json,
+            DEFAULT_TIMEZONE,
+            first,
+            tx,
+        ));
+        let mut document = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            document.extend_from_slice(&chunk.unwrap());
+        }
+
+        let exported: Vec<Value> = serde_json::from_slice(&document).unwrap();
+        let timestamps: Vec<i64> = exported
+            .iter()
+            .map(|m| m["timestamp"].as_str().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(timestamps, (0..total).collect::<Vec<_>>());
+    }
 
+    #[tokio::test]
+    async fn test_malformed_uuid_segment_is_rejected_with_param_name() {
+        use tower::ServiceExt;
 
+        let app = axum::Router::new().route(
+            "/messages/:user_id",
+            axum::routing::get(|UuidPath(id): UuidPath| async move { id.to_string() }),
+        );
+        let request = |uri: String| {
+            axum::http::Request::builder()
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("/messages/not-a-uuid".into()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body_json(response).await,
+            json!({ "error": "invalid_uuid", "param": "user_id" })
+        );
+
+        let id = Uuid::new_v4();
+        let response = app
+            .oneshot(request(format!("/messages/{}", id)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, id.to_string().as_bytes());
+    }
+}
@@ -0,0 +1,714 @@
+//! NDJSON backup and restore for Safe Chat backend
+//!
+//! `GET /admin/export.ndjson` streams one JSON object per row, each with a `table` field
+//! naming the table it came from, followed by a summary record:
+//!
+//! ```text
+//! {"table": "users", "id": "…", "username": "alice", …}
+//! {"table": "messages", "id": "…", "timestamp": 1715342400000, …}
+//! {"summary":{"rows":{"users":1,"messages":1},"sha256":"…"}}
+//! ```
+//!
+//! Rows are the table's columns as Postgres renders them with `to_jsonb`, so they restore
+//! losslessly (`bytea` as `\x…` hex). The checksum is the SHA-256 of every row line,
+//! newline included. An export that ends without a summary was cut short.
+//!
+//! `POST /admin/import.ndjson` restores such a stream into an empty database, in one
+//! transaction. It is disabled unless `ALLOW_NDJSON_IMPORT` is set.
+
+use crate::api::{DUMP_CHANNEL_CAPACITY, DumpSender, abort_dump, send_dump_chunk};
+use crate::db::{IsolationLevel, WithIsolation};
+use crate::state::AppState;
+
+use axum::body::{Bytes, StreamBody};
+use axum::extract::{BodyStream, Query, State};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt, TryStreamExt, stream};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// Bytes of NDJSON collected before a chunk is sent to the client.
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+/// Longest line `import.ndjson` accepts; a row is never near this unless the file is corrupt.
+const MAX_IMPORT_LINE_BYTES: usize = 16 * 1024 * 1024;
+
+/// A table that can be exported and restored.
+struct BackupTable {
+    name: &'static str,
+    /// Columns of the primary key, which rows are exported in the order of.
+    key: &'static str,
+    /// Condition selecting rows created at or after `$1` (a `timestamptz`), for tables
+    /// that record when a row was created.
+    since_filter: Option<&'static str>,
+    /// Whether `id` is a `BIGSERIAL`, whose sequence a restore moves past the restored ids.
+    serial_id: bool,
+}
+
+/// Every table of the schema except the copies old migrations left behind
+/// (`users_backup_keys`, `messages_backup_content`). Exported in this order whatever order
+/// they are requested in, so rows are restored after the rows they reference.
+const BACKUP_TABLES: [BackupTable; 21] = [
+    BackupTable {
+        name: "users",
+        key: "id",
+        since_filter: Some("created_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "devices",
+        key: "id",
+        since_filter: Some("created_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "contacts",
+        key: "id",
+        since_filter: None,
+        serial_id: false,
+    },
+    BackupTable {
+        name: "messages",
+        key: "id",
+        since_filter: Some("timestamp >= (EXTRACT(EPOCH FROM $1::timestamptz) * 1000)::bigint"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "message_pins",
+        key: "message_id",
+        since_filter: Some("pinned_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "delivery_attempts",
+        key: "id",
+        since_filter: Some("attempt_at >= $1"),
+        serial_id: true,
+    },
+    BackupTable {
+        name: "user_contacts",
+        key: "owner_id, contact_id",
+        since_filter: Some("created_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "conversation_peers",
+        key: "user_id, peer_id",
+        since_filter: None,
+        serial_id: false,
+    },
+    BackupTable {
+        name: "conversation_settings",
+        key: "user_id, peer_id",
+        since_filter: None,
+        serial_id: false,
+    },
+    BackupTable {
+        name: "contact_requests",
+        key: "id",
+        since_filter: Some("created_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "notification_prefs",
+        key: "user_id",
+        since_filter: None,
+        serial_id: false,
+    },
+    BackupTable {
+        name: "username_changes",
+        key: "id",
+        since_filter: Some("changed_at >= $1"),
+        serial_id: true,
+    },
+    BackupTable {
+        name: "deleted_usernames",
+        key: "username",
+        since_filter: Some("deleted_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "key_changes",
+        key: "id",
+        since_filter: Some("changed_at >= $1"),
+        serial_id: true,
+    },
+    BackupTable {
+        name: "send_counters",
+        key: "user_id, window_name, window_start",
+        since_filter: None,
+        serial_id: false,
+    },
+    BackupTable {
+        name: "message_routing",
+        key: "routing_id",
+        since_filter: None,
+        serial_id: false,
+    },
+    BackupTable {
+        name: "sealed_messages",
+        key: "id",
+        since_filter: None,
+        serial_id: false,
+    },
+    BackupTable {
+        name: "delivery_receipts",
+        key: "message_id",
+        since_filter: Some("received_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "invites",
+        key: "id",
+        since_filter: Some("created_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "announcements",
+        key: "id",
+        since_filter: Some("created_at >= $1"),
+        serial_id: false,
+    },
+    BackupTable {
+        name: "admin_audit_log",
+        key: "id",
+        since_filter: Some("created_at >= $1"),
+        serial_id: true,
+    },
+];
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// Comma-separated tables to export; all of them when absent.
+    pub tables: Option<String>,
+    /// RFC 3339 instant; tables with a creation time only export rows created since.
+    pub since: Option<String>,
+}
+
+fn backup_table(name: &str) -> Option<&'static BackupTable> {
+    BACKUP_TABLES.iter().find(|table| table.name == name)
+}
+
+/// Resolves the `tables` parameter to the tables to export, in export order.
+fn parse_tables(raw: Option<&str>) -> Result<Vec<&'static BackupTable>, String> {
+    let Some(raw) = raw else {
+        return Ok(BACKUP_TABLES.iter().collect());
+    };
+    let mut requested = Vec::new();
+    for name in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match backup_table(name) {
+            Some(table) => requested.push(table.name),
+            None => return Err(format!("Unknown table: {}", name)),
+        }
+    }
+    if requested.is_empty() {
+        return Err("No tables requested".to_string());
+    }
+    Ok(BACKUP_TABLES
+        .iter()
+        .filter(|table| requested.contains(&table.name))
+        .collect())
+}
+
+fn export_query(table: &BackupTable, since: bool) -> String {
+    let filter = match table.since_filter {
+        Some(filter) if since => format!(" WHERE {}", filter),
+        _ => String::new(),
+    };
+    format!(
+        "SELECT (jsonb_build_object('table', '{name}') || to_jsonb(t))::text AS line FROM {name} t{filter} ORDER BY {key}",
+        name = table.name,
+        filter = filter,
+        key = table.key
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn summary_line(rows: &BTreeMap<&str, u64>, checksum: &str) -> String {
+    let summary = json!({ "summary": { "rows": rows, "sha256": checksum } });
+    format!("{}\n", summary)
+}
+
+/// Streams each table's rows from a single snapshot, then the summary. A database error
+/// aborts the response without a summary.
+async fn write_export(
+    db: PgPool,
+    tables: Vec<&'static BackupTable>,
+    since: Option<DateTime<Utc>>,
+    tx: DumpSender,
+) {
+    let mut conn = match db
+        .begin_with_isolation(IsolationLevel::RepeatableRead)
+        .await
+    {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to start NDJSON export: {}", e);
+            abort_dump(&tx, "Database error").await;
+            return;
+        }
+    };
+    if let Err(e) = sqlx::query("SET TRANSACTION READ ONLY")
+        .execute(&mut *conn)
+        .await
+    {
+        error!("Failed to start NDJSON export: {}", e);
+        abort_dump(&tx, "Database error").await;
+        return;
+    }
+    let mut hasher = Sha256::new();
+    let mut rows: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut chunk = String::new();
+    for table in tables {
+        let query = export_query(table, since.is_some());
+        let mut query = sqlx::query(&query);
+        if let Some(since) = since
+            && table.since_filter.is_some()
+        {
+            query = query.bind(since);
+        }
+        let count = rows.entry(table.name).or_default();
+        let mut lines = query.fetch(&mut *conn);
+        while let Some(row) = lines.next().await {
+            let line: String = match row.and_then(|row| row.try_get("line")) {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Database error exporting {}: {}", table.name, e);
+                    send_dump_chunk(&tx, chunk).await;
+                    abort_dump(&tx, "Database error").await;
+                    return;
+                }
+            };
+            hasher.update(line.as_bytes());
+            hasher.update(b"\n");
+            chunk.push_str(&line);
+            chunk.push('\n');
+            *count += 1;
+            if chunk.len() >= EXPORT_CHUNK_BYTES
+                && !send_dump_chunk(&tx, std::mem::take(&mut chunk)).await
+            {
+                info!("Client disconnected during /admin/export.ndjson");
+                return;
+            }
+        }
+    }
+    chunk.push_str(&summary_line(&rows, &hex(&hasher.finalize())));
+    send_dump_chunk(&tx, chunk).await;
+}
+
+/// Streams the requested tables as NDJSON for backup tooling.
+///
+/// `tables` is a comma-separated subset of `BACKUP_TABLES`; `since` restricts the tables
+/// that record a creation time to rows created at or after it, for incremental backups. Rows are read through a streaming query on one snapshot, so memory
+/// use does not grow with the table size.
+pub async fn export_ndjson(
+    Query(query): Query<ExportQuery>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let tables = match parse_tables(query.tables.as_deref()) {
+        Ok(tables) => tables,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let since = match query.since.as_deref().map(DateTime::parse_from_rfc3339) {
+        None => None,
+        Some(Ok(since)) => Some(since.with_timezone(&Utc)),
+        Some(Err(_)) => {
+            return (StatusCode::BAD_REQUEST, "Invalid since timestamp").into_response();
+        }
+    };
+    let (tx, rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+    tokio::spawn(write_export(state.db.clone(), tables, since, tx));
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(chunks),
+    )
+        .into_response()
+}
+
+/// Validates an export line by line: every row belongs to a known table, the summary
+/// comes last, and its counts and checksum match the rows before it.
+#[derive(Default)]
+struct ImportCheck {
+    hasher: Sha256,
+    rows: BTreeMap<&'static str, u64>,
+    summary: Option<Value>,
+}
+
+impl ImportCheck {
+    /// Parses one line, returning the table and columns of a row, or `None` for the summary.
+    fn line(&mut self, line: &str) -> Result<Option<(&'static BackupTable, Value)>, String> {
+        if self.summary.is_some() {
+            return Err("Rows after the summary".to_string());
+        }
+        let mut object: Map<String, Value> =
+            serde_json::from_str(line).map_err(|e| format!("Invalid JSON line: {}", e))?;
+        if let Some(summary) = object.remove("summary") {
+            self.summary = Some(summary);
+            return Ok(None);
+        }
+        let table = match object.remove("table") {
+            Some(Value::String(name)) => {
+                backup_table(&name).ok_or_else(|| format!("Unknown table: {}", name))?
+            }
+            _ => return Err("Row without a table".to_string()),
+        };
+        self.hasher.update(line.as_bytes());
+        self.hasher.update(b"\n");
+        *self.rows.entry(table.name).or_default() += 1;
+        Ok(Some((table, Value::Object(object))))
+    }
+
+    /// Checks the summary against the rows seen, returning the row counts.
+    fn finish(self) -> Result<BTreeMap<&'static str, u64>, String> {
+        let summary = self
+            .summary
+            .ok_or("Missing summary; the export is incomplete")?;
+        if summary["sha256"].as_str() != Some(hex(&self.hasher.finalize()).as_str()) {
+            return Err("Checksum mismatch".to_string());
+        }
+        let expected = summary["rows"]
+            .as_object()
+            .ok_or("Summary without row counts")?;
+        let counts_match = expected.iter().all(|(table, count)| {
+            count.as_u64().unwrap_or(0) == self.rows.get(table.as_str()).copied().unwrap_or(0)
+        }) && self.rows.keys().all(|table| expected.contains_key(*table));
+        if !counts_match {
+            return Err("Row counts do not match the summary".to_string());
+        }
+        Ok(self.rows)
+    }
+}
+
+enum ImportError {
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for ImportError {
+    fn from(e: sqlx::Error) -> Self {
+        ImportError::Database(e)
+    }
+}
+
+async fn import_line(
+    conn: &mut Transaction<'_, Postgres>,
+    check: &mut ImportCheck,
+    line: &[u8],
+) -> Result<(), ImportError> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| ImportError::Invalid("Line is not UTF-8".to_string()))?
+        .trim_end_matches('\r');
+    if line.is_empty() {
+        return Ok(());
+    }
+    let Some((table, columns)) = check.line(line).map_err(ImportError::Invalid)? else {
+        return Ok(());
+    };
+    let insert = format!(
+        "INSERT INTO {name} SELECT * FROM jsonb_populate_record(NULL::{name}, $1::jsonb)",
+        name = table.name
+    );
+    sqlx::query(&insert)
+        .bind(columns.to_string())
+        .execute(&mut **conn)
+        .await
+        .map_err(|e| match e {
+            // Constraint violations mean the file does not fit this database
+            sqlx::Error::Database(db) => ImportError::Invalid(format!("Row rejected: {}", db)),
+            e => ImportError::Database(e),
+        })?;
+    Ok(())
+}
+
+/// Moves the sequence of each restored `BIGSERIAL` id past the largest restored id, so new
+/// rows do not collide with them.
+async fn advance_sequences(conn: &mut Transaction<'_, Postgres>) -> Result<(), sqlx::Error> {
+    for table in BACKUP_TABLES.iter().filter(|table| table.serial_id) {
+        let query = format!(
+            "SELECT setval(pg_get_serial_sequence('{name}', 'id'), MAX(id)) FROM {name} HAVING MAX(id) IS NOT NULL",
+            name = table.name
+        );
+        sqlx::query(&query).execute(&mut **conn).await?;
+    }
+    Ok(())
+}
+
+/// Inserts every row of `body` through `conn`, checking it against the summary. The
+/// caller commits.
+async fn import_into<S, E>(
+    conn: &mut Transaction<'_, Postgres>,
+    mut body: S,
+) -> Result<BTreeMap<&'static str, u64>, ImportError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    for table in &BACKUP_TABLES {
+        let query = format!("SELECT EXISTS (SELECT 1 FROM {}) AS has_rows", table.name);
+        let has_rows: bool = sqlx::query(&query)
+            .fetch_one(&mut **conn)
+            .await?
+            .try_get("has_rows")?;
+        if has_rows {
+            return Err(ImportError::Invalid(format!(
+                "Table {} is not empty; restore into an empty database",
+                table.name
+            )));
+        }
+    }
+    let mut check = ImportCheck::default();
+    let mut pending: Vec<u8> = Vec::new();
+    while let Some(chunk) = body
+        .try_next()
+        .await
+        .map_err(|e| ImportError::Invalid(format!("Failed to read body: {}", e)))?
+    {
+        pending.extend_from_slice(&chunk);
+        let mut start = 0;
+        while let Some(end) = pending[start..].iter().position(|byte| *byte == b'\n') {
+            import_line(conn, &mut check, &pending[start..start + end]).await?;
+            start += end + 1;
+        }
+        pending.drain(..start);
+        if pending.len() > MAX_IMPORT_LINE_BYTES {
+            return Err(ImportError::Invalid("Line too long".to_string()));
+        }
+    }
+    import_line(conn, &mut check, &pending).await?;
+    let rows = check.finish().map_err(ImportError::Invalid)?;
+    advance_sequences(conn).await?;
+    Ok(rows)
+}
+
+async fn import_stream(
+    db: &PgPool,
+    body: BodyStream,
+) -> Result<BTreeMap<&'static str, u64>, ImportError> {
+    let mut conn = db.begin().await?;
+    let rows = import_into(&mut conn, body).await?;
+    conn.commit().await?;
+    Ok(rows)
+}
+
+/// Restores an `export.ndjson` stream into an empty database.
+///
+/// Every row is inserted in one transaction, which is only committed once the summary's
+/// counts and checksum match, so a truncated or edited file leaves the database untouched.
+/// Returns 403 unless `ALLOW_NDJSON_IMPORT` is set, 400 if the file is invalid or does
+/// not fit the schema, and 200 with the restored row counts.
+pub async fn import_ndjson(
+    State(state): State<Arc<AppState>>,
+    body: BodyStream,
+) -> impl IntoResponse {
+    if !state.allow_ndjson_import {
+        return (StatusCode::FORBIDDEN, "NDJSON import is disabled").into_response();
+    }
+    match import_stream(&state.db, body).await {
+        Ok(rows) => {
+            info!("Restored NDJSON import: {:?}", rows);
+            (StatusCode::OK, axum::Json(json!({ "rows": rows }))).into_response()
+        }
+        Err(ImportError::Invalid(message)) => {
+            info!("Rejected NDJSON import: {}", message);
+            (StatusCode::BAD_REQUEST, message).into_response()
+        }
+        Err(ImportError::Database(e)) => {
+            error!("Database error in /admin/import.ndjson: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tables_keeps_export_order() {
+        let names = |tables: Vec<&BackupTable>| tables.iter().map(|t| t.name).collect::<Vec<_>>();
+        assert_eq!(
+            names(parse_tables(None).unwrap()).len(),
+            BACKUP_TABLES.len()
+        );
+        assert_eq!(
+            names(parse_tables(Some("messages, users")).unwrap()),
+            ["users", "messages"]
+        );
+        assert!(parse_tables(Some("users,passwords")).is_err());
+        assert!(parse_tables(Some("")).is_err());
+    }
+
+    fn export_lines() -> Vec<String> {
+        let rows = [
+            r#"{"table":"users","id":"6f1c0d52-5f0c-4a8e-9d43-3f4a2b1c0e01","username":"alice"}"#,
+            r#"{"table":"messages","id":"6f1c0d52-5f0c-4a8e-9d43-3f4a2b1c0e02","timestamp":1}"#,
+        ];
+        let mut hasher = Sha256::new();
+        for row in rows {
+            hasher.update(row.as_bytes());
+            hasher.update(b"\n");
+        }
+        let counts = BTreeMap::from([("users", 1), ("messages", 1)]);
+        let mut lines: Vec<String> = rows.iter().map(|row| row.to_string()).collect();
+        lines.push(
+            summary_line(&counts, &hex(&hasher.finalize()))
+                .trim_end()
+                .to_string(),
+        );
+        lines
+    }
+
+    fn check(lines: &[String]) -> Result<BTreeMap<&'static str, u64>, String> {
+        let mut check = ImportCheck::default();
+        for line in lines {
+            check.line(line)?;
+        }
+        check.finish()
+    }
+
+    #[test]
+    fn test_import_check_accepts_export() {
+        let rows = check(&export_lines()).unwrap();
+        assert_eq!(rows.get("users"), Some(&1));
+        assert_eq!(rows.get("messages"), Some(&1));
+    }
+
+    #[test]
+    fn test_import_check_rejects_tampering() {
+        let mut edited = export_lines();
+        edited[0] = edited[0].replace("alice", "mallory");
+        assert_eq!(check(&edited).unwrap_err(), "Checksum mismatch");
+
+        let truncated = export_lines()[..2].to_vec();
+        assert!(
+            check(&truncated)
+                .unwrap_err()
+                .starts_with("Missing summary")
+        );
+
+        let mut trailing = export_lines();
+        trailing.push(trailing[0].clone());
+        assert_eq!(check(&trailing).unwrap_err(), "Rows after the summary");
+    }
+
+    /// Tables that are deliberately not exported: sqlx's bookkeeping and the copies old
+    /// migrations made before rewriting a table.
+    const NOT_BACKED_UP: [&str; 3] = [
+        "_sqlx_migrations",
+        "users_backup_keys",
+        "messages_backup_content",
+    ];
+
+    async fn migrated_db() -> PgPool {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a migrated database");
+        PgPool::connect(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_backup_tables_cover_schema() {
+        let db = migrated_db().await;
+        let tables: Vec<String> = sqlx::query_scalar(
+            "SELECT table_name::text FROM information_schema.tables \
+             WHERE table_schema = current_schema() AND table_type = 'BASE TABLE'",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        let mut live: Vec<&str> = tables
+            .iter()
+            .map(String::as_str)
+            .filter(|table| !NOT_BACKED_UP.contains(table))
+            .collect();
+        let mut backed_up: Vec<&str> = BACKUP_TABLES.iter().map(|table| table.name).collect();
+        live.sort_unstable();
+        backed_up.sort_unstable();
+        assert_eq!(live, backed_up, "a table is missing from BACKUP_TABLES");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_export_restores_every_table() {
+        let db = migrated_db().await;
+        let (tx, mut rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+        tokio::spawn(write_export(
+            db.clone(),
+            BACKUP_TABLES.iter().collect(),
+            None,
+            tx,
+        ));
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk);
+        }
+        let export: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.as_ref().unwrap().to_vec())
+            .collect();
+        let summary_line = std::str::from_utf8(&export)
+            .unwrap()
+            .lines()
+            .last()
+            .unwrap();
+        let summary: Value = serde_json::from_str(summary_line).unwrap();
+        let exported = summary["summary"]["rows"].as_object().unwrap();
+        let mut exported_tables: Vec<&str> = exported.keys().map(String::as_str).collect();
+        let mut expected: Vec<&str> = BACKUP_TABLES.iter().map(|table| table.name).collect();
+        exported_tables.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(exported_tables, expected);
+
+        // Empty temporary copies shadow the real tables for the rest of the transaction,
+        // which is rolled back
+        let mut conn = db.begin().await.unwrap();
+        for table in &BACKUP_TABLES {
+            sqlx::query(&format!(
+                "CREATE TEMP TABLE {name} (LIKE {name} INCLUDING DEFAULTS) ON COMMIT DROP",
+                name = table.name
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        }
+        if let Err(e) = import_into(&mut conn, stream::iter(chunks)).await {
+            match e {
+                ImportError::Invalid(message) => panic!("export did not restore: {}", message),
+                ImportError::Database(e) => panic!("database error: {}", e),
+            }
+        }
+        for table in &BACKUP_TABLES {
+            let query = format!("SELECT COUNT(*) FROM {}", table.name);
+            let count: i64 = sqlx::query_scalar(&query)
+                .fetch_one(&mut *conn)
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(count as u64),
+                exported[table.name].as_u64(),
+                "{} was not fully restored",
+                table.name
+            );
+        }
+        conn.rollback().await.unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! Database circuit breaker for Safe Chat backend
+//!
+//! When Postgres is down or the pool is exhausted, every request would otherwise wait for
+//! a connection until the pool times out, piling up work on an instance that cannot
+//! finish it. `DB_CIRCUIT_BREAKER` counts consecutive outage errors (see `db::is_outage`)
+//! as they are converted to `RepoError`. After `DB_BREAKER_FAILURE_THRESHOLD` of them in a
+//! row the breaker opens, and `db_circuit_layer` answers requests with a fast 503 for
+//! `DB_BREAKER_COOLDOWN_SECS`. Then it half-opens: a single request is let through to
+//! probe the database. A query that succeeds closes the breaker again (see
+//! `repo::RecordOutcome`); another outage error reopens it for a new cooldown. Times come
+//! from the `Clock` passed to `configure`, the same one as `AppState::clock`.
+//!
+//! Paths that never touch the database are exempt, and `/health/ready` reports the
+//! breaker's state so load balancers can route around the instance.
+
+use axum::Json;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::clock::Clock;
+
+/// Consecutive outage errors that open the breaker unless `DB_BREAKER_FAILURE_THRESHOLD`
+/// is set. 0 disables the breaker.
+pub const DEFAULT_DB_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+/// How long the breaker stays open unless `DB_BREAKER_COOLDOWN_SECS` is set.
+pub const DEFAULT_DB_BREAKER_COOLDOWN_SECS: u64 = 10;
+
+/// Served while the breaker is open: they do not use the database, and load balancers
+/// need the health checks to see the instance's state.
+const EXEMPT_PATHS: &[&str] = &[
+    "/",
+    "/health",
+    "/health/ready",
+    "/version",
+    "/server-info",
+    "/ws/schema",
+];
+
+/// The breaker shared by every request and every repository query.
+pub static DB_CIRCUIT_BREAKER: CircuitBreaker = CircuitBreaker::new(
+    DEFAULT_DB_BREAKER_FAILURE_THRESHOLD,
+    Duration::from_secs(DEFAULT_DB_BREAKER_COOLDOWN_SECS),
+);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Closed,
+    /// Requests are rejected until `until`.
+    Open {
+        until: DateTime<Utc>,
+    },
+    /// One probe request was let through at `probe_sent`; the rest are rejected until it
+    /// either reaches the database or fails.
+    HalfOpen {
+        probe_sent: DateTime<Utc>,
+    },
+}
+
+struct BreakerState {
+    consecutive_failures: u32,
+    phase: Phase,
+}
+
+pub struct CircuitBreaker {
+    failure_threshold: AtomicU32,
+    cooldown_ms: AtomicU64,
+    state: Mutex<BreakerState>,
+    /// Until `configure` sets one, the wall clock.
+    clock: Mutex<Option<Arc<dyn Clock>>>,
+}
+
+/// The breaker's state as reported by `/health/ready`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct BreakerStatus {
+    /// `closed`, `open` or `half_open`.
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+    /// Seconds until the breaker half-opens; only set while it is open.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub const fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: AtomicU32::new(failure_threshold),
+            cooldown_ms: AtomicU64::new(cooldown.as_millis() as u64),
+            state: Mutex::new(BreakerState {
+                consecutive_failures: 0,
+                phase: Phase::Closed,
+            }),
+            clock: Mutex::new(None),
+        }
+    }
+
+    /// Replaces the thresholds and the clock, keeping the current state.
+    pub fn configure(&self, failure_threshold: u32, cooldown: Duration, clock: Arc<dyn Clock>) {
+        self.failure_threshold
+            .store(failure_threshold, Ordering::Relaxed);
+        self.cooldown_ms
+            .store(cooldown.as_millis() as u64, Ordering::Relaxed);
+        *self.clock.lock().unwrap() = Some(clock);
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_millis(self.cooldown_ms.load(Ordering::Relaxed))
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        match &*self.clock.lock().unwrap() {
+            Some(clock) => clock.now(),
+            None => Utc::now(),
+        }
+    }
+
+    /// Whether a request may use the database. `Err` carries how long the caller should
+    /// wait before trying again.
+    pub fn allow_request(&self) -> Result<(), Duration> {
+        self.allow_request_at(self.now())
+    }
+
+    fn allow_request_at(&self, now: DateTime<Utc>) -> Result<(), Duration> {
+        let cooldown = self.cooldown();
+        let mut state = self.state.lock().unwrap();
+        match state.phase {
+            Phase::Closed => Ok(()),
+            Phase::Open { until } if now < until => Err(elapsed(now, until)),
+            Phase::Open { .. } => {
+                warn!("db_circuit: cooldown over, letting a request probe the database");
+                state.phase = Phase::HalfOpen { probe_sent: now };
+                Ok(())
+            }
+            // A probe that never reached the database (e.g. it failed authentication first)
+            // must not keep the breaker half-open forever
+            Phase::HalfOpen { probe_sent } if elapsed(probe_sent, now) >= cooldown => {
+                state.phase = Phase::HalfOpen { probe_sent: now };
+                Ok(())
+            }
+            Phase::HalfOpen { probe_sent } => Err(cooldown - elapsed(probe_sent, now)),
+        }
+    }
+
+    /// Records that a query reached the database, closing the breaker.
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.phase != Phase::Closed {
+            warn!("db_circuit: database reachable again, closing the breaker");
+            state.phase = Phase::Closed;
+        }
+        state.consecutive_failures = 0;
+    }
+
+    /// Records an outage error, opening the breaker once the threshold is reached or when
+    /// the half-open probe failed.
+    pub fn record_failure(&self) {
+        self.record_failure_at(self.now())
+    }
+
+    fn record_failure_at(&self, now: DateTime<Utc>) {
+        let threshold = self.failure_threshold.load(Ordering::Relaxed);
+        if threshold == 0 {
+            return;
+        }
+        let cooldown = self.cooldown();
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        match state.phase {
+            Phase::HalfOpen { .. } => {
+                warn!(
+                    "db_circuit: probe failed, rejecting database requests for {}s",
+                    cooldown.as_secs()
+                );
+                state.phase = Phase::Open {
+                    until: now + to_delta(cooldown),
+                };
+            }
+            Phase::Closed if state.consecutive_failures >= threshold => {
+                warn!(
+                    "db_circuit: {} consecutive database failures, rejecting database requests for {}s",
+                    state.consecutive_failures,
+                    cooldown.as_secs()
+                );
+                state.phase = Phase::Open {
+                    until: now + to_delta(cooldown),
+                };
+            }
+            Phase::Closed | Phase::Open { .. } => {}
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        self.status_at(self.now())
+    }
+
+    fn status_at(&self, now: DateTime<Utc>) -> BreakerStatus {
+        let state = self.state.lock().unwrap();
+        let (name, retry_after_secs) = match state.phase {
+            Phase::Closed => ("closed", None),
+            // Reported as open until a request actually half-opens it
+            Phase::Open { until } => ("open", Some(retry_after_secs(elapsed(now, until)))),
+            Phase::HalfOpen { .. } => ("half_open", None),
+        };
+        BreakerStatus {
+            state: name,
+            consecutive_failures: state.consecutive_failures,
+            retry_after_secs,
+        }
+    }
+}
+
+/// Time from `from` to `to`, zero if `to` is earlier (e.g. the clock was set back).
+fn elapsed(from: DateTime<Utc>, to: DateTime<Utc>) -> Duration {
+    (to - from).to_std().unwrap_or_default()
+}
+
+fn to_delta(duration: Duration) -> TimeDelta {
+    TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX)
+}
+
+/// Whole seconds to wait, rounded up and at least 1, for `Retry-After`.
+fn retry_after_secs(wait: Duration) -> u64 {
+    (wait.as_millis().div_ceil(1000) as u64).max(1)
+}
+
+fn database_unavailable_response(wait: Duration) -> Response {
+    let secs = retry_after_secs(wait);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, secs.to_string())],
+        Json(json!({ "error": "database_unavailable", "retry_after": secs })),
+    )
+        .into_response()
+}
+
+/// Answers 503 `{"error": "database_unavailable"}` with `Retry-After` while
+/// `DB_CIRCUIT_BREAKER` rejects requests, without calling the handler.
+pub async fn db_circuit_layer<B>(request: Request<B>, next: Next<B>) -> Response {
+    if !EXEMPT_PATHS.contains(&request.uri().path())
+        && let Err(wait) = DB_CIRCUIT_BREAKER.allow_request()
+    {
+        return database_unavailable_response(wait);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use chrono::TimeZone;
+
+    const COOLDOWN: Duration = Duration::from_secs(10);
+
+    fn start_time() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        let now = start_time();
+        breaker.record_failure_at(now);
+        breaker.record_failure_at(now);
+        // A success in between resets the count
+        breaker.record_success();
+        breaker.record_failure_at(now);
+        breaker.record_failure_at(now);
+        assert_eq!(breaker.allow_request_at(now), Ok(()));
+
+        breaker.record_failure_at(now);
+        assert_eq!(breaker.allow_request_at(now), Err(COOLDOWN));
+        let status = breaker.status_at(now + TimeDelta::milliseconds(2500));
+        assert_eq!(status.state, "open");
+        assert_eq!(status.consecutive_failures, 3);
+        assert_eq!(status.retry_after_secs, Some(8));
+    }
+
+    #[test]
+    fn test_half_open_lets_one_probe_through() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let start = start_time();
+        breaker.record_failure_at(start);
+
+        let after_cooldown = start + to_delta(COOLDOWN);
+        assert_eq!(breaker.allow_request_at(after_cooldown), Ok(()));
+        assert_eq!(breaker.status_at(after_cooldown).state, "half_open");
+        assert!(breaker.allow_request_at(after_cooldown).is_err());
+
+        breaker.record_success();
+        assert_eq!(breaker.status_at(after_cooldown).state, "closed");
+        assert_eq!(breaker.allow_request_at(after_cooldown), Ok(()));
+        assert_eq!(breaker.status_at(after_cooldown).consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_failed_probe_reopens() {
+        let breaker = CircuitBreaker::new(5, COOLDOWN);
+        let start = start_time();
+        for _ in 0..5 {
+            breaker.record_failure_at(start);
+        }
+        let probe = start + to_delta(COOLDOWN);
+        assert_eq!(breaker.allow_request_at(probe), Ok(()));
+        // One failure is enough while half-open
+        breaker.record_failure_at(probe);
+        assert_eq!(breaker.allow_request_at(probe), Err(COOLDOWN));
+    }
+
+    #[test]
+    fn test_stale_probe_allows_another() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let start = start_time();
+        breaker.record_failure_at(start);
+        assert_eq!(breaker.allow_request_at(start + to_delta(COOLDOWN)), Ok(()));
+        assert!(
+            breaker
+                .allow_request_at(start + to_delta(COOLDOWN) + TimeDelta::seconds(1))
+                .is_err()
+        );
+        assert_eq!(
+            breaker.allow_request_at(start + to_delta(COOLDOWN * 2)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_uses_configured_clock() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let clock = Arc::new(MockClock::new(start_time()));
+        breaker.configure(1, COOLDOWN, clock.clone());
+        breaker.record_failure();
+        assert_eq!(breaker.status().retry_after_secs, Some(10));
+
+        clock.advance(TimeDelta::seconds(4));
+        assert_eq!(breaker.allow_request(), Err(Duration::from_secs(6)));
+        clock.advance(TimeDelta::seconds(6));
+        assert_eq!(breaker.allow_request(), Ok(()));
+        assert_eq!(breaker.status().state, "half_open");
+    }
+
+    #[test]
+    fn test_zero_threshold_disables() {
+        let breaker = CircuitBreaker::new(0, COOLDOWN);
+        let now = start_time();
+        for _ in 0..100 {
+            breaker.record_failure_at(now);
+        }
+        assert_eq!(breaker.allow_request_at(now), Ok(()));
+    }
+
+    #[test]
+    fn test_retry_after_rounds_up() {
+        assert_eq!(retry_after_secs(Duration::ZERO), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1001)), 2);
+        assert_eq!(retry_after_secs(Duration::from_secs(10)), 10);
+    }
+}
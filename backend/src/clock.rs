@@ -0,0 +1,76 @@
+//! Clock module for Safe Chat backend
+//!
+//! Handlers and background tasks read the current time from `AppState::clock` instead of
+//! calling `Utc::now()` directly, so time-dependent rules (token lifetimes, session expiry,
+//! retention cutoffs) can be tested with a `MockClock` that is pinned and advanced by hand.
+//! Production always uses `SystemClock`.
+//!
+//! JWT signature checks in `decode_jwt_token` still compare `exp` against the system time,
+//! since `jsonwebtoken` reads it itself.
+
+use chrono::{DateTime, Utc};
+#[cfg(any(test, feature = "test-utils"))]
+use std::sync::Mutex;
+
+/// Source of the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The real wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(any(test, feature = "test-utils"))]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MockClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+#[cfg_attr(not(test), allow(dead_code))]
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_mock_clock_only_moves_when_told() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+        clock.advance(chrono::Duration::minutes(90));
+        assert_eq!(clock.now(), start + chrono::Duration::minutes(90));
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
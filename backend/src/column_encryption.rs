@@ -0,0 +1,196 @@
+//! Column encryption maintenance for Safe Chat backend
+//!
+//! With `COLUMN_ENCRYPTION_KEY` set, avatars are encrypted with AES-256-GCM before they are
+//! stored (see `crypto::encrypt_column`). `POST /admin/rotate-column-key` stores every
+//! avatar again under a new key, in batches, reporting progress as NDJSON while it runs.
+//! Without an old key it encrypts the avatars still stored in the clear, which is how an
+//! existing deployment turns encryption on.
+
+use crate::announcements::audit_actor;
+use crate::api::{DUMP_CHANNEL_CAPACITY, DumpSender, send_dump_chunk};
+use crate::crypto::ColumnKey;
+use crate::repo::AuditActor;
+use crate::service::{self, ColumnRotateBatch, DEFAULT_COLUMN_ROTATE_BATCH};
+use crate::state::AppState;
+
+use axum::body::StreamBody;
+use axum::extract::{ConnectInfo, Json, Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use futures_util::stream;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct RotateQuery {
+    /// Avatars read per batch; defaults to `DEFAULT_COLUMN_ROTATE_BATCH`.
+    pub batch_size: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RotateColumnKeyRequest {
+    /// The key the avatars are encrypted under now, as 32 bytes in base64. Omitted when
+    /// encrypting avatars stored in the clear.
+    pub old_key: Option<String>,
+    /// The key to encrypt them under, as 32 bytes in base64.
+    pub new_key: String,
+}
+
+/// Stores every avatar encrypted under `new_key`, `batch_size` avatars at a time.
+///
+/// Responds with one NDJSON line per batch, then a line with `"done": true`, the totals
+/// and the users whose avatar could be decrypted with neither key. Each rewrite is
+/// recorded in the admin audit log. The run goes on if the client disconnects, and
+/// running it again skips avatars already under `new_key`. Instances keep using their
+/// configured keys; with `new_key` current and `old_key` in `COLUMN_ENCRYPTION_OLD_KEYS`
+/// they read avatars under either while the run goes on.
+pub async fn rotate_column_key(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<RotateQuery>,
+    Json(payload): Json<RotateColumnKeyRequest>,
+) -> impl IntoResponse {
+    let old_key = match payload
+        .old_key
+        .as_deref()
+        .map(ColumnKey::from_base64)
+        .transpose()
+    {
+        Ok(key) => key,
+        Err(reason) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid old_key: {}", reason),
+            )
+                .into_response();
+        }
+    };
+    let new_key = match ColumnKey::from_base64(&payload.new_key) {
+        Ok(key) => key,
+        Err(reason) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid new_key: {}", reason),
+            )
+                .into_response();
+        }
+    };
+    if old_key
+        .as_ref()
+        .is_some_and(|old_key| old_key.id() == new_key.id())
+    {
+        return (StatusCode::BAD_REQUEST, "new_key must differ from old_key").into_response();
+    }
+    let actor = audit_actor(&state, &headers, peer);
+    let batch_size = query.batch_size.unwrap_or(DEFAULT_COLUMN_ROTATE_BATCH);
+    // The first batch runs before responding, so a bad batch size is a plain 400
+    let first = match service::rotate_avatar_batch(
+        state.users.as_ref(),
+        old_key.as_ref(),
+        &new_key,
+        None,
+        batch_size,
+        &actor,
+    )
+    .await
+    {
+        Ok(batch) => batch,
+        Err(err) => {
+            info!("Rotating the column key failed: {}", err);
+            return err.into_response();
+        }
+    };
+    let (tx, rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+    let keys = RotationKeys { old_key, new_key };
+    tokio::spawn(run_rotation(state, keys, batch_size, actor, first, tx));
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(chunks),
+    )
+        .into_response()
+}
+
+struct RotationKeys {
+    old_key: Option<ColumnKey>,
+    new_key: ColumnKey,
+}
+
+/// Runs the batches after `first`, sending a progress line after each.
+async fn run_rotation(
+    state: Arc<AppState>,
+    keys: RotationKeys,
+    batch_size: i64,
+    actor: AuditActor,
+    first: ColumnRotateBatch,
+    tx: DumpSender,
+) {
+    let (mut scanned, mut rotated) = (0, 0);
+    let mut failed: Vec<Uuid> = Vec::new();
+    let mut next = Ok(first);
+    for batch_number in 1.. {
+        let batch = match next {
+            Ok(batch) => batch,
+            Err(err) => {
+                error!(
+                    "Rotating the column key stopped after {} avatars: {}",
+                    scanned, err
+                );
+                let line = json!({ "error": "Database error", "scanned": scanned, "rotated": rotated, "failed": failed.len() });
+                send_line(&tx, line).await;
+                return;
+            }
+        };
+        scanned += batch.scanned;
+        rotated += batch.rotated;
+        failed.extend(batch.failed);
+        let Some(after) = batch.next_after else {
+            break;
+        };
+        send_line(
+            &tx,
+            json!({ "batch": batch_number, "scanned": scanned, "rotated": rotated, "failed": failed.len() }),
+        )
+        .await;
+        next = service::rotate_avatar_batch(
+            state.users.as_ref(),
+            keys.old_key.as_ref(),
+            &keys.new_key,
+            Some(after),
+            batch_size,
+            &actor,
+        )
+        .await;
+    }
+    info!(
+        "Re-encrypted {} of {} avatars under column key {} ({} failed), requested from {}",
+        rotated,
+        scanned,
+        keys.new_key.id(),
+        failed.len(),
+        actor.source_ip
+    );
+    let line = json!({
+        "done": true,
+        "scanned": scanned,
+        "rotated": rotated,
+        "failed": failed.len(),
+        "failed_user_ids": failed,
+    });
+    send_line(&tx, line).await;
+}
+
+/// Sends one NDJSON line. A client that went away does not stop the run.
+async fn send_line(tx: &DumpSender, line: Value) {
+    send_dump_chunk(tx, format!("{}\n", line)).await;
+}
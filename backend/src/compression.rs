@@ -0,0 +1,77 @@
+//! Response compression for Safe Chat backend
+//!
+//! `compression_layer` gzip- or brotli-compresses responses for clients that send
+//! `Accept-Encoding`, which mostly pays off for the JSON list endpoints and the database
+//! dump. Images and raw bytes are already compact and the NDJSON export is streamed to
+//! backup tools as it is read, so those pass through unchanged, as do WebSocket upgrades.
+//! Compression is on unless `COMPRESS_RESPONSES` is `false` or `0`.
+
+use axum::body::HttpBody;
+use axum::http::{Response, StatusCode};
+use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+
+/// Decides which responses `compression_layer` compresses.
+#[derive(Debug, Clone, Copy)]
+pub struct Compressible {
+    pub enabled: bool,
+}
+
+impl Predicate for Compressible {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        // `DefaultPredicate` already skips images, gRPC, event streams and tiny bodies
+        self.enabled
+            && response.status() != StatusCode::SWITCHING_PROTOCOLS
+            && DefaultPredicate::new()
+                .and(NotForContentType::const_new("application/octet-stream"))
+                .and(NotForContentType::const_new("application/x-ndjson"))
+                .should_compress(response)
+    }
+}
+
+pub fn compression_layer(enabled: bool) -> CompressionLayer<Compressible> {
+    CompressionLayer::new().compress_when(Compressible { enabled })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::header::CONTENT_TYPE;
+
+    fn response(status: StatusCode, content_type: &str) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from("x".repeat(1024)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compresses_json_only_when_enabled() {
+        let json = response(StatusCode::OK, "application/json");
+        assert!(Compressible { enabled: true }.should_compress(&json));
+        assert!(!Compressible { enabled: false }.should_compress(&json));
+    }
+
+    #[test]
+    fn test_skips_binary_streaming_and_upgrade_responses() {
+        let compressible = Compressible { enabled: true };
+        for content_type in [
+            "image/png",
+            "application/octet-stream",
+            "application/x-ndjson",
+        ] {
+            assert!(
+                !compressible.should_compress(&response(StatusCode::OK, content_type)),
+                "{}",
+                content_type
+            );
+        }
+        let upgrade = response(StatusCode::SWITCHING_PROTOCOLS, "text/plain");
+        assert!(!compressible.should_compress(&upgrade));
+    }
+}
@@ -0,0 +1,785 @@
+//! Contacts module for Safe Chat backend
+//!
+//! Handles the per-user contact list, the contact request handshake, and the
+//! relationship cache used to decide whether one user may message another when
+//! `REQUIRE_CONTACT_FOR_MESSAGES` is enabled.
+//! A receiver accepts messages from a sender when the sender is in the receiver's
+//! contacts, or when the receiver has previously messaged the sender. Accepting a
+//! contact request adds both users to each other's contacts.
+
+use crate::api::{UserResponse, extract_user_id_from_auth, user_response};
+use crate::preferences::{DEFAULT_TIMEZONE, format_timestamp, preferred_timezone};
+use crate::repo::{ContactRequestRecord, RepoResult};
+use crate::service::{self, ContactIdentifier, ContactRequestAction};
+use crate::state::AppState;
+use crate::websocket::{ContactRequestNotification, broadcast_contact_request_to_user};
+
+use axum::extract::{Json, Path, Query, State};
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+/// Per-user set of peers that user accepts messages from, loaded lazily from the database.
+pub type RelationshipCache = Arc<DashMap<Uuid, HashSet<Uuid>>>;
+
+const SYNC_LIMIT_PER_WINDOW: u32 = 5;
+const SYNC_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize)]
+pub struct AddContactRequest {
+    pub user_id: String,
+}
+
+#[derive(Serialize)]
+pub struct ContactResponse {
+    pub id: String,
+    pub username: String,
+    pub public_key: String,
+    pub added_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewContactRequest {
+    pub user_id: String,
+    /// Optional note shown to the target with the request.
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RespondContactRequest {
+    pub action: String,
+}
+
+#[derive(Serialize)]
+pub struct ContactRequestResponse {
+    pub id: String,
+    pub requester_id: String,
+    pub requester_username: String,
+    pub target_id: String,
+    pub status: String,
+    pub created_at: String,
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ContactRequestListQuery {
+    /// `incoming` or `outgoing`; both when unset.
+    pub direction: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ContactSyncRequest {
+    pub identifiers: Vec<ContactIdentifier>,
+}
+
+#[derive(Serialize)]
+pub struct ContactSyncResponse {
+    pub found: Vec<UserResponse>,
+    pub not_found: Vec<String>,
+}
+
+/// A contact's current key, or the reason it cannot be used.
+#[derive(Serialize)]
+pub struct ContactKeyResponse {
+    pub user_id: String,
+    pub public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ContactRequestList {
+    pub incoming: Vec<ContactRequestResponse>,
+    pub outgoing: Vec<ContactRequestResponse>,
+}
+
+pub fn create_relationship_cache() -> RelationshipCache {
+    Arc::new(DashMap::new())
+}
+
+/// Rate limit key of `user_id`'s `POST /contacts/sync` calls.
+fn sync_limit_key(user_id: Uuid) -> String {
+    format!("contact_sync:{}", user_id)
+}
+
+/// Decides whether a message from `sender_id` may be delivered.
+///
+/// When the contact requirement is disabled every send is permitted. Otherwise the
+/// sender must be in the receiver's set of accepted peers.
+pub fn message_permitted(
+    require_contact: bool,
+    receiver_peers: &HashSet<Uuid>,
+    sender_id: Uuid,
+) -> bool {
+    !require_contact || receiver_peers.contains(&sender_id)
+}
+
+/// Loads the set of peers `user_id` accepts messages from, using the cache when possible.
+async fn load_accepted_peers(state: &AppState, user_id: Uuid) -> RepoResult<HashSet<Uuid>> {
+    if let Some(peers) = state.relationships.get(&user_id) {
+        return Ok(peers.clone());
+    }
+    let peers = state.contacts.accepted_peers(user_id).await?;
+    state.relationships.insert(user_id, peers.clone());
+    Ok(peers)
+}
+
+/// Returns whether `receiver_id` accepts a message from `sender_id` under the deployment settings.
+pub async fn can_message(state: &AppState, sender_id: Uuid, receiver_id: Uuid) -> RepoResult<bool> {
+    if !state.require_contact_for_messages {
+        return Ok(true);
+    }
+    let peers = load_accepted_peers(state, receiver_id).await?;
+    Ok(message_permitted(true, &peers, sender_id))
+}
+
+/// Adds `peer_id` to the cached peer set of `user_id` if that set is already loaded.
+fn remember_peer(cache: &RelationshipCache, user_id: Uuid, peer_id: Uuid) {
+    if let Some(mut peers) = cache.get_mut(&user_id) {
+        peers.insert(peer_id);
+    }
+}
+
+/// Records that `user_id` has messaged `peer_id`, so replies from `peer_id` are always accepted.
+/// `opened_at` is set when the message opened the conversation, counting it towards the
+/// sender's new conversation limit.
+pub async fn record_conversation_peer(
+    state: &AppState,
+    user_id: Uuid,
+    peer_id: Uuid,
+    opened_at: Option<DateTime<Utc>>,
+) -> RepoResult<()> {
+    state
+        .contacts
+        .record_conversation_peer(user_id, peer_id, opened_at)
+        .await?;
+    remember_peer(&state.relationships, user_id, peer_id);
+    Ok(())
+}
+
+/// Lists the authenticated user's contacts.
+///
+/// Requires a valid JWT Bearer token in the `Authorization` header. Returns a JSON array of
+/// contacts with their username and public key, ordered by when they were added.
+pub async fn list_contacts(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts endpoint");
+            return e.into_response();
+        }
+    };
+    let records = match state.contacts.list_contacts(user_id).await {
+        Ok(records) => records,
+        Err(err) => {
+            info!("Database error in /contacts: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let timezone = preferred_timezone(state.users.as_ref(), user_id).await;
+    let contacts: Vec<ContactResponse> = records
+        .into_iter()
+        .map(|contact| ContactResponse {
+            id: contact.user_id.to_string(),
+            username: contact.username,
+            public_key: contact.public_key,
+            added_at: format_timestamp(contact.added_at, timezone),
+        })
+        .collect();
+    (StatusCode::OK, Json(contacts)).into_response()
+}
+
+/// Lists the current public key and fingerprint of each of the authenticated user's
+/// contacts, so a client can encrypt to all of them without one lookup per contact.
+/// Contacts without a usable key get an `error` instead of a fingerprint.
+pub async fn list_contact_keys(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts/keys endpoint");
+            return e.into_response();
+        }
+    };
+    let keys = match service::contact_keys(state.contacts.as_ref(), user_id).await {
+        Ok(keys) => keys,
+        Err(err) => {
+            info!("Listing contact keys failed for user {}: {}", user_id, err);
+            return err.into_response();
+        }
+    };
+    let keys: Vec<ContactKeyResponse> = keys
+        .into_iter()
+        .map(|key| {
+            let (fingerprint, error) = match key.fingerprint {
+                Ok(fingerprint) => (Some(fingerprint), None),
+                Err(reason) => (None, Some(reason.to_string())),
+            };
+            ContactKeyResponse {
+                user_id: key.user_id.to_string(),
+                public_key: key.public_key,
+                fingerprint,
+                error,
+            }
+        })
+        .collect();
+    (StatusCode::OK, Json(serde_json::json!({ "keys": keys }))).into_response()
+}
+
+/// Adds another user to the authenticated user's contacts.
+///
+/// Returns 201 when the contact was added (or already present), 400 for an invalid or
+/// self-referencing user ID, and 404 if the user does not exist.
+pub async fn add_contact(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<AddContactRequest>,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts endpoint");
+            return e.into_response();
+        }
+    };
+    let contact_id = match Uuid::parse_str(&payload.user_id) {
+        Ok(uid) => uid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user_id format").into_response(),
+    };
+    match service::add_contact(
+        state.contacts.as_ref(),
+        user_id,
+        contact_id,
+        state.max_contacts,
+    )
+    .await
+    {
+        Ok(()) => {
+            remember_peer(&state.relationships, user_id, contact_id);
+            info!("User {} added contact {}", user_id, contact_id);
+            (StatusCode::CREATED, "Contact added").into_response()
+        }
+        Err(err) => {
+            info!("Adding contact failed for user {}: {}", user_id, err);
+            err.into_response()
+        }
+    }
+}
+
+/// Removes a user from the authenticated user's contacts.
+///
+/// Returns 204 on success and 404 if the user was not a contact.
+pub async fn remove_contact(
+    Path(contact_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts/{{}} endpoint");
+            return e.into_response();
+        }
+    };
+    let contact_id = match Uuid::parse_str(&contact_id) {
+        Ok(uid) => uid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user_id format").into_response(),
+    };
+    match state.contacts.remove_contact(user_id, contact_id).await {
+        Ok(true) => {
+            // The peer may still be accepted through a conversation, so reload on next check
+            state.relationships.remove(&user_id);
+            info!("User {} removed contact {}", user_id, contact_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "Contact not found").into_response(),
+        Err(err) => {
+            info!(
+                "Database error removing contact for user {}: {}",
+                user_id, err
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// Matches a batch of address book identifiers against registered users.
+///
+/// Accepts up to 200 `{"type": "email"|"username", "value": ...}` entries and returns the
+/// matching users plus the values that matched nobody. Limited to 5 syncs per user per hour;
+/// further requests get 429 with `Retry-After`.
+pub async fn sync_contacts(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ContactSyncRequest>,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts/sync endpoint");
+            return e.into_response();
+        }
+    };
+    if let Err(retry_after) = state
+        .rate_limiter
+        .check_and_increment(&sync_limit_key(user_id), SYNC_LIMIT_PER_WINDOW, SYNC_WINDOW)
+        .await
+        .into_result()
+    {
+        info!(
+            "Contact sync for user {} rate limited for {}s",
+            user_id,
+            retry_after.as_secs()
+        );
+        return service::rate_limited_response(retry_after);
+    }
+    match service::sync_contacts(state.users.as_ref(), &payload.identifiers).await {
+        Ok(result) => {
+            info!(
+                "Contact sync for user {}: {} found, {} not found",
+                user_id,
+                result.found.len(),
+                result.not_found.len()
+            );
+            let response = ContactSyncResponse {
+                found: result
+                    .found
+                    .into_iter()
+                    .map(|user| user_response(user, &state.column_keys))
+                    .collect(),
+                not_found: result.not_found,
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Requests are also sent to the other party as events, so they are rendered in UTC.
+fn contact_request_response(request: ContactRequestRecord) -> ContactRequestResponse {
+    ContactRequestResponse {
+        id: request.id.to_string(),
+        requester_id: request.requester_id.to_string(),
+        requester_username: request.requester_username,
+        target_id: request.target_id.to_string(),
+        status: request.status,
+        created_at: format_timestamp(request.created_at, DEFAULT_TIMEZONE),
+        message: request.message,
+    }
+}
+
+fn contact_request_notification(request: &ContactRequestResponse) -> ContactRequestNotification {
+    ContactRequestNotification {
+        id: request.id.clone(),
+        requester_id: request.requester_id.clone(),
+        requester_username: request.requester_username.clone(),
+        target_id: request.target_id.clone(),
+        status: request.status.clone(),
+        created_at: request.created_at.clone(),
+        message: request.message.clone(),
+    }
+}
+
+/// Sends a contact request from the authenticated user to another user, optionally with a
+/// note of up to 500 characters.
+///
+/// Only one pending request may exist per pair of users. A request that was declined or
+/// withdrawn cannot be re-sent until the configured cooldown has passed. The target is
+/// notified with a `contact_request` WebSocket event.
+///
+/// Returns 201 with the created request, 400 for an invalid or self-referencing user ID or
+/// an overlong note, 404 if the user does not exist, 409 if the users are already contacts or a request is
+/// pending, and 429 with `Retry-After` during the cooldown.
+pub async fn create_contact_request(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<NewContactRequest>,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts/requests endpoint");
+            return e.into_response();
+        }
+    };
+    let target_id = match Uuid::parse_str(&payload.user_id) {
+        Ok(uid) => uid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid user_id format").into_response(),
+    };
+    let request = match service::create_contact_request(
+        state.users.as_ref(),
+        state.contacts.as_ref(),
+        user_id,
+        target_id,
+        payload.message.as_deref(),
+        state.contact_request_cooldown_secs,
+        state.clock.now(),
+    )
+    .await
+    {
+        Ok(request) => contact_request_response(request),
+        Err(err) => {
+            info!(
+                "Contact request from {} to {} rejected: {}",
+                user_id, target_id, err
+            );
+            return err.into_response();
+        }
+    };
+
+    info!(
+        "User {} sent contact request {} to {}",
+        user_id, request.id, target_id
+    );
+    broadcast_contact_request_to_user(&state, target_id, contact_request_notification(&request))
+        .await;
+    (StatusCode::CREATED, Json(request)).into_response()
+}
+
+/// Lists the authenticated user's pending contact requests, both incoming and outgoing.
+///
+/// With `direction=incoming` or `direction=outgoing` the other list is left empty. Returns
+/// 400 for any other direction.
+pub async fn list_contact_requests(
+    Query(query): Query<ContactRequestListQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts/requests endpoint");
+            return e.into_response();
+        }
+    };
+    let (want_incoming, want_outgoing) = match query.direction.as_deref() {
+        None => (true, true),
+        Some("incoming") => (true, false),
+        Some("outgoing") => (false, true),
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Invalid direction. Must be one of: incoming, outgoing",
+            )
+                .into_response();
+        }
+    };
+    let records = match state.contacts.list_pending_requests(user_id).await {
+        Ok(records) => records,
+        Err(err) => {
+            info!("Database error in /contacts/requests: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let user_id_str = user_id.to_string();
+    let (mut outgoing, mut incoming): (Vec<_>, Vec<_>) = records
+        .into_iter()
+        .map(contact_request_response)
+        .partition(|r| r.requester_id == user_id_str);
+    if !want_incoming {
+        incoming.clear();
+    }
+    if !want_outgoing {
+        outgoing.clear();
+    }
+    (
+        StatusCode::OK,
+        Json(ContactRequestList { incoming, outgoing }),
+    )
+        .into_response()
+}
+
+/// Accepts, declines, or withdraws a pending contact request.
+///
+/// The target may accept or decline; the requester may withdraw. Accepting adds both users
+/// to each other's contacts in the same transaction. The other participant is notified with
+/// a `contact_request` WebSocket event carrying the new status.
+pub async fn respond_contact_request(
+    Path(request_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<RespondContactRequest>,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /contacts/requests/{{}} endpoint");
+            return e.into_response();
+        }
+    };
+    let request_id = match Uuid::parse_str(&request_id) {
+        Ok(uid) => uid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid request id format").into_response(),
+    };
+    let action = match ContactRequestAction::parse(&payload.action) {
+        Some(action) => action,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Invalid action. Must be one of: accept, decline, withdraw",
+            )
+                .into_response();
+        }
+    };
+
+    let record = match service::resolve_contact_request(
+        state.contacts.as_ref(),
+        user_id,
+        request_id,
+        action,
+    )
+    .await
+    {
+        Ok(record) => record,
+        Err(err) => {
+            info!(
+                "Contact request {} not updated by user {}: {}",
+                request_id, user_id, err
+            );
+            return err.into_response();
+        }
+    };
+    let (requester_id, target_id) = (record.requester_id, record.target_id);
+
+    if action == ContactRequestAction::Accept {
+        remember_peer(&state.relationships, requester_id, target_id);
+        remember_peer(&state.relationships, target_id, requester_id);
+    }
+    info!(
+        "Contact request {} set to {} by user {}",
+        request_id, record.status, user_id
+    );
+
+    let request = contact_request_response(record);
+    let other_party = if user_id == requester_id {
+        target_id
+    } else {
+        requester_id
+    };
+    broadcast_contact_request_to_user(&state, other_party, contact_request_notification(&request))
+        .await;
+    (StatusCode::OK, Json(request)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::InMemoryBackend;
+    use crate::websocket::tests::fake_state;
+    use axum::body::HttpBody;
+    use axum::http::header::AUTHORIZATION;
+    use std::time::Instant;
+
+    fn auth_headers(state: &AppState, user_id: Uuid) -> HeaderMap {
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(user_id, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    async fn body_bytes(response: axum::response::Response) -> Vec<u8> {
+        response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_any_sender_permitted_when_setting_disabled() {
+        let peers = HashSet::new();
+        assert!(message_permitted(false, &peers, Uuid::new_v4()));
+    }
+
+    #[test]
+    fn test_stranger_rejected_when_setting_enabled() {
+        let mut peers = HashSet::new();
+        peers.insert(Uuid::new_v4());
+        assert!(!message_permitted(true, &peers, Uuid::new_v4()));
+    }
+
+    #[test]
+    fn test_reply_in_existing_conversation_permitted() {
+        let cache = create_relationship_cache();
+        let receiver = Uuid::new_v4();
+        let sender = Uuid::new_v4();
+        cache.insert(receiver, HashSet::new());
+
+        // The receiver previously messaged the sender, so the sender's reply is accepted
+        remember_peer(&cache, receiver, sender);
+
+        let peers = cache.get(&receiver).unwrap();
+        assert!(message_permitted(true, &peers, sender));
+    }
+
+    #[tokio::test]
+    async fn test_contacts_must_be_mutual_to_message_both_ways() {
+        let (state, _) = fake_state(true);
+        let alice = state
+            .users
+            .create_user("alice", "hash", "key-a")
+            .await
+            .unwrap();
+        let bob = state
+            .users
+            .create_user("bob", "hash", "key-b")
+            .await
+            .unwrap();
+        let add = |owner: Uuid, contact: Uuid| {
+            let state = state.clone();
+            async move {
+                let headers = auth_headers(&state, owner);
+                let payload = AddContactRequest {
+                    user_id: contact.to_string(),
+                };
+                add_contact(State(state), headers, Json(payload))
+                    .await
+                    .into_response()
+            }
+        };
+
+        let response = add(alice, bob).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(body_bytes(response).await, b"Contact added");
+        let response = list_contacts(State(state.clone()), auth_headers(&state, alice))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let contacts: serde_json::Value =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        let contacts = contacts.as_array().unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0]["id"], bob.to_string());
+        assert_eq!(contacts[0]["username"], "bob");
+        assert_eq!(contacts[0]["public_key"], "key-b");
+
+        // Alice accepts Bob, but Bob has not accepted Alice
+        assert!(can_message(&state, bob, alice).await.unwrap());
+        assert!(!can_message(&state, alice, bob).await.unwrap());
+        let response = list_contacts(State(state.clone()), auth_headers(&state, bob))
+            .await
+            .into_response();
+        assert_eq!(body_bytes(response).await, b"[]");
+
+        let response = add(bob, alice).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(can_message(&state, alice, bob).await.unwrap());
+
+        // Removing a contact stops that direction only
+        let response = remove_contact(
+            Path(bob.to_string()),
+            State(state.clone()),
+            auth_headers(&state, alice),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(body_bytes(response).await.is_empty());
+        assert!(!can_message(&state, bob, alice).await.unwrap());
+        assert!(can_message(&state, alice, bob).await.unwrap());
+        let response = remove_contact(
+            Path(bob.to_string()),
+            State(state.clone()),
+            auth_headers(&state, alice),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_bytes(response).await, b"Contact not found");
+    }
+
+    #[test]
+    fn test_remember_peer_ignores_unloaded_users() {
+        let cache = create_relationship_cache();
+        remember_peer(&cache, Uuid::new_v4(), Uuid::new_v4());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_sync_rate_limit() {
+        let limiter = InMemoryBackend::new();
+        let try_acquire_sync = |user_id: Uuid, now: Instant| {
+            limiter
+                .check_and_increment_at(
+                    &sync_limit_key(user_id),
+                    SYNC_LIMIT_PER_WINDOW,
+                    SYNC_WINDOW,
+                    now,
+                )
+                .into_result()
+        };
+        let user = Uuid::new_v4();
+        let start = Instant::now();
+        for _ in 0..SYNC_LIMIT_PER_WINDOW {
+            assert!(try_acquire_sync(user, start).is_ok());
+        }
+        let retry_after = try_acquire_sync(user, start + Duration::from_secs(600)).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(3000));
+        // Other users have their own budget
+        assert!(try_acquire_sync(Uuid::new_v4(), start).is_ok());
+        // Once the window has passed the user may sync again
+        assert!(try_acquire_sync(user, start + SYNC_WINDOW).is_ok());
+    }
+}
@@ -0,0 +1,208 @@
+//! Conversations module for Safe Chat backend
+//!
+//! Lets a client filter its conversation list on the server. A conversation exists
+//! between two users once either has messaged the other (see `conversation_peers`),
+//! even after the messages themselves were deleted on read.
+//!
+//! Each participant can also ask to keep a conversation's read messages (history mode).
+//! Messages are only deleted if neither participant keeps them.
+
+use crate::api::{UuidPath, extract_user_id_from_auth};
+use crate::repo::ConversationSettings;
+use crate::service::{self, ConversationSettingsView};
+use crate::state::AppState;
+
+use axum::extract::{Json, Query, State};
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct ConversationSearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ConversationResponse {
+    pub user_id: String,
+    pub username: String,
+    pub unread_count: i64,
+}
+
+/// A page of results; `total` counts every match, not just this page.
+#[derive(Serialize)]
+pub struct ConversationPageResponse {
+    pub items: Vec<ConversationResponse>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// Searches the authenticated user's conversations by peer username.
+pub async fn search_conversations(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ConversationSearchQuery>,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /conversations/search endpoint");
+            return e.into_response();
+        }
+    };
+    match service::search_conversations(
+        state.contacts.as_ref(),
+        state.messages.as_ref(),
+        user_id,
+        &query.q,
+        query.limit,
+        query.offset,
+    )
+    .await
+    {
+        Ok(page) => (
+            StatusCode::OK,
+            Json(ConversationPageResponse {
+                items: page
+                    .items
+                    .into_iter()
+                    .map(|item| ConversationResponse {
+                        user_id: item.peer.user_id.to_string(),
+                        username: item.peer.username,
+                        unread_count: item.unread_count,
+                    })
+                    .collect(),
+                total: page.total,
+                limit: page.limit,
+                offset: page.offset,
+            }),
+        )
+            .into_response(),
+        Err(err) => {
+            info!("Conversation search failed for user {}: {}", user_id, err);
+            err.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateConversationSettingsRequest {
+    pub keep_read_messages: bool,
+}
+
+#[derive(Serialize)]
+pub struct ConversationSettingsResponse {
+    /// The caller's own setting.
+    pub keep_read_messages: bool,
+    /// The other participant's setting.
+    pub peer_keep_read_messages: bool,
+    /// Whether READ messages of this conversation are deleted, which needs both to be off.
+    pub read_messages_deleted: bool,
+}
+
+impl From<ConversationSettingsView> for ConversationSettingsResponse {
+    fn from(settings: ConversationSettingsView) -> Self {
+        ConversationSettingsResponse {
+            keep_read_messages: settings.own.keep_read_messages,
+            peer_keep_read_messages: settings.peer.keep_read_messages,
+            read_messages_deleted: !settings.keeps_read_messages(),
+        }
+    }
+}
+
+/// Authenticates a request to `/conversations/{user_id}/settings`.
+fn settings_caller(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<Uuid, (StatusCode, &'static str)> {
+    extract_user_id_from_auth(
+        headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    )
+    .inspect_err(|_| info!("Unauthorized access attempt to /conversations/{{}}/settings endpoint"))
+}
+
+/// Returns both participants' settings for the caller's conversation with `user_id`.
+pub async fn get_conversation_settings(
+    UuidPath(peer): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let caller = match settings_caller(&state, &headers) {
+        Ok(caller) => caller,
+        Err(e) => return e.into_response(),
+    };
+    match service::conversation_settings(state.contacts.as_ref(), caller, peer).await {
+        Ok(settings) => (
+            StatusCode::OK,
+            Json(ConversationSettingsResponse::from(settings)),
+        )
+            .into_response(),
+        Err(err) => {
+            info!(
+                "Loading settings of conversation {} / {} failed: {}",
+                caller, peer, err
+            );
+            err.into_response()
+        }
+    }
+}
+
+/// Sets the caller's settings for their conversation with `user_id` and returns both
+/// participants' settings. 404 if `user_id` does not exist.
+pub async fn update_conversation_settings(
+    UuidPath(peer): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<UpdateConversationSettingsRequest>,
+) -> impl IntoResponse {
+    let caller = match settings_caller(&state, &headers) {
+        Ok(caller) => caller,
+        Err(e) => return e.into_response(),
+    };
+    let settings = ConversationSettings {
+        keep_read_messages: payload.keep_read_messages,
+    };
+    match service::set_conversation_settings(
+        state.users.as_ref(),
+        state.contacts.as_ref(),
+        caller,
+        peer,
+        settings,
+    )
+    .await
+    {
+        Ok(settings) => {
+            info!(
+                "User {} set keep_read_messages={} for conversation with {}",
+                caller, payload.keep_read_messages, peer
+            );
+            (
+                StatusCode::OK,
+                Json(ConversationSettingsResponse::from(settings)),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            info!(
+                "Updating settings of conversation {} / {} failed: {}",
+                caller, peer, err
+            );
+            err.into_response()
+        }
+    }
+}
@@ -1,13 +1,88 @@
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
 use base64::{Engine as _, engine::general_purpose};
-use rand_core::OsRng;
-use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
+use chrono::NaiveDate;
+use ed25519_dalek::{Signature, VerifyingKey};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use uuid::Uuid;
+use x25519_dalek::{
+    EphemeralSecret, PublicKey as X25519PublicKey, StaticSecret, X25519_BASEPOINT_BYTES, x25519,
+};
+
+pub use safechat_types::ws::{DEFAULT_CIPHER_SUITE, DEFAULT_ENCRYPTION_VERSION};
 
 // X.509 ASN.1 header for X25519 public keys
 const X25519_X509_HEADER: [u8; 12] = [
     0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x03, 0x21, 0x00
 ];
+// X.509 ASN.1 header for Ed25519 public keys; only the algorithm OID differs
+const ED25519_X509_HEADER: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
 
-pub fn generate_keypair_base64() -> String {
+/// Domain separator mixed into key possession proofs, so they cannot be replayed elsewhere.
+const KEY_PROOF_CONTEXT: &[u8] = b"safechat-key-proof-v1";
+/// Domain separator for the routing ids of sealed messages.
+const SEALED_ROUTING_CONTEXT: &[u8] = b"safechat-sealed-routing-v1";
+/// HKDF label of the key sealed routing ids are computed with.
+const SEALED_ROUTING_KEY_INFO: &[u8] = b"safechat-sealed-routing-key-v1";
+/// Starts every column value encrypted with `encrypt_column`, followed by the format
+/// version, the key id and the nonce.
+const COLUMN_CIPHERTEXT_MAGIC: &[u8; 3] = b"SCE";
+const COLUMN_CIPHERTEXT_VERSION: u8 = 1;
+const COLUMN_KEY_ID_LEN: usize = 4;
+const COLUMN_NONCE_LEN: usize = 12;
+const COLUMN_HEADER_LEN: usize =
+    COLUMN_CIPHERTEXT_MAGIC.len() + 1 + COLUMN_KEY_ID_LEN + COLUMN_NONCE_LEN;
+
+/// Message encryption schemes the server accepts, identified by `encryption_version`.
+/// Version 1 is the scheme the Android client has used since launch.
+pub const SUPPORTED_ENCRYPTION_VERSIONS: [i16; 1] = [1];
+
+pub fn max_encryption_version() -> i16 {
+    SUPPORTED_ENCRYPTION_VERSIONS
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(DEFAULT_ENCRYPTION_VERSION)
+}
+
+pub fn encryption_version_supported(version: i16) -> bool {
+    SUPPORTED_ENCRYPTION_VERSIONS.contains(&version)
+}
+
+/// Symmetric ciphers a message's content may be encrypted with, named by `cipher_suite`.
+pub const SUPPORTED_CIPHER_SUITES: [&str; 2] = ["AES_256_GCM", "CHACHA20_POLY1305"];
+
+/// Parses `CIPHER_SUITES`, a comma-separated subset of `SUPPORTED_CIPHER_SUITES`.
+/// An empty list allows every supported suite.
+pub fn parse_cipher_suites(raw: &str) -> Result<Vec<String>, String> {
+    let suites: Vec<String> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let suite = entry.to_ascii_uppercase();
+            if SUPPORTED_CIPHER_SUITES.contains(&suite.as_str()) {
+                Ok(suite)
+            } else {
+                Err(format!("Invalid CIPHER_SUITES entry: {}", entry))
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    if suites.is_empty() {
+        return Ok(SUPPORTED_CIPHER_SUITES
+            .iter()
+            .map(|s| s.to_string())
+            .collect());
+    }
+    Ok(suites)
+}
+
+fn generate_x509_public_key() -> Vec<u8> {
     let secret = EphemeralSecret::random_from_rng(OsRng);
     let public = X25519PublicKey::from(&secret);
     
@@ -16,8 +91,40 @@ pub fn generate_keypair_base64() -> String {
     let mut x509_bytes = Vec::with_capacity(X25519_X509_HEADER.len() + raw_key_bytes.len());
     x509_bytes.extend_from_slice(&X25519_X509_HEADER);
     x509_bytes.extend_from_slice(raw_key_bytes);
-    
-    general_purpose::STANDARD.encode(&x509_bytes)
+    x509_bytes
+}
+
+pub fn generate_keypair_base64() -> String {
+    general_purpose::STANDARD.encode(generate_x509_public_key())
+}
+
+/// Like `generate_keypair_base64`, but base64url-encoded so the key can be used in a URL
+/// path without percent-encoding.
+#[allow(dead_code)]
+pub fn generate_keypair_base64url() -> String {
+    encode_url_safe(&generate_x509_public_key())
+}
+
+/// Base64url without padding: `-` and `_` instead of `+` and `/`, and no `=`.
+pub fn encode_url_safe(bytes: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Decodes base64url, with or without trailing `=` padding.
+pub fn decode_url_safe(b64url: &str) -> Result<Vec<u8>, &'static str> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(b64url.trim_end_matches('='))
+        .map_err(|_| "Invalid base64url encoding")
+}
+
+/// A public key in standard base64, the form keys are stored in, given the key in standard
+/// base64 or base64url. `None` if it is not a valid key in either encoding.
+pub fn standard_public_key(key: &str) -> Option<String> {
+    if validate_x509_public_key(key) {
+        return Some(key.to_string());
+    }
+    let standard = general_purpose::STANDARD.encode(decode_url_safe(key).ok()?);
+    validate_x509_public_key(&standard).then_some(standard)
 }
 
 pub fn encode_raw_key_to_x509(raw_key: &[u8; 32]) -> String {
@@ -53,10 +160,356 @@ pub fn decode_x509_to_raw_key(x509_base64: &str) -> Result<[u8; 32], &'static st
     Ok(raw_key)
 }
 
+/// Length of a bare 32-byte key in standard base64.
+const RAW_KEY_BASE64_LEN: usize = 44;
+
+/// How a stored public key is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    X509,
+    /// The bare 32-byte key, which clients uploaded before keys had to be X.509.
+    Raw,
+    Unparseable,
+}
+
+/// The encoding of `key`. `decode_x509_to_raw_key` accepts both forms, so a raw key is told
+/// apart by its length.
+pub fn key_format(key: &str) -> KeyFormat {
+    match decode_x509_to_raw_key(key) {
+        Err(_) => KeyFormat::Unparseable,
+        Ok(_) if key.len() == RAW_KEY_BASE64_LEN => KeyFormat::Raw,
+        Ok(_) => KeyFormat::X509,
+    }
+}
+
+/// Human-comparable fingerprint of an X25519 public key: the SHA-256 of the raw key as 16
+/// groups of 4 uppercase hex digits. The X.509 and raw encodings of a key share a fingerprint.
+pub fn key_fingerprint(raw_key: &[u8; 32]) -> String {
+    use sha2::Digest;
+    let digest = Sha256::digest(raw_key);
+    digest
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[allow(dead_code)]
 pub fn validate_x509_public_key(x509_base64: &str) -> bool {
     decode_x509_to_raw_key(x509_base64).is_ok()
 }
 
+/// Server half of a key possession challenge: an ephemeral X25519 key pair and a nonce.
+pub struct KeyChallengeKeys {
+    pub server_secret: [u8; 32],
+    pub server_public: [u8; 32],
+    pub nonce: [u8; 32],
+}
+
+pub fn generate_key_challenge() -> KeyChallengeKeys {
+    let mut server_secret = [0u8; 32];
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut server_secret);
+    OsRng.fill_bytes(&mut nonce);
+    KeyChallengeKeys {
+        server_secret,
+        server_public: x25519(server_secret, X25519_BASEPOINT_BYTES),
+        nonce,
+    }
+}
+
+/// The proof a client sends to show it holds the private half of `proposed_key`:
+/// `HMAC-SHA256(shared_secret, "safechat-key-proof-v1" || nonce || proposed_key)`, where
+/// `shared_secret` is the X25519 agreement between the proposed key and the server's
+/// ephemeral key.
+///
+/// This is the client's half; the server only calls it in tests.
+#[allow(dead_code)]
+pub fn key_possession_proof(
+    shared_secret: &[u8; 32],
+    nonce: &[u8; 32],
+    proposed_key: &[u8; 32],
+) -> [u8; 32] {
+    key_proof_mac(shared_secret, nonce, proposed_key)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Checks a client's proof against the server's ephemeral secret, in constant time.
+///
+/// Rejects keys that produce an all-zero shared secret (low-order points), since anyone
+/// could compute that proof without a private key.
+pub fn verify_key_possession(
+    server_secret: [u8; 32],
+    proposed_key: [u8; 32],
+    nonce: &[u8; 32],
+    proof: &[u8],
+) -> bool {
+    let shared_secret = x25519(server_secret, proposed_key);
+    if shared_secret == [0u8; 32] {
+        return false;
+    }
+    key_proof_mac(&shared_secret, nonce, &proposed_key)
+        .verify_slice(proof)
+        .is_ok()
+}
+
+/// The X25519 shared secret of our base64 private key and their base64 X.509 (or raw)
+/// public key, as the Android client computes it with Conscrypt.
+///
+/// Rejects public keys that produce an all-zero secret (low-order points), which would
+/// not depend on our private key.
+#[allow(dead_code)]
+pub fn compute_x25519_shared_secret(
+    our_secret_b64: &str,
+    their_public_x509_b64: &str,
+) -> Result<[u8; 32], &'static str> {
+    let secret: [u8; 32] = general_purpose::STANDARD
+        .decode(our_secret_b64)
+        .map_err(|_| "Invalid base64 encoding")?
+        .try_into()
+        .map_err(|_| "Invalid X25519 private key length")?;
+    let their_public = X25519PublicKey::from(decode_x509_to_raw_key(their_public_x509_b64)?);
+    let shared = StaticSecret::from(secret).diffie_hellman(&their_public);
+    if !shared.was_contributory() {
+        return Err("Low-order X25519 public key");
+    }
+    Ok(shared.to_bytes())
+}
+
+/// Checks a detached Ed25519 `signature` of `message` against a base64 X.509 (or raw)
+/// Ed25519 public key.
+///
+/// `Ok(false)` means the signature does not match; `Err` means the key or signature is
+/// malformed, including an X25519 key, which cannot sign.
+pub fn verify_ed25519_signature(
+    public_key_x509_b64: &str,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<bool, &'static str> {
+    let key_bytes = general_purpose::STANDARD
+        .decode(public_key_x509_b64)
+        .map_err(|_| "Invalid base64 encoding")?;
+    let raw_key: [u8; 32] = match key_bytes.len() {
+        32 => key_bytes.try_into().expect("length checked"),
+        len if len == ED25519_X509_HEADER.len() + 32 => {
+            if key_bytes[..X25519_X509_HEADER.len()] == X25519_X509_HEADER {
+                return Err("X25519 keys cannot verify signatures");
+            }
+            if key_bytes[..ED25519_X509_HEADER.len()] != ED25519_X509_HEADER {
+                return Err("Invalid X.509 header for Ed25519 key");
+            }
+            key_bytes[ED25519_X509_HEADER.len()..]
+                .try_into()
+                .expect("length checked")
+        }
+        _ => return Err("Invalid X.509 key length"),
+    };
+    let verifying_key =
+        VerifyingKey::from_bytes(&raw_key).map_err(|_| "Invalid Ed25519 public key")?;
+    let signature =
+        Signature::from_slice(signature).map_err(|_| "Invalid Ed25519 signature length")?;
+    Ok(verifying_key.verify_strict(message, &signature).is_ok())
+}
+
+/// Whether `signature_b64` is base64 of 64 bytes, the size of an Ed25519 signature.
+pub fn is_ed25519_signature(signature_b64: &str) -> bool {
+    general_purpose::STANDARD
+        .decode(signature_b64)
+        .is_ok_and(|bytes| Signature::from_slice(&bytes).is_ok())
+}
+
+fn key_proof_mac(
+    shared_secret: &[u8; 32],
+    nonce: &[u8; 32],
+    proposed_key: &[u8; 32],
+) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(shared_secret).expect("HMAC accepts any key length");
+    mac.update(KEY_PROOF_CONTEXT);
+    mac.update(nonce);
+    mac.update(proposed_key);
+    mac
+}
+
+/// An AES-256-GCM key for encrypting columns at rest, from `COLUMN_ENCRYPTION_KEY`.
+#[derive(Clone)]
+pub struct ColumnKey {
+    cipher: Aes256Gcm,
+    /// The first bytes of the key's SHA-256, stored with every value it encrypts so the
+    /// key a value needs can be told apart from the one configured.
+    id: [u8; COLUMN_KEY_ID_LEN],
+}
+
+impl ColumnKey {
+    /// Parses 32 bytes in standard base64.
+    pub fn from_base64(key: &str) -> Result<Self, &'static str> {
+        use aes_gcm::KeyInit;
+        use sha2::Digest;
+        let key: [u8; 32] = general_purpose::STANDARD
+            .decode(key.trim())
+            .map_err(|_| "Invalid base64 encoding")?
+            .try_into()
+            .map_err(|_| "Column encryption key must be 32 bytes")?;
+        let digest = Sha256::digest(key);
+        Ok(ColumnKey {
+            cipher: Aes256Gcm::new(&key.into()),
+            id: digest[..COLUMN_KEY_ID_LEN]
+                .try_into()
+                .expect("digest is longer"),
+        })
+    }
+
+    /// The key id as hex, for logs.
+    pub fn id(&self) -> String {
+        self.id.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// The keys column values are encrypted and decrypted with: new values go under `current`
+/// (`COLUMN_ENCRYPTION_KEY`), while values still under a retired key from
+/// `COLUMN_ENCRYPTION_OLD_KEYS` stay readable until they are rotated. Each value names its
+/// key by id, so decryption picks the matching key instead of trying them all. Empty when
+/// encryption is off.
+#[derive(Clone, Default)]
+pub struct ColumnKeyring {
+    current: Option<ColumnKey>,
+    old: Vec<ColumnKey>,
+}
+
+impl ColumnKeyring {
+    pub fn new(current: Option<ColumnKey>, old: Vec<ColumnKey>) -> Self {
+        Self { current, old }
+    }
+
+    /// Parses `COLUMN_ENCRYPTION_OLD_KEYS`: keys as for `ColumnKey::from_base64`, separated
+    /// by commas.
+    pub fn parse_old_keys(keys: &str) -> Result<Vec<ColumnKey>, &'static str> {
+        keys.split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(ColumnKey::from_base64)
+            .collect()
+    }
+
+    /// The key new values are encrypted under, if encryption is on.
+    pub fn current(&self) -> Option<&ColumnKey> {
+        self.current.as_ref()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.current.is_none() && self.old.is_empty()
+    }
+
+    fn find(&self, id: &[u8]) -> Option<&ColumnKey> {
+        self.current
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == id)
+    }
+}
+
+impl From<ColumnKey> for ColumnKeyring {
+    fn from(key: ColumnKey) -> Self {
+        Self::new(Some(key), Vec::new())
+    }
+}
+
+/// Whether `value` was written by `encrypt_column` rather than stored in the clear.
+pub fn is_column_encrypted(value: &[u8]) -> bool {
+    value.starts_with(COLUMN_CIPHERTEXT_MAGIC)
+}
+
+/// Encrypts a column value under a random nonce. `context` names the column and row, so a
+/// value copied to another row fails to decrypt.
+///
+/// The result is `"SCE" || version || key id || nonce || ciphertext`.
+pub fn encrypt_column(key: &ColumnKey, context: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; COLUMN_NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = key
+        .cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: context,
+            },
+        )
+        .expect("AES-GCM encrypts any column-sized value");
+    let mut value = Vec::with_capacity(COLUMN_HEADER_LEN + ciphertext.len());
+    value.extend_from_slice(COLUMN_CIPHERTEXT_MAGIC);
+    value.push(COLUMN_CIPHERTEXT_VERSION);
+    value.extend_from_slice(&key.id);
+    value.extend_from_slice(&nonce);
+    value.extend_from_slice(&ciphertext);
+    value
+}
+
+/// Decrypts a value written by `encrypt_column` with the same `context`, using the key of
+/// `keys` it names. Values stored in the clear, before a key was configured, are returned
+/// unchanged.
+pub fn decrypt_column(
+    keys: &ColumnKeyring,
+    context: &[u8],
+    value: Vec<u8>,
+) -> Result<Vec<u8>, &'static str> {
+    if !is_column_encrypted(&value) {
+        return Ok(value);
+    }
+    if keys.is_empty() {
+        return Err("Value is encrypted, but COLUMN_ENCRYPTION_KEY is not set");
+    }
+    if value.len() < COLUMN_HEADER_LEN {
+        return Err("Encrypted value is truncated");
+    }
+    let (header, ciphertext) = value.split_at(COLUMN_HEADER_LEN);
+    if header[COLUMN_CIPHERTEXT_MAGIC.len()] != COLUMN_CIPHERTEXT_VERSION {
+        return Err("Unsupported column encryption version");
+    }
+    let (key_id, nonce) = header[COLUMN_CIPHERTEXT_MAGIC.len() + 1..].split_at(COLUMN_KEY_ID_LEN);
+    let Some(key) = keys.find(key_id) else {
+        return Err("Value is encrypted under a different COLUMN_ENCRYPTION_KEY");
+    };
+    key.cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: context,
+            },
+        )
+        .map_err(|_| "Encrypted value failed authentication")
+}
+
+/// Derives the key of `sealed_routing_id` from the server's `JWT_SECRET` with HKDF-SHA256,
+/// so the secret that signs tokens is never used directly as another HMAC key.
+pub fn sealed_routing_key(secret: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(SEALED_ROUTING_KEY_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Pseudonymous routing id of a sealed message: the lowercase hex
+/// `HMAC-SHA256(key, "safechat-sealed-routing-v1" || sender || receiver || day)`. A sender's
+/// messages to one receiver share it for a UTC day; without `key` it cannot be linked to
+/// either user, even by hashing every pair of known ids.
+pub fn sealed_routing_id(key: &[u8], sender_id: Uuid, receiver_id: Uuid, day: NaiveDate) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(SEALED_ROUTING_CONTEXT);
+    mac.update(sender_id.as_bytes());
+    mac.update(receiver_id.as_bytes());
+    mac.update(day.format("%Y-%m-%d").to_string().as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,6 +528,389 @@ mod tests {
         assert_eq!(raw_key, decoded_raw);
     }
 
+    #[test]
+    fn test_encryption_versions() {
+        assert!(encryption_version_supported(DEFAULT_ENCRYPTION_VERSION));
+        assert_eq!(max_encryption_version(), 1);
+        assert!(!encryption_version_supported(0));
+        assert!(!encryption_version_supported(2));
+        assert!(!encryption_version_supported(-1));
+    }
+
+    #[test]
+    fn test_parse_cipher_suites() {
+        assert_eq!(parse_cipher_suites("").unwrap(), SUPPORTED_CIPHER_SUITES);
+        assert_eq!(
+            parse_cipher_suites(" chacha20_poly1305 ,").unwrap(),
+            ["CHACHA20_POLY1305"]
+        );
+        assert!(parse_cipher_suites("AES_256_GCM,AES_128_CBC").is_err());
+    }
+
+    #[test]
+    fn test_key_fingerprint() {
+        let raw_key = [0u8; 32];
+        // SHA-256 of 32 zero bytes
+        assert_eq!(
+            key_fingerprint(&raw_key),
+            "6668 7AAD F862 BD77 6C8F C18B 8E9F 8E20 0897 1485 6EE2 33B3 902A 591D 0D5F 2925"
+        );
+        let x509 = encode_raw_key_to_x509(&raw_key);
+        assert_eq!(
+            key_fingerprint(&decode_x509_to_raw_key(&x509).unwrap()),
+            key_fingerprint(&raw_key)
+        );
+        assert_ne!(key_fingerprint(&[1u8; 32]), key_fingerprint(&raw_key));
+    }
+
+    #[test]
+    fn test_sealed_routing_id_is_per_pair_day_and_key() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let id = sealed_routing_id(b"key", a, b, day);
+        assert_eq!(id.len(), 64);
+        assert_eq!(id, sealed_routing_id(b"key", a, b, day));
+        assert_ne!(id, sealed_routing_id(b"key", b, a, day));
+        assert_ne!(id, sealed_routing_id(b"key", a, b, day.succ_opt().unwrap()));
+        assert_ne!(id, sealed_routing_id(b"other", a, b, day));
+    }
+
+    #[test]
+    fn test_sealed_routing_key_is_not_the_secret() {
+        let secret = b"jwt-secret";
+        let key = sealed_routing_key(secret);
+        assert_eq!(key, sealed_routing_key(secret));
+        assert_ne!(key, sealed_routing_key(b"other-secret"));
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        assert_ne!(
+            sealed_routing_id(&key, a, b, day),
+            sealed_routing_id(secret, a, b, day)
+        );
+    }
+
+    fn hex32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_shared_secret_matches_rfc_7748_vectors() {
+        // RFC 7748 section 6.1
+        let alice_secret =
+            hex32("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let alice_public =
+            hex32("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        let bob_secret = hex32("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let bob_public = hex32("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let shared = hex32("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        let b64 = |bytes: [u8; 32]| general_purpose::STANDARD.encode(bytes);
+
+        let from_alice =
+            compute_x25519_shared_secret(&b64(alice_secret), &encode_raw_key_to_x509(&bob_public));
+        assert_eq!(from_alice, Ok(shared));
+        // Raw public keys are accepted like everywhere else
+        assert_eq!(
+            compute_x25519_shared_secret(&b64(bob_secret), &b64(alice_public)),
+            Ok(shared)
+        );
+
+        assert!(compute_x25519_shared_secret("not base64!", &b64(bob_public)).is_err());
+        assert!(compute_x25519_shared_secret(&b64(alice_secret), "not a key").is_err());
+        let short_secret = general_purpose::STANDARD.encode([1u8; 16]);
+        assert!(compute_x25519_shared_secret(&short_secret, &b64(bob_public)).is_err());
+        assert_eq!(
+            compute_x25519_shared_secret(&b64(alice_secret), &b64([0u8; 32])),
+            Err("Low-order X25519 public key")
+        );
+    }
+
+    #[test]
+    fn test_column_encryption_round_trips() {
+        let key = ColumnKey::from_base64(&general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+        let keys = ColumnKeyring::from(key.clone());
+        let avatar = b"\x89PNG\r\n\x1a\n image bytes".to_vec();
+        let sealed = encrypt_column(&key, b"users.avatar:1", &avatar);
+        assert!(is_column_encrypted(&sealed));
+        assert_ne!(sealed[COLUMN_HEADER_LEN..], avatar[..]);
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:1", sealed.clone()),
+            Ok(avatar.clone())
+        );
+        // A fresh nonce every time
+        assert_ne!(encrypt_column(&key, b"users.avatar:1", &avatar), sealed);
+        // Values stored before encryption was enabled pass through, with or without a key
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:1", avatar.clone()),
+            Ok(avatar.clone())
+        );
+        assert_eq!(
+            decrypt_column(&ColumnKeyring::default(), b"users.avatar:1", avatar.clone()),
+            Ok(avatar)
+        );
+    }
+
+    #[test]
+    fn test_column_decryption_rejects_wrong_key_and_context() {
+        let key = ColumnKey::from_base64(&general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+        let other = ColumnKey::from_base64(&general_purpose::STANDARD.encode([8u8; 32])).unwrap();
+        let sealed = encrypt_column(&key, b"users.avatar:1", b"avatar");
+        let keys = ColumnKeyring::from(key.clone());
+
+        assert_eq!(
+            decrypt_column(&ColumnKeyring::default(), b"users.avatar:1", sealed.clone()),
+            Err("Value is encrypted, but COLUMN_ENCRYPTION_KEY is not set")
+        );
+        assert_eq!(
+            decrypt_column(
+                &ColumnKeyring::from(other.clone()),
+                b"users.avatar:1",
+                sealed.clone()
+            ),
+            Err("Value is encrypted under a different COLUMN_ENCRYPTION_KEY")
+        );
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:2", sealed.clone()),
+            Err("Encrypted value failed authentication")
+        );
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:1", tampered),
+            Err("Encrypted value failed authentication")
+        );
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:1", sealed[..10].to_vec()),
+            Err("Encrypted value is truncated")
+        );
+
+        assert!(ColumnKey::from_base64("not base64!").is_err());
+        assert!(ColumnKey::from_base64(&general_purpose::STANDARD.encode([7u8; 16])).is_err());
+        assert_eq!(key.id().len(), 2 * COLUMN_KEY_ID_LEN);
+        assert_ne!(key.id(), other.id());
+    }
+
+    #[test]
+    fn test_column_keyring_decrypts_with_old_keys() {
+        let key = |byte: u8| {
+            ColumnKey::from_base64(&general_purpose::STANDARD.encode([byte; 32])).unwrap()
+        };
+        let under_old = encrypt_column(&key(7), b"users.avatar:1", b"old avatar");
+        let under_older = encrypt_column(&key(8), b"users.avatar:1", b"older avatar");
+        let under_current = encrypt_column(&key(9), b"users.avatar:1", b"new avatar");
+        let keys = ColumnKeyring::new(Some(key(9)), vec![key(7), key(8)]);
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:1", under_old),
+            Ok(b"old avatar".to_vec())
+        );
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:1", under_older.clone()),
+            Ok(b"older avatar".to_vec())
+        );
+        assert_eq!(
+            decrypt_column(&keys, b"users.avatar:1", under_current),
+            Ok(b"new avatar".to_vec())
+        );
+
+        // Old keys alone still decrypt, e.g. while turning encryption off
+        let retired = ColumnKeyring::new(None, vec![key(8)]);
+        assert!(retired.current().is_none());
+        assert_eq!(
+            decrypt_column(&retired, b"users.avatar:1", under_older),
+            Ok(b"older avatar".to_vec())
+        );
+
+        let parsed = ColumnKeyring::parse_old_keys(&format!(
+            " {}, {},",
+            general_purpose::STANDARD.encode([7u8; 32]),
+            general_purpose::STANDARD.encode([8u8; 32])
+        ))
+        .unwrap();
+        assert_eq!(
+            parsed.iter().map(ColumnKey::id).collect::<Vec<_>>(),
+            [key(7).id(), key(8).id()]
+        );
+        assert!(ColumnKeyring::parse_old_keys("").unwrap().is_empty());
+        assert!(ColumnKeyring::parse_old_keys("not base64!").is_err());
+    }
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_ed25519_signature_matches_rfc_8032_vectors() {
+        // RFC 8032 section 7.1, tests 1 and 2
+        let public_1 = hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let signature_1 = hex(
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        );
+        let public_2 = hex("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c");
+        let signature_2 = hex(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        );
+        let x509 =
+            |raw: &[u8]| general_purpose::STANDARD.encode([&ED25519_X509_HEADER[..], raw].concat());
+
+        assert_eq!(
+            verify_ed25519_signature(&x509(&public_1), b"", &signature_1),
+            Ok(true)
+        );
+        assert_eq!(
+            verify_ed25519_signature(&x509(&public_2), &[0x72], &signature_2),
+            Ok(true)
+        );
+        // Raw keys are accepted like everywhere else
+        let raw_2 = general_purpose::STANDARD.encode(&public_2);
+        assert_eq!(
+            verify_ed25519_signature(&raw_2, &[0x72], &signature_2),
+            Ok(true)
+        );
+
+        assert_eq!(
+            verify_ed25519_signature(&x509(&public_2), &[0x73], &signature_2),
+            Ok(false)
+        );
+        assert_eq!(
+            verify_ed25519_signature(&x509(&public_1), &[0x72], &signature_2),
+            Ok(false)
+        );
+        assert_eq!(
+            verify_ed25519_signature(&x509(&public_1), b"", &signature_1[..32]),
+            Err("Invalid Ed25519 signature length")
+        );
+        let x25519_key = encode_raw_key_to_x509(&public_1.clone().try_into().unwrap());
+        assert_eq!(
+            verify_ed25519_signature(&x25519_key, b"", &signature_1),
+            Err("X25519 keys cannot verify signatures")
+        );
+        assert!(verify_ed25519_signature("not a key", b"", &signature_1).is_err());
+    }
+
+    #[test]
+    fn test_key_possession_proof_vectors() {
+        // RFC 7748 section 6.1: the server plays Alice, the client's new key is Bob's
+        let server_secret =
+            hex32("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let server_public =
+            hex32("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        let client_secret =
+            hex32("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let client_public =
+            hex32("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let shared = hex32("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(x25519(server_secret, X25519_BASEPOINT_BYTES), server_public);
+        assert_eq!(x25519(client_secret, server_public), shared);
+
+        let nonce: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let proof = key_possession_proof(&shared, &nonce, &client_public);
+        assert_eq!(
+            proof,
+            hex32("faf080c67e19a2c1cc6e23fed794c3289ef9a7eb55e04ef97f1c6d4d32d3b361")
+        );
+        assert!(verify_key_possession(
+            server_secret,
+            client_public,
+            &nonce,
+            &proof
+        ));
+    }
+
+    #[test]
+    fn test_key_possession_rejects_wrong_proofs() {
+        let challenge = generate_key_challenge();
+        let client_secret = [7u8; 32];
+        let client_public = x25519(client_secret, X25519_BASEPOINT_BYTES);
+        let shared = x25519(client_secret, challenge.server_public);
+        let proof = key_possession_proof(&shared, &challenge.nonce, &client_public);
+        assert!(verify_key_possession(
+            challenge.server_secret,
+            client_public,
+            &challenge.nonce,
+            &proof
+        ));
+
+        // A proof for another key, another nonce, or a truncated proof fails
+        let other_public = x25519([9u8; 32], X25519_BASEPOINT_BYTES);
+        assert!(!verify_key_possession(
+            challenge.server_secret,
+            other_public,
+            &challenge.nonce,
+            &proof
+        ));
+        assert!(!verify_key_possession(
+            challenge.server_secret,
+            client_public,
+            &[0u8; 32],
+            &proof
+        ));
+        assert!(!verify_key_possession(
+            challenge.server_secret,
+            client_public,
+            &challenge.nonce,
+            &proof[..16]
+        ));
+
+        // The all-zero point yields a predictable shared secret and is refused
+        let zero_proof = key_possession_proof(&[0u8; 32], &challenge.nonce, &[0u8; 32]);
+        assert!(!verify_key_possession(
+            challenge.server_secret,
+            [0u8; 32],
+            &challenge.nonce,
+            &zero_proof
+        ));
+    }
+
+    #[test]
+    fn test_url_safe_keys_match_their_standard_form() {
+        let url_key = generate_keypair_base64url();
+        assert!(!url_key.contains(['+', '/', '=']));
+        let standard = standard_public_key(&url_key).unwrap();
+        assert!(validate_x509_public_key(&standard));
+        assert_eq!(
+            encode_url_safe(&general_purpose::STANDARD.decode(&standard).unwrap()),
+            url_key
+        );
+        assert_eq!(
+            standard_public_key(&standard).as_deref(),
+            Some(standard.as_str())
+        );
+        assert_eq!(
+            decode_url_safe(&format!("{}=", url_key)),
+            decode_url_safe(&url_key)
+        );
+
+        // Bytes that need `+` and `/` in standard base64
+        assert_eq!(encode_url_safe(&[0xfb, 0xff]), "-_8");
+        assert_eq!(decode_url_safe("-_8").unwrap(), [0xfb, 0xff]);
+        assert!(decode_url_safe("+/8").is_err());
+        assert_eq!(standard_public_key("not a key"), None);
+    }
+
+    #[test]
+    fn test_key_format_tells_raw_keys_from_x509() {
+        let x509 = generate_keypair_base64();
+        assert_eq!(key_format(&x509), KeyFormat::X509);
+        let raw = decode_x509_to_raw_key(&x509).unwrap();
+        assert_eq!(
+            key_format(&general_purpose::STANDARD.encode(raw)),
+            KeyFormat::Raw
+        );
+        assert_eq!(encode_raw_key_to_x509(&raw), x509);
+        assert_eq!(key_format(""), KeyFormat::Unparseable);
+        assert_eq!(
+            key_format(&general_purpose::STANDARD.encode([0u8; 31])),
+            KeyFormat::Unparseable
+        );
+        assert_eq!(key_format("not a key"), KeyFormat::Unparseable);
+    }
+
     #[test]
     fn test_x509_validation() {
         let valid_key = generate_keypair_base64();
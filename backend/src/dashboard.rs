@@ -0,0 +1,206 @@
+//! Admin dashboard for Safe Chat backend
+//!
+//! `GET /admin/dashboard` is an HTML page for operators: open WebSocket connections,
+//! messages sent in the last hour, registrations in the last 24 hours, rate limit hits and
+//! the users who sent the most messages today. It reloads itself every 30 seconds.
+//!
+//! Like every admin route, the page is behind `require_admin`. It is rendered here,
+//! escaping everything users control, such as usernames.
+
+use crate::preferences::{DEFAULT_TIMEZONE, format_timestamp};
+use crate::service::{self, ActivitySummary};
+use crate::state::AppState;
+
+use axum::extract::State;
+use axum::http::header::CACHE_CONTROL;
+use axum::response::{Html, IntoResponse, Response};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+/// How often the page reloads itself, in seconds.
+const REFRESH_SECS: u32 = 30;
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The instance counters shown next to the activity summary.
+struct InstanceCounters {
+    ws_connections: usize,
+    rate_limit_hits: u64,
+    new_conversation_limit_hits: u64,
+}
+
+fn render_dashboard(
+    counters: &InstanceCounters,
+    summary: &ActivitySummary,
+    generated_at: &str,
+) -> String {
+    let stats = [
+        (
+            "WebSocket connections on this instance",
+            counters.ws_connections.to_string(),
+        ),
+        (
+            "Messages sent in the last hour",
+            summary.messages_last_hour.to_string(),
+        ),
+        (
+            "Registrations in the last 24 hours",
+            summary.registrations_last_day.to_string(),
+        ),
+        (
+            "Rate limit hits since this instance started",
+            counters.rate_limit_hits.to_string(),
+        ),
+        (
+            "New conversation limit hits since this instance started",
+            counters.new_conversation_limit_hits.to_string(),
+        ),
+    ];
+    let stat_rows: String = stats
+        .iter()
+        .map(|(label, value)| format!("<tr><th>{}</th><td>{}</td></tr>\n", label, value))
+        .collect();
+    let sender_rows: String = if summary.top_senders.is_empty() {
+        "<tr><td colspan=\"3\">No messages sent today</td></tr>\n".to_string()
+    } else {
+        summary
+            .top_senders
+            .iter()
+            .enumerate()
+            .map(|(rank, (sender, username))| {
+                let username = match username {
+                    Some(username) => escape_html(username),
+                    None => "<em>deleted account</em>".to_string(),
+                };
+                format!(
+                    "<tr><td>{}</td><td title=\"{}\">{}</td><td>{}</td></tr>\n",
+                    rank + 1,
+                    sender.user_id,
+                    username,
+                    sender.sends
+                )
+            })
+            .collect()
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+
+<head>
+    <meta charset="UTF-8">
+    <meta http-equiv="refresh" content="{refresh}">
+    <title>Safe Chat Admin Dashboard</title>
+    <style>
+        body {{
+            font-family: Arial, sans-serif;
+            margin: 2em;
+        }}
+
+        table {{
+            border-collapse: collapse;
+            margin-bottom: 2em;
+        }}
+
+        th, td {{
+            border: 1px solid #ccc;
+            padding: 0.4em 0.8em;
+            text-align: left;
+        }}
+    </style>
+</head>
+
+<body>
+    <h1>Safe Chat Admin Dashboard</h1>
+    <p>Updated {generated_at}; reloads every {refresh} seconds. <a href="/admin/index.html">Admin pages</a></p>
+    <table>
+{stat_rows}    </table>
+    <h2>Most active senders today (UTC)</h2>
+    <table>
+        <tr><th>#</th><th>User</th><th>Messages</th></tr>
+{sender_rows}    </table>
+</body>
+
+</html>
+"#,
+        refresh = REFRESH_SECS,
+        generated_at = escape_html(generated_at),
+        stat_rows = stat_rows,
+        sender_rows = sender_rows,
+    )
+}
+
+/// Serves the dashboard.
+pub async fn get_dashboard(State(state): State<Arc<AppState>>) -> Response {
+    let now = state.clock.now();
+    let summary =
+        match service::activity_summary(state.users.as_ref(), state.messages.as_ref(), now).await {
+            Ok(summary) => summary,
+            Err(e) => return e.into_response(),
+        };
+    let counters = InstanceCounters {
+        ws_connections: state.connection_tracker.open_connections(),
+        rate_limit_hits: service::RATE_LIMIT_HITS.load(Ordering::Relaxed),
+        new_conversation_limit_hits: state.new_conversation_limit_hits.load(Ordering::Relaxed),
+    };
+    let page = render_dashboard(
+        &counters,
+        &summary,
+        &format_timestamp(now, DEFAULT_TIMEZONE),
+    );
+    ([(CACHE_CONTROL, "no-store")], Html(page)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::MessageRepo;
+    use crate::service::{DEFAULT_SEND_LIMITS, send_quota_windows};
+    use crate::websocket::tests::fake_state_with;
+    use axum::body::HttpBody;
+
+    async fn body(response: Response) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_lists_activity_with_escaped_usernames() {
+        let (state, messages) = fake_state_with(|_| {});
+        let quiet = state
+            .users
+            .create_user("quiet", "hash", "key")
+            .await
+            .unwrap();
+        let loud = state
+            .users
+            .create_user("<b>loud</b>", "hash", "key2")
+            .await
+            .unwrap();
+        let windows = send_quota_windows(DEFAULT_SEND_LIMITS.standard, state.clock.now());
+        messages.record_send(quiet, &windows).await.unwrap();
+        for _ in 0..3 {
+            messages.record_send(loud, &windows).await.unwrap();
+        }
+
+        let page = body(get_dashboard(State(state)).await).await;
+        assert!(page.contains("<meta http-equiv=\"refresh\" content=\"30\">"));
+        assert!(page.contains("<th>Messages sent in the last hour</th><td>4</td>"));
+        assert!(page.contains("<th>Registrations in the last 24 hours</th><td>2</td>"));
+        assert!(!page.contains("<b>loud</b>"));
+        let loud_row = page.find("&lt;b&gt;loud&lt;/b&gt;</td><td>3</td>").unwrap();
+        assert!(loud_row < page.find("quiet</td><td>1</td>").unwrap());
+    }
+}
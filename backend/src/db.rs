@@ -0,0 +1,268 @@
+//! Database transaction helpers for Safe Chat backend
+//!
+//! Transactions run at Postgres' default `READ COMMITTED` unless they opt into a stricter
+//! level through `WithIsolation::begin_with_isolation`. Under `SERIALIZABLE` Postgres
+//! aborts one of two conflicting transactions with SQLSTATE `40001`; the caller is expected
+//! to run the whole transaction again, which `retry_serializable` does.
+
+use async_trait::async_trait;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+pub type PgTransaction = Transaction<'static, Postgres>;
+
+/// How many times `retry_serializable` runs a transaction before returning its error.
+pub const MAX_SERIALIZATION_ATTEMPTS: u32 = 5;
+/// Wait before the second attempt; each further attempt waits this much longer.
+const SERIALIZATION_RETRY_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Transaction isolation levels, see the Postgres `SET TRANSACTION` documentation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    fn as_sql(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+#[async_trait]
+pub trait WithIsolation {
+    /// Starts a transaction at `level`.
+    async fn begin_with_isolation(
+        &self,
+        level: IsolationLevel,
+    ) -> Result<PgTransaction, sqlx::Error>;
+}
+
+#[async_trait]
+impl WithIsolation for PgPool {
+    async fn begin_with_isolation(
+        &self,
+        level: IsolationLevel,
+    ) -> Result<PgTransaction, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        // Must be the first statement of the transaction
+        sqlx::query(&format!(
+            "SET TRANSACTION ISOLATION LEVEL {}",
+            level.as_sql()
+        ))
+        .execute(&mut *tx)
+        .await?;
+        Ok(tx)
+    }
+}
+
+/// Whether Postgres aborted the transaction to keep it serializable (`40001`) or to break
+/// a deadlock (`40P01`). Either way, running it again may succeed.
+pub fn is_serialization_failure(error: &sqlx::Error) -> bool {
+    error
+        .as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|code| code == "40001" || code == "40P01")
+}
+
+/// Whether `error` means the database could not be reached or cannot serve queries: a lost
+/// or refused connection, a pool timeout, or Postgres refusing connections or shutting down
+/// (SQLSTATE classes `08` and `53`, and the `57P` shutdown codes). Errors about the query itself
+/// are not outages. These are what trip the database circuit breaker.
+pub fn is_outage(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::Protocol(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(e) => e.code().is_some_and(|code| {
+            code.starts_with("08") || code.starts_with("53") || code.starts_with("57P")
+        }),
+        _ => false,
+    }
+}
+
+/// Runs `transaction` until it does not fail with a serialization failure, at most
+/// `MAX_SERIALIZATION_ATTEMPTS` times. `transaction` must begin, run and commit the whole
+/// transaction, since an aborted one cannot continue.
+pub async fn retry_serializable<T, F, Fut>(mut transaction: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match transaction().await {
+            Err(e) if attempt < MAX_SERIALIZATION_ATTEMPTS && is_serialization_failure(&e) => {
+                warn!(
+                    "Serialization failure on attempt {}, retrying: {}",
+                    attempt, e
+                );
+                tokio::time::sleep(SERIALIZATION_RETRY_BACKOFF * attempt).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Row;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use uuid::Uuid;
+
+    async fn migrated_db() -> PgPool {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a migrated database");
+        PgPool::connect(&url).await.unwrap()
+    }
+
+    /// A user with one SENT message to themselves, returning both ids.
+    async fn seed_message(db: &PgPool) -> (Uuid, Uuid) {
+        let user: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (username, password_hash, public_key) VALUES ($1, 'hash', $1) RETURNING id",
+        )
+        .bind(format!("isolation-{}", Uuid::new_v4().simple()))
+        .fetch_one(db)
+        .await
+        .unwrap();
+        let message = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO messages (id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv) VALUES ($1, 0, $2, $2, 'SENT', 'Text', '\\x01', '\\x00')",
+        )
+        .bind(message)
+        .bind(user)
+        .execute(db)
+        .await
+        .unwrap();
+        (user, message)
+    }
+
+    /// Reads the message's status, then overwrites it in the same transaction.
+    async fn read_then_update(
+        tx: &mut PgTransaction,
+        message: Uuid,
+        status: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT status FROM messages WHERE id = $1")
+            .bind(message)
+            .fetch_one(&mut **tx)
+            .await?;
+        sqlx::query("UPDATE messages SET status = $1 WHERE id = $2")
+            .bind(status)
+            .bind(message)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_conflicting_serializable_transactions_are_retried() {
+        let db = migrated_db().await;
+        let (user, message) = seed_message(&db).await;
+
+        // Two transactions read the same row; the second write after the first commits fails
+        let mut first = db
+            .begin_with_isolation(IsolationLevel::Serializable)
+            .await
+            .unwrap();
+        let mut second = db
+            .begin_with_isolation(IsolationLevel::Serializable)
+            .await
+            .unwrap();
+        sqlx::query("SELECT status FROM messages WHERE id = $1")
+            .bind(message)
+            .fetch_one(&mut *second)
+            .await
+            .unwrap();
+        read_then_update(&mut first, message, "DELIVERED")
+            .await
+            .unwrap();
+        first.commit().await.unwrap();
+        let err = read_then_update(&mut second, message, "READ")
+            .await
+            .unwrap_err();
+        assert!(is_serialization_failure(&err), "unexpected error: {}", err);
+        second.rollback().await.unwrap();
+
+        // The same conflict inside retry_serializable: the loser runs again and succeeds
+        let attempts = AtomicU32::new(0);
+        retry_serializable(|| async {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+            let mut tx = db
+                .begin_with_isolation(IsolationLevel::Serializable)
+                .await?;
+            sqlx::query("SELECT status FROM messages WHERE id = $1")
+                .bind(message)
+                .fetch_one(&mut *tx)
+                .await?;
+            if attempt == 1 {
+                let mut other = db
+                    .begin_with_isolation(IsolationLevel::Serializable)
+                    .await?;
+                read_then_update(&mut other, message, "DELIVERED").await?;
+                other.commit().await?;
+            }
+            read_then_update(&mut tx, message, "READ").await?;
+            tx.commit().await
+        })
+        .await
+        .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let status: String = sqlx::query("SELECT status FROM messages WHERE id = $1")
+            .bind(message)
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .get("status");
+        assert_eq!(status, "READ");
+
+        sqlx::query("DELETE FROM messages WHERE id = $1")
+            .bind(message)
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_only_connection_errors_are_outages() {
+        assert!(is_outage(&sqlx::Error::PoolTimedOut));
+        assert!(is_outage(&sqlx::Error::Io(
+            std::io::ErrorKind::ConnectionReset.into()
+        )));
+        assert!(!is_outage(&sqlx::Error::RowNotFound));
+        assert!(!is_outage(&sqlx::Error::ColumnNotFound(
+            "avatar".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_are_not_retried() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), sqlx::Error> = retry_serializable(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(sqlx::Error::RowNotFound)
+        })
+        .await;
+        assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
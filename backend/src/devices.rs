@@ -0,0 +1,244 @@
+//! Devices module for Safe Chat backend
+//!
+//! With `MULTI_DEVICE` enabled, each of a user's devices registers its own X25519 key
+//! instead of sharing the account key. Profiles list the device keys so senders can
+//! encrypt one copy per device, each `send_message` copy names its target `device_id`, and
+//! a device connects with `/ws?device_id=` to receive only the copies meant for it.
+//!
+//! When the flag is off these endpoints answer 404 and the account's single key is used.
+
+use crate::api::{UuidPath, extract_user_id_from_auth};
+use crate::preferences::{DEFAULT_TIMEZONE, format_timestamp};
+use crate::repo::DeviceRecord;
+use crate::service;
+use crate::state::AppState;
+use crate::websocket::{DeviceRevoked, broadcast_device_revoked_to_user};
+
+use axum::extract::{Json, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct RegisterDeviceRequest {
+    pub name: String,
+    /// Base64 X.509 X25519 public key generated on the device.
+    pub public_key: String,
+    /// Cipher suites the device can decrypt; `AES_256_GCM` when omitted.
+    #[serde(default)]
+    pub supported_ciphers: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceResponse {
+    pub id: String,
+    pub name: String,
+    pub public_key: String,
+    pub created_at: String,
+    pub last_active: Option<String>,
+    pub supported_ciphers: Vec<String>,
+}
+
+/// A device key as shown to other users; device names stay private to the owner.
+#[derive(Serialize)]
+pub struct DeviceKey {
+    pub id: String,
+    pub public_key: String,
+    pub supported_ciphers: Vec<String>,
+}
+
+fn device_response(device: DeviceRecord) -> DeviceResponse {
+    DeviceResponse {
+        id: device.id.to_string(),
+        name: device.name,
+        public_key: device.public_key,
+        created_at: format_timestamp(device.created_at, DEFAULT_TIMEZONE),
+        last_active: device
+            .last_active
+            .map(|at| format_timestamp(at, DEFAULT_TIMEZONE)),
+        supported_ciphers: device.supported_ciphers,
+    }
+}
+
+pub(crate) fn device_key(device: DeviceRecord) -> DeviceKey {
+    DeviceKey {
+        id: device.id.to_string(),
+        public_key: device.public_key,
+        supported_ciphers: device.supported_ciphers,
+    }
+}
+
+/// Authenticates the request and checks that multi-device support is enabled.
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<Uuid, (StatusCode, &'static str)> {
+    let user_id = extract_user_id_from_auth(
+        headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    )
+    .inspect_err(|_| info!("Unauthorized access attempt to /devices endpoint"))?;
+    if !state.multi_device {
+        return Err((StatusCode::NOT_FOUND, "Multi-device support is disabled"));
+    }
+    Ok(user_id)
+}
+
+/// Registers a device key for the authenticated user.
+///
+/// Returns 201 with the device, 400 for an invalid name or key, and 409 with
+/// `device_already_registered` or `device_limit_reached`.
+pub async fn register_device(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<RegisterDeviceRequest>,
+) -> impl IntoResponse {
+    let user_id = match authorize(&state, &headers) {
+        Ok(uid) => uid,
+        Err(e) => return e.into_response(),
+    };
+    match service::register_device(
+        state.devices.as_ref(),
+        user_id,
+        &payload.name,
+        &payload.public_key,
+        &payload.supported_ciphers,
+        state.clock.now(),
+    )
+    .await
+    {
+        Ok(device) => {
+            info!("User {} registered device {}", user_id, device.id);
+            (StatusCode::CREATED, Json(device_response(device))).into_response()
+        }
+        Err(err) => {
+            info!("Registering device for user {} failed: {}", user_id, err);
+            err.into_response()
+        }
+    }
+}
+
+/// Lists the authenticated user's devices, oldest first.
+pub async fn list_devices(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match authorize(&state, &headers) {
+        Ok(uid) => uid,
+        Err(e) => return e.into_response(),
+    };
+    match state.devices.list_devices(user_id).await {
+        Ok(devices) => {
+            let devices: Vec<DeviceResponse> = devices.into_iter().map(device_response).collect();
+            (StatusCode::OK, Json(devices)).into_response()
+        }
+        Err(err) => {
+            info!("Database error in /devices: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// Revokes one of the authenticated user's devices.
+///
+/// Message copies addressed to the device are deleted with it. Its WebSocket connections
+/// are closed with `device_revoked` and the user's other devices get a `device_revoked` event.
+pub async fn revoke_device(
+    Path(device_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match authorize(&state, &headers) {
+        Ok(uid) => uid,
+        Err(e) => return e.into_response(),
+    };
+    let device_id = match Uuid::parse_str(&device_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid device_id format").into_response(),
+    };
+    if let Err(err) = service::revoke_device(state.devices.as_ref(), user_id, device_id).await {
+        info!(
+            "Revoking device {} for user {} failed: {}",
+            device_id, user_id, err
+        );
+        return err.into_response();
+    }
+    info!("User {} revoked device {}", user_id, device_id);
+    broadcast_device_revoked_to_user(
+        &state,
+        user_id,
+        DeviceRevoked {
+            device_id: device_id.to_string(),
+        },
+    )
+    .await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Serialize)]
+pub struct SupportedCiphersResponse {
+    /// Suites every device of the user can decrypt and this server accepts; encrypt with one
+    /// of these.
+    pub cipher_suites: Vec<String>,
+    pub devices: Vec<DeviceCiphers>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceCiphers {
+    pub id: String,
+    pub supported_ciphers: Vec<String>,
+}
+
+/// Lists the cipher suites a user's devices support, so a sender can pick one every device
+/// can decrypt.
+///
+/// Without `MULTI_DEVICE` the user's devices are ignored and the account key's
+/// `AES_256_GCM` is reported. Responds with 401 if authentication fails, 404 if the user
+/// does not exist, or 500 on database errors.
+pub async fn get_supported_ciphers(
+    UuidPath(target_user_id): UuidPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(e) = extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        info!("Unauthorized access attempt to /users/{{}}/supported-ciphers endpoint");
+        return e.into_response();
+    }
+    match state.users.find_by_id(target_user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(err) => {
+            info!("Database error in /users/{{id}}/supported-ciphers: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    }
+    let devices = if state.multi_device {
+        match state.devices.list_devices(target_user_id).await {
+            Ok(devices) => devices,
+            Err(err) => {
+                info!("Database error in /users/{{id}}/supported-ciphers: {}", err);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    let response = SupportedCiphersResponse {
+        cipher_suites: service::shared_cipher_suites(&devices, &state.cipher_suites),
+        devices: devices
+            .into_iter()
+            .map(|device| DeviceCiphers {
+                id: device.id.to_string(),
+                supported_ciphers: device.supported_ciphers,
+            })
+            .collect(),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
@@ -0,0 +1,96 @@
+//! Invites module for Safe Chat backend
+//!
+//! With `REGISTRATION_MODE=invite`, `POST /register` needs an `invite_code`. Operators
+//! create single-use codes through `POST /admin/invites` and list the ones still usable
+//! through `GET /admin/invites`. Every invite created is written to the admin audit log.
+
+use crate::announcements::audit_actor;
+use crate::preferences::{DEFAULT_TIMEZONE, format_timestamp};
+use crate::repo::InviteRecord;
+use crate::service;
+use crate::state::AppState;
+
+use axum::extract::{ConnectInfo, Json, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Deserialize)]
+pub struct CreateInviteRequest {
+    /// Shown in `GET /admin/invites`, e.g. who the invite is for.
+    pub note: Option<String>,
+    /// RFC 3339 time after which the code is no longer accepted; defaults to seven days.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct InviteResponse {
+    pub id: String,
+    pub code: String,
+    pub note: Option<String>,
+    pub created_at: String,
+    pub expires_at: String,
+}
+
+impl InviteResponse {
+    fn from_record(record: &InviteRecord) -> Self {
+        Self {
+            id: record.id.to_string(),
+            code: record.code.clone(),
+            note: record.note.clone(),
+            created_at: format_timestamp(record.created_at, DEFAULT_TIMEZONE),
+            expires_at: format_timestamp(record.expires_at, DEFAULT_TIMEZONE),
+        }
+    }
+}
+
+/// Creates a single-use invite code.
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateInviteRequest>,
+) -> impl IntoResponse {
+    let actor = audit_actor(&state, &headers, peer);
+    match service::create_invite(
+        state.users.as_ref(),
+        payload.note.as_deref(),
+        payload.expires_at,
+        state.clock.now(),
+        &actor,
+    )
+    .await
+    {
+        Ok(record) => {
+            info!("Invite {} created from {}", record.id, actor.source_ip);
+            (
+                StatusCode::CREATED,
+                Json(InviteResponse::from_record(&record)),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            info!("Creating invite failed: {}", err);
+            err.into_response()
+        }
+    }
+}
+
+/// Lists the invites that are neither used nor expired, oldest first.
+pub async fn list_invites(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match state.users.unused_invites(state.clock.now()).await {
+        Ok(records) => {
+            let invites: Vec<InviteResponse> =
+                records.iter().map(InviteResponse::from_record).collect();
+            (StatusCode::OK, Json(invites)).into_response()
+        }
+        Err(err) => {
+            info!("Database error in /admin/invites: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
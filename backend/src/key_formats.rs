@@ -0,0 +1,141 @@
+//! Public key format maintenance for Safe Chat backend
+//!
+//! Keys are stored as base64 X.509 (SubjectPublicKeyInfo), but older clients uploaded the
+//! bare 32-byte X25519 key. `GET /admin/keys/stats` counts the stored keys by format and
+//! lists the users whose key cannot be parsed at all, so support can follow up with them.
+//! `POST /admin/keys/normalize` stores every raw key again as X.509, in batches, reporting
+//! progress as NDJSON while it runs.
+
+use crate::announcements::audit_actor;
+use crate::api::{DUMP_CHANNEL_CAPACITY, DumpSender, send_dump_chunk};
+use crate::repo::AuditActor;
+use crate::service::{self, DEFAULT_KEY_NORMALIZE_BATCH, KeyNormalizeBatch};
+use crate::state::AppState;
+
+use axum::body::StreamBody;
+use axum::extract::{ConnectInfo, Json, Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use futures_util::stream;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// Counts the stored public keys by format.
+///
+/// ```json
+/// { "x509": 1200, "raw": 14, "unparseable": 1, "unparseable_user_ids": ["..."] }
+/// ```
+pub async fn get_key_stats(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match service::key_format_stats(state.users.as_ref()).await {
+        Ok(stats) => (
+            StatusCode::OK,
+            Json(json!({
+                "x509": stats.x509,
+                "raw": stats.raw,
+                "unparseable": stats.unparseable.len(),
+                "unparseable_user_ids": stats.unparseable,
+            })),
+        )
+            .into_response(),
+        Err(err) => {
+            info!("Database error in /admin/keys/stats: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NormalizeQuery {
+    /// Users read per batch; defaults to `DEFAULT_KEY_NORMALIZE_BATCH`.
+    pub batch_size: Option<i64>,
+}
+
+/// Stores every raw public key as X.509, `batch_size` users at a time.
+///
+/// Responds with one NDJSON line per batch, then a line with `"done": true` and the
+/// totals. Each rewrite is recorded in the key history as format-only and in the admin
+/// audit log; partners are not notified, since the key itself is the same. The run goes
+/// on if the client disconnects, and running it again only picks up keys still raw.
+pub async fn normalize_keys(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<NormalizeQuery>,
+) -> impl IntoResponse {
+    let actor = audit_actor(&state, &headers, peer);
+    let batch_size = query.batch_size.unwrap_or(DEFAULT_KEY_NORMALIZE_BATCH);
+    // The first batch runs before responding, so a bad batch size is a plain 400
+    let first =
+        match service::normalize_key_batch(state.users.as_ref(), None, batch_size, &actor).await {
+            Ok(batch) => batch,
+            Err(err) => {
+                info!("Normalizing keys failed: {}", err);
+                return err.into_response();
+            }
+        };
+    let (tx, rx) = mpsc::channel(DUMP_CHANNEL_CAPACITY);
+    tokio::spawn(run_normalize(state, batch_size, actor, first, tx));
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(chunks),
+    )
+        .into_response()
+}
+
+/// Runs the batches after `first`, sending a progress line after each.
+async fn run_normalize(
+    state: Arc<AppState>,
+    batch_size: i64,
+    actor: AuditActor,
+    first: KeyNormalizeBatch,
+    tx: DumpSender,
+) {
+    let (mut scanned, mut normalized) = (0, 0);
+    let mut next = Ok(first);
+    for batch_number in 1.. {
+        let batch = match next {
+            Ok(batch) => batch,
+            Err(err) => {
+                error!("Normalizing keys stopped after {} users: {}", scanned, err);
+                let line = json!({ "error": "Database error", "scanned": scanned, "normalized": normalized });
+                send_line(&tx, line).await;
+                return;
+            }
+        };
+        scanned += batch.scanned;
+        normalized += batch.normalized;
+        let Some(after) = batch.next_after else {
+            break;
+        };
+        send_line(
+            &tx,
+            json!({ "batch": batch_number, "scanned": scanned, "normalized": normalized }),
+        )
+        .await;
+        next = service::normalize_key_batch(state.users.as_ref(), Some(after), batch_size, &actor)
+            .await;
+    }
+    info!(
+        "Normalized {} raw keys of {} users, requested from {}",
+        normalized, scanned, actor.source_ip
+    );
+    send_line(
+        &tx,
+        json!({ "done": true, "scanned": scanned, "normalized": normalized }),
+    )
+    .await;
+}
+
+/// Sends one NDJSON line. A client that went away does not stop the run.
+async fn send_line(tx: &DumpSender, line: Value) {
+    send_dump_chunk(tx, format!("{}\n", line)).await;
+}
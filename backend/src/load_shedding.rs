@@ -0,0 +1,155 @@
+//! Load shedding for Safe Chat backend
+//!
+//! A background task samples the host's memory use every `MEMORY_CHECK_INTERVAL` and
+//! sets `AppState::load_shedding` while it is above `MAX_MEMORY_PERCENT`. While the flag
+//! is set, `LoadSheddingLayer` answers every request except `/health` with 503 without
+//! running its handler, and `/health` itself reports the instance as overloaded.
+
+use crate::state::AppState;
+
+use axum::Json;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::BoxFuture;
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use sysinfo::System;
+use tower::{Layer, Service};
+use tracing::warn;
+
+/// Memory use, in percent of the host's total, above which requests are shed.
+pub const DEFAULT_MAX_MEMORY_PERCENT: f64 = 90.0;
+/// How often memory use is sampled; also the `Retry-After` given to shed requests.
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Always served, so load balancers can see the instance is degraded.
+const EXEMPT_PATH: &str = "/health";
+
+/// Percent of `total` that `used` represents; 0 when the total is unknown.
+fn memory_percent(used: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    used as f64 * 100.0 / total as f64
+}
+
+fn should_shed(shedding: bool, path: &str) -> bool {
+    shedding && path != EXEMPT_PATH
+}
+
+fn overloaded_response() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, MEMORY_CHECK_INTERVAL.as_secs().to_string())],
+        Json(json!({ "error": "server_overloaded" })),
+    )
+        .into_response()
+}
+
+/// Spawns a task that updates `state.load_shedding` from the memory use every
+/// `MEMORY_CHECK_INTERVAL`. Changes are logged as `load_shedding` warnings.
+pub fn spawn_memory_monitor(state: Arc<AppState>, max_memory_percent: f64) {
+    tokio::spawn(async move {
+        let mut system = System::new();
+        let mut interval = tokio::time::interval(MEMORY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            system.refresh_memory();
+            let percent = memory_percent(system.used_memory(), system.total_memory());
+            let shedding = percent > max_memory_percent;
+            if state.load_shedding.swap(shedding, Ordering::Relaxed) != shedding {
+                if shedding {
+                    warn!(
+                        "load_shedding: memory use {:.1}% is above {}%, rejecting requests",
+                        percent, max_memory_percent
+                    );
+                } else {
+                    warn!(
+                        "load_shedding: memory use back to {:.1}%, serving requests",
+                        percent
+                    );
+                }
+            }
+        }
+    });
+}
+
+/// Rejects requests with 503 `{"error": "server_overloaded"}` while the instance is
+/// shedding load, without calling the inner service.
+#[derive(Clone)]
+pub struct LoadSheddingLayer {
+    state: Arc<AppState>,
+}
+
+impl LoadSheddingLayer {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+}
+
+impl<S> Layer<S> for LoadSheddingLayer {
+    type Service = LoadShedding<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LoadShedding {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoadShedding<S> {
+    inner: S,
+    state: Arc<AppState>,
+}
+
+impl<S, B> Service<Request<B>> for LoadShedding<S>
+where
+    S: Service<Request<B>, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let shedding = self.state.load_shedding.load(Ordering::Relaxed);
+        if should_shed(shedding, request.uri().path()) {
+            return Box::pin(async { Ok(overloaded_response()) });
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_percent() {
+        assert_eq!(memory_percent(45, 50), 90.0);
+        assert_eq!(memory_percent(1, 0), 0.0);
+    }
+
+    #[test]
+    fn test_health_is_never_shed() {
+        assert!(should_shed(true, "/messages/unread-counts"));
+        assert!(!should_shed(true, "/health"));
+        assert!(!should_shed(false, "/messages/unread-counts"));
+    }
+
+    #[test]
+    fn test_overloaded_response_contract() {
+        let response = overloaded_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "5");
+    }
+}
@@ -1,32 +1,228 @@
+mod admin;
+mod announcements;
 mod api;
 mod auth;
+mod backup;
+mod circuit_breaker;
+mod clock;
+mod column_encryption;
+mod compression;
+mod contacts;
+mod conversations;
 mod crypto;
+mod dashboard;
+mod db;
+mod devices;
+mod invites;
+mod key_formats;
+mod load_shedding;
+mod media;
+mod preferences;
+mod rate_limit;
+mod repo;
+mod routes;
+mod sealed;
+mod selftest;
+mod service;
 mod state;
+mod webhooks;
 mod websocket;
+mod ws_schema;
 
-use api::{
-    db_dump, get_messages_with_user, get_user_by_id, get_user_by_public_key,
+use admin::{
+    ADMIN_STATIC_DIR, AdminCredentials, create_backlog_cache, parse_ip_allowlist,
+    spawn_backlog_monitor,
 };
-use auth::{get_profile, login, register, update_profile, update_public_key};
-use axum::{Router, routing::get};
+use announcements::list_active_announcements;
+use api::{DEFAULT_MAX_REQUEST_BODY_BYTES, get_version};
+use auth::{
+    DEFAULT_JWT_LEEWAY_SECS, DEFAULT_USERNAME_GRACE_PERIOD_DAYS, KeyChallengeStore,
+    audit_impersonation, check_jwt_secret, database_url_password, generate_jwt_secret,
+    password_hash_params, spawn_username_reservation_cleanup,
+};
+use axum::{
+    Router, ServiceExt,
+    extract::{DefaultBodyLimit, State},
+    middleware,
+    routing::get,
+};
+use circuit_breaker::{
+    DB_CIRCUIT_BREAKER, DEFAULT_DB_BREAKER_COOLDOWN_SECS, DEFAULT_DB_BREAKER_FAILURE_THRESHOLD,
+    db_circuit_layer,
+};
+use clock::{Clock, SystemClock};
+use compression::compression_layer;
+use contacts::create_relationship_cache;
+use crypto::{ColumnKey, ColumnKeyring, parse_cipher_suites};
 use dotenv::dotenv;
+use load_shedding::{DEFAULT_MAX_MEMORY_PERCENT, LoadSheddingLayer, spawn_memory_monitor};
+use preferences::DEFAULT_TIMEZONE;
+use rate_limit::{
+    InMemoryBackend, RateLimitBackend, RateLimitBackendKind, RedisBackend, spawn_rate_limit_pruner,
+};
+use repo::postgres::{PgMessageRepo, PgUserRepo};
+use repo::{MessageRepo, UserRepo};
+use routes::{
+    admin_routes, allow_header_layer, auth_routes, contact_routes, device_routes, message_routes,
+    user_routes,
+};
+use service::{
+    BacklogThresholds, DEFAULT_MAX_CONTACTS, DEFAULT_NEW_CONVERSATION_LIMITS, DEFAULT_SEND_LIMITS,
+    NewConversationLimits, SendLimits, SendQuota,
+};
 use sqlx::postgres::PgPoolOptions;
-use state::AppState;
+use state::{
+    AppState, DEFAULT_CONTACT_REQUEST_COOLDOWN_HOURS, DEFAULT_MIN_CLIENT_VERSION, FeatureFlags,
+    RegistrationMode,
+};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tower::Layer;
 use tower_http::services::ServeFile;
 use tracing_subscriber;
-use websocket::{create_connection_manager, websocket_handler};
+use webhooks::{DEFAULT_WEBHOOK_MAX_ATTEMPTS, WebhookConfig, spawn_webhook_dispatcher};
+use websocket::{
+    CloseReason, ConnectionTracker, DEFAULT_WS_ACK_TIMEOUT, DEFAULT_WS_MAX_CONNECTIONS,
+    DEFAULT_WS_MAX_CONNECTIONS_PER_USER, DEFAULT_WS_MAX_FRAME_BYTES, INSTANCE_ID_HEADER, close_all,
+    connect_redis, create_connection_manager, create_nonce_cache,
+    create_sharded_connection_manager, spawn_nonce_evictor, spawn_pending_message_sweeper,
+    websocket_handler,
+};
+use ws_schema::get_ws_schema;
 
-/// Returns a 200 OK response for health check endpoints.
+/// Returns a 200 OK response for health check endpoints, with this instance's
+/// `X-Instance-Id` header. While the instance is shedding load it returns 503 instead, so
+/// load balancers can route around it.
 ///
 /// # Examples
 ///
 /// ```
-/// let response = health_check().await;
+/// let response = health_check(State(state)).await;
 /// assert_eq!(response.into_response().status(), axum::http::StatusCode::OK);
 /// ```
-async fn health_check() -> impl axum::response::IntoResponse {
-    (axum::http::StatusCode::OK, "OK")
+async fn health_check(State(state): State<Arc<AppState>>) -> impl axum::response::IntoResponse {
+    let (status, body) = if state.load_shedding.load(Ordering::Relaxed) {
+        (axum::http::StatusCode::SERVICE_UNAVAILABLE, "OVERLOADED")
+    } else {
+        (axum::http::StatusCode::OK, "OK")
+    };
+    (
+        status,
+        [(INSTANCE_ID_HEADER, state.instance_id.to_string())],
+        body,
+    )
+}
+
+/// Whether this instance should receive traffic: 200 while the database circuit breaker
+/// is closed, the instance is not shedding load and, with Redis, its subscription is
+/// connected; 503 otherwise. The body reports each, with this instance's `X-Instance-Id`
+/// header.
+///
+/// ```json
+/// { "ready": false, "load_shedding": false, "redis_subscribed": true,
+///   "database": { "state": "open", "consecutive_failures": 5, "retry_after_secs": 7 } }
+/// ```
+async fn readiness_check(State(state): State<Arc<AppState>>) -> impl axum::response::IntoResponse {
+    let load_shedding = state.load_shedding.load(Ordering::Relaxed);
+    let database = DB_CIRCUIT_BREAKER.status();
+    let redis_subscribed = state
+        .redis_subscriptions
+        .as_ref()
+        .map(|subscriptions| subscriptions.is_connected());
+    let ready = !load_shedding && database.state == "closed" && redis_subscribed != Some(false);
+    let status = if ready {
+        axum::http::StatusCode::OK
+    } else {
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        [(INSTANCE_ID_HEADER, state.instance_id.to_string())],
+        axum::Json(serde_json::json!({
+            "ready": ready,
+            "load_shedding": load_shedding,
+            "redis_subscribed": redis_subscribed,
+            "database": database,
+        })),
+    )
+}
+
+/// Describes this deployment so clients can degrade gracefully: the server version, the
+/// oldest client it supports, which optional features are enabled, who may register and
+/// the largest WebSocket message it accepts. No authentication required.
+async fn server_info(State(state): State<Arc<AppState>>) -> impl axum::response::IntoResponse {
+    axum::Json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "min_client_version": state.min_client_version,
+        "features": state.features,
+        "cipher_suites": state.cipher_suites,
+        "registration_mode": state.registration_mode,
+        "timezone": DEFAULT_TIMEZONE.name(),
+        "max_message_size_bytes": state.ws_max_frame_bytes as u64,
+    }))
+}
+
+/// Whether the feature flag variable `name` is set to `true` or `1`.
+fn feature_enabled(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Checks `JWT_SECRET` at startup. A weak secret is an error with `ENVIRONMENT=production`
+/// and a warning otherwise; a secret equal to the database password is always a warning.
+fn validate_jwt_secret(jwt_secret: &str, db_url: &str) -> Result<(), String> {
+    let production = std::env::var("ENVIRONMENT").is_ok_and(|v| v == "production");
+    if let Err(weakness) = check_jwt_secret(jwt_secret) {
+        let message = format!(
+            "{}; generate one with `backend --generate-jwt-secret`",
+            weakness
+        );
+        if production {
+            return Err(message);
+        }
+        tracing::warn!("{} (refused when ENVIRONMENT=production)", message);
+    }
+    let database_password = database_url_password(db_url)
+        .map(str::to_string)
+        .or_else(|| std::env::var("POSTGRES_PASSWORD").ok());
+    if database_password.as_deref() == Some(jwt_secret) {
+        tracing::warn!(
+            "JWT_SECRET equals the database password: anyone who reads the connection string can forge tokens"
+        );
+    }
+    Ok(())
+}
+
+/// Resolves once the process receives Ctrl+C or SIGTERM, after telling every
+/// WebSocket client that the server is going away.
+async fn shutdown_signal(state: Arc<AppState>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("shutdown signal received, closing WebSocket connections");
+    close_all(&state.connections, CloseReason::ServerShutdown).await;
+    // Give the outgoing tasks a moment to flush their close frames
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 }
 
 #[tokio::main]
@@ -67,45 +263,443 @@ async fn health_check() -> impl axum::response::IntoResponse {
 /// }
 /// ```
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--generate-jwt-secret") {
+        println!("{}", generate_jwt_secret());
+        return;
+    }
     dotenv().ok();
     tracing_subscriber::fmt::init();
 
     let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let jwt_secret_check = validate_jwt_secret(&jwt_secret, &db_url);
+    if args.iter().any(|arg| arg == "--check-config") {
+        match jwt_secret_check {
+            Ok(()) => println!("Configuration OK"),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Err(e) = jwt_secret_check {
+        tracing::error!("Refusing to start: {}", e);
+        std::process::exit(1);
+    }
+    let breaker_failure_threshold = std::env::var("DB_BREAKER_FAILURE_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_DB_BREAKER_FAILURE_THRESHOLD);
+    let breaker_cooldown_secs = std::env::var("DB_BREAKER_COOLDOWN_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_DB_BREAKER_COOLDOWN_SECS);
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    DB_CIRCUIT_BREAKER.configure(
+        breaker_failure_threshold,
+        Duration::from_secs(breaker_cooldown_secs),
+        clock.clone(),
+    );
     let db = PgPoolOptions::new()
         .max_connections(5)
         .connect(&db_url)
         .await
         .expect("Failed to connect to Postgres");
-    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let connections = create_connection_manager();
-    let state = Arc::new(AppState { db, jwt_secret, connections });
+    let jwt_leeway_secs = std::env::var("JWT_LEEWAY_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_JWT_LEEWAY_SECS);
+    let argon2_cost = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|v| *v > 0)
+    };
+    let password_hash_params = password_hash_params(
+        argon2_cost("ARGON2_MEMORY_KIB"),
+        argon2_cost("ARGON2_ITERATIONS"),
+        argon2_cost("ARGON2_PARALLELISM"),
+    );
+    // DashMap picks a shard count from the CPU count unless one is configured
+    let connections = match std::env::var("WS_CONNECTION_SHARD_COUNT").ok() {
+        Some(value) => match value.parse::<usize>() {
+            Ok(shards) if shards > 1 && shards.is_power_of_two() => {
+                create_sharded_connection_manager(shards)
+            }
+            _ => {
+                tracing::warn!(
+                    "WS_CONNECTION_SHARD_COUNT={} is not a power of two above 1, using the default",
+                    value
+                );
+                create_connection_manager()
+            }
+        },
+        None => create_connection_manager(),
+    };
+    let messages: Arc<dyn MessageRepo> = Arc::new(PgMessageRepo::new(db.clone()));
+    spawn_pending_message_sweeper(messages.clone(), clock.clone());
+    let relationships = create_relationship_cache();
+    let nonces = create_nonce_cache();
+    spawn_nonce_evictor(nonces.clone());
+    let require_contact_for_messages = std::env::var("REQUIRE_CONTACT_FOR_MESSAGES")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let self_messages_enabled = std::env::var("SELF_MESSAGES_ENABLED")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let contact_request_cooldown_secs = std::env::var("CONTACT_REQUEST_COOLDOWN_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_CONTACT_REQUEST_COOLDOWN_HOURS)
+        * 3600;
+    let max_contacts = std::env::var("MAX_CONTACTS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_MAX_CONTACTS);
+    let pins_exempt_from_read_deletion = std::env::var("PINS_EXEMPT_FROM_READ_DELETION")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let allow_unproven_key_updates = std::env::var("ALLOW_UNPROVEN_KEY_UPDATES")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(true);
+    let registration_mode = std::env::var("REGISTRATION_MODE")
+        .map(|v| {
+            RegistrationMode::parse(&v).expect("REGISTRATION_MODE must be open, invite or closed")
+        })
+        .unwrap_or_default();
+    let username_grace_period_days = std::env::var("USERNAME_GRACE_PERIOD_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<i32>().ok())
+        .filter(|days| *days >= 0)
+        .unwrap_or(DEFAULT_USERNAME_GRACE_PERIOD_DAYS);
+    let allow_ndjson_import = std::env::var("ALLOW_NDJSON_IMPORT")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let column_key = std::env::var("COLUMN_ENCRYPTION_KEY")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| {
+            ColumnKey::from_base64(&v).expect("COLUMN_ENCRYPTION_KEY must be 32 bytes in base64")
+        });
+    let old_column_keys = ColumnKeyring::parse_old_keys(
+        &std::env::var("COLUMN_ENCRYPTION_OLD_KEYS").unwrap_or_default(),
+    )
+    .expect("COLUMN_ENCRYPTION_OLD_KEYS must be comma-separated 32-byte keys in base64");
+    let multi_device = std::env::var("MULTI_DEVICE")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let cipher_suites = parse_cipher_suites(&std::env::var("CIPHER_SUITES").unwrap_or_default())
+        .expect(
+            "CIPHER_SUITES must be a comma-separated list of AES_256_GCM and CHACHA20_POLY1305",
+        );
+    let features = FeatureFlags {
+        group_messaging: feature_enabled("FEATURE_GROUP_MESSAGING"),
+        reactions: feature_enabled("FEATURE_REACTIONS"),
+        disappearing_messages: feature_enabled("FEATURE_DISAPPEARING_MESSAGES"),
+        sealed_sender: feature_enabled("FEATURE_SEALED_SENDER"),
+        pre_keys: feature_enabled("FEATURE_PRE_KEYS"),
+    };
+    let min_client_version = std::env::var("MIN_CLIENT_VERSION")
+        .unwrap_or_else(|_| DEFAULT_MIN_CLIENT_VERSION.to_string());
+    let compress_responses = std::env::var("COMPRESS_RESPONSES")
+        .map(|v| v != "false" && v != "0")
+        .unwrap_or(true);
+    let serve_root_html = std::env::var("SERVE_ROOT_HTML")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let admin_ip_allowlist =
+        parse_ip_allowlist(&std::env::var("ADMIN_IP_ALLOWLIST").unwrap_or_default())
+            .expect("ADMIN_IP_ALLOWLIST must be a comma-separated list of CIDRs");
+    let admin_credentials = match (
+        std::env::var("ADMIN_USERNAME"),
+        std::env::var("ADMIN_PASSWORD"),
+    ) {
+        (Ok(username), Ok(password)) if !username.is_empty() && !password.is_empty() => {
+            Some(AdminCredentials { username, password })
+        }
+        _ => {
+            tracing::warn!(
+                "ADMIN_USERNAME or ADMIN_PASSWORD is not set; the /admin routes are disabled"
+            );
+            None
+        }
+    };
+    let trust_proxy_headers = std::env::var("TRUST_PROXY_HEADERS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let backlog_thresholds = BacklogThresholds {
+        max_count: std::env::var("BACKLOG_ALERT_COUNT")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok()),
+        max_age_secs: std::env::var("BACKLOG_ALERT_AGE_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok()),
+    };
+    let send_limit = |name: &str, default: i64| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .filter(|limit| *limit > 0)
+            .unwrap_or(default)
+    };
+    let (standard, new_account) = (
+        DEFAULT_SEND_LIMITS.standard,
+        DEFAULT_SEND_LIMITS.new_account,
+    );
+    let send_limits = SendLimits {
+        standard: SendQuota {
+            per_minute: send_limit("SEND_LIMIT_PER_MINUTE", standard.per_minute),
+            per_hour: send_limit("SEND_LIMIT_PER_HOUR", standard.per_hour),
+            per_day: send_limit("SEND_LIMIT_PER_DAY", standard.per_day),
+        },
+        new_account: SendQuota {
+            per_minute: send_limit("NEW_ACCOUNT_SEND_LIMIT_PER_MINUTE", new_account.per_minute),
+            per_hour: send_limit("NEW_ACCOUNT_SEND_LIMIT_PER_HOUR", new_account.per_hour),
+            per_day: send_limit("NEW_ACCOUNT_SEND_LIMIT_PER_DAY", new_account.per_day),
+        },
+    };
+    let new_conversation_limits = NewConversationLimits {
+        per_day: send_limit(
+            "NEW_CONVERSATIONS_PER_DAY",
+            DEFAULT_NEW_CONVERSATION_LIMITS.per_day,
+        ),
+        established_per_day: send_limit(
+            "ESTABLISHED_NEW_CONVERSATIONS_PER_DAY",
+            DEFAULT_NEW_CONVERSATION_LIMITS.established_per_day,
+        ),
+    };
+    let ws_ack_timeout = Duration::from_millis(
+        std::env::var("WS_ACK_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|ms| *ms > 0)
+            .unwrap_or(DEFAULT_WS_ACK_TIMEOUT.as_millis() as u64),
+    );
+    let ws_max_frame_bytes = std::env::var("WS_MAX_FRAME_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|bytes| *bytes > 0)
+        .unwrap_or(DEFAULT_WS_MAX_FRAME_BYTES);
+    let ws_max_connections = std::env::var("WS_MAX_CONNECTIONS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_WS_MAX_CONNECTIONS);
+    let ws_max_connections_per_user = std::env::var("WS_MAX_CONNECTIONS_PER_USER")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_WS_MAX_CONNECTIONS_PER_USER);
+    let max_request_body_bytes = std::env::var("MAX_REQUEST_BODY_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|bytes| *bytes > 0)
+        .unwrap_or(DEFAULT_MAX_REQUEST_BODY_BYTES);
+    let server_port = std::env::var("SERVER_PORT")
+        .ok()
+        .and_then(|v| v.parse::<u16>().ok())
+        .unwrap_or(8080);
+    let max_memory_percent = std::env::var("MAX_MEMORY_PERCENT")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|percent| *percent > 0.0 && *percent <= 100.0)
+        .unwrap_or(DEFAULT_MAX_MEMORY_PERCENT);
+    let (redis_client, redis_subscriptions) = match std::env::var("REDIS_URL") {
+        Ok(url) if !url.is_empty() => {
+            let (publisher, subscriptions) = connect_redis(&url, connections.clone())
+                .await
+                .expect("Failed to connect to Redis");
+            tracing::info!("Cross-instance WebSocket delivery enabled through Redis");
+            (Some(publisher), Some(subscriptions))
+        }
+        _ => (None, None),
+    };
+    let rate_limit_backend = std::env::var("RATE_LIMIT_BACKEND")
+        .map(|v| {
+            RateLimitBackendKind::parse(&v).expect("RATE_LIMIT_BACKEND must be memory or redis")
+        })
+        .unwrap_or_default();
+    let rate_limiter: Arc<dyn RateLimitBackend> = match rate_limit_backend {
+        RateLimitBackendKind::Memory => {
+            let backend = Arc::new(InMemoryBackend::new());
+            spawn_rate_limit_pruner(backend.clone());
+            backend
+        }
+        RateLimitBackendKind::Redis => {
+            let conn = redis_client
+                .clone()
+                .expect("RATE_LIMIT_BACKEND=redis requires REDIS_URL");
+            tracing::info!("Rate limits shared across instances through Redis");
+            Arc::new(RedisBackend::new(conn))
+        }
+    };
+    let webhooks = match std::env::var("WEBHOOK_URL") {
+        Ok(url) if !url.is_empty() => {
+            reqwest::Url::parse(&url).expect("WEBHOOK_URL must be an absolute URL");
+            let max_attempts = std::env::var("WEBHOOK_MAX_ATTEMPTS")
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(DEFAULT_WEBHOOK_MAX_ATTEMPTS);
+            tracing::info!("Message webhooks enabled");
+            Some(spawn_webhook_dispatcher(WebhookConfig {
+                url,
+                secret: std::env::var("WEBHOOK_SECRET")
+                    .ok()
+                    .filter(|s| !s.is_empty()),
+                max_attempts,
+                initial_backoff: Duration::from_secs(1),
+            }))
+        }
+        _ => None,
+    };
+    let users: Arc<dyn UserRepo> = Arc::new(PgUserRepo::new(db.clone()));
+    spawn_username_reservation_cleanup(users.clone(), clock.clone());
+    let backlog_cache = create_backlog_cache();
+    spawn_backlog_monitor(
+        backlog_cache.clone(),
+        messages.clone(),
+        backlog_thresholds,
+        clock.clone(),
+        webhooks.clone(),
+    );
+    let state = AppState::builder(db.clone(), jwt_secret)
+        .clock(clock)
+        .connections(connections)
+        .connection_tracker(ConnectionTracker::new(
+            ws_max_connections,
+            ws_max_connections_per_user,
+        ))
+        .relationships(relationships)
+        .nonces(nonces)
+        .backlog_cache(backlog_cache)
+        .users(users)
+        .messages(messages)
+        .rate_limiter(rate_limiter)
+        .configure(|state| {
+            state.jwt_leeway_secs = jwt_leeway_secs;
+            state.password_hash_params = password_hash_params;
+            state.require_contact_for_messages = require_contact_for_messages;
+            state.self_messages_enabled = self_messages_enabled;
+            state.contact_request_cooldown_secs = contact_request_cooldown_secs;
+            state.max_contacts = max_contacts;
+            state.pins_exempt_from_read_deletion = pins_exempt_from_read_deletion;
+            state.allow_unproven_key_updates = allow_unproven_key_updates;
+            state.registration_mode = registration_mode;
+            state.username_grace_period_days = username_grace_period_days;
+            state.allow_ndjson_import = allow_ndjson_import;
+            state.column_keys = ColumnKeyring::new(column_key, old_column_keys);
+            state.multi_device = multi_device;
+            state.cipher_suites = cipher_suites;
+            state.features = features;
+            state.min_client_version = min_client_version;
+            state.admin_ip_allowlist = admin_ip_allowlist;
+            state.admin_credentials = admin_credentials;
+            state.trust_proxy_headers = trust_proxy_headers;
+            state.ws_ack_timeout = ws_ack_timeout;
+            state.ws_max_frame_bytes = ws_max_frame_bytes;
+            if let Some(redis) = &redis_client {
+                state.key_challenges = KeyChallengeStore::shared(redis.clone());
+            }
+            state.redis_client = redis_client;
+            state.redis_subscriptions = redis_subscriptions;
+            state.webhooks = webhooks;
+            state.send_limits = send_limits;
+            state.new_conversation_limits = new_conversation_limits;
+            state.server_port = server_port;
+        })
+        .build();
+    tracing::info!("Starting instance {}", state.instance_id);
+    let state = Arc::new(state);
+
+    spawn_memory_monitor(state.clone(), max_memory_percent);
 
     let app = Router::new()
         .route("/health", get(health_check))
-        .route("/auth/register", axum::routing::post(register))
-        .route("/auth/login", axum::routing::post(login))
-        .route("/profile", axum::routing::get(get_profile))
-        .route("/profile", axum::routing::put(update_profile))
-        .route("/profile/key", axum::routing::put(update_public_key))
-        .route(
-            "/messages/:user_id",
-            axum::routing::get(get_messages_with_user),
-        )
-        .route(
-            "/user/:public_key",
-            axum::routing::get(get_user_by_public_key),
-        )
-        .route("/user/by-id/:user_id", axum::routing::get(get_user_by_id))
+        .route("/health/ready", get(readiness_check))
+        .route("/version", get(get_version))
+        .route("/server-info", get(server_info))
+        .route("/announcements/active", get(list_active_announcements))
         .route("/ws", get(websocket_handler))
-        .route("/admin/dbdump", get(db_dump))
-        .nest_service("/admin/dbtable.html", ServeFile::new("src/dbtable.html"))
-        .with_state(state);
+        .route("/ws/schema", get(get_ws_schema))
+        .merge(auth_routes())
+        .merge(user_routes())
+        .merge(message_routes())
+        .merge(contact_routes())
+        .merge(device_routes())
+        .merge(admin_routes(state.clone()));
+    let app = if serve_root_html {
+        app.route_service(
+            "/",
+            ServeFile::new(format!("{}/landing.html", ADMIN_STATIC_DIR)),
+        )
+    } else {
+        app
+    };
+    // Bodies larger than this are refused with 413 before any handler parses them
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            audit_impersonation,
+        ))
+        .layer(middleware::from_fn(db_circuit_layer))
+        .layer(DefaultBodyLimit::max(max_request_body_bytes))
+        .layer(compression_layer(compress_responses))
+        .layer(LoadSheddingLayer::new(state.clone()))
+        .with_state(state.clone());
+    // Around the whole router: it only adds `Allow` to a 405 after the route layers ran
+    let app = middleware::from_fn(allow_header_layer).layer(app);
 
-    let port = std::env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string());
-    let addr = format!("0.0.0.0:{}", port);
+    let addr = format!("0.0.0.0:{}", server_port);
     tracing::info!("listening on {}", addr);
     axum::Server::bind(&addr.parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(state))
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::RegistrationMode;
+    use crate::websocket::tests::fake_state_with;
+    use axum::body::HttpBody;
+    use axum::response::IntoResponse;
+
+    #[tokio::test]
+    async fn test_server_info_reports_configured_features() {
+        let (state, _) = fake_state_with(|state| {
+            state.min_client_version = "2.3.0".to_string();
+            state.features = FeatureFlags {
+                reactions: true,
+                pre_keys: true,
+                ..FeatureFlags::default()
+            };
+            state.registration_mode = RegistrationMode::Invite;
+            state.ws_max_frame_bytes = 4096;
+        });
+        let response = server_info(State(state)).await.into_response();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["min_client_version"], "2.3.0");
+        assert_eq!(
+            body["features"],
+            serde_json::json!({
+                "group_messaging": false,
+                "reactions": true,
+                "disappearing_messages": false,
+                "sealed_sender": false,
+                "pre_keys": true,
+            })
+        );
+        assert_eq!(body["registration_mode"], "invite");
+        assert_eq!(body["timezone"], "UTC");
+        assert_eq!(body["max_message_size_bytes"], 4096);
+    }
+}
@@ -0,0 +1,242 @@
+//! Media module for Safe Chat backend
+//!
+//! Validates user-uploaded images and builds the responses that serve user-supplied
+//! bytes. Uploaded avatars are identified by their magic bytes rather than by what the
+//! client claims, so SVG or HTML renamed to `.png` is rejected. Served content always
+//! carries `X-Content-Type-Options: nosniff` so browsers never reinterpret it, which
+//! matters because it shares an origin with the admin HTML pages.
+
+use axum::http::StatusCode;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS};
+use axum::response::{IntoResponse, Response};
+use std::fmt;
+
+const MAX_FILENAME_LEN: usize = 64;
+
+/// Largest avatar accepted on upload or returned by `POST /avatars/batch`.
+pub const MAX_AVATAR_BYTES: usize = 512 * 1024;
+
+/// Image formats accepted for avatars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageType {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageType {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageType::Png => "image/png",
+            ImageType::Jpeg => "image/jpeg",
+            ImageType::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageType::Png => "png",
+            ImageType::Jpeg => "jpg",
+            ImageType::Webp => "webp",
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "image/png" => Some(ImageType::Png),
+            "image/jpeg" => Some(ImageType::Jpeg),
+            "image/webp" => Some(ImageType::Webp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MediaError {
+    /// SVG can carry scripts, so it is never accepted.
+    SvgNotAllowed,
+    /// Not one of the accepted image formats.
+    UnsupportedType,
+    /// Larger than `MAX_AVATAR_BYTES`.
+    TooLarge,
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::SvgNotAllowed => write!(f, "SVG images are not allowed"),
+            MediaError::UnsupportedType => {
+                write!(f, "Unsupported image type. Must be PNG, JPEG or WebP")
+            }
+            MediaError::TooLarge => {
+                write!(f, "Avatar is larger than {} bytes", MAX_AVATAR_BYTES)
+            }
+        }
+    }
+}
+
+/// Identifies an uploaded image from its leading bytes.
+pub fn detect_image_type(bytes: &[u8]) -> Result<ImageType, MediaError> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Ok(ImageType::Png);
+    }
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Ok(ImageType::Jpeg);
+    }
+    if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Ok(ImageType::Webp);
+    }
+    if looks_like_svg(bytes) {
+        return Err(MediaError::SvgNotAllowed);
+    }
+    Err(MediaError::UnsupportedType)
+}
+
+/// Checks an uploaded avatar's size and format.
+pub fn validate_avatar(bytes: &[u8]) -> Result<ImageType, MediaError> {
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return Err(MediaError::TooLarge);
+    }
+    detect_image_type(bytes)
+}
+
+fn looks_like_svg(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(512)];
+    let text = String::from_utf8_lossy(head).to_lowercase();
+    text.contains("<svg")
+}
+
+/// Reduces a filename to `[A-Za-z0-9._-]`, so it is safe inside a `Content-Disposition` header.
+pub fn sanitize_filename(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let trimmed: String = cleaned
+        .trim_start_matches('.')
+        .chars()
+        .take(MAX_FILENAME_LEN)
+        .collect();
+    if trimmed.is_empty() {
+        "file".to_string()
+    } else {
+        trimmed
+    }
+}
+
+/// Serves a validated image inline with its content type and a sanitized filename.
+pub fn inline_image_response(bytes: Vec<u8>, image_type: ImageType, filename: &str) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, image_type.content_type().to_string()),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("inline; filename=\"{}\"", sanitize_filename(filename)),
+            ),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+/// Serves arbitrary user bytes as a download that browsers will not render.
+pub fn attachment_response(bytes: Vec<u8>, filename: &str) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, "application/octet-stream".to_string()),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", sanitize_filename(filename)),
+            ),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_whitelisted_images() {
+        assert_eq!(
+            detect_image_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Ok(ImageType::Png)
+        );
+        assert_eq!(
+            detect_image_type(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10]),
+            Ok(ImageType::Jpeg)
+        );
+        assert_eq!(
+            detect_image_type(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Ok(ImageType::Webp)
+        );
+    }
+
+    #[test]
+    fn test_rejects_svg_upload() {
+        let svg = br#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"><script>alert(1)</script></svg>"#;
+        assert_eq!(detect_image_type(svg), Err(MediaError::SvgNotAllowed));
+    }
+
+    #[test]
+    fn test_rejects_html_disguised_as_png() {
+        // The client claims avatar.png, but the bytes are an HTML page
+        let html =
+            b"<!DOCTYPE html><html><body><script>alert(document.cookie)</script></body></html>";
+        assert_eq!(detect_image_type(html), Err(MediaError::UnsupportedType));
+    }
+
+    #[test]
+    fn test_rejects_oversized_avatar() {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.resize(MAX_AVATAR_BYTES, 0);
+        assert_eq!(validate_avatar(&png), Ok(ImageType::Png));
+        png.push(0);
+        assert_eq!(validate_avatar(&png), Err(MediaError::TooLarge));
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("avatar.png"), "avatar.png");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(
+            sanitize_filename("evil\".png\r\nX-Header: 1"),
+            "evil_.png__X-Header__1"
+        );
+        assert_eq!(sanitize_filename("..."), "file");
+        assert_eq!(sanitize_filename(&"a".repeat(100)).len(), MAX_FILENAME_LEN);
+    }
+
+    #[test]
+    fn test_responses_disable_sniffing() {
+        let inline = inline_image_response(vec![1], ImageType::Png, "a b.png");
+        assert_eq!(inline.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(inline.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(
+            inline.headers()[CONTENT_DISPOSITION],
+            "inline; filename=\"a_b.png\""
+        );
+
+        let attachment = attachment_response(vec![1], "report.html");
+        assert_eq!(
+            attachment.headers()[CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        assert_eq!(
+            attachment.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"report.html\""
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Preferences module for Safe Chat backend
+//!
+//! Timestamps are generated and stored in UTC, whatever region the server runs in.
+//! They are only converted when rendered: lists the requesting user reads (messages,
+//! pins, contacts) use the timezone stored in `notification_prefs`, everything else
+//! (profiles, WebSocket events, objects shared between users) is rendered in UTC.
+//!
+//! Settings that change how the server treats a user's data, such as deleting sent
+//! messages once delivered, are kept in `users.user_preferences` and returned by
+//! `GET /profile`.
+
+use crate::api::extract_user_id_from_auth;
+use crate::repo::UserRepo;
+use crate::state::AppState;
+
+use axum::extract::{Json, State};
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Timezone used for users without a preference and for timestamps not tied to one user.
+pub const DEFAULT_TIMEZONE: Tz = Tz::UTC;
+
+#[derive(Deserialize)]
+pub struct UpdatePrefsRequest {
+    /// IANA timezone name, e.g. `Europe/Brussels`.
+    pub timezone: String,
+}
+
+#[derive(Serialize)]
+pub struct PrefsResponse {
+    pub timezone: String,
+}
+
+/// Preferences to change; absent fields keep their current value.
+#[derive(Deserialize)]
+pub struct UpdateUserPreferencesRequest {
+    pub delete_on_delivered: Option<bool>,
+}
+
+/// Renders `at` as RFC 3339 in `timezone`.
+pub fn format_timestamp(at: DateTime<Utc>, timezone: Tz) -> String {
+    at.with_timezone(&timezone).to_rfc3339()
+}
+
+/// Renders a Unix millisecond timestamp as RFC 3339 in `timezone`.
+pub fn format_millis(millis: i64, timezone: Tz) -> String {
+    let at = DateTime::from_timestamp_millis(millis).unwrap_or_default();
+    format_timestamp(at, timezone)
+}
+
+/// Like `format_millis`, but `None` for a timestamp outside chrono's range instead of the
+/// Unix epoch, for callers that must not hide corrupt values.
+pub fn try_format_millis(millis: i64, timezone: Tz) -> Option<String> {
+    DateTime::from_timestamp_millis(millis).map(|at| format_timestamp(at, timezone))
+}
+
+/// The user's preferred timezone, falling back to `DEFAULT_TIMEZONE` when none is stored
+/// or it cannot be loaded.
+pub async fn preferred_timezone(users: &dyn UserRepo, user_id: Uuid) -> Tz {
+    match users.timezone(user_id).await {
+        Ok(Some(name)) => name.parse().unwrap_or(DEFAULT_TIMEZONE),
+        Ok(None) => DEFAULT_TIMEZONE,
+        Err(e) => {
+            error!("Failed to load timezone for user {}: {}", user_id, e);
+            DEFAULT_TIMEZONE
+        }
+    }
+}
+
+/// Returns the authenticated user's preferences.
+pub async fn get_prefs(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /profile/notification-prefs endpoint");
+            return e.into_response();
+        }
+    };
+    let timezone = preferred_timezone(state.users.as_ref(), user_id).await;
+    (
+        StatusCode::OK,
+        Json(PrefsResponse {
+            timezone: timezone.name().to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// Sets the timezone the authenticated user's timestamps are rendered in.
+///
+/// Returns 400 if `timezone` is not an IANA timezone name.
+pub async fn update_prefs(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<UpdatePrefsRequest>,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /profile/notification-prefs endpoint");
+            return e.into_response();
+        }
+    };
+    let timezone: Tz = match payload.timezone.parse() {
+        Ok(tz) => tz,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid timezone").into_response(),
+    };
+    match state.users.set_timezone(user_id, timezone.name()).await {
+        Ok(()) => {
+            info!("User {} set timezone to {}", user_id, timezone.name());
+            (
+                StatusCode::OK,
+                Json(PrefsResponse {
+                    timezone: timezone.name().to_string(),
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Failed to store timezone for user {}: {}", user_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// Updates the authenticated user's preferences and returns all of them.
+///
+/// Returns 400 if the body sets no preference.
+pub async fn update_user_preferences(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<UpdateUserPreferencesRequest>,
+) -> impl IntoResponse {
+    let user_id = match extract_user_id_from_auth(
+        &headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(uid) => uid,
+        Err(e) => {
+            info!("Unauthorized access attempt to /profile/preferences endpoint");
+            return e.into_response();
+        }
+    };
+    let Some(delete_on_delivered) = payload.delete_on_delivered else {
+        return (StatusCode::BAD_REQUEST, "No preferences to update").into_response();
+    };
+    let mut preferences = match state.users.preferences(user_id).await {
+        Ok(current) => current,
+        Err(e) => {
+            error!("Failed to load preferences for user {}: {}", user_id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    preferences.delete_on_delivered = delete_on_delivered;
+    match state.users.set_preferences(user_id, &preferences).await {
+        Ok(()) => {
+            info!("User {} updated preferences: {:?}", user_id, preferences);
+            (StatusCode::OK, Json(preferences)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to store preferences for user {}: {}", user_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::fake::FakeUserRepo;
+
+    #[test]
+    fn test_format_millis_in_timezone() {
+        // 2024-05-10 12:00:00 UTC
+        let millis = 1_715_342_400_000;
+        assert_eq!(
+            format_millis(millis, DEFAULT_TIMEZONE),
+            "2024-05-10T12:00:00+00:00"
+        );
+        let new_york: Tz = "America/New_York".parse().unwrap();
+        assert_eq!(format_millis(millis, new_york), "2024-05-10T08:00:00-04:00");
+        assert_eq!(
+            try_format_millis(millis, DEFAULT_TIMEZONE),
+            Some(format_millis(millis, DEFAULT_TIMEZONE))
+        );
+        assert_eq!(try_format_millis(i64::MAX, DEFAULT_TIMEZONE), None);
+        assert_eq!(try_format_millis(i64::MIN, DEFAULT_TIMEZONE), None);
+    }
+
+    #[tokio::test]
+    async fn test_preferred_timezone_falls_back_to_utc() {
+        let users = FakeUserRepo::new();
+        let alice = users.seed_user("alice");
+        assert_eq!(preferred_timezone(&users, alice).await, DEFAULT_TIMEZONE);
+
+        users.set_timezone(alice, "Asia/Tokyo").await.unwrap();
+        assert_eq!(
+            preferred_timezone(&users, alice).await,
+            chrono_tz::Asia::Tokyo
+        );
+    }
+}
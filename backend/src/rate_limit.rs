@@ -0,0 +1,417 @@
+//! Rate limit storage for Safe Chat backend
+//!
+//! Contact syncs, conversation exports, probes and signature checks are limited per key
+//! through a `RateLimitBackend`, selected with `RATE_LIMIT_BACKEND`:
+//! - `memory` (default) keeps a sliding window of attempt times per key in this process.
+//!   Limits reset on restart and each instance counts on its own.
+//! - `redis` keeps a fixed-window counter per key in Redis (`REDIS_URL`), shared by every
+//!   instance and surviving restarts.
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use redis::Script;
+use redis::aio::ConnectionManager;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::error;
+
+/// Prefix of the Redis keys holding rate limit counters.
+const REDIS_KEY_PREFIX: &str = "safechat:rate_limit:";
+
+/// Counts an attempt unless the key already reached `ARGV[1]` attempts in its window of
+/// `ARGV[2]` milliseconds. Returns -1 when the attempt was counted, otherwise the
+/// milliseconds until the window resets.
+const CHECK_AND_INCREMENT_SCRIPT: &str = r"
+local count = tonumber(redis.call('GET', KEYS[1]) or '0')
+if count >= tonumber(ARGV[1]) then
+    local ttl = redis.call('PTTL', KEYS[1])
+    if ttl < 0 then
+        redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        ttl = tonumber(ARGV[2])
+    end
+    return ttl
+end
+if redis.call('INCR', KEYS[1]) == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return -1
+";
+
+/// Takes back one attempt of a counter that still exists and is positive. A plain `DECR`
+/// would recreate an expired counter at -1 without a TTL, granting an extra attempt forever.
+const REFUND_SCRIPT: &str = r"
+if tonumber(redis.call('GET', KEYS[1]) or '0') > 0 then
+    return redis.call('DECR', KEYS[1])
+end
+return 0
+";
+
+/// How often the in-memory backend forgets keys whose attempts all left their window.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitResult {
+    Allowed,
+    /// The key is at its limit; the next attempt may succeed after `retry_after`.
+    Limited {
+        retry_after: Duration,
+    },
+}
+
+impl RateLimitResult {
+    /// `Err` with the wait when limited, for use with `?` and `if let Err`.
+    pub fn into_result(self) -> Result<(), Duration> {
+        match self {
+            RateLimitResult::Allowed => Ok(()),
+            RateLimitResult::Limited { retry_after } => Err(retry_after),
+        }
+    }
+}
+
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Counts an attempt for `key` unless it already made `limit` attempts within `window`.
+    async fn check_and_increment(&self, key: &str, limit: u32, window: Duration)
+    -> RateLimitResult;
+    /// Takes back the latest counted attempt for `key`, for work that turned out not to happen.
+    async fn refund(&self, key: &str);
+}
+
+/// Which backend `RATE_LIMIT_BACKEND` selects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitBackendKind {
+    #[default]
+    Memory,
+    Redis,
+}
+
+impl RateLimitBackendKind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind.trim().to_lowercase().as_str() {
+            "memory" => Some(RateLimitBackendKind::Memory),
+            "redis" => Some(RateLimitBackendKind::Redis),
+            _ => None,
+        }
+    }
+}
+
+/// The attempts of one key within its window, oldest first.
+struct KeyAttempts {
+    window: Duration,
+    times: VecDeque<Instant>,
+}
+
+impl KeyAttempts {
+    fn forget_expired(&mut self, now: Instant) {
+        while self
+            .times
+            .front()
+            .is_some_and(|t| now.saturating_duration_since(*t) >= self.window)
+        {
+            self.times.pop_front();
+        }
+    }
+}
+
+/// Attempt times per key, kept in this process. Keys without attempts in their window are
+/// removed, by `prune_at` for keys that are no longer used.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    attempts: DashMap<String, KeyAttempts>,
+}
+
+impl InMemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets attempts that left their window at `now`, and the keys left without any.
+    pub fn prune_at(&self, now: Instant) {
+        self.attempts.retain(|_, attempts| {
+            attempts.forget_expired(now);
+            !attempts.times.is_empty()
+        });
+    }
+
+    fn remove_if_empty(&self, key: &str) {
+        self.attempts
+            .remove_if(key, |_, attempts| attempts.times.is_empty());
+    }
+
+    /// `check_and_increment` at `now`. Attempts older than `window` are forgotten; when the
+    /// key is at its limit, the wait is until its oldest attempt leaves the window.
+    pub fn check_and_increment_at(
+        &self,
+        key: &str,
+        limit: u32,
+        window: Duration,
+        now: Instant,
+    ) -> RateLimitResult {
+        let mut attempts = self
+            .attempts
+            .entry(key.to_string())
+            .or_insert_with(|| KeyAttempts {
+                window,
+                times: VecDeque::new(),
+            });
+        attempts.window = window;
+        attempts.forget_expired(now);
+        if attempts.times.len() >= limit as usize {
+            let Some(oldest) = attempts.times.front() else {
+                // A limit of zero allows nothing
+                drop(attempts);
+                self.remove_if_empty(key);
+                return RateLimitResult::Limited {
+                    retry_after: window,
+                };
+            };
+            return RateLimitResult::Limited {
+                retry_after: window - now.saturating_duration_since(*oldest),
+            };
+        }
+        attempts.times.push_back(now);
+        RateLimitResult::Allowed
+    }
+}
+
+/// Spawns a background task that periodically prunes `backend`.
+pub fn spawn_rate_limit_pruner(backend: Arc<InMemoryBackend>) {
+    tokio::spawn(async move {
+        loop {
+            sleep(PRUNE_INTERVAL).await;
+            backend.prune_at(Instant::now());
+        }
+    });
+}
+
+#[async_trait]
+impl RateLimitBackend for InMemoryBackend {
+    async fn check_and_increment(
+        &self,
+        key: &str,
+        limit: u32,
+        window: Duration,
+    ) -> RateLimitResult {
+        self.check_and_increment_at(key, limit, window, Instant::now())
+    }
+
+    async fn refund(&self, key: &str) {
+        if let Some(mut attempts) = self.attempts.get_mut(key) {
+            attempts.times.pop_back();
+        }
+        self.remove_if_empty(key);
+    }
+}
+
+/// Counters in Redis, shared by every instance. A Redis error lets the attempt through:
+/// these limits protect against abuse, and a Redis outage should not lock users out.
+pub struct RedisBackend {
+    conn: ConnectionManager,
+    script: Script,
+    refund_script: Script,
+}
+
+impl RedisBackend {
+    pub fn new(conn: ConnectionManager) -> Self {
+        Self {
+            conn,
+            script: Script::new(CHECK_AND_INCREMENT_SCRIPT),
+            refund_script: Script::new(REFUND_SCRIPT),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for RedisBackend {
+    async fn check_and_increment(
+        &self,
+        key: &str,
+        limit: u32,
+        window: Duration,
+    ) -> RateLimitResult {
+        let mut conn = self.conn.clone();
+        let result: redis::RedisResult<i64> = self
+            .script
+            .key(format!("{}{}", REDIS_KEY_PREFIX, key))
+            .arg(limit)
+            .arg(window.as_millis().max(1) as u64)
+            .invoke_async(&mut conn)
+            .await;
+        match result {
+            Ok(wait_ms) if wait_ms >= 0 => RateLimitResult::Limited {
+                retry_after: Duration::from_millis(wait_ms as u64),
+            },
+            Ok(_) => RateLimitResult::Allowed,
+            Err(e) => {
+                error!("Rate limit check for {} failed, allowing it: {}", key, e);
+                RateLimitResult::Allowed
+            }
+        }
+    }
+
+    async fn refund(&self, key: &str) {
+        let mut conn = self.conn.clone();
+        let result: redis::RedisResult<i64> = self
+            .refund_script
+            .key(format!("{}{}", REDIS_KEY_PREFIX, key))
+            .invoke_async(&mut conn)
+            .await;
+        if let Err(e) = result {
+            error!("Rate limit refund for {} failed: {}", key, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_memory_window_slides_per_key() {
+        let backend = InMemoryBackend::new();
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        for offset in [0, 10, 20] {
+            let at = start + Duration::from_secs(offset);
+            assert_eq!(
+                backend.check_and_increment_at("a", 3, window, at),
+                RateLimitResult::Allowed
+            );
+        }
+        assert_eq!(
+            backend.check_and_increment_at("a", 3, window, start + Duration::from_secs(30)),
+            RateLimitResult::Limited {
+                retry_after: Duration::from_secs(30)
+            }
+        );
+        assert_eq!(
+            backend.check_and_increment_at("b", 3, window, start),
+            RateLimitResult::Allowed
+        );
+        // The first attempt leaves the window, freeing one slot
+        let later = start + window;
+        assert_eq!(
+            backend.check_and_increment_at("a", 3, window, later),
+            RateLimitResult::Allowed
+        );
+        assert!(
+            backend
+                .check_and_increment_at("a", 3, window, later)
+                .into_result()
+                .is_err()
+        );
+        assert!(
+            backend
+                .check_and_increment_at("c", 0, window, start)
+                .into_result()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refund_frees_the_latest_attempt() {
+        let backend = InMemoryBackend::new();
+        let window = Duration::from_secs(3600);
+        assert_eq!(
+            backend.check_and_increment("export", 1, window).await,
+            RateLimitResult::Allowed
+        );
+        assert!(
+            backend
+                .check_and_increment("export", 1, window)
+                .await
+                .into_result()
+                .is_err()
+        );
+        backend.refund("export").await;
+        assert_eq!(
+            backend.check_and_increment("export", 1, window).await,
+            RateLimitResult::Allowed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keys_without_attempts_are_removed() {
+        let backend = InMemoryBackend::new();
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        assert!(
+            backend
+                .check_and_increment_at("zero", 0, window, start)
+                .into_result()
+                .is_err()
+        );
+        backend.check_and_increment_at("refunded", 1, window, start);
+        backend.refund("refunded").await;
+        backend.check_and_increment_at("old", 1, window, start);
+        backend.check_and_increment_at("recent", 1, window, start + Duration::from_secs(30));
+        assert_eq!(backend.attempts.len(), 2);
+
+        backend.prune_at(start + window);
+        assert_eq!(backend.attempts.len(), 1);
+        assert!(backend.attempts.contains_key("recent"));
+    }
+
+    #[test]
+    fn test_backend_kind_parsing() {
+        assert_eq!(
+            RateLimitBackendKind::parse("memory"),
+            Some(RateLimitBackendKind::Memory)
+        );
+        assert_eq!(
+            RateLimitBackendKind::parse(" Redis "),
+            Some(RateLimitBackendKind::Redis)
+        );
+        assert_eq!(RateLimitBackendKind::parse("memcached"), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs REDIS_URL pointing to a Redis server"]
+    async fn test_redis_counter_is_shared_and_expires() {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+        let client = redis::Client::open(url).unwrap();
+        let first = RedisBackend::new(ConnectionManager::new(client.clone()).await.unwrap());
+        let second = RedisBackend::new(ConnectionManager::new(client).await.unwrap());
+        let key = format!("test:{}", uuid::Uuid::new_v4().simple());
+        let window = Duration::from_millis(500);
+
+        assert_eq!(
+            first.check_and_increment(&key, 2, window).await,
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            second.check_and_increment(&key, 2, window).await,
+            RateLimitResult::Allowed
+        );
+        let RateLimitResult::Limited { retry_after } =
+            first.check_and_increment(&key, 2, window).await
+        else {
+            panic!("third attempt should be limited across instances");
+        };
+        assert!(retry_after <= window);
+        second.refund(&key).await;
+        assert_eq!(
+            first.check_and_increment(&key, 2, window).await,
+            RateLimitResult::Allowed
+        );
+
+        tokio::time::sleep(window + Duration::from_millis(100)).await;
+        assert_eq!(
+            first.check_and_increment(&key, 2, window).await,
+            RateLimitResult::Allowed
+        );
+
+        // A refund after the counter expired does not leave a counter without a TTL behind
+        tokio::time::sleep(window + Duration::from_millis(100)).await;
+        first.refund(&key).await;
+        let mut conn = first.conn.clone();
+        let exists: bool = redis::cmd("EXISTS")
+            .arg(format!("{}{}", REDIS_KEY_PREFIX, key))
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(!exists);
+    }
+}
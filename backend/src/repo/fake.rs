@@ -0,0 +1,1574 @@
+//! In-memory fake repositories for fast tests without a database.
+//!
+//! These mirror the behaviour of the PostgreSQL implementations closely enough to
+//! exercise business rules; they are not meant for production use.
+
+use super::{
+    AddContactOutcome, AnnouncementRecord, AnnouncementRepo, AuditActor, AvatarRewrite,
+    BacklogRecord, ContactRecord, ContactRepo, ContactRequestRecord, ConversationPeerRecord,
+    ConversationQuery, ConversationSettings, CreateRequestOutcome, DeliveryAttemptRecord,
+    DeliveryReceiptRecord, DeviceRecord, DeviceRepo, InviteRecord, KeyFormatChange, MessageCursor,
+    MessageRecord, MessageRepo, MessageStatus, MessageTypeCount, OrderedInsert,
+    PinnedMessageRecord, ProfileUpdateOutcome, RepoError, RepoResult, SealedMessageRecord,
+    SendQuotaOutcome, SendQuotaWindow, SenderActivity, SortOrder, UserPreferences, UserRecord,
+    UserRepo, UsernameChangeLimit, UsernameChangeRecord,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+#[derive(Default)]
+pub struct FakeUserRepo {
+    users: Mutex<HashMap<Uuid, UserRecord>>,
+    /// Reserved usernames and when they become available again.
+    reserved_usernames: Mutex<HashMap<String, DateTime<Utc>>>,
+    timezones: Mutex<HashMap<Uuid, String>>,
+    preferences: Mutex<HashMap<Uuid, UserPreferences>>,
+    last_seen: Mutex<HashMap<Uuid, DateTime<Utc>>>,
+    username_changes: Mutex<HashMap<Uuid, Vec<UsernameChangeRecord>>>,
+    /// Key history as `(user_id, old_public_key, new_public_key, format_only)`.
+    key_changes: Mutex<Vec<(Uuid, String, String, bool)>>,
+    /// Audit entries as `(action, user_id, details)`.
+    audit_log: Mutex<Vec<(String, Uuid, String)>>,
+    invites: Mutex<Vec<InviteRecord>>,
+    /// Codes of spent invites.
+    used_invites: Mutex<HashSet<String>>,
+    new_conversation_limit_exempt: Mutex<HashSet<Uuid>>,
+}
+
+impl FakeUserRepo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn audit_log(&self) -> Vec<(String, Uuid, String)> {
+        self.audit_log.lock().unwrap().clone()
+    }
+
+    pub fn key_changes(&self) -> Vec<(Uuid, String, String, bool)> {
+        self.key_changes.lock().unwrap().clone()
+    }
+
+    /// Stores `public_key` directly, without bumping the version or recording history.
+    pub fn set_public_key(&self, id: Uuid, public_key: &str) {
+        if let Some(user) = self.users.lock().unwrap().get_mut(&id) {
+            user.public_key = public_key.to_string();
+        }
+    }
+
+    /// Stores `avatar` bytes directly, as a server without or with another column key would.
+    pub fn set_avatar(&self, id: Uuid, avatar: Vec<u8>) {
+        if let Some(user) = self.users.lock().unwrap().get_mut(&id) {
+            user.avatar = Some(avatar);
+        }
+    }
+
+    /// Inserts a user directly and returns its id.
+    pub fn seed_user(&self, username: &str) -> Uuid {
+        let id = Uuid::new_v4();
+        self.users.lock().unwrap().insert(
+            id,
+            UserRecord {
+                id,
+                username: username.to_string(),
+                password_hash: String::new(),
+                public_key: String::new(),
+                created_at: Utc::now(),
+                avatar: None,
+                avatar_content_type: None,
+                key_version: 0,
+                profile_updated_at: Utc::now(),
+            },
+        );
+        id
+    }
+
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.users.lock().unwrap().contains_key(&id)
+    }
+
+    pub fn username(&self, id: Uuid) -> Option<String> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|u| u.username.clone())
+    }
+
+    pub fn public_key(&self, id: Uuid) -> Option<String> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|u| u.public_key.clone())
+    }
+}
+
+#[async_trait]
+impl UserRepo for FakeUserRepo {
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        public_key: &str,
+    ) -> RepoResult<Uuid> {
+        let mut users = self.users.lock().unwrap();
+        if users.values().any(|u| u.username == username) {
+            return Err(RepoError::Duplicate);
+        }
+        let id = Uuid::new_v4();
+        users.insert(
+            id,
+            UserRecord {
+                id,
+                username: username.to_string(),
+                password_hash: password_hash.to_string(),
+                public_key: public_key.to_string(),
+                created_at: Utc::now(),
+                avatar: None,
+                avatar_content_type: None,
+                key_version: 0,
+                profile_updated_at: Utc::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    async fn create_user_with_invite(
+        &self,
+        username: &str,
+        password_hash: &str,
+        public_key: &str,
+        invite_code: &str,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Option<Uuid>> {
+        let usable = !self.used_invites.lock().unwrap().contains(invite_code)
+            && self
+                .invites
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|i| i.code == invite_code && i.expires_at > now);
+        if !usable {
+            return Ok(None);
+        }
+        let id = self
+            .create_user(username, password_hash, public_key)
+            .await?;
+        self.used_invites
+            .lock()
+            .unwrap()
+            .insert(invite_code.to_string());
+        Ok(Some(id))
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> RepoResult<Option<UserRecord>> {
+        Ok(self.users.lock().unwrap().get(&id).cloned())
+    }
+
+    async fn find_by_username(&self, username: &str) -> RepoResult<Option<UserRecord>> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .find(|u| u.username == username)
+            .cloned())
+    }
+
+    async fn find_by_public_key(&self, public_key: &str) -> RepoResult<Option<UserRecord>> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .find(|u| u.public_key == public_key)
+            .cloned())
+    }
+
+    async fn find_by_usernames(&self, usernames: &[String]) -> RepoResult<Vec<UserRecord>> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|u| usernames.contains(&u.username))
+            .cloned()
+            .collect())
+    }
+
+    async fn find_by_ids(&self, ids: &[Uuid]) -> RepoResult<Vec<UserRecord>> {
+        let users = self.users.lock().unwrap();
+        Ok(ids.iter().filter_map(|id| users.get(id).cloned()).collect())
+    }
+
+    async fn update_public_key(
+        &self,
+        id: Uuid,
+        public_key: &str,
+        expected_version: Option<i32>,
+    ) -> RepoResult<Option<i32>> {
+        let mut users = self.users.lock().unwrap();
+        let Some(user) = users.get_mut(&id) else {
+            return Ok(None);
+        };
+        if expected_version.is_some_and(|v| v != user.key_version) {
+            return Ok(None);
+        }
+        self.key_changes.lock().unwrap().push((
+            id,
+            std::mem::replace(&mut user.public_key, public_key.to_string()),
+            public_key.to_string(),
+            false,
+        ));
+        user.key_version += 1;
+        user.profile_updated_at = Utc::now();
+        Ok(Some(user.key_version))
+    }
+
+    async fn public_keys_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> RepoResult<Vec<(Uuid, String)>> {
+        let mut keys: Vec<(Uuid, String)> = self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|u| after.is_none_or(|after| u.id > after))
+            .map(|u| (u.id, u.public_key.clone()))
+            .collect();
+        keys.sort_by_key(|(id, _)| *id);
+        keys.truncate(limit.max(0) as usize);
+        Ok(keys)
+    }
+
+    async fn rewrite_key_formats(
+        &self,
+        changes: &[KeyFormatChange],
+        _actor: &AuditActor,
+    ) -> RepoResult<Vec<Uuid>> {
+        let mut users = self.users.lock().unwrap();
+        let mut changed = Vec::new();
+        for change in changes {
+            let Some(user) = users.get_mut(&change.user_id) else {
+                continue;
+            };
+            if user.public_key != change.old_public_key {
+                continue;
+            }
+            user.public_key = change.new_public_key.clone();
+            user.profile_updated_at = Utc::now();
+            self.key_changes.lock().unwrap().push((
+                change.user_id,
+                change.old_public_key.clone(),
+                change.new_public_key.clone(),
+                true,
+            ));
+            self.audit_log.lock().unwrap().push((
+                "key.normalize".to_string(),
+                change.user_id,
+                "raw key stored as X.509".to_string(),
+            ));
+            changed.push(change.user_id);
+        }
+        Ok(changed)
+    }
+
+    async fn avatars_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> RepoResult<Vec<(Uuid, Vec<u8>)>> {
+        let mut avatars: Vec<(Uuid, Vec<u8>)> = self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|u| after.is_none_or(|after| u.id > after))
+            .filter_map(|u| Some((u.id, u.avatar.clone()?)))
+            .collect();
+        avatars.sort_by_key(|(id, _)| *id);
+        avatars.truncate(limit.max(0) as usize);
+        Ok(avatars)
+    }
+
+    async fn rewrite_avatars(
+        &self,
+        rewrites: &[AvatarRewrite],
+        _actor: &AuditActor,
+    ) -> RepoResult<Vec<Uuid>> {
+        let mut users = self.users.lock().unwrap();
+        let mut changed = Vec::new();
+        for rewrite in rewrites {
+            let Some(user) = users.get_mut(&rewrite.user_id) else {
+                continue;
+            };
+            if user.avatar.as_ref() != Some(&rewrite.old_avatar) {
+                continue;
+            }
+            user.avatar = Some(rewrite.new_avatar.clone());
+            self.audit_log.lock().unwrap().push((
+                "column_key.rotate".to_string(),
+                rewrite.user_id,
+                "avatar re-encrypted".to_string(),
+            ));
+            changed.push(rewrite.user_id);
+        }
+        Ok(changed)
+    }
+
+    async fn replace_password_hash(
+        &self,
+        id: Uuid,
+        current_hash: &str,
+        new_hash: &str,
+    ) -> RepoResult<bool> {
+        match self.users.lock().unwrap().get_mut(&id) {
+            Some(user) if user.password_hash == current_hash => {
+                user.password_hash = new_hash.to_string();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn update_profile(
+        &self,
+        id: Uuid,
+        username: Option<&str>,
+        avatar: Option<(Vec<u8>, &str)>,
+        username_limit: UsernameChangeLimit,
+    ) -> RepoResult<ProfileUpdateOutcome> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(username) = username
+            && users.values().any(|u| u.id != id && u.username == username)
+        {
+            return Err(RepoError::Duplicate);
+        }
+        if let Some(user) = users.get_mut(&id) {
+            let mut changes = self.username_changes.lock().unwrap();
+            let history = changes.entry(id).or_default();
+            if let Some(username) = username
+                && user.username != username
+            {
+                // History is kept newest first
+                let recent: Vec<DateTime<Utc>> = history
+                    .iter()
+                    .map(|change| change.changed_at)
+                    .filter(|changed_at| *changed_at > username_limit.since)
+                    .take(username_limit.max_changes as usize)
+                    .collect();
+                if recent.len() as i64 >= username_limit.max_changes
+                    && let Some(oldest) = recent.last()
+                {
+                    return Ok(ProfileUpdateOutcome::UsernameChangeLimited(*oldest));
+                }
+                history.insert(
+                    0,
+                    UsernameChangeRecord {
+                        old_username: user.username.clone(),
+                        new_username: username.to_string(),
+                        changed_at: Utc::now(),
+                    },
+                );
+            }
+            if username.is_some() || avatar.is_some() {
+                user.profile_updated_at = Utc::now();
+            }
+            if let Some(username) = username {
+                user.username = username.to_string();
+            }
+            if let Some((avatar, content_type)) = avatar {
+                user.avatar = Some(avatar);
+                user.avatar_content_type = Some(content_type.to_string());
+            }
+        }
+        Ok(ProfileUpdateOutcome::Updated)
+    }
+
+    async fn username_history(&self, id: Uuid) -> RepoResult<Vec<UsernameChangeRecord>> {
+        Ok(self
+            .username_changes
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn delete_user(&self, id: Uuid, grace_period_days: i32) -> RepoResult<bool> {
+        let Some(user) = self.users.lock().unwrap().remove(&id) else {
+            return Ok(false);
+        };
+        self.reserved_usernames.lock().unwrap().insert(
+            user.username,
+            Utc::now() + chrono::Duration::days(grace_period_days.into()),
+        );
+        Ok(true)
+    }
+
+    async fn username_reserved_until(
+        &self,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Option<DateTime<Utc>>> {
+        Ok(self
+            .reserved_usernames
+            .lock()
+            .unwrap()
+            .get(username)
+            .copied()
+            .filter(|available_after| *available_after > now))
+    }
+
+    async fn purge_username_reservations(&self, now: DateTime<Utc>) -> RepoResult<u64> {
+        let mut reserved = self.reserved_usernames.lock().unwrap();
+        let before = reserved.len();
+        reserved.retain(|_, available_after| *available_after > now);
+        Ok((before - reserved.len()) as u64)
+    }
+
+    async fn timezone(&self, id: Uuid) -> RepoResult<Option<String>> {
+        Ok(self.timezones.lock().unwrap().get(&id).cloned())
+    }
+
+    async fn set_timezone(&self, id: Uuid, timezone: &str) -> RepoResult<()> {
+        self.timezones
+            .lock()
+            .unwrap()
+            .insert(id, timezone.to_string());
+        Ok(())
+    }
+
+    async fn preferences(&self, id: Uuid) -> RepoResult<UserPreferences> {
+        Ok(self
+            .preferences
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_preferences(&self, id: Uuid, preferences: &UserPreferences) -> RepoResult<()> {
+        if let Some(user) = self.users.lock().unwrap().get_mut(&id) {
+            user.profile_updated_at = Utc::now();
+            self.preferences
+                .lock()
+                .unwrap()
+                .insert(id, preferences.clone());
+        }
+        Ok(())
+    }
+
+    async fn last_seen(&self, id: Uuid) -> RepoResult<Option<DateTime<Utc>>> {
+        Ok(self.last_seen.lock().unwrap().get(&id).copied())
+    }
+
+    async fn set_last_seen(&self, id: Uuid, at: DateTime<Utc>) -> RepoResult<()> {
+        self.last_seen.lock().unwrap().insert(id, at);
+        Ok(())
+    }
+
+    async fn record_audit(
+        &self,
+        action: &str,
+        user_id: Uuid,
+        _actor: &AuditActor,
+        details: &str,
+    ) -> RepoResult<()> {
+        self.audit_log
+            .lock()
+            .unwrap()
+            .push((action.to_string(), user_id, details.to_string()));
+        Ok(())
+    }
+
+    async fn create_invite(&self, invite: &InviteRecord, _actor: &AuditActor) -> RepoResult<()> {
+        let mut invites = self.invites.lock().unwrap();
+        if invites.iter().any(|i| i.code == invite.code) {
+            return Err(RepoError::Duplicate);
+        }
+        invites.push(invite.clone());
+        self.audit_log.lock().unwrap().push((
+            "invite.create".to_string(),
+            invite.id,
+            invite.note.clone().unwrap_or_default(),
+        ));
+        Ok(())
+    }
+
+    async fn unused_invites(&self, now: DateTime<Utc>) -> RepoResult<Vec<InviteRecord>> {
+        let used = self.used_invites.lock().unwrap();
+        let mut unused: Vec<InviteRecord> = self
+            .invites
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|i| !used.contains(&i.code) && i.expires_at > now)
+            .cloned()
+            .collect();
+        unused.sort_by_key(|i| i.created_at);
+        Ok(unused)
+    }
+
+    async fn new_conversation_limit_exempt(&self, id: Uuid) -> RepoResult<bool> {
+        Ok(self
+            .new_conversation_limit_exempt
+            .lock()
+            .unwrap()
+            .contains(&id))
+    }
+
+    async fn set_new_conversation_limit_exempt(
+        &self,
+        id: Uuid,
+        exempt: bool,
+        _actor: &AuditActor,
+    ) -> RepoResult<bool> {
+        if !self.users.lock().unwrap().contains_key(&id) {
+            return Ok(false);
+        }
+        let mut exempted = self.new_conversation_limit_exempt.lock().unwrap();
+        if exempt {
+            exempted.insert(id);
+        } else {
+            exempted.remove(&id);
+        }
+        self.audit_log.lock().unwrap().push((
+            "new_conversation_limit.exempt".to_string(),
+            id,
+            format!("exempt={}", exempt),
+        ));
+        Ok(true)
+    }
+
+    async fn registrations_since(&self, since: DateTime<Utc>) -> RepoResult<i64> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|u| u.created_at > since)
+            .count() as i64)
+    }
+}
+
+/// A send counter: user, window name and window start.
+type SendCounterKey = (Uuid, &'static str, DateTime<Utc>);
+
+#[derive(Default)]
+pub struct FakeMessageRepo {
+    messages: Mutex<HashMap<Uuid, MessageRecord>>,
+    pins: Mutex<HashMap<Uuid, (Uuid, DateTime<Utc>)>>,
+    send_counters: Mutex<HashMap<SendCounterKey, i64>>,
+    /// Delivery attempts per message, oldest first.
+    delivery_attempts: Mutex<HashMap<Uuid, Vec<DeliveryAttemptRecord>>>,
+    delivery_receipts: Mutex<HashMap<Uuid, DeliveryReceiptRecord>>,
+    sealed: Mutex<HashMap<Uuid, SealedMessageRecord>>,
+    /// Receiver of each routing id.
+    routing: Mutex<HashMap<String, Uuid>>,
+}
+
+impl FakeMessageRepo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a message directly with the given status and returns its id.
+    pub fn seed_message(&self, sender_id: Uuid, receiver_id: Uuid, status: MessageStatus) -> Uuid {
+        self.seed_message_at(
+            sender_id,
+            receiver_id,
+            status,
+            Utc::now().timestamp_millis(),
+        )
+    }
+
+    /// Like `seed_message`, with an explicit Unix millisecond timestamp.
+    pub fn seed_message_at(
+        &self,
+        sender_id: Uuid,
+        receiver_id: Uuid,
+        status: MessageStatus,
+        timestamp: i64,
+    ) -> Uuid {
+        let id = Uuid::new_v4();
+        self.messages.lock().unwrap().insert(
+            id,
+            MessageRecord {
+                id,
+                timestamp,
+                sender_id,
+                receiver_id,
+                status,
+                r#type: "Text".to_string(),
+                encrypted_content: vec![1, 2, 3],
+                iv: vec![0; 12],
+                forwarded_from_id: None,
+                forward_count: 0,
+                reply_to_id: None,
+                reply_to_deleted: false,
+                encryption_version: 1,
+                device_id: None,
+                cipher_suite: "AES_256_GCM".to_string(),
+            },
+        );
+        id
+    }
+
+    fn backlogs(&self) -> HashMap<Uuid, BacklogRecord> {
+        let mut backlogs: HashMap<Uuid, BacklogRecord> = HashMap::new();
+        for message in self.messages.lock().unwrap().values() {
+            if message.status != MessageStatus::Sent {
+                continue;
+            }
+            let entry = backlogs
+                .entry(message.receiver_id)
+                .or_insert(BacklogRecord {
+                    user_id: message.receiver_id,
+                    count: 0,
+                    oldest_timestamp: None,
+                    oldest_message_id: None,
+                });
+            entry.count += 1;
+            let oldest = entry.oldest_timestamp.zip(entry.oldest_message_id);
+            if oldest.is_none_or(|oldest| (message.timestamp, message.id) < oldest) {
+                entry.oldest_timestamp = Some(message.timestamp);
+                entry.oldest_message_id = Some(message.id);
+            }
+        }
+        backlogs
+    }
+
+    pub fn status_of(&self, id: Uuid) -> Option<MessageStatus> {
+        self.messages.lock().unwrap().get(&id).map(|m| m.status)
+    }
+}
+
+/// A stored message as the repository returns it, with `reply_to_deleted` filled in.
+fn read_message(messages: &HashMap<Uuid, MessageRecord>, message: &MessageRecord) -> MessageRecord {
+    let mut message = message.clone();
+    message.reply_to_deleted = message
+        .reply_to_id
+        .is_some_and(|id| !messages.contains_key(&id));
+    message
+}
+
+fn in_conversation(message: &MessageRecord, user_a: Uuid, user_b: Uuid) -> bool {
+    (message.sender_id == user_a && message.receiver_id == user_b)
+        || (message.sender_id == user_b && message.receiver_id == user_a)
+}
+
+#[async_trait]
+impl MessageRepo for FakeMessageRepo {
+    async fn find_message(&self, id: Uuid) -> RepoResult<Option<MessageRecord>> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages.get(&id).map(|m| read_message(&messages, m)))
+    }
+
+    async fn update_status(
+        &self,
+        id: Uuid,
+        from: MessageStatus,
+        to: MessageStatus,
+    ) -> RepoResult<bool> {
+        let mut messages = self.messages.lock().unwrap();
+        match messages.get_mut(&id) {
+            Some(message) if message.status == from => {
+                message.status = to;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn set_message_type(&self, id: Uuid, message_type: &str) -> RepoResult<bool> {
+        let mut messages = self.messages.lock().unwrap();
+        match messages.get_mut(&id) {
+            Some(message) => {
+                message.r#type = message_type.to_string();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn mark_sent_if_pending(&self, id: Uuid) -> RepoResult<()> {
+        if let Some(message) = self.messages.lock().unwrap().get_mut(&id)
+            && message.status == MessageStatus::Pending
+        {
+            message.status = MessageStatus::Sent;
+        }
+        Ok(())
+    }
+
+    async fn mark_delivered(&self, receiver_id: Uuid, ids: &[Uuid]) -> RepoResult<Vec<Uuid>> {
+        let mut messages = self.messages.lock().unwrap();
+        let mut changed = Vec::new();
+        for id in ids {
+            if let Some(message) = messages.get_mut(id)
+                && message.receiver_id == receiver_id
+                && message.status == MessageStatus::Sent
+            {
+                message.status = MessageStatus::Delivered;
+                changed.push(*id);
+            }
+        }
+        Ok(changed)
+    }
+
+    async fn record_delivery_attempt(
+        &self,
+        attempt: &DeliveryAttemptRecord,
+        keep: i64,
+    ) -> RepoResult<()> {
+        let mut attempts = self.delivery_attempts.lock().unwrap();
+        let attempts = attempts.entry(attempt.message_id).or_default();
+        attempts.push(attempt.clone());
+        let excess = attempts.len().saturating_sub(keep.max(0) as usize);
+        attempts.drain(..excess);
+        Ok(())
+    }
+
+    async fn delivery_attempts(&self, message_id: Uuid) -> RepoResult<Vec<DeliveryAttemptRecord>> {
+        let mut attempts = self
+            .delivery_attempts
+            .lock()
+            .unwrap()
+            .get(&message_id)
+            .cloned()
+            .unwrap_or_default();
+        // Newest first, like `ORDER BY attempt_at DESC, id DESC`: attempts are recorded in
+        // the background, so they may arrive out of order
+        attempts.reverse();
+        attempts.sort_by_key(|attempt| std::cmp::Reverse(attempt.attempt_at));
+        Ok(attempts)
+    }
+
+    async fn save_delivery_receipt(&self, receipt: &DeliveryReceiptRecord) -> RepoResult<()> {
+        self.delivery_receipts
+            .lock()
+            .unwrap()
+            .entry(receipt.message_id)
+            .and_modify(|stored| {
+                stored.status = receipt.status;
+                stored.signature = receipt.signature.clone();
+                stored.received_at = receipt.received_at;
+            })
+            .or_insert_with(|| receipt.clone());
+        Ok(())
+    }
+
+    async fn delivery_receipt(
+        &self,
+        message_id: Uuid,
+    ) -> RepoResult<Option<DeliveryReceiptRecord>> {
+        Ok(self
+            .delivery_receipts
+            .lock()
+            .unwrap()
+            .get(&message_id)
+            .cloned())
+    }
+
+    async fn sweep_pending(&self, cutoff_millis: i64) -> RepoResult<u64> {
+        let mut count = 0;
+        for message in self.messages.lock().unwrap().values_mut() {
+            if message.status == MessageStatus::Pending && message.timestamp < cutoff_millis {
+                message.status = MessageStatus::Sent;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    async fn increment_forward_count(&self, id: Uuid) -> RepoResult<bool> {
+        match self.messages.lock().unwrap().get_mut(&id) {
+            Some(message) => {
+                message.forward_count += 1;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn unread_counts(
+        &self,
+        receiver_id: Uuid,
+        sender_ids: Option<&[Uuid]>,
+    ) -> RepoResult<HashMap<Uuid, i64>> {
+        let mut counts = HashMap::new();
+        for message in self.messages.lock().unwrap().values() {
+            if message.receiver_id == receiver_id
+                && sender_ids.is_none_or(|ids| ids.contains(&message.sender_id))
+                && matches!(
+                    message.status,
+                    MessageStatus::Pending | MessageStatus::Sent | MessageStatus::Delivered
+                )
+            {
+                *counts.entry(message.sender_id).or_insert(0) += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn message_type_counts(&self, user_id: Uuid) -> RepoResult<Vec<MessageTypeCount>> {
+        let mut counts: BTreeMap<String, MessageTypeCount> = BTreeMap::new();
+        for message in self.messages.lock().unwrap().values() {
+            if message.sender_id != user_id && message.receiver_id != user_id {
+                continue;
+            }
+            let count = counts
+                .entry(message.r#type.clone())
+                .or_insert_with(|| MessageTypeCount {
+                    r#type: message.r#type.clone(),
+                    sent: 0,
+                    received: 0,
+                });
+            count.sent += i64::from(message.sender_id == user_id);
+            count.received += i64::from(message.receiver_id == user_id);
+        }
+        Ok(counts.into_values().collect())
+    }
+
+    async fn backlog_for(&self, user_id: Uuid) -> RepoResult<BacklogRecord> {
+        Ok(self.backlogs().remove(&user_id).unwrap_or(BacklogRecord {
+            user_id,
+            count: 0,
+            oldest_timestamp: None,
+            oldest_message_id: None,
+        }))
+    }
+
+    async fn largest_backlogs(&self, limit: i64) -> RepoResult<Vec<BacklogRecord>> {
+        let mut backlogs: Vec<BacklogRecord> = self.backlogs().into_values().collect();
+        backlogs.sort_by_key(|b| (std::cmp::Reverse(b.count), b.oldest_timestamp));
+        backlogs.truncate(limit.max(0) as usize);
+        Ok(backlogs)
+    }
+
+    async fn delete_read_message(&self, id: Uuid, keep_pinned: bool) -> RepoResult<bool> {
+        if keep_pinned && self.pins.lock().unwrap().contains_key(&id) {
+            return Ok(false);
+        }
+        let removed = self.messages.lock().unwrap().remove(&id).is_some();
+        self.pins.lock().unwrap().remove(&id);
+        Ok(removed)
+    }
+
+    async fn conversation(
+        &self,
+        user_a: Uuid,
+        user_b: Uuid,
+        query: ConversationQuery,
+    ) -> RepoResult<Vec<MessageRecord>> {
+        let key = |m: &MessageRecord| (m.timestamp, m.id);
+        let past_cursor = |m: &MessageRecord| match (query.cursor, query.order) {
+            (None, _) => true,
+            (Some(c), SortOrder::Asc) => key(m) > (c.timestamp, c.id),
+            (Some(c), SortOrder::Desc) => key(m) < (c.timestamp, c.id),
+        };
+        let stored = self.messages.lock().unwrap();
+        let mut messages: Vec<MessageRecord> = stored
+            .values()
+            .filter(|m| in_conversation(m, user_a, user_b))
+            .filter(|m| query.after.is_none_or(|after| m.timestamp >= after))
+            .filter(|m| query.before.is_none_or(|before| m.timestamp <= before))
+            .filter(|m| past_cursor(m))
+            .map(|m| read_message(&stored, m))
+            .collect();
+        messages.sort_by_key(key);
+        if query.order == SortOrder::Desc {
+            messages.reverse();
+        }
+        if let Some(limit) = query.limit {
+            messages.truncate(limit.max(0) as usize);
+        }
+        Ok(messages)
+    }
+
+    async fn messages_of_user(
+        &self,
+        user_id: Uuid,
+        cursor: Option<MessageCursor>,
+        limit: i64,
+    ) -> RepoResult<Vec<MessageRecord>> {
+        let key = |m: &MessageRecord| (m.timestamp, m.id);
+        let stored = self.messages.lock().unwrap();
+        let mut messages: Vec<MessageRecord> = stored
+            .values()
+            .filter(|m| m.sender_id == user_id || m.receiver_id == user_id)
+            .filter(|m| cursor.is_none_or(|c| key(m) > (c.timestamp, c.id)))
+            .map(|m| read_message(&stored, m))
+            .collect();
+        messages.sort_by_key(key);
+        messages.truncate(limit.max(0) as usize);
+        Ok(messages)
+    }
+
+    async fn insert_message_after_latest(
+        &self,
+        message: &MessageRecord,
+    ) -> RepoResult<OrderedInsert> {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.contains_key(&message.id) {
+            return Err(RepoError::Duplicate);
+        }
+        let latest = messages
+            .values()
+            .filter(|m| in_conversation(m, message.sender_id, message.receiver_id))
+            .map(|m| m.timestamp)
+            .max();
+        let mut stored = message.clone();
+        if let Some(latest) = latest
+            && stored.timestamp <= latest
+        {
+            stored.timestamp = latest + 1;
+        }
+        let inserted = OrderedInsert {
+            timestamp: stored.timestamp,
+            was_behind: latest.is_some_and(|latest| message.timestamp < latest),
+        };
+        messages.insert(stored.id, stored);
+        Ok(inserted)
+    }
+
+    async fn delete_conversation(&self, user_a: Uuid, user_b: Uuid) -> RepoResult<u64> {
+        let mut messages = self.messages.lock().unwrap();
+        let before = messages.len();
+        messages.retain(|_, m| !in_conversation(m, user_a, user_b));
+        let removed = before - messages.len();
+        let mut pins = self.pins.lock().unwrap();
+        pins.retain(|id, _| messages.contains_key(id));
+        Ok(removed as u64)
+    }
+
+    async fn pin(&self, message_id: Uuid, pinned_by: Uuid) -> RepoResult<bool> {
+        if !self.messages.lock().unwrap().contains_key(&message_id) {
+            return Err(RepoError::Database("message does not exist".to_string()));
+        }
+        let mut pins = self.pins.lock().unwrap();
+        if pins.contains_key(&message_id) {
+            return Ok(false);
+        }
+        pins.insert(message_id, (pinned_by, Utc::now()));
+        Ok(true)
+    }
+
+    async fn unpin(&self, message_id: Uuid) -> RepoResult<bool> {
+        Ok(self.pins.lock().unwrap().remove(&message_id).is_some())
+    }
+
+    async fn pinned_in_conversation(
+        &self,
+        user_a: Uuid,
+        user_b: Uuid,
+    ) -> RepoResult<Vec<PinnedMessageRecord>> {
+        let messages = self.messages.lock().unwrap();
+        let pins = self.pins.lock().unwrap();
+        let mut pinned: Vec<PinnedMessageRecord> = pins
+            .iter()
+            .filter_map(|(id, (pinned_by, pinned_at))| {
+                messages
+                    .get(id)
+                    .filter(|m| in_conversation(m, user_a, user_b))
+                    .map(|m| PinnedMessageRecord {
+                        message: read_message(&messages, m),
+                        pinned_by: *pinned_by,
+                        pinned_at: *pinned_at,
+                    })
+            })
+            .collect();
+        pinned.sort_by_key(|p| p.message.timestamp);
+        Ok(pinned)
+    }
+
+    async fn record_send(
+        &self,
+        user_id: Uuid,
+        windows: &[SendQuotaWindow],
+    ) -> RepoResult<SendQuotaOutcome> {
+        let mut counters = self.send_counters.lock().unwrap();
+        if let Some(oldest) = windows.iter().map(|window| window.start).min() {
+            counters.retain(|(user, _, start), _| *user != user_id || *start >= oldest);
+        }
+        for window in windows {
+            let count = counters
+                .get(&(user_id, window.name, window.start))
+                .copied()
+                .unwrap_or(0);
+            if count + 1 > window.limit {
+                return Ok(SendQuotaOutcome::Exceeded(*window));
+            }
+        }
+        for window in windows {
+            *counters
+                .entry((user_id, window.name, window.start))
+                .or_insert(0) += 1;
+        }
+        Ok(SendQuotaOutcome::Allowed)
+    }
+
+    async fn refund_send(&self, user_id: Uuid, windows: &[SendQuotaWindow]) -> RepoResult<()> {
+        let mut counters = self.send_counters.lock().unwrap();
+        for window in windows {
+            if let Some(count) = counters.get_mut(&(user_id, window.name, window.start)) {
+                *count = (*count - 1).max(0);
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_counts(
+        &self,
+        user_id: Uuid,
+        windows: &[SendQuotaWindow],
+    ) -> RepoResult<Vec<i64>> {
+        let counters = self.send_counters.lock().unwrap();
+        Ok(windows
+            .iter()
+            .map(|window| {
+                counters
+                    .get(&(user_id, window.name, window.start))
+                    .copied()
+                    .unwrap_or(0)
+            })
+            .collect())
+    }
+
+    async fn sends_since(&self, window: &str, since: DateTime<Utc>) -> RepoResult<i64> {
+        Ok(self
+            .send_counters
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((_, name, start), _)| *name == window && *start >= since)
+            .map(|(_, count)| count)
+            .sum())
+    }
+
+    async fn top_senders(
+        &self,
+        window: &str,
+        start: DateTime<Utc>,
+        limit: i64,
+    ) -> RepoResult<Vec<SenderActivity>> {
+        let mut senders: Vec<SenderActivity> = self
+            .send_counters
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((_, name, window_start), _)| *name == window && *window_start == start)
+            .map(|((user_id, _, _), count)| SenderActivity {
+                user_id: *user_id,
+                sends: *count,
+            })
+            .collect();
+        senders.sort_by_key(|s| (std::cmp::Reverse(s.sends), s.user_id));
+        senders.truncate(limit.max(0) as usize);
+        Ok(senders)
+    }
+
+    async fn insert_sealed_message(
+        &self,
+        message: &SealedMessageRecord,
+        receiver_id: Uuid,
+    ) -> RepoResult<bool> {
+        let mut sealed = self.sealed.lock().unwrap();
+        if sealed.contains_key(&message.id) {
+            return Ok(false);
+        }
+        self.routing
+            .lock()
+            .unwrap()
+            .insert(message.routing_id.clone(), receiver_id);
+        sealed.insert(message.id, message.clone());
+        Ok(true)
+    }
+
+    async fn sealed_messages_for(&self, receiver_id: Uuid) -> RepoResult<Vec<SealedMessageRecord>> {
+        let routing = self.routing.lock().unwrap();
+        let mut messages: Vec<SealedMessageRecord> = self
+            .sealed
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|m| routing.get(&m.routing_id) == Some(&receiver_id))
+            .cloned()
+            .collect();
+        messages.sort_by_key(|m| (m.day, m.id));
+        Ok(messages)
+    }
+
+    async fn delete_sealed_message(&self, id: Uuid, receiver_id: Uuid) -> RepoResult<bool> {
+        let mut routing = self.routing.lock().unwrap();
+        let mut sealed = self.sealed.lock().unwrap();
+        let Some(routing_id) = sealed
+            .get(&id)
+            .map(|m| m.routing_id.clone())
+            .filter(|routing_id| routing.get(routing_id) == Some(&receiver_id))
+        else {
+            return Ok(false);
+        };
+        sealed.remove(&id);
+        if !sealed.values().any(|m| m.routing_id == routing_id) {
+            routing.remove(&routing_id);
+        }
+        Ok(true)
+    }
+}
+
+/// A stored request and when it was declined or withdrawn, if it was.
+type StoredRequest = (ContactRequestRecord, Option<DateTime<Utc>>);
+/// When each `(user_id, peer_id)` row opened its conversation, if it did.
+type ConversationPeers = HashMap<(Uuid, Uuid), Option<DateTime<Utc>>>;
+
+pub struct FakeContactRepo {
+    users: Arc<FakeUserRepo>,
+    contacts: Mutex<Vec<(Uuid, Uuid, DateTime<Utc>)>>,
+    conversation_peers: Mutex<ConversationPeers>,
+    conversation_settings: Mutex<HashMap<(Uuid, Uuid), ConversationSettings>>,
+    requests: Mutex<HashMap<Uuid, StoredRequest>>,
+}
+
+impl FakeContactRepo {
+    /// Contacts reference users, so the fake shares the user store it validates against.
+    pub fn new(users: Arc<FakeUserRepo>) -> Self {
+        Self {
+            users,
+            contacts: Mutex::new(Vec::new()),
+            conversation_peers: Mutex::new(HashMap::new()),
+            conversation_settings: Mutex::new(HashMap::new()),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Marks a request as closed at the given time, for cooldown tests.
+    pub fn close_request_at(&self, id: Uuid, status: &str, responded_at: DateTime<Utc>) {
+        if let Some((request, closed)) = self.requests.lock().unwrap().get_mut(&id) {
+            request.status = status.to_string();
+            *closed = Some(responded_at);
+        }
+    }
+}
+
+#[async_trait]
+impl ContactRepo for FakeContactRepo {
+    async fn accepted_peers(&self, user_id: Uuid) -> RepoResult<HashSet<Uuid>> {
+        let mut peers: HashSet<Uuid> = self
+            .contacts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(owner, _, _)| *owner == user_id)
+            .map(|(_, contact, _)| *contact)
+            .collect();
+        peers.extend(
+            self.conversation_peers
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|(user, _)| *user == user_id)
+                .map(|(_, peer)| *peer),
+        );
+        Ok(peers)
+    }
+
+    async fn record_conversation_peer(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+        opened_at: Option<DateTime<Utc>>,
+    ) -> RepoResult<()> {
+        self.conversation_peers
+            .lock()
+            .unwrap()
+            .entry((user_id, peer_id))
+            .or_insert(opened_at);
+        Ok(())
+    }
+
+    async fn has_conversation(&self, user_id: Uuid, peer_id: Uuid) -> RepoResult<bool> {
+        let peers = self.conversation_peers.lock().unwrap();
+        Ok(peers.contains_key(&(user_id, peer_id)) || peers.contains_key(&(peer_id, user_id)))
+    }
+
+    async fn conversations_opened_since(
+        &self,
+        user_id: Uuid,
+        since: DateTime<Utc>,
+    ) -> RepoResult<(i64, Option<DateTime<Utc>>)> {
+        let opened: Vec<DateTime<Utc>> = self
+            .conversation_peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((user, _), _)| *user == user_id)
+            .filter_map(|(_, opened_at)| *opened_at)
+            .filter(|opened_at| *opened_at > since)
+            .collect();
+        Ok((opened.len() as i64, opened.into_iter().min()))
+    }
+
+    async fn conversation_settings(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+    ) -> RepoResult<ConversationSettings> {
+        Ok(self
+            .conversation_settings
+            .lock()
+            .unwrap()
+            .get(&(user_id, peer_id))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_conversation_settings(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+        settings: &ConversationSettings,
+    ) -> RepoResult<()> {
+        self.conversation_settings
+            .lock()
+            .unwrap()
+            .insert((user_id, peer_id), settings.clone());
+        Ok(())
+    }
+
+    async fn search_conversation_peers(
+        &self,
+        user_id: Uuid,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> RepoResult<(Vec<ConversationPeerRecord>, i64)> {
+        let peer_ids: HashSet<Uuid> = self
+            .conversation_peers
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|&(from, to)| match (from == user_id, to == user_id) {
+                (true, _) => Some(to),
+                (_, true) => Some(from),
+                _ => None,
+            })
+            .collect();
+        let needle = query.to_lowercase();
+        let mut matches: Vec<ConversationPeerRecord> = self
+            .users
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|user| peer_ids.contains(&user.id))
+            .filter(|user| user.username.to_lowercase().contains(&needle))
+            .map(|user| ConversationPeerRecord {
+                user_id: user.id,
+                username: user.username.clone(),
+            })
+            .collect();
+        matches.sort_by(|a, b| {
+            (a.username.to_lowercase(), a.user_id).cmp(&(b.username.to_lowercase(), b.user_id))
+        });
+        let total = matches.len() as i64;
+        let page = matches
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect();
+        Ok((page, total))
+    }
+
+    async fn list_contacts(&self, owner_id: Uuid) -> RepoResult<Vec<ContactRecord>> {
+        Ok(self
+            .contacts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(owner, _, _)| *owner == owner_id)
+            .map(|(_, contact, added_at)| ContactRecord {
+                user_id: *contact,
+                username: self.users.username(*contact).unwrap_or_default(),
+                public_key: self.users.public_key(*contact).unwrap_or_default(),
+                added_at: *added_at,
+            })
+            .collect())
+    }
+
+    async fn add_contact(
+        &self,
+        owner_id: Uuid,
+        contact_id: Uuid,
+        max_contacts: i64,
+    ) -> RepoResult<AddContactOutcome> {
+        if !self.users.contains(contact_id) {
+            return Ok(AddContactOutcome::UserNotFound);
+        }
+        let mut contacts = self.contacts.lock().unwrap();
+        if contacts
+            .iter()
+            .any(|(o, c, _)| *o == owner_id && *c == contact_id)
+        {
+            return Ok(AddContactOutcome::Added);
+        }
+        let count = contacts.iter().filter(|(o, _, _)| *o == owner_id).count();
+        if count as i64 >= max_contacts {
+            return Ok(AddContactOutcome::LimitReached);
+        }
+        contacts.push((owner_id, contact_id, Utc::now()));
+        Ok(AddContactOutcome::Added)
+    }
+
+    async fn remove_contact(&self, owner_id: Uuid, contact_id: Uuid) -> RepoResult<bool> {
+        let mut contacts = self.contacts.lock().unwrap();
+        let before = contacts.len();
+        contacts.retain(|(o, c, _)| !(*o == owner_id && *c == contact_id));
+        Ok(contacts.len() != before)
+    }
+
+    async fn is_contact(&self, owner_id: Uuid, contact_id: Uuid) -> RepoResult<bool> {
+        Ok(self
+            .contacts
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(o, c, _)| *o == owner_id && *c == contact_id))
+    }
+
+    async fn create_request(
+        &self,
+        requester_id: Uuid,
+        target_id: Uuid,
+        message: Option<&str>,
+        closed_after: DateTime<Utc>,
+    ) -> RepoResult<CreateRequestOutcome> {
+        let mut requests = self.requests.lock().unwrap();
+        let last_closed = requests
+            .values()
+            .filter(|(r, _)| {
+                r.requester_id == requester_id
+                    && r.target_id == target_id
+                    && (r.status == "DECLINED" || r.status == "WITHDRAWN")
+            })
+            .filter_map(|(_, closed)| *closed)
+            .max();
+        if let Some(closed_at) = last_closed
+            && closed_at > closed_after
+        {
+            return Ok(CreateRequestOutcome::CoolingDown(closed_at));
+        }
+        let pair_pending = requests.values().any(|(r, _)| {
+            r.status == "PENDING"
+                && ((r.requester_id == requester_id && r.target_id == target_id)
+                    || (r.requester_id == target_id && r.target_id == requester_id))
+        });
+        if pair_pending {
+            return Err(RepoError::Duplicate);
+        }
+        let request = ContactRequestRecord {
+            id: Uuid::new_v4(),
+            requester_id,
+            requester_username: self.users.username(requester_id).unwrap_or_default(),
+            target_id,
+            status: "PENDING".to_string(),
+            created_at: Utc::now(),
+            message: message.map(str::to_string),
+        };
+        requests.insert(request.id, (request.clone(), None));
+        Ok(CreateRequestOutcome::Created(request))
+    }
+
+    async fn find_request(&self, id: Uuid) -> RepoResult<Option<ContactRequestRecord>> {
+        Ok(self
+            .requests
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|(r, _)| r.clone()))
+    }
+
+    async fn list_pending_requests(&self, user_id: Uuid) -> RepoResult<Vec<ContactRequestRecord>> {
+        let mut pending: Vec<ContactRequestRecord> = self
+            .requests
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(r, _)| {
+                r.status == "PENDING" && (r.requester_id == user_id || r.target_id == user_id)
+            })
+            .map(|(r, _)| r.clone())
+            .collect();
+        pending.sort_by_key(|r| r.created_at);
+        Ok(pending)
+    }
+
+    async fn resolve_request(
+        &self,
+        id: Uuid,
+        status: &str,
+        accept: bool,
+    ) -> RepoResult<Option<ContactRequestRecord>> {
+        let resolved = {
+            let mut requests = self.requests.lock().unwrap();
+            match requests.get_mut(&id) {
+                Some((request, closed)) if request.status == "PENDING" => {
+                    request.status = status.to_string();
+                    *closed = Some(Utc::now());
+                    request.clone()
+                }
+                _ => return Ok(None),
+            }
+        };
+        if accept {
+            // Accepted requests are not subject to the contact limit
+            self.add_contact(resolved.requester_id, resolved.target_id, i64::MAX)
+                .await?;
+            self.add_contact(resolved.target_id, resolved.requester_id, i64::MAX)
+                .await?;
+        }
+        Ok(Some(resolved))
+    }
+}
+
+#[derive(Default)]
+pub struct FakeAnnouncementRepo {
+    announcements: Mutex<Vec<AnnouncementRecord>>,
+    /// Audit entries as `(action, target_id, source_ip)`.
+    audit_log: Mutex<Vec<(String, Uuid, String)>>,
+}
+
+impl FakeAnnouncementRepo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn audit_actions(&self) -> Vec<String> {
+        self.audit_log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(action, _, _)| action.clone())
+            .collect()
+    }
+}
+
+#[async_trait]
+impl AnnouncementRepo for FakeAnnouncementRepo {
+    async fn create_announcement(
+        &self,
+        announcement: &AnnouncementRecord,
+        actor: &AuditActor,
+    ) -> RepoResult<()> {
+        self.announcements
+            .lock()
+            .unwrap()
+            .push(announcement.clone());
+        self.audit_log.lock().unwrap().push((
+            "announcement.create".to_string(),
+            announcement.id,
+            actor.source_ip.clone(),
+        ));
+        Ok(())
+    }
+
+    async fn active_announcements(
+        &self,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Vec<AnnouncementRecord>> {
+        let mut active: Vec<AnnouncementRecord> = self
+            .announcements
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|a| a.expires_at.is_none_or(|expires_at| expires_at > now))
+            .cloned()
+            .collect();
+        active.sort_by_key(|a| a.created_at);
+        Ok(active)
+    }
+
+    async fn delete_announcement(&self, id: Uuid, actor: &AuditActor) -> RepoResult<bool> {
+        let mut announcements = self.announcements.lock().unwrap();
+        let before = announcements.len();
+        announcements.retain(|a| a.id != id);
+        if announcements.len() == before {
+            return Ok(false);
+        }
+        self.audit_log.lock().unwrap().push((
+            "announcement.delete".to_string(),
+            id,
+            actor.source_ip.clone(),
+        ));
+        Ok(true)
+    }
+}
+
+#[derive(Default)]
+pub struct FakeDeviceRepo {
+    devices: Mutex<Vec<DeviceRecord>>,
+}
+
+impl FakeDeviceRepo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl DeviceRepo for FakeDeviceRepo {
+    async fn register_device(&self, device: &DeviceRecord) -> RepoResult<()> {
+        let mut devices = self.devices.lock().unwrap();
+        if devices
+            .iter()
+            .any(|d| d.user_id == device.user_id && d.public_key == device.public_key)
+        {
+            return Err(RepoError::Duplicate);
+        }
+        devices.push(device.clone());
+        Ok(())
+    }
+
+    async fn list_devices(&self, user_id: Uuid) -> RepoResult<Vec<DeviceRecord>> {
+        let mut devices: Vec<DeviceRecord> = self
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|d| d.user_id == user_id)
+            .cloned()
+            .collect();
+        devices.sort_by_key(|d| d.created_at);
+        Ok(devices)
+    }
+
+    async fn find_device(&self, id: Uuid) -> RepoResult<Option<DeviceRecord>> {
+        Ok(self
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|d| d.id == id)
+            .cloned())
+    }
+
+    async fn revoke_device(&self, user_id: Uuid, id: Uuid) -> RepoResult<bool> {
+        let mut devices = self.devices.lock().unwrap();
+        let before = devices.len();
+        devices.retain(|d| !(d.id == id && d.user_id == user_id));
+        Ok(devices.len() != before)
+    }
+
+    async fn touch_device(&self, id: Uuid, now: DateTime<Utc>) -> RepoResult<()> {
+        if let Some(device) = self.devices.lock().unwrap().iter_mut().find(|d| d.id == id) {
+            device.last_active = Some(now);
+        }
+        Ok(())
+    }
+}
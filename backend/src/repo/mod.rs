@@ -0,0 +1,812 @@
+//! Repository layer for Safe Chat backend
+//!
+//! Handlers and services talk to storage through the `UserRepo`, `MessageRepo`,
+//! `ContactRepo`, `AnnouncementRepo` and `DeviceRepo` traits. `postgres` holds the production implementations wrapping the
+//! SQL queries; `fake` holds in-memory implementations for tests (enabled with the
+//! `test-utils` feature or in unit tests).
+
+pub mod postgres;
+
+// A binary crate has no downstream users, so outside tests the fakes are unused.
+#[cfg(any(test, feature = "test-utils"))]
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod fake;
+
+use crate::circuit_breaker::DB_CIRCUIT_BREAKER;
+use crate::db::is_outage;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use uuid::Uuid;
+
+pub use safechat_types::auth::UserPreferences;
+pub use safechat_types::messages::MessageStatus;
+
+#[derive(Debug)]
+pub enum RepoError {
+    /// A unique constraint was violated (e.g. username taken, request already pending).
+    Duplicate,
+    Database(String),
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoError::Duplicate => write!(f, "duplicate record"),
+            RepoError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+/// Every database error the repositories return passes through here, so this is also
+/// where outages are counted towards opening `DB_CIRCUIT_BREAKER`.
+impl From<sqlx::Error> for RepoError {
+    fn from(e: sqlx::Error) -> Self {
+        if is_outage(&e) {
+            DB_CIRCUIT_BREAKER.record_failure();
+        }
+        match e.as_database_error() {
+            Some(db_err) if db_err.is_unique_violation() => RepoError::Duplicate,
+            _ => RepoError::Database(e.to_string()),
+        }
+    }
+}
+
+/// The other half of the `From<sqlx::Error>` conversion above: the Postgres repositories
+/// pass each query result through `recorded` before `?`, so a query that actually
+/// reached the database closes `DB_CIRCUIT_BREAKER` (e.g. the half-open probe).
+pub(crate) trait RecordOutcome {
+    fn recorded(self) -> Self;
+}
+
+impl<T> RecordOutcome for Result<T, sqlx::Error> {
+    fn recorded(self) -> Self {
+        if self.is_ok() {
+            DB_CIRCUIT_BREAKER.record_success();
+        }
+        self
+    }
+}
+
+pub type RepoResult<T> = Result<T, RepoError>;
+
+#[derive(Debug, Clone)]
+pub struct UserRecord {
+    pub id: Uuid,
+    pub username: String,
+    pub password_hash: String,
+    pub public_key: String,
+    pub created_at: DateTime<Utc>,
+    pub avatar: Option<Vec<u8>>,
+    /// Content type detected when the avatar was uploaded.
+    pub avatar_content_type: Option<String>,
+    pub key_version: i32,
+    /// Bumped whenever the username, avatar, public key or preferences change; used for
+    /// profile ETags.
+    pub profile_updated_at: DateTime<Utc>,
+}
+
+/// A past username change of one user.
+#[derive(Debug, Clone)]
+pub struct UsernameChangeRecord {
+    pub old_username: String,
+    pub new_username: String,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// A public key stored again in another encoding, see `UserRepo::rewrite_key_formats`.
+#[derive(Debug, Clone)]
+pub struct KeyFormatChange {
+    pub user_id: Uuid,
+    pub old_public_key: String,
+    pub new_public_key: String,
+}
+
+/// An avatar stored again under another column key, see `UserRepo::rewrite_avatars`.
+#[derive(Debug, Clone)]
+pub struct AvatarRewrite {
+    pub user_id: Uuid,
+    pub old_avatar: Vec<u8>,
+    pub new_avatar: Vec<u8>,
+}
+
+/// At most `max_changes` username changes are allowed after `since`.
+#[derive(Debug, Clone, Copy)]
+pub struct UsernameChangeLimit {
+    pub max_changes: i64,
+    pub since: DateTime<Utc>,
+}
+
+/// Result of `UserRepo::update_profile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileUpdateOutcome {
+    Updated,
+    /// The username change would exceed the limit; nothing was updated. Holds when the
+    /// oldest change counted against the limit was made.
+    UsernameChangeLimited(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageRecord {
+    pub id: Uuid,
+    pub timestamp: i64,
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    pub status: MessageStatus,
+    pub r#type: String,
+    pub encrypted_content: Vec<u8>,
+    pub iv: Vec<u8>,
+    /// The message this one was forwarded from, if any.
+    pub forwarded_from_id: Option<Uuid>,
+    /// How many times this message has been forwarded.
+    pub forward_count: i32,
+    /// The message this one replies to, if any.
+    pub reply_to_id: Option<Uuid>,
+    /// Whether the message `reply_to_id` points at no longer exists. Filled in when the
+    /// message is read; ignored when it is stored.
+    pub reply_to_deleted: bool,
+    /// Scheme the content was encrypted with; see `crypto::SUPPORTED_ENCRYPTION_VERSIONS`.
+    pub encryption_version: i16,
+    /// The receiver's device this ciphertext copy was encrypted for; `None` for the account key.
+    pub device_id: Option<Uuid>,
+    /// Symmetric cipher of the content; see `crypto::SUPPORTED_CIPHER_SUITES`.
+    pub cipher_suite: String,
+}
+
+/// Direction a conversation is listed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A position in a conversation listing: the last message of the previous page. The next
+/// page holds the messages strictly past it in the listing order, compared by
+/// `(timestamp, id)` so messages sharing a timestamp are neither skipped nor repeated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageCursor {
+    pub timestamp: i64,
+    pub id: Uuid,
+}
+
+/// Which messages of a conversation to list, and how.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConversationQuery {
+    /// Inclusive lower bound on the Unix millisecond timestamp.
+    pub after: Option<i64>,
+    /// Inclusive upper bound on the Unix millisecond timestamp.
+    pub before: Option<i64>,
+    pub order: SortOrder,
+    pub cursor: Option<MessageCursor>,
+    /// Most messages to return; all of them when unset.
+    pub limit: Option<i64>,
+}
+
+/// One fixed window of a user's send quota: at most `limit` sends from `start` on.
+/// Where `MessageRepo::insert_message_after_latest` stored a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderedInsert {
+    pub timestamp: i64,
+    /// The message's own timestamp was before the conversation's latest one. A timestamp
+    /// equal to the latest is also moved 1ms forward, but is not behind.
+    pub was_behind: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendQuotaWindow {
+    pub name: &'static str,
+    pub start: DateTime<Utc>,
+    pub limit: i64,
+}
+
+/// Result of `MessageRepo::record_send`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendQuotaOutcome {
+    Allowed,
+    /// The send would exceed this window's limit; nothing was counted.
+    Exceeded(SendQuotaWindow),
+}
+
+/// How many of a user's stored messages have one `type`, by direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTypeCount {
+    pub r#type: String,
+    pub sent: i64,
+    pub received: i64,
+}
+
+/// How many messages one user sent in a send counter window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderActivity {
+    pub user_id: Uuid,
+    pub sends: i64,
+}
+
+/// Undelivered (SENT) messages waiting for one receiver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacklogRecord {
+    pub user_id: Uuid,
+    pub count: i64,
+    /// Timestamp of the oldest waiting message in Unix milliseconds.
+    pub oldest_timestamp: Option<i64>,
+    pub oldest_message_id: Option<Uuid>,
+}
+
+/// One attempt to hand a message to its receiver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryAttemptRecord {
+    pub message_id: Uuid,
+    pub attempt_at: DateTime<Utc>,
+    /// `ws` for a connection on this instance, `redis` when published for other instances.
+    pub channel: String,
+    /// `delivered`, `published`, `offline` or `failed`.
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+/// A DELIVERED or READ receipt signed by the receiver of a message. Kept after the
+/// message itself is deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReceiptRecord {
+    pub message_id: Uuid,
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    pub status: MessageStatus,
+    /// The message's timestamp in Unix milliseconds, which the signature covers.
+    pub message_timestamp: i64,
+    /// Base64 Ed25519 signature, stored as the receiver sent it.
+    pub signature: String,
+    pub received_at: DateTime<Utc>,
+}
+
+/// A message whose sender and exact time are sealed in `encrypted_metadata`, which only the
+/// receiver can decrypt. The server knows its receiver through `message_routing`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedMessageRecord {
+    pub id: Uuid,
+    /// Pseudonymous id shared by a sender's sealed messages to one receiver on one UTC day.
+    pub routing_id: String,
+    /// UTC day the server received the message, the only time it keeps.
+    pub day: NaiveDate,
+    pub r#type: String,
+    pub encrypted_metadata: Vec<u8>,
+    pub encrypted_content: Vec<u8>,
+    pub iv: Vec<u8>,
+    pub encryption_version: i16,
+}
+
+#[derive(Debug, Clone)]
+pub struct PinnedMessageRecord {
+    pub message: MessageRecord,
+    pub pinned_by: Uuid,
+    pub pinned_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContactRecord {
+    pub user_id: Uuid,
+    pub username: String,
+    pub public_key: String,
+    pub added_at: DateTime<Utc>,
+}
+
+/// The other participant of one of a user's conversations.
+#[derive(Debug, Clone)]
+pub struct ConversationPeerRecord {
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+/// One participant's settings for a conversation; defaults until they change one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConversationSettings {
+    /// Keep READ messages of this conversation instead of deleting them.
+    pub keep_read_messages: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContactRequestRecord {
+    pub id: Uuid,
+    pub requester_id: Uuid,
+    pub requester_username: String,
+    pub target_id: Uuid,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    /// Note from the requester to the target, if they wrote one.
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnouncementRecord {
+    pub id: Uuid,
+    pub message: String,
+    /// One of `info`, `warning`, `critical`.
+    pub severity: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A device of a user with its own identity key.
+#[derive(Debug, Clone)]
+pub struct DeviceRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub public_key: String,
+    pub created_at: DateTime<Utc>,
+    /// When the device last opened a WebSocket connection.
+    pub last_active: Option<DateTime<Utc>>,
+    /// Cipher suites the device can decrypt.
+    pub supported_ciphers: Vec<String>,
+}
+
+/// A single-use registration invite, see `REGISTRATION_MODE`.
+#[derive(Debug, Clone)]
+pub struct InviteRecord {
+    pub id: Uuid,
+    pub code: String,
+    /// Free-form note from the admin, e.g. who the invite is for.
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Who performed an admin action, recorded in the audit log.
+#[derive(Debug, Clone)]
+pub struct AuditActor {
+    pub source_ip: String,
+    /// The `ADMIN_USERNAME` the request authenticated with; for requests made with an
+    /// impersonation token, the admin who started the session.
+    pub admin: Option<String>,
+}
+
+/// Result of `ContactRepo::create_request`.
+#[derive(Debug, Clone)]
+pub enum CreateRequestOutcome {
+    Created(ContactRequestRecord),
+    /// The requester's last declined or withdrawn request to the target was closed at this
+    /// time, within the cooldown; nothing was created.
+    CoolingDown(DateTime<Utc>),
+}
+
+/// Result of `ContactRepo::add_contact`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddContactOutcome {
+    Added,
+    /// The contact user does not exist.
+    UserNotFound,
+    /// The owner is at the contact limit.
+    LimitReached,
+}
+
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    /// Inserts a new user and returns its id. Fails with `Duplicate` if the username is taken.
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        public_key: &str,
+    ) -> RepoResult<Uuid>;
+    /// Like `create_user`, spending the invite `invite_code` in the same transaction.
+    ///
+    /// Returns `None`, creating nothing, if the code does not exist, was already used or
+    /// expired before `now`. A taken username fails with `Duplicate` and leaves the invite
+    /// unused.
+    async fn create_user_with_invite(
+        &self,
+        username: &str,
+        password_hash: &str,
+        public_key: &str,
+        invite_code: &str,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Option<Uuid>>;
+    async fn find_by_id(&self, id: Uuid) -> RepoResult<Option<UserRecord>>;
+    async fn find_by_username(&self, username: &str) -> RepoResult<Option<UserRecord>>;
+    async fn find_by_public_key(&self, public_key: &str) -> RepoResult<Option<UserRecord>>;
+    /// Users whose username is in `usernames`, looked up in a single query.
+    async fn find_by_usernames(&self, usernames: &[String]) -> RepoResult<Vec<UserRecord>>;
+    /// Users whose id is in `ids`, looked up in a single query.
+    async fn find_by_ids(&self, ids: &[Uuid]) -> RepoResult<Vec<UserRecord>>;
+    /// Replaces the public key and bumps `key_version`, returning the new version. The
+    /// change is recorded in the user's key history.
+    ///
+    /// With `expected_version` set, only applies if the stored version still matches;
+    /// returns `None` on a mismatch or if the user does not exist.
+    async fn update_public_key(
+        &self,
+        id: Uuid,
+        public_key: &str,
+        expected_version: Option<i32>,
+    ) -> RepoResult<Option<i32>>;
+    /// Ids and public keys of up to `limit` users ordered by id, starting after `after`.
+    /// Used to scan every key in pages.
+    async fn public_keys_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> RepoResult<Vec<(Uuid, String)>>;
+    /// Stores each key in its new encoding and records the change in the key history as
+    /// format-only and in the admin audit log, in one transaction. `key_version` is left
+    /// alone, since the key itself is the same. A user whose key is no longer
+    /// `old_public_key` is skipped. Returns the ids of the users that were changed.
+    async fn rewrite_key_formats(
+        &self,
+        changes: &[KeyFormatChange],
+        actor: &AuditActor,
+    ) -> RepoResult<Vec<Uuid>>;
+    /// Ids and stored avatars of up to `limit` users with an avatar, ordered by id,
+    /// starting after `after`. Used to re-encrypt every avatar in pages.
+    async fn avatars_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> RepoResult<Vec<(Uuid, Vec<u8>)>>;
+    /// Stores each avatar in its new encryption and records the change in the admin audit
+    /// log, in one transaction. `profile_updated_at` is left alone, since the image is the
+    /// same. A user whose avatar is no longer `old_avatar` is skipped. Returns the ids of
+    /// the users that were changed.
+    async fn rewrite_avatars(
+        &self,
+        rewrites: &[AvatarRewrite],
+        actor: &AuditActor,
+    ) -> RepoResult<Vec<Uuid>>;
+    /// Replaces the password hash if it is still `current_hash`, returning whether it did.
+    async fn replace_password_hash(
+        &self,
+        id: Uuid,
+        current_hash: &str,
+        new_hash: &str,
+    ) -> RepoResult<bool>;
+    /// Updates the given profile fields; `None` leaves a field unchanged.
+    /// The avatar is stored together with its validated content type.
+    ///
+    /// A new username is recorded in the user's history, unless the user already made
+    /// `username_limit.max_changes` changes since `username_limit.since`, in which case
+    /// nothing is updated. Setting the current username again is not a change.
+    async fn update_profile(
+        &self,
+        id: Uuid,
+        username: Option<&str>,
+        avatar: Option<(Vec<u8>, &str)>,
+        username_limit: UsernameChangeLimit,
+    ) -> RepoResult<ProfileUpdateOutcome>;
+    /// The user's username changes, newest first.
+    async fn username_history(&self, id: Uuid) -> RepoResult<Vec<UsernameChangeRecord>>;
+    /// Deletes a user together with their messages and reserves the username for
+    /// `grace_period_days`. Returns whether the user existed.
+    async fn delete_user(&self, id: Uuid, grace_period_days: i32) -> RepoResult<bool>;
+    /// When a reserved username becomes available again, or `None` if it is not reserved at `now`.
+    async fn username_reserved_until(
+        &self,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Option<DateTime<Utc>>>;
+    /// Drops reservations whose grace period ended before `now`, returning how many were removed.
+    async fn purge_username_reservations(&self, now: DateTime<Utc>) -> RepoResult<u64>;
+    /// The user's preferred IANA timezone name, if they set one.
+    async fn timezone(&self, id: Uuid) -> RepoResult<Option<String>>;
+    async fn set_timezone(&self, id: Uuid, timezone: &str) -> RepoResult<()>;
+    /// The user's preferences; defaults if the user does not exist.
+    async fn preferences(&self, id: Uuid) -> RepoResult<UserPreferences>;
+    /// Stores the user's preferences and bumps `profile_updated_at`. Keys stored by a newer
+    /// server are kept.
+    async fn set_preferences(&self, id: Uuid, preferences: &UserPreferences) -> RepoResult<()>;
+    /// When the user's last WebSocket connection closed, if it ever did.
+    async fn last_seen(&self, id: Uuid) -> RepoResult<Option<DateTime<Utc>>>;
+    async fn set_last_seen(&self, id: Uuid, at: DateTime<Utc>) -> RepoResult<()>;
+    /// Appends an entry about `user_id` to the admin audit log.
+    async fn record_audit(
+        &self,
+        action: &str,
+        user_id: Uuid,
+        actor: &AuditActor,
+        details: &str,
+    ) -> RepoResult<()>;
+    /// Stores an invite and records its creation in the admin audit log in the same
+    /// transaction. Fails with `Duplicate` if the code is taken.
+    async fn create_invite(&self, invite: &InviteRecord, actor: &AuditActor) -> RepoResult<()>;
+    /// Invites that are neither used nor expired at `now`, oldest first.
+    async fn unused_invites(&self, now: DateTime<Utc>) -> RepoResult<Vec<InviteRecord>>;
+    /// Whether the user may open any number of conversations a day; false if the user
+    /// does not exist.
+    async fn new_conversation_limit_exempt(&self, id: Uuid) -> RepoResult<bool>;
+    /// Sets the exemption and records the change in the admin audit log in the same
+    /// transaction. Returns whether the user exists.
+    async fn set_new_conversation_limit_exempt(
+        &self,
+        id: Uuid,
+        exempt: bool,
+        actor: &AuditActor,
+    ) -> RepoResult<bool>;
+    /// Users who registered after `since`.
+    async fn registrations_since(&self, since: DateTime<Utc>) -> RepoResult<i64>;
+}
+
+#[async_trait]
+pub trait MessageRepo: Send + Sync {
+    async fn find_message(&self, id: Uuid) -> RepoResult<Option<MessageRecord>>;
+    /// Sets the status if it is still `from`, returning whether it was set. False when the
+    /// message does not exist or its status changed since it was read.
+    async fn update_status(
+        &self,
+        id: Uuid,
+        from: MessageStatus,
+        to: MessageStatus,
+    ) -> RepoResult<bool>;
+    /// Sets the `type`, returning whether the message existed.
+    async fn set_message_type(&self, id: Uuid, message_type: &str) -> RepoResult<bool>;
+    /// Moves a message from PENDING to SENT, leaving any later status untouched.
+    async fn mark_sent_if_pending(&self, id: Uuid) -> RepoResult<()>;
+    /// Moves the listed messages addressed to `receiver_id` from SENT to DELIVERED,
+    /// returning the ids that changed.
+    async fn mark_delivered(&self, receiver_id: Uuid, ids: &[Uuid]) -> RepoResult<Vec<Uuid>>;
+    /// Stores a delivery attempt, then prunes all but the newest `keep` attempts of its message.
+    async fn record_delivery_attempt(
+        &self,
+        attempt: &DeliveryAttemptRecord,
+        keep: i64,
+    ) -> RepoResult<()>;
+    /// Delivery attempts of a message, newest first.
+    async fn delivery_attempts(&self, message_id: Uuid) -> RepoResult<Vec<DeliveryAttemptRecord>>;
+    /// Stores a signed receipt, replacing the message's previous one.
+    async fn save_delivery_receipt(&self, receipt: &DeliveryReceiptRecord) -> RepoResult<()>;
+    async fn delivery_receipt(&self, message_id: Uuid)
+    -> RepoResult<Option<DeliveryReceiptRecord>>;
+    /// Upgrades PENDING messages older than `cutoff_millis` to SENT, returning how many changed.
+    async fn sweep_pending(&self, cutoff_millis: i64) -> RepoResult<u64>;
+    /// Deletes a read message, or a delivered one whose sender has `delete_on_delivered`,
+    /// optionally sparing pinned ones. Returns whether it was deleted.
+    async fn delete_read_message(&self, id: Uuid, keep_pinned: bool) -> RepoResult<bool>;
+    /// Messages between two users matching `query`, ordered by `(timestamp, id)` in
+    /// `query.order`.
+    async fn conversation(
+        &self,
+        user_a: Uuid,
+        user_b: Uuid,
+        query: ConversationQuery,
+    ) -> RepoResult<Vec<MessageRecord>>;
+    /// Up to `limit` messages the user sent or received in any conversation, ordered by
+    /// `(timestamp, id)` and starting after `cursor`.
+    async fn messages_of_user(
+        &self,
+        user_id: Uuid,
+        cursor: Option<MessageCursor>,
+        limit: i64,
+    ) -> RepoResult<Vec<MessageRecord>>;
+    /// Inserts a message after every other message of its conversation: if its timestamp is
+    /// not past the latest one, it is stored 1ms after it instead. Concurrent inserts into
+    /// one conversation are serialized.
+    async fn insert_message_after_latest(
+        &self,
+        message: &MessageRecord,
+    ) -> RepoResult<OrderedInsert>;
+    /// Increments the forward count of a message, returning whether it still exists.
+    async fn increment_forward_count(&self, id: Uuid) -> RepoResult<bool>;
+    /// Per sender, how many messages to `receiver_id` are not yet READ, limited to
+    /// `sender_ids` when given. Senders without unread messages are omitted.
+    async fn unread_counts(
+        &self,
+        receiver_id: Uuid,
+        sender_ids: Option<&[Uuid]>,
+    ) -> RepoResult<HashMap<Uuid, i64>>;
+    /// Per `type`, how many stored messages `user_id` sent and received, ordered by type.
+    /// A note to self counts as both.
+    async fn message_type_counts(&self, user_id: Uuid) -> RepoResult<Vec<MessageTypeCount>>;
+    /// Backlog of SENT messages addressed to `user_id`.
+    async fn backlog_for(&self, user_id: Uuid) -> RepoResult<BacklogRecord>;
+    /// Receivers with the largest SENT backlogs, largest first.
+    async fn largest_backlogs(&self, limit: i64) -> RepoResult<Vec<BacklogRecord>>;
+    /// Deletes every message between two users, returning how many were removed.
+    async fn delete_conversation(&self, user_a: Uuid, user_b: Uuid) -> RepoResult<u64>;
+    /// Pins a message, returning whether it was newly pinned.
+    async fn pin(&self, message_id: Uuid, pinned_by: Uuid) -> RepoResult<bool>;
+    /// Unpins a message, returning whether it was pinned.
+    async fn unpin(&self, message_id: Uuid) -> RepoResult<bool>;
+    async fn pinned_in_conversation(
+        &self,
+        user_a: Uuid,
+        user_b: Uuid,
+    ) -> RepoResult<Vec<PinnedMessageRecord>>;
+    /// Counts a send in every window unless that would take one of them past its limit, in
+    /// which case nothing is counted. Counters of windows older than all of `windows` are pruned.
+    async fn record_send(
+        &self,
+        user_id: Uuid,
+        windows: &[SendQuotaWindow],
+    ) -> RepoResult<SendQuotaOutcome>;
+    /// Takes back a send `record_send` counted in `windows`, for a message that was not stored.
+    async fn refund_send(&self, user_id: Uuid, windows: &[SendQuotaWindow]) -> RepoResult<()>;
+    /// Sends counted so far in each of `windows`, in the same order.
+    async fn send_counts(&self, user_id: Uuid, windows: &[SendQuotaWindow])
+    -> RepoResult<Vec<i64>>;
+    /// Sends of all users counted in the `window` counters (e.g. `minute`) starting at or
+    /// after `since`.
+    async fn sends_since(&self, window: &str, since: DateTime<Utc>) -> RepoResult<i64>;
+    /// The users with the most sends counted in the `window` counter starting at `start`,
+    /// most first.
+    async fn top_senders(
+        &self,
+        window: &str,
+        start: DateTime<Utc>,
+        limit: i64,
+    ) -> RepoResult<Vec<SenderActivity>>;
+    /// Stores a sealed message and routes its `routing_id` to `receiver_id`. Returns false,
+    /// storing nothing, if a sealed message with the same id exists.
+    async fn insert_sealed_message(
+        &self,
+        message: &SealedMessageRecord,
+        receiver_id: Uuid,
+    ) -> RepoResult<bool>;
+    /// Sealed messages routed to `receiver_id`, oldest day first.
+    async fn sealed_messages_for(&self, receiver_id: Uuid) -> RepoResult<Vec<SealedMessageRecord>>;
+    /// Deletes a sealed message routed to `receiver_id`, and its routing once no message uses
+    /// it. Returns whether the message was deleted.
+    async fn delete_sealed_message(&self, id: Uuid, receiver_id: Uuid) -> RepoResult<bool>;
+}
+
+#[async_trait]
+pub trait ContactRepo: Send + Sync {
+    /// Users `user_id` accepts messages from: their contacts plus everyone they have messaged.
+    async fn accepted_peers(&self, user_id: Uuid) -> RepoResult<HashSet<Uuid>>;
+    /// Records that `user_id` messaged `peer_id`. `opened_at` marks the message that opened
+    /// the conversation; a row that already exists is left unchanged.
+    async fn record_conversation_peer(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+        opened_at: Option<DateTime<Utc>>,
+    ) -> RepoResult<()>;
+    /// Whether `user_id` and `peer_id` have messaged each other before, in either direction.
+    async fn has_conversation(&self, user_id: Uuid, peer_id: Uuid) -> RepoResult<bool>;
+    /// How many conversations `user_id` opened after `since`, and when the oldest of
+    /// them was opened.
+    async fn conversations_opened_since(
+        &self,
+        user_id: Uuid,
+        since: DateTime<Utc>,
+    ) -> RepoResult<(i64, Option<DateTime<Utc>>)>;
+    /// `user_id`'s settings for their conversation with `peer_id`.
+    async fn conversation_settings(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+    ) -> RepoResult<ConversationSettings>;
+    async fn set_conversation_settings(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+        settings: &ConversationSettings,
+    ) -> RepoResult<()>;
+    /// Peers `user_id` has a conversation with, in either direction, whose username contains
+    /// `query` case-insensitively. Ordered by username; returns the page and the total match count.
+    async fn search_conversation_peers(
+        &self,
+        user_id: Uuid,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> RepoResult<(Vec<ConversationPeerRecord>, i64)>;
+    async fn list_contacts(&self, owner_id: Uuid) -> RepoResult<Vec<ContactRecord>>;
+    /// Adds a contact unless the owner already has `max_contacts` of them. Re-adding an
+    /// existing contact always succeeds.
+    async fn add_contact(
+        &self,
+        owner_id: Uuid,
+        contact_id: Uuid,
+        max_contacts: i64,
+    ) -> RepoResult<AddContactOutcome>;
+    /// Removes a contact, returning whether it existed.
+    async fn remove_contact(&self, owner_id: Uuid, contact_id: Uuid) -> RepoResult<bool>;
+    async fn is_contact(&self, owner_id: Uuid, contact_id: Uuid) -> RepoResult<bool>;
+    /// Creates a pending request, unless a request from `requester_id` to `target_id` was
+    /// declined or withdrawn after `closed_after`. The cooldown is checked as part of the
+    /// insert, so a request closed while this one is being sent still counts. Fails with
+    /// `Duplicate` if one is already pending for the pair.
+    async fn create_request(
+        &self,
+        requester_id: Uuid,
+        target_id: Uuid,
+        message: Option<&str>,
+        closed_after: DateTime<Utc>,
+    ) -> RepoResult<CreateRequestOutcome>;
+    async fn find_request(&self, id: Uuid) -> RepoResult<Option<ContactRequestRecord>>;
+    /// Pending requests where `user_id` is the requester or the target.
+    async fn list_pending_requests(&self, user_id: Uuid) -> RepoResult<Vec<ContactRequestRecord>>;
+    /// Moves a pending request to `status`, adding reciprocal contacts when `accept` is set.
+    ///
+    /// Runs atomically and returns `None` if the request was no longer pending.
+    async fn resolve_request(
+        &self,
+        id: Uuid,
+        status: &str,
+        accept: bool,
+    ) -> RepoResult<Option<ContactRequestRecord>>;
+}
+
+/// Operator announcements. Every change is written to the admin audit log in the same
+/// transaction.
+#[async_trait]
+pub trait AnnouncementRepo: Send + Sync {
+    async fn create_announcement(
+        &self,
+        announcement: &AnnouncementRecord,
+        actor: &AuditActor,
+    ) -> RepoResult<()>;
+    /// Announcements that have not expired at `now`, oldest first.
+    async fn active_announcements(&self, now: DateTime<Utc>)
+    -> RepoResult<Vec<AnnouncementRecord>>;
+    /// Deletes an announcement, returning whether it existed.
+    async fn delete_announcement(&self, id: Uuid, actor: &AuditActor) -> RepoResult<bool>;
+}
+
+/// Per-device identity keys. Registering or revoking a device bumps the owner's
+/// `profile_updated_at`, since the device list is part of their profile.
+#[async_trait]
+pub trait DeviceRepo: Send + Sync {
+    /// Registers a device. Fails with `Duplicate` if the user already has a device with this key.
+    async fn register_device(&self, device: &DeviceRecord) -> RepoResult<()>;
+    /// The user's devices, oldest first.
+    async fn list_devices(&self, user_id: Uuid) -> RepoResult<Vec<DeviceRecord>>;
+    async fn find_device(&self, id: Uuid) -> RepoResult<Option<DeviceRecord>>;
+    /// Deletes one of the user's devices, returning whether it existed.
+    async fn revoke_device(&self, user_id: Uuid, id: Uuid) -> RepoResult<bool>;
+    /// Records that the device is active at `now`.
+    async fn touch_device(&self, id: Uuid, now: DateTime<Utc>) -> RepoResult<()>;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{MessageCursor, SortOrder};
+    use std::future::Future;
+    use uuid::Uuid;
+
+    /// Timestamps for the cursor tests, whose shared values straddle the page seams. Yields
+    /// `(sender, receiver, timestamp)` with the direction alternating between `a` and `b`.
+    pub(crate) fn seam_messages(a: Uuid, b: Uuid) -> impl Iterator<Item = (Uuid, Uuid, i64)> {
+        [100, 100, 100, 200, 200, 200, 300, 300]
+            .into_iter()
+            .enumerate()
+            .map(move |(i, timestamp)| {
+                let (sender, receiver) = if i % 2 == 0 { (a, b) } else { (b, a) };
+                (sender, receiver, timestamp)
+            })
+    }
+
+    /// Pages through a conversation in both orders with `next_page`, which returns a page's
+    /// ids and the cursor of the next page, if any. Checks that the pages together hold
+    /// `all` (oldest first) exactly once each, and returns the ascending and descending pages.
+    pub(crate) async fn assert_pages_cover_conversation<F, Fut>(
+        all: &[Uuid],
+        mut next_page: F,
+    ) -> (Vec<Vec<Uuid>>, Vec<Vec<Uuid>>)
+    where
+        F: FnMut(SortOrder, Option<MessageCursor>) -> Fut,
+        Fut: Future<Output = (Vec<Uuid>, Option<MessageCursor>)>,
+    {
+        let mut walk = async |order| {
+            let mut pages = Vec::new();
+            let mut cursor = None;
+            loop {
+                let (page, next) = next_page(order, cursor).await;
+                if !page.is_empty() {
+                    pages.push(page);
+                }
+                cursor = match next {
+                    Some(next) => Some(next),
+                    None => break,
+                };
+            }
+            pages
+        };
+        let asc = walk(SortOrder::Asc).await;
+        let desc = walk(SortOrder::Desc).await;
+        assert_eq!(asc.concat(), all);
+        let newest_first: Vec<Uuid> = all.iter().rev().copied().collect();
+        assert_eq!(desc.concat(), newest_first);
+        (asc, desc)
+    }
+}
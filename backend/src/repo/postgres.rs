@@ -0,0 +1,3323 @@
+//! PostgreSQL implementations of the repository traits.
+
+use super::{
+    AddContactOutcome, AnnouncementRecord, AnnouncementRepo, AuditActor, AvatarRewrite,
+    BacklogRecord, ContactRecord, ContactRepo, ContactRequestRecord, ConversationPeerRecord,
+    ConversationQuery, ConversationSettings, CreateRequestOutcome, DeliveryAttemptRecord,
+    DeliveryReceiptRecord, DeviceRecord, DeviceRepo, InviteRecord, KeyFormatChange, MessageCursor,
+    MessageRecord, MessageRepo, MessageStatus, MessageTypeCount, OrderedInsert,
+    PinnedMessageRecord, ProfileUpdateOutcome, RecordOutcome, RepoResult, SealedMessageRecord,
+    SendQuotaOutcome, SendQuotaWindow, SenderActivity, SortOrder, UserPreferences, UserRecord,
+    UserRepo, UsernameChangeLimit, UsernameChangeRecord,
+};
+use crate::db::{IsolationLevel, WithIsolation, retry_serializable};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::PgRow;
+use sqlx::{Decode, PgExecutor, PgPool, Postgres, Row, Type};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::error;
+use uuid::Uuid;
+
+const USER_COLUMNS: &str = "id, username, password_hash, public_key, created_at, avatar, avatar_content_type, key_version, profile_updated_at";
+const MESSAGE_COLUMNS: &str = "m.id, m.timestamp, m.sender_id, m.receiver_id, m.status, m.type, m.encrypted_content, m.iv, m.forwarded_from_id, m.forward_count, m.reply_to_id, \
+    (m.reply_to_id IS NOT NULL AND NOT EXISTS (SELECT 1 FROM messages r WHERE r.id = m.reply_to_id)) AS reply_to_deleted, \
+    m.encryption_version, m.device_id, m.cipher_suite";
+const DEVICE_COLUMNS: &str =
+    "id, user_id, name, public_key, created_at, last_active, supported_ciphers";
+const CONTACT_REQUEST_COLUMNS: &str = "r.id, r.requester_id, u.username AS requester_username, r.target_id, r.status, r.created_at, r.message";
+
+/// Columns that could not be decoded since this process started, across all row mappings.
+/// Reported by `GET /admin/metrics`.
+pub static ROW_DECODE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads a column that may be NULL.
+///
+/// NULL is `Ok(None)`. A column that is missing or does not decode as `T` (e.g. after a
+/// migration changed its type) is returned as an error instead of being treated like
+/// NULL; it is logged with `record_id` and counted in `ROW_DECODE_ERRORS`.
+pub(crate) fn nullable<'r, T>(
+    row: &'r PgRow,
+    column: &str,
+    record_id: Uuid,
+) -> Result<Option<T>, sqlx::Error>
+where
+    T: Decode<'r, Postgres> + Type<Postgres>,
+{
+    row.try_get::<Option<T>, _>(column).inspect_err(|e| {
+        ROW_DECODE_ERRORS.fetch_add(1, Ordering::Relaxed);
+        error!(
+            "row_decode_error: column {} of record {}: {}",
+            column, record_id, e
+        );
+    })
+}
+
+fn user_from_row(row: &PgRow) -> RepoResult<UserRecord> {
+    let id = row.try_get("id")?;
+    Ok(UserRecord {
+        id,
+        username: row.try_get("username")?,
+        password_hash: row.try_get("password_hash")?,
+        public_key: row.try_get("public_key")?,
+        created_at: row
+            .try_get::<Option<DateTime<Utc>>, _>("created_at")?
+            .unwrap_or_else(Utc::now),
+        avatar: nullable(row, "avatar", id)?,
+        avatar_content_type: nullable(row, "avatar_content_type", id)?,
+        key_version: nullable(row, "key_version", id)?.unwrap_or_default(),
+        profile_updated_at: nullable(row, "profile_updated_at", id)?.unwrap_or_else(Utc::now),
+    })
+}
+
+/// Builds the conversation history query for participants `$1` and `$2`. The parameters
+/// `query` uses are bound in this order: `after`, `before`, the cursor's timestamp and id,
+/// then `limit`.
+///
+/// Each direction of the conversation is a separate branch, so both can use
+/// `messages_sender_receiver_timestamp_idx` and be merged in timestamp order instead
+/// of filtering the whole table with an `OR`. With a limit, each branch stops after that
+/// many rows before the merge.
+fn conversation_query(query: &ConversationQuery) -> String {
+    let mut range = String::new();
+    let mut next_param = 3;
+    if query.after.is_some() {
+        range.push_str(&format!(" AND m.timestamp >= ${}", next_param));
+        next_param += 1;
+    }
+    if query.before.is_some() {
+        range.push_str(&format!(" AND m.timestamp <= ${}", next_param));
+        next_param += 1;
+    }
+    let (direction, past) = match query.order {
+        SortOrder::Asc => ("ASC", ">"),
+        SortOrder::Desc => ("DESC", "<"),
+    };
+    if query.cursor.is_some() {
+        range.push_str(&format!(
+            " AND (m.timestamp, m.id) {} (${}, ${})",
+            past,
+            next_param,
+            next_param + 1
+        ));
+        next_param += 2;
+    }
+    let order_by = format!("ORDER BY m.timestamp {0}, m.id {0}", direction);
+    let (branch_limit, limit) = match query.limit {
+        Some(_) => (
+            format!(" {} LIMIT ${}", order_by, next_param),
+            format!(" LIMIT ${}", next_param),
+        ),
+        None => (String::new(), String::new()),
+    };
+    format!(
+        "SELECT * FROM (\
+            (SELECT {columns} FROM messages m WHERE m.sender_id = $1 AND m.receiver_id = $2{range}{branch_limit}) \
+            UNION ALL \
+            (SELECT {columns} FROM messages m WHERE m.sender_id = $2 AND m.receiver_id = $1{range}{branch_limit})\
+        ) m {order_by}{limit}",
+        columns = MESSAGE_COLUMNS,
+        range = range,
+        branch_limit = branch_limit,
+        order_by = order_by,
+        limit = limit
+    )
+}
+
+async fn insert_message_row<'e, E: PgExecutor<'e>>(
+    executor: E,
+    message: &MessageRecord,
+    timestamp: i64,
+) -> RepoResult<()> {
+    sqlx::query(
+        "INSERT INTO messages (id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv, forwarded_from_id, reply_to_id, encryption_version, device_id, cipher_suite) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)"
+    )
+    .bind(message.id)
+    .bind(timestamp)
+    .bind(message.sender_id)
+    .bind(message.receiver_id)
+    .bind(message.status)
+    .bind(&message.r#type)
+    .bind(&message.encrypted_content)
+    .bind(&message.iv)
+    .bind(message.forwarded_from_id)
+    .bind(message.reply_to_id)
+    .bind(message.encryption_version)
+    .bind(message.device_id)
+    .bind(&message.cipher_suite)
+    .execute(executor)
+    .await
+    .recorded()?;
+    Ok(())
+}
+
+/// One attempt of `insert_sealed_message`.
+async fn insert_sealed_once(
+    db: &PgPool,
+    message: &SealedMessageRecord,
+    receiver_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db
+        .begin_with_isolation(IsolationLevel::Serializable)
+        .await
+        .recorded()?;
+    // The no-op update locks an existing routing so a concurrent delete cannot drop it
+    sqlx::query(
+        "INSERT INTO message_routing (routing_id, actual_receiver_id) VALUES ($1, $2) \
+         ON CONFLICT (routing_id) DO UPDATE SET actual_receiver_id = EXCLUDED.actual_receiver_id",
+    )
+    .bind(&message.routing_id)
+    .bind(receiver_id)
+    .execute(&mut *tx)
+    .await
+    .recorded()?;
+    let inserted = sqlx::query(
+        "INSERT INTO sealed_messages \
+         (id, routing_id, day, type, encrypted_metadata, encrypted_content, iv, encryption_version) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (id) DO NOTHING",
+    )
+    .bind(message.id)
+    .bind(&message.routing_id)
+    .bind(message.day)
+    .bind(&message.r#type)
+    .bind(&message.encrypted_metadata)
+    .bind(&message.encrypted_content)
+    .bind(&message.iv)
+    .bind(message.encryption_version)
+    .execute(&mut *tx)
+    .await
+    .recorded()?
+    .rows_affected()
+        > 0;
+    tx.commit().await.recorded()?;
+    Ok(inserted)
+}
+
+fn message_from_row(row: &PgRow) -> RepoResult<MessageRecord> {
+    let id = row.try_get("id")?;
+    Ok(MessageRecord {
+        id,
+        timestamp: row.try_get("timestamp")?,
+        sender_id: row.try_get("sender_id")?,
+        receiver_id: row.try_get("receiver_id")?,
+        status: nullable(row, "status", id)?.unwrap_or_default(),
+        r#type: nullable(row, "type", id)?.unwrap_or_default(),
+        encrypted_content: nullable(row, "encrypted_content", id)?.unwrap_or_default(),
+        iv: nullable(row, "iv", id)?.unwrap_or_default(),
+        forwarded_from_id: row.try_get("forwarded_from_id")?,
+        forward_count: nullable(row, "forward_count", id)?.unwrap_or_default(),
+        reply_to_id: row.try_get("reply_to_id")?,
+        reply_to_deleted: row.try_get("reply_to_deleted")?,
+        encryption_version: row.try_get("encryption_version")?,
+        device_id: row.try_get("device_id")?,
+        cipher_suite: row.try_get("cipher_suite")?,
+    })
+}
+
+fn contact_request_from_row(row: &PgRow) -> RepoResult<ContactRequestRecord> {
+    let id = row.try_get("id")?;
+    Ok(ContactRequestRecord {
+        id,
+        requester_id: row.try_get("requester_id")?,
+        requester_username: nullable(row, "requester_username", id)?.unwrap_or_default(),
+        target_id: row.try_get("target_id")?,
+        status: nullable(row, "status", id)?.unwrap_or_default(),
+        created_at: row
+            .try_get::<Option<DateTime<Utc>>, _>("created_at")?
+            .unwrap_or_else(Utc::now),
+        message: row.try_get("message")?,
+    })
+}
+
+pub struct PgUserRepo {
+    db: PgPool,
+}
+
+impl PgUserRepo {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl UserRepo for PgUserRepo {
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        public_key: &str,
+    ) -> RepoResult<Uuid> {
+        let row = sqlx::query(
+            "INSERT INTO users (username, password_hash, public_key) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(username)
+        .bind(password_hash)
+        .bind(public_key)
+        .fetch_one(&self.db)
+        .await
+        .recorded()?;
+        Ok(row.try_get("id")?)
+    }
+
+    async fn create_user_with_invite(
+        &self,
+        username: &str,
+        password_hash: &str,
+        public_key: &str,
+        invite_code: &str,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Option<Uuid>> {
+        let mut tx = self.db.begin().await.recorded()?;
+        // The row lock taken here makes a concurrent registration with the same code wait
+        // for this transaction and then find the invite used.
+        let invite = sqlx::query(
+            "UPDATE invites SET used_at = $2 WHERE code = $1 AND used_at IS NULL AND expires_at > $2 RETURNING id",
+        )
+        .bind(invite_code)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await
+        .recorded()?;
+        let Some(invite) = invite else {
+            return Ok(None);
+        };
+        let invite_id: Uuid = invite.try_get("id")?;
+        let row = sqlx::query(
+            "INSERT INTO users (username, password_hash, public_key) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(username)
+        .bind(password_hash)
+        .bind(public_key)
+        .fetch_one(&mut *tx)
+        .await
+        .recorded()?;
+        let id: Uuid = row.try_get("id")?;
+        sqlx::query("UPDATE invites SET used_by = $1 WHERE id = $2")
+            .bind(id)
+            .bind(invite_id)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(Some(id))
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> RepoResult<Option<UserRecord>> {
+        let query = format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS);
+        let row = sqlx::query(&query)
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        row.as_ref().map(user_from_row).transpose()
+    }
+
+    async fn find_by_username(&self, username: &str) -> RepoResult<Option<UserRecord>> {
+        let query = format!("SELECT {} FROM users WHERE username = $1", USER_COLUMNS);
+        let row = sqlx::query(&query)
+            .bind(username)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        row.as_ref().map(user_from_row).transpose()
+    }
+
+    async fn find_by_public_key(&self, public_key: &str) -> RepoResult<Option<UserRecord>> {
+        let query = format!("SELECT {} FROM users WHERE public_key = $1", USER_COLUMNS);
+        let row = sqlx::query(&query)
+            .bind(public_key)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        row.as_ref().map(user_from_row).transpose()
+    }
+
+    async fn find_by_usernames(&self, usernames: &[String]) -> RepoResult<Vec<UserRecord>> {
+        let query = format!(
+            "SELECT {} FROM users WHERE username = ANY($1)",
+            USER_COLUMNS
+        );
+        let rows = sqlx::query(&query)
+            .bind(usernames)
+            .fetch_all(&self.db)
+            .await
+            .recorded()?;
+        rows.iter().map(user_from_row).collect()
+    }
+
+    async fn find_by_ids(&self, ids: &[Uuid]) -> RepoResult<Vec<UserRecord>> {
+        let query = format!("SELECT {} FROM users WHERE id = ANY($1)", USER_COLUMNS);
+        let rows = sqlx::query(&query)
+            .bind(ids)
+            .fetch_all(&self.db)
+            .await
+            .recorded()?;
+        rows.iter().map(user_from_row).collect()
+    }
+
+    async fn update_public_key(
+        &self,
+        id: Uuid,
+        public_key: &str,
+        expected_version: Option<i32>,
+    ) -> RepoResult<Option<i32>> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let Some(old_key) = sqlx::query_scalar::<_, String>(
+            "SELECT public_key FROM users WHERE id = $1 FOR UPDATE",
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .recorded()?
+        else {
+            return Ok(None);
+        };
+        let row = match expected_version {
+            Some(expected) => {
+                sqlx::query(
+                    "UPDATE users SET public_key = $1, key_version = key_version + 1, profile_updated_at = NOW() WHERE id = $2 AND key_version = $3 RETURNING key_version",
+                )
+                .bind(public_key)
+                .bind(id)
+                .bind(expected)
+                .fetch_optional(&mut *tx)
+                .await
+                .recorded()?
+            }
+            None => {
+                sqlx::query(
+                    "UPDATE users SET public_key = $1, key_version = key_version + 1, profile_updated_at = NOW() WHERE id = $2 RETURNING key_version",
+                )
+                .bind(public_key)
+                .bind(id)
+                .fetch_optional(&mut *tx)
+                .await
+                .recorded()?
+            }
+        };
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        sqlx::query(
+            "INSERT INTO key_changes (user_id, old_public_key, new_public_key) VALUES ($1, $2, $3)",
+        )
+        .bind(id)
+        .bind(old_key)
+        .bind(public_key)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(Some(nullable(&row, "key_version", id)?.unwrap_or_default()))
+    }
+
+    async fn public_keys_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> RepoResult<Vec<(Uuid, String)>> {
+        let rows = sqlx::query(
+            "SELECT id, public_key FROM users WHERE $1::uuid IS NULL OR id > $1 ORDER BY id LIMIT $2",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| Ok((row.try_get("id")?, row.try_get("public_key")?)))
+            .collect()
+    }
+
+    async fn rewrite_key_formats(
+        &self,
+        changes: &[KeyFormatChange],
+        actor: &AuditActor,
+    ) -> RepoResult<Vec<Uuid>> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let mut changed = Vec::new();
+        for change in changes {
+            // Compared with the old key so a key replaced meanwhile is not overwritten
+            let updated = sqlx::query(
+                "UPDATE users SET public_key = $1, profile_updated_at = NOW() WHERE id = $2 AND public_key = $3",
+            )
+            .bind(&change.new_public_key)
+            .bind(change.user_id)
+            .bind(&change.old_public_key)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+            if updated.rows_affected() == 0 {
+                continue;
+            }
+            sqlx::query(
+                "INSERT INTO key_changes (user_id, old_public_key, new_public_key, format_only) VALUES ($1, $2, $3, TRUE)",
+            )
+            .bind(change.user_id)
+            .bind(&change.old_public_key)
+            .bind(&change.new_public_key)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+            sqlx::query(
+                "INSERT INTO admin_audit_log (action, target_id, source_ip, details, admin_username) VALUES ('key.normalize', $1, $2, 'raw key stored as X.509', $3)",
+            )
+            .bind(change.user_id)
+            .bind(&actor.source_ip)
+            .bind(&actor.admin)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+            changed.push(change.user_id);
+        }
+        tx.commit().await.recorded()?;
+        Ok(changed)
+    }
+
+    async fn avatars_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> RepoResult<Vec<(Uuid, Vec<u8>)>> {
+        let rows = sqlx::query(
+            "SELECT id, avatar FROM users WHERE avatar IS NOT NULL AND ($1::uuid IS NULL OR id > $1) ORDER BY id LIMIT $2",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| Ok((row.try_get("id")?, row.try_get("avatar")?)))
+            .collect()
+    }
+
+    async fn rewrite_avatars(
+        &self,
+        rewrites: &[AvatarRewrite],
+        actor: &AuditActor,
+    ) -> RepoResult<Vec<Uuid>> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let mut changed = Vec::new();
+        for rewrite in rewrites {
+            // Compared with the old value so an avatar uploaded meanwhile is not overwritten
+            let updated = sqlx::query("UPDATE users SET avatar = $1 WHERE id = $2 AND avatar = $3")
+                .bind(&rewrite.new_avatar)
+                .bind(rewrite.user_id)
+                .bind(&rewrite.old_avatar)
+                .execute(&mut *tx)
+                .await
+                .recorded()?;
+            if updated.rows_affected() == 0 {
+                continue;
+            }
+            sqlx::query(
+                "INSERT INTO admin_audit_log (action, target_id, source_ip, details, admin_username) VALUES ('column_key.rotate', $1, $2, 'avatar re-encrypted', $3)",
+            )
+            .bind(rewrite.user_id)
+            .bind(&actor.source_ip)
+            .bind(&actor.admin)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+            changed.push(rewrite.user_id);
+        }
+        tx.commit().await.recorded()?;
+        Ok(changed)
+    }
+
+    async fn replace_password_hash(
+        &self,
+        id: Uuid,
+        current_hash: &str,
+        new_hash: &str,
+    ) -> RepoResult<bool> {
+        let result =
+            sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2 AND password_hash = $3")
+                .bind(new_hash)
+                .bind(id)
+                .bind(current_hash)
+                .execute(&self.db)
+                .await
+                .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn update_profile(
+        &self,
+        id: Uuid,
+        username: Option<&str>,
+        avatar: Option<(Vec<u8>, &str)>,
+        username_limit: UsernameChangeLimit,
+    ) -> RepoResult<ProfileUpdateOutcome> {
+        let mut tx = self.db.begin().await.recorded()?;
+        // Locking the user serializes concurrent renames, so both cannot pass the limit check
+        let mut renamed_from = None;
+        if let Some(username) = username
+            && let Some(row) = sqlx::query("SELECT username FROM users WHERE id = $1 FOR UPDATE")
+                .bind(id)
+                .fetch_optional(&mut *tx)
+                .await
+                .recorded()?
+        {
+            let current: String = row.try_get("username")?;
+            if current != username {
+                let recent = sqlx::query(
+                    "SELECT changed_at FROM username_changes WHERE user_id = $1 AND changed_at > $2 ORDER BY changed_at DESC LIMIT $3",
+                )
+                .bind(id)
+                .bind(username_limit.since)
+                .bind(username_limit.max_changes)
+                .fetch_all(&mut *tx)
+                .await
+                .recorded()?;
+                if recent.len() as i64 >= username_limit.max_changes
+                    && let Some(oldest) = recent.last()
+                {
+                    return Ok(ProfileUpdateOutcome::UsernameChangeLimited(
+                        oldest.try_get("changed_at")?,
+                    ));
+                }
+                renamed_from = Some(current);
+            }
+        }
+        let mut set_clauses: Vec<String> = Vec::new();
+        if username.is_some() {
+            set_clauses.push("username = $1".to_string());
+        }
+        if avatar.is_some() {
+            set_clauses.push(format!("avatar = ${}", set_clauses.len() + 1));
+            set_clauses.push(format!("avatar_content_type = ${}", set_clauses.len() + 1));
+        }
+        if set_clauses.is_empty() {
+            return Ok(ProfileUpdateOutcome::Updated);
+        }
+        let query = format!(
+            "UPDATE users SET {}, profile_updated_at = NOW() WHERE id = ${}",
+            set_clauses.join(", "),
+            set_clauses.len() + 1
+        );
+        let mut sql_query = sqlx::query(&query);
+        if let Some(username) = username {
+            sql_query = sql_query.bind(username);
+        }
+        if let Some((avatar, content_type)) = avatar {
+            sql_query = sql_query.bind(avatar).bind(content_type);
+        }
+        sql_query.bind(id).execute(&mut *tx).await.recorded()?;
+        if let (Some(old_username), Some(new_username)) = (renamed_from, username) {
+            sqlx::query(
+                "INSERT INTO username_changes (user_id, old_username, new_username) VALUES ($1, $2, $3)",
+            )
+            .bind(id)
+            .bind(old_username)
+            .bind(new_username)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+        }
+        tx.commit().await.recorded()?;
+        Ok(ProfileUpdateOutcome::Updated)
+    }
+
+    async fn username_history(&self, id: Uuid) -> RepoResult<Vec<UsernameChangeRecord>> {
+        let rows = sqlx::query(
+            "SELECT old_username, new_username, changed_at FROM username_changes WHERE user_id = $1 ORDER BY changed_at DESC, id DESC",
+        )
+        .bind(id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(UsernameChangeRecord {
+                    old_username: row.try_get("old_username")?,
+                    new_username: row.try_get("new_username")?,
+                    changed_at: row.try_get("changed_at")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_user(&self, id: Uuid, grace_period_days: i32) -> RepoResult<bool> {
+        let mut tx = self.db.begin().await.recorded()?;
+        // Messages reference users without cascading, so they go first
+        sqlx::query("DELETE FROM messages WHERE sender_id = $1 OR receiver_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+        let Some(row) = sqlx::query("DELETE FROM users WHERE id = $1 RETURNING username")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await
+            .recorded()?
+        else {
+            return Ok(false);
+        };
+        let username: String = row.try_get("username")?;
+        sqlx::query(
+            "INSERT INTO deleted_usernames (username, deleted_at, grace_period_days) VALUES ($1, NOW(), $2) \
+             ON CONFLICT (username) DO UPDATE SET deleted_at = EXCLUDED.deleted_at, grace_period_days = EXCLUDED.grace_period_days",
+        )
+        .bind(&username)
+        .bind(grace_period_days)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(true)
+    }
+
+    async fn username_reserved_until(
+        &self,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Option<DateTime<Utc>>> {
+        let row = sqlx::query(
+            "SELECT deleted_at + make_interval(days => grace_period_days) AS available_after \
+             FROM deleted_usernames \
+             WHERE username = $1 AND deleted_at + make_interval(days => grace_period_days) > $2",
+        )
+        .bind(username)
+        .bind(now)
+        .fetch_optional(&self.db)
+        .await
+        .recorded()?;
+        row.map(|r| r.try_get("available_after"))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn purge_username_reservations(&self, now: DateTime<Utc>) -> RepoResult<u64> {
+        let result = sqlx::query(
+            "DELETE FROM deleted_usernames WHERE deleted_at + make_interval(days => grace_period_days) <= $1",
+        )
+        .bind(now)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(result.rows_affected())
+    }
+
+    async fn timezone(&self, id: Uuid) -> RepoResult<Option<String>> {
+        let row = sqlx::query("SELECT timezone FROM notification_prefs WHERE user_id = $1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        row.map(|r| r.try_get("timezone"))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn set_timezone(&self, id: Uuid, timezone: &str) -> RepoResult<()> {
+        sqlx::query(
+            "INSERT INTO notification_prefs (user_id, timezone) VALUES ($1, $2) \
+             ON CONFLICT (user_id) DO UPDATE SET timezone = EXCLUDED.timezone, updated_at = NOW()",
+        )
+        .bind(id)
+        .bind(timezone)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(())
+    }
+
+    async fn preferences(&self, id: Uuid) -> RepoResult<UserPreferences> {
+        let row = sqlx::query(
+            "SELECT user_preferences::text AS user_preferences FROM users WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await
+        .recorded()?;
+        let Some(row) = row else {
+            return Ok(UserPreferences::default());
+        };
+        let json: String = row.try_get("user_preferences")?;
+        serde_json::from_str(&json).map_err(|e| {
+            super::RepoError::Database(format!("invalid user_preferences of user {}: {}", id, e))
+        })
+    }
+
+    async fn set_preferences(&self, id: Uuid, preferences: &UserPreferences) -> RepoResult<()> {
+        let json = serde_json::to_string(preferences)
+            .map_err(|e| super::RepoError::Database(e.to_string()))?;
+        sqlx::query(
+            "UPDATE users SET user_preferences = user_preferences || $2::jsonb, profile_updated_at = NOW() WHERE id = $1",
+        )
+        .bind(id)
+        .bind(json)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(())
+    }
+
+    async fn last_seen(&self, id: Uuid) -> RepoResult<Option<DateTime<Utc>>> {
+        let row = sqlx::query("SELECT last_seen FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        match row {
+            Some(row) => Ok(row.try_get("last_seen")?),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_last_seen(&self, id: Uuid, at: DateTime<Utc>) -> RepoResult<()> {
+        sqlx::query("UPDATE users SET last_seen = $2 WHERE id = $1")
+            .bind(id)
+            .bind(at)
+            .execute(&self.db)
+            .await
+            .recorded()?;
+        Ok(())
+    }
+
+    async fn record_audit(
+        &self,
+        action: &str,
+        user_id: Uuid,
+        actor: &AuditActor,
+        details: &str,
+    ) -> RepoResult<()> {
+        sqlx::query(
+            "INSERT INTO admin_audit_log (action, target_id, source_ip, details, admin_username) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(action)
+        .bind(user_id)
+        .bind(&actor.source_ip)
+        .bind(details)
+        .bind(&actor.admin)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(())
+    }
+
+    async fn create_invite(&self, invite: &InviteRecord, actor: &AuditActor) -> RepoResult<()> {
+        let mut tx = self.db.begin().await.recorded()?;
+        sqlx::query(
+            "INSERT INTO invites (id, code, note, created_at, expires_at) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(invite.id)
+        .bind(&invite.code)
+        .bind(&invite.note)
+        .bind(invite.created_at)
+        .bind(invite.expires_at)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        sqlx::query(
+            "INSERT INTO admin_audit_log (action, target_id, source_ip, details, admin_username) VALUES ('invite.create', $1, $2, $3, $4)",
+        )
+        .bind(invite.id)
+        .bind(&actor.source_ip)
+        .bind(&invite.note)
+        .bind(&actor.admin)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(())
+    }
+
+    async fn unused_invites(&self, now: DateTime<Utc>) -> RepoResult<Vec<InviteRecord>> {
+        let rows = sqlx::query(
+            "SELECT id, code, note, created_at, expires_at FROM invites WHERE used_at IS NULL AND expires_at > $1 ORDER BY created_at ASC",
+        )
+        .bind(now)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter().map(invite_from_row).collect()
+    }
+
+    async fn new_conversation_limit_exempt(&self, id: Uuid) -> RepoResult<bool> {
+        let row = sqlx::query("SELECT new_conversation_limit_exempt FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        match row {
+            Some(row) => Ok(row.try_get("new_conversation_limit_exempt")?),
+            None => Ok(false),
+        }
+    }
+
+    async fn set_new_conversation_limit_exempt(
+        &self,
+        id: Uuid,
+        exempt: bool,
+        actor: &AuditActor,
+    ) -> RepoResult<bool> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let result =
+            sqlx::query("UPDATE users SET new_conversation_limit_exempt = $2 WHERE id = $1")
+                .bind(id)
+                .bind(exempt)
+                .execute(&mut *tx)
+                .await
+                .recorded()?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            "INSERT INTO admin_audit_log (action, target_id, source_ip, details, admin_username) VALUES ('new_conversation_limit.exempt', $1, $2, $3, $4)",
+        )
+        .bind(id)
+        .bind(&actor.source_ip)
+        .bind(format!("exempt={}", exempt))
+        .bind(&actor.admin)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(true)
+    }
+
+    async fn registrations_since(&self, since: DateTime<Utc>) -> RepoResult<i64> {
+        Ok(
+            sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE created_at > $1")
+                .bind(since)
+                .fetch_one(&self.db)
+                .await
+                .recorded()?,
+        )
+    }
+}
+
+fn invite_from_row(row: &PgRow) -> RepoResult<InviteRecord> {
+    Ok(InviteRecord {
+        id: row.try_get("id")?,
+        code: row.try_get("code")?,
+        note: row.try_get("note")?,
+        created_at: row.try_get("created_at")?,
+        expires_at: row.try_get("expires_at")?,
+    })
+}
+
+pub struct PgMessageRepo {
+    db: PgPool,
+}
+
+impl PgMessageRepo {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl MessageRepo for PgMessageRepo {
+    async fn find_message(&self, id: Uuid) -> RepoResult<Option<MessageRecord>> {
+        let query = format!("SELECT {} FROM messages m WHERE m.id = $1", MESSAGE_COLUMNS);
+        let row = sqlx::query(&query)
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        row.as_ref().map(message_from_row).transpose()
+    }
+
+    async fn update_status(
+        &self,
+        id: Uuid,
+        from: MessageStatus,
+        to: MessageStatus,
+    ) -> RepoResult<bool> {
+        let result = sqlx::query("UPDATE messages SET status = $1 WHERE id = $2 AND status = $3")
+            .bind(to)
+            .bind(id)
+            .bind(from)
+            .execute(&self.db)
+            .await
+            .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_message_type(&self, id: Uuid, message_type: &str) -> RepoResult<bool> {
+        let result = sqlx::query("UPDATE messages SET type = $1 WHERE id = $2")
+            .bind(message_type)
+            .bind(id)
+            .execute(&self.db)
+            .await
+            .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn mark_sent_if_pending(&self, id: Uuid) -> RepoResult<()> {
+        sqlx::query("UPDATE messages SET status = 'SENT' WHERE id = $1 AND status = 'PENDING'")
+            .bind(id)
+            .execute(&self.db)
+            .await
+            .recorded()?;
+        Ok(())
+    }
+
+    async fn mark_delivered(&self, receiver_id: Uuid, ids: &[Uuid]) -> RepoResult<Vec<Uuid>> {
+        // A single statement: each row is re-checked for SENT after any concurrent update to
+        // it commits, so RETURNING lists exactly the messages this call moved
+        let rows = sqlx::query(
+            "UPDATE messages SET status = 'DELIVERED' WHERE id = ANY($1) AND receiver_id = $2 AND status = 'SENT' RETURNING id",
+        )
+        .bind(ids)
+        .bind(receiver_id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        Ok(rows
+            .iter()
+            .map(|row| row.try_get("id"))
+            .collect::<Result<Vec<Uuid>, _>>()?)
+    }
+
+    async fn record_delivery_attempt(
+        &self,
+        attempt: &DeliveryAttemptRecord,
+        keep: i64,
+    ) -> RepoResult<()> {
+        let mut tx = self.db.begin().await.recorded()?;
+        sqlx::query(
+            "INSERT INTO delivery_attempts (message_id, attempt_at, channel, outcome, error) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(attempt.message_id)
+        .bind(attempt.attempt_at)
+        .bind(&attempt.channel)
+        .bind(&attempt.outcome)
+        .bind(&attempt.error)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        sqlx::query(
+            "DELETE FROM delivery_attempts WHERE message_id = $1 AND id NOT IN \
+             (SELECT id FROM delivery_attempts WHERE message_id = $1 ORDER BY attempt_at DESC, id DESC LIMIT $2)",
+        )
+        .bind(attempt.message_id)
+        .bind(keep.max(0))
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(())
+    }
+
+    async fn delivery_attempts(&self, message_id: Uuid) -> RepoResult<Vec<DeliveryAttemptRecord>> {
+        let rows = sqlx::query(
+            "SELECT message_id, attempt_at, channel, outcome, error FROM delivery_attempts WHERE message_id = $1 ORDER BY attempt_at DESC, id DESC",
+        )
+        .bind(message_id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(DeliveryAttemptRecord {
+                    message_id: row.try_get("message_id")?,
+                    attempt_at: row.try_get("attempt_at")?,
+                    channel: row.try_get("channel")?,
+                    outcome: row.try_get("outcome")?,
+                    error: row.try_get("error")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn save_delivery_receipt(&self, receipt: &DeliveryReceiptRecord) -> RepoResult<()> {
+        sqlx::query(
+            "INSERT INTO delivery_receipts (message_id, sender_id, receiver_id, status, message_timestamp, signature, received_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7) \
+             ON CONFLICT (message_id) DO UPDATE SET status = EXCLUDED.status, signature = EXCLUDED.signature, received_at = EXCLUDED.received_at",
+        )
+        .bind(receipt.message_id)
+        .bind(receipt.sender_id)
+        .bind(receipt.receiver_id)
+        .bind(receipt.status)
+        .bind(receipt.message_timestamp)
+        .bind(&receipt.signature)
+        .bind(receipt.received_at)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(())
+    }
+
+    async fn delivery_receipt(
+        &self,
+        message_id: Uuid,
+    ) -> RepoResult<Option<DeliveryReceiptRecord>> {
+        let row = sqlx::query(
+            "SELECT message_id, sender_id, receiver_id, status, message_timestamp, signature, received_at \
+             FROM delivery_receipts WHERE message_id = $1",
+        )
+        .bind(message_id)
+        .fetch_optional(&self.db)
+        .await
+        .recorded()?;
+        row.map(|row| {
+            Ok(DeliveryReceiptRecord {
+                message_id: row.try_get("message_id")?,
+                sender_id: row.try_get("sender_id")?,
+                receiver_id: row.try_get("receiver_id")?,
+                status: row.try_get("status")?,
+                message_timestamp: row.try_get("message_timestamp")?,
+                signature: row.try_get("signature")?,
+                received_at: row.try_get("received_at")?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn sweep_pending(&self, cutoff_millis: i64) -> RepoResult<u64> {
+        let result = sqlx::query(
+            "UPDATE messages SET status = 'SENT' WHERE status = 'PENDING' AND timestamp < $1",
+        )
+        .bind(cutoff_millis)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(result.rows_affected())
+    }
+
+    async fn increment_forward_count(&self, id: Uuid) -> RepoResult<bool> {
+        let result =
+            sqlx::query("UPDATE messages SET forward_count = forward_count + 1 WHERE id = $1")
+                .bind(id)
+                .execute(&self.db)
+                .await
+                .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn unread_counts(
+        &self,
+        receiver_id: Uuid,
+        sender_ids: Option<&[Uuid]>,
+    ) -> RepoResult<HashMap<Uuid, i64>> {
+        if sender_ids.is_some_and(|ids| ids.is_empty()) {
+            return Ok(HashMap::new());
+        }
+        let rows = sqlx::query(
+            "SELECT sender_id, COUNT(*) AS unread FROM messages WHERE receiver_id = $1 AND ($2::uuid[] IS NULL OR sender_id = ANY($2)) AND status IN ('PENDING', 'SENT', 'DELIVERED') GROUP BY sender_id",
+        )
+        .bind(receiver_id)
+        .bind(sender_ids)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| Ok((row.try_get("sender_id")?, row.try_get("unread")?)))
+            .collect()
+    }
+
+    async fn message_type_counts(&self, user_id: Uuid) -> RepoResult<Vec<MessageTypeCount>> {
+        let rows = sqlx::query(
+            "SELECT type, COUNT(*) FILTER (WHERE sender_id = $1) AS sent, COUNT(*) FILTER (WHERE receiver_id = $1) AS received FROM messages WHERE sender_id = $1 OR receiver_id = $1 GROUP BY type ORDER BY type",
+        )
+        .bind(user_id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(MessageTypeCount {
+                    r#type: row
+                        .try_get::<Option<String>, _>("type")?
+                        .unwrap_or_default(),
+                    sent: row.try_get("sent")?,
+                    received: row.try_get("received")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn backlog_for(&self, user_id: Uuid) -> RepoResult<BacklogRecord> {
+        let row = sqlx::query(
+            "SELECT COUNT(*) AS count, MIN(timestamp) AS oldest, (array_agg(id ORDER BY timestamp, id))[1] AS oldest_id FROM messages WHERE receiver_id = $1 AND status = 'SENT'",
+        )
+        .bind(user_id)
+        .fetch_one(&self.db)
+        .await
+        .recorded()?;
+        Ok(BacklogRecord {
+            user_id,
+            count: row.try_get("count")?,
+            oldest_timestamp: row.try_get("oldest")?,
+            oldest_message_id: row.try_get("oldest_id")?,
+        })
+    }
+
+    async fn largest_backlogs(&self, limit: i64) -> RepoResult<Vec<BacklogRecord>> {
+        let rows = sqlx::query(
+            "SELECT receiver_id, COUNT(*) AS count, MIN(timestamp) AS oldest, (array_agg(id ORDER BY timestamp, id))[1] AS oldest_id FROM messages WHERE status = 'SENT' GROUP BY receiver_id ORDER BY count DESC, oldest ASC LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(BacklogRecord {
+                    user_id: row.try_get("receiver_id")?,
+                    count: row.try_get("count")?,
+                    oldest_timestamp: row.try_get("oldest")?,
+                    oldest_message_id: row.try_get("oldest_id")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_read_message(&self, id: Uuid, keep_pinned: bool) -> RepoResult<bool> {
+        let query = if keep_pinned {
+            "DELETE FROM messages WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM message_pins WHERE message_id = $1)"
+        } else {
+            "DELETE FROM messages WHERE id = $1"
+        };
+        let result = sqlx::query(query)
+            .bind(id)
+            .execute(&self.db)
+            .await
+            .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn conversation(
+        &self,
+        user_a: Uuid,
+        user_b: Uuid,
+        query: ConversationQuery,
+    ) -> RepoResult<Vec<MessageRecord>> {
+        let sql = conversation_query(&query);
+        let mut sql_query = sqlx::query(&sql).bind(user_a).bind(user_b);
+        if let Some(after) = query.after {
+            sql_query = sql_query.bind(after);
+        }
+        if let Some(before) = query.before {
+            sql_query = sql_query.bind(before);
+        }
+        if let Some(cursor) = query.cursor {
+            sql_query = sql_query.bind(cursor.timestamp).bind(cursor.id);
+        }
+        if let Some(limit) = query.limit {
+            sql_query = sql_query.bind(limit.max(0));
+        }
+        let rows = sql_query.fetch_all(&self.db).await.recorded()?;
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn messages_of_user(
+        &self,
+        user_id: Uuid,
+        cursor: Option<MessageCursor>,
+        limit: i64,
+    ) -> RepoResult<Vec<MessageRecord>> {
+        // Each direction walks its own (user, timestamp, id) index; messages to oneself
+        // are only taken from the sent side
+        let past_cursor = "($2::bigint IS NULL OR (m.timestamp, m.id) > ($2, $3))";
+        let sql = format!(
+            "SELECT * FROM (\
+                (SELECT {columns} FROM messages m WHERE m.sender_id = $1 AND {past_cursor} ORDER BY m.timestamp, m.id LIMIT $4) \
+                UNION ALL \
+                (SELECT {columns} FROM messages m WHERE m.receiver_id = $1 AND m.sender_id <> $1 AND {past_cursor} ORDER BY m.timestamp, m.id LIMIT $4)\
+            ) m ORDER BY m.timestamp, m.id LIMIT $4",
+            columns = MESSAGE_COLUMNS,
+            past_cursor = past_cursor,
+        );
+        let rows = sqlx::query(&sql)
+            .bind(user_id)
+            .bind(cursor.map(|c| c.timestamp))
+            .bind(cursor.map(|c| c.id))
+            .bind(limit.max(0))
+            .fetch_all(&self.db)
+            .await
+            .recorded()?;
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn insert_message_after_latest(
+        &self,
+        message: &MessageRecord,
+    ) -> RepoResult<OrderedInsert> {
+        // READ COMMITTED on purpose: a SERIALIZABLE snapshot would be taken before waiting
+        // for the lock below and miss the sends that held it, aborting nearly every waiter
+        let mut tx = self.db.begin().await.recorded()?;
+        // Held until commit, so concurrent sends in a conversation read and insert in turn
+        sqlx::query(
+            "SELECT pg_advisory_xact_lock(hashtextextended(LEAST($1::text, $2::text) || GREATEST($1::text, $2::text), 0))",
+        )
+        .bind(message.sender_id)
+        .bind(message.receiver_id)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        // One index lookup per direction on messages_sender_receiver_timestamp_idx
+        let latest: Option<i64> = sqlx::query(
+            "SELECT GREATEST((SELECT MAX(timestamp) FROM messages WHERE sender_id = $1 AND receiver_id = $2), (SELECT MAX(timestamp) FROM messages WHERE sender_id = $2 AND receiver_id = $1)) AS latest",
+        )
+        .bind(message.sender_id)
+        .bind(message.receiver_id)
+        .fetch_one(&mut *tx)
+        .await
+        .recorded()?
+        .try_get("latest")?;
+        let was_behind = latest.is_some_and(|latest| message.timestamp < latest);
+        let timestamp = match latest {
+            Some(latest) if message.timestamp <= latest => latest + 1,
+            _ => message.timestamp,
+        };
+        insert_message_row(&mut *tx, message, timestamp).await?;
+        tx.commit().await.recorded()?;
+        Ok(OrderedInsert {
+            timestamp,
+            was_behind,
+        })
+    }
+
+    async fn delete_conversation(&self, user_a: Uuid, user_b: Uuid) -> RepoResult<u64> {
+        // Pins go with their messages through ON DELETE CASCADE
+        let mut tx = self.db.begin().await.recorded()?;
+        let result = sqlx::query(
+            "DELETE FROM messages WHERE (sender_id = $1 AND receiver_id = $2) OR (sender_id = $2 AND receiver_id = $1)",
+        )
+        .bind(user_a)
+        .bind(user_b)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(result.rows_affected())
+    }
+
+    async fn pin(&self, message_id: Uuid, pinned_by: Uuid) -> RepoResult<bool> {
+        let result = sqlx::query(
+            "INSERT INTO message_pins (message_id, pinned_by) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(message_id)
+        .bind(pinned_by)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn unpin(&self, message_id: Uuid) -> RepoResult<bool> {
+        let result = sqlx::query("DELETE FROM message_pins WHERE message_id = $1")
+            .bind(message_id)
+            .execute(&self.db)
+            .await
+            .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn pinned_in_conversation(
+        &self,
+        user_a: Uuid,
+        user_b: Uuid,
+    ) -> RepoResult<Vec<PinnedMessageRecord>> {
+        let query = format!(
+            "SELECT {}, p.pinned_by, p.pinned_at FROM message_pins p JOIN messages m ON m.id = p.message_id WHERE (m.sender_id = $1 AND m.receiver_id = $2) OR (m.sender_id = $2 AND m.receiver_id = $1) ORDER BY m.timestamp ASC",
+            MESSAGE_COLUMNS
+        );
+        let rows = sqlx::query(&query)
+            .bind(user_a)
+            .bind(user_b)
+            .fetch_all(&self.db)
+            .await
+            .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(PinnedMessageRecord {
+                    message: message_from_row(row)?,
+                    pinned_by: row.try_get("pinned_by")?,
+                    pinned_at: row
+                        .try_get::<Option<DateTime<Utc>>, _>("pinned_at")?
+                        .unwrap_or_else(Utc::now),
+                })
+            })
+            .collect()
+    }
+
+    async fn record_send(
+        &self,
+        user_id: Uuid,
+        windows: &[SendQuotaWindow],
+    ) -> RepoResult<SendQuotaOutcome> {
+        let mut tx = self.db.begin().await.recorded()?;
+        if let Some(oldest) = windows.iter().map(|window| window.start).min() {
+            sqlx::query("DELETE FROM send_counters WHERE user_id = $1 AND window_start < $2")
+                .bind(user_id)
+                .bind(oldest)
+                .execute(&mut *tx)
+                .await
+                .recorded()?;
+        }
+        // The upsert locks each counter row, so concurrent sends are counted one at a time
+        for window in windows {
+            let count: i32 = sqlx::query_scalar(
+                "INSERT INTO send_counters (user_id, window_name, window_start, count) VALUES ($1, $2, $3, 1) \
+                 ON CONFLICT (user_id, window_name, window_start) DO UPDATE SET count = send_counters.count + 1 \
+                 RETURNING count",
+            )
+            .bind(user_id)
+            .bind(window.name)
+            .bind(window.start)
+            .fetch_one(&mut *tx)
+            .await
+            .recorded()?;
+            if i64::from(count) > window.limit {
+                tx.rollback().await.recorded()?;
+                return Ok(SendQuotaOutcome::Exceeded(*window));
+            }
+        }
+        tx.commit().await.recorded()?;
+        Ok(SendQuotaOutcome::Allowed)
+    }
+
+    async fn refund_send(&self, user_id: Uuid, windows: &[SendQuotaWindow]) -> RepoResult<()> {
+        let names: Vec<String> = windows.iter().map(|w| w.name.to_string()).collect();
+        let starts: Vec<DateTime<Utc>> = windows.iter().map(|w| w.start).collect();
+        sqlx::query(
+            "UPDATE send_counters c SET count = c.count - 1 \
+             FROM UNNEST($2::text[], $3::timestamptz[]) AS w(name, start) \
+             WHERE c.user_id = $1 AND c.window_name = w.name AND c.window_start = w.start AND c.count > 0",
+        )
+        .bind(user_id)
+        .bind(&names)
+        .bind(&starts)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(())
+    }
+
+    async fn send_counts(
+        &self,
+        user_id: Uuid,
+        windows: &[SendQuotaWindow],
+    ) -> RepoResult<Vec<i64>> {
+        // Matched in SQL so the window starts compare at the column's precision
+        let names: Vec<String> = windows.iter().map(|w| w.name.to_string()).collect();
+        let starts: Vec<DateTime<Utc>> = windows.iter().map(|w| w.start).collect();
+        let rows = sqlx::query(
+            "SELECT COALESCE(c.count, 0) AS count \
+             FROM UNNEST($2::text[], $3::timestamptz[]) WITH ORDINALITY AS w(name, start, ord) \
+             LEFT JOIN send_counters c \
+               ON c.user_id = $1 AND c.window_name = w.name AND c.window_start = w.start \
+             ORDER BY w.ord",
+        )
+        .bind(user_id)
+        .bind(&names)
+        .bind(&starts)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| Ok(i64::from(row.try_get::<i32, _>("count")?)))
+            .collect()
+    }
+
+    async fn sends_since(&self, window: &str, since: DateTime<Utc>) -> RepoResult<i64> {
+        Ok(sqlx::query_scalar(
+            "SELECT COALESCE(SUM(count), 0)::BIGINT FROM send_counters WHERE window_name = $1 AND window_start >= $2",
+        )
+        .bind(window)
+        .bind(since)
+        .fetch_one(&self.db)
+        .await
+        .recorded()?)
+    }
+
+    async fn top_senders(
+        &self,
+        window: &str,
+        start: DateTime<Utc>,
+        limit: i64,
+    ) -> RepoResult<Vec<SenderActivity>> {
+        let rows = sqlx::query(
+            "SELECT user_id, count FROM send_counters WHERE window_name = $1 AND window_start = $2 \
+             ORDER BY count DESC, user_id LIMIT $3",
+        )
+        .bind(window)
+        .bind(start)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(SenderActivity {
+                    user_id: row.try_get("user_id")?,
+                    sends: i64::from(row.try_get::<i32, _>("count")?),
+                })
+            })
+            .collect()
+    }
+
+    async fn insert_sealed_message(
+        &self,
+        message: &SealedMessageRecord,
+        receiver_id: Uuid,
+    ) -> RepoResult<bool> {
+        // SERIALIZABLE: a resend racing the original is aborted, and on retry finds the
+        // original stored and reports a duplicate
+        Ok(
+            retry_serializable(|| insert_sealed_once(&self.db, message, receiver_id))
+                .await
+                .recorded()?,
+        )
+    }
+
+    async fn sealed_messages_for(&self, receiver_id: Uuid) -> RepoResult<Vec<SealedMessageRecord>> {
+        let rows = sqlx::query(
+            "SELECT s.id, s.routing_id, s.day, s.type, s.encrypted_metadata, s.encrypted_content, s.iv, s.encryption_version \
+             FROM sealed_messages s JOIN message_routing r ON r.routing_id = s.routing_id \
+             WHERE r.actual_receiver_id = $1 ORDER BY s.day, s.id",
+        )
+        .bind(receiver_id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(SealedMessageRecord {
+                    id: row.try_get("id")?,
+                    routing_id: row.try_get("routing_id")?,
+                    day: row.try_get("day")?,
+                    r#type: row.try_get("type")?,
+                    encrypted_metadata: row.try_get("encrypted_metadata")?,
+                    encrypted_content: row.try_get("encrypted_content")?,
+                    iv: row.try_get("iv")?,
+                    encryption_version: row.try_get("encryption_version")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_sealed_message(&self, id: Uuid, receiver_id: Uuid) -> RepoResult<bool> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let routing_id: Option<String> = sqlx::query_scalar(
+            "DELETE FROM sealed_messages s USING message_routing r \
+             WHERE s.id = $1 AND r.routing_id = s.routing_id AND r.actual_receiver_id = $2 \
+             RETURNING s.routing_id",
+        )
+        .bind(id)
+        .bind(receiver_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .recorded()?;
+        let Some(routing_id) = routing_id else {
+            return Ok(false);
+        };
+        sqlx::query(
+            "DELETE FROM message_routing WHERE routing_id = $1 \
+             AND NOT EXISTS (SELECT 1 FROM sealed_messages WHERE routing_id = $1)",
+        )
+        .bind(&routing_id)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(true)
+    }
+}
+
+pub struct PgContactRepo {
+    db: PgPool,
+}
+
+impl PgContactRepo {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl ContactRepo for PgContactRepo {
+    async fn accepted_peers(&self, user_id: Uuid) -> RepoResult<HashSet<Uuid>> {
+        let rows = sqlx::query(
+            "SELECT contact_id AS peer_id FROM user_contacts WHERE owner_id = $1 UNION SELECT peer_id FROM conversation_peers WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        Ok(rows
+            .iter()
+            .map(|row| row.try_get::<Uuid, _>("peer_id"))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn record_conversation_peer(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+        opened_at: Option<DateTime<Utc>>,
+    ) -> RepoResult<()> {
+        sqlx::query(
+            "INSERT INTO conversation_peers (user_id, peer_id, opened_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(peer_id)
+        .bind(opened_at)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(())
+    }
+
+    async fn has_conversation(&self, user_id: Uuid, peer_id: Uuid) -> RepoResult<bool> {
+        let row = sqlx::query(
+            "SELECT 1 FROM conversation_peers WHERE (user_id = $1 AND peer_id = $2) OR (user_id = $2 AND peer_id = $1) LIMIT 1",
+        )
+        .bind(user_id)
+        .bind(peer_id)
+        .fetch_optional(&self.db)
+        .await
+        .recorded()?;
+        Ok(row.is_some())
+    }
+
+    async fn conversations_opened_since(
+        &self,
+        user_id: Uuid,
+        since: DateTime<Utc>,
+    ) -> RepoResult<(i64, Option<DateTime<Utc>>)> {
+        let row = sqlx::query(
+            "SELECT COUNT(*) AS opened, MIN(opened_at) AS oldest FROM conversation_peers WHERE user_id = $1 AND opened_at > $2",
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_one(&self.db)
+        .await
+        .recorded()?;
+        Ok((row.try_get("opened")?, row.try_get("oldest")?))
+    }
+
+    async fn conversation_settings(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+    ) -> RepoResult<ConversationSettings> {
+        let row = sqlx::query(
+            "SELECT keep_read_messages FROM conversation_settings WHERE user_id = $1 AND peer_id = $2",
+        )
+        .bind(user_id)
+        .bind(peer_id)
+        .fetch_optional(&self.db)
+        .await
+        .recorded()?;
+        Ok(match row {
+            Some(row) => ConversationSettings {
+                keep_read_messages: row.try_get("keep_read_messages")?,
+            },
+            None => ConversationSettings::default(),
+        })
+    }
+
+    async fn set_conversation_settings(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+        settings: &ConversationSettings,
+    ) -> RepoResult<()> {
+        sqlx::query(
+            "INSERT INTO conversation_settings (user_id, peer_id, keep_read_messages) VALUES ($1, $2, $3) \
+             ON CONFLICT (user_id, peer_id) DO UPDATE SET keep_read_messages = EXCLUDED.keep_read_messages, updated_at = NOW()",
+        )
+        .bind(user_id)
+        .bind(peer_id)
+        .bind(settings.keep_read_messages)
+        .execute(&self.db)
+        .await
+        .recorded()?;
+        Ok(())
+    }
+
+    async fn search_conversation_peers(
+        &self,
+        user_id: Uuid,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> RepoResult<(Vec<ConversationPeerRecord>, i64)> {
+        let pattern = escape_like(query);
+        let total: i64 = sqlx::query_scalar(&format!(
+            "{} SELECT COUNT(*) FROM peers p JOIN users u ON u.id = p.id WHERE {}",
+            CONVERSATION_PEERS_CTE, USERNAME_CONTAINS
+        ))
+        .bind(user_id)
+        .bind(&pattern)
+        .fetch_one(&self.db)
+        .await
+        .recorded()?;
+        let rows = sqlx::query(&format!(
+            "{} SELECT u.id, u.username FROM peers p JOIN users u ON u.id = p.id WHERE {} ORDER BY lower(u.username), u.id LIMIT $3 OFFSET $4",
+            CONVERSATION_PEERS_CTE, USERNAME_CONTAINS
+        ))
+        .bind(user_id)
+        .bind(&pattern)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        let peers = rows
+            .iter()
+            .map(|row| {
+                Ok(ConversationPeerRecord {
+                    user_id: row.try_get("id")?,
+                    username: row.try_get("username")?,
+                })
+            })
+            .collect::<RepoResult<Vec<_>>>()?;
+        Ok((peers, total))
+    }
+
+    async fn list_contacts(&self, owner_id: Uuid) -> RepoResult<Vec<ContactRecord>> {
+        let rows = sqlx::query(
+            "SELECT u.id, u.username, u.public_key, c.created_at FROM user_contacts c JOIN users u ON u.id = c.contact_id WHERE c.owner_id = $1 ORDER BY c.created_at ASC",
+        )
+        .bind(owner_id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter()
+            .map(|row| {
+                Ok(ContactRecord {
+                    user_id: row.try_get("id")?,
+                    username: row.try_get("username")?,
+                    public_key: row.try_get("public_key")?,
+                    added_at: row
+                        .try_get::<Option<DateTime<Utc>>, _>("created_at")?
+                        .unwrap_or_else(Utc::now),
+                })
+            })
+            .collect()
+    }
+
+    async fn add_contact(
+        &self,
+        owner_id: Uuid,
+        contact_id: Uuid,
+        max_contacts: i64,
+    ) -> RepoResult<AddContactOutcome> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let exists = sqlx::query("SELECT 1 FROM users WHERE id = $1")
+            .bind(contact_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .recorded()?;
+        if exists.is_none() {
+            return Ok(AddContactOutcome::UserNotFound);
+        }
+        // Lock the owner's row so concurrent adds are counted one after another
+        sqlx::query("SELECT 1 FROM users WHERE id = $1 FOR UPDATE")
+            .bind(owner_id)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+        let row = sqlx::query(
+            "SELECT COUNT(*) AS count, BOOL_OR(contact_id = $2) AS already FROM user_contacts WHERE owner_id = $1",
+        )
+        .bind(owner_id)
+        .bind(contact_id)
+        .fetch_one(&mut *tx)
+        .await
+        .recorded()?;
+        let count: i64 = row.try_get("count")?;
+        let already: Option<bool> = row.try_get("already")?;
+        if !already.unwrap_or(false) && count >= max_contacts {
+            return Ok(AddContactOutcome::LimitReached);
+        }
+        sqlx::query(
+            "INSERT INTO user_contacts (owner_id, contact_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(owner_id)
+        .bind(contact_id)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(AddContactOutcome::Added)
+    }
+
+    async fn remove_contact(&self, owner_id: Uuid, contact_id: Uuid) -> RepoResult<bool> {
+        let result =
+            sqlx::query("DELETE FROM user_contacts WHERE owner_id = $1 AND contact_id = $2")
+                .bind(owner_id)
+                .bind(contact_id)
+                .execute(&self.db)
+                .await
+                .recorded()?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn is_contact(&self, owner_id: Uuid, contact_id: Uuid) -> RepoResult<bool> {
+        let row =
+            sqlx::query("SELECT 1 FROM user_contacts WHERE owner_id = $1 AND contact_id = $2")
+                .bind(owner_id)
+                .bind(contact_id)
+                .fetch_optional(&self.db)
+                .await
+                .recorded()?;
+        Ok(row.is_some())
+    }
+
+    async fn create_request(
+        &self,
+        requester_id: Uuid,
+        target_id: Uuid,
+        message: Option<&str>,
+        closed_after: DateTime<Utc>,
+    ) -> RepoResult<CreateRequestOutcome> {
+        let mut tx = self.db.begin().await.recorded()?;
+        // Waits for a decline or withdrawal of the pair's request that is in flight, so the
+        // insert below sees it
+        sqlx::query(
+            "SELECT id FROM contact_requests WHERE requester_id = $1 AND target_id = $2 FOR UPDATE",
+        )
+        .bind(requester_id)
+        .bind(target_id)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        let row = sqlx::query(
+            "INSERT INTO contact_requests (requester_id, target_id, message) SELECT $1, $2, $3 \
+             WHERE NOT EXISTS (SELECT 1 FROM contact_requests WHERE requester_id = $1 AND target_id = $2 \
+             AND status IN ('DECLINED', 'WITHDRAWN') AND responded_at > $4) RETURNING id",
+        )
+        .bind(requester_id)
+        .bind(target_id)
+        .bind(message)
+        .bind(closed_after)
+        .fetch_optional(&mut *tx)
+        .await
+        .recorded()?;
+        let Some(row) = row else {
+            let closed_at: DateTime<Utc> = sqlx::query_scalar(
+                "SELECT MAX(responded_at) FROM contact_requests WHERE requester_id = $1 AND target_id = $2 \
+                 AND status IN ('DECLINED', 'WITHDRAWN')",
+            )
+            .bind(requester_id)
+            .bind(target_id)
+            .fetch_one(&mut *tx)
+            .await
+            .recorded()?;
+            tx.commit().await.recorded()?;
+            return Ok(CreateRequestOutcome::CoolingDown(closed_at));
+        };
+        tx.commit().await.recorded()?;
+        let id: Uuid = row.try_get("id")?;
+        let request = self.find_request(id).await?.ok_or_else(|| {
+            super::RepoError::Database("Created contact request not found".into())
+        })?;
+        Ok(CreateRequestOutcome::Created(request))
+    }
+
+    async fn find_request(&self, id: Uuid) -> RepoResult<Option<ContactRequestRecord>> {
+        let query = format!(
+            "SELECT {} FROM contact_requests r JOIN users u ON u.id = r.requester_id WHERE r.id = $1",
+            CONTACT_REQUEST_COLUMNS
+        );
+        let row = sqlx::query(&query)
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+            .recorded()?;
+        row.as_ref().map(contact_request_from_row).transpose()
+    }
+
+    async fn list_pending_requests(&self, user_id: Uuid) -> RepoResult<Vec<ContactRequestRecord>> {
+        let query = format!(
+            "SELECT {} FROM contact_requests r JOIN users u ON u.id = r.requester_id WHERE r.status = 'PENDING' AND (r.target_id = $1 OR r.requester_id = $1) ORDER BY r.created_at ASC",
+            CONTACT_REQUEST_COLUMNS
+        );
+        let rows = sqlx::query(&query)
+            .bind(user_id)
+            .fetch_all(&self.db)
+            .await
+            .recorded()?;
+        rows.iter().map(contact_request_from_row).collect()
+    }
+
+    async fn resolve_request(
+        &self,
+        id: Uuid,
+        status: &str,
+        accept: bool,
+    ) -> RepoResult<Option<ContactRequestRecord>> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let row = sqlx::query(
+            "UPDATE contact_requests SET status = $1, responded_at = NOW() WHERE id = $2 AND status = 'PENDING' RETURNING requester_id, target_id",
+        )
+        .bind(status)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .recorded()?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        if accept {
+            let requester_id: Uuid = row.try_get("requester_id")?;
+            let target_id: Uuid = row.try_get("target_id")?;
+            sqlx::query(
+                "INSERT INTO user_contacts (owner_id, contact_id) VALUES ($1, $2), ($2, $1) ON CONFLICT DO NOTHING",
+            )
+            .bind(requester_id)
+            .bind(target_id)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+        }
+        tx.commit().await.recorded()?;
+        self.find_request(id).await
+    }
+}
+
+/// Everyone `$1` has a conversation with: people they messaged and people who messaged them.
+const CONVERSATION_PEERS_CTE: &str = "WITH peers AS (SELECT peer_id AS id FROM conversation_peers WHERE user_id = $1 UNION SELECT user_id FROM conversation_peers WHERE peer_id = $1)";
+/// Case-insensitive substring match of `u.username` against the escaped pattern `$2`.
+/// Folding follows the database's `LC_CTYPE`, which must be a UTF-8 locale for non-ASCII names.
+const USERNAME_CONTAINS: &str = "lower(u.username) LIKE '%' || lower($2) || '%' ESCAPE '\\'";
+
+/// Escapes `LIKE` wildcards so user input only matches literally.
+fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub struct PgAnnouncementRepo {
+    db: PgPool,
+}
+
+impl PgAnnouncementRepo {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+fn announcement_from_row(row: &PgRow) -> RepoResult<AnnouncementRecord> {
+    Ok(AnnouncementRecord {
+        id: row.try_get("id")?,
+        message: row.try_get("message")?,
+        severity: row.try_get("severity")?,
+        created_at: row.try_get("created_at")?,
+        expires_at: row.try_get("expires_at")?,
+    })
+}
+
+#[async_trait]
+impl AnnouncementRepo for PgAnnouncementRepo {
+    async fn create_announcement(
+        &self,
+        announcement: &AnnouncementRecord,
+        actor: &AuditActor,
+    ) -> RepoResult<()> {
+        let mut tx = self.db.begin().await.recorded()?;
+        sqlx::query(
+            "INSERT INTO announcements (id, message, severity, created_at, expires_at) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(announcement.id)
+        .bind(&announcement.message)
+        .bind(&announcement.severity)
+        .bind(announcement.created_at)
+        .bind(announcement.expires_at)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        sqlx::query(
+            "INSERT INTO admin_audit_log (action, target_id, source_ip, details, admin_username) VALUES ('announcement.create', $1, $2, $3, $4)",
+        )
+        .bind(announcement.id)
+        .bind(&actor.source_ip)
+        .bind(format!("[{}] {}", announcement.severity, announcement.message))
+        .bind(&actor.admin)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(())
+    }
+
+    async fn active_announcements(
+        &self,
+        now: DateTime<Utc>,
+    ) -> RepoResult<Vec<AnnouncementRecord>> {
+        let rows = sqlx::query(
+            "SELECT id, message, severity, created_at, expires_at FROM announcements WHERE expires_at IS NULL OR expires_at > $1 ORDER BY created_at ASC",
+        )
+        .bind(now)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter().map(announcement_from_row).collect()
+    }
+
+    async fn delete_announcement(&self, id: Uuid, actor: &AuditActor) -> RepoResult<bool> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let result = sqlx::query("DELETE FROM announcements WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            "INSERT INTO admin_audit_log (action, target_id, source_ip, admin_username) VALUES ('announcement.delete', $1, $2, $3)",
+        )
+        .bind(id)
+        .bind(&actor.source_ip)
+        .bind(&actor.admin)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        tx.commit().await.recorded()?;
+        Ok(true)
+    }
+}
+
+pub struct PgDeviceRepo {
+    db: PgPool,
+}
+
+impl PgDeviceRepo {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+fn device_from_row(row: &PgRow) -> RepoResult<DeviceRecord> {
+    Ok(DeviceRecord {
+        id: row.try_get("id")?,
+        user_id: row.try_get("user_id")?,
+        name: row.try_get("name")?,
+        public_key: row.try_get("public_key")?,
+        created_at: row.try_get("created_at")?,
+        last_active: row.try_get("last_active")?,
+        supported_ciphers: row.try_get("supported_ciphers")?,
+    })
+}
+
+async fn bump_profile_updated_at<'e, E: PgExecutor<'e>>(
+    executor: E,
+    user_id: Uuid,
+) -> RepoResult<()> {
+    sqlx::query("UPDATE users SET profile_updated_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .execute(executor)
+        .await
+        .recorded()?;
+    Ok(())
+}
+
+#[async_trait]
+impl DeviceRepo for PgDeviceRepo {
+    async fn register_device(&self, device: &DeviceRecord) -> RepoResult<()> {
+        let mut tx = self.db.begin().await.recorded()?;
+        sqlx::query(
+            "INSERT INTO devices (id, user_id, name, public_key, created_at, supported_ciphers) VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(device.id)
+        .bind(device.user_id)
+        .bind(&device.name)
+        .bind(&device.public_key)
+        .bind(device.created_at)
+        .bind(&device.supported_ciphers)
+        .execute(&mut *tx)
+        .await
+        .recorded()?;
+        bump_profile_updated_at(&mut *tx, device.user_id).await?;
+        tx.commit().await.recorded()?;
+        Ok(())
+    }
+
+    async fn list_devices(&self, user_id: Uuid) -> RepoResult<Vec<DeviceRecord>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM devices WHERE user_id = $1 ORDER BY created_at ASC",
+            DEVICE_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(&self.db)
+        .await
+        .recorded()?;
+        rows.iter().map(device_from_row).collect()
+    }
+
+    async fn find_device(&self, id: Uuid) -> RepoResult<Option<DeviceRecord>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM devices WHERE id = $1",
+            DEVICE_COLUMNS
+        ))
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await
+        .recorded()?;
+        row.as_ref().map(device_from_row).transpose()
+    }
+
+    async fn revoke_device(&self, user_id: Uuid, id: Uuid) -> RepoResult<bool> {
+        let mut tx = self.db.begin().await.recorded()?;
+        let result = sqlx::query("DELETE FROM devices WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .recorded()?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        bump_profile_updated_at(&mut *tx, user_id).await?;
+        tx.commit().await.recorded()?;
+        Ok(true)
+    }
+
+    async fn touch_device(&self, id: Uuid, now: DateTime<Utc>) -> RepoResult<()> {
+        sqlx::query("UPDATE devices SET last_active = $2 WHERE id = $1")
+            .bind(id)
+            .bind(now)
+            .execute(&self.db)
+            .await
+            .recorded()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::RepoError;
+    use crate::repo::tests::{assert_pages_cover_conversation, seam_messages};
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!(escape_like("ann"), "ann");
+        assert_eq!(escape_like("50%_off\\"), "50\\%\\_off\\\\");
+    }
+
+    #[test]
+    fn test_conversation_query_params() {
+        let query = conversation_query(&ConversationQuery::default());
+        assert!(!query.contains("$3"));
+        assert!(!query.contains(" OR "));
+        assert!(query.ends_with("ORDER BY m.timestamp ASC, m.id ASC"));
+
+        // Both branches reuse the same range parameters
+        let range = ConversationQuery {
+            after: Some(0),
+            before: Some(1),
+            ..Default::default()
+        };
+        let query = conversation_query(&range);
+        assert_eq!(query.matches("m.timestamp >= $3").count(), 2);
+        assert_eq!(query.matches("m.timestamp <= $4").count(), 2);
+
+        let query = conversation_query(&ConversationQuery {
+            before: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(query.matches("m.timestamp <= $3").count(), 2);
+
+        // The cursor follows the range and flips with the order; the limit comes last
+        let cursor = Some(MessageCursor {
+            timestamp: 0,
+            id: Uuid::nil(),
+        });
+        let query = conversation_query(&ConversationQuery {
+            order: SortOrder::Desc,
+            cursor,
+            limit: Some(10),
+            ..range
+        });
+        assert_eq!(query.matches("(m.timestamp, m.id) < ($5, $6)").count(), 2);
+        assert_eq!(query.matches("LIMIT $7").count(), 3);
+        assert!(query.ends_with("ORDER BY m.timestamp DESC, m.id DESC LIMIT $7"));
+    }
+
+    /// Returns the plan for `query` with sequential scans disabled, so the test shows
+    /// whether an index can serve it regardless of how small the table is.
+    async fn explain(db: &PgPool, query: &str, params: &[Uuid]) -> String {
+        let mut tx = db.begin().await.unwrap();
+        sqlx::query("SET LOCAL enable_seqscan = off")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let explain = format!("EXPLAIN {}", query);
+        let mut sql_query = sqlx::query(&explain);
+        for param in params {
+            sql_query = sql_query.bind(*param);
+        }
+        let rows = sql_query.fetch_all(&mut *tx).await.unwrap();
+        rows.iter()
+            .map(|row| row.get::<String, _>(0))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    async fn migrated_db() -> PgPool {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a migrated database");
+        PgPool::connect(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_conversation_query_uses_index() {
+        let db = migrated_db().await;
+        let plan = explain(
+            &db,
+            &conversation_query(&ConversationQuery::default()),
+            &[Uuid::new_v4(), Uuid::new_v4()],
+        )
+        .await;
+        assert_eq!(
+            plan.matches("messages_sender_receiver_timestamp_idx")
+                .count(),
+            2,
+            "{}",
+            plan
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_receiver_status_query_uses_index() {
+        let db = migrated_db().await;
+        let plan = explain(
+            &db,
+            "SELECT COUNT(*) FROM messages WHERE receiver_id = $1 AND status = 'DELIVERED'",
+            &[Uuid::new_v4()],
+        )
+        .await;
+        assert!(plan.contains("messages_receiver_status_idx"), "{}", plan);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_conversation_search_stays_on_indexes() {
+        let db = migrated_db().await;
+        let mut tx = db.begin().await.unwrap();
+        sqlx::query("SET LOCAL enable_seqscan = off")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let query = format!(
+            "EXPLAIN {} SELECT u.id, u.username FROM peers p JOIN users u ON u.id = p.id WHERE {} ORDER BY lower(u.username), u.id LIMIT $3 OFFSET $4",
+            CONVERSATION_PEERS_CTE, USERNAME_CONTAINS
+        );
+        let plan = sqlx::query(&query)
+            .bind(Uuid::new_v4())
+            .bind("ann")
+            .bind(20_i64)
+            .bind(0_i64)
+            .fetch_all(&mut *tx)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(0))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(!plan.contains("Seq Scan"), "{}", plan);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_mistyped_column_is_an_error_not_null() {
+        let db = migrated_db().await;
+        let schema = format!("scratch_{}", Uuid::new_v4().simple());
+        let mut conn = db.acquire().await.unwrap();
+        for statement in [
+            format!("CREATE SCHEMA {}", schema),
+            // `avatar` is TEXT instead of BYTEA, as after a botched migration
+            format!(
+                "CREATE TABLE {}.users (id UUID PRIMARY KEY, username TEXT NOT NULL, password_hash TEXT NOT NULL, public_key TEXT NOT NULL, created_at TIMESTAMPTZ, avatar TEXT, avatar_content_type TEXT, key_version INT, profile_updated_at TIMESTAMPTZ)",
+                schema
+            ),
+        ] {
+            sqlx::query(&statement).execute(&mut *conn).await.unwrap();
+        }
+        let (with_avatar, without_avatar) = (Uuid::new_v4(), Uuid::new_v4());
+        sqlx::query(&format!(
+            "INSERT INTO {}.users (id, username, password_hash, public_key, avatar) VALUES ($1, 'a', 'hash', 'key', 'not bytes'), ($2, 'b', 'hash', 'key', NULL)",
+            schema
+        ))
+        .bind(with_avatar)
+        .bind(without_avatar)
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        let select = format!(
+            "SELECT {} FROM {}.users WHERE id = $1",
+            USER_COLUMNS, schema
+        );
+        let fetch = |id: Uuid| sqlx::query(&select).bind(id);
+        let null_row = fetch(without_avatar).fetch_one(&mut *conn).await.unwrap();
+        let mistyped_row = fetch(with_avatar).fetch_one(&mut *conn).await.unwrap();
+        sqlx::query(&format!("DROP SCHEMA {} CASCADE", schema))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        let user = user_from_row(&null_row).unwrap();
+        assert_eq!(user.avatar, None);
+        assert_eq!(user.key_version, 0);
+        let errors_before = ROW_DECODE_ERRORS.load(Ordering::Relaxed);
+        let err = user_from_row(&mistyped_row).unwrap_err();
+        assert!(err.to_string().contains("avatar"), "{}", err);
+        assert!(ROW_DECODE_ERRORS.load(Ordering::Relaxed) > errors_before);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_unknown_message_status_is_a_decode_error() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let suffix = Uuid::new_v4().simple().to_string();
+        let alice = users
+            .create_user(&format!("status-a-{}", suffix), "hash", "key-a")
+            .await
+            .unwrap();
+        let bob = users
+            .create_user(&format!("status-b-{}", suffix), "hash", "key-b")
+            .await
+            .unwrap();
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO messages (id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv) VALUES ($1, 0, $2, $3, 'ARCHIVED', 'Text', '\\x01', '\\x00')",
+        )
+        .bind(id)
+        .bind(alice)
+        .bind(bob)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let errors_before = ROW_DECODE_ERRORS.load(Ordering::Relaxed);
+        let err = messages.find_message(id).await.unwrap_err();
+        assert!(err.to_string().contains("status"), "{}", err);
+        assert!(ROW_DECODE_ERRORS.load(Ordering::Relaxed) > errors_before);
+
+        // A known status round-trips through the same column
+        sqlx::query("UPDATE messages SET status = 'DELIVERED' WHERE id = $1")
+            .bind(id)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(
+            messages.find_message(id).await.unwrap().unwrap().status,
+            MessageStatus::Delivered
+        );
+        for user in [alice, bob] {
+            users.delete_user(user, 0).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied, in a UTF-8 locale"]
+    async fn test_conversation_search_folds_accented_usernames() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let contacts = PgContactRepo::new(db.clone());
+        let suffix = Uuid::new_v4().simple().to_string();
+        let create = |name: String| {
+            let users = &users;
+            async move { users.create_user(&name, "hash", "key").await.unwrap() }
+        };
+        let alice = create(format!("alice-{}", suffix)).await;
+        let emile = create(format!("Émile-{}", suffix)).await;
+        let emilia = create(format!("Emilia-{}", suffix)).await;
+        contacts
+            .record_conversation_peer(alice, emile, None)
+            .await
+            .unwrap();
+        contacts
+            .record_conversation_peer(emilia, alice, None)
+            .await
+            .unwrap();
+
+        let (found, total) = contacts
+            .search_conversation_peers(alice, "éMI", 20, 0)
+            .await
+            .unwrap();
+        let (unaccented, _) = contacts
+            .search_conversation_peers(alice, "emi", 20, 0)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM users WHERE id = ANY($1)")
+            .bind(vec![alice, emile, emilia])
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert_eq!(total, 1);
+        assert_eq!(found[0].user_id, emile);
+        assert_eq!(unaccented.len(), 1);
+        assert_eq!(unaccented[0].user_id, emilia);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_preferences_keep_unknown_keys() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let username = format!("prefs-{}", Uuid::new_v4().simple());
+        let user = users.create_user(&username, "hash", "key").await.unwrap();
+        assert_eq!(
+            users.preferences(user).await.unwrap(),
+            UserPreferences::default()
+        );
+
+        // A key written by a newer server survives an update from this one
+        sqlx::query("UPDATE users SET user_preferences = '{\"future_setting\": 3}' WHERE id = $1")
+            .bind(user)
+            .execute(&db)
+            .await
+            .unwrap();
+        let before = users
+            .find_by_id(user)
+            .await
+            .unwrap()
+            .unwrap()
+            .profile_updated_at;
+        let preferences = UserPreferences {
+            delete_on_delivered: true,
+        };
+        users.set_preferences(user, &preferences).await.unwrap();
+        assert_eq!(users.preferences(user).await.unwrap(), preferences);
+        assert!(
+            users
+                .find_by_id(user)
+                .await
+                .unwrap()
+                .unwrap()
+                .profile_updated_at
+                > before
+        );
+        let stored: String = sqlx::query_scalar(
+            "SELECT user_preferences->>'future_setting' FROM users WHERE id = $1",
+        )
+        .bind(user)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(stored, "3");
+        users.delete_user(user, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_message_type_counts_split_by_direction() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let username = format!("types-{}", Uuid::new_v4().simple());
+        let user = users.create_user(&username, "hash", "key-a").await.unwrap();
+        let peer = users
+            .create_user(&format!("{}-peer", username), "hash", "key-b")
+            .await
+            .unwrap();
+        let stranger = users
+            .create_user(&format!("{}-other", username), "hash", "key-c")
+            .await
+            .unwrap();
+        for (sender_id, receiver_id, r#type) in [
+            (user, peer, "Image"),
+            (user, peer, "Image"),
+            (peer, user, "Image"),
+            (peer, user, "Text"),
+            (peer, stranger, "File"),
+        ] {
+            let message = MessageRecord {
+                id: Uuid::new_v4(),
+                timestamp: Utc::now().timestamp_millis(),
+                sender_id,
+                receiver_id,
+                status: MessageStatus::Sent,
+                r#type: r#type.to_string(),
+                encrypted_content: vec![1],
+                iv: vec![0; 12],
+                forwarded_from_id: None,
+                forward_count: 0,
+                reply_to_id: None,
+                reply_to_deleted: false,
+                encryption_version: 1,
+                device_id: None,
+                cipher_suite: "AES_256_GCM".to_string(),
+            };
+            messages
+                .insert_message_after_latest(&message)
+                .await
+                .unwrap();
+        }
+
+        let counts = messages.message_type_counts(user).await.unwrap();
+        assert_eq!(
+            counts,
+            [
+                MessageTypeCount {
+                    r#type: "Image".to_string(),
+                    sent: 2,
+                    received: 1
+                },
+                MessageTypeCount {
+                    r#type: "Text".to_string(),
+                    sent: 0,
+                    received: 1
+                },
+            ]
+        );
+        for id in [user, peer, stranger] {
+            users.delete_user(id, 0).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_invite_is_spent_with_the_registration() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let actor = AuditActor {
+            source_ip: "127.0.0.1".to_string(),
+            admin: None,
+        };
+        let now = Utc::now();
+        let invite = InviteRecord {
+            id: Uuid::new_v4(),
+            code: format!("code-{}", Uuid::new_v4().simple()),
+            note: Some("test".to_string()),
+            created_at: now,
+            expires_at: now + chrono::Duration::days(1),
+        };
+        users.create_invite(&invite, &actor).await.unwrap();
+        let taken = format!("invite-{}", Uuid::new_v4().simple());
+        let existing = users.create_user(&taken, "hash", "key-a").await.unwrap();
+
+        // A taken username rolls back and leaves the invite usable
+        let err = users
+            .create_user_with_invite(&taken, "hash", "key-b", &invite.code, now)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RepoError::Duplicate));
+        assert!(
+            users
+                .unused_invites(now)
+                .await
+                .unwrap()
+                .iter()
+                .any(|i| i.id == invite.id)
+        );
+
+        let name = format!("{}-new", taken);
+        let id = users
+            .create_user_with_invite(&name, "hash", "key-c", &invite.code, now)
+            .await
+            .unwrap()
+            .expect("unused invite");
+        let used_by: Option<Uuid> = sqlx::query_scalar("SELECT used_by FROM invites WHERE id = $1")
+            .bind(invite.id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(used_by, Some(id));
+        let again = users
+            .create_user_with_invite(
+                &format!("{}-again", taken),
+                "hash",
+                "key-d",
+                &invite.code,
+                now,
+            )
+            .await
+            .unwrap();
+        assert_eq!(again, None);
+
+        for id in [existing, id] {
+            users.delete_user(id, 0).await.unwrap();
+        }
+        sqlx::query("DELETE FROM invites WHERE id = $1")
+            .bind(invite.id)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_key_changes_are_recorded() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let actor = AuditActor {
+            source_ip: "127.0.0.1".to_string(),
+            admin: None,
+        };
+        let id = users
+            .create_user(
+                &format!("keys-{}", Uuid::new_v4().simple()),
+                "hash",
+                "key-a",
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            users.update_public_key(id, "key-b", Some(0)).await.unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            users.update_public_key(id, "key-c", Some(0)).await.unwrap(),
+            None
+        );
+
+        let change = |old: &str, new: &str| KeyFormatChange {
+            user_id: id,
+            old_public_key: old.to_string(),
+            new_public_key: new.to_string(),
+        };
+        // A key replaced since it was read is left alone
+        let changed = users
+            .rewrite_key_formats(&[change("key-a", "key-x")], &actor)
+            .await
+            .unwrap();
+        assert!(changed.is_empty());
+        let changed = users
+            .rewrite_key_formats(&[change("key-b", "key-b2")], &actor)
+            .await
+            .unwrap();
+        assert_eq!(changed, vec![id]);
+        let user = users.find_by_id(id).await.unwrap().unwrap();
+        assert_eq!((user.public_key.as_str(), user.key_version), ("key-b2", 1));
+
+        let history: Vec<(String, String, bool)> = sqlx::query_as(
+            "SELECT old_public_key, new_public_key, format_only FROM key_changes WHERE user_id = $1 ORDER BY id",
+        )
+        .bind(id)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            history,
+            vec![
+                ("key-a".to_string(), "key-b".to_string(), false),
+                ("key-b".to_string(), "key-b2".to_string(), true),
+            ]
+        );
+        users.delete_user(id, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_avatar_rewrites_skip_replaced_avatars() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let actor = AuditActor {
+            source_ip: "127.0.0.1".to_string(),
+            admin: None,
+        };
+        let id = users
+            .create_user(
+                &format!("avatars-{}", Uuid::new_v4().simple()),
+                "hash",
+                "key-a",
+            )
+            .await
+            .unwrap();
+        let limit = UsernameChangeLimit {
+            max_changes: 1,
+            since: Utc::now(),
+        };
+        users
+            .update_profile(id, None, Some((vec![1, 2, 3], "image/png")), limit)
+            .await
+            .unwrap();
+        let updated_at = users
+            .find_by_id(id)
+            .await
+            .unwrap()
+            .unwrap()
+            .profile_updated_at;
+        let page = users.avatars_after(None, i64::MAX).await.unwrap();
+        assert!(page.contains(&(id, vec![1, 2, 3])));
+
+        let rewrite = |old: Vec<u8>, new: Vec<u8>| AvatarRewrite {
+            user_id: id,
+            old_avatar: old,
+            new_avatar: new,
+        };
+        // An avatar replaced since it was read is left alone
+        let changed = users
+            .rewrite_avatars(&[rewrite(vec![9], vec![4])], &actor)
+            .await
+            .unwrap();
+        assert!(changed.is_empty());
+        let changed = users
+            .rewrite_avatars(&[rewrite(vec![1, 2, 3], vec![4, 5])], &actor)
+            .await
+            .unwrap();
+        assert_eq!(changed, vec![id]);
+        let user = users.find_by_id(id).await.unwrap().unwrap();
+        assert_eq!(user.avatar, Some(vec![4, 5]));
+        assert_eq!(user.profile_updated_at, updated_at);
+
+        let audited: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM admin_audit_log WHERE action = 'column_key.rotate' AND target_id = $1",
+        )
+        .bind(id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(audited, 1);
+        users.delete_user(id, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_reply_reports_deleted_quote() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let name = format!("quoter-{}", Uuid::new_v4().simple());
+        let alice = users.create_user(&name, "hash", "key-a").await.unwrap();
+        let bob = users
+            .create_user(&format!("{}-peer", name), "hash", "key-b")
+            .await
+            .unwrap();
+        let message = |sender_id, receiver_id, reply_to_id| MessageRecord {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now().timestamp_millis(),
+            sender_id,
+            receiver_id,
+            status: MessageStatus::Read,
+            r#type: "Text".to_string(),
+            encrypted_content: vec![1],
+            iv: vec![0; 12],
+            forwarded_from_id: None,
+            forward_count: 0,
+            reply_to_id,
+            reply_to_deleted: false,
+            encryption_version: 1,
+            device_id: None,
+            cipher_suite: "AES_256_GCM".to_string(),
+        };
+        let quoted = message(alice, bob, None);
+        messages.insert_message_after_latest(&quoted).await.unwrap();
+        let reply = message(bob, alice, Some(quoted.id));
+        messages.insert_message_after_latest(&reply).await.unwrap();
+
+        let found = messages.find_message(reply.id).await.unwrap().unwrap();
+        assert_eq!(found.reply_to_id, Some(quoted.id));
+        assert!(!found.reply_to_deleted);
+
+        assert!(
+            messages
+                .delete_read_message(quoted.id, false)
+                .await
+                .unwrap()
+        );
+        let listed = messages
+            .conversation(alice, bob, ConversationQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].reply_to_id, Some(quoted.id));
+        assert!(listed[0].reply_to_deleted);
+        users.delete_user(alice, 0).await.unwrap();
+        users.delete_user(bob, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_status_update_only_applies_to_the_status_read() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let name = format!("racer-{}", Uuid::new_v4().simple());
+        let alice = users.create_user(&name, "hash", "key-a").await.unwrap();
+        let bob = users
+            .create_user(&format!("{}-peer", name), "hash", "key-b")
+            .await
+            .unwrap();
+        let message = MessageRecord {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now().timestamp_millis(),
+            sender_id: alice,
+            receiver_id: bob,
+            status: MessageStatus::Sent,
+            r#type: "Text".to_string(),
+            encrypted_content: vec![1],
+            iv: vec![0; 12],
+            forwarded_from_id: None,
+            forward_count: 0,
+            reply_to_id: None,
+            reply_to_deleted: false,
+            encryption_version: 1,
+            device_id: None,
+            cipher_suite: "AES_256_GCM".to_string(),
+        };
+        messages
+            .insert_message_after_latest(&message)
+            .await
+            .unwrap();
+
+        // READ lands first; a DELIVERED checked against the earlier SENT must not undo it
+        assert!(
+            messages
+                .update_status(message.id, MessageStatus::Sent, MessageStatus::Read)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !messages
+                .update_status(message.id, MessageStatus::Sent, MessageStatus::Delivered)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            messages
+                .find_message(message.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .status,
+            MessageStatus::Read
+        );
+        assert!(
+            !messages
+                .update_status(Uuid::new_v4(), MessageStatus::Sent, MessageStatus::Read)
+                .await
+                .unwrap()
+        );
+
+        users.delete_user(alice, 0).await.unwrap();
+        users.delete_user(bob, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_activity_aggregates_send_counters() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let name = format!("busy-{}", Uuid::new_v4().simple());
+        let quiet = users.create_user(&name, "hash", "key-a").await.unwrap();
+        let loud = users
+            .create_user(&format!("{}-loud", name), "hash", "key-b")
+            .await
+            .unwrap();
+        // A day far ahead, so counters of other tests do not show up in the sums
+        let day = Utc::now()
+            + chrono::Duration::days(36_500 + (Uuid::new_v4().as_u128() % 10_000) as i64);
+        let windows = [
+            SendQuotaWindow {
+                name: "minute",
+                start: day,
+                limit: 100,
+            },
+            SendQuotaWindow {
+                name: "day",
+                start: day,
+                limit: 100,
+            },
+        ];
+        messages.record_send(quiet, &windows).await.unwrap();
+        for _ in 0..3 {
+            messages.record_send(loud, &windows).await.unwrap();
+        }
+
+        assert_eq!(messages.sends_since("minute", day).await.unwrap(), 4);
+        let top = messages.top_senders("day", day, 10).await.unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].user_id, top[0].sends), (loud, 3));
+        assert_eq!((top[1].user_id, top[1].sends), (quiet, 1));
+        assert_eq!(messages.top_senders("day", day, 1).await.unwrap().len(), 1);
+        assert!(
+            users
+                .registrations_since(Utc::now() - chrono::Duration::minutes(1))
+                .await
+                .unwrap()
+                >= 2
+        );
+
+        users.delete_user(quiet, 0).await.unwrap();
+        users.delete_user(loud, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_deleted_username_is_reserved_for_grace_period() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let username = format!("leaving-{}", Uuid::new_v4().simple());
+        let leaving = users.create_user(&username, "hash", "key-a").await.unwrap();
+        let peer = users
+            .create_user(&format!("{}-peer", username), "hash", "key-b")
+            .await
+            .unwrap();
+        let message = MessageRecord {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now().timestamp_millis(),
+            sender_id: peer,
+            receiver_id: leaving,
+            status: MessageStatus::Sent,
+            r#type: "Text".to_string(),
+            encrypted_content: vec![1],
+            iv: vec![0; 12],
+            forwarded_from_id: None,
+            forward_count: 0,
+            reply_to_id: None,
+            reply_to_deleted: false,
+            encryption_version: 1,
+            device_id: None,
+            cipher_suite: "AES_256_GCM".to_string(),
+        };
+        messages
+            .insert_message_after_latest(&message)
+            .await
+            .unwrap();
+
+        assert!(users.delete_user(leaving, 30).await.unwrap());
+        assert!(!users.delete_user(leaving, 30).await.unwrap());
+        assert!(messages.find_message(message.id).await.unwrap().is_none());
+
+        let now = Utc::now();
+        let available_after = users
+            .username_reserved_until(&username, now)
+            .await
+            .unwrap()
+            .expect("username should be reserved");
+        assert!(available_after > now + chrono::Duration::days(29));
+        let later = available_after + chrono::Duration::seconds(1);
+        assert!(
+            users
+                .username_reserved_until(&username, later)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(users.purge_username_reservations(later).await.unwrap() >= 1);
+        assert!(
+            users
+                .username_reserved_until(&username, now)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(peer)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_revoking_device_removes_its_message_copies() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let devices = PgDeviceRepo::new(db.clone());
+        let username = format!("devices-{}", Uuid::new_v4().simple());
+        let owner = users.create_user(&username, "hash", "key-a").await.unwrap();
+        let peer = users
+            .create_user(&format!("{}-peer", username), "hash", "key-b")
+            .await
+            .unwrap();
+        let before = users
+            .find_by_id(owner)
+            .await
+            .unwrap()
+            .unwrap()
+            .profile_updated_at;
+        let device = DeviceRecord {
+            id: Uuid::new_v4(),
+            user_id: owner,
+            name: "Phone".to_string(),
+            public_key: "device-key".to_string(),
+            created_at: Utc::now(),
+            last_active: None,
+            supported_ciphers: vec!["AES_256_GCM".to_string()],
+        };
+        devices.register_device(&device).await.unwrap();
+        assert!(matches!(
+            devices
+                .register_device(&DeviceRecord {
+                    id: Uuid::new_v4(),
+                    ..device.clone()
+                })
+                .await,
+            Err(crate::repo::RepoError::Duplicate)
+        ));
+        let registered_at = users
+            .find_by_id(owner)
+            .await
+            .unwrap()
+            .unwrap()
+            .profile_updated_at;
+        let message = MessageRecord {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now().timestamp_millis(),
+            sender_id: peer,
+            receiver_id: owner,
+            status: MessageStatus::Sent,
+            r#type: "Text".to_string(),
+            encrypted_content: vec![1],
+            iv: vec![0; 12],
+            forwarded_from_id: None,
+            forward_count: 0,
+            reply_to_id: None,
+            reply_to_deleted: false,
+            encryption_version: 1,
+            device_id: Some(device.id),
+            cipher_suite: "AES_256_GCM".to_string(),
+        };
+        messages
+            .insert_message_after_latest(&message)
+            .await
+            .unwrap();
+        let stored = messages.find_message(message.id).await.unwrap().unwrap();
+
+        assert!(!devices.revoke_device(peer, device.id).await.unwrap());
+        assert!(devices.revoke_device(owner, device.id).await.unwrap());
+        let copy_after_revoke = messages.find_message(message.id).await.unwrap();
+        sqlx::query("DELETE FROM users WHERE id = ANY($1)")
+            .bind(vec![owner, peer])
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert!(registered_at > before);
+        assert_eq!(stored.device_id, Some(device.id));
+        assert!(copy_after_revoke.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_conversation_pages_follow_the_cursor_in_both_orders() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let suffix = Uuid::new_v4().simple().to_string();
+        let alice = users
+            .create_user(&format!("page-a-{}", suffix), "hash", "key-a")
+            .await
+            .unwrap();
+        let bob = users
+            .create_user(&format!("page-b-{}", suffix), "hash", "key-b")
+            .await
+            .unwrap();
+        for (sender_id, receiver_id, timestamp) in seam_messages(alice, bob) {
+            let message = MessageRecord {
+                id: Uuid::new_v4(),
+                timestamp,
+                sender_id,
+                receiver_id,
+                status: MessageStatus::Sent,
+                r#type: "Text".to_string(),
+                encrypted_content: vec![1],
+                iv: vec![0; 12],
+                forwarded_from_id: None,
+                forward_count: 0,
+                reply_to_id: None,
+                reply_to_deleted: false,
+                encryption_version: 1,
+                device_id: None,
+                cipher_suite: "AES_256_GCM".to_string(),
+            };
+            insert_message_row(&db, &message, timestamp).await.unwrap();
+        }
+        let all: Vec<Uuid> = messages
+            .conversation(alice, bob, ConversationQuery::default())
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(all.len(), 8);
+
+        let messages = &messages;
+        assert_pages_cover_conversation(&all, |order, cursor| async move {
+            let query = ConversationQuery {
+                order,
+                cursor,
+                limit: Some(3),
+                ..Default::default()
+            };
+            let page = messages.conversation(alice, bob, query).await.unwrap();
+            let next = page.last().map(|last| MessageCursor {
+                timestamp: last.timestamp,
+                id: last.id,
+            });
+            (page.iter().map(|m| m.id).collect(), next)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_send_over_quota_is_not_counted() {
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let username = format!("quota-{}", Uuid::new_v4().simple());
+        let user = users
+            .create_user(&username, "hash", &username)
+            .await
+            .unwrap();
+        let window = |name, start, limit| SendQuotaWindow { name, start, limit };
+        let yesterday = Utc::now() - chrono::Duration::days(1);
+        let today = Utc::now();
+        let stale = [window("day", yesterday, 10)];
+        let windows = [window("minute", today, 2), window("day", today, 3)];
+
+        messages.record_send(user, &stale).await.unwrap();
+        for _ in 0..2 {
+            let outcome = messages.record_send(user, &windows).await.unwrap();
+            assert_eq!(outcome, SendQuotaOutcome::Allowed);
+        }
+        let outcome = messages.record_send(user, &windows).await.unwrap();
+        assert_eq!(outcome, SendQuotaOutcome::Exceeded(windows[0]));
+        assert_eq!(
+            messages.send_counts(user, &windows).await.unwrap(),
+            vec![2, 2]
+        );
+        // Counters older than every current window were pruned
+        assert_eq!(messages.send_counts(user, &stale).await.unwrap(), vec![0]);
+
+        // A refund takes back one send, never going below zero
+        for _ in 0..3 {
+            messages.refund_send(user, &windows).await.unwrap();
+        }
+        assert_eq!(
+            messages.send_counts(user, &windows).await.unwrap(),
+            vec![0, 0]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_delivery_receipt_outlives_message() {
+        use chrono::SubsecRound;
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let messages = PgMessageRepo::new(db.clone());
+        let username = format!("receipt-{}", Uuid::new_v4().simple());
+        let sender_id = users.create_user(&username, "hash", "key-a").await.unwrap();
+        let receiver_id = users
+            .create_user(&format!("{}-peer", username), "hash", "key-b")
+            .await
+            .unwrap();
+        let message = MessageRecord {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now().timestamp_millis(),
+            sender_id,
+            receiver_id,
+            status: MessageStatus::Read,
+            r#type: "Text".to_string(),
+            encrypted_content: vec![1],
+            iv: vec![0; 12],
+            forwarded_from_id: None,
+            forward_count: 0,
+            reply_to_id: None,
+            reply_to_deleted: false,
+            encryption_version: 1,
+            device_id: None,
+            cipher_suite: "AES_256_GCM".to_string(),
+        };
+        let timestamp = messages
+            .insert_message_after_latest(&message)
+            .await
+            .unwrap()
+            .timestamp;
+        let mut receipt = DeliveryReceiptRecord {
+            message_id: message.id,
+            sender_id,
+            receiver_id,
+            status: MessageStatus::Delivered,
+            message_timestamp: timestamp,
+            signature: "delivered".to_string(),
+            received_at: Utc::now().trunc_subsecs(3),
+        };
+        messages.save_delivery_receipt(&receipt).await.unwrap();
+        receipt.status = MessageStatus::Read;
+        receipt.signature = "read".to_string();
+        messages.save_delivery_receipt(&receipt).await.unwrap();
+        assert!(
+            messages
+                .delete_read_message(message.id, false)
+                .await
+                .unwrap()
+        );
+
+        assert_eq!(
+            messages.delivery_receipt(message.id).await.unwrap(),
+            Some(receipt)
+        );
+        for id in [sender_id, receiver_id] {
+            users.delete_user(id, 0).await.unwrap();
+        }
+        assert_eq!(messages.delivery_receipt(message.id).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_contact_request_cooldown_is_checked_by_the_insert() {
+        use chrono::Duration;
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let contacts = std::sync::Arc::new(PgContactRepo::new(db.clone()));
+        let username = format!("cooldown-{}", Uuid::new_v4().simple());
+        let alice = users
+            .create_user(&format!("{}-a", username), "hash", "key")
+            .await
+            .unwrap();
+        let bob = users
+            .create_user(&format!("{}-b", username), "hash", "key")
+            .await
+            .unwrap();
+        let created = |outcome| match outcome {
+            CreateRequestOutcome::Created(request) => request,
+            other => panic!("expected a new request, got {:?}", other),
+        };
+
+        // A decline racing a re-send: the re-send is either a duplicate or cooling down
+        let request = created(
+            contacts
+                .create_request(alice, bob, None, Utc::now())
+                .await
+                .unwrap(),
+        );
+        let decline = tokio::spawn({
+            let contacts = contacts.clone();
+            async move {
+                contacts
+                    .resolve_request(request.id, "DECLINED", false)
+                    .await
+            }
+        });
+        let resend = contacts
+            .create_request(alice, bob, None, Utc::now() - Duration::hours(1))
+            .await;
+        assert!(decline.await.unwrap().unwrap().is_some());
+        assert!(matches!(
+            resend,
+            Err(RepoError::Duplicate) | Ok(CreateRequestOutcome::CoolingDown(_))
+        ));
+
+        let outcome = contacts
+            .create_request(alice, bob, None, Utc::now() - Duration::hours(1))
+            .await
+            .unwrap();
+        let CreateRequestOutcome::CoolingDown(closed_at) = outcome else {
+            panic!("expected the cooldown, got {:?}", outcome);
+        };
+        let request = created(
+            contacts
+                .create_request(alice, bob, Some("hi"), closed_at)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(request.message.as_deref(), Some("hi"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_conversations_opened_since_counts_only_openings() {
+        use chrono::Duration;
+        let db = migrated_db().await;
+        let users = PgUserRepo::new(db.clone());
+        let contacts = PgContactRepo::new(db.clone());
+        let username = format!("opener-{}", Uuid::new_v4().simple());
+        let mut ids = Vec::new();
+        for n in 0..4 {
+            ids.push(
+                users
+                    .create_user(&format!("{}-{}", username, n), "hash", "key")
+                    .await
+                    .unwrap(),
+            );
+        }
+        let (sender, peers) = (ids[0], &ids[1..]);
+        let now = Utc::now();
+        contacts
+            .record_conversation_peer(sender, peers[0], Some(now - Duration::hours(30)))
+            .await
+            .unwrap();
+        contacts
+            .record_conversation_peer(sender, peers[1], Some(now - Duration::hours(2)))
+            .await
+            .unwrap();
+        contacts
+            .record_conversation_peer(sender, peers[2], Some(now))
+            .await
+            .unwrap();
+        // A reply records no opening, and recording an existing row again changes nothing
+        contacts
+            .record_conversation_peer(peers[0], sender, None)
+            .await
+            .unwrap();
+        contacts
+            .record_conversation_peer(sender, peers[2], None)
+            .await
+            .unwrap();
+
+        let (opened, oldest) = contacts
+            .conversations_opened_since(sender, now - Duration::hours(24))
+            .await
+            .unwrap();
+        assert_eq!(opened, 2);
+        assert_eq!(
+            oldest.map(|at| at.timestamp_millis()),
+            Some((now - Duration::hours(2)).timestamp_millis())
+        );
+        assert_eq!(
+            contacts
+                .conversations_opened_since(peers[0], now - Duration::hours(24))
+                .await
+                .unwrap()
+                .0,
+            0
+        );
+        assert!(contacts.has_conversation(peers[0], sender).await.unwrap());
+        assert!(!contacts.has_conversation(peers[0], peers[1]).await.unwrap());
+
+        let actor = AuditActor {
+            source_ip: "127.0.0.1".to_string(),
+            admin: Some("support".to_string()),
+        };
+        assert!(!users.new_conversation_limit_exempt(sender).await.unwrap());
+        assert!(
+            users
+                .set_new_conversation_limit_exempt(sender, true, &actor)
+                .await
+                .unwrap()
+        );
+        assert!(users.new_conversation_limit_exempt(sender).await.unwrap());
+        let admin: Option<String> = sqlx::query_scalar(
+            "SELECT admin_username FROM admin_audit_log WHERE target_id = $1 AND action = 'new_conversation_limit.exempt'",
+        )
+        .bind(sender)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(admin.as_deref(), Some("support"));
+        assert!(
+            !users
+                .set_new_conversation_limit_exempt(Uuid::new_v4(), true, &actor)
+                .await
+                .unwrap()
+        );
+        for id in ids {
+            users.delete_user(id, 0).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Route registration for Safe Chat backend
+//!
+//! Each resource registers its routes in its own router, merged into the app by `main`.
+//! Every path answers `OPTIONS` with the methods it allows, and a call with a method it
+//! does not allow gets `405 Method Not Allowed` with the same `Allow` header, so curl users
+//! and API explorers can discover the API. Both are derived from the registered routes
+//! by `allow_header_layer`.
+
+use crate::admin::{
+    admin_static_service, get_connection_shards, get_message_attempts, get_metrics,
+    get_user_backlog, list_backlogs, redirect_to_admin_index, require_admin,
+    static_file_etag_layer,
+};
+use crate::announcements::{create_announcement, delete_announcement};
+use crate::api::{
+    backup_messages, db_dump, delete_conversation, export_conversation, get_avatars_batch,
+    get_forward_count, get_message_receipt, get_messages_on_date, get_messages_with_user,
+    get_pinned_messages, get_unread_counts, get_user_avatar, get_user_by_id,
+    get_user_by_public_key, get_user_online, key_fingerprints, pin_message, unpin_message,
+    update_message_meta, validate_message, verify_signature,
+};
+use crate::auth::{
+    create_key_challenge, delete_account, get_message_type_counts, get_profile, get_send_limits,
+    get_username_history, impersonate_user, login, register, set_new_conversation_exemption,
+    update_profile, update_public_key, verify,
+};
+use crate::backup::{export_ndjson, import_ndjson};
+use crate::column_encryption::rotate_column_key;
+use crate::contacts::{
+    add_contact, create_contact_request, list_contact_keys, list_contact_requests, list_contacts,
+    remove_contact, respond_contact_request, sync_contacts,
+};
+use crate::conversations::{
+    get_conversation_settings, search_conversations, update_conversation_settings,
+};
+use crate::dashboard::get_dashboard;
+use crate::devices::{get_supported_ciphers, list_devices, register_device, revoke_device};
+use crate::invites::{create_invite, list_invites};
+use crate::key_formats::{get_key_stats, normalize_keys};
+use crate::preferences::{get_prefs, update_prefs, update_user_preferences};
+use crate::sealed::{delete_sealed_message, list_sealed_messages};
+use crate::selftest::run_self_test;
+use crate::state::AppState;
+
+use axum::Router;
+use axum::http::header::ALLOW;
+use axum::http::{HeaderValue, Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, patch, post, put};
+use std::sync::Arc;
+
+pub fn auth_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/auth/register", post(register))
+        .route("/auth/login", post(login))
+        .route("/auth/verify", post(verify))
+}
+
+/// The caller's profile and lookups of other users and their keys.
+pub fn user_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/profile",
+            get(get_profile).put(update_profile).delete(delete_account),
+        )
+        .route("/profile/key", put(update_public_key))
+        .route("/profile/key/challenge", post(create_key_challenge))
+        .route("/profile/username-history", get(get_username_history))
+        .route("/profile/limits", get(get_send_limits))
+        .route("/profile/message-types", get(get_message_type_counts))
+        .route(
+            "/profile/notification-prefs",
+            get(get_prefs).put(update_prefs),
+        )
+        .route("/profile/preferences", put(update_user_preferences))
+        .route("/user/:public_key", get(get_user_by_public_key))
+        .route("/user/by-id/:user_id/avatar", get(get_user_avatar))
+        .route("/user/by-id/:user_id/online", get(get_user_online))
+        .route("/user/by-id/:user_id", get(get_user_by_id))
+        .route("/users/:id/supported-ciphers", get(get_supported_ciphers))
+        .route("/avatars/batch", post(get_avatars_batch))
+        .route("/keys/fingerprints", post(key_fingerprints))
+        .route("/verify-signature", post(verify_signature))
+}
+
+/// Stored messages and the conversations they belong to.
+pub fn message_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/messages/unread-counts", get(get_unread_counts))
+        .route("/messages/backup", get(backup_messages))
+        .route("/messages/validate", post(validate_message))
+        .route("/messages/sealed", get(list_sealed_messages))
+        .route("/messages/sealed/:id", delete(delete_sealed_message))
+        .route(
+            "/messages/:user_id",
+            get(get_messages_with_user).delete(delete_conversation),
+        )
+        .route("/messages/:user_id/on-date", get(get_messages_on_date))
+        .route("/messages/:user_id/pinned", get(get_pinned_messages))
+        .route("/messages/:id/forward-count", get(get_forward_count))
+        .route("/messages/:id/receipt", get(get_message_receipt))
+        .route("/messages/:id/meta", patch(update_message_meta))
+        .route("/messages/:id/pin", post(pin_message).delete(unpin_message))
+        .route("/conversations/search", get(search_conversations))
+        .route(
+            "/conversations/:contact_id/export",
+            get(export_conversation),
+        )
+        .route(
+            "/conversations/:user_id/settings",
+            get(get_conversation_settings).put(update_conversation_settings),
+        )
+}
+
+pub fn contact_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/contacts", get(list_contacts).post(add_contact))
+        .route("/contacts/sync", post(sync_contacts))
+        .route("/contacts/keys", get(list_contact_keys))
+        .route("/contacts/:user_id", delete(remove_contact))
+        .route(
+            "/contacts/requests",
+            get(list_contact_requests).post(create_contact_request),
+        )
+        .route("/contacts/requests/:id", put(respond_contact_request))
+}
+
+pub fn device_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/devices", get(list_devices).post(register_device))
+        .route("/devices/:id", delete(revoke_device))
+}
+
+/// The `/admin` endpoints, behind the admin credentials and `ADMIN_IP_ALLOWLIST`. Any
+/// other path under `/admin` is looked up in the static admin pages.
+pub fn admin_routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    let admin = Router::new()
+        .route("/", get(redirect_to_admin_index))
+        .route("/dbdump", get(db_dump))
+        .route("/export.ndjson", get(export_ndjson))
+        .route("/import.ndjson", post(import_ndjson))
+        .route("/backlog", get(list_backlogs))
+        .route("/metrics", get(get_metrics))
+        .route("/dashboard", get(get_dashboard))
+        .route("/connections/shards", get(get_connection_shards))
+        .route("/selftest", post(run_self_test))
+        .route("/announcements", post(create_announcement))
+        .route("/announcements/:id", delete(delete_announcement))
+        .route("/invites", get(list_invites).post(create_invite))
+        .route("/keys/stats", get(get_key_stats))
+        .route("/keys/normalize", post(normalize_keys))
+        .route("/rotate-column-key", post(rotate_column_key))
+        .route("/users/:id/backlog", get(get_user_backlog))
+        .route("/users/:id/impersonate", post(impersonate_user))
+        .route(
+            "/users/:id/new-conversation-limit",
+            put(set_new_conversation_exemption),
+        )
+        .route("/messages/:id/attempts", get(get_message_attempts))
+        .fallback_service(admin_static_service())
+        .layer(middleware::from_fn(static_file_etag_layer))
+        .layer(middleware::from_fn_with_state(state, require_admin));
+    Router::new()
+        // The nested router only matches /admin itself, not the trailing slash
+        .route("/admin/", get(redirect_to_admin_index))
+        .nest("/admin", admin)
+}
+
+/// Answers `OPTIONS` with `204 No Content` and the methods the path allows, and adds
+/// `OPTIONS` to the `Allow` header of every 405.
+///
+/// No route registers an `OPTIONS` handler, so the router answers an `OPTIONS` request
+/// with a 405 listing the methods it does have; this layer turns that into the 204.
+/// Paths that are not routes keep their 404, and a CORS preflight answered by a CORS
+/// layer is not a 405, so it passes through unchanged.
+///
+/// The router only sets `Allow` after the route layers ran, so this has to wrap the
+/// whole router rather than be added with `Router::layer`.
+pub async fn allow_header_layer<B>(request: Request<B>, next: Next<B>) -> Response {
+    let is_options = request.method() == Method::OPTIONS;
+    let mut response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let Some(allow) = response
+        .headers()
+        .get(ALLOW)
+        .and_then(|allow| allow.to_str().ok())
+        .filter(|allow| !allow.is_empty())
+        .and_then(|allow| HeaderValue::from_str(&format!("{},OPTIONS", allow)).ok())
+    else {
+        return response;
+    };
+    if is_options {
+        return (StatusCode::NO_CONTENT, [(ALLOW, allow)]).into_response();
+    }
+    response.headers_mut().insert(ALLOW, allow);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::tests::fake_state;
+    use axum::body::Body;
+    use tower::{Layer, ServiceExt};
+    use uuid::Uuid;
+
+    async fn call(method: Method, uri: &str) -> Response {
+        let (state, _) = fake_state(false);
+        let app = Router::new()
+            .merge(user_routes())
+            .merge(message_routes())
+            .with_state(state);
+        let app = middleware::from_fn(allow_header_layer).layer(app);
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    fn allow(response: &Response) -> &str {
+        response.headers().get(ALLOW).unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_options_lists_allowed_methods() {
+        let response = call(Method::OPTIONS, "/profile").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(allow(&response), "GET,HEAD,PUT,DELETE,OPTIONS");
+
+        let messages = format!("/messages/{}", Uuid::new_v4());
+        let response = call(Method::OPTIONS, &messages).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(allow(&response), "GET,HEAD,DELETE,OPTIONS");
+
+        let response = call(Method::OPTIONS, "/no-such-route").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get(ALLOW).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_method_gets_allow_header() {
+        let response = call(Method::POST, "/profile").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow(&response), "GET,HEAD,PUT,DELETE,OPTIONS");
+
+        let response = call(Method::PUT, "/messages/unread-counts").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow(&response), "GET,HEAD,OPTIONS");
+    }
+}
@@ -0,0 +1,89 @@
+//! Sealed metadata module for Safe Chat backend
+//!
+//! With `FEATURE_SEALED_SENDER` enabled, a `send_message` with `sealed_metadata: true`
+//! carries its sender and timestamp only inside `encrypted_metadata`, encrypted for the
+//! receiver. The server stores such messages under a pseudonymous routing id (see
+//! `crypto::sealed_routing_id`) and keeps only the receiver and the UTC day in the clear.
+//! Receivers fetch what arrived while they were offline here and delete each message once
+//! it is stored on the device.
+//!
+//! When the flag is off these endpoints answer 404.
+
+use crate::api::extract_user_id_from_auth;
+use crate::service;
+use crate::state::AppState;
+use crate::websocket::SealedMessageNotification;
+
+use axum::extract::{Json, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+/// Authenticates the request and checks that sealed metadata is enabled.
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<Uuid, (StatusCode, &'static str)> {
+    let user_id = extract_user_id_from_auth(
+        headers,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    )
+    .inspect_err(|_| info!("Unauthorized access attempt to /messages/sealed endpoint"))?;
+    if !state.features.sealed_sender {
+        return Err((StatusCode::NOT_FOUND, "Sealed metadata is disabled"));
+    }
+    Ok(user_id)
+}
+
+/// Lists the sealed messages waiting for the authenticated user, oldest day first.
+pub async fn list_sealed_messages(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match authorize(&state, &headers) {
+        Ok(uid) => uid,
+        Err(e) => return e.into_response(),
+    };
+    match state.messages.sealed_messages_for(user_id).await {
+        Ok(messages) => {
+            let messages: Vec<SealedMessageNotification> = messages
+                .iter()
+                .map(SealedMessageNotification::from_record)
+                .collect();
+            (StatusCode::OK, Json(messages)).into_response()
+        }
+        Err(err) => {
+            info!("Database error in /messages/sealed: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// Deletes a sealed message addressed to the authenticated user.
+///
+/// Returns 204, or 404 if no such message is waiting for the user.
+pub async fn delete_sealed_message(
+    Path(message_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match authorize(&state, &headers) {
+        Ok(uid) => uid,
+        Err(e) => return e.into_response(),
+    };
+    let message_id = match Uuid::parse_str(&message_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid message_id format").into_response(),
+    };
+    match service::delete_sealed_message(state.messages.as_ref(), user_id, message_id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            info!(
+                "Deleting sealed message {} for user {} failed: {}",
+                message_id, user_id, err
+            );
+            err.into_response()
+        }
+    }
+}
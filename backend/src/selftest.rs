@@ -0,0 +1,387 @@
+//! Post-deploy self-test for Safe Chat backend
+//!
+//! `POST /admin/selftest` drives the whole message pipeline through this instance's own
+//! listener with `safechat-client`, the way two clients would: two marker users connect
+//! over WebSocket, one sends a message, the other receives it and marks it DELIVERED then
+//! READ, and the server deletes it after the read. Each stage is timed and the report
+//! names the first one that failed. The marker users are deleted afterwards whatever the
+//! outcome.
+
+use crate::auth::Claims;
+use crate::repo::MessageStatus;
+use crate::repo::RepoError;
+use crate::state::AppState;
+use crate::websocket::SendMessageData;
+
+use axum::extract::{Json, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use base64::Engine;
+use base64::engine::general_purpose;
+use futures_util::StreamExt;
+use jsonwebtoken::{EncodingKey, Header, encode};
+use safechat_client::{Client, ClientError, Connection, ServerEvent, new_message};
+use serde::Serialize;
+use serde_json::json;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, timeout};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Prefix of the marker users' usernames. `POST /auth/register` and `PUT /profile` refuse
+/// usernames starting with it, so a real account cannot take a marker user's name.
+const MARKER_USERNAME_PREFIX: &str = "selftest-marker-";
+/// Usernames of the two marker users; only one self-test can hold them at a time.
+const SENDER_USERNAME: &str = "selftest-marker-sender";
+const RECEIVER_USERNAME: &str = "selftest-marker-receiver";
+/// Longest a stage may take before it is reported as failed.
+const STAGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Lifetime of the marker users' tokens; the whole run fits well within it.
+const TOKEN_TTL_SECS: i64 = 120;
+
+/// Whether `username` is reserved for the self-test's marker users, ignoring case.
+pub fn is_marker_username(username: &str) -> bool {
+    username.to_lowercase().starts_with(MARKER_USERNAME_PREFIX)
+}
+
+#[derive(Debug, Serialize)]
+pub struct StageReport {
+    pub stage: &'static str,
+    pub ok: bool,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    /// The first stage that failed; later stages other than `cleanup` are skipped.
+    pub failed_stage: Option<&'static str>,
+    pub duration_ms: u64,
+    pub stages: Vec<StageReport>,
+}
+
+#[derive(Default)]
+struct Run {
+    stages: Vec<StageReport>,
+}
+
+impl Run {
+    /// Runs one stage under `STAGE_TIMEOUT` and records how it went.
+    async fn stage<T>(
+        &mut self,
+        stage: &'static str,
+        work: impl Future<Output = Result<T, String>>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let result = match timeout(STAGE_TIMEOUT, work).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {}s", STAGE_TIMEOUT.as_secs())),
+        };
+        let error = result.as_ref().err().cloned();
+        self.stages.push(StageReport {
+            stage,
+            ok: error.is_none(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            error,
+        });
+        result.ok()
+    }
+
+    fn report(self, started: Instant) -> SelfTestReport {
+        let failed_stage = self.stages.iter().find(|s| !s.ok).map(|s| s.stage);
+        SelfTestReport {
+            passed: failed_stage.is_none(),
+            failed_stage,
+            duration_ms: started.elapsed().as_millis() as u64,
+            stages: self.stages,
+        }
+    }
+}
+
+struct MarkerUser {
+    id: Uuid,
+    token: String,
+}
+
+fn marker_token(state: &AppState, user_id: Uuid) -> Result<String, String> {
+    let claims = Claims::new(
+        user_id,
+        (state.clock.now().timestamp() + TOKEN_TTL_SECS) as usize,
+    );
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    )
+    .map_err(|e| format!("token creation failed: {}", e))
+}
+
+/// Creates a marker user that cannot log in: its password hash is not a valid hash.
+async fn create_marker_user(state: &AppState, username: &str) -> Result<MarkerUser, String> {
+    let public_key = general_purpose::STANDARD.encode(Uuid::new_v4().as_bytes());
+    let id = state
+        .users
+        .create_user(username, "!", &public_key)
+        .await
+        .map_err(|e| match e {
+            // A concurrent run took the username after the existence check
+            RepoError::Duplicate => {
+                format!("{} already exists; another self-test is running", username)
+            }
+            e => format!("creating {} failed: {}", username, e),
+        })?;
+    let token = marker_token(state, id)?;
+    Ok(MarkerUser { id, token })
+}
+
+/// Reads events until one matches; the connection acknowledges any that ask for it. An
+/// `error` event fails the wait, since the server rejected something the test sent.
+async fn wait_for(
+    client: &mut Connection,
+    description: &str,
+    matches: impl Fn(&ServerEvent) -> bool,
+) -> Result<ServerEvent, String> {
+    while let Some(event) = client.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(ClientError::Closed(reason)) => {
+                return Err(format!(
+                    "connection closed waiting for {}: {:?}",
+                    description, reason
+                ));
+            }
+            Err(e) => {
+                return Err(format!(
+                    "connection failed waiting for {}: {}",
+                    description, e
+                ));
+            }
+        };
+        if let ServerEvent::Error(error) = &event {
+            return Err(format!(
+                "server error waiting for {}: {:?} {}",
+                description, error.code, error.message
+            ));
+        }
+        if matches(&event) {
+            return Ok(event);
+        }
+    }
+    Err(format!("connection ended waiting for {}", description))
+}
+
+/// Connects a marker user to this instance's own listener. The connection is returned
+/// once the server answered `hello`, so events sent afterwards reach it.
+async fn connect(state: &AppState, user: &MarkerUser) -> Result<Connection, String> {
+    Client::new(&format!("http://127.0.0.1:{}", state.server_port))
+        .map_err(|e| e.to_string())?
+        .with_token(&user.token)
+        .connect_ws()
+        .await
+        .map_err(|e| format!("upgrade failed: {}", e))
+}
+
+fn is_status(event: &ServerEvent, message_id: Uuid, status: MessageStatus) -> bool {
+    matches!(event, ServerEvent::StatusUpdate(update)
+        if update.message_id == message_id.to_string() && update.status == status)
+}
+
+async fn mark(
+    receiver: &mut Connection,
+    sender: &mut Connection,
+    message_id: Uuid,
+    status: MessageStatus,
+) -> Result<(), String> {
+    receiver
+        .update_status(message_id, status)
+        .await
+        .map_err(|e| format!("sending update_status failed: {}", e))?;
+    wait_for(sender, &format!("{} status", status), |e| {
+        is_status(e, message_id, status)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Stages after `auth`, stopping at the first failure.
+async fn exercise(
+    run: &mut Run,
+    state: &AppState,
+    sender: &MarkerUser,
+    receiver: &MarkerUser,
+) -> Option<()> {
+    let (mut sender_client, mut receiver_client) = run
+        .stage("upgrade", async {
+            Ok((
+                connect(state, sender).await?,
+                connect(state, receiver).await?,
+            ))
+        })
+        .await?;
+    let message_id = Uuid::new_v4();
+
+    run.stage("insert", async {
+        let message = SendMessageData {
+            message_id: message_id.to_string(),
+            ..new_message(receiver.id, "Text", b"selftest", &[0u8; 12])
+        };
+        sender_client
+            .send_message(&message)
+            .await
+            .map_err(|e| format!("sending send_message failed: {}", e))?;
+        wait_for(&mut sender_client, "SENT status", |e| {
+            is_status(e, message_id, MessageStatus::Sent)
+        })
+        .await?;
+        match state.messages.find_message(message_id).await {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err("message is not in the database".to_string()),
+            Err(e) => Err(format!("database error: {}", e)),
+        }
+    })
+    .await?;
+
+    run.stage("broadcast", async {
+        wait_for(&mut receiver_client, "new_message", |e| {
+            matches!(e, ServerEvent::NewMessage(message) if message.id == message_id.to_string())
+        })
+        .await
+        .map(|_| ())
+    })
+    .await?;
+
+    run.stage("status", async {
+        mark(
+            &mut receiver_client,
+            &mut sender_client,
+            message_id,
+            MessageStatus::Delivered,
+        )
+        .await?;
+        mark(
+            &mut receiver_client,
+            &mut sender_client,
+            message_id,
+            MessageStatus::Read,
+        )
+        .await
+    })
+    .await?;
+
+    run.stage("read_deletion", async {
+        loop {
+            match state.messages.find_message(message_id).await {
+                Ok(None) => return Ok(()),
+                Ok(Some(_)) => sleep(Duration::from_millis(250)).await,
+                Err(e) => return Err(format!("database error: {}", e)),
+            }
+        }
+    })
+    .await?;
+
+    let _ = sender_client.close().await;
+    let _ = receiver_client.close().await;
+    Some(())
+}
+
+async fn delete_marker_user(state: &AppState, user_id: Uuid) -> Result<(), String> {
+    state.connections.remove(&user_id);
+    state.relationships.remove(&user_id);
+    // No grace period: the marker usernames must be free for the next run
+    state
+        .users
+        .delete_user(user_id, 0)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("deleting user {} failed: {}", user_id, e))
+}
+
+/// Runs the self-test against this instance and reports each stage as JSON.
+///
+/// Returns 200 when every stage passed, 500 with the same report otherwise, and 409 if
+/// the marker users already exist, which means another self-test is running or a previous
+/// one could not clean up.
+pub async fn run_self_test(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    for username in [SENDER_USERNAME, RECEIVER_USERNAME] {
+        match state.users.find_by_username(username).await {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(json!({ "error": "selftest_in_progress" })),
+                )
+                    .into_response();
+            }
+            Err(e) => {
+                error!("Database error in /admin/selftest: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+            }
+        }
+    }
+
+    let started = Instant::now();
+    let mut run = Run::default();
+    let mut created: Vec<Uuid> = Vec::new();
+    let users = run
+        .stage("auth", async {
+            let sender = create_marker_user(&state, SENDER_USERNAME).await?;
+            created.push(sender.id);
+            let receiver = create_marker_user(&state, RECEIVER_USERNAME).await?;
+            created.push(receiver.id);
+            if state.require_contact_for_messages {
+                state
+                    .contacts
+                    .add_contact(receiver.id, sender.id, state.max_contacts)
+                    .await
+                    .map_err(|e| format!("adding contact failed: {}", e))?;
+            }
+            Ok((sender, receiver))
+        })
+        .await;
+    if let Some((sender, receiver)) = &users {
+        exercise(&mut run, &state, sender, receiver).await;
+    }
+    run.stage("cleanup", async {
+        for user_id in &created {
+            delete_marker_user(&state, *user_id).await?;
+        }
+        Ok(())
+    })
+    .await;
+
+    let report = run.report(started);
+    if report.passed {
+        info!("Self-test passed in {}ms", report.duration_ms);
+        (StatusCode::OK, Json(report)).into_response()
+    } else {
+        warn!("Self-test failed at stage {:?}", report.failed_stage);
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(report)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_report_names_first_failed_stage() {
+        let mut run = Run::default();
+        assert_eq!(run.stage("auth", async { Ok(()) }).await, Some(()));
+        assert_eq!(
+            run.stage("upgrade", async { Err::<(), _>("refused".to_string()) })
+                .await,
+            None
+        );
+        run.stage("cleanup", async { Err::<(), _>("also failed".to_string()) })
+            .await;
+
+        let report = run.report(Instant::now());
+        assert!(!report.passed);
+        assert_eq!(report.failed_stage, Some("upgrade"));
+        assert_eq!(report.stages[1].error.as_deref(), Some("refused"));
+    }
+}
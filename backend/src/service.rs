@@ -0,0 +1,3880 @@
+//! Service layer for Safe Chat backend
+//!
+//! Business rules shared by the REST and WebSocket handlers. Services only depend on
+//! the repository traits, so the rules can be unit-tested against the in-memory fakes.
+
+use crate::crypto::{
+    ColumnKey, ColumnKeyring, DEFAULT_CIPHER_SUITE, KeyFormat, SUPPORTED_CIPHER_SUITES,
+    decode_x509_to_raw_key, decrypt_column, encode_raw_key_to_x509, encode_url_safe,
+    encrypt_column, is_column_encrypted, is_ed25519_signature, key_fingerprint, key_format,
+    validate_x509_public_key,
+};
+use crate::repo::{
+    AddContactOutcome, AnnouncementRecord, AnnouncementRepo, AuditActor, AvatarRewrite,
+    BacklogRecord, ContactRepo, ContactRequestRecord, ConversationPeerRecord, ConversationQuery,
+    ConversationSettings, CreateRequestOutcome, DeliveryAttemptRecord, DeliveryReceiptRecord,
+    DeviceRecord, DeviceRepo, InviteRecord, KeyFormatChange, MessageCursor, MessageRecord,
+    MessageRepo, MessageStatus, RepoError, SealedMessageRecord, SendQuotaOutcome, SendQuotaWindow,
+    SenderActivity, UserRecord, UserRepo,
+};
+
+use axum::Json;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::error;
+use uuid::Uuid;
+
+/// Statuses a client may set through `update_status`.
+pub const CLIENT_SETTABLE_STATUSES: [MessageStatus; 4] = [
+    MessageStatus::Sent,
+    MessageStatus::Delivered,
+    MessageStatus::Read,
+    MessageStatus::Failed,
+];
+
+#[derive(Debug)]
+pub enum ServiceError {
+    BadRequest(String),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    /// A machine-readable conflict code, returned as `{"error": code}`.
+    Conflict(&'static str),
+    /// The action is rate limited for this many more seconds.
+    CooldownActive(i64),
+    Repo(RepoError),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::BadRequest(msg) => write!(f, "{}", msg),
+            ServiceError::Forbidden(msg) => write!(f, "{}", msg),
+            ServiceError::NotFound(msg) => write!(f, "{}", msg),
+            ServiceError::Conflict(code) => write!(f, "{}", code),
+            ServiceError::CooldownActive(secs) => write!(f, "Cooldown active for {}s", secs),
+            ServiceError::Repo(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<RepoError> for ServiceError {
+    fn from(e: RepoError) -> Self {
+        ServiceError::Repo(e)
+    }
+}
+
+impl IntoResponse for ServiceError {
+    fn into_response(self) -> Response {
+        match self {
+            ServiceError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            ServiceError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg).into_response(),
+            ServiceError::NotFound(msg) => (StatusCode::NOT_FOUND, msg).into_response(),
+            ServiceError::Conflict(code) => {
+                (StatusCode::CONFLICT, Json(json!({ "error": code }))).into_response()
+            }
+            ServiceError::CooldownActive(remaining) => {
+                rate_limited_response(std::time::Duration::from_secs(remaining.max(0) as u64))
+            }
+            ServiceError::Repo(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+            }
+        }
+    }
+}
+
+/// Requests and messages refused by a rate limit since this process started: every
+/// `rate_limited_response`, plus `send_message` refusals over the send or probe limits.
+/// Reported by `GET /admin/metrics` and the admin dashboard.
+pub static RATE_LIMIT_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// The response every rate limiter returns: `429` with a `Retry-After` header and
+/// `{"error": "rate_limited", "retry_after": <secs>}`, so clients have one throttling path.
+///
+/// The wait is rounded up to whole seconds and is at least one second.
+pub fn rate_limited_response(retry_after: std::time::Duration) -> Response {
+    RATE_LIMIT_HITS.fetch_add(1, Ordering::Relaxed);
+    let secs = retry_after
+        .as_secs()
+        .saturating_add(u64::from(retry_after.subsec_nanos() > 0))
+        .max(1);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, secs.to_string())],
+        Json(json!({ "error": "rate_limited", "retry_after": secs })),
+    )
+        .into_response()
+}
+
+fn status_rank(status: MessageStatus) -> Option<u8> {
+    match status {
+        MessageStatus::Pending => Some(0),
+        MessageStatus::Sent => Some(1),
+        MessageStatus::Delivered => Some(2),
+        MessageStatus::Read => Some(3),
+        MessageStatus::Failed => None,
+    }
+}
+
+/// Whether a message may move from `current` to `next`.
+///
+/// Statuses only move forward (PENDING → SENT → DELIVERED → READ), READ is final,
+/// FAILED can only be reported before delivery, and a FAILED message may be re-sent.
+/// Repeating the current status is allowed so clients can safely retry.
+pub fn status_transition_allowed(current: MessageStatus, next: MessageStatus) -> bool {
+    use MessageStatus::{Failed, Pending, Read, Sent};
+    if current == next {
+        return true;
+    }
+    match (current, next) {
+        (Read, _) => false,
+        (Failed, Sent) => true,
+        (Failed, _) => false,
+        (_, Failed) => matches!(current, Pending | Sent),
+        _ => match (status_rank(current), status_rank(next)) {
+            (Some(from), Some(to)) => to > from,
+            _ => false,
+        },
+    }
+}
+
+/// Result of a successful status change, used to notify both participants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusChange {
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    pub status: MessageStatus,
+}
+
+/// How often a status change is re-checked when the status moves concurrently.
+const STATUS_UPDATE_ATTEMPTS: usize = 3;
+
+/// Applies a client-requested status change to a message.
+///
+/// Only the sender or receiver may update a message, only the receiver may mark it READ,
+/// and the change must follow `status_transition_allowed`. The change is only written if
+/// the status is still the one it was checked against; when another update got there
+/// first, the message is read and checked again, so a concurrent READ is never undone.
+pub async fn update_message_status(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    message_id: Uuid,
+    requested_status: &str,
+) -> Result<StatusChange, ServiceError> {
+    let status = match requested_status.trim().to_uppercase().parse() {
+        Ok(status) if CLIENT_SETTABLE_STATUSES.contains(&status) => status,
+        _ => {
+            return Err(ServiceError::BadRequest(
+                "Invalid status. Must be one of: SENT, DELIVERED, READ, FAILED".to_string(),
+            ));
+        }
+    };
+    for _ in 0..STATUS_UPDATE_ATTEMPTS {
+        let message = messages
+            .find_message(message_id)
+            .await?
+            .ok_or(ServiceError::NotFound("Message not found"))?;
+        if user_id != message.sender_id && user_id != message.receiver_id {
+            return Err(ServiceError::Forbidden(
+                "Only the message participants can update its status",
+            ));
+        }
+        if status == MessageStatus::Read && user_id != message.receiver_id {
+            return Err(ServiceError::Forbidden(
+                "Only the message receiver can mark it as read",
+            ));
+        }
+        if !status_transition_allowed(message.status, status) {
+            return Err(ServiceError::BadRequest(format!(
+                "Invalid status transition from {} to {}",
+                message.status, status
+            )));
+        }
+        if messages
+            .update_status(message_id, message.status, status)
+            .await?
+        {
+            return Ok(StatusChange {
+                sender_id: message.sender_id,
+                receiver_id: message.receiver_id,
+                status,
+            });
+        }
+    }
+    Err(ServiceError::Conflict("status_changed"))
+}
+
+/// Statuses a receiver may sign a receipt for.
+pub const SIGNABLE_RECEIPT_STATUSES: [MessageStatus; 2] =
+    [MessageStatus::Delivered, MessageStatus::Read];
+
+/// Applies a status change the receiver signed and stores the signature as the message's
+/// receipt, replacing an earlier one. The same transition rules as `update_message_status`
+/// apply. The signature is only checked for its shape: the server does not know the
+/// receiver's signing key, which is the point of the receipt.
+pub async fn record_signed_receipt(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    message_id: Uuid,
+    requested_status: &str,
+    signature: &str,
+    now: DateTime<Utc>,
+) -> Result<(StatusChange, DeliveryReceiptRecord), ServiceError> {
+    match requested_status.trim().to_uppercase().parse() {
+        Ok(status) if SIGNABLE_RECEIPT_STATUSES.contains(&status) => {}
+        _ => {
+            return Err(ServiceError::BadRequest(
+                "Invalid status. Only DELIVERED and READ receipts can be signed".to_string(),
+            ));
+        }
+    }
+    if !is_ed25519_signature(signature) {
+        return Err(ServiceError::BadRequest(
+            "recipient_signature must be a base64 Ed25519 signature".to_string(),
+        ));
+    }
+    let message = messages
+        .find_message(message_id)
+        .await?
+        .ok_or(ServiceError::NotFound("Message not found"))?;
+    if user_id != message.receiver_id {
+        return Err(ServiceError::Forbidden(
+            "Only the message receiver can sign a receipt",
+        ));
+    }
+    let change = update_message_status(messages, user_id, message_id, requested_status).await?;
+    let receipt = DeliveryReceiptRecord {
+        message_id,
+        sender_id: message.sender_id,
+        receiver_id: message.receiver_id,
+        status: change.status,
+        message_timestamp: message.timestamp,
+        signature: signature.to_string(),
+        received_at: now,
+    };
+    messages.save_delivery_receipt(&receipt).await?;
+    Ok((change, receipt))
+}
+
+/// The signed receipt of a message, for either of its participants.
+pub async fn delivery_receipt(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    message_id: Uuid,
+) -> Result<DeliveryReceiptRecord, ServiceError> {
+    messages
+        .delivery_receipt(message_id)
+        .await?
+        .filter(|receipt| user_id == receipt.sender_id || user_id == receipt.receiver_id)
+        .ok_or(ServiceError::NotFound("Receipt not found"))
+}
+
+/// Marks the SENT messages among `fetched` that are addressed to `receiver_id` as
+/// DELIVERED, updating `fetched` to match, and returns the messages that changed.
+///
+/// Messages the receiver sent, or that have already moved past SENT, are left alone.
+pub async fn mark_fetched_delivered(
+    messages: &dyn MessageRepo,
+    receiver_id: Uuid,
+    fetched: &mut [MessageRecord],
+) -> Result<Vec<MessageRecord>, ServiceError> {
+    let ids: Vec<Uuid> = fetched
+        .iter()
+        .filter(|m| m.receiver_id == receiver_id && m.status == MessageStatus::Sent)
+        .map(|m| m.id)
+        .collect();
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let changed = messages.mark_delivered(receiver_id, &ids).await?;
+    Ok(fetched
+        .iter_mut()
+        .filter(|m| changed.contains(&m.id))
+        .map(|m| {
+            m.status = MessageStatus::Delivered;
+            m.clone()
+        })
+        .collect())
+}
+
+pub const DEFAULT_MESSAGE_PAGE_SIZE: i64 = 50;
+pub const MAX_MESSAGE_PAGE_SIZE: i64 = 200;
+
+/// One page of a conversation. `next_cursor` is set when more messages follow in the
+/// same order.
+#[derive(Debug)]
+pub struct MessagePage {
+    pub items: Vec<MessageRecord>,
+    pub next_cursor: Option<MessageCursor>,
+}
+
+/// Lists the conversation between two users matching `query`, which may limit it to an
+/// inclusive `after..=before` window of Unix millisecond timestamps.
+pub async fn conversation_messages(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    other_user_id: Uuid,
+    query: ConversationQuery,
+) -> Result<Vec<MessageRecord>, ServiceError> {
+    if let (Some(after), Some(before)) = (query.after, query.before)
+        && after >= before
+    {
+        return Err(ServiceError::BadRequest(
+            "`after` must be earlier than `before`".to_string(),
+        ));
+    }
+    Ok(messages.conversation(user_id, other_user_id, query).await?)
+}
+
+/// Lists one page of the conversation, continuing past `query.cursor` in `query.order`.
+/// `query.limit` defaults to 50 and is capped at 200.
+pub async fn conversation_page(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    other_user_id: Uuid,
+    query: ConversationQuery,
+) -> Result<MessagePage, ServiceError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE)
+        .clamp(1, MAX_MESSAGE_PAGE_SIZE);
+    // One extra row tells whether another page follows
+    let query = ConversationQuery {
+        limit: Some(limit + 1),
+        ..query
+    };
+    let mut items = conversation_messages(messages, user_id, other_user_id, query).await?;
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|last| MessageCursor {
+            timestamp: last.timestamp,
+            id: last.id,
+        })
+    } else {
+        None
+    };
+    Ok(MessagePage { items, next_cursor })
+}
+
+/// Renders a cursor for clients, which treat it as opaque.
+pub fn encode_message_cursor(cursor: &MessageCursor) -> String {
+    format!("{}_{}", cursor.timestamp, cursor.id.simple())
+}
+
+pub fn parse_message_cursor(cursor: &str) -> Result<MessageCursor, ServiceError> {
+    cursor
+        .split_once('_')
+        .and_then(|(timestamp, id)| {
+            Some(MessageCursor {
+                timestamp: timestamp.parse().ok()?,
+                id: Uuid::parse_str(id).ok()?,
+            })
+        })
+        .ok_or_else(|| ServiceError::BadRequest("Invalid cursor".to_string()))
+}
+
+/// Returns the first and last Unix millisecond of `date` in `timezone`.
+///
+/// Days are measured between local midnights, so they can be 23 or 25 hours long
+/// around DST changes. Returns `None` if the date is out of range.
+pub fn day_range_millis(date: NaiveDate, timezone: Tz) -> Option<(i64, i64)> {
+    let start_of = |day: NaiveDate| {
+        let midnight = day.and_hms_opt(0, 0, 0)?;
+        // Midnight can fall in a DST gap in some zones; the day then starts an hour later
+        timezone
+            .from_local_datetime(&midnight)
+            .earliest()
+            .or_else(|| {
+                timezone
+                    .from_local_datetime(&(midnight + Duration::hours(1)))
+                    .earliest()
+            })
+    };
+    let start = start_of(date)?;
+    let end = start_of(date.succ_opt()?)?;
+    Some((start.timestamp_millis(), end.timestamp_millis() - 1))
+}
+
+/// Default for `MAX_CONTACTS`, the most contacts a user may add themselves.
+pub const DEFAULT_MAX_CONTACTS: i64 = 5000;
+
+/// Adds `contact_id` to the contacts of `owner_id`, enforcing the contact limit.
+pub async fn add_contact(
+    contacts: &dyn ContactRepo,
+    owner_id: Uuid,
+    contact_id: Uuid,
+    max_contacts: i64,
+) -> Result<(), ServiceError> {
+    if owner_id == contact_id {
+        return Err(ServiceError::BadRequest(
+            "Cannot add yourself as a contact".to_string(),
+        ));
+    }
+    match contacts
+        .add_contact(owner_id, contact_id, max_contacts)
+        .await?
+    {
+        AddContactOutcome::Added => Ok(()),
+        AddContactOutcome::UserNotFound => Err(ServiceError::NotFound("User not found")),
+        AddContactOutcome::LimitReached => Err(ServiceError::Conflict("contact_limit_reached")),
+    }
+}
+
+/// Whether a user is online, and when their last connection closed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Presence {
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Reports the presence of `target_id` to `user_id`, who must have them as a contact.
+/// `online` is whether the target holds a WebSocket connection.
+pub async fn contact_presence(
+    users: &dyn UserRepo,
+    contacts: &dyn ContactRepo,
+    user_id: Uuid,
+    target_id: Uuid,
+    online: bool,
+) -> Result<Presence, ServiceError> {
+    if !contacts.is_contact(user_id, target_id).await? {
+        return Err(ServiceError::Forbidden(
+            "Presence is only visible for contacts",
+        ));
+    }
+    Ok(Presence {
+        online,
+        last_seen: users.last_seen(target_id).await?,
+    })
+}
+
+/// Stores a new message, keeping timestamps in a conversation strictly increasing.
+///
+/// Timestamps come from the clock of whichever instance handles the send, so a replica
+/// that is behind could file a message before ones already in the conversation. In that
+/// case the timestamp is moved to 1ms after the latest one, and the correction in
+/// milliseconds is returned. A send in the same millisecond as the latest message is also
+/// moved 1ms forward to keep its place, but that is not clock skew and returns `None`.
+pub async fn insert_message_in_order(
+    messages: &dyn MessageRepo,
+    record: &mut MessageRecord,
+) -> Result<Option<i64>, ServiceError> {
+    let stored = messages.insert_message_after_latest(record).await?;
+    let correction = stored
+        .was_behind
+        .then(|| stored.timestamp - record.timestamp);
+    record.timestamp = stored.timestamp;
+    Ok(correction)
+}
+
+/// Forward counts at or above this are shown as "forwarded many times" instead of exactly.
+pub const FORWARD_COUNT_DISPLAY_CAP: i32 = 5;
+
+/// The forward count shown to clients, capped at `FORWARD_COUNT_DISPLAY_CAP`.
+pub fn displayed_forward_count(forward_count: i32) -> i32 {
+    forward_count.min(FORWARD_COUNT_DISPLAY_CAP)
+}
+
+pub fn forwarded_many_times(forward_count: i32) -> bool {
+    forward_count >= FORWARD_COUNT_DISPLAY_CAP
+}
+
+/// Checks that `user_id` may forward `source_id`.
+///
+/// Only participants of the source conversation may forward it. A source that was already
+/// deleted after being read cannot be checked and is allowed.
+pub async fn check_forward_source(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    source_id: Uuid,
+) -> Result<(), ServiceError> {
+    match messages.find_message(source_id).await? {
+        Some(source) if user_id != source.sender_id && user_id != source.receiver_id => {
+            Err(ServiceError::NotFound("Message not found"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that a message from `sender_id` to `receiver_id` may reply to `target_id`, and
+/// returns whether the quoted message was already deleted.
+///
+/// The quoted message must belong to the same conversation. As with forwarding, a message
+/// that was already deleted after being read cannot be checked and is allowed; the reply
+/// then refers to it as deleted.
+pub async fn check_reply_target(
+    messages: &dyn MessageRepo,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    target_id: Uuid,
+) -> Result<bool, ServiceError> {
+    match messages.find_message(target_id).await? {
+        None => Ok(true),
+        Some(target)
+            if (target.sender_id == sender_id && target.receiver_id == receiver_id)
+                || (target.sender_id == receiver_id && target.receiver_id == sender_id) =>
+        {
+            Ok(false)
+        }
+        Some(_) => Err(ServiceError::NotFound(
+            "Message not found in this conversation",
+        )),
+    }
+}
+
+/// Stores a sealed message routed to `receiver_id`. Message ids are chosen by clients, so a
+/// reused id is rejected with `duplicate_message_id` instead of overwriting the first message.
+pub async fn insert_sealed_message(
+    messages: &dyn MessageRepo,
+    message: &SealedMessageRecord,
+    receiver_id: Uuid,
+) -> Result<(), ServiceError> {
+    if messages.insert_sealed_message(message, receiver_id).await? {
+        Ok(())
+    } else {
+        Err(ServiceError::Conflict("duplicate_message_id"))
+    }
+}
+
+/// Deletes a sealed message once its receiver has stored it. Messages routed to other users
+/// are reported as missing.
+pub async fn delete_sealed_message(
+    messages: &dyn MessageRepo,
+    receiver_id: Uuid,
+    message_id: Uuid,
+) -> Result<(), ServiceError> {
+    if messages
+        .delete_sealed_message(message_id, receiver_id)
+        .await?
+    {
+        Ok(())
+    } else {
+        Err(ServiceError::NotFound("Sealed message not found"))
+    }
+}
+
+/// Returns the exact forward count of a message visible to `user_id`.
+pub async fn message_forward_count(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    message_id: Uuid,
+) -> Result<i32, ServiceError> {
+    let message = messages
+        .find_message(message_id)
+        .await?
+        .ok_or(ServiceError::NotFound("Message not found"))?;
+    if user_id != message.sender_id && user_id != message.receiver_id {
+        return Err(ServiceError::NotFound("Message not found"));
+    }
+    Ok(message.forward_count)
+}
+
+/// Result of pinning or unpinning, used to notify both participants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinChange {
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    /// False when the message already had the requested pin state.
+    pub changed: bool,
+}
+
+/// Pins or unpins a message. Only the two participants of its conversation may do so.
+pub async fn set_message_pin(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    message_id: Uuid,
+    pinned: bool,
+) -> Result<PinChange, ServiceError> {
+    let message = messages
+        .find_message(message_id)
+        .await?
+        .ok_or(ServiceError::NotFound("Message not found"))?;
+    // Hide messages from other conversations rather than revealing they exist
+    if user_id != message.sender_id && user_id != message.receiver_id {
+        return Err(ServiceError::NotFound("Message not found"));
+    }
+    let changed = if pinned {
+        messages.pin(message_id, user_id).await?
+    } else {
+        messages.unpin(message_id).await?
+    };
+    if !pinned && !changed {
+        return Err(ServiceError::NotFound("Message is not pinned"));
+    }
+    Ok(PinChange {
+        sender_id: message.sender_id,
+        receiver_id: message.receiver_id,
+        changed,
+    })
+}
+
+/// Values a message's `type` may take, checked on send and on metadata updates.
+pub const MESSAGE_TYPES: [&str; 3] = ["Text", "Image", "File"];
+
+pub fn message_type_allowed(message_type: &str) -> bool {
+    MESSAGE_TYPES.contains(&message_type)
+}
+
+/// `type` of a delivery probe. Probes are relayed but never stored, so it is not one of
+/// `MESSAGE_TYPES`.
+pub const PROBE_MESSAGE_TYPE: &str = "PROBE";
+
+/// Metadata fields a sender may correct after sending. Content and participants are
+/// deliberately absent.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageMetaUpdate {
+    pub r#type: Option<String>,
+}
+
+/// Result of a metadata update, used to notify both participants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaChange {
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    /// The message's `type` after the update.
+    pub r#type: String,
+    /// False when the message already had the requested metadata.
+    pub changed: bool,
+}
+
+/// Corrects a message's metadata. Only its sender may do so.
+pub async fn update_message_meta(
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    message_id: Uuid,
+    update: &MessageMetaUpdate,
+) -> Result<MetaChange, ServiceError> {
+    let message = messages
+        .find_message(message_id)
+        .await?
+        .ok_or(ServiceError::NotFound("Message not found"))?;
+    // Hide messages from other conversations rather than revealing they exist
+    if user_id != message.sender_id && user_id != message.receiver_id {
+        return Err(ServiceError::NotFound("Message not found"));
+    }
+    if user_id != message.sender_id {
+        return Err(ServiceError::Forbidden(
+            "Only the sender can update message metadata",
+        ));
+    }
+    let Some(message_type) = update.r#type.as_deref() else {
+        return Err(ServiceError::BadRequest(
+            "No metadata fields to update".to_string(),
+        ));
+    };
+    if !message_type_allowed(message_type) {
+        return Err(ServiceError::BadRequest(format!(
+            "Invalid type {}; expected one of {}",
+            message_type,
+            MESSAGE_TYPES.join(", ")
+        )));
+    }
+    let changed = message.r#type != message_type;
+    if changed && !messages.set_message_type(message_id, message_type).await? {
+        return Err(ServiceError::NotFound("Message not found"));
+    }
+    Ok(MetaChange {
+        sender_id: message.sender_id,
+        receiver_id: message.receiver_id,
+        r#type: message_type.to_string(),
+        changed,
+    })
+}
+
+/// Maximum number of identifiers accepted by one contact sync.
+pub const MAX_SYNC_IDENTIFIERS: usize = 200;
+
+/// An address book entry a client wants to match against registered users.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum ContactIdentifier {
+    Email(String),
+    Username(String),
+}
+
+#[derive(Debug, Default)]
+pub struct ContactSyncResult {
+    pub found: Vec<UserRecord>,
+    /// Values of the identifiers that matched no user, in request order.
+    pub not_found: Vec<String>,
+}
+
+/// Matches address book identifiers against registered users.
+///
+/// Usernames are resolved with one query for the whole batch. Accounts do not store an
+/// email address, so email identifiers never match.
+pub async fn sync_contacts(
+    users: &dyn UserRepo,
+    identifiers: &[ContactIdentifier],
+) -> Result<ContactSyncResult, ServiceError> {
+    if identifiers.len() > MAX_SYNC_IDENTIFIERS {
+        return Err(ServiceError::BadRequest(format!(
+            "At most {} identifiers can be synced at once",
+            MAX_SYNC_IDENTIFIERS
+        )));
+    }
+    let mut usernames: Vec<String> = Vec::new();
+    for identifier in identifiers {
+        if let ContactIdentifier::Username(username) = identifier
+            && !usernames.contains(username)
+        {
+            usernames.push(username.clone());
+        }
+    }
+    let matches = if usernames.is_empty() {
+        Vec::new()
+    } else {
+        users.find_by_usernames(&usernames).await?
+    };
+
+    let mut result = ContactSyncResult::default();
+    for identifier in identifiers {
+        match identifier {
+            ContactIdentifier::Username(username) => {
+                match matches.iter().find(|u| &u.username == username) {
+                    Some(user) if !result.found.iter().any(|f| f.id == user.id) => {
+                        result.found.push(user.clone())
+                    }
+                    Some(_) => {}
+                    None => result.not_found.push(username.clone()),
+                }
+            }
+            ContactIdentifier::Email(email) => result.not_found.push(email.clone()),
+        }
+    }
+    Ok(result)
+}
+
+/// Maximum number of keys accepted by one `POST /keys/fingerprints`.
+pub const MAX_FINGERPRINT_KEYS: usize = 100;
+
+/// Computes the fingerprint of each key, in request order. A key that cannot be decoded
+/// gets the reason instead, without failing the rest of the batch.
+pub fn key_fingerprints(
+    public_keys: &[String],
+) -> Result<Vec<Result<String, &'static str>>, ServiceError> {
+    if public_keys.len() > MAX_FINGERPRINT_KEYS {
+        return Err(ServiceError::BadRequest(format!(
+            "At most {} keys can be fingerprinted at once",
+            MAX_FINGERPRINT_KEYS
+        )));
+    }
+    Ok(public_keys
+        .iter()
+        .map(|key| decode_x509_to_raw_key(key).map(|raw_key| key_fingerprint(&raw_key)))
+        .collect())
+}
+
+/// What an encrypted avatar is bound to, so it cannot be copied to another user.
+fn avatar_context(user_id: Uuid) -> Vec<u8> {
+    format!("users.avatar:{}", user_id).into_bytes()
+}
+
+/// An uploaded avatar as it is stored: encrypted under the current key when
+/// `COLUMN_ENCRYPTION_KEY` is set, unchanged otherwise.
+pub fn seal_avatar(column_keys: &ColumnKeyring, user_id: Uuid, avatar: Vec<u8>) -> Vec<u8> {
+    match column_keys.current() {
+        Some(key) => encrypt_column(key, &avatar_context(user_id), &avatar),
+        None => avatar,
+    }
+}
+
+/// A stored avatar as it was uploaded. Fails if it is encrypted under a key that is not
+/// in `column_keys`.
+pub fn open_avatar(
+    column_keys: &ColumnKeyring,
+    user_id: Uuid,
+    stored: Vec<u8>,
+) -> Result<Vec<u8>, &'static str> {
+    decrypt_column(column_keys, &avatar_context(user_id), stored)
+}
+
+/// Like `open_avatar`, for responses that embed the avatar: one that cannot be decrypted
+/// is left out, so a misconfigured key does not lock users out of their profile, and the
+/// reason is logged for the operator.
+pub fn readable_avatar(
+    column_keys: &ColumnKeyring,
+    user_id: Uuid,
+    stored: Option<Vec<u8>>,
+) -> Option<Vec<u8>> {
+    match open_avatar(column_keys, user_id, stored?) {
+        Ok(avatar) => Some(avatar),
+        Err(reason) => {
+            error!("Avatar of user {} cannot be decrypted: {}", user_id, reason);
+            None
+        }
+    }
+}
+
+/// Maximum number of users one `POST /avatars/batch` can ask for.
+pub const MAX_AVATAR_BATCH: usize = 50;
+
+/// The avatars of `user_ids`, each user once, in request order. Unknown users, users
+/// without an avatar, avatars over `MAX_AVATAR_BYTES` (stored before the cap) and
+/// avatars that cannot be decrypted are left out.
+pub async fn avatars(
+    users: &dyn UserRepo,
+    column_keys: &ColumnKeyring,
+    user_ids: &[Uuid],
+) -> Result<Vec<(Uuid, Vec<u8>)>, ServiceError> {
+    if user_ids.len() > MAX_AVATAR_BATCH {
+        return Err(ServiceError::BadRequest(format!(
+            "At most {} avatars can be fetched at once",
+            MAX_AVATAR_BATCH
+        )));
+    }
+    let mut ids: Vec<Uuid> = Vec::new();
+    for id in user_ids {
+        if !ids.contains(id) {
+            ids.push(*id);
+        }
+    }
+    let mut found = if ids.is_empty() {
+        Vec::new()
+    } else {
+        users.find_by_ids(&ids).await?
+    };
+    Ok(ids
+        .into_iter()
+        .filter_map(|id| {
+            let position = found.iter().position(|user| user.id == id)?;
+            let avatar = readable_avatar(column_keys, id, found.swap_remove(position).avatar)?;
+            (avatar.len() <= crate::media::MAX_AVATAR_BYTES).then_some((id, avatar))
+        })
+        .collect())
+}
+
+/// A contact's current public key, for encrypting to them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactKey {
+    pub user_id: Uuid,
+    pub public_key: String,
+    /// The key's fingerprint, or why the key cannot be used.
+    pub fingerprint: Result<String, &'static str>,
+}
+
+/// The current public key and fingerprint of each of `owner_id`'s contacts, in the order
+/// they were added. A contact without a usable key gets the reason instead of failing the
+/// rest of the list.
+pub async fn contact_keys(
+    contacts: &dyn ContactRepo,
+    owner_id: Uuid,
+) -> Result<Vec<ContactKey>, ServiceError> {
+    Ok(contacts
+        .list_contacts(owner_id)
+        .await?
+        .into_iter()
+        .map(|contact| {
+            let fingerprint = if contact.public_key.is_empty() {
+                Err("User has no public key")
+            } else {
+                decode_x509_to_raw_key(&contact.public_key).map(|raw_key| key_fingerprint(&raw_key))
+            };
+            ContactKey {
+                user_id: contact.user_id,
+                public_key: contact.public_key,
+                fingerprint,
+            }
+        })
+        .collect())
+}
+
+/// Messages a user may send in each fixed UTC minute, hour and day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendQuota {
+    pub per_minute: i64,
+    pub per_hour: i64,
+    pub per_day: i64,
+}
+
+/// Send quotas for established accounts and for accounts younger than
+/// `NEW_ACCOUNT_AGE_HOURS`, which get stricter ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendLimits {
+    pub standard: SendQuota,
+    pub new_account: SendQuota,
+}
+
+pub const NEW_ACCOUNT_AGE_HOURS: i64 = 24;
+
+pub const DEFAULT_SEND_LIMITS: SendLimits = SendLimits {
+    standard: SendQuota {
+        per_minute: 60,
+        per_hour: 1000,
+        per_day: 5000,
+    },
+    new_account: SendQuota {
+        per_minute: 10,
+        per_hour: 100,
+        per_day: 300,
+    },
+};
+
+impl SendLimits {
+    pub fn is_new_account(created_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now - created_at < Duration::hours(NEW_ACCOUNT_AGE_HOURS)
+    }
+
+    pub fn quota_for(&self, created_at: DateTime<Utc>, now: DateTime<Utc>) -> SendQuota {
+        if Self::is_new_account(created_at, now) {
+            self.new_account
+        } else {
+            self.standard
+        }
+    }
+}
+
+const SEND_WINDOWS: [(&str, i64); 3] = [("minute", 60), ("hour", 3600), ("day", 86_400)];
+
+/// The minute, hour and day windows containing `now`, aligned to the Unix epoch (so days
+/// start at midnight UTC), with their limits from `quota`.
+pub fn send_quota_windows(quota: SendQuota, now: DateTime<Utc>) -> [SendQuotaWindow; 3] {
+    let limits = [quota.per_minute, quota.per_hour, quota.per_day];
+    std::array::from_fn(|i| {
+        let (name, secs) = SEND_WINDOWS[i];
+        let start = now.timestamp().div_euclid(secs) * secs;
+        SendQuotaWindow {
+            name,
+            start: Utc.timestamp_opt(start, 0).single().unwrap_or(now),
+            limit: limits[i],
+        }
+    })
+}
+
+/// When the counter of `window` starts over.
+pub fn send_window_resets_at(window: &SendQuotaWindow) -> DateTime<Utc> {
+    let secs = SEND_WINDOWS
+        .iter()
+        .find(|(name, _)| *name == window.name)
+        .map_or(0, |(_, secs)| *secs);
+    window.start + Duration::seconds(secs)
+}
+
+/// Counts a message from `sender_id` against their send quota, unless it is addressed to
+/// themselves. Returns the window that is used up when the message must be rejected.
+pub async fn check_send_quota(
+    users: &dyn UserRepo,
+    messages: &dyn MessageRepo,
+    limits: &SendLimits,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<SendQuotaOutcome, ServiceError> {
+    if sender_id == receiver_id {
+        return Ok(SendQuotaOutcome::Allowed);
+    }
+    let sender = users
+        .find_by_id(sender_id)
+        .await?
+        .ok_or(ServiceError::NotFound("User not found"))?;
+    let windows = send_quota_windows(limits.quota_for(sender.created_at, now), now);
+    Ok(messages.record_send(sender_id, &windows).await?)
+}
+
+/// Takes back a send `check_send_quota` counted at `counted_at` when its message could not
+/// be stored, so a failed insert does not use up the sender's quota.
+pub async fn refund_send_quota(
+    messages: &dyn MessageRepo,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    counted_at: DateTime<Utc>,
+) -> Result<(), ServiceError> {
+    if sender_id == receiver_id {
+        return Ok(());
+    }
+    // Only the window names and starts are used, not the limits
+    let windows = send_quota_windows(DEFAULT_SEND_LIMITS.standard, counted_at);
+    Ok(messages.refund_send(sender_id, &windows).await?)
+}
+
+/// A user's current send quota windows and how much of each is used.
+#[derive(Debug)]
+pub struct SendUsage {
+    pub new_account: bool,
+    pub windows: Vec<(SendQuotaWindow, i64)>,
+}
+
+pub async fn send_usage(
+    users: &dyn UserRepo,
+    messages: &dyn MessageRepo,
+    limits: &SendLimits,
+    user_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<SendUsage, ServiceError> {
+    let user = users
+        .find_by_id(user_id)
+        .await?
+        .ok_or(ServiceError::NotFound("User not found"))?;
+    let windows = send_quota_windows(limits.quota_for(user.created_at, now), now);
+    let counts = messages.send_counts(user_id, &windows).await?;
+    Ok(SendUsage {
+        new_account: SendLimits::is_new_account(user.created_at, now),
+        windows: windows.into_iter().zip(counts).collect(),
+    })
+}
+
+/// How many of the most active senders the admin dashboard lists.
+pub const TOP_SENDERS_LIMIT: i64 = 10;
+
+/// Server-wide activity shown on the admin dashboard.
+///
+/// Messages are counted through the send counters, since read messages are deleted: only
+/// messages that count against the send limits are included, so messages to oneself and
+/// probes are not. Counters of previous days are pruned by a user's first send of the day,
+/// so just after midnight UTC the last hour can be undercounted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActivitySummary {
+    /// Messages sent in the hour before `now`.
+    pub messages_last_hour: i64,
+    /// Users who registered in the 24 hours before `now`.
+    pub registrations_last_day: i64,
+    /// The users who sent the most messages since midnight UTC, most first, with their
+    /// username; `None` if the account was deleted since.
+    pub top_senders: Vec<(SenderActivity, Option<String>)>,
+}
+
+pub async fn activity_summary(
+    users: &dyn UserRepo,
+    messages: &dyn MessageRepo,
+    now: DateTime<Utc>,
+) -> Result<ActivitySummary, ServiceError> {
+    // Only the window names and starts are used, not the limits
+    let [minute, _, day] = send_quota_windows(DEFAULT_SEND_LIMITS.standard, now);
+    let messages_last_hour = messages
+        .sends_since(minute.name, now - Duration::hours(1))
+        .await?;
+    let registrations_last_day = users.registrations_since(now - Duration::days(1)).await?;
+    let senders = messages
+        .top_senders(day.name, day.start, TOP_SENDERS_LIMIT)
+        .await?;
+    let ids: Vec<Uuid> = senders.iter().map(|sender| sender.user_id).collect();
+    let usernames: HashMap<Uuid, String> = users
+        .find_by_ids(&ids)
+        .await?
+        .into_iter()
+        .map(|user| (user.id, user.username))
+        .collect();
+    Ok(ActivitySummary {
+        messages_last_hour,
+        registrations_last_day,
+        top_senders: senders
+            .into_iter()
+            .map(|sender| (sender, usernames.get(&sender.user_id).cloned()))
+            .collect(),
+    })
+}
+
+/// Conversations a user may open in any 24 hours, for accounts younger than
+/// `ESTABLISHED_ACCOUNT_AGE_DAYS` and for older ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewConversationLimits {
+    pub per_day: i64,
+    pub established_per_day: i64,
+}
+
+pub const ESTABLISHED_ACCOUNT_AGE_DAYS: i64 = 7;
+
+pub const DEFAULT_NEW_CONVERSATION_LIMITS: NewConversationLimits = NewConversationLimits {
+    per_day: 20,
+    established_per_day: 100,
+};
+
+impl NewConversationLimits {
+    pub fn limit_for(&self, created_at: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+        if now - created_at < Duration::days(ESTABLISHED_ACCOUNT_AGE_DAYS) {
+            self.per_day
+        } else {
+            self.established_per_day
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewConversationCheck {
+    /// The users already have a conversation, or the receiver added the sender as a
+    /// contact; no limit applies.
+    Existing,
+    /// The message opens a conversation and is within the sender's limit.
+    Opens,
+    /// The sender already opened `limit` conversations in the last 24 hours. Another one
+    /// may be opened at `resets_at`.
+    Exceeded {
+        limit: i64,
+        resets_at: DateTime<Utc>,
+    },
+}
+
+/// Checks whether a message from `sender_id` opens a conversation and whether the sender
+/// may open another one.
+///
+/// Replies and messages within a conversation are never limited, and neither is a message
+/// to someone who added the sender as a contact. The sender's own contacts do not count,
+/// since adding a contact needs no consent from them. Users exempted by an admin are never
+/// refused.
+pub async fn check_new_conversation(
+    users: &dyn UserRepo,
+    contacts: &dyn ContactRepo,
+    limits: &NewConversationLimits,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<NewConversationCheck, ServiceError> {
+    if sender_id == receiver_id
+        || contacts.has_conversation(sender_id, receiver_id).await?
+        || contacts.is_contact(receiver_id, sender_id).await?
+    {
+        return Ok(NewConversationCheck::Existing);
+    }
+    if users.new_conversation_limit_exempt(sender_id).await? {
+        return Ok(NewConversationCheck::Opens);
+    }
+    let sender = users
+        .find_by_id(sender_id)
+        .await?
+        .ok_or(ServiceError::NotFound("User not found"))?;
+    let limit = limits.limit_for(sender.created_at, now);
+    let (opened, oldest) = contacts
+        .conversations_opened_since(sender_id, now - Duration::days(1))
+        .await?;
+    if opened < limit {
+        return Ok(NewConversationCheck::Opens);
+    }
+    Ok(NewConversationCheck::Exceeded {
+        limit,
+        resets_at: oldest.unwrap_or(now) + Duration::days(1),
+    })
+}
+
+/// Exempts a user from the new conversation limit, or ends the exemption.
+pub async fn set_new_conversation_limit_exempt(
+    users: &dyn UserRepo,
+    user_id: Uuid,
+    exempt: bool,
+    actor: &AuditActor,
+) -> Result<(), ServiceError> {
+    if !users
+        .set_new_conversation_limit_exempt(user_id, exempt, actor)
+        .await?
+    {
+        return Err(ServiceError::NotFound("User not found"));
+    }
+    Ok(())
+}
+
+/// Thresholds above which a user's undelivered backlog is reported. `None` disables a check.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BacklogThresholds {
+    pub max_count: Option<i64>,
+    pub max_age_secs: Option<i64>,
+}
+
+impl BacklogThresholds {
+    pub fn enabled(&self) -> bool {
+        self.max_count.is_some() || self.max_age_secs.is_some()
+    }
+}
+
+/// Delivery attempts kept per message; older ones are pruned as new ones are recorded.
+pub const MAX_DELIVERY_ATTEMPTS_PER_MESSAGE: i64 = 20;
+
+/// Outcomes of a delivery attempt: queued on a connection on this instance, published to
+/// Redis for another instance, no connection anywhere, or an error. A message the client
+/// did not acknowledge is `resent` on its connection, and `exhausted` once the retries are
+/// used up.
+pub const DELIVERY_DELIVERED: &str = "delivered";
+pub const DELIVERY_PUBLISHED: &str = "published";
+pub const DELIVERY_OFFLINE: &str = "offline";
+pub const DELIVERY_FAILED: &str = "failed";
+pub const DELIVERY_RESENT: &str = "resent";
+pub const DELIVERY_EXHAUSTED: &str = "exhausted";
+
+/// Where a message stands according to its latest delivery attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    NeverAttempted,
+    /// Queued on a connection of the receiver, directly or through Redis.
+    DeliveredToSocket,
+    /// The receiver had no connection; they will load the message when they come back.
+    ReceiverOffline,
+    /// The receiver's connection never acknowledged it, however often it was resent.
+    Exhausted,
+    Failed,
+}
+
+/// Condenses a message's delivery attempts, newest first, into its current state.
+pub fn delivery_state(attempts: &[DeliveryAttemptRecord]) -> DeliveryState {
+    match attempts.first().map(|attempt| attempt.outcome.as_str()) {
+        None => DeliveryState::NeverAttempted,
+        Some(DELIVERY_DELIVERED | DELIVERY_PUBLISHED | DELIVERY_RESENT) => {
+            DeliveryState::DeliveredToSocket
+        }
+        Some(DELIVERY_OFFLINE) => DeliveryState::ReceiverOffline,
+        Some(DELIVERY_EXHAUSTED) => DeliveryState::Exhausted,
+        Some(_) => DeliveryState::Failed,
+    }
+}
+
+/// Age in seconds of the oldest message in a backlog, or 0 if it is empty.
+pub fn backlog_age_secs(backlog: &BacklogRecord, now_millis: i64) -> i64 {
+    backlog
+        .oldest_timestamp
+        .map_or(0, |oldest| ((now_millis - oldest) / 1000).max(0))
+}
+
+/// Whether a backlog is over either configured threshold.
+pub fn backlog_exceeds(
+    backlog: &BacklogRecord,
+    thresholds: BacklogThresholds,
+    now_millis: i64,
+) -> bool {
+    thresholds.max_count.is_some_and(|max| backlog.count > max)
+        || thresholds
+            .max_age_secs
+            .is_some_and(|max| backlog_age_secs(backlog, now_millis) > max)
+}
+
+/// Whether an alerting backlog is back under half of every configured threshold. The gap
+/// below `backlog_exceeds` keeps a backlog hovering at a threshold from alerting repeatedly.
+pub fn backlog_recovered(
+    backlog: &BacklogRecord,
+    thresholds: BacklogThresholds,
+    now_millis: i64,
+) -> bool {
+    thresholds
+        .max_count
+        .is_none_or(|max| backlog.count <= max / 2)
+        && thresholds
+            .max_age_secs
+            .is_none_or(|max| backlog_age_secs(backlog, now_millis) <= max / 2)
+}
+
+/// Ways a participant can resolve a pending contact request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactRequestAction {
+    Accept,
+    Decline,
+    Withdraw,
+}
+
+impl ContactRequestAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action.trim().to_lowercase().as_str() {
+            "accept" => Some(ContactRequestAction::Accept),
+            "decline" => Some(ContactRequestAction::Decline),
+            "withdraw" => Some(ContactRequestAction::Withdraw),
+            _ => None,
+        }
+    }
+
+    pub fn resulting_status(self) -> &'static str {
+        match self {
+            ContactRequestAction::Accept => "ACCEPTED",
+            ContactRequestAction::Decline => "DECLINED",
+            ContactRequestAction::Withdraw => "WITHDRAWN",
+        }
+    }
+
+    /// Only the target may accept or decline; only the requester may withdraw.
+    pub fn allowed_for(self, is_requester: bool) -> bool {
+        match self {
+            ContactRequestAction::Accept | ContactRequestAction::Decline => !is_requester,
+            ContactRequestAction::Withdraw => is_requester,
+        }
+    }
+}
+
+/// Returns the seconds left before a declined or withdrawn request may be re-sent, if any.
+pub fn cooldown_remaining(
+    responded_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+    cooldown_secs: i64,
+) -> Option<i64> {
+    let elapsed = (now - responded_at).num_seconds();
+    if elapsed < cooldown_secs {
+        Some(cooldown_secs - elapsed)
+    } else {
+        None
+    }
+}
+
+/// Longest note, in characters, a requester may attach to a contact request.
+pub const MAX_CONTACT_REQUEST_MESSAGE_CHARS: usize = 500;
+
+/// Creates a contact request from `requester_id` to `target_id` with an optional note.
+///
+/// Rejects self-requests, notes over `MAX_CONTACT_REQUEST_MESSAGE_CHARS`, unknown targets,
+/// existing contacts, duplicate pending requests, and re-sends within `cooldown_secs` of a
+/// declined or withdrawn request. A blank note is stored as none.
+pub async fn create_contact_request(
+    users: &dyn UserRepo,
+    contacts: &dyn ContactRepo,
+    requester_id: Uuid,
+    target_id: Uuid,
+    message: Option<&str>,
+    cooldown_secs: i64,
+    now: DateTime<Utc>,
+) -> Result<ContactRequestRecord, ServiceError> {
+    if requester_id == target_id {
+        return Err(ServiceError::BadRequest(
+            "Cannot send a contact request to yourself".to_string(),
+        ));
+    }
+    let message = message.map(str::trim).filter(|m| !m.is_empty());
+    if message.is_some_and(|m| m.chars().count() > MAX_CONTACT_REQUEST_MESSAGE_CHARS) {
+        return Err(ServiceError::BadRequest(format!(
+            "Message must be at most {} characters",
+            MAX_CONTACT_REQUEST_MESSAGE_CHARS
+        )));
+    }
+    if users.find_by_id(target_id).await?.is_none() {
+        return Err(ServiceError::NotFound("User not found"));
+    }
+    if contacts.is_contact(requester_id, target_id).await? {
+        return Err(ServiceError::Conflict("already_contacts"));
+    }
+    let closed_after = now - Duration::seconds(cooldown_secs);
+    match contacts
+        .create_request(requester_id, target_id, message, closed_after)
+        .await
+    {
+        Ok(CreateRequestOutcome::Created(request)) => Ok(request),
+        Ok(CreateRequestOutcome::CoolingDown(responded_at)) => Err(ServiceError::CooldownActive(
+            cooldown_remaining(responded_at, now, cooldown_secs).unwrap_or(1),
+        )),
+        Err(RepoError::Duplicate) => Err(ServiceError::Conflict("request_already_pending")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Accepts, declines, or withdraws a pending contact request on behalf of `user_id`.
+pub async fn resolve_contact_request(
+    contacts: &dyn ContactRepo,
+    user_id: Uuid,
+    request_id: Uuid,
+    action: ContactRequestAction,
+) -> Result<ContactRequestRecord, ServiceError> {
+    let request = contacts
+        .find_request(request_id)
+        .await?
+        .ok_or(ServiceError::NotFound("Contact request not found"))?;
+    if user_id != request.requester_id && user_id != request.target_id {
+        return Err(ServiceError::NotFound("Contact request not found"));
+    }
+    if !action.allowed_for(user_id == request.requester_id) {
+        return Err(ServiceError::Forbidden(
+            "Not allowed to perform this action on the contact request",
+        ));
+    }
+    contacts
+        .resolve_request(
+            request_id,
+            action.resulting_status(),
+            action == ContactRequestAction::Accept,
+        )
+        .await?
+        .ok_or(ServiceError::Conflict("request_not_pending"))
+}
+
+pub const DEFAULT_CONVERSATION_PAGE_SIZE: i64 = 20;
+pub const MAX_CONVERSATION_PAGE_SIZE: i64 = 100;
+pub const MAX_CONVERSATION_QUERY_LENGTH: usize = 64;
+
+/// One conversation in a search result.
+#[derive(Debug, Clone)]
+pub struct ConversationSummary {
+    pub peer: ConversationPeerRecord,
+    /// Messages from the peer to the searching user that are not READ yet.
+    pub unread_count: i64,
+}
+
+#[derive(Debug)]
+pub struct ConversationPage {
+    pub items: Vec<ConversationSummary>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// Finds the user's conversations whose peer username contains `query`, ignoring case.
+///
+/// Case folding is Unicode-aware but accents are significant: "émile" matches "Émile",
+/// not "Emile". `limit` defaults to 20 and is capped at 100.
+pub async fn search_conversations(
+    contacts: &dyn ContactRepo,
+    messages: &dyn MessageRepo,
+    user_id: Uuid,
+    query: &str,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<ConversationPage, ServiceError> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(ServiceError::BadRequest(
+            "Search query cannot be empty".to_string(),
+        ));
+    }
+    if query.chars().count() > MAX_CONVERSATION_QUERY_LENGTH {
+        return Err(ServiceError::BadRequest(format!(
+            "Search query cannot exceed {} characters",
+            MAX_CONVERSATION_QUERY_LENGTH
+        )));
+    }
+    let limit = limit
+        .unwrap_or(DEFAULT_CONVERSATION_PAGE_SIZE)
+        .clamp(1, MAX_CONVERSATION_PAGE_SIZE);
+    let offset = offset.unwrap_or(0).max(0);
+    let (peers, total) = contacts
+        .search_conversation_peers(user_id, query, limit, offset)
+        .await?;
+    let peer_ids: Vec<Uuid> = peers.iter().map(|peer| peer.user_id).collect();
+    let unread = messages.unread_counts(user_id, Some(&peer_ids)).await?;
+    let items = peers
+        .into_iter()
+        .map(|peer| ConversationSummary {
+            unread_count: unread.get(&peer.user_id).copied().unwrap_or(0),
+            peer,
+        })
+        .collect();
+    Ok(ConversationPage {
+        items,
+        total,
+        limit,
+        offset,
+    })
+}
+
+/// Both participants' settings for a conversation, from `user_id`'s point of view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversationSettingsView {
+    pub own: ConversationSettings,
+    pub peer: ConversationSettings,
+}
+
+impl ConversationSettingsView {
+    /// READ messages are only deleted if neither participant keeps them.
+    pub fn keeps_read_messages(&self) -> bool {
+        self.own.keep_read_messages || self.peer.keep_read_messages
+    }
+}
+
+/// Loads both participants' settings for the conversation of `user_id` with `peer_id`.
+pub async fn conversation_settings(
+    contacts: &dyn ContactRepo,
+    user_id: Uuid,
+    peer_id: Uuid,
+) -> Result<ConversationSettingsView, ServiceError> {
+    Ok(ConversationSettingsView {
+        own: contacts.conversation_settings(user_id, peer_id).await?,
+        peer: contacts.conversation_settings(peer_id, user_id).await?,
+    })
+}
+
+/// Stores `user_id`'s settings for their conversation with `peer_id`, who must exist.
+pub async fn set_conversation_settings(
+    users: &dyn UserRepo,
+    contacts: &dyn ContactRepo,
+    user_id: Uuid,
+    peer_id: Uuid,
+    settings: ConversationSettings,
+) -> Result<ConversationSettingsView, ServiceError> {
+    if users.find_by_id(peer_id).await?.is_none() {
+        return Err(ServiceError::NotFound("User not found"));
+    }
+    contacts
+        .set_conversation_settings(user_id, peer_id, &settings)
+        .await?;
+    conversation_settings(contacts, user_id, peer_id).await
+}
+
+/// Announcement severities, from least to most urgent.
+pub const ANNOUNCEMENT_SEVERITIES: [&str; 3] = ["info", "warning", "critical"];
+pub const MAX_ANNOUNCEMENT_LENGTH: usize = 1000;
+
+/// Validates and stores an operator announcement, recording it in the audit log.
+///
+/// `severity` defaults to `info`. An expiry, when given, must be in the future.
+pub async fn create_announcement(
+    announcements: &dyn AnnouncementRepo,
+    message: &str,
+    severity: Option<&str>,
+    expires_at: Option<DateTime<Utc>>,
+    actor: &AuditActor,
+    now: DateTime<Utc>,
+) -> Result<AnnouncementRecord, ServiceError> {
+    let message = message.trim();
+    if message.is_empty() {
+        return Err(ServiceError::BadRequest(
+            "Announcement message cannot be empty".to_string(),
+        ));
+    }
+    if message.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+        return Err(ServiceError::BadRequest(format!(
+            "Announcement message cannot exceed {} characters",
+            MAX_ANNOUNCEMENT_LENGTH
+        )));
+    }
+    let severity = severity.unwrap_or("info");
+    if !ANNOUNCEMENT_SEVERITIES.contains(&severity) {
+        return Err(ServiceError::BadRequest(format!(
+            "Invalid severity. Must be one of: {}",
+            ANNOUNCEMENT_SEVERITIES.join(", ")
+        )));
+    }
+    if let Some(expires_at) = expires_at
+        && expires_at <= now
+    {
+        return Err(ServiceError::BadRequest(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+    let announcement = AnnouncementRecord {
+        id: Uuid::new_v4(),
+        message: message.to_string(),
+        severity: severity.to_string(),
+        created_at: now,
+        expires_at,
+    };
+    announcements
+        .create_announcement(&announcement, actor)
+        .await?;
+    Ok(announcement)
+}
+
+/// How long an invite stays usable when the admin does not set `expires_at`.
+pub const DEFAULT_INVITE_TTL_DAYS: i64 = 7;
+pub const MAX_INVITE_NOTE_LENGTH: usize = 200;
+
+/// Creates a single-use invite with a random code, recording it in the audit log.
+///
+/// `expires_at` defaults to `DEFAULT_INVITE_TTL_DAYS` after `now` and must be in the future.
+pub async fn create_invite(
+    users: &dyn UserRepo,
+    note: Option<&str>,
+    expires_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    actor: &AuditActor,
+) -> Result<InviteRecord, ServiceError> {
+    use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+    let note = note.map(str::trim).filter(|n| !n.is_empty());
+    if note.is_some_and(|n| n.chars().count() > MAX_INVITE_NOTE_LENGTH) {
+        return Err(ServiceError::BadRequest(format!(
+            "Invite note cannot exceed {} characters",
+            MAX_INVITE_NOTE_LENGTH
+        )));
+    }
+    let expires_at = expires_at.unwrap_or(now + Duration::days(DEFAULT_INVITE_TTL_DAYS));
+    if expires_at <= now {
+        return Err(ServiceError::BadRequest(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let invite = InviteRecord {
+        id: Uuid::new_v4(),
+        code: encode_url_safe(&bytes),
+        note: note.map(str::to_string),
+        created_at: now,
+        expires_at,
+    };
+    users.create_invite(&invite, actor).await?;
+    Ok(invite)
+}
+
+/// Users read per page when `key_format_stats` scans every public key.
+pub const KEY_SCAN_PAGE_SIZE: i64 = 1000;
+/// Users read per batch by `POST /admin/keys/normalize` unless it asks for another size.
+pub const DEFAULT_KEY_NORMALIZE_BATCH: i64 = 500;
+pub const MAX_KEY_NORMALIZE_BATCH: i64 = 5000;
+
+/// How the stored public keys are encoded.
+#[derive(Debug, Default, Serialize)]
+pub struct KeyFormatStats {
+    pub x509: u64,
+    pub raw: u64,
+    /// Users whose key is neither, so support can ask them to upload a new one.
+    pub unparseable: Vec<Uuid>,
+}
+
+/// Counts the stored public keys by `KeyFormat`, reading `KEY_SCAN_PAGE_SIZE` users at a
+/// time so memory use does not grow with the number of users.
+pub async fn key_format_stats(users: &dyn UserRepo) -> Result<KeyFormatStats, ServiceError> {
+    let mut stats = KeyFormatStats::default();
+    let mut after = None;
+    loop {
+        let page = users.public_keys_after(after, KEY_SCAN_PAGE_SIZE).await?;
+        for (id, key) in &page {
+            match key_format(key) {
+                KeyFormat::X509 => stats.x509 += 1,
+                KeyFormat::Raw => stats.raw += 1,
+                KeyFormat::Unparseable => stats.unparseable.push(*id),
+            }
+        }
+        if (page.len() as i64) < KEY_SCAN_PAGE_SIZE {
+            return Ok(stats);
+        }
+        after = page.last().map(|(id, _)| *id);
+    }
+}
+
+/// Result of one `normalize_key_batch`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct KeyNormalizeBatch {
+    /// Users read.
+    pub scanned: u64,
+    /// Raw keys stored as X.509.
+    pub normalized: u64,
+    /// Where the next batch starts; `None` once every user was read.
+    pub next_after: Option<Uuid>,
+}
+
+/// Reads up to `batch_size` users after `after` and stores their raw keys as X.509.
+///
+/// Each rewrite is recorded as a format-only key change. Partners are not notified and
+/// `key_version` stays the same: the key itself did not change, so nothing encrypted to
+/// it needs to be redone.
+pub async fn normalize_key_batch(
+    users: &dyn UserRepo,
+    after: Option<Uuid>,
+    batch_size: i64,
+    actor: &AuditActor,
+) -> Result<KeyNormalizeBatch, ServiceError> {
+    if !(1..=MAX_KEY_NORMALIZE_BATCH).contains(&batch_size) {
+        return Err(ServiceError::BadRequest(format!(
+            "batch_size must be between 1 and {}",
+            MAX_KEY_NORMALIZE_BATCH
+        )));
+    }
+    let page = users.public_keys_after(after, batch_size).await?;
+    let changes: Vec<KeyFormatChange> = page
+        .iter()
+        .filter(|(_, key)| key_format(key) == KeyFormat::Raw)
+        .filter_map(|(id, key)| {
+            let raw_key = decode_x509_to_raw_key(key).ok()?;
+            Some(KeyFormatChange {
+                user_id: *id,
+                old_public_key: key.clone(),
+                new_public_key: encode_raw_key_to_x509(&raw_key),
+            })
+        })
+        .collect();
+    let normalized = if changes.is_empty() {
+        0
+    } else {
+        users.rewrite_key_formats(&changes, actor).await?.len() as u64
+    };
+    let next_after = if (page.len() as i64) < batch_size {
+        None
+    } else {
+        page.last().map(|(id, _)| *id)
+    };
+    Ok(KeyNormalizeBatch {
+        scanned: page.len() as u64,
+        normalized,
+        next_after,
+    })
+}
+
+/// Users read per batch by `POST /admin/rotate-column-key` unless it asks for another size.
+pub const DEFAULT_COLUMN_ROTATE_BATCH: i64 = 500;
+pub const MAX_COLUMN_ROTATE_BATCH: i64 = 5000;
+
+/// Result of one `rotate_avatar_batch`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ColumnRotateBatch {
+    /// Avatars read.
+    pub scanned: u64,
+    /// Avatars stored under the new key.
+    pub rotated: u64,
+    /// Users whose avatar is encrypted under neither key, or fails to decrypt.
+    pub failed: Vec<Uuid>,
+    /// Where the next batch starts; `None` once every avatar was read.
+    pub next_after: Option<Uuid>,
+}
+
+/// Reads up to `batch_size` avatars after `after` and stores them encrypted under
+/// `new_key`.
+///
+/// Avatars encrypted under `old_key` are decrypted first; without `old_key` only avatars
+/// stored in the clear are encrypted, which is how an existing deployment turns
+/// encryption on. Avatars already under `new_key` are left alone, so an interrupted
+/// rotation can simply be run again.
+pub async fn rotate_avatar_batch(
+    users: &dyn UserRepo,
+    old_key: Option<&ColumnKey>,
+    new_key: &ColumnKey,
+    after: Option<Uuid>,
+    batch_size: i64,
+    actor: &AuditActor,
+) -> Result<ColumnRotateBatch, ServiceError> {
+    if !(1..=MAX_COLUMN_ROTATE_BATCH).contains(&batch_size) {
+        return Err(ServiceError::BadRequest(format!(
+            "batch_size must be between 1 and {}",
+            MAX_COLUMN_ROTATE_BATCH
+        )));
+    }
+    let page = users.avatars_after(after, batch_size).await?;
+    let old_keys = ColumnKeyring::new(old_key.cloned(), Vec::new());
+    let new_keys = ColumnKeyring::from(new_key.clone());
+    let mut rewrites = Vec::new();
+    let mut failed = Vec::new();
+    for (user_id, stored) in &page {
+        if is_column_encrypted(stored) && open_avatar(&new_keys, *user_id, stored.clone()).is_ok() {
+            continue;
+        }
+        match open_avatar(&old_keys, *user_id, stored.clone()) {
+            Ok(avatar) => rewrites.push(AvatarRewrite {
+                user_id: *user_id,
+                old_avatar: stored.clone(),
+                new_avatar: seal_avatar(&new_keys, *user_id, avatar),
+            }),
+            Err(reason) => {
+                error!("Avatar of user {} cannot be rotated: {}", user_id, reason);
+                failed.push(*user_id);
+            }
+        }
+    }
+    let rotated = if rewrites.is_empty() {
+        0
+    } else {
+        users.rewrite_avatars(&rewrites, actor).await?.len() as u64
+    };
+    let next_after = if (page.len() as i64) < batch_size {
+        None
+    } else {
+        page.last().map(|(id, _)| *id)
+    };
+    Ok(ColumnRotateBatch {
+        scanned: page.len() as u64,
+        rotated,
+        failed,
+        next_after,
+    })
+}
+
+/// Audit action recorded when an admin starts impersonating a user.
+pub const AUDIT_IMPERSONATION_START: &str = "user.impersonate";
+/// Audit action recorded for each audited request made with an impersonation token.
+pub const AUDIT_IMPERSONATED_REQUEST: &str = "user.impersonated_request";
+
+/// Records in the audit log that an admin is starting impersonation session `session_id`
+/// as `user_id`.
+pub async fn start_impersonation(
+    users: &dyn UserRepo,
+    user_id: Uuid,
+    session_id: Uuid,
+    actor: &AuditActor,
+) -> Result<(), ServiceError> {
+    if users.find_by_id(user_id).await?.is_none() {
+        return Err(ServiceError::NotFound("User not found"));
+    }
+    users
+        .record_audit(
+            AUDIT_IMPERSONATION_START,
+            user_id,
+            actor,
+            &format!("session {}", session_id),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Actions an impersonation token may not perform: they stay with the account owner.
+/// These are the changes to the account's credentials, keys and devices, such as
+/// registering a device whose key would receive the user's messages, and to its profile
+/// and preferences.
+pub fn blocked_while_impersonating(method: &Method, path: &str) -> bool {
+    let device = path
+        .strip_prefix("/devices/")
+        .is_some_and(|id| !id.is_empty() && !id.contains('/'));
+    matches!(
+        (method.as_str(), path),
+        ("PUT" | "DELETE", "/profile")
+            | ("PUT", "/profile/key")
+            | ("POST", "/profile/key/challenge")
+            | ("PUT", "/profile/preferences")
+            | ("POST", "/devices")
+    ) || (*method == Method::DELETE && device)
+}
+
+/// Requests made with an impersonation token that are written to the audit log: every
+/// mutation, and opening a WebSocket, through which messages can be sent.
+pub fn audited_while_impersonating(method: &Method, path: &str) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) || path == "/ws"
+}
+
+/// Records a request made during impersonation session `session_id` as `user_id`.
+pub async fn record_impersonated_request(
+    users: &dyn UserRepo,
+    user_id: Uuid,
+    session_id: Uuid,
+    actor: &AuditActor,
+    method: &Method,
+    path: &str,
+) -> Result<(), ServiceError> {
+    users
+        .record_audit(
+            AUDIT_IMPERSONATED_REQUEST,
+            user_id,
+            actor,
+            &format!("session {}: {} {}", session_id, method, path),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Most devices one user may register.
+pub const MAX_DEVICES_PER_USER: usize = 10;
+pub const MAX_DEVICE_NAME_LENGTH: usize = 64;
+
+/// Registers a device with its own identity key for `user_id`.
+///
+/// The key must be an X.509 X25519 public key. A user may not register the same key twice
+/// or more than `MAX_DEVICES_PER_USER` devices. `supported_ciphers` must name cipher suites
+/// from `SUPPORTED_CIPHER_SUITES`; a device that lists none supports `DEFAULT_CIPHER_SUITE`.
+pub async fn register_device(
+    devices: &dyn DeviceRepo,
+    user_id: Uuid,
+    name: &str,
+    public_key: &str,
+    supported_ciphers: &[String],
+    now: DateTime<Utc>,
+) -> Result<DeviceRecord, ServiceError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_DEVICE_NAME_LENGTH {
+        return Err(ServiceError::BadRequest(format!(
+            "Device name must be between 1 and {} characters",
+            MAX_DEVICE_NAME_LENGTH
+        )));
+    }
+    if !validate_x509_public_key(public_key) {
+        return Err(ServiceError::BadRequest(
+            "Invalid public key format".to_string(),
+        ));
+    }
+    let mut ciphers = Vec::new();
+    for cipher in supported_ciphers {
+        let cipher = cipher.trim().to_ascii_uppercase();
+        if !SUPPORTED_CIPHER_SUITES.contains(&cipher.as_str()) {
+            return Err(ServiceError::BadRequest(format!(
+                "Unsupported cipher suite {}; expected one of {}",
+                cipher,
+                SUPPORTED_CIPHER_SUITES.join(", ")
+            )));
+        }
+        if !ciphers.contains(&cipher) {
+            ciphers.push(cipher);
+        }
+    }
+    if ciphers.is_empty() {
+        ciphers.push(DEFAULT_CIPHER_SUITE.to_string());
+    }
+    if devices.list_devices(user_id).await?.len() >= MAX_DEVICES_PER_USER {
+        return Err(ServiceError::Conflict("device_limit_reached"));
+    }
+    let device = DeviceRecord {
+        id: Uuid::new_v4(),
+        user_id,
+        name: name.to_string(),
+        public_key: public_key.to_string(),
+        created_at: now,
+        last_active: None,
+        supported_ciphers: ciphers,
+    };
+    match devices.register_device(&device).await {
+        Ok(()) => Ok(device),
+        Err(RepoError::Duplicate) => Err(ServiceError::Conflict("device_already_registered")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Revokes one of `user_id`'s devices. Devices of other users are reported as not found.
+pub async fn revoke_device(
+    devices: &dyn DeviceRepo,
+    user_id: Uuid,
+    device_id: Uuid,
+) -> Result<(), ServiceError> {
+    if devices.revoke_device(user_id, device_id).await? {
+        Ok(())
+    } else {
+        Err(ServiceError::NotFound("Device not found"))
+    }
+}
+
+/// The cipher suites a sender may encrypt for a user with: those every one of the user's
+/// `devices` supports and the server `allowed`. A user without devices decrypts with their
+/// account key, which supports `DEFAULT_CIPHER_SUITE`.
+pub fn shared_cipher_suites(devices: &[DeviceRecord], allowed: &[String]) -> Vec<String> {
+    allowed
+        .iter()
+        .filter(|suite| {
+            if devices.is_empty() {
+                suite.as_str() == DEFAULT_CIPHER_SUITE
+            } else {
+                devices
+                    .iter()
+                    .all(|device| device.supported_ciphers.contains(suite))
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether `device_id` is a device of `user_id`, so a message copy may target it.
+pub async fn device_belongs_to(
+    devices: &dyn DeviceRepo,
+    user_id: Uuid,
+    device_id: Uuid,
+) -> Result<bool, RepoError> {
+    Ok(devices
+        .find_device(device_id)
+        .await?
+        .is_some_and(|device| device.user_id == user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_keypair_base64;
+    use crate::repo::UsernameChangeLimit;
+    use crate::repo::fake::{
+        FakeAnnouncementRepo, FakeContactRepo, FakeDeviceRepo, FakeMessageRepo, FakeUserRepo,
+    };
+    use crate::repo::tests::{assert_pages_cover_conversation, seam_messages};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rate_limited_response_contract() {
+        let response = rate_limited_response(std::time::Duration::from_millis(2_500));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "3");
+
+        // The cooldown uses the same contract
+        let response = ServiceError::CooldownActive(0).into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+
+    #[test]
+    fn test_status_transitions() {
+        assert!(status_transition_allowed(
+            MessageStatus::Pending,
+            MessageStatus::Sent
+        ));
+        assert!(status_transition_allowed(
+            MessageStatus::Sent,
+            MessageStatus::Delivered
+        ));
+        assert!(status_transition_allowed(
+            MessageStatus::Sent,
+            MessageStatus::Read
+        ));
+        assert!(status_transition_allowed(
+            MessageStatus::Delivered,
+            MessageStatus::Read
+        ));
+        assert!(status_transition_allowed(
+            MessageStatus::Delivered,
+            MessageStatus::Delivered
+        ));
+        assert!(status_transition_allowed(
+            MessageStatus::Sent,
+            MessageStatus::Failed
+        ));
+        assert!(status_transition_allowed(
+            MessageStatus::Failed,
+            MessageStatus::Sent
+        ));
+
+        assert!(!status_transition_allowed(
+            MessageStatus::Read,
+            MessageStatus::Delivered
+        ));
+        assert!(!status_transition_allowed(
+            MessageStatus::Delivered,
+            MessageStatus::Sent
+        ));
+        assert!(!status_transition_allowed(
+            MessageStatus::Delivered,
+            MessageStatus::Failed
+        ));
+        assert!(!status_transition_allowed(
+            MessageStatus::Failed,
+            MessageStatus::Read
+        ));
+    }
+
+    #[test]
+    fn test_message_status_wire_format_matches_database_values() {
+        for status in [
+            MessageStatus::Pending,
+            MessageStatus::Sent,
+            MessageStatus::Delivered,
+            MessageStatus::Read,
+            MessageStatus::Failed,
+        ] {
+            assert_eq!(serde_json::to_value(status).unwrap(), status.as_str());
+            assert_eq!(status.to_string().parse::<MessageStatus>(), Ok(status));
+            assert_eq!(
+                serde_json::from_value::<MessageStatus>(json!(status.as_str())).unwrap(),
+                status
+            );
+        }
+        assert!("read".parse::<MessageStatus>().is_err());
+        assert!("ARCHIVED".parse::<MessageStatus>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_receiver_marks_message_read() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+
+        let change = update_message_status(&messages, bob, id, "read")
+            .await
+            .unwrap();
+
+        assert_eq!(change.status, MessageStatus::Read);
+        assert_eq!((change.sender_id, change.receiver_id), (alice, bob));
+        assert_eq!(messages.status_of(id), Some(MessageStatus::Read));
+    }
+
+    #[tokio::test]
+    async fn test_sender_cannot_mark_message_read() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+
+        let err = update_message_status(&messages, alice, id, "READ")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ServiceError::Forbidden(_)));
+        assert_eq!(messages.status_of(id), Some(MessageStatus::Sent));
+    }
+
+    #[tokio::test]
+    async fn test_outsider_cannot_update_status() {
+        let messages = FakeMessageRepo::new();
+        let id = messages.seed_message(Uuid::new_v4(), Uuid::new_v4(), MessageStatus::Sent);
+
+        let err = update_message_status(&messages, Uuid::new_v4(), id, "DELIVERED")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ServiceError::Forbidden(_)));
+    }
+
+    #[tokio::test]
+    async fn test_status_cannot_move_backwards() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Delivered);
+
+        let err = update_message_status(&messages, bob, id, "SENT")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+        assert_eq!(messages.status_of(id), Some(MessageStatus::Delivered));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_status_rejected() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+
+        let err = update_message_status(&messages, bob, id, "PENDING")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_signed_receipt_is_kept_for_both_participants() {
+        use base64::Engine;
+        use base64::engine::general_purpose;
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message_at(alice, bob, MessageStatus::Delivered, 1_718_000_000_000);
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let signed = format!("{}READ1718000000000", id);
+        let signature =
+            general_purpose::STANDARD.encode(signing_key.sign(signed.as_bytes()).to_bytes());
+        let now = Utc::now();
+
+        let (change, receipt) = record_signed_receipt(&messages, bob, id, "read", &signature, now)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            (change.sender_id, change.status),
+            (alice, MessageStatus::Read)
+        );
+        assert_eq!(messages.status_of(id), Some(MessageStatus::Read));
+        assert_eq!(receipt.message_timestamp, 1_718_000_000_000);
+        for user in [alice, bob] {
+            assert_eq!(
+                delivery_receipt(&messages, user, id).await.unwrap(),
+                receipt
+            );
+        }
+        let err = delivery_receipt(&messages, Uuid::new_v4(), id)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::NotFound(_)));
+
+        // The sender checks the stored signature against the receiver's signing key
+        let public_key = general_purpose::STANDARD.encode(signing_key.verifying_key().to_bytes());
+        let stored = general_purpose::STANDARD
+            .decode(&receipt.signature)
+            .unwrap();
+        let signed = format!(
+            "{}{}{}",
+            receipt.message_id, receipt.status, receipt.message_timestamp
+        );
+        assert_eq!(
+            crate::crypto::verify_ed25519_signature(&public_key, signed.as_bytes(), &stored),
+            Ok(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signed_receipt_rejections() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+        let signature = "A".repeat(86) + "==";
+
+        let cases = [
+            (bob, "SENT", signature.as_str()),
+            (bob, "READ", "AAAA"),
+            (alice, "READ", signature.as_str()),
+        ];
+        for (user, status, signature) in cases {
+            let result =
+                record_signed_receipt(&messages, user, id, status, signature, Utc::now()).await;
+            assert!(
+                matches!(
+                    result,
+                    Err(ServiceError::BadRequest(_) | ServiceError::Forbidden(_))
+                ),
+                "{} {} was accepted",
+                status,
+                signature
+            );
+        }
+        assert_eq!(messages.status_of(id), Some(MessageStatus::Sent));
+        assert!(messages.delivery_receipt(id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_only_sender_can_update_meta() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+        let image = MessageMetaUpdate {
+            r#type: Some("Image".to_string()),
+        };
+
+        let err = update_message_meta(&messages, Uuid::new_v4(), id, &image)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::NotFound(_)));
+        let err = update_message_meta(&messages, bob, id, &image)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::Forbidden(_)));
+
+        let change = update_message_meta(&messages, alice, id, &image)
+            .await
+            .unwrap();
+        assert!(change.changed);
+        assert_eq!(
+            messages.find_message(id).await.unwrap().unwrap().r#type,
+            "Image"
+        );
+        let again = update_message_meta(&messages, alice, id, &image)
+            .await
+            .unwrap();
+        assert!(!again.changed);
+    }
+
+    #[tokio::test]
+    async fn test_meta_update_validates_type() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+        let update = |t: &str| MessageMetaUpdate {
+            r#type: Some(t.to_string()),
+        };
+
+        let err = update_message_meta(&messages, alice, id, &update("Video"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+        let err = update_message_meta(&messages, alice, id, &MessageMetaUpdate { r#type: None })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+        assert!(serde_json::from_str::<MessageMetaUpdate>(r#"{"receiver_id":"x"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_participants_can_pin() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+
+        let err = set_message_pin(&messages, Uuid::new_v4(), id, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::NotFound(_)));
+
+        let change = set_message_pin(&messages, bob, id, true).await.unwrap();
+        assert!(change.changed);
+        let again = set_message_pin(&messages, alice, id, true).await.unwrap();
+        assert!(!again.changed);
+        set_message_pin(&messages, alice, id, false).await.unwrap();
+        let err = set_message_pin(&messages, alice, id, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_conversation_range_filters_messages() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+        let timestamp = messages.find_message(id).await.unwrap().unwrap().timestamp;
+
+        let range = |after, before| ConversationQuery {
+            after,
+            before,
+            ..Default::default()
+        };
+        let within = conversation_messages(
+            &messages,
+            bob,
+            alice,
+            range(Some(timestamp), Some(timestamp + 1)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(within.len(), 1);
+        let later = conversation_messages(&messages, bob, alice, range(Some(timestamp + 1), None))
+            .await
+            .unwrap();
+        assert!(later.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_conversation_range_must_be_ordered() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let query = ConversationQuery {
+            after: Some(10),
+            before: Some(10),
+            ..Default::default()
+        };
+        let err = conversation_messages(&messages, alice, bob, query)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_conversation_pages_meet_without_gaps_in_both_orders() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        for (from, to, timestamp) in seam_messages(alice, bob) {
+            messages.seed_message_at(from, to, MessageStatus::Sent, timestamp);
+        }
+        let all = conversation_messages(&messages, alice, bob, ConversationQuery::default())
+            .await
+            .unwrap();
+        let all: Vec<Uuid> = all.iter().map(|m| m.id).collect();
+
+        let messages = &messages;
+        let (asc, desc) = assert_pages_cover_conversation(&all, |order, cursor| async move {
+            let query = ConversationQuery {
+                order,
+                cursor,
+                limit: Some(2),
+                ..Default::default()
+            };
+            let page = conversation_page(messages, alice, bob, query)
+                .await
+                .unwrap();
+            // Cursors survive the round trip through their client form
+            let next = page
+                .next_cursor
+                .map(|next| parse_message_cursor(&encode_message_cursor(&next)).unwrap());
+            (page.items.iter().map(|m| m.id).collect(), next)
+        })
+        .await;
+        // Eight messages in pages of two: both directions cut at the same places
+        let mirrored: Vec<Vec<Uuid>> = asc
+            .iter()
+            .rev()
+            .map(|page| page.iter().rev().copied().collect())
+            .collect();
+        assert_eq!(desc, mirrored);
+        assert_eq!(asc.len(), 4);
+
+        assert!(parse_message_cursor("100").is_err());
+        assert!(parse_message_cursor("x_00000000000000000000000000000000").is_err());
+    }
+
+    #[test]
+    fn test_day_range_in_timezone() {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 7).unwrap();
+        let (start, end) = day_range_millis(date, chrono_tz::Europe::Brussels).unwrap();
+        // Brussels is UTC+2 in May
+        assert_eq!(
+            start,
+            Utc.with_ymd_and_hms(2024, 5, 6, 22, 0, 0)
+                .unwrap()
+                .timestamp_millis()
+        );
+        assert_eq!(end - start + 1, 24 * 3_600_000);
+
+        // The spring-forward day is an hour shorter
+        let dst = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let (start, end) = day_range_millis(dst, chrono_tz::Europe::Brussels).unwrap();
+        assert_eq!(end - start + 1, 23 * 3_600_000);
+    }
+
+    #[tokio::test]
+    async fn test_backlog_counts_only_sent_messages() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        messages.seed_message(alice, bob, MessageStatus::Sent);
+        messages.seed_message(carol, bob, MessageStatus::Sent);
+        messages.seed_message(alice, bob, MessageStatus::Delivered);
+        messages.seed_message(bob, carol, MessageStatus::Sent);
+
+        assert_eq!(messages.backlog_for(bob).await.unwrap().count, 2);
+        assert_eq!(messages.backlog_for(alice).await.unwrap().count, 0);
+        let top = messages.largest_backlogs(1).await.unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].user_id, bob);
+    }
+
+    #[tokio::test]
+    async fn test_delivery_attempts_are_capped_and_condensed() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let oldest = messages.seed_message_at(alice, bob, MessageStatus::Sent, 1_000);
+        messages.seed_message_at(alice, bob, MessageStatus::Sent, 2_000);
+        assert_eq!(
+            messages.backlog_for(bob).await.unwrap().oldest_message_id,
+            Some(oldest)
+        );
+        assert_eq!(delivery_state(&[]), DeliveryState::NeverAttempted);
+
+        let start = Utc::now();
+        for i in 0..MAX_DELIVERY_ATTEMPTS_PER_MESSAGE + 5 {
+            let attempt = DeliveryAttemptRecord {
+                message_id: oldest,
+                attempt_at: start + Duration::seconds(i),
+                channel: "ws".to_string(),
+                outcome: DELIVERY_OFFLINE.to_string(),
+                error: None,
+            };
+            messages
+                .record_delivery_attempt(&attempt, MAX_DELIVERY_ATTEMPTS_PER_MESSAGE)
+                .await
+                .unwrap();
+        }
+        let failed = DeliveryAttemptRecord {
+            attempt_at: start + Duration::hours(1),
+            outcome: DELIVERY_FAILED.to_string(),
+            error: Some("connection reset".to_string()),
+            ..messages.delivery_attempts(oldest).await.unwrap()[0].clone()
+        };
+        messages
+            .record_delivery_attempt(&failed, MAX_DELIVERY_ATTEMPTS_PER_MESSAGE)
+            .await
+            .unwrap();
+
+        // Only the newest attempts are kept, newest first
+        let attempts = messages.delivery_attempts(oldest).await.unwrap();
+        assert_eq!(attempts.len() as i64, MAX_DELIVERY_ATTEMPTS_PER_MESSAGE);
+        assert_eq!(attempts[0], failed);
+        assert_eq!(
+            attempts.last().unwrap().attempt_at,
+            start + Duration::seconds(6)
+        );
+        assert_eq!(delivery_state(&attempts), DeliveryState::Failed);
+    }
+
+    #[test]
+    fn test_backlog_thresholds() {
+        let now = 1_000_000;
+        let backlog = BacklogRecord {
+            user_id: Uuid::new_v4(),
+            count: 5,
+            oldest_timestamp: Some(now - 120_000),
+            oldest_message_id: None,
+        };
+        assert_eq!(backlog_age_secs(&backlog, now), 120);
+        assert!(!backlog_exceeds(
+            &backlog,
+            BacklogThresholds::default(),
+            now
+        ));
+
+        let by_count = BacklogThresholds {
+            max_count: Some(4),
+            max_age_secs: None,
+        };
+        assert!(backlog_exceeds(&backlog, by_count, now));
+        let by_age = BacklogThresholds {
+            max_count: Some(10),
+            max_age_secs: Some(300),
+        };
+        assert!(!backlog_exceeds(&backlog, by_age, now));
+        assert!(backlog_exceeds(&backlog, by_age, now + 200_000));
+
+        // Between half the threshold and the threshold, a backlog neither alerts nor recovers
+        assert!(!backlog_recovered(&backlog, by_count, now));
+        let by_count = BacklogThresholds {
+            max_count: Some(10),
+            max_age_secs: None,
+        };
+        assert!(!backlog_exceeds(&backlog, by_count, now));
+        assert!(backlog_recovered(&backlog, by_count, now));
+        assert!(!backlog_recovered(&backlog, by_age, now + 100_000));
+        assert!(backlog_recovered(&backlog, by_age, now));
+    }
+
+    #[tokio::test]
+    async fn test_only_participants_can_forward() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob, mallory) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+
+        assert!(check_forward_source(&messages, bob, id).await.is_ok());
+        assert!(matches!(
+            check_forward_source(&messages, mallory, id).await,
+            Err(ServiceError::NotFound(_))
+        ));
+        // Read messages are deleted, so a missing source cannot be checked
+        assert!(
+            check_forward_source(&messages, mallory, Uuid::new_v4())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replies_stay_in_their_conversation() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Read);
+
+        // Either participant may quote it
+        assert!(!check_reply_target(&messages, bob, alice, id).await.unwrap());
+        assert!(!check_reply_target(&messages, alice, bob, id).await.unwrap());
+        assert!(matches!(
+            check_reply_target(&messages, alice, carol, id).await,
+            Err(ServiceError::NotFound(_))
+        ));
+        // A deleted message is quoted as a tombstone
+        messages.delete_read_message(id, false).await.unwrap();
+        assert!(
+            check_reply_target(&messages, alice, carol, id)
+                .await
+                .unwrap()
+        );
+    }
+
+    fn message_at(sender_id: Uuid, receiver_id: Uuid, timestamp: i64) -> MessageRecord {
+        MessageRecord {
+            id: Uuid::new_v4(),
+            timestamp,
+            sender_id,
+            receiver_id,
+            status: MessageStatus::Pending,
+            r#type: "Text".to_string(),
+            encrypted_content: vec![1, 2, 3],
+            iv: vec![0; 12],
+            forwarded_from_id: None,
+            forward_count: 0,
+            reply_to_id: None,
+            reply_to_deleted: false,
+            encryption_version: 1,
+            device_id: None,
+            cipher_suite: "AES_256_GCM".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skewed_clock_keeps_conversation_ordered() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let first = messages.seed_message(alice, bob, MessageStatus::Sent);
+        let latest = messages
+            .find_message(first)
+            .await
+            .unwrap()
+            .unwrap()
+            .timestamp;
+
+        // Bob's reply is handled by an instance whose clock is 5 seconds behind
+        let mut skewed = message_at(bob, alice, latest - 5_000);
+        let correction = insert_message_in_order(&messages, &mut skewed)
+            .await
+            .unwrap();
+        assert_eq!(correction, Some(5_001));
+        assert_eq!(skewed.timestamp, latest + 1);
+
+        // A send in the same millisecond keeps its place without counting as skew
+        let mut same_ms = message_at(alice, bob, latest + 1);
+        let correction = insert_message_in_order(&messages, &mut same_ms)
+            .await
+            .unwrap();
+        assert_eq!(correction, None);
+        assert_eq!(same_ms.timestamp, latest + 2);
+
+        let mut on_time = message_at(alice, bob, latest + 60_000);
+        let correction = insert_message_in_order(&messages, &mut on_time)
+            .await
+            .unwrap();
+        assert_eq!(correction, None);
+
+        let conversation = messages
+            .conversation(alice, bob, ConversationQuery::default())
+            .await
+            .unwrap();
+        let ids: Vec<Uuid> = conversation.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![first, skewed.id, same_ms.id, on_time.id]);
+        assert!(
+            conversation
+                .windows(2)
+                .all(|w| w[0].timestamp < w[1].timestamp)
+        );
+
+        // Other conversations are not affected
+        let mut elsewhere = message_at(alice, Uuid::new_v4(), latest - 5_000);
+        let correction = insert_message_in_order(&messages, &mut elsewhere)
+            .await
+            .unwrap();
+        assert_eq!(correction, None);
+    }
+
+    #[tokio::test]
+    async fn test_forward_count_is_capped_for_display() {
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = messages.seed_message(alice, bob, MessageStatus::Sent);
+        for _ in 0..7 {
+            assert!(messages.increment_forward_count(id).await.unwrap());
+        }
+
+        let count = message_forward_count(&messages, bob, id).await.unwrap();
+        assert_eq!(count, 7);
+        assert_eq!(displayed_forward_count(count), FORWARD_COUNT_DISPLAY_CAP);
+        assert!(forwarded_many_times(count));
+        assert!(!forwarded_many_times(4));
+        assert!(matches!(
+            message_forward_count(&messages, Uuid::new_v4(), id).await,
+            Err(ServiceError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_contact_sync_matches_usernames() {
+        let users = FakeUserRepo::new();
+        let alice = users.seed_user("alice");
+        let identifiers: Vec<ContactIdentifier> = serde_json::from_value(json!([
+            { "type": "username", "value": "alice" },
+            { "type": "username", "value": "alice" },
+            { "type": "username", "value": "nobody" },
+            { "type": "email", "value": "bob@example.com" },
+        ]))
+        .unwrap();
+
+        let result = sync_contacts(&users, &identifiers).await.unwrap();
+        assert_eq!(result.found.len(), 1);
+        assert_eq!(result.found[0].id, alice);
+        assert_eq!(result.not_found, vec!["nobody", "bob@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_contact_sync_rejects_oversized_batches() {
+        let users = FakeUserRepo::new();
+        let identifiers =
+            vec![ContactIdentifier::Username("x".to_string()); MAX_SYNC_IDENTIFIERS + 1];
+        assert!(matches!(
+            sync_contacts(&users, &identifiers).await,
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_key_fingerprints_report_invalid_keys_per_item() {
+        let valid = crate::crypto::encode_raw_key_to_x509(&[7u8; 32]);
+        let keys = vec![valid, "not base64!".to_string(), "AAAA".to_string()];
+        let results = key_fingerprints(&keys).unwrap();
+        assert_eq!(results[0], Ok(key_fingerprint(&[7u8; 32])));
+        assert_eq!(results[1], Err("Invalid base64 encoding"));
+        assert_eq!(results[2], Err("Invalid X.509 key length"));
+
+        let too_many = vec![keys[0].clone(); MAX_FINGERPRINT_KEYS + 1];
+        assert!(matches!(
+            key_fingerprints(&too_many),
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_avatars_skip_users_without_one() {
+        let users = FakeUserRepo::new();
+        let (alice, bob) = (users.seed_user("alice"), users.seed_user("bob"));
+        let png = b"\x89PNG\r\n\x1a\n".to_vec();
+        users
+            .update_profile(
+                alice,
+                None,
+                Some((png.clone(), "image/png")),
+                UsernameChangeLimit {
+                    max_changes: 1,
+                    since: Utc::now(),
+                },
+            )
+            .await
+            .unwrap();
+
+        let result = avatars(
+            &users,
+            &ColumnKeyring::default(),
+            &[bob, alice, Uuid::new_v4(), alice],
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, vec![(alice, png)]);
+
+        let too_many = vec![alice; MAX_AVATAR_BATCH + 1];
+        assert!(matches!(
+            avatars(&users, &ColumnKeyring::default(), &too_many).await,
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_conversation_settings_need_both_participants_to_delete() {
+        let users = Arc::new(FakeUserRepo::new());
+        let contacts = FakeContactRepo::new(users.clone());
+        let (alice, bob) = (users.seed_user("alice"), users.seed_user("bob"));
+        let settings = conversation_settings(&contacts, alice, bob).await.unwrap();
+        assert!(!settings.keeps_read_messages());
+
+        let keep = ConversationSettings {
+            keep_read_messages: true,
+        };
+        let settings =
+            set_conversation_settings(users.as_ref(), &contacts, bob, alice, keep.clone())
+                .await
+                .unwrap();
+        assert_eq!(settings.own, keep);
+        assert!(settings.keeps_read_messages());
+        // Seen from the other side, it is the peer's setting
+        let settings = conversation_settings(&contacts, alice, bob).await.unwrap();
+        assert_eq!(settings.peer, keep);
+        assert!(!settings.own.keep_read_messages);
+        assert!(settings.keeps_read_messages());
+
+        assert!(matches!(
+            set_conversation_settings(users.as_ref(), &contacts, alice, Uuid::new_v4(), keep).await,
+            Err(ServiceError::NotFound(_))
+        ));
+    }
+
+    const TEST_QUOTA: SendQuota = SendQuota {
+        per_minute: 2,
+        per_hour: 3,
+        per_day: 4,
+    };
+
+    async fn send_at(
+        messages: &FakeMessageRepo,
+        user: Uuid,
+        at: DateTime<Utc>,
+    ) -> SendQuotaOutcome {
+        messages
+            .record_send(user, &send_quota_windows(TEST_QUOTA, at))
+            .await
+            .unwrap()
+    }
+
+    fn exceeded_window(outcome: SendQuotaOutcome) -> Option<&'static str> {
+        match outcome {
+            SendQuotaOutcome::Allowed => None,
+            SendQuotaOutcome::Exceeded(window) => Some(window.name),
+        }
+    }
+
+    #[test]
+    fn test_send_windows_are_aligned_to_utc() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 10, 59, 30).unwrap();
+        let [minute, hour, day] = send_quota_windows(TEST_QUOTA, now);
+        assert_eq!(
+            minute.start,
+            Utc.with_ymd_and_hms(2024, 3, 1, 10, 59, 0).unwrap()
+        );
+        assert_eq!(
+            hour.start,
+            Utc.with_ymd_and_hms(2024, 3, 1, 10, 0, 0).unwrap()
+        );
+        assert_eq!(
+            day.start,
+            Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            send_window_resets_at(&minute),
+            Utc.with_ymd_and_hms(2024, 3, 1, 11, 0, 0).unwrap()
+        );
+        assert_eq!(
+            send_window_resets_at(&day),
+            Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_quota_resets_at_each_window_boundary() {
+        let messages = FakeMessageRepo::new();
+        let user = Uuid::new_v4();
+        let at = |h, m, s| Utc.with_ymd_and_hms(2024, 3, 1, h, m, s).unwrap();
+
+        // Minute: the third send in 10:00 is refused, the next minute allows it
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, at(10, 0, 10)).await),
+            None
+        );
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, at(10, 0, 20)).await),
+            None
+        );
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, at(10, 0, 59)).await),
+            Some("minute")
+        );
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, at(10, 1, 0)).await),
+            None
+        );
+
+        // Hour: three sends used, a refused send is not counted
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, at(10, 59, 59)).await),
+            Some("hour")
+        );
+        let windows = send_quota_windows(TEST_QUOTA, at(10, 59, 59));
+        assert_eq!(
+            messages.send_counts(user, &windows).await.unwrap(),
+            vec![0, 3, 3]
+        );
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, at(11, 0, 0)).await),
+            None
+        );
+
+        // Day: four sends used until midnight UTC
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, at(23, 59, 59)).await),
+            Some("day")
+        );
+        let next_day = Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            exceeded_window(send_at(&messages, user, next_day).await),
+            None
+        );
+
+        // Other users have their own counters
+        assert_eq!(
+            exceeded_window(send_at(&messages, Uuid::new_v4(), at(23, 59, 59)).await),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_accounts_get_stricter_limits_and_self_messages_are_exempt() {
+        let users = FakeUserRepo::new();
+        let messages = FakeMessageRepo::new();
+        let limits = SendLimits {
+            standard: SendQuota {
+                per_minute: 5,
+                ..TEST_QUOTA
+            },
+            new_account: SendQuota {
+                per_minute: 1,
+                ..TEST_QUOTA
+            },
+        };
+        let sender = users.seed_user("sender");
+        let receiver = users.seed_user("receiver");
+        let now = Utc::now();
+
+        for _ in 0..3 {
+            let outcome = check_send_quota(&users, &messages, &limits, sender, sender, now).await;
+            assert_eq!(outcome.unwrap(), SendQuotaOutcome::Allowed);
+        }
+        let outcome = check_send_quota(&users, &messages, &limits, sender, receiver, now).await;
+        assert_eq!(outcome.unwrap(), SendQuotaOutcome::Allowed);
+        let outcome = check_send_quota(&users, &messages, &limits, sender, receiver, now).await;
+        assert_eq!(exceeded_window(outcome.unwrap()), Some("minute"));
+        let usage = send_usage(&users, &messages, &limits, sender, now)
+            .await
+            .unwrap();
+        assert!(usage.new_account);
+        assert_eq!(usage.windows[0].0.limit, 1);
+        assert_eq!(usage.windows[0].1, 1);
+
+        // A day later the same account gets the standard limits
+        let later = now + Duration::hours(NEW_ACCOUNT_AGE_HOURS);
+        assert_eq!(limits.quota_for(now, later), limits.standard);
+        let usage = send_usage(&users, &messages, &limits, sender, later)
+            .await
+            .unwrap();
+        assert!(!usage.new_account);
+        assert_eq!(usage.windows[0].0.limit, 5);
+    }
+
+    #[tokio::test]
+    async fn test_new_conversations_are_limited_per_day() {
+        let users = Arc::new(FakeUserRepo::new());
+        let contacts = FakeContactRepo::new(users.clone());
+        let limits = NewConversationLimits {
+            per_day: 2,
+            established_per_day: 3,
+        };
+        let sender = users.seed_user("sender");
+        let peers: Vec<Uuid> = (0..5)
+            .map(|n| users.seed_user(&format!("peer{}", n)))
+            .collect();
+        let start = Utc::now();
+        let check = |receiver: Uuid, now: DateTime<Utc>| {
+            let (users, contacts) = (users.clone(), &contacts);
+            async move {
+                check_new_conversation(users.as_ref(), contacts, &limits, sender, receiver, now)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        for (n, peer) in peers[..2].iter().enumerate() {
+            let at = start + Duration::hours(n as i64);
+            assert_eq!(check(*peer, at).await, NewConversationCheck::Opens);
+            contacts
+                .record_conversation_peer(sender, *peer, Some(at))
+                .await
+                .unwrap();
+        }
+        let later = start + Duration::hours(3);
+        assert_eq!(
+            check(peers[2], later).await,
+            NewConversationCheck::Exceeded {
+                limit: 2,
+                resets_at: start + Duration::days(1),
+            }
+        );
+        // Existing conversations, replies and self-messages are not limited
+        assert_eq!(check(peers[0], later).await, NewConversationCheck::Existing);
+        contacts
+            .record_conversation_peer(peers[3], sender, None)
+            .await
+            .unwrap();
+        assert_eq!(check(peers[3], later).await, NewConversationCheck::Existing);
+        assert_eq!(check(sender, later).await, NewConversationCheck::Existing);
+        // Being the receiver's contact counts as an existing conversation, unlike adding them
+        contacts.add_contact(sender, peers[2], 10).await.unwrap();
+        assert!(matches!(
+            check(peers[2], later).await,
+            NewConversationCheck::Exceeded { .. }
+        ));
+        contacts.add_contact(peers[2], sender, 10).await.unwrap();
+        assert_eq!(check(peers[2], later).await, NewConversationCheck::Existing);
+
+        // The first opening leaves the window after 24 hours
+        let next_day = start + Duration::days(1);
+        assert_eq!(check(peers[4], next_day).await, NewConversationCheck::Opens);
+        // Established accounts get the higher limit
+        let established = start + Duration::days(ESTABLISHED_ACCOUNT_AGE_DAYS);
+        assert_eq!(limits.limit_for(start, established), 3);
+
+        let actor = AuditActor {
+            source_ip: "10.0.0.1".to_string(),
+            admin: None,
+        };
+        set_new_conversation_limit_exempt(users.as_ref(), sender, true, &actor)
+            .await
+            .unwrap();
+        assert_eq!(check(peers[4], later).await, NewConversationCheck::Opens);
+        assert_eq!(
+            users.audit_log().last().unwrap(),
+            &(
+                "new_conversation_limit.exempt".to_string(),
+                sender,
+                "exempt=true".to_string()
+            )
+        );
+        let missing =
+            set_new_conversation_limit_exempt(users.as_ref(), Uuid::new_v4(), true, &actor).await;
+        assert!(matches!(missing, Err(ServiceError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_contact_request_action_permissions() {
+        assert!(ContactRequestAction::Accept.allowed_for(false));
+        assert!(!ContactRequestAction::Accept.allowed_for(true));
+        assert!(ContactRequestAction::Decline.allowed_for(false));
+        assert!(!ContactRequestAction::Decline.allowed_for(true));
+        assert!(ContactRequestAction::Withdraw.allowed_for(true));
+        assert!(!ContactRequestAction::Withdraw.allowed_for(false));
+        assert_eq!(
+            ContactRequestAction::parse(" Accept "),
+            Some(ContactRequestAction::Accept)
+        );
+        assert_eq!(ContactRequestAction::parse("block"), None);
+    }
+
+    #[test]
+    fn test_cooldown_remaining() {
+        let now = Utc::now();
+        let responded_at = now - chrono::Duration::seconds(100);
+        assert_eq!(cooldown_remaining(responded_at, now, 300), Some(200));
+        assert_eq!(cooldown_remaining(responded_at, now, 100), None);
+        assert_eq!(cooldown_remaining(responded_at, now, 0), None);
+    }
+
+    fn contact_fixture() -> (Arc<FakeUserRepo>, FakeContactRepo, Uuid, Uuid) {
+        let users = Arc::new(FakeUserRepo::new());
+        let alice = users.seed_user("alice");
+        let bob = users.seed_user("bob");
+        let contacts = FakeContactRepo::new(users.clone());
+        (users, contacts, alice, bob)
+    }
+
+    #[tokio::test]
+    async fn test_contact_limit() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        let carol = users.seed_user("carol");
+
+        add_contact(&contacts, alice, bob, 1).await.unwrap();
+        // Re-adding an existing contact does not count against the limit
+        add_contact(&contacts, alice, bob, 1).await.unwrap();
+        let err = add_contact(&contacts, alice, carol, 1).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ServiceError::Conflict("contact_limit_reached")
+        ));
+        assert!(!contacts.is_contact(alice, carol).await.unwrap());
+
+        assert!(matches!(
+            add_contact(&contacts, alice, alice, 10).await,
+            Err(ServiceError::BadRequest(_))
+        ));
+        assert!(matches!(
+            add_contact(&contacts, alice, Uuid::new_v4(), 10).await,
+            Err(ServiceError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_impersonation_is_audited() {
+        let users = FakeUserRepo::new();
+        let alice = users.seed_user("alice");
+        let (session, actor) = (
+            Uuid::new_v4(),
+            AuditActor {
+                source_ip: "10.0.0.1".to_string(),
+                admin: None,
+            },
+        );
+        start_impersonation(&users, alice, session, &actor)
+            .await
+            .unwrap();
+        assert!(matches!(
+            start_impersonation(&users, Uuid::new_v4(), session, &actor).await,
+            Err(ServiceError::NotFound(_))
+        ));
+        record_impersonated_request(&users, alice, session, &actor, &Method::POST, "/contacts")
+            .await
+            .unwrap();
+        let log = users.audit_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].0, AUDIT_IMPERSONATION_START);
+        assert_eq!(
+            log[1],
+            (
+                AUDIT_IMPERSONATED_REQUEST.to_string(),
+                alice,
+                format!("session {}: POST /contacts", session),
+            )
+        );
+
+        assert!(blocked_while_impersonating(&Method::DELETE, "/profile"));
+        assert!(blocked_while_impersonating(&Method::PUT, "/profile/key"));
+        assert!(blocked_while_impersonating(
+            &Method::POST,
+            "/profile/key/challenge"
+        ));
+        assert!(!blocked_while_impersonating(&Method::GET, "/profile"));
+        assert!(!blocked_while_impersonating(&Method::POST, "/contacts"));
+        assert!(!blocked_while_impersonating(&Method::GET, "/devices"));
+        assert!(audited_while_impersonating(&Method::POST, "/contacts"));
+        assert!(audited_while_impersonating(&Method::GET, "/ws"));
+        assert!(!audited_while_impersonating(&Method::GET, "/profile"));
+    }
+
+    #[tokio::test]
+    async fn test_contact_keys_mark_unusable_keys() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        let key = generate_keypair_base64();
+        let carol = users.create_user("carol", "hash", &key).await.unwrap();
+        add_contact(&contacts, alice, carol, 10).await.unwrap();
+        add_contact(&contacts, alice, bob, 10).await.unwrap();
+
+        let keys = contact_keys(&contacts, alice).await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            (keys[0].user_id, keys[0].public_key.as_str()),
+            (carol, key.as_str())
+        );
+        assert_eq!(
+            keys[0].fingerprint,
+            Ok(key_fingerprint(&decode_x509_to_raw_key(&key).unwrap()))
+        );
+        // bob was seeded without a key
+        assert_eq!(keys[1].user_id, bob);
+        assert_eq!(keys[1].fingerprint, Err("User has no public key"));
+        assert!(contact_keys(&contacts, bob).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_presence_limited_to_contacts() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        let err = contact_presence(users.as_ref(), &contacts, alice, bob, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::Forbidden(_)));
+
+        add_contact(&contacts, alice, bob, 10).await.unwrap();
+        let presence = contact_presence(users.as_ref(), &contacts, alice, bob, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            presence,
+            Presence {
+                online: true,
+                last_seen: None
+            }
+        );
+
+        let seen = Utc::now();
+        users.set_last_seen(bob, seen).await.unwrap();
+        let presence = contact_presence(users.as_ref(), &contacts, alice, bob, false)
+            .await
+            .unwrap();
+        assert_eq!(presence.last_seen, Some(seen));
+        // Contacts are one-way: bob has not added alice
+        assert!(
+            contact_presence(users.as_ref(), &contacts, bob, alice, false)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accepting_request_creates_reciprocal_contacts() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        let request = create_contact_request(
+            users.as_ref(),
+            &contacts,
+            alice,
+            bob,
+            None,
+            3600,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+
+        // Only the target can accept
+        let err =
+            resolve_contact_request(&contacts, alice, request.id, ContactRequestAction::Accept)
+                .await
+                .unwrap_err();
+        assert!(matches!(err, ServiceError::Forbidden(_)));
+
+        let accepted =
+            resolve_contact_request(&contacts, bob, request.id, ContactRequestAction::Accept)
+                .await
+                .unwrap();
+        assert_eq!(accepted.status, "ACCEPTED");
+        assert!(contacts.is_contact(alice, bob).await.unwrap());
+        assert!(contacts.is_contact(bob, alice).await.unwrap());
+
+        let err =
+            resolve_contact_request(&contacts, bob, request.id, ContactRequestAction::Decline)
+                .await
+                .unwrap_err();
+        assert!(matches!(err, ServiceError::Conflict("request_not_pending")));
+    }
+
+    #[tokio::test]
+    async fn test_one_pending_request_per_pair() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        create_contact_request(
+            users.as_ref(),
+            &contacts,
+            alice,
+            bob,
+            None,
+            3600,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+
+        let err = create_contact_request(
+            users.as_ref(),
+            &contacts,
+            bob,
+            alice,
+            None,
+            3600,
+            Utc::now(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ServiceError::Conflict("request_already_pending")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_contact_request_message() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        let too_long = "x".repeat(MAX_CONTACT_REQUEST_MESSAGE_CHARS + 1);
+        let err = create_contact_request(
+            users.as_ref(),
+            &contacts,
+            alice,
+            bob,
+            Some(&too_long),
+            3600,
+            Utc::now(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+
+        let request = create_contact_request(
+            users.as_ref(),
+            &contacts,
+            alice,
+            bob,
+            Some("  hi, it's Alice "),
+            3600,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.message.as_deref(), Some("hi, it's Alice"));
+        let stored = contacts.find_request(request.id).await.unwrap().unwrap();
+        assert_eq!(stored.message, request.message);
+
+        let carol = users.seed_user("carol");
+        let request = create_contact_request(
+            users.as_ref(),
+            &contacts,
+            carol,
+            bob,
+            Some("   "),
+            3600,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.message, None);
+    }
+
+    #[tokio::test]
+    async fn test_declined_request_cooldown() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        let now = Utc::now();
+        let request =
+            create_contact_request(users.as_ref(), &contacts, alice, bob, None, 3600, now)
+                .await
+                .unwrap();
+        contacts.close_request_at(request.id, "DECLINED", now - chrono::Duration::seconds(600));
+
+        let err = create_contact_request(users.as_ref(), &contacts, alice, bob, None, 3600, now)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::CooldownActive(3000)));
+
+        // Once the cooldown has passed the request can be sent again
+        let later = now + chrono::Duration::seconds(3000);
+        create_contact_request(users.as_ref(), &contacts, alice, bob, None, 3600, later)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_request_racing_a_decline_respects_the_cooldown() {
+        let users = Arc::new(FakeUserRepo::new());
+        let contacts = Arc::new(FakeContactRepo::new(users.clone()));
+        for round in 0..50 {
+            let alice = users.seed_user(&format!("alice-{}", round));
+            let bob = users.seed_user(&format!("bob-{}", round));
+            let request = create_contact_request(
+                users.as_ref(),
+                contacts.as_ref(),
+                alice,
+                bob,
+                None,
+                3600,
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+
+            // Bob declines while Alice sends the request again
+            let decline = tokio::spawn({
+                let contacts = contacts.clone();
+                async move {
+                    resolve_contact_request(
+                        contacts.as_ref(),
+                        bob,
+                        request.id,
+                        ContactRequestAction::Decline,
+                    )
+                    .await
+                }
+            });
+            let resend = tokio::spawn({
+                let (users, contacts) = (users.clone(), contacts.clone());
+                async move {
+                    create_contact_request(
+                        users.as_ref(),
+                        contacts.as_ref(),
+                        alice,
+                        bob,
+                        None,
+                        3600,
+                        Utc::now(),
+                    )
+                    .await
+                }
+            });
+            decline.await.unwrap().unwrap();
+            // Either the first request was still pending, or the decline starts the cooldown
+            match resend.await.unwrap() {
+                Err(ServiceError::Conflict("request_already_pending")) => {}
+                Err(ServiceError::CooldownActive(remaining)) => assert!(remaining > 3590),
+                other => panic!("re-sent request got past the decline: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outsider_cannot_resolve_request() {
+        let (users, contacts, alice, bob) = contact_fixture();
+        let request = create_contact_request(
+            users.as_ref(),
+            &contacts,
+            alice,
+            bob,
+            None,
+            3600,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+
+        let err = resolve_contact_request(
+            &contacts,
+            Uuid::new_v4(),
+            request.id,
+            ContactRequestAction::Withdraw,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ServiceError::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_announcement_validation() {
+        let repo = FakeAnnouncementRepo::new();
+        let actor = AuditActor {
+            source_ip: "10.0.0.1".to_string(),
+            admin: None,
+        };
+
+        for (message, severity, expires_at) in [
+            ("   ", None, None),
+            ("Maintenance", Some("urgent"), None),
+            (
+                "Maintenance",
+                None,
+                Some(Utc::now() - chrono::Duration::minutes(1)),
+            ),
+        ] {
+            let err = create_announcement(&repo, message, severity, expires_at, &actor, Utc::now())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, ServiceError::BadRequest(_)));
+        }
+        let too_long = "a".repeat(MAX_ANNOUNCEMENT_LENGTH + 1);
+        assert!(
+            create_announcement(&repo, &too_long, None, None, &actor, Utc::now())
+                .await
+                .is_err()
+        );
+
+        let announcement = create_announcement(
+            &repo,
+            " Maintenance at 22:00 ",
+            None,
+            None,
+            &actor,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(announcement.message, "Maintenance at 22:00");
+        assert_eq!(announcement.severity, "info");
+        assert_eq!(repo.audit_actions(), vec!["announcement.create"]);
+    }
+
+    #[tokio::test]
+    async fn test_invite_validation_and_defaults() {
+        let users = FakeUserRepo::new();
+        let actor = AuditActor {
+            source_ip: "10.0.0.1".to_string(),
+            admin: None,
+        };
+        let now = Utc::now();
+
+        let past = Some(now - Duration::minutes(1));
+        assert!(matches!(
+            create_invite(&users, None, past, now, &actor)
+                .await
+                .unwrap_err(),
+            ServiceError::BadRequest(_)
+        ));
+        let too_long = "a".repeat(MAX_INVITE_NOTE_LENGTH + 1);
+        assert!(
+            create_invite(&users, Some(&too_long), None, now, &actor)
+                .await
+                .is_err()
+        );
+
+        let invite = create_invite(&users, Some(" for Bob "), None, now, &actor)
+            .await
+            .unwrap();
+        assert_eq!(invite.note.as_deref(), Some("for Bob"));
+        assert_eq!(
+            invite.expires_at,
+            now + Duration::days(DEFAULT_INVITE_TTL_DAYS)
+        );
+        let other = create_invite(&users, Some("  "), None, now, &actor)
+            .await
+            .unwrap();
+        assert_ne!(invite.code, other.code);
+        assert_eq!(other.note, None);
+        assert_eq!(users.unused_invites(now).await.unwrap().len(), 2);
+        assert_eq!(
+            users.audit_log()[0],
+            (
+                "invite.create".to_string(),
+                invite.id,
+                "for Bob".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_raw_keys_are_normalized_in_batches() {
+        use base64::Engine;
+        use base64::engine::general_purpose;
+
+        let users = FakeUserRepo::new();
+        let actor = AuditActor {
+            source_ip: "10.0.0.1".to_string(),
+            admin: None,
+        };
+        let x509 = crate::crypto::generate_keypair_base64();
+        let raw = general_purpose::STANDARD.encode(decode_x509_to_raw_key(&x509).unwrap());
+        let mut raw_users = Vec::new();
+        for i in 0..3 {
+            let id = users.seed_user(&format!("raw{}", i));
+            users.set_public_key(id, &raw);
+            raw_users.push(id);
+        }
+        let current = users.seed_user("current");
+        users.set_public_key(current, &x509);
+        let broken = users.seed_user("broken");
+        users.set_public_key(broken, "not a key");
+
+        let stats = key_format_stats(&users).await.unwrap();
+        assert_eq!((stats.x509, stats.raw), (1, 3));
+        assert_eq!(stats.unparseable, vec![broken]);
+
+        assert!(matches!(
+            normalize_key_batch(&users, None, 0, &actor)
+                .await
+                .unwrap_err(),
+            ServiceError::BadRequest(_)
+        ));
+        let mut after = None;
+        let (mut scanned, mut normalized) = (0, 0);
+        loop {
+            let batch = normalize_key_batch(&users, after, 2, &actor).await.unwrap();
+            scanned += batch.scanned;
+            normalized += batch.normalized;
+            after = batch.next_after;
+            if after.is_none() {
+                break;
+            }
+        }
+        assert_eq!((scanned, normalized), (5, 3));
+
+        let stats = key_format_stats(&users).await.unwrap();
+        assert_eq!((stats.x509, stats.raw), (4, 0));
+        for id in raw_users {
+            let user = users.find_by_id(id).await.unwrap().unwrap();
+            assert_eq!(user.public_key, x509);
+            assert_eq!(user.key_version, 0);
+        }
+        let changes = users.key_changes();
+        assert_eq!(changes.len(), 3);
+        assert!(
+            changes
+                .iter()
+                .all(|(_, old, new, format_only)| *old == raw && *new == x509 && *format_only)
+        );
+        assert_eq!(users.audit_log().len(), 3);
+        // A second run finds nothing left to rewrite
+        let batch = normalize_key_batch(&users, None, MAX_KEY_NORMALIZE_BATCH, &actor)
+            .await
+            .unwrap();
+        assert_eq!(
+            (batch.scanned, batch.normalized, batch.next_after),
+            (5, 0, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_avatars_are_encrypted_then_rotated_in_batches() {
+        use base64::Engine;
+        use base64::engine::general_purpose;
+
+        let key = |byte: u8| {
+            ColumnKey::from_base64(&general_purpose::STANDARD.encode([byte; 32])).unwrap()
+        };
+        let (first_key, second_key, foreign_key) = (key(1), key(2), key(3));
+        let users = FakeUserRepo::new();
+        let actor = AuditActor {
+            source_ip: "10.0.0.1".to_string(),
+            admin: None,
+        };
+        let keyring = |key: &ColumnKey| ColumnKeyring::from(key.clone());
+        let png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut clear = Vec::new();
+        for i in 0..3 {
+            let id = users.seed_user(&format!("clear{}", i));
+            users.set_avatar(id, png.clone());
+            clear.push(id);
+        }
+        let foreign = users.seed_user("foreign");
+        users.set_avatar(
+            foreign,
+            seal_avatar(&keyring(&foreign_key), foreign, png.clone()),
+        );
+        users.seed_user("no_avatar");
+
+        let rotate_all = |old_key: Option<&ColumnKey>, new_key: &ColumnKey| {
+            let (users, actor) = (&users, &actor);
+            let (old_key, new_key) = (old_key.cloned(), new_key.clone());
+            async move {
+                let (mut after, mut totals) = (None, ColumnRotateBatch::default());
+                loop {
+                    let batch =
+                        rotate_avatar_batch(users, old_key.as_ref(), &new_key, after, 2, actor)
+                            .await
+                            .unwrap();
+                    totals.scanned += batch.scanned;
+                    totals.rotated += batch.rotated;
+                    totals.failed.extend(batch.failed);
+                    after = batch.next_after;
+                    if after.is_none() {
+                        return totals;
+                    }
+                }
+            }
+        };
+
+        // Without an old key, the avatars stored in the clear get encrypted
+        let totals = rotate_all(None, &first_key).await;
+        assert_eq!(
+            (totals.scanned, totals.rotated, totals.failed),
+            (4, 3, vec![foreign])
+        );
+        let stored = users
+            .find_by_id(clear[0])
+            .await
+            .unwrap()
+            .unwrap()
+            .avatar
+            .unwrap();
+        assert!(is_column_encrypted(&stored));
+        assert_eq!(
+            open_avatar(&keyring(&first_key), clear[0], stored.clone()),
+            Ok(png.clone())
+        );
+        assert!(open_avatar(&ColumnKeyring::default(), clear[0], stored).is_err());
+        assert_eq!(
+            avatars(&users, &keyring(&first_key), &clear)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(
+            avatars(&users, &ColumnKeyring::default(), &clear)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        // With the next key configured, avatars under the old one stay readable until rotated
+        let next = ColumnKeyring::new(Some(second_key.clone()), vec![first_key.clone()]);
+        assert_eq!(avatars(&users, &next, &clear).await.unwrap().len(), 3);
+
+        let totals = rotate_all(Some(&first_key), &second_key).await;
+        assert_eq!(
+            (totals.scanned, totals.rotated, totals.failed),
+            (4, 3, vec![foreign])
+        );
+        let result = avatars(&users, &keyring(&second_key), &clear)
+            .await
+            .unwrap();
+        assert!(result.iter().all(|(_, avatar)| *avatar == png));
+        assert!(
+            avatars(&users, &keyring(&first_key), &clear)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(users.audit_log().len(), 6);
+
+        // Running it again skips what is already under the new key
+        let totals = rotate_all(Some(&first_key), &second_key).await;
+        assert_eq!((totals.rotated, totals.failed), (0, vec![foreign]));
+        assert!(matches!(
+            rotate_avatar_batch(&users, None, &second_key, None, 0, &actor)
+                .await
+                .unwrap_err(),
+            ServiceError::BadRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expired_announcements_are_not_active() {
+        let repo = FakeAnnouncementRepo::new();
+        let actor = AuditActor {
+            source_ip: "10.0.0.1".to_string(),
+            admin: None,
+        };
+        let expiring = create_announcement(
+            &repo,
+            "Short notice",
+            Some("warning"),
+            Some(Utc::now() + chrono::Duration::minutes(5)),
+            &actor,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        let standing =
+            create_announcement(&repo, "Standing notice", None, None, &actor, Utc::now())
+                .await
+                .unwrap();
+
+        let active = repo.active_announcements(Utc::now()).await.unwrap();
+        assert_eq!(active.len(), 2);
+        let later = repo
+            .active_announcements(Utc::now() + chrono::Duration::minutes(10))
+            .await
+            .unwrap();
+        assert_eq!(later.len(), 1);
+        assert_eq!(later[0].id, standing.id);
+
+        assert!(repo.delete_announcement(expiring.id, &actor).await.unwrap());
+        assert!(!repo.delete_announcement(expiring.id, &actor).await.unwrap());
+        assert_eq!(
+            repo.audit_actions(),
+            vec![
+                "announcement.create",
+                "announcement.create",
+                "announcement.delete"
+            ]
+        );
+    }
+
+    /// Alice has conversations with the given usernames; returns the repos and her id.
+    fn conversation_fixture(
+        usernames: &[&str],
+    ) -> (
+        Arc<FakeUserRepo>,
+        FakeContactRepo,
+        FakeMessageRepo,
+        Uuid,
+        Vec<Uuid>,
+    ) {
+        let users = Arc::new(FakeUserRepo::new());
+        let alice = users.seed_user("alice");
+        let peers: Vec<Uuid> = usernames.iter().map(|name| users.seed_user(name)).collect();
+        let contacts = FakeContactRepo::new(users.clone());
+        (users, contacts, FakeMessageRepo::new(), alice, peers)
+    }
+
+    #[tokio::test]
+    async fn test_conversation_search_case_folding() {
+        let (_users, contacts, messages, alice, peers) =
+            conversation_fixture(&["Émile", "Emilia", "ZOË", "bob"]);
+        for (i, peer) in peers.iter().enumerate() {
+            // Both directions count as a conversation
+            if i % 2 == 0 {
+                contacts
+                    .record_conversation_peer(alice, *peer, None)
+                    .await
+                    .unwrap();
+            } else {
+                contacts
+                    .record_conversation_peer(*peer, alice, None)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let names = |page: ConversationPage| -> Vec<String> {
+            page.items
+                .into_iter()
+                .map(|item| item.peer.username)
+                .collect()
+        };
+        let page = search_conversations(&contacts, &messages, alice, "ÉMI", None, None)
+            .await
+            .unwrap();
+        assert_eq!(names(page), vec!["Émile"]);
+        // Accents are not stripped: "emi" only finds the unaccented name
+        let page = search_conversations(&contacts, &messages, alice, "emi", None, None)
+            .await
+            .unwrap();
+        assert_eq!(names(page), vec!["Emilia"]);
+        let page = search_conversations(&contacts, &messages, alice, "zoë", None, None)
+            .await
+            .unwrap();
+        assert_eq!(names(page), vec!["ZOË"]);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_search_pages_and_unread_counts() {
+        let (_users, contacts, messages, alice, peers) =
+            conversation_fixture(&["anna", "annika", "hannah", "stranger_ann"]);
+        for peer in &peers[..3] {
+            contacts
+                .record_conversation_peer(alice, *peer, None)
+                .await
+                .unwrap();
+        }
+        messages.seed_message(peers[1], alice, MessageStatus::Sent);
+        messages.seed_message(peers[1], alice, MessageStatus::Delivered);
+        messages.seed_message(peers[1], alice, MessageStatus::Read);
+        messages.seed_message(alice, peers[1], MessageStatus::Sent);
+
+        let page = search_conversations(&contacts, &messages, alice, "ann", Some(2), None)
+            .await
+            .unwrap();
+        // stranger_ann has no conversation with alice
+        assert_eq!(page.total, 3);
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.items[0].peer.username, "anna");
+        assert_eq!(page.items[0].unread_count, 0);
+        assert_eq!(page.items[1].peer.username, "annika");
+        assert_eq!(page.items[1].unread_count, 2);
+
+        let page = search_conversations(&contacts, &messages, alice, "ann", Some(2), Some(2))
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].peer.username, "hannah");
+
+        let err = search_conversations(&contacts, &messages, alice, "  ", None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_register_device_validation_and_limit() {
+        let devices = FakeDeviceRepo::new();
+        let alice = Uuid::new_v4();
+        let key = generate_keypair_base64();
+
+        let err = register_device(&devices, alice, "  ", &key, &[], Utc::now())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+        let err = register_device(&devices, alice, "Phone", "not-a-key", &[], Utc::now())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+
+        let registered_at = Utc::now() - chrono::Duration::days(3);
+        let phone = register_device(&devices, alice, " Phone ", &key, &[], registered_at)
+            .await
+            .unwrap();
+        assert_eq!(phone.name, "Phone");
+        assert_eq!(phone.created_at, registered_at);
+        let err = register_device(&devices, alice, "Laptop", &key, &[], Utc::now())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ServiceError::Conflict("device_already_registered")
+        ));
+
+        for i in 1..MAX_DEVICES_PER_USER {
+            register_device(
+                &devices,
+                alice,
+                &format!("Device {}", i),
+                &generate_keypair_base64(),
+                &[],
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+        }
+        let err = register_device(
+            &devices,
+            alice,
+            "One too many",
+            &generate_keypair_base64(),
+            &[],
+            Utc::now(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ServiceError::Conflict("device_limit_reached")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shared_cipher_suites_of_a_users_devices() {
+        let devices = FakeDeviceRepo::new();
+        let alice = Uuid::new_v4();
+        let allowed: Vec<String> = SUPPORTED_CIPHER_SUITES
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(shared_cipher_suites(&[], &allowed), [DEFAULT_CIPHER_SUITE]);
+
+        let err = register_device(
+            &devices,
+            alice,
+            "Phone",
+            &generate_keypair_base64(),
+            &["AES_128_CBC".to_string()],
+            Utc::now(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ServiceError::BadRequest(_)));
+
+        let both = ["chacha20_poly1305".to_string(), "AES_256_GCM".to_string()];
+        let phone = register_device(
+            &devices,
+            alice,
+            "Phone",
+            &generate_keypair_base64(),
+            &both,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            phone.supported_ciphers,
+            ["CHACHA20_POLY1305", "AES_256_GCM"]
+        );
+        let laptop = register_device(
+            &devices,
+            alice,
+            "Laptop",
+            &generate_keypair_base64(),
+            &[],
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(laptop.supported_ciphers, [DEFAULT_CIPHER_SUITE]);
+
+        assert_eq!(
+            shared_cipher_suites(std::slice::from_ref(&phone), &allowed),
+            allowed
+        );
+        assert_eq!(
+            shared_cipher_suites(&[phone.clone(), laptop], &allowed),
+            ["AES_256_GCM"]
+        );
+        let chacha_only = ["CHACHA20_POLY1305".to_string()];
+        assert_eq!(shared_cipher_suites(&[phone], &chacha_only), chacha_only);
+    }
+
+    #[tokio::test]
+    async fn test_revoke_device_only_by_owner() {
+        let devices = FakeDeviceRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let phone = register_device(
+            &devices,
+            alice,
+            "Phone",
+            &generate_keypair_base64(),
+            &[],
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        assert!(device_belongs_to(&devices, alice, phone.id).await.unwrap());
+        assert!(!device_belongs_to(&devices, bob, phone.id).await.unwrap());
+
+        let err = revoke_device(&devices, bob, phone.id).await.unwrap_err();
+        assert!(matches!(err, ServiceError::NotFound(_)));
+        revoke_device(&devices, alice, phone.id).await.unwrap();
+        assert!(devices.list_devices(alice).await.unwrap().is_empty());
+        assert!(!device_belongs_to(&devices, alice, phone.id).await.unwrap());
+    }
+}
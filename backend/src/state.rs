@@ -1,7 +1,367 @@
-use crate::websocket::ConnectionManager;
+use crate::admin::{AdminCredentials, BacklogCache, create_backlog_cache};
+use crate::auth::{
+    DEFAULT_JWT_LEEWAY_SECS, DEFAULT_USERNAME_GRACE_PERIOD_DAYS, KeyChallengeStore,
+    create_key_challenge_store,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::contacts::{RelationshipCache, create_relationship_cache};
+use crate::crypto::{ColumnKeyring, SUPPORTED_CIPHER_SUITES, sealed_routing_key};
+use crate::rate_limit::{InMemoryBackend, RateLimitBackend};
+use crate::repo::postgres::{
+    PgAnnouncementRepo, PgContactRepo, PgDeviceRepo, PgMessageRepo, PgUserRepo,
+};
+use crate::repo::{AnnouncementRepo, ContactRepo, DeviceRepo, MessageRepo, UserRepo};
+use crate::service::{
+    DEFAULT_MAX_CONTACTS, DEFAULT_NEW_CONVERSATION_LIMITS, DEFAULT_SEND_LIMITS,
+    NewConversationLimits, SendLimits,
+};
+use crate::webhooks::WebhookDispatcher;
+use crate::websocket::{
+    ConnectionManager, ConnectionTracker, DEFAULT_WS_ACK_TIMEOUT, DEFAULT_WS_MAX_CONNECTIONS,
+    DEFAULT_WS_MAX_CONNECTIONS_PER_USER, DEFAULT_WS_MAX_FRAME_BYTES, NonceCache,
+    RedisSubscriptions, create_connection_manager, create_nonce_cache,
+};
+use ipnet::IpNet;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Oldest client version supported unless `MIN_CLIENT_VERSION` is set.
+pub const DEFAULT_MIN_CLIENT_VERSION: &str = "1.0.0";
+/// How long a user must wait to ask someone again after a declined request, unless
+/// `CONTACT_REQUEST_COOLDOWN_HOURS` is set.
+pub const DEFAULT_CONTACT_REQUEST_COOLDOWN_HOURS: i64 = 24;
+
+/// Optional client features this deployment advertises through `GET /server-info`.
+/// Each is off unless its `FEATURE_*` variable is `true` or `1`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct FeatureFlags {
+    pub group_messaging: bool,
+    pub reactions: bool,
+    pub disappearing_messages: bool,
+    pub sealed_sender: bool,
+    pub pre_keys: bool,
+}
+
+/// Who may create an account through `POST /register`, from `REGISTRATION_MODE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationMode {
+    /// Anyone may register.
+    #[default]
+    Open,
+    /// Registration needs an unused invite from `POST /admin/invites`.
+    Invite,
+    /// Nobody may register.
+    Closed,
+}
+
+impl RegistrationMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode.trim().to_lowercase().as_str() {
+            "open" => Some(RegistrationMode::Open),
+            "invite" => Some(RegistrationMode::Invite),
+            "closed" => Some(RegistrationMode::Closed),
+            _ => None,
+        }
+    }
+}
 
 pub struct AppState {
     pub db: sqlx::PgPool,
+    /// Source of the current time; `SystemClock` outside tests.
+    pub clock: Arc<dyn Clock>,
     pub jwt_secret: String,
+    /// Key of sealed message routing ids, derived from `jwt_secret`.
+    pub sealed_routing_key: [u8; 32],
+    /// Seconds of clock skew tolerated when checking a JWT's expiry.
+    pub jwt_leeway_secs: u64,
+    /// Argon2 costs for new password hashes; older hashes are upgraded on login.
+    pub password_hash_params: argon2::Params,
     pub connections: ConnectionManager,
+    /// Counts open WebSocket connections and enforces the per-user and instance limits.
+    pub connection_tracker: Arc<ConnectionTracker>,
+    pub relationships: RelationshipCache,
+    pub require_contact_for_messages: bool,
+    /// Accept messages whose receiver is the sender (notes to self); rejected otherwise.
+    pub self_messages_enabled: bool,
+    pub contact_request_cooldown_secs: i64,
+    /// Most contacts a user may add through `POST /contacts`.
+    pub max_contacts: i64,
+    pub pins_exempt_from_read_deletion: bool,
+    /// Whether `PUT /profile/key` accepts a key without a proof of possession.
+    pub allow_unproven_key_updates: bool,
+    pub key_challenges: KeyChallengeStore,
+    pub registration_mode: RegistrationMode,
+    /// Days a deleted account's username stays reserved before it can be registered again.
+    pub username_grace_period_days: i32,
+    /// Whether `POST /admin/import.ndjson` may restore a backup into an empty database.
+    pub allow_ndjson_import: bool,
+    /// Encrypts avatars at rest when `COLUMN_ENCRYPTION_KEY` is set; stored as uploaded
+    /// otherwise. Also holds the retired keys from `COLUMN_ENCRYPTION_OLD_KEYS`.
+    pub column_keys: ColumnKeyring,
+    /// Whether users may register per-device keys and messages may target one device.
+    /// When off, every connection uses the account's single key as before.
+    pub multi_device: bool,
+    /// Cipher suites `send_message` accepts, from `CIPHER_SUITES`.
+    pub cipher_suites: Vec<String>,
+    pub features: FeatureFlags,
+    /// Oldest client version this server supports, from `MIN_CLIENT_VERSION`.
+    pub min_client_version: String,
+    pub users: Arc<dyn UserRepo>,
+    pub messages: Arc<dyn MessageRepo>,
+    pub contacts: Arc<dyn ContactRepo>,
+    pub announcements: Arc<dyn AnnouncementRepo>,
+    pub devices: Arc<dyn DeviceRepo>,
+    pub admin_ip_allowlist: Vec<IpNet>,
+    /// HTTP Basic credentials of the `/admin` routes; they are disabled without them.
+    pub admin_credentials: Option<AdminCredentials>,
+    pub trust_proxy_headers: bool,
+    pub backlog_cache: BacklogCache,
+    pub nonces: NonceCache,
+    /// Counts contact syncs, conversation exports, probes and signature checks; see
+    /// `RATE_LIMIT_BACKEND`.
+    pub rate_limiter: Arc<dyn RateLimitBackend>,
+    /// How long the server waits for a client `ack` before resending an event.
+    pub ws_ack_timeout: Duration,
+    /// Largest WebSocket text or binary frame the server parses; larger ones get an `error` event.
+    pub ws_max_frame_bytes: usize,
+    /// Publishes events for users connected to other instances; set when `REDIS_URL` is.
+    pub redis_client: Option<redis::aio::ConnectionManager>,
+    /// Subscribes this instance to the channels of its connected users.
+    pub redis_subscriptions: Option<RedisSubscriptions>,
+    /// Posts message events to `WEBHOOK_URL`; unset when no webhook is configured.
+    pub webhooks: Option<WebhookDispatcher>,
+    /// Per-user message quotas per minute, hour and day.
+    pub send_limits: SendLimits,
+    /// Conversations a user may open per day.
+    pub new_conversation_limits: NewConversationLimits,
+    /// Messages refused because their sender reached the new conversation limit.
+    pub new_conversation_limit_hits: AtomicU64,
+    /// Messages whose timestamp was moved forward because this instance's clock was behind.
+    pub clock_skew_corrections: AtomicU64,
+    /// Set while memory use is above `MAX_MEMORY_PERCENT`; requests other than `/health` get 503.
+    pub load_shedding: AtomicBool,
+    /// Random id of this server process, sent as `X-Instance-Id` for sticky sessions and debugging.
+    pub instance_id: Uuid,
+    /// Port of the HTTP listener, which `POST /admin/selftest` connects back to.
+    pub server_port: u16,
+}
+
+impl AppState {
+    /// Starts a state on `db` with every other component at its default: the PostgreSQL
+    /// repositories, the system clock, in-memory rate limits, no Redis and no webhooks, and
+    /// the settings a deployment gets without any environment variables. `main` replaces
+    /// what the environment configures; tests replace what they fake.
+    pub fn builder(db: sqlx::PgPool, jwt_secret: impl Into<String>) -> AppStateBuilder {
+        let jwt_secret = jwt_secret.into();
+        AppStateBuilder {
+            state: AppState {
+                clock: Arc::new(SystemClock),
+                sealed_routing_key: sealed_routing_key(jwt_secret.as_bytes()),
+                jwt_secret,
+                jwt_leeway_secs: DEFAULT_JWT_LEEWAY_SECS,
+                password_hash_params: argon2::Params::default(),
+                connections: create_connection_manager(),
+                connection_tracker: Arc::new(ConnectionTracker::new(
+                    DEFAULT_WS_MAX_CONNECTIONS,
+                    DEFAULT_WS_MAX_CONNECTIONS_PER_USER,
+                )),
+                relationships: create_relationship_cache(),
+                require_contact_for_messages: false,
+                self_messages_enabled: false,
+                contact_request_cooldown_secs: DEFAULT_CONTACT_REQUEST_COOLDOWN_HOURS * 3600,
+                max_contacts: DEFAULT_MAX_CONTACTS,
+                pins_exempt_from_read_deletion: false,
+                allow_unproven_key_updates: true,
+                key_challenges: create_key_challenge_store(),
+                registration_mode: RegistrationMode::Open,
+                username_grace_period_days: DEFAULT_USERNAME_GRACE_PERIOD_DAYS,
+                allow_ndjson_import: false,
+                column_keys: ColumnKeyring::default(),
+                multi_device: false,
+                cipher_suites: SUPPORTED_CIPHER_SUITES
+                    .iter()
+                    .map(|suite| suite.to_string())
+                    .collect(),
+                features: FeatureFlags::default(),
+                min_client_version: DEFAULT_MIN_CLIENT_VERSION.to_string(),
+                users: Arc::new(PgUserRepo::new(db.clone())),
+                messages: Arc::new(PgMessageRepo::new(db.clone())),
+                contacts: Arc::new(PgContactRepo::new(db.clone())),
+                announcements: Arc::new(PgAnnouncementRepo::new(db.clone())),
+                devices: Arc::new(PgDeviceRepo::new(db.clone())),
+                admin_ip_allowlist: Vec::new(),
+                admin_credentials: None,
+                trust_proxy_headers: false,
+                backlog_cache: create_backlog_cache(),
+                nonces: create_nonce_cache(),
+                rate_limiter: Arc::new(InMemoryBackend::new()),
+                ws_ack_timeout: DEFAULT_WS_ACK_TIMEOUT,
+                ws_max_frame_bytes: DEFAULT_WS_MAX_FRAME_BYTES,
+                redis_client: None,
+                redis_subscriptions: None,
+                webhooks: None,
+                send_limits: DEFAULT_SEND_LIMITS,
+                new_conversation_limits: DEFAULT_NEW_CONVERSATION_LIMITS,
+                new_conversation_limit_hits: AtomicU64::new(0),
+                clock_skew_corrections: AtomicU64::new(0),
+                load_shedding: AtomicBool::new(false),
+                instance_id: Uuid::new_v4(),
+                server_port: 0,
+                db,
+            },
+        }
+    }
+
+    /// State for tests on `db`, which may be a lazy pool that never connects when the
+    /// repositories are replaced with fakes. Contact requests have no cooldown and deleted
+    /// usernames are free again at once.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn build_for_tests(db: sqlx::PgPool) -> AppState {
+        Self::test_builder(db).build()
+    }
+
+    /// The builder behind `build_for_tests`, for tests that replace components.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn test_builder(db: sqlx::PgPool) -> AppStateBuilder {
+        Self::builder(db, "test-secret").configure(|state| {
+            state.contact_request_cooldown_secs = 0;
+            state.username_grace_period_days = 0;
+        })
+    }
+}
+
+/// Builds an `AppState` from `AppState::builder`. The repositories and the components
+/// shared with background tasks have setters; everything else is changed with `configure`.
+/// `main` only replaces the repositories its background tasks share, so the others can
+/// only be replaced in tests.
+pub struct AppStateBuilder {
+    state: AppState,
+}
+
+impl AppStateBuilder {
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.state.clock = clock;
+        self
+    }
+
+    pub fn connections(mut self, connections: ConnectionManager) -> Self {
+        self.state.connections = connections;
+        self
+    }
+
+    pub fn connection_tracker(mut self, tracker: ConnectionTracker) -> Self {
+        self.state.connection_tracker = Arc::new(tracker);
+        self
+    }
+
+    pub fn relationships(mut self, relationships: RelationshipCache) -> Self {
+        self.state.relationships = relationships;
+        self
+    }
+
+    pub fn nonces(mut self, nonces: NonceCache) -> Self {
+        self.state.nonces = nonces;
+        self
+    }
+
+    pub fn backlog_cache(mut self, backlog_cache: BacklogCache) -> Self {
+        self.state.backlog_cache = backlog_cache;
+        self
+    }
+
+    pub fn users(mut self, users: Arc<dyn UserRepo>) -> Self {
+        self.state.users = users;
+        self
+    }
+
+    pub fn messages(mut self, messages: Arc<dyn MessageRepo>) -> Self {
+        self.state.messages = messages;
+        self
+    }
+
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn contacts(mut self, contacts: Arc<dyn ContactRepo>) -> Self {
+        self.state.contacts = contacts;
+        self
+    }
+
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn announcements(mut self, announcements: Arc<dyn AnnouncementRepo>) -> Self {
+        self.state.announcements = announcements;
+        self
+    }
+
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn devices(mut self, devices: Arc<dyn DeviceRepo>) -> Self {
+        self.state.devices = devices;
+        self
+    }
+
+    pub fn rate_limiter(mut self, rate_limiter: Arc<dyn RateLimitBackend>) -> Self {
+        self.state.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Changes settings in place, e.g. those read from the environment.
+    pub fn configure(mut self, configure: impl FnOnce(&mut AppState)) -> Self {
+        configure(&mut self.state);
+        self
+    }
+
+    pub fn build(self) -> AppState {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use chrono::{TimeZone, Utc};
+
+    fn lazy_pool() -> sqlx::PgPool {
+        sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_builder_defaults_match_an_unconfigured_deployment() {
+        let state = AppState::builder(lazy_pool(), "secret").build();
+        assert_eq!(state.jwt_secret, "secret");
+        assert_eq!(state.contact_request_cooldown_secs, 24 * 3600);
+        assert_eq!(
+            state.username_grace_period_days,
+            DEFAULT_USERNAME_GRACE_PERIOD_DAYS
+        );
+        assert_eq!(state.registration_mode, RegistrationMode::Open);
+        assert_eq!(state.min_client_version, DEFAULT_MIN_CLIENT_VERSION);
+        assert_eq!(state.cipher_suites, SUPPORTED_CIPHER_SUITES);
+        assert_eq!(state.ws_ack_timeout, DEFAULT_WS_ACK_TIMEOUT);
+        assert!(state.redis_client.is_none() && state.webhooks.is_none());
+
+        let tests = AppState::build_for_tests(lazy_pool());
+        assert_eq!(tests.contact_request_cooldown_secs, 0);
+        assert_eq!(tests.username_grace_period_days, 0);
+    }
+
+    #[tokio::test]
+    async fn test_builder_replaces_components_and_settings() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let state = AppState::test_builder(lazy_pool())
+            .clock(Arc::new(MockClock::new(now)))
+            .configure(|state| state.multi_device = true)
+            .build();
+        assert_eq!(state.clock.now(), now);
+        assert!(state.multi_device);
+    }
 }
@@ -0,0 +1,294 @@
+//! Webhooks module for Safe Chat backend
+//!
+//! With `WEBHOOK_URL` set, the server POSTs a JSON event to that URL whenever a message is
+//! sent or changes status, so operators can build bots and delivery analytics, and when a
+//! user's undelivered backlog crosses an alert threshold. Events carry ids, the status and
+//! the time only; ciphertext, IVs and keys never leave the server.
+//!
+//! Events are queued without waiting on the endpoint, so a slow or unreachable webhook never
+//! delays messaging. A failed delivery is retried with exponential backoff up to
+//! `WEBHOOK_MAX_ATTEMPTS` times and then dropped with a warning. When the queue is full new
+//! events are dropped the same way. With `WEBHOOK_SECRET` set each request is signed with
+//! `X-SafeChat-Signature: sha256=<hex HMAC-SHA256 of the body>`.
+
+use crate::repo::MessageStatus;
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Semaphore, mpsc};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub const SIGNATURE_HEADER: &str = "X-SafeChat-Signature";
+pub const EVENT_HEADER: &str = "X-SafeChat-Event";
+pub const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+/// Events waiting to be sent; more are dropped until the endpoint catches up.
+const QUEUE_CAPACITY: usize = 1024;
+/// Deliveries (including their retries) in flight at once.
+const MAX_CONCURRENT_DELIVERIES: usize = 16;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key for the `X-SafeChat-Signature` header; unsigned when unset.
+    pub secret: Option<String>,
+    /// Tries per event, including the first.
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubled for each later one.
+    pub initial_backoff: Duration,
+}
+
+/// A content-free notification about a message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// The server stored a message and attempted delivery.
+    MessageSent {
+        message_id: String,
+        sender_id: String,
+        receiver_id: String,
+        timestamp: String,
+    },
+    /// A message moved to a new status (`SENT`, `DELIVERED` or `READ`).
+    MessageStatus {
+        message_id: String,
+        sender_id: String,
+        receiver_id: String,
+        status: MessageStatus,
+        updated_by: String,
+        timestamp: String,
+    },
+    /// A user's undelivered backlog crossed `BACKLOG_ALERT_COUNT` or `BACKLOG_ALERT_AGE_SECS`.
+    BacklogAlert {
+        user_id: String,
+        count: i64,
+        oldest_age_secs: i64,
+        timestamp: String,
+    },
+}
+
+impl WebhookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::MessageSent { .. } => "message_sent",
+            WebhookEvent::MessageStatus { .. } => "message_status",
+            WebhookEvent::BacklogAlert { .. } => "backlog_alert",
+        }
+    }
+}
+
+/// One POST body: the event plus an id receivers can use to drop retried duplicates.
+#[derive(Serialize)]
+struct WebhookDelivery<'a> {
+    delivery_id: Uuid,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+/// Hands events to the background sender.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    queue: mpsc::Sender<WebhookEvent>,
+}
+
+impl WebhookDispatcher {
+    /// Queues `event` for delivery without waiting; drops it if the queue is full.
+    pub fn dispatch(&self, event: WebhookEvent) {
+        if let Err(e) = self.queue.try_send(event) {
+            warn!("Dropping webhook event: {}", e);
+        }
+    }
+}
+
+/// Starts the background sender for `config` and returns its dispatcher.
+pub fn spawn_webhook_dispatcher(config: WebhookConfig) -> WebhookDispatcher {
+    let (queue, mut events) = mpsc::channel::<WebhookEvent>(QUEUE_CAPACITY);
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build webhook HTTP client");
+    let config = Arc::new(config);
+    let slots = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let Ok(slot) = slots.clone().acquire_owned().await else {
+                break;
+            };
+            let (client, config) = (client.clone(), config.clone());
+            tokio::spawn(async move {
+                deliver(&client, &config, &event).await;
+                drop(slot);
+            });
+        }
+    });
+    WebhookDispatcher { queue }
+}
+
+/// Sends one event, retrying failures with backoff until it is accepted or attempts run out.
+async fn deliver(client: &reqwest::Client, config: &WebhookConfig, event: &WebhookEvent) {
+    let body = match serde_json::to_vec(&WebhookDelivery {
+        delivery_id: Uuid::new_v4(),
+        event,
+    }) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize webhook event: {}", e);
+            return;
+        }
+    };
+    let signature = config.secret.as_deref().map(|secret| sign(secret, &body));
+    for attempt in 1..=config.max_attempts.max(1) {
+        let mut request = client
+            .post(&config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event.name())
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        let retry = match request.send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => {
+                let status = response.status();
+                warn!(
+                    "Webhook {} answered {} (attempt {})",
+                    event.name(),
+                    status,
+                    attempt
+                );
+                should_retry(status)
+            }
+            Err(e) => {
+                warn!(
+                    "Webhook {} failed: {} (attempt {})",
+                    event.name(),
+                    e,
+                    attempt
+                );
+                true
+            }
+        };
+        if !retry {
+            break;
+        }
+        if attempt < config.max_attempts {
+            tokio::time::sleep(retry_delay(attempt, config.initial_backoff)).await;
+        }
+    }
+    info!("Giving up on webhook {} event", event.name());
+}
+
+/// Server errors, timeouts and throttling are worth retrying; other client errors are not.
+fn should_retry(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+}
+
+/// Wait after failed attempt `attempt` (1-based): `initial`, then doubling, capped at a minute.
+fn retry_delay(attempt: u32, initial: Duration) -> Duration {
+    initial
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+/// `sha256=` followed by the lowercase hex HMAC-SHA256 of `body` under `secret`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_retry_delay_doubles_up_to_a_minute() {
+        let initial = Duration::from_secs(1);
+        assert_eq!(retry_delay(1, initial), Duration::from_secs(1));
+        assert_eq!(retry_delay(2, initial), Duration::from_secs(2));
+        assert_eq!(retry_delay(4, initial), Duration::from_secs(8));
+        assert_eq!(retry_delay(10, initial), MAX_BACKOFF);
+        assert!(should_retry(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(should_retry(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!should_retry(reqwest::StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_is_retried_and_signed() {
+        let received: Arc<Mutex<Vec<(HeaderMap, Bytes)>>> = Arc::default();
+        let log = received.clone();
+        let app = axum::Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| {
+                let log = log.clone();
+                async move {
+                    let mut log = log.lock().unwrap();
+                    log.push((headers, body));
+                    // Fail the first attempt so the dispatcher has to retry
+                    if log.len() == 1 {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    } else {
+                        StatusCode::NO_CONTENT
+                    }
+                }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let dispatcher = spawn_webhook_dispatcher(WebhookConfig {
+            url: format!("http://{}/hook", addr),
+            secret: Some("shh".to_string()),
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+        });
+        dispatcher.dispatch(WebhookEvent::MessageStatus {
+            message_id: "m1".to_string(),
+            sender_id: "a".to_string(),
+            receiver_id: "b".to_string(),
+            status: MessageStatus::Read,
+            updated_by: "b".to_string(),
+            timestamp: "2026-10-16T09:00:00+00:00".to_string(),
+        });
+
+        for _ in 0..200 {
+            if received.lock().unwrap().len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let (headers, body) = &received[1];
+        assert_eq!(headers[EVENT_HEADER], "message_status");
+        assert_eq!(headers[SIGNATURE_HEADER], sign("shh", body).as_str());
+        let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(json["event"], "message_status");
+        assert_eq!(json["status"], "READ");
+        // Retries reuse the same delivery id so receivers can deduplicate
+        assert_eq!(body, &received[0].1);
+    }
+}
@@ -1,80 +1,777 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
         Query, State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
     },
-    http::StatusCode,
+    http::{HeaderValue, StatusCode},
     response::Response,
 };
 use base64::Engine;
-use chrono::{Utc};
-use chrono_tz::Europe::Brussels;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use futures_util::{sink::SinkExt, stream::StreamExt};
+use jsonwebtoken::errors::ErrorKind;
+use redis::AsyncCommands;
+use redis::RedisResult;
+use redis::aio::{ConnectionManager as RedisConnectionManager, PubSubSink, PubSubStream};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use sqlx::Row;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
 use tokio::time::sleep;
-use tracing::{error, info, warn};
+use tracing::field::Empty;
+use tracing::{Instrument, Span, error, info, info_span, warn};
 use uuid::Uuid;
 
-use crate::{auth::decode_jwt_token, state::AppState};
+use safechat_types::messages::ReplyReference;
+pub use safechat_types::ws::{
+    ClientAckData, DeliveryReceipt, ErrorCode, ErrorNotification, HelloData, MessageNotification,
+    SendMessageData, SignedReceiptData, StatusUpdate, UpdateStatusData, WebSocketMessage,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct WebSocketMessage {
-    pub message_type: String,
-    pub data: serde_json::Value,
+use crate::{
+    auth::decode_jwt_token,
+    clock::Clock,
+    contacts::{can_message, record_conversation_peer},
+    crypto::{
+        DEFAULT_CIPHER_SUITE, SUPPORTED_ENCRYPTION_VERSIONS, encryption_version_supported,
+        max_encryption_version, sealed_routing_id,
+    },
+    preferences::{DEFAULT_TIMEZONE, format_millis, format_timestamp},
+    repo::{
+        AnnouncementRecord, DeliveryAttemptRecord, DeliveryReceiptRecord, MessageRecord,
+        MessageRepo, MessageStatus, RepoResult, SealedMessageRecord, SendQuotaOutcome,
+    },
+    service::{self, NewConversationCheck},
+    state::AppState,
+    webhooks::WebhookEvent,
+};
+
+/// Data of `user_online` and `user_offline`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Presence<'a> {
+    pub user_id: &'a str,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SendMessageData {
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PinUpdate {
     pub message_id: String,
-    pub receiver_id: String,
-    pub r#type: String,
-    pub encrypted_content: String,
-    pub iv: String,
+    pub pinned: bool,
+    pub updated_by: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UpdateStatusData {
+/// A sender corrected a message's metadata; its content is unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MetaUpdate {
     pub message_id: String,
-    pub status: String,
+    pub r#type: String,
+    pub updated_by: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MessageNotification {
+/// One of the user's devices was revoked; its connections are closed.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DeviceRevoked {
+    pub device_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ContactRequestNotification {
     pub id: String,
-    pub timestamp: String,
-    pub sender_id: String,
-    pub receiver_id: String,
+    pub requester_id: String,
+    pub requester_username: String,
+    pub target_id: String,
     pub status: String,
+    pub created_at: String,
+    pub message: Option<String>,
+}
+
+/// A message with sealed metadata. The sender and exact time are only inside
+/// `encrypted_metadata`; `day` is the UTC day the server received it.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SealedMessageNotification {
+    pub id: String,
+    pub day: String,
     pub r#type: String,
+    pub encrypted_metadata: String,
     pub encrypted_content: String,
     pub iv: String,
+    pub encryption_version: i16,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct StatusUpdate {
+impl SealedMessageNotification {
+    pub fn from_record(record: &SealedMessageRecord) -> Self {
+        let base64 = &base64::engine::general_purpose::STANDARD;
+        SealedMessageNotification {
+            id: record.id.to_string(),
+            day: record.day.format("%Y-%m-%d").to_string(),
+            r#type: record.r#type.clone(),
+            encrypted_metadata: base64.encode(&record.encrypted_metadata),
+            encrypted_content: base64.encode(&record.encrypted_content),
+            iv: base64.encode(&record.iv),
+            encryption_version: record.encryption_version,
+        }
+    }
+}
+
+/// A delivery probe from `sender_id`. It is not a message: nothing is stored and
+/// no acknowledgement is expected.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ProbeNotification {
+    pub id: String,
+    pub sender_id: String,
+    pub timestamp: String,
+}
+
+/// Tells the sender of a probe whether it reached a connection of the receiver.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ProbeResult {
     pub message_id: String,
-    pub status: String,
-    pub updated_by: String,
+    pub receiver_id: String,
+    pub delivered: bool,
 }
 
-#[derive(Debug, Clone)]
+/// Reply to a client `hello`, advertising what the server supports.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HelloAck {
+    pub max_encryption_version: i16,
+    pub supported_encryption_versions: Vec<i16>,
+}
+
+impl HelloAck {
+    pub fn current() -> Self {
+        HelloAck {
+            max_encryption_version: max_encryption_version(),
+            supported_encryption_versions: SUPPORTED_ENCRYPTION_VERSIONS.to_vec(),
+        }
+    }
+}
+
+/// Unread messages addressed to the user, keyed by sender id. Senders without unread
+/// messages are omitted.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UnreadCounts {
+    pub counts: HashMap<String, i64>,
+    pub total: i64,
+}
+
+impl From<HashMap<Uuid, i64>> for UnreadCounts {
+    fn from(counts: HashMap<Uuid, i64>) -> Self {
+        UnreadCounts {
+            total: counts.values().sum(),
+            counts: counts
+                .into_iter()
+                .map(|(sender_id, count)| (sender_id.to_string(), count))
+                .collect(),
+        }
+    }
+}
+
+/// Tells a participant that the conversation with `user_id` was deleted.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ConversationCleared {
+    /// The other participant, from the receiving user's point of view.
+    pub user_id: String,
+    pub cleared_by: String,
+    pub deleted_count: u64,
+}
+
+/// Tells a participant that a message was deleted from the server after reaching `status`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessageDeleted {
+    pub message_id: String,
+    /// `READ`, or `DELIVERED` when the sender has `delete_on_delivered` set.
+    pub status: MessageStatus,
+}
+
+/// An operator announcement sent to every connected user. Announcements are plaintext
+/// and always carry `origin: "server"` so clients never render them as a contact's message.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AnnouncementNotification {
+    pub id: String,
+    pub message: String,
+    pub severity: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub origin: String,
+}
+
+impl AnnouncementNotification {
+    pub fn from_record(record: &AnnouncementRecord) -> Self {
+        AnnouncementNotification {
+            id: record.id.to_string(),
+            message: record.message.clone(),
+            severity: record.severity.clone(),
+            created_at: format_timestamp(record.created_at, DEFAULT_TIMEZONE),
+            expires_at: record
+                .expires_at
+                .map(|expires_at| format_timestamp(expires_at, DEFAULT_TIMEZONE)),
+            origin: "server".to_string(),
+        }
+    }
+}
+
+/// Acknowledges a write message that carried a `nonce`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Ack {
+    pub nonce: String,
+    /// True when the frame was a replay and was ignored.
+    pub duplicate: bool,
+}
+
+/// Sent instead of `hello_ack` when the handshake token was rejected, right before the
+/// connection is closed with the same reason.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AuthFailed {
+    /// `token_expired` or `invalid_token`.
+    pub code: String,
+    pub message: String,
+}
+
+impl AuthFailed {
+    fn for_reason(reason: CloseReason) -> Self {
+        let message = match reason {
+            CloseReason::TokenExpired => "The token has expired; log in again",
+            _ => "The token is not valid for this server; log in again",
+        };
+        AuthFailed {
+            code: reason.reason().to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WSEvent {
     NewMessage(MessageNotification),
+    SealedMessage(SealedMessageNotification),
     StatusUpdate(StatusUpdate),
+    DeliveryReceipt(DeliveryReceipt),
     UserOnline(String),
     UserOffline(String),
+    PinUpdate(PinUpdate),
+    MetaUpdate(MetaUpdate),
+    DeviceRevoked(DeviceRevoked),
+    ContactRequest(ContactRequestNotification),
+    ConversationCleared(ConversationCleared),
+    MessageDeleted(MessageDeleted),
+    Announcement(AnnouncementNotification),
+    Probe(ProbeNotification),
+    ProbeResult(ProbeResult),
+    Error(ErrorNotification),
+    HelloAck(HelloAck),
+    UnreadCounts(UnreadCounts),
+    Ack(Ack),
+    AuthFailed(AuthFailed),
+    Close(CloseReason),
+}
+
+impl WSEvent {
+    /// Whether the client must acknowledge this event. Presence updates and replies to the
+    /// client's own frames are not retried.
+    fn requires_ack(&self) -> bool {
+        matches!(
+            self,
+            WSEvent::NewMessage(_)
+                | WSEvent::SealedMessage(_)
+                | WSEvent::StatusUpdate(_)
+                | WSEvent::DeliveryReceipt(_)
+                | WSEvent::PinUpdate(_)
+                | WSEvent::MetaUpdate(_)
+                | WSEvent::DeviceRevoked(_)
+                | WSEvent::ContactRequest(_)
+                | WSEvent::ConversationCleared(_)
+                | WSEvent::MessageDeleted(_)
+        )
+    }
+}
+
+/// Reasons the server closes a WebSocket, each mapped to a close code so clients
+/// can decide whether to reconnect, re-authenticate, or stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CloseReason {
+    /// The server is shutting down; reconnect later.
+    ServerShutdown,
+    /// The client broke the protocol; reconnecting with the same behaviour will fail again.
+    PolicyViolation,
+    /// The client fell too far behind on events; reconnect and resync.
+    TryAgainLater,
+    /// A newer connection for the same user took over; do not reconnect.
+    Replaced,
+    /// The JWT used to open the connection has expired; log in again.
+    TokenExpired,
+    /// The device the connection was opened for was revoked; do not reconnect with it.
+    DeviceRevoked,
+    /// The user opened more connections than `WS_MAX_CONNECTIONS_PER_USER`, and this was
+    /// the oldest; do not reconnect automatically.
+    ConnectionLimit,
+    /// The token the connection was opened with is not valid for this server; log in again.
+    InvalidToken,
+    /// The client sent a frame too large for the WebSocket layer to read.
+    FrameTooLarge,
+}
+
+impl CloseReason {
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::ServerShutdown => close_code::AWAY,
+            CloseReason::PolicyViolation => close_code::POLICY,
+            CloseReason::TryAgainLater => close_code::AGAIN,
+            CloseReason::Replaced => 4000,
+            CloseReason::TokenExpired => 4001,
+            CloseReason::DeviceRevoked => 4002,
+            CloseReason::ConnectionLimit => 4003,
+            CloseReason::InvalidToken => 4004,
+            CloseReason::FrameTooLarge => close_code::SIZE,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::ServerShutdown => "server_shutdown",
+            CloseReason::PolicyViolation => "policy_violation",
+            CloseReason::TryAgainLater => "try_again_later",
+            CloseReason::Replaced => "replaced",
+            CloseReason::TokenExpired => "token_expired",
+            CloseReason::DeviceRevoked => "device_revoked",
+            CloseReason::ConnectionLimit => "connection_limit",
+            CloseReason::InvalidToken => "invalid_token",
+            CloseReason::FrameTooLarge => "frame_too_large",
+        }
+    }
+
+    pub fn close_frame(self) -> CloseFrame<'static> {
+        CloseFrame {
+            code: self.code(),
+            reason: self.reason().into(),
+        }
+    }
 }
 
+/// Each connected user's event channel. With `MULTI_DEVICE` enabled every connection of a
+/// user subscribes to the same channel; otherwise a new connection replaces the previous one.
 pub type ConnectionManager = Arc<DashMap<Uuid, broadcast::Sender<WSEvent>>>;
 
+/// Connections one user may hold unless `WS_MAX_CONNECTIONS_PER_USER` is set.
+pub const DEFAULT_WS_MAX_CONNECTIONS_PER_USER: usize = 5;
+/// Connections this instance accepts unless `WS_MAX_CONNECTIONS` is set.
+pub const DEFAULT_WS_MAX_CONNECTIONS: usize = 10_000;
+
+/// Counts this instance's WebSocket connections and enforces `WS_MAX_CONNECTIONS` and
+/// `WS_MAX_CONNECTIONS_PER_USER`. Each user's connections are kept in the order they were
+/// established, so the one evicted for a new connection is always the oldest.
+pub struct ConnectionTracker {
+    max_total: usize,
+    max_per_user: usize,
+    open: AtomicUsize,
+    next_id: AtomicU64,
+    per_user: DashMap<Uuid, VecDeque<(u64, oneshot::Sender<CloseReason>)>>,
+    /// Connections closed because their user opened a newer one over the limit.
+    pub evictions: AtomicU64,
+    /// Upgrades refused because the instance was full.
+    pub refused: AtomicU64,
+}
+
+impl ConnectionTracker {
+    pub fn new(max_total: usize, max_per_user: usize) -> Self {
+        Self {
+            max_total,
+            max_per_user,
+            open: AtomicUsize::new(0),
+            next_id: AtomicU64::new(0),
+            per_user: DashMap::new(),
+            evictions: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    /// Connections currently open on this instance, including upgrades in progress.
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Reserves a place for a new connection, or returns `None` when the instance is full.
+    pub fn try_reserve(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let reserved = self
+            .open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < self.max_total).then_some(open + 1)
+            })
+            .is_ok();
+        if !reserved {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(ConnectionSlot {
+            tracker: self.clone(),
+            registered: None,
+        })
+    }
+}
+
+/// A connection's place under the instance limit, held for as long as the connection is
+/// open; dropping it frees the place and forgets the connection.
+pub struct ConnectionSlot {
+    tracker: Arc<ConnectionTracker>,
+    registered: Option<(Uuid, u64)>,
+}
+
+impl ConnectionSlot {
+    /// Records the connection as `user_id`'s newest and closes the user's oldest ones beyond
+    /// the per-user limit. The returned receiver fires if this connection is evicted in turn.
+    fn register(&mut self, user_id: Uuid) -> oneshot::Receiver<CloseReason> {
+        let tracker = &self.tracker;
+        let id = tracker.next_id.fetch_add(1, Ordering::Relaxed);
+        let (close_tx, close_rx) = oneshot::channel();
+        let evicted: Vec<_> = {
+            let mut connections = tracker.per_user.entry(user_id).or_default();
+            connections.push_back((id, close_tx));
+            let excess = connections.len().saturating_sub(tracker.max_per_user);
+            connections.drain(..excess).collect()
+        };
+        for (evicted_id, close) in evicted {
+            tracker.evictions.fetch_add(1, Ordering::Relaxed);
+            info!(
+                "User {} is over {} connections, closing their oldest connection {}",
+                user_id, tracker.max_per_user, evicted_id
+            );
+            let _ = close.send(CloseReason::ConnectionLimit);
+        }
+        self.registered = Some((user_id, id));
+        close_rx
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let tracker = &self.tracker;
+        if let Some((user_id, id)) = self.registered {
+            if let Some(mut connections) = tracker.per_user.get_mut(&user_id) {
+                connections.retain(|(other, _)| *other != id);
+            }
+            tracker
+                .per_user
+                .remove_if(&user_id, |_, connections| connections.is_empty());
+        }
+        tracker.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Response header carrying the id of the instance that served the request.
+pub const INSTANCE_ID_HEADER: &str = "x-instance-id";
+
+/// Client nonces seen recently on write messages, keyed by user, used to drop replayed frames.
+pub type NonceCache = Arc<DashMap<(Uuid, String), Instant>>;
+
+/// How long a nonce is remembered; replays within this window are ignored.
+const NONCE_WINDOW: Duration = Duration::from_secs(300);
+const NONCE_EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+/// Most nonces remembered at once. When the cache is full even after dropping expired
+/// nonces, new nonces are accepted without being remembered until older ones expire.
+const MAX_REMEMBERED_NONCES: usize = 100_000;
+const MAX_NONCE_LEN: usize = 128;
+
+/// Subprotocol for JSON text frames, the default when the client asks for none.
+pub const JSON_SUBPROTOCOL: &str = "safechat.json";
+/// Subprotocol for MessagePack binary frames, preferred when the client offers both.
+pub const MSGPACK_SUBPROTOCOL: &str = "safechat.msgpack";
+/// Offered subprotocols in order of preference.
+pub const SUBPROTOCOLS: [&str; 2] = [MSGPACK_SUBPROTOCOL, JSON_SUBPROTOCOL];
+
+/// How long the server waits for an `ack` unless `WS_ACK_TIMEOUT_MS` is set.
+pub const DEFAULT_WS_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest text or binary frame parsed unless `WS_MAX_FRAME_BYTES` is set.
+pub const DEFAULT_WS_MAX_FRAME_BYTES: usize = 128 * 1024;
+/// Frames up to this multiple of the limit are read and answered with an `error`; larger
+/// ones are not buffered, and the connection is closed after the `error`.
+const WS_PROTOCOL_LIMIT_FACTOR: usize = 8;
+/// How long a connection closed for an unreadable frame is kept open for the client to
+/// read the `error` and close frames.
+const FRAME_TOO_LARGE_LINGER: Duration = Duration::from_secs(1);
+/// Deepest nesting accepted in a client frame, JSON or MessagePack; real messages use three levels.
+const MAX_FRAME_DEPTH: usize = 32;
+/// Rejected frames a connection may send before it is closed as a policy violation.
+const MAX_FRAME_VIOLATIONS: u32 = 5;
+
+/// Why a client frame was rejected before parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameRejection {
+    TooLarge,
+    TooDeep,
+}
+
+impl FrameRejection {
+    fn notification(self, max_bytes: usize) -> ErrorNotification {
+        let (code, message) = match self {
+            FrameRejection::TooLarge => (
+                ErrorCode::FrameTooLarge,
+                format!("Frames may not exceed {} bytes", max_bytes),
+            ),
+            FrameRejection::TooDeep => (
+                ErrorCode::FrameTooDeep,
+                format!("Frames may not nest deeper than {} levels", MAX_FRAME_DEPTH),
+            ),
+        };
+        ErrorNotification {
+            code,
+            message,
+            message_id: None,
+            retry_after: None,
+        }
+    }
+}
+
+/// How frames on a connection are encoded, negotiated through the WebSocket subprotocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Text frames holding a JSON `WebSocketMessage`.
+    Json,
+    /// Binary frames holding one MessagePack-encoded `WebSocketMessage` each, with the
+    /// same field names and values as the JSON form.
+    MessagePack,
+}
+
+impl WireFormat {
+    fn negotiated(protocol: Option<&HeaderValue>) -> Self {
+        match protocol.and_then(|p| p.to_str().ok()) {
+            Some(MSGPACK_SUBPROTOCOL) => WireFormat::MessagePack,
+            _ => WireFormat::Json,
+        }
+    }
+
+    /// Encodes a server frame.
+    fn encode(self, message: &WebSocketMessage) -> Result<Message, String> {
+        match self {
+            WireFormat::Json => serde_json::to_string(message)
+                .map(Message::Text)
+                .map_err(|e| e.to_string()),
+            WireFormat::MessagePack => rmp_serde::to_vec_named(message)
+                .map(Message::Binary)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// A data frame received from a client.
+enum ClientFrame {
+    Text(String),
+    MessagePack(Vec<u8>),
+}
+
+/// Why a client frame could not be handled.
+enum FrameError {
+    /// Failed the checks; answered with an `error` event and counted against the connection.
+    Rejected(FrameRejection),
+    /// Passed the checks but is not a valid message; only logged.
+    Malformed(String),
+}
+
+impl ClientFrame {
+    fn len(&self) -> usize {
+        match self {
+            ClientFrame::Text(text) => text.len(),
+            ClientFrame::MessagePack(bytes) => bytes.len(),
+        }
+    }
+
+    /// Checks the frame, then parses it.
+    fn decode(&self, max_bytes: usize) -> Result<WebSocketMessage, FrameError> {
+        match self {
+            ClientFrame::Text(text) => {
+                check_client_frame(text, max_bytes).map_err(FrameError::Rejected)?;
+                serde_json::from_str(text).map_err(|e| {
+                    FrameError::Malformed(format!("Failed to parse client message: {}", e))
+                })
+            }
+            ClientFrame::MessagePack(bytes) => {
+                if bytes.len() > max_bytes {
+                    return Err(FrameError::Rejected(FrameRejection::TooLarge));
+                }
+                decode_msgpack_frame(bytes)
+            }
+        }
+    }
+}
+
+/// Parses a MessagePack frame. MessagePack cannot be scanned for nesting as cheaply as
+/// JSON, so the decoder itself stops at `MAX_FRAME_DEPTH`.
+fn decode_msgpack_frame(bytes: &[u8]) -> Result<WebSocketMessage, FrameError> {
+    let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes);
+    deserializer.set_max_depth(MAX_FRAME_DEPTH);
+    WebSocketMessage::deserialize(&mut deserializer).map_err(|e| match e {
+        rmp_serde::decode::Error::DepthLimitExceeded => {
+            FrameError::Rejected(FrameRejection::TooDeep)
+        }
+        e => FrameError::Malformed(format!("Failed to parse client message: {}", e)),
+    })
+}
+
+/// Cheap checks run on every text frame before it reaches the JSON parser.
+fn check_client_frame(text: &str, max_bytes: usize) -> Result<(), FrameRejection> {
+    if text.len() > max_bytes {
+        return Err(FrameRejection::TooLarge);
+    }
+    if json_depth_exceeds(text.as_bytes(), MAX_FRAME_DEPTH) {
+        return Err(FrameRejection::TooDeep);
+    }
+    Ok(())
+}
+
+/// Whether `json` opens more than `max_depth` nested arrays or objects. Brackets inside
+/// strings are skipped; the input is otherwise not validated.
+fn json_depth_exceeds(json: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Probes one sender may send one receiver per `PROBE_WINDOW`.
+const PROBE_LIMIT_PER_WINDOW: u32 = 5;
+const PROBE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Rate limit key of the probes from `sender_id` to `receiver_id`.
+fn probe_limit_key(sender_id: Uuid, receiver_id: Uuid) -> String {
+    format!("probe:{}:{}", sender_id, receiver_id)
+}
+
+/// A connection's acknowledgement state: whether the client opted in with `hello`, and the
+/// events it has not acknowledged yet, keyed by `ack_id`, with the time they were first sent.
+#[derive(Clone, Default)]
+pub struct PendingAcks {
+    enabled: Arc<AtomicBool>,
+    events: Arc<tokio::sync::Mutex<HashMap<u64, (WSEvent, Instant)>>>,
+}
+
+impl PendingAcks {
+    /// Whether the client asked for acknowledgements in its `hello`.
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    async fn lock(&self) -> tokio::sync::MutexGuard<'_, HashMap<u64, (WSEvent, Instant)>> {
+        self.events.lock().await
+    }
+}
+
+/// Unacknowledged events are resent this many times before the connection is deemed unhealthy.
+const MAX_ACK_RETRIES: u32 = 3;
+
+/// What to do with an unacknowledged event that was first sent `elapsed` ago.
+#[derive(Debug, PartialEq, Eq)]
+enum AckRetry {
+    Wait,
+    Resend,
+    GiveUp,
+}
+
+fn ack_retry(elapsed: Duration, timeout: Duration) -> AckRetry {
+    // Retries are due at every multiple of the timeout after the first send
+    let due = elapsed.as_millis() / timeout.as_millis().max(1);
+    if due == 0 {
+        AckRetry::Wait
+    } else if due <= MAX_ACK_RETRIES as u128 {
+        AckRetry::Resend
+    } else {
+        AckRetry::GiveUp
+    }
+}
+
 #[derive(Deserialize)]
 pub struct WSQueryParams {
     token: String,
+    /// The registered device opening the connection, when `MULTI_DEVICE` is enabled.
+    #[serde(default)]
+    device_id: Option<String>,
+}
+
+/// How a connection opened for a device handles an event on the user's channel.
+#[derive(Debug, PartialEq, Eq)]
+enum DeviceRouting {
+    Deliver,
+    Skip,
+    Close,
+}
+
+/// Message copies encrypted for one device only reach that device's connections, and
+/// connections without a device only get untargeted messages. A device's own revocation
+/// closes its connections; other devices receive it as a notification.
+fn route_for_device(event: &WSEvent, connection_device: Option<Uuid>) -> DeviceRouting {
+    let target = match event {
+        WSEvent::NewMessage(message) => match message.device_id.as_deref() {
+            Some(target) => target,
+            None => return DeviceRouting::Deliver,
+        },
+        WSEvent::DeviceRevoked(revoked) => {
+            return if Uuid::parse_str(&revoked.device_id).ok() == connection_device
+                && connection_device.is_some()
+            {
+                DeviceRouting::Close
+            } else {
+                DeviceRouting::Deliver
+            };
+        }
+        _ => return DeviceRouting::Deliver,
+    };
+    if connection_device.is_some() && Uuid::parse_str(target).ok() == connection_device {
+        DeviceRouting::Deliver
+    } else {
+        DeviceRouting::Skip
+    }
+}
+
+/// Adds a connection to the user's shared channel, creating the channel for their first
+/// connection. Subscribing happens under the map entry's lock, so a disconnecting
+/// connection cannot remove the channel in between. Returns whether this is the user's
+/// only connection.
+fn join_user_channel(
+    connections: &ConnectionManager,
+    user_id: Uuid,
+) -> (
+    broadcast::Sender<WSEvent>,
+    broadcast::Receiver<WSEvent>,
+    bool,
+) {
+    match connections.entry(user_id) {
+        Entry::Occupied(existing) => {
+            let tx = existing.get().clone();
+            let rx = tx.subscribe();
+            (tx, rx, false)
+        }
+        Entry::Vacant(slot) => {
+            let (tx, rx) = broadcast::channel(100);
+            slot.insert(tx.clone());
+            (tx, rx, true)
+        }
+    }
 }
 
 pub async fn websocket_handler(
@@ -83,50 +780,187 @@ pub async fn websocket_handler(
     State(state): State<Arc<AppState>>,
 ) -> Result<Response, StatusCode> {
     // Validate JWT token
-    let user_id = match decode_jwt_token(&params.token, &state.jwt_secret) {
-        Ok(claims) => claims.sub,
-        Err(_) => {
-            warn!("WebSocket connection attempt with invalid token");
+    let (user_id, token_exp) = match decode_jwt_token(
+        &params.token,
+        &state.jwt_secret,
+        state.jwt_leeway_secs,
+        state.clock.now(),
+    ) {
+        Ok(claims) => (claims.sub, claims.exp),
+        Err(_) if params.token.is_empty() => {
+            warn!("WebSocket connection attempt without a token");
             return Err(StatusCode::UNAUTHORIZED);
         }
+        Err(e) => {
+            // Browsers hide the status of a refused upgrade, so say why over the socket
+            let reason = match e.kind() {
+                ErrorKind::ExpiredSignature => CloseReason::TokenExpired,
+                _ => CloseReason::InvalidToken,
+            };
+            warn!(
+                "WebSocket connection attempt with rejected token: {}",
+                reason.reason()
+            );
+            return Ok(ws
+                .protocols(SUBPROTOCOLS)
+                .on_upgrade(move |socket| reject_connection(socket, reason)));
+        }
+    };
+
+    info!(
+        "WebSocket connection established for user: {} on instance {}",
+        user_id, state.instance_id
+    );
+
+    let device_id = match params.device_id.as_deref() {
+        None => None,
+        Some(_) if !state.multi_device => {
+            warn!(
+                "WebSocket connection for user {} named a device, but MULTI_DEVICE is disabled",
+                user_id
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Some(raw) => {
+            let device_id = Uuid::parse_str(raw).map_err(|_| StatusCode::BAD_REQUEST)?;
+            match service::device_belongs_to(state.devices.as_ref(), user_id, device_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(
+                        "WebSocket connection for user {} with unknown device {}",
+                        user_id, device_id
+                    );
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                Err(e) => {
+                    error!("Failed to look up device {}: {}", device_id, e);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+            if let Err(e) = state
+                .devices
+                .touch_device(device_id, state.clock.now())
+                .await
+            {
+                warn!("Failed to record activity of device {}: {}", device_id, e);
+            }
+            Some(device_id)
+        }
     };
 
-    info!("WebSocket connection established for user: {}", user_id);
+    let Some(slot) = state.connection_tracker.try_reserve() else {
+        warn!(
+            "Refusing WebSocket connection for user {}: {} connections open",
+            user_id,
+            state.connection_tracker.open_connections()
+        );
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    };
+
+    let instance_id = HeaderValue::from_str(&state.instance_id.to_string())
+        .expect("UUIDs are valid header values");
+    let protocol_limit = state.ws_max_frame_bytes * WS_PROTOCOL_LIMIT_FACTOR;
+    let ws = ws
+        .protocols(SUBPROTOCOLS)
+        .max_frame_size(protocol_limit)
+        .max_message_size(protocol_limit);
+    let mut response = ws.on_upgrade(move |socket| {
+        handle_websocket(socket, user_id, device_id, token_exp, slot, state)
+    });
+    // Lets load balancers pin the client to this instance
+    response
+        .headers_mut()
+        .insert(INSTANCE_ID_HEADER, instance_id);
+    Ok(response)
+}
 
-    Ok(ws.on_upgrade(move |socket| {
-        handle_websocket(socket, user_id, state)
-    }))
+/// Tells the client why its token was rejected with an `auth_failed` event, then closes
+/// the connection with the same reason.
+async fn reject_connection(mut socket: WebSocket, reason: CloseReason) {
+    let format = WireFormat::negotiated(socket.protocol());
+    let event = event_message(&WSEvent::AuthFailed(AuthFailed::for_reason(reason)))
+        .expect("auth_failed is sent to the client");
+    match format.encode(&event) {
+        Ok(frame) => {
+            if let Err(e) = socket.send(frame).await {
+                warn!("Failed to send auth_failed ({}): {}", reason.reason(), e);
+            }
+        }
+        Err(e) => error!("Failed to encode auth_failed: {}", e),
+    }
+    if let Err(e) = socket
+        .send(Message::Close(Some(reason.close_frame())))
+        .await
+    {
+        warn!("Failed to send close frame ({}): {}", reason.reason(), e);
+    }
 }
 
 async fn handle_websocket(
     socket: WebSocket,
     user_id: Uuid,
+    device_id: Option<Uuid>,
+    token_exp: usize,
+    mut slot: ConnectionSlot,
     state: Arc<AppState>,
 ) {
+    let format = WireFormat::negotiated(socket.protocol());
+    let mut evicted = slot.register(user_id);
     let (sender, mut receiver) = socket.split();
     let sender = Arc::new(tokio::sync::Mutex::new(sender));
 
-    // Create broadcast channel for this user
-    let (tx, mut rx) = broadcast::channel(100);
-    state.connections.insert(user_id, tx.clone());
+    let (tx, mut rx, first_connection) = if state.multi_device {
+        join_user_channel(&state.connections, user_id)
+    } else {
+        // Create broadcast channel for this user
+        let (tx, rx) = broadcast::channel(100);
+        if let Some(previous) = state.connections.insert(user_id, tx.clone()) {
+            info!(
+                "Replacing existing WebSocket connection for user {}",
+                user_id
+            );
+            let _ = previous.send(WSEvent::Close(CloseReason::Replaced));
+        }
+        (tx, rx, true)
+    };
+
+    subscribe_to_user_channel(&state, user_id).await;
 
-    info!("User {} connected to WebSocket", user_id);
+    info!(
+        "User {} connected to WebSocket (device {:?}, {:?} frames)",
+        user_id, device_id, format
+    );
 
     // Broadcast user online status
-    broadcast_to_all(&state.connections, WSEvent::UserOnline(user_id.to_string())).await;
+    if first_connection {
+        broadcast_to_all(&state.connections, WSEvent::UserOnline(user_id.to_string())).await;
+    }
+
+    let pending_acks = PendingAcks::default();
 
     // Handle incoming messages from client
     let connections_clone = state.connections.clone();
+    let pending_acks_clone = pending_acks.clone();
     let state_clone = state.clone();
     let user_id_clone = user_id;
     let sender_clone = sender.clone();
-    let incoming_task = tokio::spawn(async move {
+    // Same tolerance as the handshake, so the session ends when the token stops validating
+    let session_expiry = token_exp + state.jwt_leeway_secs as usize;
+    let mut incoming_task = tokio::spawn(async move {
+        let mut frame_violations = 0;
         while let Some(msg) = receiver.next().await {
-            match msg {
-                Ok(Message::Text(text)) => {
-                    if let Err(e) = handle_client_message(&text, user_id_clone, &connections_clone, state_clone.clone()).await {
-                        error!("Error handling client message: {}", e);
-                    }
+            let frame = match msg {
+                Ok(Message::Text(text)) => ClientFrame::Text(text),
+                Ok(Message::Binary(bytes)) if format == WireFormat::MessagePack => {
+                    ClientFrame::MessagePack(bytes)
+                }
+                Ok(Message::Binary(_)) => {
+                    warn!(
+                        "Binary frame received from user {} without the {} subprotocol, closing connection",
+                        user_id_clone, MSGPACK_SUBPROTOCOL
+                    );
+                    send_close(&sender_clone, CloseReason::PolicyViolation).await;
+                    break;
                 }
                 Ok(Message::Close(_)) => {
                     info!("WebSocket connection closed by client for user: {}", user_id_clone);
@@ -137,85 +971,441 @@ async fn handle_websocket(
                     if sender_guard.send(Message::Pong(data)).await.is_err() {
                         break;
                     }
+                    continue;
                 }
                 Ok(_) => {
                     // Handle other message types if needed
+                    continue;
+                }
+                Err(e) if exceeds_protocol_limit(&e) => {
+                    warn!(
+                        "Frame from user {} exceeds the WebSocket limit, closing connection",
+                        user_id_clone
+                    );
+                    // Written directly: the event queue is not flushed once the connection closes
+                    let notification =
+                        FrameRejection::TooLarge.notification(state_clone.ws_max_frame_bytes);
+                    if let Some(message) = event_message(&WSEvent::Error(notification))
+                        && send_ws_message(&sender_clone, &message, format)
+                            .await
+                            .is_ok()
+                    {
+                        send_close(&sender_clone, CloseReason::FrameTooLarge).await;
+                        // The rest of the frame is never read, so dropping the socket right
+                        // away resets it and the client may lose both frames
+                        sleep(FRAME_TOO_LARGE_LINGER).await;
+                    }
+                    break;
                 }
                 Err(e) => {
                     error!("WebSocket error for user {}: {}", user_id_clone, e);
                     break;
                 }
-            }
-        }
-    });
-
-    // Handle outgoing messages to client
-    let outgoing_task = tokio::spawn(async move {
-        while let Ok(event) = rx.recv().await {
-            let message = match event {
-                WSEvent::NewMessage(msg) => WebSocketMessage {
-                    message_type: "new_message".to_string(),
-                    data: serde_json::to_value(msg).unwrap_or_default(),
-                },
-                WSEvent::StatusUpdate(update) => WebSocketMessage {
-                    message_type: "status_update".to_string(),
-                    data: serde_json::to_value(update).unwrap_or_default(),
-                },
-                WSEvent::UserOnline(user) => WebSocketMessage {
-                    message_type: "user_online".to_string(),
-                    data: serde_json::json!({ "user_id": user }),
-                },
-                WSEvent::UserOffline(user) => WebSocketMessage {
-                    message_type: "user_offline".to_string(),
-                    data: serde_json::json!({ "user_id": user }),
-                },
             };
-
-            let text = match serde_json::to_string(&message) {
-                Ok(text) => text,
-                Err(e) => {
-                    error!("Failed to serialize WebSocket message: {}", e);
+            if state_clone.clock.now().timestamp() as usize >= session_expiry {
+                info!("WebSocket token expired for user: {}", user_id_clone);
+                send_close(&sender_clone, CloseReason::TokenExpired).await;
+                break;
+            }
+            let max_bytes = state_clone.ws_max_frame_bytes;
+            let message = match frame.decode(max_bytes) {
+                Ok(message) => message,
+                Err(FrameError::Rejected(rejection)) => {
+                    frame_violations += 1;
+                    warn!(
+                        "Rejected {:?} frame ({} bytes) from user {}, violation {}",
+                        rejection,
+                        frame.len(),
+                        user_id_clone,
+                        frame_violations
+                    );
+                    if frame_violations >= MAX_FRAME_VIOLATIONS {
+                        send_close(&sender_clone, CloseReason::PolicyViolation).await;
+                        break;
+                    }
+                    send_error_to_user(
+                        &connections_clone,
+                        user_id_clone,
+                        rejection.notification(max_bytes),
+                    );
+                    continue;
+                }
+                Err(FrameError::Malformed(e)) => {
+                    error!("Error handling client message: {}", e);
                     continue;
                 }
             };
-
-            let mut sender_guard = sender.lock().await;
-            if sender_guard.send(Message::Text(text)).await.is_err() {
-                break;
+            if let Err(e) = handle_client_message(
+                message,
+                user_id_clone,
+                &connections_clone,
+                &pending_acks_clone,
+                state_clone.clone(),
+            )
+            .await
+            {
+                error!("Error handling client message: {}", e);
             }
         }
     });
 
-    // Wait for either task to complete
-    tokio::select! {
-        _ = incoming_task => {},
-        _ = outgoing_task => {},
-    }
-
-    // Clean up connection
-    state.connections.remove(&user_id);
-    info!("User {} disconnected from WebSocket", user_id);
-
-    // Broadcast user offline status
-    broadcast_to_all(&state.connections, WSEvent::UserOffline(user_id.to_string())).await;
+    // Handle outgoing messages to client
+    let ack_timeout = state.ws_ack_timeout;
+    let outgoing_state = state.clone();
+    let mut outgoing_task = tokio::spawn(async move {
+        let mut next_ack_id: u64 = 0;
+        // Set once the client stops acknowledging; it is then no longer asked to
+        let mut unhealthy = false;
+        let mut retry_interval = tokio::time::interval(ack_timeout);
+        loop {
+            let received = tokio::select! {
+                received = rx.recv() => received,
+                reason = &mut evicted => {
+                    if let Ok(reason) = reason {
+                        info!("Closing WebSocket for user {}: {}", user_id, reason.reason());
+                        send_close(&sender, reason).await;
+                    }
+                    break;
+                }
+                _ = retry_interval.tick() => {
+                    if unhealthy {
+                        continue;
+                    }
+                    match resend_unacked(&sender, &pending_acks, ack_timeout, format, &outgoing_state).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            warn!(
+                                "User {} did not acknowledge events after {} retries, connection is unhealthy",
+                                user_id, MAX_ACK_RETRIES
+                            );
+                            unhealthy = true;
+                            let given_up: Vec<WSEvent> =
+                                pending_acks.lock().await.drain().map(|(_, (event, _))| event).collect();
+                            for message_id in given_up.iter().filter_map(acked_message_id) {
+                                let error = format!("Not acknowledged after {} retries", MAX_ACK_RETRIES);
+                                record_ack_attempt(&outgoing_state, message_id, service::DELIVERY_EXHAUSTED, Some(error));
+                            }
+                        }
+                        Err(()) => break,
+                    }
+                    continue;
+                }
+            };
+            let event = match received {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "User {} lagged behind by {} events, closing connection",
+                        user_id, skipped
+                    );
+                    send_close(&sender, CloseReason::TryAgainLater).await;
+                    break;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if let WSEvent::Close(reason) = event {
+                info!(
+                    "Closing WebSocket for user {}: {}",
+                    user_id,
+                    reason.reason()
+                );
+                send_close(&sender, reason).await;
+                break;
+            }
+            match route_for_device(&event, device_id) {
+                DeviceRouting::Deliver => {}
+                DeviceRouting::Skip => continue,
+                DeviceRouting::Close => {
+                    info!(
+                        "Closing WebSocket for user {}: device {:?} revoked",
+                        user_id, device_id
+                    );
+                    send_close(&sender, CloseReason::DeviceRevoked).await;
+                    break;
+                }
+            }
+            let Some(mut message) = event_message(&event) else {
+                continue;
+            };
+            if event.requires_ack() && pending_acks.enabled() && !unhealthy {
+                next_ack_id += 1;
+                message.ack_id = Some(next_ack_id);
+                pending_acks
+                    .lock()
+                    .await
+                    .insert(next_ack_id, (event, Instant::now()));
+            }
+
+            if send_ws_message(&sender, &message, format).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Wait for either task to complete, then stop the other so its receiver is dropped
+    let other_task = tokio::select! {
+        _ = &mut incoming_task => outgoing_task,
+        _ = &mut outgoing_task => incoming_task,
+    };
+    other_task.abort();
+    let _ = other_task.await;
+
+    // Clean up connection, unless a newer connection for this user has replaced it or,
+    // with several devices, another connection still shares the channel
+    let removed = state
+        .connections
+        .remove_if(&user_id, |_, current| {
+            current.same_channel(&tx) && current.receiver_count() == 0
+        })
+        .is_some();
+    info!("User {} disconnected from WebSocket", user_id);
+
+    // Broadcast user offline status
+    if removed {
+        if let Err(err) = state.users.set_last_seen(user_id, state.clock.now()).await {
+            warn!(
+                "Failed to record last seen time for user {}: {}",
+                user_id, err
+            );
+        }
+        unsubscribe_from_user_channel(&state, user_id).await;
+        broadcast_to_all(
+            &state.connections,
+            WSEvent::UserOffline(user_id.to_string()),
+        )
+        .await;
+    }
+}
+
+type SharedSink = Arc<tokio::sync::Mutex<futures_util::stream::SplitSink<WebSocket, Message>>>;
+
+/// Converts an event into the frame sent to the client; `Close` has no frame.
+pub(crate) fn event_message(event: &WSEvent) -> Option<WebSocketMessage> {
+    let (message_type, data) = match event {
+        WSEvent::NewMessage(msg) => ("new_message", serde_json::to_value(msg)),
+        WSEvent::SealedMessage(msg) => ("sealed_message", serde_json::to_value(msg)),
+        WSEvent::StatusUpdate(update) => ("status_update", serde_json::to_value(update)),
+        WSEvent::DeliveryReceipt(receipt) => ("delivery_receipt", serde_json::to_value(receipt)),
+        WSEvent::UserOnline(user) => (
+            "user_online",
+            serde_json::to_value(Presence { user_id: user }),
+        ),
+        WSEvent::UserOffline(user) => (
+            "user_offline",
+            serde_json::to_value(Presence { user_id: user }),
+        ),
+        WSEvent::PinUpdate(update) => ("pin_update", serde_json::to_value(update)),
+        WSEvent::MetaUpdate(update) => ("message_meta_update", serde_json::to_value(update)),
+        WSEvent::DeviceRevoked(revoked) => ("device_revoked", serde_json::to_value(revoked)),
+        WSEvent::ContactRequest(request) => ("contact_request", serde_json::to_value(request)),
+        WSEvent::ConversationCleared(cleared) => {
+            ("conversation_cleared", serde_json::to_value(cleared))
+        }
+        WSEvent::MessageDeleted(deleted) => ("message_deleted", serde_json::to_value(deleted)),
+        WSEvent::Announcement(announcement) => ("announcement", serde_json::to_value(announcement)),
+        WSEvent::Probe(probe) => ("probe", serde_json::to_value(probe)),
+        WSEvent::ProbeResult(result) => ("probe_result", serde_json::to_value(result)),
+        WSEvent::Error(err) => ("error", serde_json::to_value(err)),
+        WSEvent::HelloAck(ack) => ("hello_ack", serde_json::to_value(ack)),
+        WSEvent::UnreadCounts(counts) => ("unread_counts", serde_json::to_value(counts)),
+        WSEvent::Ack(ack) => ("ack", serde_json::to_value(ack)),
+        WSEvent::AuthFailed(failed) => ("auth_failed", serde_json::to_value(failed)),
+        WSEvent::Close(_) => return None,
+    };
+    Some(WebSocketMessage {
+        message_type: message_type.to_string(),
+        data: data.unwrap_or_default(),
+        ack_id: None,
+    })
+}
+
+/// Whether a read failed because the frame or message was larger than the limits set on
+/// the upgrade, rather than because the connection broke.
+fn exceeds_protocol_limit(error: &axum::Error) -> bool {
+    std::error::Error::source(error)
+        .and_then(|source| source.downcast_ref::<tokio_tungstenite::tungstenite::Error>())
+        .is_some_and(|e| matches!(e, tokio_tungstenite::tungstenite::Error::Capacity(_)))
+}
+
+/// Serializes and writes a frame in the connection's format. Serialization failures are
+/// logged and skipped; only a broken socket is reported as an error.
+async fn send_ws_message(
+    sender: &SharedSink,
+    message: &WebSocketMessage,
+    format: WireFormat,
+) -> Result<(), ()> {
+    let frame = match format.encode(message) {
+        Ok(frame) => frame,
+        Err(e) => {
+            error!("Failed to serialize WebSocket message: {}", e);
+            return Ok(());
+        }
+    };
+    let mut sender_guard = sender.lock().await;
+    sender_guard.send(frame).await.map_err(|_| ())
+}
+
+/// The message a `new_message` event carries, whose resends are recorded as delivery attempts.
+fn acked_message_id(event: &WSEvent) -> Option<Uuid> {
+    match event {
+        WSEvent::NewMessage(message) => Uuid::parse_str(&message.id).ok(),
+        _ => None,
+    }
+}
+
+/// Records a resend of an unacknowledged message, or giving up on it, as a delivery attempt.
+fn record_ack_attempt(state: &AppState, message_id: Uuid, outcome: &str, error: Option<String>) {
+    record_delivery_attempt(
+        state,
+        DeliveryAttemptRecord {
+            message_id,
+            attempt_at: state.clock.now(),
+            channel: "ws".to_string(),
+            outcome: outcome.to_string(),
+            error,
+        },
+    );
+}
+
+/// Resends every unacknowledged event whose retry is due, recording each resent message.
+/// Returns `Ok(false)` once an event has used up its retries, and `Err(())` if the socket
+/// is broken.
+async fn resend_unacked(
+    sender: &SharedSink,
+    pending_acks: &PendingAcks,
+    timeout: Duration,
+    format: WireFormat,
+    state: &AppState,
+) -> Result<bool, ()> {
+    let mut due = Vec::new();
+    {
+        let pending = pending_acks.lock().await;
+        for (ack_id, (event, sent_at)) in pending.iter() {
+            match ack_retry(sent_at.elapsed(), timeout) {
+                AckRetry::Wait => {}
+                AckRetry::Resend => due.push((*ack_id, event.clone())),
+                AckRetry::GiveUp => return Ok(false),
+            }
+        }
+    }
+    for (ack_id, event) in due {
+        if let Some(mut message) = event_message(&event) {
+            message.ack_id = Some(ack_id);
+            send_ws_message(sender, &message, format).await?;
+            if let Some(message_id) = acked_message_id(&event) {
+                record_ack_attempt(state, message_id, service::DELIVERY_RESENT, None);
+            }
+        }
+    }
+    Ok(true)
+}
+
+async fn send_close(sender: &SharedSink, reason: CloseReason) {
+    let mut sender_guard = sender.lock().await;
+    if let Err(e) = sender_guard
+        .send(Message::Close(Some(reason.close_frame())))
+        .await
+    {
+        warn!("Failed to send close frame ({}): {}", reason.reason(), e);
+    }
 }
 
+#[tracing::instrument(
+    skip(message, connections, pending_acks, state),
+    fields(user_id = %user_id, message_type = Empty)
+)]
 async fn handle_client_message(
-    text: &str,
+    message: WebSocketMessage,
     user_id: Uuid,
     connections: &ConnectionManager,
+    pending_acks: &PendingAcks,
     state: Arc<AppState>,
 ) -> Result<(), String> {
-    let message: WebSocketMessage = serde_json::from_str(text)
-        .map_err(|e| format!("Failed to parse client message: {}", e))?;
+    Span::current().record("message_type", message.message_type.as_str());
 
     info!("Received WebSocket message from user {}: {:?}", user_id, message.message_type);
 
+    // Write messages may carry a nonce; a repeat within the window is acknowledged but not re-applied
+    let is_write = matches!(
+        message.message_type.as_str(),
+        "send_message" | "update_status" | "signed_receipt"
+    );
+    let nonce = if is_write {
+        message
+            .data
+            .get("nonce")
+            .and_then(|n| n.as_str())
+            .map(str::to_string)
+    } else {
+        None
+    };
+    if let Some(ref nonce) = nonce {
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(format!("Invalid nonce from user {}", user_id));
+        }
+        if !remember_nonce(&state.nonces, user_id, nonce, Instant::now()) {
+            info!(
+                "Ignoring replayed {} from user {} (nonce {})",
+                message.message_type, user_id, nonce
+            );
+            send_ack_to_user(connections, user_id, nonce.clone(), true);
+            return Ok(());
+        }
+    }
+
+    let handled =
+        dispatch_client_message(message, user_id, connections, pending_acks, state.clone()).await;
+    if let Some(nonce) = nonce {
+        if handled.is_ok() {
+            send_ack_to_user(connections, user_id, nonce, false);
+        } else {
+            // The write was not applied, so a retry with the same nonce must run again
+            forget_nonce(&state.nonces, user_id, &nonce);
+        }
+    }
+    handled
+}
+
+/// Runs a client message after its nonce was checked.
+async fn dispatch_client_message(
+    message: WebSocketMessage,
+    user_id: Uuid,
+    connections: &ConnectionManager,
+    pending_acks: &PendingAcks,
+    state: Arc<AppState>,
+) -> Result<(), String> {
     match message.message_type.as_str() {
         "ping" => {
             // Handle ping/pong for connection health
             info!("Received ping from user: {}", user_id);
         }
+        "ack" => {
+            // Confirms delivery of a server event sent with an `ack_id`
+            let data: ClientAckData = serde_json::from_value(message.data)
+                .map_err(|e| format!("Invalid ack data: {}", e))?;
+            if pending_acks.lock().await.remove(&data.ack_id).is_none() {
+                info!(
+                    "User {} acknowledged unknown ack_id {}",
+                    user_id, data.ack_id
+                );
+            }
+        }
+        "hello" => {
+            // Lets the client negotiate which encryption versions it may use, and opt in to
+            // acknowledging events. Clients that predate `HelloData` may send any data.
+            let data: HelloData = serde_json::from_value(message.data).unwrap_or_default();
+            if data.acks {
+                pending_acks.enabled.store(true, Ordering::Relaxed);
+            }
+            send_hello_ack_to_user(connections, user_id);
+        }
+        "unread_counts" => {
+            // Same data as GET /messages/unread-counts, without a separate HTTP call
+            let counts = timed_db("unread_counts", state.messages.unread_counts(user_id, None))
+                .await
+                .map_err(|e| format!("Failed to load unread counts for user {}: {}", user_id, e))?;
+            send_unread_counts_to_user(connections, user_id, counts.into());
+        }
         "mark_typing" => {
             // Could implement typing indicators here
             info!("User {} is typing", user_id);
@@ -224,23 +1414,275 @@ async fn handle_client_message(
             handle_send_message(user_id, message.data, connections, state).await?;
         }
         "update_status" => {
-            handle_update_status(user_id, message.data, connections, state).await?;
+            handle_update_status(user_id, message.data, state).await?;
+        }
+        "signed_receipt" => {
+            handle_signed_receipt(user_id, message.data, state).await?;
         }
         _ => {
             warn!("Unknown message type: {}", message.message_type);
         }
     }
-
     Ok(())
 }
 
+/// Runs a database call in a `db_query` child span that records its `db_latency_ms`.
+async fn timed_db<F: std::future::Future>(query: &'static str, call: F) -> F::Output {
+    let span = info_span!("db_query", query, db_latency_ms = Empty);
+    async move {
+        let started = Instant::now();
+        let output = call.await;
+        Span::current().record("db_latency_ms", started.elapsed().as_secs_f64() * 1000.0);
+        output
+    }
+    .instrument(span)
+    .await
+}
+
+const UNKNOWN_DEVICE_MESSAGE: &str = "device_id is not a device of the receiver";
+const NOT_A_CONTACT_MESSAGE: &str = "Receiver has not added you as a contact";
+const SEALED_NEW_CONVERSATION_MESSAGE: &str =
+    "Sealed messages cannot start a conversation; send the first message unsealed";
+const INVALID_REPLY_TARGET_MESSAGE: &str = "reply_to is not a message of this conversation";
+
+/// The rules of `send_message` that need no database, each broken one with the code and
+/// message of the `error` it gets. `send_message` reports the first; `POST /messages/validate`
+/// reports them all. A probe is only checked for being addressed to its sender.
+pub fn send_rule_violations(
+    state: &AppState,
+    to_self: bool,
+    data: &SendMessageData,
+) -> Vec<(ErrorCode, String)> {
+    let mut violations = Vec::new();
+    if to_self && !state.self_messages_enabled {
+        violations.push((
+            ErrorCode::SelfMessageDisabled,
+            "Messages to yourself are not enabled on this server".to_string(),
+        ));
+    }
+    if data.r#type == service::PROBE_MESSAGE_TYPE {
+        return violations;
+    }
+    if !encryption_version_supported(data.encryption_version) {
+        violations.push((
+            ErrorCode::UnsupportedEncryptionVersion,
+            format!(
+                "Encryption version {} is not supported; maximum is {}",
+                data.encryption_version,
+                max_encryption_version()
+            ),
+        ));
+    }
+    if !state.cipher_suites.contains(&data.cipher_suite) {
+        violations.push((
+            ErrorCode::UnsupportedCipherSuite,
+            format!(
+                "Cipher suite {} is not supported; expected one of {}",
+                data.cipher_suite,
+                state.cipher_suites.join(", ")
+            ),
+        ));
+    }
+    if !service::message_type_allowed(&data.r#type) {
+        violations.push((
+            ErrorCode::InvalidMessageType,
+            format!(
+                "Invalid type {}; expected one of {}",
+                data.r#type,
+                service::MESSAGE_TYPES.join(", ")
+            ),
+        ));
+    }
+    if data.sealed_metadata {
+        if !state.features.sealed_sender {
+            violations.push((
+                ErrorCode::SealedMetadataDisabled,
+                "This server does not accept sealed metadata".to_string(),
+            ));
+        } else if data.forwarded_from_id.is_some()
+            || data.reply_to.is_some()
+            || data.device_id.is_some()
+        {
+            violations.push((
+                ErrorCode::SealedMetadataUnsupported,
+                "Sealed messages cannot be forwarded, reply to a message or be addressed to a device"
+                    .to_string(),
+            ));
+        } else if data.cipher_suite != DEFAULT_CIPHER_SUITE {
+            // Sealed rows keep no cipher_suite, so the receiver assumes the default
+            violations.push((
+                ErrorCode::SealedMetadataUnsupported,
+                "Sealed messages must use AES_256_GCM".to_string(),
+            ));
+        }
+    } else if data.device_id.is_some() && !state.multi_device {
+        violations.push((
+            ErrorCode::MultiDeviceDisabled,
+            "This server does not support per-device messages".to_string(),
+        ));
+    }
+    violations
+}
+
+/// A `send_message` checked by `POST /messages/validate` without being sent.
+pub struct MessageDraft {
+    pub data: SendMessageData,
+    /// Fields the client left out because the message is not encrypted yet; they are not
+    /// checked.
+    pub omitted: Vec<&'static str>,
+    /// Size of the `send_message` frame that would carry the message.
+    pub frame_bytes: usize,
+}
+
+/// Every rule of `send_message` the draft breaks, including those that need the database:
+/// malformed ids and base64, the frame size, the receiver's existence, the forwarded and
+/// quoted messages, the target device and the contact rule. Send limits and the new conversation
+/// limit depend on when the message is sent and are not checked. Reads only.
+pub async fn validate_send_message(
+    state: &AppState,
+    sender_id: Uuid,
+    draft: &MessageDraft,
+) -> Result<Vec<(ErrorCode, String)>, service::ServiceError> {
+    let data = &draft.data;
+    let checked = |field: &str| !draft.omitted.contains(&field);
+    let parse_id = |field: &'static str, value: &str| {
+        Uuid::parse_str(value)
+            .map_err(|_| (ErrorCode::InvalidField, format!("{} is not a UUID", field)))
+    };
+    let mut violations = Vec::new();
+    let receiver_id = match parse_id("receiver_id", &data.receiver_id) {
+        Ok(receiver_id) => Some(receiver_id),
+        Err(violation) => {
+            violations.push(violation);
+            None
+        }
+    };
+    if checked("message_id")
+        && let Err(violation) = parse_id("message_id", &data.message_id)
+    {
+        violations.push(violation);
+    }
+    let to_self = receiver_id == Some(sender_id);
+    violations.extend(send_rule_violations(state, to_self, data));
+    if draft.frame_bytes > state.ws_max_frame_bytes {
+        violations.push((
+            ErrorCode::FrameTooLarge,
+            format!(
+                "The send_message frame would be {} bytes; the limit is {}",
+                draft.frame_bytes, state.ws_max_frame_bytes
+            ),
+        ));
+    }
+
+    // Probes carry no content and only need a receiver that accepts them
+    if data.r#type != service::PROBE_MESSAGE_TYPE {
+        let base64 = &base64::engine::general_purpose::STANDARD;
+        let encoded = [
+            ("encrypted_content", Some(&data.encrypted_content)),
+            ("iv", Some(&data.iv)),
+            ("encrypted_metadata", data.encrypted_metadata.as_ref()),
+        ];
+        for (field, value) in encoded {
+            if checked(field)
+                && let Some(value) = value
+                && base64.decode(value).is_err()
+            {
+                violations.push((
+                    ErrorCode::InvalidField,
+                    format!("{} is not valid base64", field),
+                ));
+            }
+        }
+        if let Some(source) = data.forwarded_from_id.as_deref() {
+            match parse_id("forwarded_from_id", source) {
+                Ok(source_id) => match service::check_forward_source(
+                    state.messages.as_ref(),
+                    sender_id,
+                    source_id,
+                )
+                .await
+                {
+                    Ok(()) => {}
+                    Err(service::ServiceError::NotFound(_)) => violations.push((
+                        ErrorCode::InvalidField,
+                        "forwarded_from_id is not a message you sent or received".to_string(),
+                    )),
+                    Err(err) => return Err(err),
+                },
+                Err(violation) => violations.push(violation),
+            }
+        }
+        if let Some(target) = data.reply_to.as_deref() {
+            match parse_id("reply_to", target) {
+                Ok(target_id) => {
+                    if let Some(receiver_id) = receiver_id {
+                        match service::check_reply_target(
+                            state.messages.as_ref(),
+                            sender_id,
+                            receiver_id,
+                            target_id,
+                        )
+                        .await
+                        {
+                            Ok(_) => {}
+                            Err(service::ServiceError::NotFound(_)) => violations.push((
+                                ErrorCode::InvalidReplyTarget,
+                                INVALID_REPLY_TARGET_MESSAGE.to_string(),
+                            )),
+                            Err(err) => return Err(err),
+                        }
+                    }
+                }
+                Err(violation) => violations.push(violation),
+            }
+        }
+        if let Some(device) = data.device_id.as_deref() {
+            match parse_id("device_id", device) {
+                Ok(device_id) => {
+                    if state.multi_device
+                        && !data.sealed_metadata
+                        && let Some(receiver_id) = receiver_id
+                        && !service::device_belongs_to(
+                            state.devices.as_ref(),
+                            receiver_id,
+                            device_id,
+                        )
+                        .await?
+                    {
+                        violations
+                            .push((ErrorCode::UnknownDevice, UNKNOWN_DEVICE_MESSAGE.to_string()));
+                    }
+                }
+                Err(violation) => violations.push(violation),
+            }
+        }
+    }
+
+    if let Some(receiver_id) = receiver_id
+        && !to_self
+    {
+        if state.users.find_by_id(receiver_id).await?.is_none() {
+            violations.push((
+                ErrorCode::UnknownReceiver,
+                "receiver_id is not a user".to_string(),
+            ));
+        } else if !can_message(state, sender_id, receiver_id).await? {
+            violations.push((ErrorCode::NotAContact, NOT_A_CONTACT_MESSAGE.to_string()));
+        }
+    }
+    Ok(violations)
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(sender_id = %sender_id, receiver_id = Empty, message_id = Empty, encrypted_content_bytes = Empty)
+)]
 async fn handle_send_message(
     sender_id: Uuid,
     data: serde_json::Value,
     connections: &ConnectionManager,
     state: Arc<AppState>,
 ) -> Result<(), String> {
-    
     let send_data: SendMessageData = serde_json::from_value(data)
         .map_err(|e| format!("Failed to parse send_message data: {}", e))?;
 
@@ -249,213 +1691,1366 @@ async fn handle_send_message(
         .map_err(|_| "Invalid receiver_id format".to_string())?;
     let message_id = Uuid::parse_str(&send_data.message_id)
         .map_err(|_| "Invalid message_id format".to_string())?;
+    let span = Span::current();
+    span.record("receiver_id", tracing::field::display(receiver_id));
+    span.record("message_id", tracing::field::display(message_id));
+
+    let to_self = sender_id == receiver_id;
+    if let Some((code, message)) = send_rule_violations(&state, to_self, &send_data)
+        .into_iter()
+        .next()
+    {
+        send_error_to_user(
+            connections,
+            sender_id,
+            ErrorNotification {
+                code,
+                message: message.clone(),
+                message_id: Some(message_id.to_string()),
+                retry_after: None,
+            },
+        );
+        return Err(format!(
+            "Message {} from {} rejected ({:?}): {}",
+            message_id, sender_id, code, message
+        ));
+    }
+
+    if send_data.r#type == service::PROBE_MESSAGE_TYPE {
+        return handle_probe(sender_id, receiver_id, message_id, connections, &state).await;
+    }
+
+    if send_data.sealed_metadata {
+        return handle_sealed_message(
+            sender_id,
+            receiver_id,
+            message_id,
+            send_data,
+            connections,
+            &state,
+        )
+        .await;
+    }
+
+    let forwarded_from_id = send_data
+        .forwarded_from_id
+        .as_deref()
+        .map(Uuid::parse_str)
+        .transpose()
+        .map_err(|_| "Invalid forwarded_from_id format".to_string())?;
+    if let Some(source_id) = forwarded_from_id {
+        timed_db(
+            "check_forward_source",
+            service::check_forward_source(state.messages.as_ref(), sender_id, source_id),
+        )
+        .await
+        .map_err(|e| format!("Cannot forward message {}: {}", source_id, e))?;
+    }
 
-    // Generate timestamp
-    let now = Utc::now().with_timezone(&Brussels);
-    let timestamp_millis = now.timestamp_millis();
+    let reply_to_id = send_data
+        .reply_to
+        .as_deref()
+        .map(Uuid::parse_str)
+        .transpose()
+        .map_err(|_| "Invalid reply_to format".to_string())?;
+    let mut reply_to_deleted = false;
+    if let Some(target_id) = reply_to_id {
+        match timed_db(
+            "check_reply_target",
+            service::check_reply_target(state.messages.as_ref(), sender_id, receiver_id, target_id),
+        )
+        .await
+        {
+            Ok(deleted) => reply_to_deleted = deleted,
+            Err(service::ServiceError::NotFound(_)) => {
+                send_error_to_user(
+                    connections,
+                    sender_id,
+                    ErrorNotification {
+                        code: ErrorCode::InvalidReplyTarget,
+                        message: INVALID_REPLY_TARGET_MESSAGE.to_string(),
+                        message_id: Some(message_id.to_string()),
+                        retry_after: None,
+                    },
+                );
+                return Err(format!(
+                    "Message {} rejected ({:?}): {} is in another conversation",
+                    message_id,
+                    ErrorCode::InvalidReplyTarget,
+                    target_id
+                ));
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Database error checking reply target {}: {}",
+                    target_id, e
+                ));
+            }
+        }
+    }
+
+    let device_id = send_data
+        .device_id
+        .as_deref()
+        .map(Uuid::parse_str)
+        .transpose()
+        .map_err(|_| "Invalid device_id format".to_string())?;
+    // `send_rule_violations` already refused a device_id without MULTI_DEVICE
+    if let Some(device_id) = device_id {
+        let known = timed_db(
+            "check_target_device",
+            service::device_belongs_to(state.devices.as_ref(), receiver_id, device_id),
+        )
+        .await
+        .map_err(|e| format!("Database error checking device {}: {}", device_id, e))?;
+        if !known {
+            send_error_to_user(
+                connections,
+                sender_id,
+                ErrorNotification {
+                    code: ErrorCode::UnknownDevice,
+                    message: UNKNOWN_DEVICE_MESSAGE.to_string(),
+                    message_id: Some(message_id.to_string()),
+                    retry_after: None,
+                },
+            );
+            return Err(format!(
+                "Message {} rejected ({:?}) for device {}",
+                message_id,
+                ErrorCode::UnknownDevice,
+                device_id
+            ));
+        }
+    }
 
     // Decode base64 fields
     let encrypted_content = base64::engine::general_purpose::STANDARD.decode(&send_data.encrypted_content)
         .map_err(|_| "Invalid base64 for encrypted_content".to_string())?;
     let iv = base64::engine::general_purpose::STANDARD.decode(&send_data.iv)
         .map_err(|_| "Invalid base64 for iv".to_string())?;
+    Span::current().record("encrypted_content_bytes", encrypted_content.len());
+
+    // A note to self needs no contact; it goes to the user's own channel like any message
+    let opens_conversation = if to_self {
+        false
+    } else {
+        check_can_message(&state, connections, sender_id, receiver_id, message_id).await?;
+        check_new_conversation_quota(&state, connections, sender_id, receiver_id, message_id)
+            .await?
+    };
+    let counted_at =
+        check_send_quota(&state, connections, sender_id, receiver_id, message_id).await?;
 
-    let status = "SENT";
+    // Server timestamps are always UTC; clients render them in their own timezone
+    let timestamp_millis = state.clock.now().timestamp_millis();
 
+    // Stored as PENDING until the first delivery attempt has been made
     // Insert into database
-    let res = sqlx::query(
-        "INSERT INTO messages (id, timestamp, sender_id, receiver_id, status, type, encrypted_content, iv) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+    let mut record = MessageRecord {
+        id: message_id,
+        timestamp: timestamp_millis,
+        sender_id,
+        receiver_id,
+        status: MessageStatus::Pending,
+        r#type: send_data.r#type.clone(),
+        encrypted_content,
+        iv,
+        forwarded_from_id,
+        forward_count: 0,
+        reply_to_id,
+        reply_to_deleted,
+        encryption_version: send_data.encryption_version,
+        device_id,
+        cipher_suite: send_data.cipher_suite.clone(),
+    };
+    match timed_db(
+        "insert_message",
+        service::insert_message_in_order(state.messages.as_ref(), &mut record),
     )
-    .bind(message_id)
-    .bind(timestamp_millis)
-    .bind(sender_id)
-    .bind(receiver_id)
-    .bind(status)
-    .bind(&send_data.r#type)
-    .bind(&encrypted_content)
-    .bind(&iv)
-    .execute(&state.db)
-    .await;
-
-    if let Err(e) = res {
-        return Err(format!("Database error: {}", e));
+    .await
+    {
+        Ok(Some(delta)) => {
+            state.clock_skew_corrections.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "clock_skew_corrected: message {} timestamp moved forward by {}ms to follow the conversation",
+                message_id, delta
+            );
+        }
+        Ok(None) => {}
+        Err(e) => {
+            refund_send_quota(&state, sender_id, receiver_id, counted_at).await;
+            return Err(format!("Database error: {}", e));
+        }
     }
+    if let Some(source_id) = forwarded_from_id
+        && let Err(e) = timed_db(
+            "increment_forward_count",
+            state.messages.increment_forward_count(source_id),
+        )
+        .await
+    {
+        warn!(
+            "Failed to increment forward count of message {}: {}",
+            source_id, e
+        );
+    }
+
+    info!(
+        "Message {} stored in database with PENDING status",
+        message_id
+    );
 
-    info!("Message {} stored in database with SENT status", message_id);
+    // Remember the conversation so the receiver can always reply
+    if !to_self
+        && let Err(e) = timed_db(
+            "record_conversation_peer",
+            record_conversation_peer(
+                &state,
+                sender_id,
+                receiver_id,
+                opens_conversation.then(|| state.clock.now()),
+            ),
+        )
+        .await
+    {
+        warn!(
+            "Failed to record conversation peer {} -> {}: {}",
+            sender_id, receiver_id, e
+        );
+    }
 
     // Create message notification for receiver
     let message_notification = MessageNotification {
         id: message_id.to_string(),
-        timestamp: timestamp_millis.to_string(),
+        timestamp: record.timestamp.to_string(),
         sender_id: sender_id.to_string(),
         receiver_id: receiver_id.to_string(),
-        status: status.to_string(),
+        status: MessageStatus::Sent,
         r#type: send_data.r#type,
         encrypted_content: send_data.encrypted_content,
         iv: send_data.iv,
+        was_forwarded: forwarded_from_id.is_some(),
+        reply_to: reply_to_id.map(|id| ReplyReference {
+            message_id: id.to_string(),
+            deleted: reply_to_deleted,
+        }),
+        encryption_version: send_data.encryption_version,
+        cipher_suite: send_data.cipher_suite,
+        device_id: device_id.map(|id| id.to_string()),
     };
 
     // Send new message notification to receiver
-    broadcast_message_to_user(connections, receiver_id, message_notification).await;
-    
+    broadcast_message_to_user(&state, receiver_id, message_id, message_notification).await;
+    notify_webhook(
+        &state,
+        WebhookEvent::MessageSent {
+            message_id: message_id.to_string(),
+            sender_id: sender_id.to_string(),
+            receiver_id: receiver_id.to_string(),
+            timestamp: format_millis(record.timestamp, DEFAULT_TIMEZONE),
+        },
+    );
+
+    // Delivery has been attempted (whether or not the receiver is online), so the message is now SENT.
+    // Guard on PENDING so a fast DELIVERED/READ from the receiver is not overwritten.
+    if let Err(e) = timed_db("mark_sent", state.messages.mark_sent_if_pending(message_id)).await {
+        error!("Failed to mark message {} as SENT: {}", message_id, e);
+    }
+
     // Send SENT status update to sender to confirm message was received by server
     let sent_status_update = StatusUpdate {
         message_id: message_id.to_string(),
-        status: "SENT".to_string(),
+        status: MessageStatus::Sent,
         updated_by: "server".to_string(),
     };
-    broadcast_status_update_to_user(connections, sender_id, sent_status_update).await;
+    broadcast_status_update_to_user(&state, sender_id, sent_status_update).await;
+    notify_webhook(
+        &state,
+        WebhookEvent::MessageStatus {
+            message_id: message_id.to_string(),
+            sender_id: sender_id.to_string(),
+            receiver_id: receiver_id.to_string(),
+            status: MessageStatus::Sent,
+            updated_by: "server".to_string(),
+            timestamp: format_timestamp(state.clock.now(), DEFAULT_TIMEZONE),
+        },
+    );
 
     info!("Message sent via WebSocket: {} -> {}, sender notified of SENT status", sender_id, receiver_id);
     Ok(())
 }
 
-async fn handle_update_status(
-    user_id: Uuid,
-    data: serde_json::Value,
+/// Rejects sends to users who have not accepted the sender when the deployment requires it.
+async fn check_can_message(
+    state: &AppState,
     connections: &ConnectionManager,
-    state: Arc<AppState>,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    message_id: Uuid,
 ) -> Result<(), String> {
-    let update_data: UpdateStatusData = serde_json::from_value(data)
-        .map_err(|e| format!("Failed to parse update_status data: {}", e))?;
-
-    let message_id = Uuid::parse_str(&update_data.message_id)
-        .map_err(|_| "Invalid message_id format".to_string())?;
+    let permitted = timed_db("can_message", can_message(state, sender_id, receiver_id))
+        .await
+        .map_err(|e| format!("Database error checking contacts: {}", e))?;
+    if !permitted {
+        send_error_to_user(
+            connections,
+            sender_id,
+            ErrorNotification {
+                code: ErrorCode::NotAContact,
+                message: NOT_A_CONTACT_MESSAGE.to_string(),
+                message_id: Some(message_id.to_string()),
+                retry_after: None,
+            },
+        );
+        return Err(format!(
+            "User {} is not a contact of {}, message {} rejected",
+            sender_id, receiver_id, message_id
+        ));
+    }
+    Ok(())
+}
 
-    let status = update_data.status.trim().to_uppercase();
-    if !["SENT", "DELIVERED", "READ", "FAILED"].contains(&status.as_str()) {
-        return Err("Invalid status. Must be one of: SENT, DELIVERED, READ, FAILED".to_string());
+/// Rejects a message that would open a conversation once the sender opened as many as
+/// `state.new_conversation_limits` allows in the last 24 hours. Returns whether the message
+/// opens a conversation.
+async fn check_new_conversation_quota(
+    state: &AppState,
+    connections: &ConnectionManager,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    message_id: Uuid,
+) -> Result<bool, String> {
+    let now = state.clock.now();
+    let check = timed_db(
+        "check_new_conversation",
+        service::check_new_conversation(
+            state.users.as_ref(),
+            state.contacts.as_ref(),
+            &state.new_conversation_limits,
+            sender_id,
+            receiver_id,
+            now,
+        ),
+    )
+    .await
+    .map_err(|e| {
+        format!(
+            "Failed to check new conversations of user {}: {}",
+            sender_id, e
+        )
+    })?;
+    match check {
+        NewConversationCheck::Existing => Ok(false),
+        NewConversationCheck::Opens => Ok(true),
+        NewConversationCheck::Exceeded { limit, resets_at } => {
+            state
+                .new_conversation_limit_hits
+                .fetch_add(1, Ordering::Relaxed);
+            send_error_to_user(
+                connections,
+                sender_id,
+                ErrorNotification {
+                    code: ErrorCode::NewConversationLimit,
+                    message: format!(
+                        "Limit of {} new conversations per day reached; another can be started at {}",
+                        limit,
+                        format_timestamp(resets_at, DEFAULT_TIMEZONE)
+                    ),
+                    message_id: Some(message_id.to_string()),
+                    retry_after: Some((resets_at - now).num_seconds().max(1) as u64),
+                },
+            );
+            Err(format!(
+                "User {} exceeded the new conversation limit, message {} to {} rejected",
+                sender_id, message_id, receiver_id
+            ))
+        }
     }
+}
 
-    info!("Processing status update: message {} to status {} by user {}", message_id, status, user_id);
+/// Counts the send against the sender's quota, rejecting it once a window is full. Returns
+/// when the send was counted, to refund it with `refund_send_quota` if the message is not
+/// stored after all.
+async fn check_send_quota(
+    state: &AppState,
+    connections: &ConnectionManager,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    message_id: Uuid,
+) -> Result<DateTime<Utc>, String> {
+    let now = state.clock.now();
+    let quota = timed_db(
+        "record_send",
+        service::check_send_quota(
+            state.users.as_ref(),
+            state.messages.as_ref(),
+            &state.send_limits,
+            sender_id,
+            receiver_id,
+            now,
+        ),
+    )
+    .await
+    .map_err(|e| format!("Failed to check send quota of user {}: {}", sender_id, e))?;
+    if let SendQuotaOutcome::Exceeded(window) = quota {
+        service::RATE_LIMIT_HITS.fetch_add(1, Ordering::Relaxed);
+        let resets_at = service::send_window_resets_at(&window);
+        send_error_to_user(
+            connections,
+            sender_id,
+            ErrorNotification {
+                code: ErrorCode::RateLimited,
+                message: format!(
+                    "Send limit of {} messages per {} reached; resets at {}",
+                    window.limit,
+                    window.name,
+                    format_timestamp(resets_at, DEFAULT_TIMEZONE)
+                ),
+                message_id: Some(message_id.to_string()),
+                retry_after: Some((resets_at - now).num_seconds().max(1) as u64),
+            },
+        );
+        return Err(format!(
+            "User {} exceeded the {} send limit, message {} rejected",
+            sender_id, window.name, message_id
+        ));
+    }
+    Ok(now)
+}
 
-    // Get message details
-    let message_check = match sqlx::query("SELECT receiver_id, sender_id FROM messages WHERE id = $1")
-        .bind(message_id)
-        .fetch_optional(&state.db)
-        .await
+/// Takes back a send counted by `check_send_quota` whose message could not be stored.
+async fn refund_send_quota(
+    state: &AppState,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    counted_at: DateTime<Utc>,
+) {
+    if let Err(e) = timed_db(
+        "refund_send",
+        service::refund_send_quota(state.messages.as_ref(), sender_id, receiver_id, counted_at),
+    )
+    .await
     {
-        Ok(row) => row,
-        Err(e) => {
-            return Err(format!("Database error checking message: {}", e));
-        }
-    };
+        warn!(
+            "Failed to refund the send quota of user {}: {}",
+            sender_id, e
+        );
+    }
+}
 
-    let (receiver_id, sender_id) = match message_check {
-        Some(row) => {
-            let receiver_id = row.try_get::<Uuid, _>("receiver_id")
-                .map_err(|_| "Invalid receiver_id in database".to_string())?;
-            let sender_id = row.try_get::<Uuid, _>("sender_id")
-                .map_err(|_| "Invalid sender_id in database".to_string())?;
-            (receiver_id, sender_id)
-        }
-        None => {
-            return Err("Message not found".to_string());
-        }
-    };
+/// Stores and relays a message whose sender and timestamp are sealed in
+/// `encrypted_metadata`. The contact check and send quota still apply, but nothing stored
+/// names the sender: the message is kept under a pseudonymous routing id, and no
+/// conversation peer, webhook, delivery attempt or status is recorded for it. For the same
+/// reason a sealed message could not be counted towards the new conversation limit, so it
+/// may not open a conversation at all. The receiver deletes it with
+/// `DELETE /messages/sealed/:id` once it has been stored on the device.
+async fn handle_sealed_message(
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    message_id: Uuid,
+    send_data: SendMessageData,
+    connections: &ConnectionManager,
+    state: &AppState,
+) -> Result<(), String> {
+    let encrypted_metadata = send_data
+        .encrypted_metadata
+        .as_deref()
+        .filter(|metadata| !metadata.is_empty())
+        .ok_or_else(|| format!("Sealed message {} has no encrypted_metadata", message_id))?;
+    let base64 = &base64::engine::general_purpose::STANDARD;
+    let encrypted_metadata = base64
+        .decode(encrypted_metadata)
+        .map_err(|_| "Invalid base64 for encrypted_metadata".to_string())?;
+    let encrypted_content = base64
+        .decode(&send_data.encrypted_content)
+        .map_err(|_| "Invalid base64 for encrypted_content".to_string())?;
+    let iv = base64
+        .decode(&send_data.iv)
+        .map_err(|_| "Invalid base64 for iv".to_string())?;
 
-    // Only the receiver can mark a message as read
-    if receiver_id != user_id && status == "READ" {
-        return Err("Only the message receiver can mark it as read".to_string());
+    check_can_message(state, connections, sender_id, receiver_id, message_id).await?;
+    if check_new_conversation_quota(state, connections, sender_id, receiver_id, message_id).await? {
+        send_error_to_user(
+            connections,
+            sender_id,
+            ErrorNotification {
+                code: ErrorCode::SealedNewConversation,
+                message: SEALED_NEW_CONVERSATION_MESSAGE.to_string(),
+                message_id: Some(message_id.to_string()),
+                retry_after: None,
+            },
+        );
+        return Err(format!(
+            "Sealed message {} from {} would open a conversation with {}, rejected",
+            message_id, sender_id, receiver_id
+        ));
     }
+    let counted_at =
+        check_send_quota(state, connections, sender_id, receiver_id, message_id).await?;
 
-    // Update the message status in database
-    let update_result = sqlx::query("UPDATE messages SET status = $1 WHERE id = $2")
-        .bind(&status)
-        .bind(message_id)
-        .execute(&state.db)
-        .await;
+    let day = state.clock.now().date_naive();
+    let record = SealedMessageRecord {
+        id: message_id,
+        routing_id: sealed_routing_id(&state.sealed_routing_key, sender_id, receiver_id, day),
+        day,
+        r#type: send_data.r#type,
+        encrypted_metadata,
+        encrypted_content,
+        iv,
+        encryption_version: send_data.encryption_version,
+    };
+    if let Err(e) = timed_db(
+        "insert_sealed_message",
+        service::insert_sealed_message(state.messages.as_ref(), &record, receiver_id),
+    )
+    .await
+    {
+        refund_send_quota(state, sender_id, receiver_id, counted_at).await;
+        return Err(format!("Cannot store sealed message {}: {}", message_id, e));
+    }
+    info!("Sealed message {} stored", message_id);
 
-    match update_result {
-        Ok(result) => {
-            if result.rows_affected() > 0 {
-                info!("Message {} status updated to {} by user {}", message_id, status, user_id);
+    let notification = SealedMessageNotification::from_record(&record);
+    match deliver_to_user(state, receiver_id, WSEvent::SealedMessage(notification)).await {
+        Ok(Delivery::Offline) => info!("Receiver of sealed message {} not connected", message_id),
+        Ok(_) => {}
+        Err(e) => error!("Failed to deliver sealed message {}: {}", message_id, e),
+    }
+    broadcast_status_update_to_user(
+        state,
+        sender_id,
+        StatusUpdate {
+            message_id: message_id.to_string(),
+            status: MessageStatus::Sent,
+            updated_by: "server".to_string(),
+        },
+    )
+    .await;
+    Ok(())
+}
 
-                // Create status update notification
-                let status_update = StatusUpdate {
-                    message_id: message_id.to_string(),
-                    status: status.clone(),
-                    updated_by: user_id.to_string(),
-                };
+/// Relays a delivery probe and tells the sender whether a connection of the receiver got
+/// it. Probes never touch the messages table, so they are absent from history, unread
+/// counts and backlogs, and they are not retried.
+async fn handle_probe(
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    message_id: Uuid,
+    connections: &ConnectionManager,
+    state: &AppState,
+) -> Result<(), String> {
+    if let Err(retry_after) = state
+        .rate_limiter
+        .check_and_increment(
+            &probe_limit_key(sender_id, receiver_id),
+            PROBE_LIMIT_PER_WINDOW,
+            PROBE_WINDOW,
+        )
+        .await
+        .into_result()
+    {
+        service::RATE_LIMIT_HITS.fetch_add(1, Ordering::Relaxed);
+        send_error_to_user(
+            connections,
+            sender_id,
+            ErrorNotification {
+                code: ErrorCode::RateLimited,
+                message: "Too many probes to this user".to_string(),
+                message_id: Some(message_id.to_string()),
+                retry_after: Some(retry_after.as_secs().max(1)),
+            },
+        );
+        return Err(format!(
+            "Probe {} from {} rate limited",
+            message_id, sender_id
+        ));
+    }
+    check_can_message(state, connections, sender_id, receiver_id, message_id).await?;
 
-                // Always notify both sender and receiver about status changes
-                // This ensures both parties always know the current message status
-                broadcast_status_update_to_user(connections, sender_id, status_update.clone()).await;
-                broadcast_status_update_to_user(connections, receiver_id, status_update).await;
-                
-                info!("Broadcasted {} status update for message {} to both sender {} and receiver {}", 
-                      status, message_id, sender_id, receiver_id);
-
-                // If status is READ, schedule delayed deletion to ensure all parties received the update
-                if status == "READ" {
-                    let db_clone = state.db.clone();
-                    let message_id_clone = message_id;
-                    
-                    tokio::spawn(async move {
-                        // Wait 5 seconds to ensure all status updates are delivered
-                        sleep(Duration::from_secs(5)).await;
-                        
-                        // Delete the message from database
-                        match sqlx::query("DELETE FROM messages WHERE id = $1")
-                            .bind(message_id_clone)
-                            .execute(&db_clone)
-                            .await 
-                        {
-                            Ok(result) => {
-                                if result.rows_affected() > 0 {
-                                    info!("Successfully deleted read message {} after 5-second delay", message_id_clone);
-                                } else {
-                                    info!("Message {} was already deleted during the delay period", message_id_clone);
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to delete read message {} after delay: {}", message_id_clone, e);
-                            }
-                        }
-                    });
-                }
-            } else {
-                return Err(format!("Message {} not found for status update", message_id));
-            }
-        }
+    let probe = ProbeNotification {
+        id: message_id.to_string(),
+        sender_id: sender_id.to_string(),
+        timestamp: state.clock.now().timestamp_millis().to_string(),
+    };
+    let delivered = match deliver_to_user(state, receiver_id, WSEvent::Probe(probe)).await {
+        Ok(Delivery::Local | Delivery::Published) => true,
+        Ok(Delivery::Offline) => false,
         Err(e) => {
-            return Err(format!("Failed to update message status: {}", e));
+            warn!(
+                "Failed to deliver probe {} to user {}: {}",
+                message_id, receiver_id, e
+            );
+            false
         }
+    };
+    if let Some(sender) = connections.get(&sender_id)
+        && let Err(e) = sender.send(WSEvent::ProbeResult(ProbeResult {
+            message_id: message_id.to_string(),
+            receiver_id: receiver_id.to_string(),
+            delivered,
+        }))
+    {
+        error!("Failed to send probe_result to user {}: {}", sender_id, e);
     }
-
+    info!(
+        "Probe {} -> {} delivered: {}",
+        sender_id, receiver_id, delivered
+    );
     Ok(())
 }
 
-pub async fn broadcast_message_to_user(
-    connections: &ConnectionManager,
+#[tracing::instrument(
+    skip_all,
+    fields(actor_id = %user_id, message_id = Empty, new_status = Empty)
+)]
+async fn handle_update_status(
     user_id: Uuid,
-    message: MessageNotification,
-) {
-    if let Some(sender) = connections.get(&user_id) {
-        if let Err(e) = sender.send(WSEvent::NewMessage(message)) {
-            error!("Failed to send message to user {}: {}", user_id, e);
-        }
-    } else {
-        info!("User {} not connected to WebSocket", user_id);
+    data: serde_json::Value,
+    state: Arc<AppState>,
+) -> Result<(), String> {
+    let update_data: UpdateStatusData = serde_json::from_value(data)
+        .map_err(|e| format!("Failed to parse update_status data: {}", e))?;
+
+    let message_id = Uuid::parse_str(&update_data.message_id)
+        .map_err(|_| "Invalid message_id format".to_string())?;
+    let span = Span::current();
+    span.record("message_id", tracing::field::display(message_id));
+    span.record("new_status", update_data.status.as_str());
+
+    info!(
+        "Processing status update: message {} to status {} by user {}",
+        message_id, update_data.status, user_id
+    );
+
+    // Participant, read-receipt and transition rules live in the service layer
+    let change = timed_db(
+        "update_message_status",
+        service::update_message_status(
+            state.messages.as_ref(),
+            user_id,
+            message_id,
+            &update_data.status,
+        ),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    info!(
+        "Message {} status updated to {} by user {}",
+        message_id, change.status, user_id
+    );
+    announce_status_change(&state, user_id, message_id, change).await;
+    Ok(())
+}
+
+/// Applies a DELIVERED or READ status the receiver signed, like `update_status`, and sends
+/// the signed receipt to the sender as `delivery_receipt`.
+#[tracing::instrument(
+    skip_all,
+    fields(actor_id = %user_id, message_id = Empty, new_status = Empty)
+)]
+async fn handle_signed_receipt(
+    user_id: Uuid,
+    data: serde_json::Value,
+    state: Arc<AppState>,
+) -> Result<(), String> {
+    let receipt_data: SignedReceiptData = serde_json::from_value(data)
+        .map_err(|e| format!("Failed to parse signed_receipt data: {}", e))?;
+
+    let message_id = Uuid::parse_str(&receipt_data.message_id)
+        .map_err(|_| "Invalid message_id format".to_string())?;
+    let span = Span::current();
+    span.record("message_id", tracing::field::display(message_id));
+    span.record("new_status", receipt_data.status.as_str());
+
+    let (change, receipt) = timed_db(
+        "record_signed_receipt",
+        service::record_signed_receipt(
+            state.messages.as_ref(),
+            user_id,
+            message_id,
+            &receipt_data.status,
+            &receipt_data.recipient_signature,
+            state.clock.now(),
+        ),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    info!(
+        "Message {} status updated to {} with a signed receipt by user {}",
+        message_id, change.status, user_id
+    );
+    let sender_id = change.sender_id;
+    announce_status_change(&state, user_id, message_id, change).await;
+
+    let event = WSEvent::DeliveryReceipt(delivery_receipt_response(&receipt, DEFAULT_TIMEZONE));
+    match deliver_to_user(&state, sender_id, event).await {
+        Ok(Delivery::Offline) => info!(
+            "User {} not connected to WebSocket for delivery receipt of message {}",
+            sender_id, message_id
+        ),
+        Ok(_) => {}
+        Err(e) => error!(
+            "Failed to send delivery receipt to user {}: {}",
+            sender_id, e
+        ),
+    }
+    Ok(())
+}
+
+/// The wire form of a signed receipt, with `received_at` rendered in `timezone`.
+pub fn delivery_receipt_response(receipt: &DeliveryReceiptRecord, timezone: Tz) -> DeliveryReceipt {
+    DeliveryReceipt {
+        message_id: receipt.message_id.to_string(),
+        status: receipt.status,
+        timestamp: receipt.message_timestamp.to_string(),
+        signed_by: receipt.receiver_id.to_string(),
+        recipient_signature: receipt.signature.clone(),
+        received_at: format_timestamp(receipt.received_at, timezone),
+    }
+}
+
+/// Sends a status change made by `user_id` to both participants and the webhook, and
+/// schedules the message's deletion if it is now read (or delivered, for senders who
+/// asked for that).
+async fn announce_status_change(
+    state: &Arc<AppState>,
+    user_id: Uuid,
+    message_id: Uuid,
+    change: service::StatusChange,
+) {
+    let (sender_id, receiver_id, status) = (change.sender_id, change.receiver_id, change.status);
+
+    // Create status update notification
+    let status_update = StatusUpdate {
+        message_id: message_id.to_string(),
+        status,
+        updated_by: user_id.to_string(),
+    };
+
+    // Always notify both sender and receiver about status changes
+    // This ensures both parties always know the current message status
+    broadcast_status_update_to_user(state, sender_id, status_update.clone()).await;
+    broadcast_status_update_to_user(state, receiver_id, status_update).await;
+    notify_webhook(
+        state,
+        WebhookEvent::MessageStatus {
+            message_id: message_id.to_string(),
+            sender_id: sender_id.to_string(),
+            receiver_id: receiver_id.to_string(),
+            status,
+            updated_by: user_id.to_string(),
+            timestamp: format_timestamp(state.clock.now(), DEFAULT_TIMEZONE),
+        },
+    );
+
+    info!(
+        "Broadcasted {} status update for message {} to both sender {} and receiver {}",
+        status, message_id, sender_id, receiver_id
+    );
+
+    // Read messages, and delivered ones of senders who asked for it, are deleted once
+    // all parties have had time to receive the update, unless the conversation keeps them
+    let delete = status == MessageStatus::Read
+        || (status == MessageStatus::Delivered && deletes_on_delivered(state, sender_id).await);
+    if delete && !keeps_read_messages(state, sender_id, receiver_id).await {
+        schedule_message_deletion(state.clone(), message_id, sender_id, receiver_id, status);
+    }
+}
+
+/// Delay before a READ or DELIVERED message is deleted, so every status update is sent first.
+const MESSAGE_DELETION_DELAY: Duration = Duration::from_secs(5);
+
+/// Whether `sender_id` has `delete_on_delivered` set. Failing to load the preference keeps
+/// the message.
+async fn deletes_on_delivered(state: &AppState, sender_id: Uuid) -> bool {
+    match state.users.preferences(sender_id).await {
+        Ok(preferences) => preferences.delete_on_delivered,
+        Err(e) => {
+            error!("Failed to load preferences of user {}: {}", sender_id, e);
+            false
+        }
+    }
+}
+
+/// Whether either participant keeps the read messages of their conversation. Failing to
+/// load the settings keeps the message.
+async fn keeps_read_messages(state: &AppState, sender_id: Uuid, receiver_id: Uuid) -> bool {
+    match service::conversation_settings(state.contacts.as_ref(), sender_id, receiver_id).await {
+        Ok(settings) => settings.keeps_read_messages(),
+        Err(e) => {
+            error!(
+                "Failed to load settings of conversation {} / {}: {}",
+                sender_id, receiver_id, e
+            );
+            true
+        }
+    }
+}
+
+/// Deletes a message that reached `status` after `MESSAGE_DELETION_DELAY`, sparing pinned
+/// messages if configured, and sends `message_deleted` to both participants.
+fn schedule_message_deletion(
+    state: Arc<AppState>,
+    message_id: Uuid,
+    sender_id: Uuid,
+    receiver_id: Uuid,
+    status: MessageStatus,
+) {
+    tokio::spawn(async move {
+        sleep(MESSAGE_DELETION_DELAY).await;
+        let keep_pinned = state.pins_exempt_from_read_deletion;
+        match timed_db(
+            "delete_read_message",
+            state.messages.delete_read_message(message_id, keep_pinned),
+        )
+        .await
+        {
+            Ok(true) => {
+                info!(
+                    "Successfully deleted {} message {} after 5-second delay",
+                    status, message_id
+                );
+                let deleted = MessageDeleted {
+                    message_id: message_id.to_string(),
+                    status,
+                };
+                for user_id in [sender_id, receiver_id] {
+                    match deliver_to_user(&state, user_id, WSEvent::MessageDeleted(deleted.clone()))
+                        .await
+                    {
+                        Ok(Delivery::Offline) => info!(
+                            "User {} not connected to WebSocket for message deleted",
+                            user_id
+                        ),
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!("Failed to send message deleted to user {}: {}", user_id, e)
+                        }
+                    }
+                }
+            }
+            Ok(false) => {
+                info!(
+                    "Message {} was already deleted during the delay period or is pinned",
+                    message_id
+                );
+            }
+            Err(e) => {
+                error!(
+                    "Failed to delete {} message {} after delay: {}",
+                    status, message_id, e
+                );
+            }
+        }
+    });
+}
+
+/// Passes a message event to the configured webhook, if any.
+fn notify_webhook(state: &AppState, event: WebhookEvent) {
+    if let Some(webhooks) = &state.webhooks {
+        webhooks.dispatch(event);
     }
 }
 
+/// Sends a new message to its receiver and records the attempt for `GET /admin/messages/{id}/attempts`.
+pub async fn broadcast_message_to_user(
+    state: &AppState,
+    user_id: Uuid,
+    message_id: Uuid,
+    message: MessageNotification,
+) {
+    let delivery = deliver_to_user(state, user_id, WSEvent::NewMessage(message)).await;
+    // Without a local connection the attempt went through Redis, if it is configured
+    let remote_channel = if state.redis_client.is_some() {
+        "redis"
+    } else {
+        "ws"
+    };
+    let (channel, outcome, error) = match delivery {
+        Ok(Delivery::Local) => ("ws", service::DELIVERY_DELIVERED, None),
+        Ok(Delivery::Published) => ("redis", service::DELIVERY_PUBLISHED, None),
+        Ok(Delivery::Offline) => {
+            info!("User {} not connected to WebSocket", user_id);
+            (remote_channel, service::DELIVERY_OFFLINE, None)
+        }
+        Err(e) => {
+            error!("Failed to send message to user {}: {}", user_id, e);
+            (remote_channel, service::DELIVERY_FAILED, Some(e))
+        }
+    };
+    record_delivery_attempt(
+        state,
+        DeliveryAttemptRecord {
+            message_id,
+            attempt_at: state.clock.now(),
+            channel: channel.to_string(),
+            outcome: outcome.to_string(),
+            error,
+        },
+    );
+}
+
+/// Stores a delivery attempt in the background, so recording it never delays delivery.
+fn record_delivery_attempt(state: &AppState, attempt: DeliveryAttemptRecord) {
+    let messages = state.messages.clone();
+    tokio::spawn(async move {
+        if let Err(e) = messages
+            .record_delivery_attempt(&attempt, service::MAX_DELIVERY_ATTEMPTS_PER_MESSAGE)
+            .await
+        {
+            warn!(
+                "Failed to record delivery attempt for message {}: {}",
+                attempt.message_id, e
+            );
+        }
+    });
+}
+
 pub async fn broadcast_status_update_to_user(
-    connections: &ConnectionManager,
+    state: &AppState,
     user_id: Uuid,
     update: StatusUpdate,
 ) {
-    if let Some(sender) = connections.get(&user_id) {
-        if let Err(e) = sender.send(WSEvent::StatusUpdate(update.clone())) {
-            error!("Failed to send status update to user {}: {}", user_id, e);
-        } else {
+    match deliver_to_user(state, user_id, WSEvent::StatusUpdate(update.clone())).await {
+        Ok(Delivery::Offline) => {
+            warn!(
+                "User {} not connected to WebSocket for status update: message {} status {}",
+                user_id, update.message_id, update.status
+            );
+        }
+        Ok(_) => {
             info!("Successfully sent status update to user {}: message {} status {}", user_id, update.message_id, update.status);
         }
+        Err(e) => error!("Failed to send status update to user {}: {}", user_id, e),
+    }
+}
+
+/// Announces messages the receiver fetched over REST as DELIVERED, the same way an
+/// `update_status` from the receiver would, including deletion for `delete_on_delivered`.
+pub async fn broadcast_fetch_receipts(
+    state: &Arc<AppState>,
+    receiver_id: Uuid,
+    delivered: &[MessageRecord],
+) {
+    for message in delivered {
+        let status_update = StatusUpdate {
+            message_id: message.id.to_string(),
+            status: MessageStatus::Delivered,
+            updated_by: receiver_id.to_string(),
+        };
+        broadcast_status_update_to_user(state, message.sender_id, status_update.clone()).await;
+        broadcast_status_update_to_user(state, receiver_id, status_update).await;
+        notify_webhook(
+            state,
+            WebhookEvent::MessageStatus {
+                message_id: message.id.to_string(),
+                sender_id: message.sender_id.to_string(),
+                receiver_id: receiver_id.to_string(),
+                status: MessageStatus::Delivered,
+                updated_by: receiver_id.to_string(),
+                timestamp: format_timestamp(state.clock.now(), DEFAULT_TIMEZONE),
+            },
+        );
+        if deletes_on_delivered(state, message.sender_id).await
+            && !keeps_read_messages(state, message.sender_id, receiver_id).await
+        {
+            schedule_message_deletion(
+                state.clone(),
+                message.id,
+                message.sender_id,
+                receiver_id,
+                MessageStatus::Delivered,
+            );
+        }
+    }
+}
+
+pub async fn broadcast_pin_update_to_user(state: &AppState, user_id: Uuid, update: PinUpdate) {
+    match deliver_to_user(state, user_id, WSEvent::PinUpdate(update)).await {
+        Ok(Delivery::Offline) => {
+            info!("User {} not connected to WebSocket for pin update", user_id)
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to send pin update to user {}: {}", user_id, e),
+    }
+}
+
+pub async fn broadcast_meta_update_to_user(state: &AppState, user_id: Uuid, update: MetaUpdate) {
+    match deliver_to_user(state, user_id, WSEvent::MetaUpdate(update)).await {
+        Ok(Delivery::Offline) => info!(
+            "User {} not connected to WebSocket for metadata update",
+            user_id
+        ),
+        Ok(_) => {}
+        Err(e) => error!("Failed to send metadata update to user {}: {}", user_id, e),
+    }
+}
+
+pub async fn broadcast_device_revoked_to_user(
+    state: &AppState,
+    user_id: Uuid,
+    revoked: DeviceRevoked,
+) {
+    match deliver_to_user(state, user_id, WSEvent::DeviceRevoked(revoked)).await {
+        Ok(Delivery::Offline) => info!(
+            "User {} not connected to WebSocket for device revocation",
+            user_id
+        ),
+        Ok(_) => {}
+        Err(e) => error!(
+            "Failed to send device revocation to user {}: {}",
+            user_id, e
+        ),
+    }
+}
+
+pub async fn broadcast_contact_request_to_user(
+    state: &AppState,
+    user_id: Uuid,
+    request: ContactRequestNotification,
+) {
+    match deliver_to_user(state, user_id, WSEvent::ContactRequest(request)).await {
+        Ok(Delivery::Offline) => info!(
+            "User {} not connected to WebSocket for contact request",
+            user_id
+        ),
+        Ok(_) => {}
+        Err(e) => error!("Failed to send contact request to user {}: {}", user_id, e),
+    }
+}
+
+pub async fn broadcast_conversation_cleared_to_user(
+    state: &AppState,
+    user_id: Uuid,
+    cleared: ConversationCleared,
+) {
+    match deliver_to_user(state, user_id, WSEvent::ConversationCleared(cleared)).await {
+        Ok(Delivery::Offline) => info!(
+            "User {} not connected to WebSocket for conversation cleared",
+            user_id
+        ),
+        Ok(_) => {}
+        Err(e) => error!(
+            "Failed to send conversation cleared to user {}: {}",
+            user_id, e
+        ),
+    }
+}
+
+/// Sends an announcement to every connected user. With Redis configured it is published on
+/// `ws:announcements` so every instance, including this one, delivers it to its own connections.
+pub async fn broadcast_announcement(state: &AppState, announcement: AnnouncementNotification) {
+    let event = WSEvent::Announcement(announcement);
+    let Some(redis) = state.redis_client.as_ref() else {
+        broadcast_to_all(&state.connections, event).await;
+        return;
+    };
+    let published = match serde_json::to_string(&event) {
+        Ok(payload) => redis
+            .clone()
+            .publish::<_, _, i64>(ANNOUNCEMENT_CHANNEL, payload)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = published {
+        error!(
+            "Failed to publish announcement, delivering locally only: {}",
+            e
+        );
+        broadcast_to_all(&state.connections, event).await;
+    }
+}
+
+/// Where `deliver_to_user` sent an event.
+enum Delivery {
+    /// Queued on this instance's connection for the user.
+    Local,
+    /// Published to Redis and picked up by another instance.
+    Published,
+    /// The user is not connected to any instance.
+    Offline,
+}
+
+/// Sends an event to the user's connection on this instance. When the user is not connected
+/// here and Redis is configured, publishes it on `ws:user:{user_id}` for the instance that
+/// holds the connection.
+///
+/// With `MULTI_DEVICE` enabled a user's devices may be connected to different instances, so
+/// events are always published when Redis is configured; this instance receives its own
+/// publication like any other subscriber.
+async fn deliver_to_user(
+    state: &AppState,
+    user_id: Uuid,
+    event: WSEvent,
+) -> Result<Delivery, String> {
+    // Clone the sender so the map guard is not held across the publish below
+    let local = state.connections.get(&user_id).map(|sender| sender.clone());
+    let fan_out = state.multi_device && state.redis_client.is_some();
+    if let Some(sender) = local.as_ref()
+        && !fan_out
+    {
+        sender.send(event).map_err(|e| e.to_string())?;
+        return Ok(Delivery::Local);
+    }
+    let Some(redis) = state.redis_client.as_ref() else {
+        return Ok(Delivery::Offline);
+    };
+    let payload = serde_json::to_string(&event).map_err(|e| e.to_string())?;
+    let published: Result<i64, String> = redis
+        .clone()
+        .publish(user_channel(user_id), payload)
+        .await
+        .map_err(|e| e.to_string());
+    let receivers = match (published, local) {
+        (Ok(receivers), _) => receivers,
+        (Err(e), Some(sender)) => {
+            error!(
+                "Failed to publish event for user {}, delivering locally only: {}",
+                user_id, e
+            );
+            sender.send(event).map_err(|e| e.to_string())?;
+            return Ok(Delivery::Local);
+        }
+        (Err(e), None) => return Err(e),
+    };
+    if receivers > 0 {
+        Ok(Delivery::Published)
     } else {
-        warn!("User {} not connected to WebSocket for status update: message {} status {}", user_id, update.message_id, update.status);
+        Ok(Delivery::Offline)
+    }
+}
+
+const USER_CHANNEL_PREFIX: &str = "ws:user:";
+const ANNOUNCEMENT_CHANNEL: &str = "ws:announcements";
+
+fn user_channel(user_id: Uuid) -> String {
+    format!("{}{}", USER_CHANNEL_PREFIX, user_id)
+}
+
+fn user_from_channel(channel: &str) -> Option<Uuid> {
+    channel
+        .strip_prefix(USER_CHANNEL_PREFIX)
+        .and_then(|id| Uuid::parse_str(id).ok())
+}
+
+/// Wait before the first attempt to restore a lost Redis subscription; doubled after each
+/// failed attempt up to `REDIS_RECONNECT_MAX_DELAY`.
+const REDIS_RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const REDIS_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// This instance's Redis subscriptions: the handle used to (un)subscribe user channels,
+/// replaced whenever the subscription connection is re-established.
+#[derive(Clone)]
+pub struct RedisSubscriptions {
+    sink: Arc<std::sync::RwLock<PubSubSink>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl RedisSubscriptions {
+    /// Whether events published by other instances currently reach this one.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn sink(&self) -> PubSubSink {
+        self.sink.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Connects to Redis for cross-instance delivery. Returns the connection used to publish
+/// and the subscriptions handle, and spawns the task that forwards published events to
+/// local connections and restores the subscription if the connection drops.
+pub async fn connect_redis(
+    url: &str,
+    connections: ConnectionManager,
+) -> RedisResult<(RedisConnectionManager, RedisSubscriptions)> {
+    let client = redis::Client::open(url)?;
+    let publisher = client.get_connection_manager().await?;
+    let (sink, stream) = open_subscriptions(&client).await?;
+    let subscriptions = RedisSubscriptions {
+        sink: Arc::new(std::sync::RwLock::new(sink)),
+        connected: Arc::new(AtomicBool::new(true)),
+    };
+    tokio::spawn(maintain_subscriptions(
+        client,
+        stream,
+        subscriptions.clone(),
+        connections,
+    ));
+    Ok((publisher, subscriptions))
+}
+
+async fn open_subscriptions(client: &redis::Client) -> RedisResult<(PubSubSink, PubSubStream)> {
+    let (mut sink, stream) = client.get_async_pubsub().await?.split();
+    sink.subscribe(ANNOUNCEMENT_CHANNEL).await?;
+    Ok((sink, stream))
+}
+
+/// Forwards published events until the subscription connection drops, then reconnects with
+/// backoff and subscribes again to the announcement channel and every locally connected
+/// user's channel. Events published while disconnected are lost, as with any pub/sub.
+async fn maintain_subscriptions(
+    client: redis::Client,
+    mut stream: PubSubStream,
+    subscriptions: RedisSubscriptions,
+    connections: ConnectionManager,
+) {
+    loop {
+        forward_published_events(stream, &connections).await;
+        subscriptions.connected.store(false, Ordering::Relaxed);
+        error!(
+            "Redis subscription lost; events published by other instances are not delivered until it is restored"
+        );
+        let mut attempt = 1;
+        stream = loop {
+            sleep(redis_reconnect_delay(attempt)).await;
+            match resubscribe(&client, &subscriptions, &connections).await {
+                Ok(stream) => break stream,
+                Err(e) => warn!(
+                    "Failed to restore Redis subscription (attempt {}): {}",
+                    attempt, e
+                ),
+            }
+            attempt += 1;
+        };
+        subscriptions.connected.store(true, Ordering::Relaxed);
+        info!("Redis subscription restored after {} attempt(s)", attempt);
+    }
+}
+
+/// Opens a new subscription connection and makes it the one users (un)subscribe on before
+/// subscribing the connected users, so a user who connects meanwhile is not missed.
+async fn resubscribe(
+    client: &redis::Client,
+    subscriptions: &RedisSubscriptions,
+    connections: &ConnectionManager,
+) -> RedisResult<PubSubStream> {
+    let (mut sink, stream) = open_subscriptions(client).await?;
+    *subscriptions
+        .sink
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = sink.clone();
+    let users: Vec<Uuid> = connections.iter().map(|entry| *entry.key()).collect();
+    for user_id in users {
+        sink.subscribe(user_channel(user_id)).await?;
+    }
+    Ok(stream)
+}
+
+/// Wait before reconnect attempt `attempt` (1-based).
+fn redis_reconnect_delay(attempt: u32) -> Duration {
+    REDIS_RECONNECT_INITIAL_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(REDIS_RECONNECT_MAX_DELAY)
+}
+
+async fn forward_published_events(mut stream: PubSubStream, connections: &ConnectionManager) {
+    while let Some(msg) = stream.next().await {
+        let channel = msg.get_channel_name();
+        let event: WSEvent = match msg
+            .get_payload::<String>()
+            .map_err(|e| e.to_string())
+            .and_then(|payload| serde_json::from_str(&payload).map_err(|e| e.to_string()))
+        {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Invalid event published on {}: {}", channel, e);
+                continue;
+            }
+        };
+        if channel == ANNOUNCEMENT_CHANNEL {
+            broadcast_to_all(connections, event).await;
+            continue;
+        }
+        let Some(user_id) = user_from_channel(channel) else {
+            warn!("Ignoring Redis message on unexpected channel {}", channel);
+            continue;
+        };
+        if let Some(sender) = connections.get(&user_id)
+            && let Err(e) = sender.send(event)
+        {
+            error!(
+                "Failed to forward published event to user {}: {}",
+                user_id, e
+            );
+        }
+    }
+}
+
+/// Subscribes this instance to the user's channel so events published elsewhere reach them.
+async fn subscribe_to_user_channel(state: &AppState, user_id: Uuid) {
+    if let Some(subscriptions) = state.redis_subscriptions.as_ref()
+        && let Err(e) = subscriptions.sink().subscribe(user_channel(user_id)).await
+    {
+        error!(
+            "Failed to subscribe to Redis channel for user {}: {}",
+            user_id, e
+        );
+    }
+}
+
+async fn unsubscribe_from_user_channel(state: &AppState, user_id: Uuid) {
+    if let Some(subscriptions) = state.redis_subscriptions.as_ref()
+        && let Err(e) = subscriptions
+            .sink()
+            .unsubscribe(user_channel(user_id))
+            .await
+    {
+        error!(
+            "Failed to unsubscribe from Redis channel for user {}: {}",
+            user_id, e
+        );
+    }
+}
+
+fn send_error_to_user(connections: &ConnectionManager, user_id: Uuid, error: ErrorNotification) {
+    if let Some(sender) = connections.get(&user_id)
+        && let Err(e) = sender.send(WSEvent::Error(error))
+    {
+        error!("Failed to send error to user {}: {}", user_id, e);
+    }
+}
+
+fn send_hello_ack_to_user(connections: &ConnectionManager, user_id: Uuid) {
+    if let Some(sender) = connections.get(&user_id)
+        && let Err(e) = sender.send(WSEvent::HelloAck(HelloAck::current()))
+    {
+        error!("Failed to send hello_ack to user {}: {}", user_id, e);
+    }
+}
+
+fn send_unread_counts_to_user(
+    connections: &ConnectionManager,
+    user_id: Uuid,
+    counts: UnreadCounts,
+) {
+    if let Some(sender) = connections.get(&user_id)
+        && let Err(e) = sender.send(WSEvent::UnreadCounts(counts))
+    {
+        error!("Failed to send unread_counts to user {}: {}", user_id, e);
+    }
+}
+
+fn send_ack_to_user(
+    connections: &ConnectionManager,
+    user_id: Uuid,
+    nonce: String,
+    duplicate: bool,
+) {
+    if let Some(sender) = connections.get(&user_id)
+        && let Err(e) = sender.send(WSEvent::Ack(Ack { nonce, duplicate }))
+    {
+        error!("Failed to send ack to user {}: {}", user_id, e);
     }
 }
 
+/// Asks every connected client to close with the given reason.
+pub async fn close_all(connections: &ConnectionManager, reason: CloseReason) {
+    broadcast_to_all(connections, WSEvent::Close(reason)).await;
+}
+
 async fn broadcast_to_all(connections: &ConnectionManager, event: WSEvent) {
     for connection in connections.iter() {
         if let Err(e) = connection.value().send(event.clone()) {
@@ -464,6 +3059,2674 @@ async fn broadcast_to_all(connections: &ConnectionManager, event: WSEvent) {
     }
 }
 
+/// Age after which a PENDING message is assumed to have missed its delivery attempt.
+const PENDING_MESSAGE_MAX_AGE_MS: i64 = 60_000;
+const PENDING_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Spawns a background task that upgrades stale PENDING messages to SENT.
+///
+/// Messages can be left PENDING if the server restarted between storing a message and
+/// attempting delivery. Receivers pick them up through the REST history endpoint.
+pub fn spawn_pending_message_sweeper(messages: Arc<dyn MessageRepo>, clock: Arc<dyn Clock>) {
+    tokio::spawn(async move {
+        loop {
+            sleep(PENDING_SWEEP_INTERVAL).await;
+            match sweep_stale_pending_messages(messages.as_ref(), clock.as_ref()).await {
+                Ok(upgraded) => {
+                    if upgraded > 0 {
+                        info!("Upgraded {} stale PENDING messages to SENT", upgraded);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to sweep PENDING messages: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Upgrades the messages that have been PENDING for longer than `PENDING_MESSAGE_MAX_AGE_MS`
+/// to SENT, returning how many changed.
+async fn sweep_stale_pending_messages(
+    messages: &dyn MessageRepo,
+    clock: &dyn Clock,
+) -> RepoResult<u64> {
+    let cutoff = clock.now().timestamp_millis() - PENDING_MESSAGE_MAX_AGE_MS;
+    messages.sweep_pending(cutoff).await
+}
+
 pub fn create_connection_manager() -> ConnectionManager {
     Arc::new(DashMap::new())
-}
\ No newline at end of file
+}
+
+/// A connection manager split into `shard_count` shards, which must be a power of two
+/// greater than 1.
+pub fn create_sharded_connection_manager(shard_count: usize) -> ConnectionManager {
+    Arc::new(DashMap::with_shard_amount(shard_count))
+}
+
+/// A shard above this multiple of the average entry count is reported as a hotspot.
+pub const SHARD_IMBALANCE_ALERT_RATIO: f64 = 5.0;
+
+/// Occupancy of one `ConnectionManager` shard.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardStats {
+    pub entry_count: u64,
+    /// Entries relative to the shard's allocated capacity.
+    pub load_factor: f64,
+}
+
+/// Per-shard occupancy of the `ConnectionManager`, for tuning `WS_CONNECTION_SHARD_COUNT`.
+#[derive(Debug, Clone)]
+pub struct ConnectionManagerStats {
+    pub shards: BTreeMap<usize, ShardStats>,
+}
+
+impl ConnectionManagerStats {
+    /// Reads every shard in turn; entries may move while the shards are read.
+    pub fn collect(connections: &ConnectionManager) -> Self {
+        let shards = connections
+            .shards()
+            .iter()
+            .enumerate()
+            .map(|(shard_id, shard)| {
+                let shard = shard.read();
+                let load_factor = if shard.capacity() == 0 {
+                    0.0
+                } else {
+                    shard.len() as f64 / shard.capacity() as f64
+                };
+                let stats = ShardStats {
+                    entry_count: shard.len() as u64,
+                    load_factor,
+                };
+                (shard_id, stats)
+            })
+            .collect();
+        Self { shards }
+    }
+
+    /// The fullest shard's entry count over the average, or 0 with no connections.
+    pub fn imbalance_ratio(&self) -> f64 {
+        let total: u64 = self.shards.values().map(|shard| shard.entry_count).sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let average = total as f64 / self.shards.len() as f64;
+        let max = self
+            .shards
+            .values()
+            .map(|shard| shard.entry_count)
+            .max()
+            .unwrap_or(0);
+        max as f64 / average
+    }
+
+    /// Logs a `shard_imbalance` warning if a shard holds more than
+    /// `SHARD_IMBALANCE_ALERT_RATIO` times the average.
+    pub fn warn_if_imbalanced(&self) {
+        let ratio = self.imbalance_ratio();
+        if ratio > SHARD_IMBALANCE_ALERT_RATIO {
+            warn!(
+                "shard_imbalance: fullest connection shard holds {:.1}x the average of {} shards; consider increasing WS_CONNECTION_SHARD_COUNT",
+                ratio,
+                self.shards.len()
+            );
+        }
+    }
+}
+
+pub fn create_nonce_cache() -> NonceCache {
+    Arc::new(DashMap::new())
+}
+
+/// Records a nonce for `user_id` at `now`, returning false if it was already seen within
+/// the window.
+fn remember_nonce(cache: &NonceCache, user_id: Uuid, nonce: &str, now: Instant) -> bool {
+    // Checked before taking the entry: `len` locks every shard, including the entry's
+    let full = cache.len() >= MAX_REMEMBERED_NONCES && {
+        evict_expired_nonces(cache, now);
+        cache.len() >= MAX_REMEMBERED_NONCES
+    };
+    match cache.entry((user_id, nonce.to_string())) {
+        Entry::Occupied(mut seen) => {
+            if now.duration_since(*seen.get()) < NONCE_WINDOW {
+                return false;
+            }
+            // Expired but not yet evicted, so treat it as new
+            seen.insert(now);
+            true
+        }
+        Entry::Vacant(_) if full => {
+            warn!(
+                "Nonce cache full, nonce from user {} is not remembered",
+                user_id
+            );
+            true
+        }
+        Entry::Vacant(slot) => {
+            slot.insert(now);
+            true
+        }
+    }
+}
+
+/// Forgets a nonce whose message failed, so the client can retry it.
+fn forget_nonce(cache: &NonceCache, user_id: Uuid, nonce: &str) {
+    cache.remove(&(user_id, nonce.to_string()));
+}
+
+fn evict_expired_nonces(cache: &NonceCache, now: Instant) {
+    cache.retain(|_, seen_at| now.saturating_duration_since(*seen_at) < NONCE_WINDOW);
+}
+
+/// Spawns a background task that periodically forgets nonces older than the replay window.
+pub fn spawn_nonce_evictor(cache: NonceCache) {
+    tokio::spawn(async move {
+        loop {
+            sleep(NONCE_EVICTION_INTERVAL).await;
+            evict_expired_nonces(&cache, Instant::now());
+        }
+    });
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::crypto::DEFAULT_ENCRYPTION_VERSION;
+    use crate::repo::fake::{
+        FakeAnnouncementRepo, FakeContactRepo, FakeDeviceRepo, FakeMessageRepo, FakeUserRepo,
+    };
+    use crate::repo::{ConversationSettings, UserPreferences};
+    use chrono::Utc;
+
+    #[test]
+    fn test_close_reasons_use_valid_codes() {
+        let reasons = [
+            CloseReason::ServerShutdown,
+            CloseReason::PolicyViolation,
+            CloseReason::TryAgainLater,
+            CloseReason::Replaced,
+            CloseReason::TokenExpired,
+            CloseReason::DeviceRevoked,
+            CloseReason::ConnectionLimit,
+            CloseReason::InvalidToken,
+            CloseReason::FrameTooLarge,
+        ];
+        for reason in reasons {
+            let frame = reason.close_frame();
+            // 1000-1015 are protocol codes, 4000-4999 are reserved for applications
+            assert!((1000..=1015).contains(&frame.code) || (4000..=4999).contains(&frame.code));
+            // Close reasons must fit in a control frame (125 bytes minus the code)
+            assert!(frame.reason.len() <= 123);
+        }
+    }
+
+    #[test]
+    fn test_redis_reconnect_delay_doubles_up_to_the_cap() {
+        assert_eq!(redis_reconnect_delay(1), REDIS_RECONNECT_INITIAL_DELAY);
+        assert_eq!(redis_reconnect_delay(3), REDIS_RECONNECT_INITIAL_DELAY * 4);
+        assert_eq!(redis_reconnect_delay(40), REDIS_RECONNECT_MAX_DELAY);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs REDIS_URL pointing to a Redis server"]
+    async fn test_redis_subscription_is_restored_after_a_disconnect() {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+        let connections = create_connection_manager();
+        let user_id = Uuid::new_v4();
+        let (_tx, mut rx, _) = join_user_channel(&connections, user_id);
+        let (mut publisher, subscriptions) =
+            connect_redis(&url, connections.clone()).await.unwrap();
+        subscriptions
+            .sink()
+            .subscribe(user_channel(user_id))
+            .await
+            .unwrap();
+
+        let _: () = redis::cmd("CLIENT")
+            .arg("KILL")
+            .arg("TYPE")
+            .arg("pubsub")
+            .query_async(&mut publisher)
+            .await
+            .unwrap();
+        for _ in 0..100 {
+            if !subscriptions.is_connected() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!subscriptions.is_connected());
+        for _ in 0..500 {
+            if subscriptions.is_connected() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(subscriptions.is_connected());
+
+        // The connected user's channel was subscribed again on the new connection
+        let payload = serde_json::to_string(&WSEvent::HelloAck(HelloAck::current())).unwrap();
+        let _: i64 = publisher
+            .publish(user_channel(user_id), payload)
+            .await
+            .unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event, WSEvent::HelloAck(_)));
+    }
+
+    #[test]
+    fn test_send_message_defaults_to_encryption_version_one() {
+        let data: SendMessageData = serde_json::from_value(serde_json::json!({
+            "message_id": Uuid::new_v4().to_string(),
+            "receiver_id": Uuid::new_v4().to_string(),
+            "type": "Text",
+            "encrypted_content": "AQID",
+            "iv": "AAAA",
+        }))
+        .unwrap();
+        assert_eq!(data.encryption_version, DEFAULT_ENCRYPTION_VERSION);
+        assert_eq!(data.cipher_suite, DEFAULT_CIPHER_SUITE);
+        assert!(data.forwarded_from_id.is_none());
+    }
+
+    #[test]
+    fn test_replayed_nonce_is_rejected() {
+        let cache = create_nonce_cache();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert!(remember_nonce(&cache, alice, "n-1", now));
+        assert!(!remember_nonce(&cache, alice, "n-1", now));
+        // Nonces are scoped per user
+        assert!(remember_nonce(&cache, bob, "n-1", now));
+        assert!(remember_nonce(&cache, alice, "n-2", now));
+    }
+
+    #[test]
+    fn test_expired_nonces_are_evicted() {
+        let cache = create_nonce_cache();
+        let alice = Uuid::new_v4();
+        let start = Instant::now();
+        assert!(remember_nonce(&cache, alice, "old", start));
+        let later = start + NONCE_WINDOW;
+        assert!(remember_nonce(&cache, alice, "fresh", later));
+
+        evict_expired_nonces(&cache, later);
+        assert_eq!(cache.len(), 1);
+        assert!(remember_nonce(&cache, alice, "old", later));
+    }
+
+    #[test]
+    fn test_full_nonce_cache_drops_expired_nonces_first() {
+        let cache = create_nonce_cache();
+        let alice = Uuid::new_v4();
+        let start = Instant::now();
+        for i in 0..MAX_REMEMBERED_NONCES {
+            cache.insert((alice, i.to_string()), start);
+        }
+
+        // Full with live nonces: a new one is accepted but not remembered
+        assert!(remember_nonce(&cache, alice, "new", start));
+        assert!(remember_nonce(&cache, alice, "new", start));
+        assert_eq!(cache.len(), MAX_REMEMBERED_NONCES);
+        assert!(!remember_nonce(&cache, alice, "0", start));
+
+        // Once they expire, they make room
+        let later = start + NONCE_WINDOW;
+        assert!(remember_nonce(&cache, alice, "new", later));
+        assert!(!remember_nonce(&cache, alice, "new", later));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_user_channel_round_trip() {
+        let user_id = Uuid::new_v4();
+        let channel = user_channel(user_id);
+        assert_eq!(channel, format!("ws:user:{}", user_id));
+        assert_eq!(user_from_channel(&channel), Some(user_id));
+        assert_eq!(user_from_channel("ws:user:not-a-uuid"), None);
+        assert_eq!(user_from_channel(&user_id.to_string()), None);
+    }
+
+    #[test]
+    fn test_published_events_round_trip() {
+        let event = WSEvent::PinUpdate(PinUpdate {
+            message_id: Uuid::new_v4().to_string(),
+            pinned: true,
+            updated_by: Uuid::new_v4().to_string(),
+        });
+        let payload = serde_json::to_string(&event).unwrap();
+        let parsed: WSEvent = serde_json::from_str(&payload).unwrap();
+        let (WSEvent::PinUpdate(sent), WSEvent::PinUpdate(received)) = (event, parsed) else {
+            panic!("event kind changed in transit");
+        };
+        assert_eq!(sent.message_id, received.message_id);
+        assert!(received.pinned);
+    }
+
+    #[test]
+    fn test_ack_retry_schedule() {
+        let timeout = Duration::from_millis(5000);
+        assert_eq!(
+            ack_retry(Duration::from_millis(4999), timeout),
+            AckRetry::Wait
+        );
+        assert_eq!(
+            ack_retry(Duration::from_millis(5000), timeout),
+            AckRetry::Resend
+        );
+        assert_eq!(
+            ack_retry(Duration::from_millis(19_999), timeout),
+            AckRetry::Resend
+        );
+        assert_eq!(
+            ack_retry(Duration::from_millis(20_000), timeout),
+            AckRetry::GiveUp
+        );
+    }
+
+    #[test]
+    fn test_ack_id_only_serialized_when_set() {
+        let event = WSEvent::UserOnline(Uuid::new_v4().to_string());
+        assert!(!event.requires_ack());
+        let message = event_message(&event).unwrap();
+        let json = serde_json::to_value(&message).unwrap();
+        assert!(json.get("ack_id").is_none());
+
+        let event = WSEvent::StatusUpdate(StatusUpdate {
+            message_id: Uuid::new_v4().to_string(),
+            status: MessageStatus::Read,
+            updated_by: Uuid::new_v4().to_string(),
+        });
+        assert!(event.requires_ack());
+        let mut message = event_message(&event).unwrap();
+        message.ack_id = Some(7);
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["ack_id"], 7);
+        assert!(event_message(&WSEvent::Close(CloseReason::Replaced)).is_none());
+
+        // Frames from clients that never send an ack_id still parse
+        let parsed: WebSocketMessage =
+            serde_json::from_str(r#"{"message_type":"ping","data":{}}"#).unwrap();
+        assert!(parsed.ack_id.is_none());
+    }
+
+    #[test]
+    fn test_announcement_is_marked_server_originated() {
+        let record = AnnouncementRecord {
+            id: Uuid::new_v4(),
+            message: "Maintenance tonight".to_string(),
+            severity: "warning".to_string(),
+            created_at: Utc::now(),
+            expires_at: None,
+        };
+        let event = WSEvent::Announcement(AnnouncementNotification::from_record(&record));
+        assert!(!event.requires_ack());
+        let message = event_message(&event).unwrap();
+        assert_eq!(message.message_type, "announcement");
+        assert_eq!(message.data["origin"], "server");
+        assert_eq!(message.data["severity"], "warning");
+        assert_eq!(message.data["expires_at"], serde_json::Value::Null);
+        assert!(user_from_channel(ANNOUNCEMENT_CHANNEL).is_none());
+    }
+
+    #[test]
+    fn test_unread_counts_event() {
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let counts = UnreadCounts::from(HashMap::from([(alice, 2), (bob, 3)]));
+        let message = event_message(&WSEvent::UnreadCounts(counts)).unwrap();
+        assert_eq!(message.message_type, "unread_counts");
+        assert_eq!(message.data["counts"][alice.to_string()], 2);
+        assert_eq!(message.data["total"], 5);
+        assert!(!WSEvent::UnreadCounts(UnreadCounts::from(HashMap::new())).requires_ack());
+    }
+
+    #[test]
+    fn test_rejects_oversized_frame_before_parsing() {
+        let frame = format!(
+            r#"{{"message_type":"ping","data":{{"pad":"{}"}}}}"#,
+            "a".repeat(10 * 1024 * 1024)
+        );
+        assert_eq!(
+            check_client_frame(&frame, DEFAULT_WS_MAX_FRAME_BYTES),
+            Err(FrameRejection::TooLarge)
+        );
+        let ping = r#"{"message_type":"ping","data":{}}"#;
+        assert_eq!(check_client_frame(ping, DEFAULT_WS_MAX_FRAME_BYTES), Ok(()));
+    }
+
+    #[test]
+    fn test_rejects_deeply_nested_frame() {
+        // 10k levels fit well within the size limit but must never reach the parser
+        let frame = format!(
+            r#"{{"message_type":"ping","data":{}{}}}"#,
+            "[".repeat(10_000),
+            "]".repeat(10_000)
+        );
+        assert!(frame.len() < DEFAULT_WS_MAX_FRAME_BYTES);
+        assert_eq!(
+            check_client_frame(&frame, DEFAULT_WS_MAX_FRAME_BYTES),
+            Err(FrameRejection::TooDeep)
+        );
+    }
+
+    fn parse_frame(text: &str) -> WebSocketMessage {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_negotiates_wire_format_from_subprotocol() {
+        let msgpack = HeaderValue::from_static(MSGPACK_SUBPROTOCOL);
+        let json = HeaderValue::from_static(JSON_SUBPROTOCOL);
+        assert_eq!(
+            WireFormat::negotiated(Some(&msgpack)),
+            WireFormat::MessagePack
+        );
+        assert_eq!(WireFormat::negotiated(Some(&json)), WireFormat::Json);
+        assert_eq!(WireFormat::negotiated(None), WireFormat::Json);
+    }
+
+    #[test]
+    fn test_msgpack_frames_decode_like_json() {
+        let json = r#"{"message_type":"update_status","data":{"message_id":"m1","status":"READ"}}"#;
+        let bytes = rmp_serde::to_vec_named(&parse_frame(json)).unwrap();
+        assert!(bytes.len() < json.len());
+        let Ok(message) = ClientFrame::MessagePack(bytes).decode(DEFAULT_WS_MAX_FRAME_BYTES) else {
+            panic!("MessagePack frame did not decode");
+        };
+        assert_eq!(message.message_type, "update_status");
+        assert_eq!(message.data, parse_frame(json).data);
+
+        let Ok(Message::Binary(encoded)) = WireFormat::MessagePack.encode(&message) else {
+            panic!("MessagePack connections must get binary frames");
+        };
+        let reply: WebSocketMessage = rmp_serde::from_slice(&encoded).unwrap();
+        assert_eq!(reply.data["status"], "READ");
+        assert!(matches!(
+            WireFormat::Json.encode(&message),
+            Ok(Message::Text(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_oversized_and_deep_msgpack_frames() {
+        let mut data = serde_json::json!([]);
+        for _ in 0..100 {
+            data = serde_json::json!([data]);
+        }
+        let deep = WebSocketMessage {
+            message_type: "ping".to_string(),
+            data,
+            ack_id: None,
+        };
+        let bytes = rmp_serde::to_vec_named(&deep).unwrap();
+        assert!(matches!(
+            ClientFrame::MessagePack(bytes.clone()).decode(DEFAULT_WS_MAX_FRAME_BYTES),
+            Err(FrameError::Rejected(FrameRejection::TooDeep))
+        ));
+        assert!(matches!(
+            ClientFrame::MessagePack(bytes).decode(16),
+            Err(FrameError::Rejected(FrameRejection::TooLarge))
+        ));
+        assert!(matches!(
+            ClientFrame::MessagePack(vec![0xc1]).decode(DEFAULT_WS_MAX_FRAME_BYTES),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+
+    fn notification_for(device_id: Option<Uuid>) -> WSEvent {
+        WSEvent::NewMessage(MessageNotification {
+            id: Uuid::new_v4().to_string(),
+            timestamp: "0".to_string(),
+            sender_id: Uuid::new_v4().to_string(),
+            receiver_id: Uuid::new_v4().to_string(),
+            status: MessageStatus::Sent,
+            r#type: "Text".to_string(),
+            encrypted_content: String::new(),
+            iv: String::new(),
+            was_forwarded: false,
+            reply_to: None,
+            encryption_version: DEFAULT_ENCRYPTION_VERSION,
+            cipher_suite: DEFAULT_CIPHER_SUITE.to_string(),
+            device_id: device_id.map(|id| id.to_string()),
+        })
+    }
+
+    #[test]
+    fn test_device_routing() {
+        let (phone, laptop) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // Untargeted messages reach every connection, targeted copies only their device
+        let untargeted = notification_for(None);
+        assert_eq!(route_for_device(&untargeted, None), DeviceRouting::Deliver);
+        assert_eq!(
+            route_for_device(&untargeted, Some(phone)),
+            DeviceRouting::Deliver
+        );
+        let for_phone = notification_for(Some(phone));
+        assert_eq!(
+            route_for_device(&for_phone, Some(phone)),
+            DeviceRouting::Deliver
+        );
+        assert_eq!(
+            route_for_device(&for_phone, Some(laptop)),
+            DeviceRouting::Skip
+        );
+        assert_eq!(route_for_device(&for_phone, None), DeviceRouting::Skip);
+
+        let revoked = WSEvent::DeviceRevoked(DeviceRevoked {
+            device_id: phone.to_string(),
+        });
+        assert_eq!(
+            route_for_device(&revoked, Some(phone)),
+            DeviceRouting::Close
+        );
+        assert_eq!(
+            route_for_device(&revoked, Some(laptop)),
+            DeviceRouting::Deliver
+        );
+        assert_eq!(route_for_device(&revoked, None), DeviceRouting::Deliver);
+    }
+
+    #[test]
+    fn test_connections_share_a_channel_per_user() {
+        let connections = create_connection_manager();
+        let user_id = Uuid::new_v4();
+        let (tx, rx, first) = join_user_channel(&connections, user_id);
+        assert!(first);
+        let (other_tx, other_rx, first) = join_user_channel(&connections, user_id);
+        assert!(!first);
+        assert!(tx.same_channel(&other_tx));
+        assert_eq!(tx.receiver_count(), 2);
+        drop((rx, other_rx));
+        assert_eq!(tx.receiver_count(), 0);
+    }
+
+    #[test]
+    fn test_shard_stats_report_hotspots() {
+        let connections = create_sharded_connection_manager(4);
+        let stats = ConnectionManagerStats::collect(&connections);
+        assert_eq!(stats.shards.len(), 4);
+        assert_eq!(stats.imbalance_ratio(), 0.0);
+
+        // Only users that hash to the first shard seen connect
+        let mut hot_shard = None;
+        while connections.len() < 8 {
+            let user_id = Uuid::new_v4();
+            let shard = connections.determine_map(&user_id);
+            if *hot_shard.get_or_insert(shard) == shard {
+                join_user_channel(&connections, user_id);
+            }
+        }
+        let stats = ConnectionManagerStats::collect(&connections);
+        let hot = &stats.shards[&hot_shard.unwrap()];
+        assert_eq!(hot.entry_count, 8);
+        assert!(hot.load_factor > 0.0 && hot.load_factor <= 1.0);
+        assert_eq!(stats.shards.values().map(|s| s.entry_count).sum::<u64>(), 8);
+        assert_eq!(stats.imbalance_ratio(), 4.0);
+    }
+
+    #[test]
+    fn test_json_depth_ignores_brackets_in_strings() {
+        let nested = format!(
+            "{}{}",
+            "[".repeat(MAX_FRAME_DEPTH),
+            "]".repeat(MAX_FRAME_DEPTH)
+        );
+        assert!(!json_depth_exceeds(nested.as_bytes(), MAX_FRAME_DEPTH));
+        assert!(json_depth_exceeds(
+            format!("[{}", nested).as_bytes(),
+            MAX_FRAME_DEPTH
+        ));
+        let quoted = format!(r#"{{"text":"{}\"{}"}}"#, "[".repeat(100), "{".repeat(100));
+        assert!(!json_depth_exceeds(quoted.as_bytes(), MAX_FRAME_DEPTH));
+    }
+
+    /// State backed by the database at `DATABASE_URL`, without Redis or background tasks.
+    async fn db_state() -> Arc<AppState> {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a migrated database");
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(10)
+            .connect(&url)
+            .await
+            .unwrap();
+        Arc::new(AppState::build_for_tests(db))
+    }
+
+    /// State backed by the in-memory fakes. The pool is never connected.
+    pub(crate) fn fake_state(
+        require_contact_for_messages: bool,
+    ) -> (Arc<AppState>, Arc<FakeMessageRepo>) {
+        fake_state_with(|state| state.require_contact_for_messages = require_contact_for_messages)
+    }
+
+    /// Like `fake_state`, with `configure` applied before the state is shared.
+    pub(crate) fn fake_state_with(
+        configure: impl FnOnce(&mut AppState),
+    ) -> (Arc<AppState>, Arc<FakeMessageRepo>) {
+        let users = Arc::new(FakeUserRepo::new());
+        let messages = Arc::new(FakeMessageRepo::new());
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+        let state = AppState::test_builder(db)
+            .users(users.clone())
+            .messages(messages.clone())
+            .contacts(Arc::new(FakeContactRepo::new(users)))
+            .announcements(Arc::new(FakeAnnouncementRepo::new()))
+            .devices(Arc::new(FakeDeviceRepo::new()))
+            .configure(configure)
+            .build();
+        (Arc::new(state), messages)
+    }
+
+    async fn send_probe(state: &Arc<AppState>, sender: Uuid, receiver: Uuid) -> Result<(), String> {
+        let frame = serde_json::json!({
+            "message_type": "send_message",
+            "data": {
+                "message_id": Uuid::new_v4().to_string(),
+                "receiver_id": receiver.to_string(),
+                "type": crate::service::PROBE_MESSAGE_TYPE,
+                "encrypted_content": "",
+                "iv": "",
+            },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        handle_client_message(
+            parse_frame(&frame),
+            sender,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_probe_reaches_connected_receiver_without_being_stored() {
+        let (state, messages) = fake_state(false);
+        let (sender, receiver) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+        let (_receiver_tx, mut receiver_rx, _) = join_user_channel(&state.connections, receiver);
+
+        send_probe(&state, sender, receiver).await.unwrap();
+        match receiver_rx.try_recv().unwrap() {
+            WSEvent::Probe(probe) => assert_eq!(probe.sender_id, sender.to_string()),
+            other => panic!("expected a probe, got {:?}", other),
+        }
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::ProbeResult(result) => assert!(result.delivered),
+            other => panic!("expected a probe_result, got {:?}", other),
+        }
+        assert!(sender_rx.try_recv().is_err(), "no SENT status for a probe");
+        assert!(
+            messages
+                .unread_counts(receiver, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_to_disconnected_receiver_is_not_delivered() {
+        let (state, _) = fake_state(false);
+        let (sender, receiver) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+
+        send_probe(&state, sender, receiver).await.unwrap();
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::ProbeResult(result) => assert!(!result.delivered),
+            other => panic!("expected a probe_result, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_to_receiver_who_has_not_added_sender_is_rejected() {
+        let (state, _) = fake_state(true);
+        let (sender, receiver) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+        let (_receiver_tx, mut receiver_rx, _) = join_user_channel(&state.connections, receiver);
+
+        assert!(send_probe(&state, sender, receiver).await.is_err());
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::Error(error) => assert_eq!(error.code, ErrorCode::NotAContact),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(receiver_rx.try_recv().is_err());
+    }
+
+    async fn send_text(state: &Arc<AppState>, sender: Uuid, receiver: Uuid) -> Uuid {
+        let message_id = Uuid::new_v4();
+        let frame = serde_json::json!({
+            "message_type": "send_message",
+            "data": {
+                "message_id": message_id.to_string(),
+                "receiver_id": receiver.to_string(),
+                "type": "Text",
+                "encrypted_content": "AQID",
+                "iv": "AAAAAAAAAAAAAAAA",
+            },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        handle_client_message(
+            parse_frame(&frame),
+            sender,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        message_id
+    }
+
+    #[tokio::test]
+    async fn test_contact_requirement_needs_the_receiver_to_add_the_sender() {
+        let (state, messages) = fake_state(true);
+        let alice = state
+            .users
+            .create_user("alice", "hash", "key-a")
+            .await
+            .unwrap();
+        let bob = state
+            .users
+            .create_user("bob", "hash", "key-b")
+            .await
+            .unwrap();
+        let (_alice_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+        let (_bob_tx, mut bob_rx, _) = join_user_channel(&state.connections, bob);
+        let message_id = Uuid::new_v4();
+        let frame = serde_json::json!({
+            "message_type": "send_message",
+            "data": {
+                "message_id": message_id.to_string(),
+                "receiver_id": bob.to_string(),
+                "type": "Text",
+                "encrypted_content": "AQID",
+                "iv": "AAAAAAAAAAAAAAAA",
+            },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        let send = || {
+            handle_client_message(
+                parse_frame(&frame),
+                alice,
+                &state.connections,
+                &pending_acks,
+                state.clone(),
+            )
+        };
+        let assert_rejected = |event: WSEvent| match event {
+            WSEvent::Error(error) => {
+                assert_eq!(error.code, ErrorCode::NotAContact);
+                assert_eq!(error.message, NOT_A_CONTACT_MESSAGE);
+                assert_eq!(error.message_id, Some(message_id.to_string()));
+                assert_eq!(error.retry_after, None);
+            }
+            other => panic!("expected not_a_contact, got {:?}", other),
+        };
+
+        assert!(send().await.is_err());
+        assert_rejected(alice_rx.try_recv().unwrap());
+        assert!(bob_rx.try_recv().is_err());
+        assert!(messages.find_message(message_id).await.unwrap().is_none());
+
+        // Alice adding Bob is one-sided: Bob has not accepted her
+        state.contacts.add_contact(alice, bob, 10).await.unwrap();
+        state.relationships.clear();
+        assert!(send().await.is_err());
+        assert_rejected(alice_rx.try_recv().unwrap());
+
+        // ...but it lets Bob message her, after which her reply is accepted
+        let opener = send_text(&state, bob, alice).await;
+        match alice_rx.try_recv().unwrap() {
+            WSEvent::NewMessage(message) => assert_eq!(message.id, opener.to_string()),
+            other => panic!("expected Bob's message, got {:?}", other),
+        }
+        while bob_rx.try_recv().is_ok() {}
+        send().await.unwrap();
+        match bob_rx.try_recv().unwrap() {
+            WSEvent::NewMessage(message) => {
+                assert_eq!(message.id, message_id.to_string());
+                assert_eq!(message.sender_id, alice.to_string());
+            }
+            other => panic!("expected Alice's reply, got {:?}", other),
+        }
+        assert!(messages.find_message(message_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_message_to_self_is_rejected_by_default() {
+        let (state, messages) = fake_state(false);
+        let user = Uuid::new_v4();
+        let (_tx, mut rx, _) = join_user_channel(&state.connections, user);
+        let frame = serde_json::json!({
+            "message_type": "send_message",
+            "data": {
+                "message_id": Uuid::new_v4().to_string(),
+                "receiver_id": user.to_string(),
+                "type": "Text",
+                "encrypted_content": "AQID",
+                "iv": "AAAAAAAAAAAAAAAA",
+            },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        let result = handle_client_message(
+            parse_frame(&frame),
+            user,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        match rx.try_recv().unwrap() {
+            WSEvent::Error(error) => assert_eq!(error.code, ErrorCode::SelfMessageDisabled),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(messages.unread_counts(user, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_message_to_self_reaches_own_channel_when_enabled() {
+        let (state, messages) = fake_state_with(|state| {
+            state.self_messages_enabled = true;
+            // A note to self needs no contact
+            state.require_contact_for_messages = true;
+        });
+        let user = Uuid::new_v4();
+        let (_tx, mut rx, _) = join_user_channel(&state.connections, user);
+
+        let message_id = send_text(&state, user, user).await;
+        match rx.try_recv().unwrap() {
+            WSEvent::NewMessage(message) => {
+                assert_eq!(message.id, message_id.to_string());
+                assert_eq!(message.receiver_id, user.to_string());
+            }
+            other => panic!("expected the note, got {:?}", other),
+        }
+        assert!(messages.find_message(message_id).await.unwrap().is_some());
+        assert!(
+            !state
+                .contacts
+                .accepted_peers(user)
+                .await
+                .unwrap()
+                .contains(&user)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cipher_suite_is_checked_and_passed_to_receiver() {
+        let (state, messages) =
+            fake_state_with(|state| state.cipher_suites = vec!["CHACHA20_POLY1305".to_string()]);
+        let alice = state
+            .users
+            .create_user("alice", "hash", "key-a")
+            .await
+            .unwrap();
+        let bob = state
+            .users
+            .create_user("bob", "hash", "key-b")
+            .await
+            .unwrap();
+        let (_alice_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+        let (_bob_tx, mut bob_rx, _) = join_user_channel(&state.connections, bob);
+        let pending_acks = PendingAcks::default();
+        let send = |cipher_suite: Option<&str>| {
+            let mut data = serde_json::json!({
+                "message_id": Uuid::new_v4().to_string(),
+                "receiver_id": bob.to_string(),
+                "type": "Text",
+                "encrypted_content": "AQID",
+                "iv": "AAAAAAAAAAAAAAAA",
+            });
+            if let Some(cipher_suite) = cipher_suite {
+                data["cipher_suite"] = cipher_suite.into();
+            }
+            serde_json::json!({ "message_type": "send_message", "data": data }).to_string()
+        };
+
+        // Omitting the field means AES_256_GCM, which this server does not accept
+        let result = handle_client_message(
+            parse_frame(&send(None)),
+            alice,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await;
+        assert!(result.is_err());
+        match alice_rx.try_recv().unwrap() {
+            WSEvent::Error(error) => assert_eq!(error.code, ErrorCode::UnsupportedCipherSuite),
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        handle_client_message(
+            parse_frame(&send(Some("CHACHA20_POLY1305"))),
+            alice,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        let message_id = match bob_rx.try_recv().unwrap() {
+            WSEvent::NewMessage(message) => {
+                assert_eq!(message.cipher_suite, "CHACHA20_POLY1305");
+                Uuid::parse_str(&message.id).unwrap()
+            }
+            other => panic!("expected the message, got {:?}", other),
+        };
+        let stored = messages.find_message(message_id).await.unwrap().unwrap();
+        assert_eq!(stored.cipher_suite, "CHACHA20_POLY1305");
+    }
+
+    /// Fetches `GET /messages/{other}` as `viewer` and returns each message's id and status.
+    async fn fetch_statuses(
+        state: &Arc<AppState>,
+        viewer: Uuid,
+        other: Uuid,
+        limit: Option<i64>,
+        no_receipt: Option<bool>,
+    ) -> Vec<(String, String)> {
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(viewer, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        fetch_statuses_with_token(state, &token, other, limit, no_receipt).await
+    }
+
+    async fn fetch_statuses_with_token(
+        state: &Arc<AppState>,
+        token: &str,
+        other: Uuid,
+        limit: Option<i64>,
+        no_receipt: Option<bool>,
+    ) -> Vec<(String, String)> {
+        use crate::api::{MessageRangeQuery, UuidPath, get_messages_with_user};
+        use axum::body::HttpBody;
+        use axum::http::HeaderMap;
+        use axum::response::IntoResponse;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "authorization",
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        let response = get_messages_with_user(
+            UuidPath(other),
+            Query(MessageRangeQuery {
+                after: None,
+                before: None,
+                order: None,
+                limit,
+                cursor: None,
+                no_receipt,
+            }),
+            State(state.clone()),
+            headers,
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let items = if limit.is_some() {
+            body["items"].clone()
+        } else {
+            body
+        };
+        items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| {
+                (
+                    m["id"].as_str().unwrap().to_string(),
+                    m["status"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fetching_marks_only_the_viewers_sent_messages_delivered() {
+        let (state, messages) = fake_state(false);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let first = messages.seed_message_at(alice, bob, MessageStatus::Sent, 1_000);
+        let read = messages.seed_message_at(alice, bob, MessageStatus::Read, 2_000);
+        let reply = messages.seed_message_at(bob, alice, MessageStatus::Sent, 3_000);
+        let latest = messages.seed_message_at(alice, bob, MessageStatus::Sent, 4_000);
+        let (_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+
+        // A page of the two newest: only alice's message to bob changes, not bob's reply
+        let page = fetch_statuses(&state, bob, alice, Some(2), None).await;
+        assert_eq!(
+            page,
+            vec![
+                (latest.to_string(), "DELIVERED".to_string()),
+                (reply.to_string(), "SENT".to_string()),
+            ]
+        );
+        match alice_rx.try_recv().unwrap() {
+            WSEvent::StatusUpdate(update) => {
+                assert_eq!(update.message_id, latest.to_string());
+                assert_eq!(update.status, MessageStatus::Delivered);
+                assert_eq!(update.updated_by, bob.to_string());
+            }
+            other => panic!("expected a status update, got {:?}", other),
+        }
+        assert!(alice_rx.try_recv().is_err());
+        assert_eq!(
+            messages.find_message(first).await.unwrap().unwrap().status,
+            MessageStatus::Sent
+        );
+
+        let all = fetch_statuses(&state, bob, alice, None, None).await;
+        let statuses: Vec<&str> = all.iter().map(|(_, status)| status.as_str()).collect();
+        assert_eq!(statuses, ["DELIVERED", "READ", "SENT", "DELIVERED"]);
+        match alice_rx.try_recv().unwrap() {
+            WSEvent::StatusUpdate(update) => assert_eq!(update.message_id, first.to_string()),
+            other => panic!("expected a status update, got {:?}", other),
+        }
+        assert!(alice_rx.try_recv().is_err());
+        assert_eq!(
+            messages.find_message(read).await.unwrap().unwrap().status,
+            MessageStatus::Read
+        );
+        assert_eq!(
+            messages.find_message(reply).await.unwrap().unwrap().status,
+            MessageStatus::Sent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_receipt_leaves_fetched_messages_sent() {
+        let (state, messages) = fake_state(false);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let message_id = messages.seed_message_at(alice, bob, MessageStatus::Sent, 1_000);
+        let (_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+
+        let fetched = fetch_statuses(&state, bob, alice, None, Some(true)).await;
+        assert_eq!(fetched, vec![(message_id.to_string(), "SENT".to_string())]);
+        assert!(alice_rx.try_recv().is_err());
+        assert_eq!(
+            messages
+                .find_message(message_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .status,
+            MessageStatus::Sent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_impersonated_fetch_sends_no_receipts() {
+        let (state, messages) = fake_state(false);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let message_id = messages.seed_message_at(alice, bob, MessageStatus::Sent, 1_000);
+        let (_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+        let token = crate::auth::create_impersonation_token(
+            bob,
+            "support",
+            Uuid::new_v4(),
+            &state.jwt_secret,
+            expires_at,
+        )
+        .unwrap();
+
+        // Even without no_receipt, and for pages as for the whole conversation
+        for limit in [None, Some(10)] {
+            let fetched = fetch_statuses_with_token(&state, &token, alice, limit, None).await;
+            assert_eq!(fetched, vec![(message_id.to_string(), "SENT".to_string())]);
+        }
+        assert!(alice_rx.try_recv().is_err());
+        assert_eq!(
+            messages
+                .find_message(message_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .status,
+            MessageStatus::Sent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signed_receipt_reaches_sender() {
+        let (state, messages) = fake_state(false);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let message_id = messages.seed_message_at(alice, bob, MessageStatus::Delivered, 1_000);
+        let (_alice_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+        let (_bob_tx, mut bob_rx, _) = join_user_channel(&state.connections, bob);
+        let signature = base64::engine::general_purpose::STANDARD.encode([1u8; 64]);
+        let frame = serde_json::json!({
+            "message_type": "signed_receipt",
+            "data": { "message_id": message_id.to_string(), "status": "READ", "recipient_signature": signature },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        handle_client_message(
+            parse_frame(&frame),
+            bob,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+
+        match alice_rx.try_recv().unwrap() {
+            WSEvent::StatusUpdate(update) => assert_eq!(update.status, MessageStatus::Read),
+            other => panic!("expected a status update, got {:?}", other),
+        }
+        match alice_rx.try_recv().unwrap() {
+            WSEvent::DeliveryReceipt(receipt) => {
+                assert_eq!(receipt.message_id, message_id.to_string());
+                assert_eq!(receipt.status, MessageStatus::Read);
+                assert_eq!(receipt.timestamp, "1000");
+                assert_eq!(receipt.signed_by, bob.to_string());
+                assert_eq!(receipt.recipient_signature, signature);
+            }
+            other => panic!("expected a delivery receipt, got {:?}", other),
+        }
+        // The receiver only gets the status update
+        assert!(matches!(bob_rx.try_recv(), Ok(WSEvent::StatusUpdate(_))));
+        assert!(bob_rx.try_recv().is_err());
+    }
+
+    async fn set_status(state: &Arc<AppState>, user: Uuid, message_id: Uuid, status: &str) {
+        let frame = serde_json::json!({
+            "message_type": "update_status",
+            "data": { "message_id": message_id.to_string(), "status": status },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        handle_client_message(
+            parse_frame(&frame),
+            user,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Drains `rx`, keeping the statuses of its `message_deleted` events.
+    fn deleted_statuses(rx: &mut broadcast::Receiver<WSEvent>) -> Vec<MessageStatus> {
+        let mut statuses = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if let WSEvent::MessageDeleted(deleted) = event {
+                statuses.push(deleted.status);
+            }
+        }
+        statuses
+    }
+
+    /// A user with `delete_on_delivered` set to `enabled`, and their channel.
+    async fn sender_with_preference(
+        state: &Arc<AppState>,
+        enabled: bool,
+    ) -> (
+        Uuid,
+        broadcast::Sender<WSEvent>,
+        broadcast::Receiver<WSEvent>,
+    ) {
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let preferences = UserPreferences {
+            delete_on_delivered: enabled,
+        };
+        state
+            .users
+            .set_preferences(sender, &preferences)
+            .await
+            .unwrap();
+        let (tx, rx, _) = join_user_channel(&state.connections, sender);
+        (sender, tx, rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delete_on_delivered_deletes_once_delivered() {
+        let (state, messages) = fake_state(false);
+        let (sender, _sender_tx, mut sender_rx) = sender_with_preference(&state, true).await;
+        let receiver = Uuid::new_v4();
+        let (_receiver_tx, mut receiver_rx, _) = join_user_channel(&state.connections, receiver);
+        let message_id = messages.seed_message(sender, receiver, MessageStatus::Sent);
+
+        set_status(&state, receiver, message_id, "DELIVERED").await;
+        sleep(MESSAGE_DELETION_DELAY * 2).await;
+        assert!(messages.find_message(message_id).await.unwrap().is_none());
+        assert_eq!(
+            deleted_statuses(&mut sender_rx),
+            vec![MessageStatus::Delivered]
+        );
+        assert_eq!(
+            deleted_statuses(&mut receiver_rx),
+            vec![MessageStatus::Delivered]
+        );
+
+        // Fetching over REST counts as delivery too
+        let fetched = messages.seed_message(sender, receiver, MessageStatus::Sent);
+        fetch_statuses(&state, receiver, sender, None, None).await;
+        sleep(MESSAGE_DELETION_DELAY * 2).await;
+        assert!(messages.find_message(fetched).await.unwrap().is_none());
+        assert_eq!(
+            deleted_statuses(&mut sender_rx),
+            vec![MessageStatus::Delivered]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delivered_messages_kept_without_preference() {
+        let (state, messages) = fake_state(false);
+        let (sender, _sender_tx, mut sender_rx) = sender_with_preference(&state, false).await;
+        let receiver = Uuid::new_v4();
+        let message_id = messages.seed_message(sender, receiver, MessageStatus::Sent);
+
+        set_status(&state, receiver, message_id, "DELIVERED").await;
+        sleep(MESSAGE_DELETION_DELAY * 2).await;
+        assert_eq!(
+            messages.status_of(message_id),
+            Some(MessageStatus::Delivered)
+        );
+        assert!(deleted_statuses(&mut sender_rx).is_empty());
+
+        // Reading still deletes it
+        set_status(&state, receiver, message_id, "READ").await;
+        sleep(MESSAGE_DELETION_DELAY * 2).await;
+        assert!(messages.find_message(message_id).await.unwrap().is_none());
+        assert_eq!(deleted_statuses(&mut sender_rx), vec![MessageStatus::Read]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_during_delivered_deletion_deletes_once() {
+        let (state, messages) = fake_state(false);
+        let (sender, _sender_tx, mut sender_rx) = sender_with_preference(&state, true).await;
+        let receiver = Uuid::new_v4();
+        let message_id = messages.seed_message(sender, receiver, MessageStatus::Sent);
+
+        set_status(&state, receiver, message_id, "DELIVERED").await;
+        set_status(&state, receiver, message_id, "READ").await;
+        sleep(MESSAGE_DELETION_DELAY * 2).await;
+        assert!(messages.find_message(message_id).await.unwrap().is_none());
+        assert_eq!(
+            deleted_statuses(&mut sender_rx),
+            vec![MessageStatus::Delivered]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_history_mode_of_either_participant_keeps_messages() {
+        let (state, messages) = fake_state(false);
+        let (sender, _sender_tx, mut sender_rx) = sender_with_preference(&state, true).await;
+        let receiver = Uuid::new_v4();
+        let keep = |keep_read_messages| ConversationSettings { keep_read_messages };
+        state
+            .contacts
+            .set_conversation_settings(receiver, sender, &keep(true))
+            .await
+            .unwrap();
+
+        // Neither READ nor the sender's delete_on_delivered removes a kept message
+        let read = messages.seed_message(sender, receiver, MessageStatus::Sent);
+        set_status(&state, receiver, read, "DELIVERED").await;
+        set_status(&state, receiver, read, "READ").await;
+        sleep(MESSAGE_DELETION_DELAY * 2).await;
+        assert_eq!(messages.status_of(read), Some(MessageStatus::Read));
+        assert!(deleted_statuses(&mut sender_rx).is_empty());
+
+        // Once both allow it, read messages are deleted again
+        state
+            .contacts
+            .set_conversation_settings(receiver, sender, &keep(false))
+            .await
+            .unwrap();
+        let later = messages.seed_message(receiver, sender, MessageStatus::Sent);
+        set_status(&state, sender, later, "READ").await;
+        sleep(MESSAGE_DELETION_DELAY * 2).await;
+        assert!(messages.find_message(later).await.unwrap().is_none());
+        assert_eq!(messages.status_of(read), Some(MessageStatus::Read));
+    }
+
+    /// Checks `frame` against the envelope and the `section` entry for its `message_type`
+    /// in the `/ws/schema` document.
+    fn assert_matches_schema(schema: &serde_json::Value, section: &str, frame: &WebSocketMessage) {
+        let json = serde_json::to_value(frame).unwrap();
+        let envelope = jsonschema::JSONSchema::compile(&schema["frame"]).unwrap();
+        assert!(
+            envelope.is_valid(&json),
+            "{} frame does not match: {}",
+            frame.message_type,
+            json
+        );
+        let data_schema = &schema[section][&frame.message_type];
+        assert!(
+            !data_schema.is_null(),
+            "no {} schema for {}",
+            section,
+            frame.message_type
+        );
+        let data_schema = jsonschema::JSONSchema::compile(data_schema).unwrap();
+        if let Err(errors) = data_schema.validate(&frame.data) {
+            let errors: Vec<String> = errors.map(|e| e.to_string()).collect();
+            panic!(
+                "{} does not match its schema: {:?} in {}",
+                frame.message_type, errors, json
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_frames_match_ws_schema() {
+        use std::collections::HashSet;
+
+        let schema = serde_json::to_value(crate::ws_schema::ws_schema()).unwrap();
+        let (state, _) = fake_state(false);
+        let alice = state
+            .users
+            .create_user("alice", "hash", "key")
+            .await
+            .unwrap();
+        let bob = Uuid::new_v4();
+        let (_alice_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+        let (_bob_tx, mut bob_rx, _) = join_user_channel(&state.connections, bob);
+        let message_id = Uuid::new_v4().to_string();
+        let send = |receiver: Uuid| {
+            serde_json::json!({
+                "message_type": "send_message",
+                "data": {
+                    "message_id": message_id,
+                    "receiver_id": receiver.to_string(),
+                    "type": "Text",
+                    "encrypted_content": "AQID",
+                    "iv": "AAAAAAAAAAAAAAAA",
+                    "nonce": format!("send-{}", receiver),
+                },
+            })
+        };
+        let status = |status: &str| {
+            serde_json::json!({
+                "message_type": "update_status",
+                "data": { "message_id": message_id, "status": status },
+            })
+        };
+        let signed_receipt = |status: &str| {
+            serde_json::json!({
+                "message_type": "signed_receipt",
+                "data": {
+                    "message_id": message_id,
+                    "status": status,
+                    "recipient_signature": base64::engine::general_purpose::STANDARD.encode([0u8; 64]),
+                },
+            })
+        };
+        // The self-test's conversation, plus a rejected note to self for an `error`
+        let client_frames = [
+            (
+                alice,
+                serde_json::json!({ "message_type": "hello", "data": {} }),
+            ),
+            (alice, send(bob)),
+            (
+                bob,
+                serde_json::json!({ "message_type": "ack", "data": { "ack_id": 1 } }),
+            ),
+            (bob, status("DELIVERED")),
+            (bob, status("read")),
+            (bob, signed_receipt("READ")),
+            (
+                bob,
+                serde_json::json!({ "message_type": "unread_counts", "data": null }),
+            ),
+            (
+                bob,
+                serde_json::json!({ "message_type": "ping", "data": {} }),
+            ),
+            (
+                bob,
+                serde_json::json!({ "message_type": "mark_typing", "data": {} }),
+            ),
+            (alice, send(alice)),
+        ];
+        let pending_acks = PendingAcks::default();
+        for (user, frame) in client_frames {
+            let message = parse_frame(&frame.to_string());
+            assert_matches_schema(&schema, "client_messages", &message);
+            let _ = handle_client_message(
+                message,
+                user,
+                &state.connections,
+                &pending_acks,
+                state.clone(),
+            )
+            .await;
+        }
+
+        let mut events = Vec::new();
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            while let Ok(event) = rx.try_recv() {
+                events.push(event);
+            }
+        }
+        // Events the conversation above does not produce
+        let base64 = "AQID".to_string();
+        events.extend([
+            WSEvent::UserOnline(alice.to_string()),
+            WSEvent::UserOffline(alice.to_string()),
+            WSEvent::PinUpdate(PinUpdate {
+                message_id: message_id.clone(),
+                pinned: true,
+                updated_by: bob.to_string(),
+            }),
+            WSEvent::MetaUpdate(MetaUpdate {
+                message_id: message_id.clone(),
+                r#type: "Image".to_string(),
+                updated_by: alice.to_string(),
+            }),
+            WSEvent::DeviceRevoked(DeviceRevoked {
+                device_id: Uuid::new_v4().to_string(),
+            }),
+            WSEvent::ContactRequest(ContactRequestNotification {
+                id: Uuid::new_v4().to_string(),
+                requester_id: alice.to_string(),
+                requester_username: "alice".to_string(),
+                target_id: bob.to_string(),
+                status: "PENDING".to_string(),
+                created_at: "2024-05-07T10:00:00+00:00".to_string(),
+                message: None,
+            }),
+            WSEvent::ConversationCleared(ConversationCleared {
+                user_id: alice.to_string(),
+                cleared_by: bob.to_string(),
+                deleted_count: 3,
+            }),
+            WSEvent::MessageDeleted(MessageDeleted {
+                message_id: message_id.clone(),
+                status: MessageStatus::Read,
+            }),
+            WSEvent::Announcement(AnnouncementNotification {
+                id: Uuid::new_v4().to_string(),
+                message: "Maintenance tonight".to_string(),
+                severity: "info".to_string(),
+                created_at: "2024-05-07T10:00:00+00:00".to_string(),
+                expires_at: None,
+                origin: "server".to_string(),
+            }),
+            WSEvent::Probe(ProbeNotification {
+                id: message_id.clone(),
+                sender_id: alice.to_string(),
+                timestamp: "1715076000000".to_string(),
+            }),
+            WSEvent::ProbeResult(ProbeResult {
+                message_id: message_id.clone(),
+                receiver_id: bob.to_string(),
+                delivered: false,
+            }),
+            WSEvent::SealedMessage(SealedMessageNotification {
+                id: message_id.clone(),
+                day: "2024-05-07".to_string(),
+                r#type: "Text".to_string(),
+                encrypted_metadata: base64.clone(),
+                encrypted_content: base64.clone(),
+                iv: base64,
+                encryption_version: 1,
+            }),
+            WSEvent::Error(FrameRejection::TooLarge.notification(1024)),
+            WSEvent::AuthFailed(AuthFailed::for_reason(CloseReason::TokenExpired)),
+        ]);
+        let mut seen = HashSet::new();
+        for event in &events {
+            if let Some(frame) = event_message(event) {
+                assert_matches_schema(&schema, "server_events", &frame);
+                seen.insert(frame.message_type);
+            }
+        }
+        // Every documented event was checked, so none can go stale unnoticed
+        let documented: HashSet<String> = schema["server_events"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(seen, documented);
+    }
+
+    #[tokio::test]
+    async fn test_sent_message_is_marked_sent_without_undoing_later_statuses() {
+        let (state, messages) = fake_state(false);
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let receiver = Uuid::new_v4();
+
+        // Stored PENDING, then SENT once delivery was attempted, even to an offline receiver
+        let message_id = send_text(&state, sender, receiver).await;
+        assert_eq!(messages.status_of(message_id), Some(MessageStatus::Sent));
+
+        // A receipt that arrived before the delivery attempt finished is kept
+        for status in [MessageStatus::Delivered, MessageStatus::Read] {
+            let id = messages.seed_message(sender, receiver, status);
+            messages.mark_sent_if_pending(id).await.unwrap();
+            assert_eq!(messages.status_of(id), Some(status));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_upgrades_only_stale_pending_messages() {
+        use crate::clock::MockClock;
+        use chrono::TimeZone;
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let clock = MockClock::new(now);
+        let messages = FakeMessageRepo::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let stale_at = now.timestamp_millis() - PENDING_MESSAGE_MAX_AGE_MS - 1;
+        let stale = messages.seed_message_at(alice, bob, MessageStatus::Pending, stale_at);
+        let recent = messages.seed_message_at(
+            alice,
+            bob,
+            MessageStatus::Pending,
+            now.timestamp_millis() - 1_000,
+        );
+        let read = messages.seed_message_at(bob, alice, MessageStatus::Read, stale_at);
+
+        assert_eq!(
+            sweep_stale_pending_messages(&messages, &clock)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(messages.status_of(stale), Some(MessageStatus::Sent));
+        assert_eq!(messages.status_of(recent), Some(MessageStatus::Pending));
+        assert_eq!(messages.status_of(read), Some(MessageStatus::Read));
+
+        // The recent one is picked up once it is old enough
+        clock.advance(chrono::Duration::minutes(1));
+        assert_eq!(
+            sweep_stale_pending_messages(&messages, &clock)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(messages.status_of(recent), Some(MessageStatus::Sent));
+    }
+
+    #[tokio::test]
+    async fn test_send_that_is_not_stored_does_not_use_quota() {
+        use crate::clock::MockClock;
+        use chrono::TimeZone;
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 10, 0, 30).unwrap();
+        let (state, messages) =
+            fake_state_with(|state| state.clock = Arc::new(MockClock::new(now)));
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let receiver = Uuid::new_v4();
+        let windows = service::send_quota_windows(state.send_limits.new_account, now);
+
+        let message_id = send_text(&state, sender, receiver).await;
+        assert_eq!(
+            messages.send_counts(sender, &windows).await.unwrap(),
+            vec![1, 1, 1]
+        );
+
+        // A retry with the same id is refused by the insert, and its send is refunded
+        let frame = serde_json::json!({
+            "message_type": "send_message",
+            "data": {
+                "message_id": message_id.to_string(),
+                "receiver_id": receiver.to_string(),
+                "type": "Text",
+                "encrypted_content": "AQID",
+                "iv": "AAAAAAAAAAAAAAAA",
+            },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        let result = handle_client_message(
+            parse_frame(&frame),
+            sender,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(
+            messages.send_counts(sender, &windows).await.unwrap(),
+            vec![1, 1, 1]
+        );
+    }
+
+    /// Waits for the background task recording a delivery attempt of `message_id`.
+    async fn recorded_attempts(
+        messages: &FakeMessageRepo,
+        message_id: Uuid,
+    ) -> Vec<DeliveryAttemptRecord> {
+        for _ in 0..100 {
+            let attempts = messages.delivery_attempts(message_id).await.unwrap();
+            if !attempts.is_empty() {
+                return attempts;
+            }
+            sleep(Duration::from_millis(5)).await;
+        }
+        panic!("no delivery attempt recorded for message {}", message_id);
+    }
+
+    #[tokio::test]
+    async fn test_delivery_attempts_recorded_for_connected_and_offline_receivers() {
+        let (state, messages) = fake_state(false);
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let (online, offline) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_receiver_tx, mut receiver_rx, _) = join_user_channel(&state.connections, online);
+
+        let delivered = send_text(&state, sender, online).await;
+        assert!(matches!(
+            receiver_rx.try_recv().unwrap(),
+            WSEvent::NewMessage(_)
+        ));
+        let attempts = recorded_attempts(&messages, delivered).await;
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(
+            (attempts[0].channel.as_str(), attempts[0].outcome.as_str()),
+            ("ws", "delivered")
+        );
+        assert_eq!(
+            service::delivery_state(&attempts),
+            service::DeliveryState::DeliveredToSocket
+        );
+
+        let waiting = send_text(&state, sender, offline).await;
+        let attempts = recorded_attempts(&messages, waiting).await;
+        assert_eq!(attempts[0].outcome, "offline");
+        assert_eq!(attempts[0].error, None);
+        assert_eq!(
+            service::delivery_state(&attempts),
+            service::DeliveryState::ReceiverOffline
+        );
+    }
+
+    async fn send_sealed(
+        state: &Arc<AppState>,
+        sender: Uuid,
+        receiver: Uuid,
+        message_id: Uuid,
+    ) -> Result<(), String> {
+        let frame = serde_json::json!({
+            "message_type": "send_message",
+            "data": {
+                "message_id": message_id.to_string(),
+                "receiver_id": receiver.to_string(),
+                "type": "Text",
+                "encrypted_content": "AQID",
+                "iv": "AAAAAAAAAAAAAAAA",
+                "sealed_metadata": true,
+                "encrypted_metadata": "BAUG",
+            },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        handle_client_message(
+            parse_frame(&frame),
+            sender,
+            &state.connections,
+            &pending_acks,
+            state.clone(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_sealed_message_is_stored_without_sender() {
+        let (state, messages) = fake_state_with(|state| state.features.sealed_sender = true);
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let receiver = state
+            .users
+            .create_user("receiver", "hash", "key2")
+            .await
+            .unwrap();
+        state
+            .contacts
+            .add_contact(receiver, sender, 10)
+            .await
+            .unwrap();
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+        let (_receiver_tx, mut receiver_rx, _) = join_user_channel(&state.connections, receiver);
+
+        let message_id = Uuid::new_v4();
+        send_sealed(&state, sender, receiver, message_id)
+            .await
+            .unwrap();
+        match receiver_rx.try_recv().unwrap() {
+            WSEvent::SealedMessage(sealed) => {
+                assert_eq!(sealed.id, message_id.to_string());
+                assert_eq!(sealed.encrypted_metadata, "BAUG");
+            }
+            other => panic!("expected a sealed message, got {:?}", other),
+        }
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::StatusUpdate(update) => assert_eq!(update.status, MessageStatus::Sent),
+            other => panic!("expected a status update, got {:?}", other),
+        }
+
+        let stored = messages.sealed_messages_for(receiver).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].encrypted_metadata, vec![4, 5, 6]);
+        assert!(!stored[0].routing_id.contains(&sender.to_string()));
+        assert!(messages.find_message(message_id).await.unwrap().is_none());
+        assert!(
+            messages
+                .unread_counts(receiver, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(
+            send_sealed(&state, sender, receiver, message_id)
+                .await
+                .is_err(),
+            "duplicate id"
+        );
+        service::delete_sealed_message(messages.as_ref(), receiver, message_id)
+            .await
+            .unwrap();
+        assert!(
+            messages
+                .sealed_messages_for(receiver)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sealed_message_cannot_open_a_conversation() {
+        let (state, messages) = fake_state_with(|state| state.features.sealed_sender = true);
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let stranger = state
+            .users
+            .create_user("stranger", "hash", "key2")
+            .await
+            .unwrap();
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+
+        assert!(
+            send_sealed(&state, sender, stranger, Uuid::new_v4())
+                .await
+                .is_err()
+        );
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::Error(error) => assert_eq!(error.code, ErrorCode::SealedNewConversation),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(
+            messages
+                .sealed_messages_for(stranger)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // Once the sender opened the conversation unsealed, sealed messages may follow
+        crate::contacts::record_conversation_peer(
+            &state,
+            sender,
+            stranger,
+            Some(state.clock.now()),
+        )
+        .await
+        .unwrap();
+        send_sealed(&state, sender, stranger, Uuid::new_v4())
+            .await
+            .unwrap();
+        assert_eq!(
+            messages.sealed_messages_for(stranger).await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sealed_message_rejected_when_feature_disabled() {
+        let (state, messages) = fake_state(false);
+        let (sender, receiver) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+
+        assert!(
+            send_sealed(&state, sender, receiver, Uuid::new_v4())
+                .await
+                .is_err()
+        );
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::Error(error) => assert_eq!(error.code, ErrorCode::SealedMetadataDisabled),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(
+            messages
+                .sealed_messages_for(receiver)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_conversation_limit_rejects_only_new_conversations() {
+        let (state, _messages) = fake_state_with(|state| {
+            state.new_conversation_limits = crate::service::NewConversationLimits {
+                per_day: 1,
+                established_per_day: 1,
+            }
+        });
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+        let send = |receiver: Uuid| {
+            let state = state.clone();
+            async move {
+                let frame = serde_json::json!({
+                    "message_type": "send_message",
+                    "data": {
+                        "message_id": Uuid::new_v4().to_string(),
+                        "receiver_id": receiver.to_string(),
+                        "type": "Text",
+                        "encrypted_content": "AQID",
+                        "iv": "AAAAAAAAAAAAAAAA",
+                    },
+                })
+                .to_string();
+                let pending_acks = PendingAcks::default();
+                handle_client_message(
+                    parse_frame(&frame),
+                    sender,
+                    &state.connections,
+                    &pending_acks,
+                    state.clone(),
+                )
+                .await
+            }
+        };
+
+        send(first).await.unwrap();
+        while sender_rx.try_recv().is_ok() {}
+        assert!(send(second).await.is_err());
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::Error(error) => {
+                assert_eq!(error.code, ErrorCode::NewConversationLimit);
+                assert!(error.retry_after.is_some_and(|secs| secs > 86_000));
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert_eq!(state.new_conversation_limit_hits.load(Ordering::Relaxed), 1);
+        // The conversation opened before the limit was reached goes on
+        send(first).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_can_be_retried_with_its_nonce() {
+        let (state, messages) = fake_state(true);
+        let sender = state
+            .users
+            .create_user("sender", "hash", "key")
+            .await
+            .unwrap();
+        let receiver = state
+            .users
+            .create_user("receiver", "hash", "key2")
+            .await
+            .unwrap();
+        let (_sender_tx, mut sender_rx, _) = join_user_channel(&state.connections, sender);
+        let message_id = Uuid::new_v4();
+        let frame = serde_json::json!({
+            "message_type": "send_message",
+            "data": {
+                "message_id": message_id.to_string(),
+                "receiver_id": receiver.to_string(),
+                "type": "Text",
+                "encrypted_content": "AQID",
+                "iv": "AAAAAAAAAAAAAAAA",
+                "nonce": "retry-1",
+            },
+        })
+        .to_string();
+        let pending_acks = PendingAcks::default();
+        let send = || {
+            handle_client_message(
+                parse_frame(&frame),
+                sender,
+                &state.connections,
+                &pending_acks,
+                state.clone(),
+            )
+        };
+
+        // Refused until the receiver adds the sender, so nothing is stored or acked
+        assert!(send().await.is_err());
+        assert!(messages.find_message(message_id).await.unwrap().is_none());
+        while let Ok(event) = sender_rx.try_recv() {
+            assert!(!matches!(event, WSEvent::Ack(_)), "failed write was acked");
+        }
+
+        state
+            .contacts
+            .add_contact(receiver, sender, 10)
+            .await
+            .unwrap();
+        state.relationships.remove(&receiver);
+        send().await.unwrap();
+        assert!(messages.find_message(message_id).await.unwrap().is_some());
+        let mut acks = Vec::new();
+        while let Ok(event) = sender_rx.try_recv() {
+            if let WSEvent::Ack(ack) = event {
+                acks.push((ack.nonce, ack.duplicate));
+            }
+        }
+        assert_eq!(acks, vec![("retry-1".to_string(), false)]);
+
+        // Once applied, the nonce is a replay again
+        send().await.unwrap();
+        match sender_rx.try_recv().unwrap() {
+            WSEvent::Ack(ack) => assert!(ack.duplicate),
+            other => panic!("expected a duplicate ack, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replies_reference_messages_of_the_same_conversation() {
+        let (state, messages) = fake_state(false);
+        let alice = state
+            .users
+            .create_user("alice", "hash", "key")
+            .await
+            .unwrap();
+        let bob = state
+            .users
+            .create_user("bob", "hash", "key2")
+            .await
+            .unwrap();
+        let carol = state
+            .users
+            .create_user("carol", "hash", "key3")
+            .await
+            .unwrap();
+        let quoted = messages.seed_message(bob, alice, MessageStatus::Read);
+        let elsewhere = messages.seed_message(carol, alice, MessageStatus::Read);
+        let (_alice_tx, mut alice_rx, _) = join_user_channel(&state.connections, alice);
+        let (_bob_tx, mut bob_rx, _) = join_user_channel(&state.connections, bob);
+        let reply = |reply_to: Uuid| {
+            let state = state.clone();
+            async move {
+                let frame = serde_json::json!({
+                    "message_type": "send_message",
+                    "data": {
+                        "message_id": Uuid::new_v4().to_string(),
+                        "receiver_id": bob.to_string(),
+                        "type": "Text",
+                        "encrypted_content": "AQID",
+                        "iv": "AAAAAAAAAAAAAAAA",
+                        "reply_to": reply_to.to_string(),
+                    },
+                })
+                .to_string();
+                let pending_acks = PendingAcks::default();
+                handle_client_message(
+                    parse_frame(&frame),
+                    alice,
+                    &state.connections,
+                    &pending_acks,
+                    state.clone(),
+                )
+                .await
+            }
+        };
+
+        reply(quoted).await.unwrap();
+        let WSEvent::NewMessage(notification) = bob_rx.try_recv().unwrap() else {
+            panic!("expected the reply");
+        };
+        let reference = notification.reply_to.unwrap();
+        assert_eq!(reference.message_id, quoted.to_string());
+        assert!(!reference.deleted);
+
+        // Once the quoted message is deleted after being read, replies and listings show a tombstone
+        messages.delete_read_message(quoted, false).await.unwrap();
+        reply(quoted).await.unwrap();
+        let WSEvent::NewMessage(notification) = bob_rx.try_recv().unwrap() else {
+            panic!("expected the reply");
+        };
+        assert!(notification.reply_to.unwrap().deleted);
+        let listed = state
+            .messages
+            .conversation(alice, bob, Default::default())
+            .await
+            .unwrap();
+        assert!(
+            listed
+                .iter()
+                .all(|m| m.reply_to_id == Some(quoted) && m.reply_to_deleted)
+        );
+
+        while alice_rx.try_recv().is_ok() {}
+        assert!(reply(elsewhere).await.is_err());
+        match alice_rx.try_recv().unwrap() {
+            WSEvent::Error(error) => assert_eq!(error.code, ErrorCode::InvalidReplyTarget),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(bob_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_probes_are_limited_per_pair() {
+        let limiter = crate::rate_limit::InMemoryBackend::new();
+        let try_acquire_probe = |sender_id: Uuid, receiver_id: Uuid, now: Instant| {
+            limiter
+                .check_and_increment_at(
+                    &probe_limit_key(sender_id, receiver_id),
+                    PROBE_LIMIT_PER_WINDOW,
+                    PROBE_WINDOW,
+                    now,
+                )
+                .into_result()
+        };
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+        for _ in 0..PROBE_LIMIT_PER_WINDOW {
+            assert!(try_acquire_probe(alice, bob, start).is_ok());
+        }
+        assert_eq!(
+            try_acquire_probe(alice, bob, start + Duration::from_secs(15)),
+            Err(Duration::from_secs(45))
+        );
+        assert!(try_acquire_probe(alice, carol, start).is_ok());
+        assert!(try_acquire_probe(bob, alice, start).is_ok());
+        assert!(try_acquire_probe(alice, bob, start + PROBE_WINDOW).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs DATABASE_URL with migrations applied"]
+    async fn test_concurrent_sends_keep_conversation_order() {
+        use crate::api::{MessageRangeQuery, UuidPath, get_messages_with_user};
+        use axum::body::HttpBody;
+        use axum::http::HeaderMap;
+        use axum::response::IntoResponse;
+        use std::collections::HashSet;
+
+        const SENDS: usize = 50;
+        let mut state = db_state().await;
+        // Fresh accounts would otherwise hit the new-account send quota
+        let generous = crate::service::SendQuota {
+            per_minute: SENDS as i64,
+            per_hour: SENDS as i64,
+            per_day: SENDS as i64,
+        };
+        Arc::get_mut(&mut state).unwrap().send_limits = crate::service::SendLimits {
+            standard: generous,
+            new_account: generous,
+        };
+        let suffix = Uuid::new_v4().simple().to_string();
+        let user_a = state
+            .users
+            .create_user(
+                &format!("order-a-{}", suffix),
+                "hash",
+                &format!("key-a-{}", suffix),
+            )
+            .await
+            .unwrap();
+        let user_b = state
+            .users
+            .create_user(
+                &format!("order-b-{}", suffix),
+                "hash",
+                &format!("key-b-{}", suffix),
+            )
+            .await
+            .unwrap();
+
+        let sends: Vec<_> = (0..SENDS)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let frame = serde_json::json!({
+                        "message_type": "send_message",
+                        "data": {
+                            "message_id": Uuid::new_v4().to_string(),
+                            "receiver_id": user_b.to_string(),
+                            "type": "Text",
+                            "encrypted_content": "AQID",
+                            "iv": "AAAAAAAAAAAAAAAA",
+                        },
+                    })
+                    .to_string();
+                    let pending_acks = PendingAcks::default();
+                    let connections = state.connections.clone();
+                    handle_client_message(
+                        parse_frame(&frame),
+                        user_a,
+                        &connections,
+                        &pending_acks,
+                        state,
+                    )
+                    .await
+                })
+            })
+            .collect();
+        for send in sends {
+            send.await.unwrap().unwrap();
+        }
+
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(user_b, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "authorization",
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        let response = get_messages_with_user(
+            UuidPath(user_a),
+            Query(MessageRangeQuery {
+                after: None,
+                before: None,
+                order: None,
+                limit: None,
+                cursor: None,
+                no_receipt: None,
+            }),
+            State(state.clone()),
+            headers,
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+
+        sqlx::query("DELETE FROM messages WHERE sender_id = $1")
+            .bind(user_a)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM users WHERE id = ANY($1)")
+            .bind(vec![user_a, user_b])
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        assert_eq!(messages.len(), SENDS);
+        let ids: HashSet<&str> = messages.iter().map(|m| m["id"].as_str().unwrap()).collect();
+        assert_eq!(ids.len(), SENDS);
+        // The timestamp is the conversation's ordering key: strictly increasing, never shared
+        let timestamps: Vec<i64> = messages
+            .iter()
+            .map(|m| m["timestamp"].as_str().unwrap().parse().unwrap())
+            .collect();
+        assert!(
+            timestamps.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            timestamps
+        );
+    }
+
+    #[test]
+    fn test_instance_limit_refuses_upgrades_until_a_slot_frees() {
+        let tracker = Arc::new(ConnectionTracker::new(
+            2,
+            DEFAULT_WS_MAX_CONNECTIONS_PER_USER,
+        ));
+        let first = tracker.try_reserve().unwrap();
+        let _second = tracker.try_reserve().unwrap();
+        assert!(tracker.try_reserve().is_none());
+        assert_eq!(tracker.refused.load(Ordering::Relaxed), 1);
+        drop(first);
+        assert_eq!(tracker.open_connections(), 1);
+        assert!(tracker.try_reserve().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connection_over_per_user_limit_closes_the_oldest() {
+        let (state, _) = fake_state_with(|state| state.multi_device = true);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(websocket_handler))
+            .with_state(state.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let user = Uuid::new_v4();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(user, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        let url = format!("ws://{}/ws?token={}", addr, token);
+
+        let tracker = &state.connection_tracker;
+        let mut clients = Vec::new();
+        for opened in 1..=DEFAULT_WS_MAX_CONNECTIONS_PER_USER + 1 {
+            let (client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            clients.push(client);
+            // Wait for the server side to register it, so the connections' age is known
+            while tracker.next_id.load(Ordering::SeqCst) < opened as u64 {
+                sleep(Duration::from_millis(5)).await;
+            }
+        }
+
+        let close = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match clients[0].next().await {
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Close(frame))) => {
+                        return frame;
+                    }
+                    Some(Ok(_)) => continue,
+                    other => panic!("oldest connection ended without a close frame: {:?}", other),
+                }
+            }
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(u16::from(close.code), CloseReason::ConnectionLimit.code());
+        assert_eq!(CloseReason::ConnectionLimit.code(), 4003);
+        assert_eq!(close.reason, "connection_limit");
+        assert_eq!(tracker.evictions.load(Ordering::Relaxed), 1);
+        let remaining: Vec<u64> = tracker
+            .per_user
+            .get(&user)
+            .unwrap()
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(remaining, vec![1, 2, 3, 4, 5]);
+        for client in &mut clients[1..] {
+            let next = tokio::time::timeout(Duration::from_millis(50), client.next()).await;
+            assert!(
+                !matches!(
+                    next,
+                    Ok(Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))))
+                ),
+                "only the oldest connection is closed"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_over_protocol_limit_gets_an_error_then_closes() {
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let (state, _) = fake_state_with(|state| state.ws_max_frame_bytes = 1024);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(websocket_handler))
+            .with_state(state.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let user = Uuid::new_v4();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(user, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        let url = format!("ws://{}/ws?token={}", addr, token);
+        let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+
+        let oversized = "x".repeat(1024 * WS_PROTOCOL_LIMIT_FACTOR + 1);
+        client.send(WsMessage::Text(oversized)).await.unwrap();
+        let (error, close) = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut error = None;
+            loop {
+                match client.next().await {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        let event: WebSocketMessage = serde_json::from_str(&text).unwrap();
+                        if event.message_type == "error" {
+                            error = Some(event.data);
+                        }
+                    }
+                    Some(Ok(WsMessage::Close(frame))) => return (error, frame),
+                    Some(Ok(_)) => continue,
+                    other => panic!("connection ended without a close frame: {:?}", other),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let error = error.expect("an error event before the close frame");
+        assert_eq!(error["code"], "frame_too_large");
+        assert_eq!(error["message"], "Frames may not exceed 1024 bytes");
+        let close = close.unwrap();
+        assert_eq!(u16::from(close.code), 1009);
+        assert_eq!(close.reason, "frame_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_only_clients_that_opt_in_are_asked_for_acks() {
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let (state, _) = fake_state(false);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(websocket_handler))
+            .with_state(state.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        for (hello, acked) in [
+            (serde_json::json!({}), false),
+            (serde_json::json!({ "acks": true }), true),
+        ] {
+            let user = Uuid::new_v4();
+            let token = jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &crate::auth::Claims::new(user, (Utc::now().timestamp() + 60) as usize),
+                &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+            )
+            .unwrap();
+            let url = format!("ws://{}/ws?token={}", addr, token);
+            let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            let frame = serde_json::json!({ "message_type": "hello", "data": hello });
+            client
+                .send(WsMessage::Text(frame.to_string()))
+                .await
+                .unwrap();
+            let mut next_event = async || loop {
+                if let Some(Ok(WsMessage::Text(text))) = client.next().await {
+                    let event: WebSocketMessage = serde_json::from_str(&text).unwrap();
+                    if event.message_type != "user_online" {
+                        return event;
+                    }
+                }
+            };
+            assert_eq!(next_event().await.message_type, "hello_ack");
+
+            let cleared = ConversationCleared {
+                user_id: Uuid::new_v4().to_string(),
+                cleared_by: user.to_string(),
+                deleted_count: 1,
+            };
+            state
+                .connections
+                .get(&user)
+                .unwrap()
+                .send(WSEvent::ConversationCleared(cleared))
+                .unwrap();
+            let event = next_event().await;
+            assert_eq!(event.message_type, "conversation_cleared");
+            assert_eq!(event.ack_id.is_some(), acked, "hello {}", hello);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_resends_are_recorded() {
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let (state, messages) =
+            fake_state_with(|state| state.ws_ack_timeout = Duration::from_millis(20));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(websocket_handler))
+            .with_state(state.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let user = Uuid::new_v4();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(user, (Utc::now().timestamp() + 60) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+        let url = format!("ws://{}/ws?token={}", addr, token);
+        let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let frame = serde_json::json!({ "message_type": "hello", "data": { "acks": true } });
+        client
+            .send(WsMessage::Text(frame.to_string()))
+            .await
+            .unwrap();
+        loop {
+            if let Some(Ok(WsMessage::Text(text))) = client.next().await
+                && serde_json::from_str::<WebSocketMessage>(&text)
+                    .unwrap()
+                    .message_type
+                    == "hello_ack"
+            {
+                break;
+            }
+        }
+
+        // The client reads the message and its resends but never acknowledges them
+        let event = notification_for(None);
+        let message_id = acked_message_id(&event).unwrap();
+        state.connections.get(&user).unwrap().send(event).unwrap();
+        tokio::spawn(async move { while client.next().await.is_some() {} });
+        let attempts = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let attempts = messages.delivery_attempts(message_id).await.unwrap();
+                if attempts.len() > 1 && attempts[0].outcome == service::DELIVERY_EXHAUSTED {
+                    return attempts;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the give-up was never recorded");
+
+        // Retries follow timer ticks, so a busy runtime may resend fewer times before giving up
+        assert!(
+            attempts[1..]
+                .iter()
+                .all(|attempt| attempt.outcome == service::DELIVERY_RESENT)
+        );
+        assert!(attempts[0].error.is_some());
+        assert_eq!(
+            service::delivery_state(&attempts),
+            service::DeliveryState::Exhausted
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_is_explained_before_close() {
+        use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+        let (state, _) = fake_state(false);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(websocket_handler))
+            .with_state(state.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let expired = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &crate::auth::Claims::new(Uuid::new_v4(), (Utc::now().timestamp() - 3600) as usize),
+            &jsonwebtoken::EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+
+        for (token, reason) in [
+            (expired.as_str(), CloseReason::TokenExpired),
+            ("not-a-jwt", CloseReason::InvalidToken),
+        ] {
+            let url = format!("ws://{}/ws?token={}", addr, token);
+            let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            let Some(Ok(WsMessage::Text(text))) = client.next().await else {
+                panic!("expected an auth_failed event for {:?}", reason);
+            };
+            let event: WebSocketMessage = serde_json::from_str(&text).unwrap();
+            assert_eq!(event.message_type, "auth_failed");
+            assert_eq!(event.data["code"], reason.reason());
+            let Some(Ok(WsMessage::Close(Some(close)))) = client.next().await else {
+                panic!("expected a close frame for {:?}", reason);
+            };
+            assert_eq!(u16::from(close.code), reason.code());
+            assert_eq!(close.reason, reason.reason());
+        }
+        assert_eq!(state.connection_tracker.open_connections(), 0);
+
+        // A request without a token is still refused before the upgrade
+        let url = format!("ws://{}/ws?token=", addr);
+        match tokio_tungstenite::connect_async(&url).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::UNAUTHORIZED),
+            other => panic!(
+                "expected 401, got {:?}",
+                other.map(|(_, response)| response)
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registration_mode_gates_register() {
+        use crate::auth::{RegisterRequest, register};
+        use crate::clock::MockClock;
+        use crate::repo::AuditActor;
+        use crate::state::RegistrationMode;
+        use axum::Json;
+        use axum::body::HttpBody;
+        use axum::response::IntoResponse;
+
+        async fn try_register(
+            state: &Arc<AppState>,
+            username: &str,
+            invite_code: Option<&str>,
+        ) -> (StatusCode, serde_json::Value) {
+            let response = register(
+                State(state.clone()),
+                Json(RegisterRequest {
+                    username: username.to_string(),
+                    password: "correct horse".to_string(),
+                    invite_code: invite_code.map(str::to_string),
+                }),
+            )
+            .await
+            .into_response();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+        let cheap_hashes = |state: &mut AppState| {
+            state.password_hash_params = argon2::Params::new(8, 1, 1, None).unwrap();
+        };
+
+        let (closed, _) = fake_state_with(|state| {
+            cheap_hashes(state);
+            state.registration_mode = RegistrationMode::Closed;
+        });
+        let (status, body) = try_register(&closed, "alice", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "registration_closed");
+        assert!(body["message"].is_string());
+
+        let now = chrono::Utc::now();
+        let clock = Arc::new(MockClock::new(now));
+        let (state, _) = fake_state_with(|state| {
+            cheap_hashes(state);
+            state.clock = clock.clone();
+            state.registration_mode = RegistrationMode::Invite;
+        });
+        let (status, body) = try_register(&state, "alice", None).await;
+        assert_eq!(
+            (status, body["error"].as_str()),
+            (StatusCode::FORBIDDEN, Some("invite_required"))
+        );
+        let (_, body) = try_register(&state, "alice", Some("made-up")).await;
+        assert_eq!(body["error"], "invalid_invite");
+
+        let actor = AuditActor {
+            source_ip: "127.0.0.1".to_string(),
+            admin: None,
+        };
+        let invite = crate::service::create_invite(state.users.as_ref(), None, None, now, &actor)
+            .await
+            .unwrap();
+        let short_lived = crate::service::create_invite(
+            state.users.as_ref(),
+            Some("expires in a minute"),
+            Some(now + chrono::Duration::minutes(1)),
+            now,
+            &actor,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.users.unused_invites(now).await.unwrap().len(), 2);
+        clock.advance(chrono::Duration::minutes(2));
+        let (_, body) = try_register(&state, "alice", Some(&short_lived.code)).await;
+        assert_eq!(body["error"], "invalid_invite");
+
+        let (status, body) = try_register(&state, "alice", Some(&invite.code)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["username"], "alice");
+        // Single use: the code is spent once an account was created with it
+        let (_, body) = try_register(&state, "bob", Some(&invite.code)).await;
+        assert_eq!(body["error"], "invalid_invite");
+        assert!(
+            state
+                .users
+                .unused_invites(clock.now())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! WebSocket protocol description for Safe Chat backend
+//!
+//! `GET /ws/schema` describes what travels over `/ws` as JSON Schema: the frame envelope,
+//! the `data` of every `message_type` a client may send and of every event the server
+//! emits, and the error codes. It also names the subprotocols and the encryption versions
+//! a client can negotiate. The schemas are derived from the types the handlers read and
+//! write, so they change together with the code.
+
+use crate::websocket::{
+    Ack, AnnouncementNotification, AuthFailed, ClientAckData, ContactRequestNotification,
+    ConversationCleared, DeliveryReceipt, DeviceRevoked, ErrorCode, ErrorNotification, HelloAck,
+    HelloData, MessageDeleted, MessageNotification, MetaUpdate, PinUpdate, Presence,
+    ProbeNotification, ProbeResult, SUBPROTOCOLS, SealedMessageNotification, SendMessageData,
+    SignedReceiptData, StatusUpdate, UnreadCounts, UpdateStatusData, WebSocketMessage,
+};
+
+use axum::Json;
+use axum::response::IntoResponse;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize)]
+pub struct WsSchema {
+    /// Values for `Sec-WebSocket-Protocol`, most preferred first.
+    pub subprotocols: [&'static str; 2],
+    /// What `hello_ack` reports: the `encryption_version`s `send_message` accepts.
+    pub encryption: HelloAck,
+    /// Every frame, in either direction; `data` depends on `message_type`.
+    pub frame: RootSchema,
+    /// `data` of each `message_type` a client may send.
+    pub client_messages: BTreeMap<&'static str, RootSchema>,
+    /// `data` of each `message_type` the server sends.
+    pub server_events: BTreeMap<&'static str, RootSchema>,
+    /// The `code` of an `error` event.
+    pub error_codes: RootSchema,
+}
+
+pub fn ws_schema() -> WsSchema {
+    // These frames carry no data the server reads
+    let no_data = schema_for!(serde_json::Value);
+    WsSchema {
+        subprotocols: SUBPROTOCOLS,
+        encryption: HelloAck::current(),
+        frame: schema_for!(WebSocketMessage),
+        client_messages: BTreeMap::from([
+            ("send_message", schema_for!(SendMessageData)),
+            ("update_status", schema_for!(UpdateStatusData)),
+            ("signed_receipt", schema_for!(SignedReceiptData)),
+            ("ack", schema_for!(ClientAckData)),
+            ("hello", schema_for!(HelloData)),
+            ("ping", no_data.clone()),
+            ("unread_counts", no_data.clone()),
+            ("mark_typing", no_data),
+        ]),
+        server_events: BTreeMap::from([
+            ("new_message", schema_for!(MessageNotification)),
+            ("sealed_message", schema_for!(SealedMessageNotification)),
+            ("status_update", schema_for!(StatusUpdate)),
+            ("delivery_receipt", schema_for!(DeliveryReceipt)),
+            ("user_online", schema_for!(Presence<'static>)),
+            ("user_offline", schema_for!(Presence<'static>)),
+            ("pin_update", schema_for!(PinUpdate)),
+            ("message_meta_update", schema_for!(MetaUpdate)),
+            ("device_revoked", schema_for!(DeviceRevoked)),
+            ("contact_request", schema_for!(ContactRequestNotification)),
+            ("conversation_cleared", schema_for!(ConversationCleared)),
+            ("message_deleted", schema_for!(MessageDeleted)),
+            ("announcement", schema_for!(AnnouncementNotification)),
+            ("probe", schema_for!(ProbeNotification)),
+            ("probe_result", schema_for!(ProbeResult)),
+            ("error", schema_for!(ErrorNotification)),
+            ("hello_ack", schema_for!(HelloAck)),
+            ("auth_failed", schema_for!(AuthFailed)),
+            ("unread_counts", schema_for!(UnreadCounts)),
+            ("ack", schema_for!(Ack)),
+        ]),
+        error_codes: schema_for!(ErrorCode),
+    }
+}
+
+/// Describes the WebSocket protocol; see the module docs. Needs no authentication.
+pub async fn get_ws_schema() -> impl IntoResponse {
+    Json(ws_schema())
+}